@@ -0,0 +1,247 @@
+//! Consumption anomaly alerts: the usual house load for each hour of the day, learned from
+//! the hourly averages of the last weeks, and a detector that fires when the load stays
+//! well above it, like a heater left on overnight.
+
+use chrono::{NaiveDate, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+
+/// Days of history each hour of the day needs before the alert is armed.
+pub const MIN_DAYS: usize = 7;
+/// Smallest spread the band is built on, so a very steady hour doesn't alert on a kettle.
+pub const MIN_SPREAD_W: f64 = 100.0;
+
+/// CONSUMPTION_ANOMALY_* settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnomalyConfig {
+    /// How many spreads (median absolute deviations) above the median the load may go.
+    pub factor: f64,
+    /// How long the load has to stay above the band before the alert fires.
+    pub sustain: Duration,
+    /// Weeks of hourly averages the usual load is learned from.
+    pub weeks: u32,
+}
+
+impl AnomalyConfig {
+    pub fn new(factor: f64) -> Self {
+        Self { factor, sustain: Duration::from_secs(1800), weeks: 4 }
+    }
+}
+
+pub fn median(values: &[f64]) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((sorted[middle - 1] + sorted[middle]) / 2.0),
+        _ => Some(sorted[middle]),
+    }
+}
+
+/// The mean load of every finished hour of the last weeks, by local date and hour, and the
+/// samples of the hour in progress.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct History {
+    hours: Vec<(NaiveDate, u32, f64)>,
+    current: Option<(NaiveDate, u32)>,
+    sum_w: f64,
+    samples: u32,
+}
+
+impl History {
+    pub fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save the consumption history to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Adds a load sample taken at local time `at`, keeping `weeks` of finished hours.
+    pub fn record(&mut self, at: NaiveDateTime, load_w: f64, weeks: u32) {
+        let hour = (at.date(), at.hour());
+        if self.current.is_some_and(|current| current != hour) {
+            self.finish_hour();
+        }
+        self.current = Some(hour);
+        self.sum_w += load_w;
+        self.samples += 1;
+        let oldest = at.date() - chrono::Days::new(u64::from(weeks) * 7);
+        self.hours.retain(|(date, _, _)| *date >= oldest);
+    }
+
+    fn finish_hour(&mut self) {
+        if let Some((date, hour)) = self.current.take().filter(|_| self.samples > 0) {
+            self.hours.push((date, hour, self.sum_w / f64::from(self.samples)));
+        }
+        self.sum_w = 0.0;
+        self.samples = 0;
+    }
+
+    /// The usual load of each hour of the day, None for the hours never seen.
+    pub fn baseline(&self) -> Baseline {
+        let mut baseline = Baseline::default();
+        for (hour, band) in baseline.hours.iter_mut().enumerate() {
+            let loads: Vec<f64> = self.hours.iter().filter(|(_, h, _)| *h as usize == hour).map(|(_, _, w)| *w).collect();
+            let Some(median_w) = median(&loads) else { continue };
+            let deviations: Vec<f64> = loads.iter().map(|w| (w - median_w).abs()).collect();
+            *band = Some(Band { median_w, spread_w: median(&deviations).unwrap_or_default(), days: loads.len() });
+        }
+        baseline
+    }
+}
+
+/// The usual load of one hour of the day.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub median_w: f64,
+    /// Median absolute deviation from `median_w`.
+    pub spread_w: f64,
+    /// Days the hour was seen on.
+    pub days: usize,
+}
+
+impl Band {
+    /// The load above which an hour counts as unusual.
+    pub fn limit_w(&self, factor: f64) -> f64 {
+        self.median_w + factor * self.spread_w.max(MIN_SPREAD_W)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Baseline {
+    pub hours: [Option<Band>; 24],
+}
+
+impl Baseline {
+    /// Whether every hour of the day has enough history.
+    pub fn is_ready(&self) -> bool {
+        self.fewest_days() >= MIN_DAYS
+    }
+
+    /// Days of history of the hour with the least.
+    pub fn fewest_days(&self) -> usize {
+        self.hours.iter().map(|band| band.map_or(0, |band| band.days)).min().unwrap_or_default()
+    }
+}
+
+/// What an alert reports: the load against the usual load of the hour it's in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub hour: u32,
+    pub load_w: f64,
+    pub typical_w: f64,
+    pub limit_w: f64,
+    /// Since when the load has been above the limit.
+    pub since: u64,
+}
+
+impl Anomaly {
+    /// How far above the usual load of the hour the load is.
+    pub fn excess_w(&self) -> f64 {
+        self.load_w - self.typical_w
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transition {
+    Fired(Anomaly),
+    Cleared,
+}
+
+/// Tracks whether the load is above its band, and for how long.
+#[derive(Debug)]
+pub struct Detector {
+    pub config: AnomalyConfig,
+    above_since: Option<u64>,
+    active: bool,
+}
+
+impl Detector {
+    pub fn new(config: AnomalyConfig) -> Self {
+        Self { config, above_since: None, active: false }
+    }
+
+    /// Checks a load sample taken at local `hour`; returns a transition when the alert
+    /// fires or clears. Nothing happens until the baseline is ready.
+    pub fn observe(&mut self, baseline: &Baseline, hour: u32, load_w: f64, now: u64) -> Option<Transition> {
+        let band = baseline.hours.get(hour as usize).copied().flatten().filter(|_| baseline.is_ready())?;
+        let limit_w = band.limit_w(self.config.factor);
+        if load_w <= limit_w {
+            self.above_since = None;
+            return std::mem::take(&mut self.active).then_some(Transition::Cleared);
+        }
+        let since = *self.above_since.get_or_insert(now);
+        if self.active || now.saturating_sub(since) < self.config.sustain.as_secs() {
+            return None;
+        }
+        self.active = true;
+        Some(Transition::Fired(Anomaly { hour, load_w, typical_w: band.median_w, limit_w, since }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u64, hour: u32, minute: u32) -> NaiveDateTime {
+        (NaiveDate::from_ymd_opt(2026, 1, 1).unwrap() + chrono::Days::new(day)).and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    /// `days` of a house using 400 W at night and 1 kW during the day, a little more on odd days.
+    fn history(days: u64) -> History {
+        let mut history = History::default();
+        for day in 0..days {
+            for hour in 0..24 {
+                let load_w = if (7..22).contains(&hour) { 1000.0 } else { 400.0 } + (day % 2) as f64 * 50.0;
+                history.record(at(day, hour, 0), load_w, 4);
+                history.record(at(day, hour, 30), load_w, 4);
+            }
+        }
+        history.record(at(days, 0, 0), 400.0, 4);
+        history
+    }
+
+    #[test]
+    fn learns_the_usual_load_per_hour() {
+        assert!(!history(MIN_DAYS as u64 - 1).baseline().is_ready());
+        assert_eq!(history(3).baseline().fewest_days(), 3);
+
+        let baseline = history(10).baseline();
+        assert!(baseline.is_ready());
+        let night = baseline.hours[2].unwrap();
+        assert_eq!((night.median_w, night.spread_w, night.days), (425.0, 25.0, 10));
+        assert_eq!(baseline.hours[12].unwrap().median_w, 1025.0);
+        assert_eq!(night.limit_w(3.0), 725.0);
+
+        // Only the last weeks are kept
+        assert_eq!(history(40).baseline().fewest_days(), 28);
+    }
+
+    #[test]
+    fn fires_after_the_load_stays_above_the_band() {
+        let baseline = history(10).baseline();
+        let mut detector = Detector::new(AnomalyConfig { sustain: Duration::from_secs(600), ..AnomalyConfig::new(3.0) });
+        // A heater at 2 AM, with a dip below the band restarting the clock
+        assert_eq!(detector.observe(&baseline, 2, 2400.0, 0), None);
+        assert_eq!(detector.observe(&baseline, 2, 500.0, 300), None);
+        assert_eq!(detector.observe(&baseline, 2, 2400.0, 360), None);
+        assert_eq!(detector.observe(&baseline, 2, 2400.0, 900), None);
+        let Some(Transition::Fired(anomaly)) = detector.observe(&baseline, 2, 2400.0, 960) else { panic!("no alert") };
+        assert_eq!((anomaly.since, anomaly.typical_w, anomaly.excess_w()), (360, 425.0, 1975.0));
+        assert_eq!(detector.observe(&baseline, 3, 2400.0, 1020), None);
+        // The same load is usual during the day
+        assert_eq!(detector.observe(&baseline, 12, 1100.0, 1080), Some(Transition::Cleared));
+        assert_eq!(detector.observe(&Baseline::default(), 2, 2400.0, 5000), None);
+    }
+}
@@ -1,22 +1,24 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs;
-use std::process::Command;
-use std::thread;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use reqwest;
 use anyhow::{Result, Context};
-use serde_json::json;
-
-#[derive(Serialize, Deserialize, Debug)]
-struct PowerStatus {
-    solar_panels: String,
-    batteries: String,
-    battery_status: String,
-    battery_power: String,
-    grid_status: String,
-    grid_power: String,
-    home_consumption: String,
-}
+use serde_json::{json, Value};
+use solax_mon::config::{self as secrets, normalize_inverter_url, parse_entries, SECRETS_PATH};
+use solax_mon::evc::EvCharger;
+use solax_mon::external::RuntimeLoad;
+use solax_mon::logfile::rotate_log;
+use solax_mon::notify::{format_runtime, send_discord_alert, send_gotify_alert, send_matrix_alert, send_pushover_alert, send_slack_alert, Admission, Alert, GotifyTarget, Governor, MatrixTarget, PushoverTarget, Routes, Severity, Templates};
+use solax_mon::mqtt::secret_matches;
+use solax_mon::outbound::{Clients, OutboundConfig};
+use solax_mon::rule::{lookup_reading, Expr, DEFAULT_RULE};
+use solax_mon::status::{runtime_minutes, Readings, StatusOutput, READING_FIELDS};
+use solax_mon::unix_now;
+use solax_mon::warnings::Warnings;
 
 #[derive(Debug)]
 struct IdracConfig {
@@ -29,267 +31,3567 @@ struct IdracServer {
     ip: String,
     username: String,
     password: String,
+    site: Option<String>,
+}
+
+/// A solax-mon instance whose /status the monitor polls.
+#[derive(Debug)]
+struct StatusSource {
+    name: String,
+    url: String,
+}
+
+#[derive(Debug)]
+struct Server {
+    target: String,
+    site: Option<String>,
+    action: ServerAction,
+    os: HostOs,
+    wake: WakeMethod,
+    check: DownCheck,
+    docker: Option<DockerStop>,
+    /// Identity file for this server instead of the monitor's key.
+    key: Option<String>,
+    user: Option<String>,
+    port: Option<u16>,
+    via: Option<JumpHost>,
+}
+
+impl Server {
+    /// The host part of `user@host`.
+    fn host(&self) -> &str {
+        self.target.rsplit('@').next().unwrap_or(&self.target)
+    }
+
+    /// The ssh destination, with `user=` taking precedence over the target's user.
+    fn destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.host()),
+            None => self.target.clone(),
+        }
+    }
+
+    /// Arguments for running `remote` on the server over ssh. A bastion is reached
+    /// with the monitor's key through ProxyCommand, the same as `-J` but without
+    /// handing the target's key to the bastion connection.
+    fn ssh_args(&self, ssh_key_path: &str, remote: &str) -> Vec<String> {
+        let mut args = vec![
+            "-i".to_string(), self.key.clone().unwrap_or_else(|| ssh_key_path.to_string()),
+            "-o".to_string(), "StrictHostKeyChecking=no".to_string(),
+        ];
+        if let Some(port) = self.port {
+            args.extend(["-p".to_string(), port.to_string()]);
+        }
+        if let Some(via) = &self.via {
+            args.extend(["-o".to_string(), format!(
+                "ProxyCommand=ssh -i {} -o StrictHostKeyChecking=no -p {} -W %h:%p {}",
+                ssh_key_path, via.port, via.destination()
+            )]);
+        }
+        args.push(self.destination());
+        args.push(remote.to_string());
+        args
+    }
+
+    /// Turns a failed ssh run into an error saying which hop couldn't be reached.
+    /// ssh exits with 255 for its own errors; anything else came from the remote command.
+    async fn ssh_failure(&self, what: &str, output: &std::process::Output) -> anyhow::Error {
+        let error = String::from_utf8_lossy(&output.stderr);
+        let error = error.trim();
+        if output.status.code() != Some(255) {
+            return match failure_hint(error, self.os) {
+                Some(hint) => anyhow::anyhow!("{} failed on {}: {}", what, self.target, hint),
+                None => anyhow::anyhow!("{} failed on {}: {}", what, self.target, error),
+            };
+        }
+        match &self.via {
+            Some(via) if !via.reachable().await => {
+                anyhow::anyhow!("{}: couldn't reach bastion {}: {}", what, via.destination(), error)
+            }
+            Some(via) => anyhow::anyhow!(
+                "{}: couldn't reach target {} via {}: {}", what, self.target, via.destination(), error
+            ),
+            None => anyhow::anyhow!("{}: couldn't reach target {}: {}", what, self.target, error),
+        }
+    }
+}
+
+/// A bastion given as `via=[user@]host[:port]`.
+#[derive(Debug, Clone, PartialEq)]
+struct JumpHost {
+    user: Option<String>,
+    host: String,
+    port: u16,
+}
+
+impl JumpHost {
+    fn parse(value: &str) -> Result<Self> {
+        let (user, rest) = match value.split_once('@') {
+            Some((user, rest)) => (Some(user.to_string()), rest),
+            None => (None, value),
+        };
+        // Bracketed IPv6 literals keep their colons
+        let (host, port) = match rest.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed.split_once(']')
+                    .with_context(|| format!("Unclosed bracket in via: {}", value))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match rest.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            },
+        };
+        if host.is_empty() {
+            anyhow::bail!("via needs a host: {}", value);
+        }
+        let port = match port {
+            Some(port) => port.parse().with_context(|| format!("Invalid port in via: {}", value))?,
+            None => 22,
+        };
+        Ok(JumpHost { user, host: host.to_string(), port })
+    }
+
+    fn destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    async fn reachable(&self) -> bool {
+        tcp_reachable(&self.host, self.port).await
+    }
+}
+
+async fn tcp_reachable(host: &str, port: u16) -> bool {
+    let Ok(addrs) = tokio::net::lookup_host((host, port)).await else {
+        return false;
+    };
+    for addr in addrs {
+        let connect = tokio::net::TcpStream::connect(addr);
+        if matches!(tokio::time::timeout(Duration::from_secs(3), connect).await, Ok(Ok(_))) {
+            return true;
+        }
+    }
+    false
+}
+
+/// What is run on a server when its site's rule triggers.
+#[derive(Debug, Clone, PartialEq)]
+enum ServerAction {
+    Poweroff,
+    Suspend,
+    Hibernate,
+    Command(String),
+}
+
+impl ServerAction {
+    fn parse(value: &str) -> Result<Self> {
+        Ok(match value {
+            "poweroff" => ServerAction::Poweroff,
+            "suspend" => ServerAction::Suspend,
+            "hibernate" => ServerAction::Hibernate,
+            _ => match value.strip_prefix("command:") {
+                Some(command) if !command.trim().is_empty() => ServerAction::Command(command.trim().to_string()),
+                _ => anyhow::bail!("Unknown server action {:?} (poweroff, suspend, hibernate, command:...)", value),
+            },
+        })
+    }
+
+    fn remote_command(&self, os: HostOs) -> &str {
+        match (os, self) {
+            (_, ServerAction::Command(command)) => command,
+            (HostOs::LinuxSudo | HostOs::Custom, ServerAction::Poweroff) => "sudo poweroff",
+            (HostOs::LinuxSudo | HostOs::Custom, ServerAction::Suspend) => "sudo systemctl suspend",
+            (HostOs::LinuxSudo | HostOs::Custom, ServerAction::Hibernate) => "sudo systemctl hibernate",
+            (HostOs::LinuxSystemctl, ServerAction::Poweroff) => "systemctl poweroff",
+            (HostOs::LinuxSystemctl, ServerAction::Suspend) => "systemctl suspend",
+            (HostOs::LinuxSystemctl, ServerAction::Hibernate) => "systemctl hibernate",
+            (HostOs::Windows, ServerAction::Poweroff) => "shutdown /s /t 0",
+            // Suspend is refused by parse_server, Windows has no command for it that can't hibernate
+            (HostOs::Windows, ServerAction::Suspend | ServerAction::Hibernate) => "shutdown /h",
+        }
+    }
+}
+
+/// What the server runs, which decides the commands used for its action. `custom` only
+/// allows `action=command:...`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HostOs {
+    /// Passwordless sudo for poweroff and systemctl.
+    LinuxSudo,
+    /// systemctl allowed for the user by polkit or logind, without sudo.
+    LinuxSystemctl,
+    /// OpenSSH on Windows, running the commands through cmd.
+    Windows,
+    Custom,
+}
+
+impl HostOs {
+    fn parse(value: &str) -> Result<Self> {
+        Ok(match value {
+            "linux-sudo" => HostOs::LinuxSudo,
+            "linux-systemctl" => HostOs::LinuxSystemctl,
+            "windows" => HostOs::Windows,
+            "custom" => HostOs::Custom,
+            _ => anyhow::bail!("Unknown server os {:?} (linux-sudo, linux-systemctl, windows, custom)", value),
+        })
+    }
+
+    /// How docker is run for stopping containers over ssh.
+    fn docker(self) -> &'static str {
+        match self {
+            HostOs::LinuxSudo => "sudo docker",
+            _ => "docker",
+        }
+    }
+}
+
+/// Says what to change when a remote command failed in a well-known way, instead of
+/// passing on its stderr.
+fn failure_hint(stderr: &str, os: HostOs) -> Option<String> {
+    let lower = stderr.to_lowercase();
+    if lower.contains("sudo: a password is required")
+        || lower.contains("sudo: a terminal is required")
+        || lower.contains("no tty present")
+        || lower.contains("[sudo] password for")
+    {
+        return Some("sudo asked for a password; allow the command with NOPASSWD in sudoers, \
+            or use os=linux-systemctl if polkit lets the user power off".to_string());
+    }
+    if lower.contains("interactive authentication required") {
+        return Some("systemctl needs authorization for this user; allow it with a polkit rule, \
+            or use os=linux-sudo with passwordless sudo".to_string());
+    }
+    if lower.contains("is not recognized as") {
+        return Some(match os {
+            HostOs::Windows => "the command isn't available on this Windows host; check action=command:".to_string(),
+            _ => "the host runs Windows; set os=windows".to_string(),
+        });
+    }
+    let missing = stderr.lines()
+        .find_map(|line| line.strip_suffix(": command not found").or_else(|| line.strip_suffix(": not found")))
+        .map(|line| line.rsplit(": ").next().unwrap_or(line).trim());
+    match missing {
+        Some("sudo") => Some("sudo isn't installed; use os=linux-systemctl".to_string()),
+        Some(command) => Some(format!(
+            "{} isn't installed or not on the PATH of the ssh session; set os= or use action=command:", command
+        )),
+        None if os == HostOs::Windows && lower.contains("access is denied") => Some(
+            "the account may not shut Windows down; give it the \"Shut down the system\" right".to_string()
+        ),
+        None => None,
+    }
+}
+
+/// How a server is brought back once conditions normalize.
+#[derive(Debug, Clone, PartialEq)]
+enum WakeMethod {
+    None,
+    WakeOnLan([u8; 6]),
+}
+
+impl WakeMethod {
+    fn parse(value: &str) -> Result<Self> {
+        if value == "none" {
+            return Ok(WakeMethod::None);
+        }
+        let Some(mac) = value.strip_prefix("wol:") else {
+            anyhow::bail!("Unknown wake method {:?} (none, wol:<mac>)", value);
+        };
+        let bytes: Vec<u8> = mac.split([':', '-'])
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("Invalid MAC address: {}", mac))?;
+        match <[u8; 6]>::try_from(bytes) {
+            Ok(mac) => Ok(WakeMethod::WakeOnLan(mac)),
+            Err(_) => anyhow::bail!("Invalid MAC address: {}", mac),
+        }
+    }
+}
+
+/// How to tell that a server actually went down after its action. Some NICs keep
+/// answering pings (or ARP) while suspended, so the SSH port is the default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DownCheck {
+    SshPort,
+    Ping,
+    None,
+}
+
+impl DownCheck {
+    fn parse(value: &str) -> Result<Self> {
+        Ok(match value {
+            "ssh" => DownCheck::SshPort,
+            "ping" => DownCheck::Ping,
+            "none" => DownCheck::None,
+            _ => anyhow::bail!("Unknown down check {:?} (ssh, ping, none)", value),
+        })
+    }
+}
+
+/// Containers stopped on a server before its action runs.
+#[derive(Debug, Clone, PartialEq)]
+enum Containers {
+    All,
+    Named(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct DockerStop {
+    containers: Containers,
+    /// Passed to `docker stop -t`; the action runs at the latest shortly after it.
+    grace: Duration,
+    /// Docker Engine API base URL; without it `docker stop` is run over SSH.
+    api: Option<String>,
+}
+
+/// Extra time on top of the grace period before giving up on the containers.
+const DOCKER_STOP_MARGIN: Duration = Duration::from_secs(15);
+
+/// How long a server may take to go down before an alert is sent.
+const DOWN_CHECK_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// How long a server is checked for after a recovery sequence before it is left to the
+/// reachability probe (SERVER_PROBE_INTERVAL_SECS) or a manual mark-up.
+const POWERON_CHECK_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Points in the shutdown and recovery sequences where hooks run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HookPoint {
+    PreShutdown,
+    PostShutdown,
+    PrePoweron,
+    PostPoweron,
+}
+
+impl HookPoint {
+    fn parse(value: &str) -> Result<Self> {
+        Ok(match value {
+            "pre_shutdown" => HookPoint::PreShutdown,
+            "post_shutdown" => HookPoint::PostShutdown,
+            "pre_poweron" => HookPoint::PrePoweron,
+            "post_poweron" => HookPoint::PostPoweron,
+            _ => anyhow::bail!(
+                "Unknown hook point {:?} (pre_shutdown, post_shutdown, pre_poweron, post_poweron)", value
+            ),
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HookPoint::PreShutdown => "pre_shutdown",
+            HookPoint::PostShutdown => "post_shutdown",
+            HookPoint::PrePoweron => "pre_poweron",
+            HookPoint::PostPoweron => "post_poweron",
+        }
+    }
+}
+
+/// A local command run by the monitor at one point of a site's sequence.
+#[derive(Debug)]
+struct Hook {
+    point: HookPoint,
+    command: String,
+    timeout: Duration,
+    required: bool,
+    site: Option<String>,
+}
+
+/// The shutdown condition for one site.
+#[derive(Debug)]
+struct Rule {
+    site: String,
+    text: String,
+    expr: Expr,
 }
 
 #[derive(Debug)]
 struct Config {
-    servers: Vec<String>,
+    servers: Vec<Server>,
     ssh_key_path: String,
     discord_webhook_url: String,
+    /// Send plain `content` instead of embeds, for bridges that don't render them.
+    discord_plain: bool,
+    /// A Slack incoming webhook alerts are sent to as well.
+    slack_webhook_url: Option<String>,
+    /// A Matrix room alerts are posted to as well.
+    matrix: Option<MatrixTarget>,
+    gotify: Option<GotifyTarget>,
+    pushover: Option<PushoverTarget>,
+    /// NOTIFY_ROUTE: which alerts each notifier gets.
+    routes: Routes,
+    /// How often the status message is edited; None disables it.
+    discord_status_interval: Option<Duration>,
+    sources: Vec<StatusSource>,
+    rules: Vec<Rule>,
     idrac: IdracConfig,
+    hooks: Vec<Hook>,
+    audit: Arc<AuditLog>,
+    governor: Mutex<Governor>,
+    quiet_hours: Option<QuietHours>,
+    /// NOTIFY_TEMPLATES_DIR templates replacing the built-in wording.
+    templates: Templates,
+    /// Outbound HTTP clients with TLS_CA_BUNDLE and TLS_ACCEPT_INVALID_CERTS applied.
+    http: Clients,
+    state: Mutex<MonitorState>,
+    /// Address of the control endpoint serving recent audit entries.
+    control_listen: Option<SocketAddr>,
+    /// Base URL of the control endpoint as reachable by whoever reads the alerts.
+    control_url: Option<String>,
+    /// Bearer token the POST routes of the control endpoint require.
+    control_token: Option<String>,
+    /// Warn (repeatedly, until acknowledged) while a site's battery is below this.
+    low_battery_warn_pct: Option<f64>,
+    low_battery_warn_repeat: Duration,
+    metrics: Mutex<MonitorMetrics>,
+    /// Pause EV charging as a first load-shedding step before shutting servers down.
+    evc_shed: Option<EvcShed>,
+    /// Usable battery capacity of each source, for the `runtime_min` estimate.
+    battery_capacity_kwh: Option<f64>,
+    /// RUNTIME_LOAD: whether `runtime_min` follows the battery power or the essential load.
+    runtime_load: RuntimeLoad,
+    /// How often every server's reachability is probed to keep its believed power state
+    /// current; None only checks around shutdowns and recoveries.
+    server_probe_interval: Option<Duration>,
+    /// How long the last readings of a source that can't be reached are still acted on.
+    status_trust: Duration,
+    /// The command powering off the monitor's own host after a shutdown sequence
+    /// (SHUTDOWN_SELF); None keeps it running.
+    shutdown_self: Option<String>,
+    /// The running shutdown and recovery sequences. Held across the edits of their messages, so
+    /// the flush when the monitor stops can't post a message twice.
+    sequences: tokio::sync::Mutex<Sequences>,
 }
 
-async fn send_discord_alert(webhook_url: &str, message: &str) -> Result<()> {
-    let client = reqwest::Client::new();
-    let payload = json!({
-        "content": message
-    });
+/// The EV charger paused before a site's shutdown sequence (EVC_PAUSE_BEFORE_SHUTDOWN).
+#[derive(Debug, Clone)]
+struct EvcShed {
+    url: String,
+    password: String,
+    site: Option<String>,
+}
 
-    let response = client.post(webhook_url)
-        .json(&payload)
-        .send()
+/// Pauses or resumes charging on the EV charger.
+async fn set_evc_charging(shed: &EvcShed, charging: bool) -> Result<()> {
+    EvCharger::new(&shed.url)
+        .set_charging(&shed.password, charging)
         .await
-        .context("Failed to send Discord webhook request")?;
-
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        anyhow::bail!(
-            "Discord webhook failed with status {}: {}", 
-            status,
-            error_text
-        );
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Append-only JSONL record of what the monitor saw and did. The newest entries
+/// are also kept in memory for the control endpoint.
+#[derive(Debug)]
+struct AuditLog {
+    path: Option<PathBuf>,
+    max_bytes: u64,
+    keep: usize,
+    recent: Mutex<VecDeque<Value>>,
+    /// The cached readings the monitor loop is acting on, added to its action entries.
+    cached: Mutex<Option<Value>>,
+}
+
+/// Entries kept in memory for `/audit`.
+const AUDIT_RECENT: usize = 500;
+
+impl AuditLog {
+    fn new(path: Option<PathBuf>, max_bytes: u64, keep: usize) -> Self {
+        Self { path, max_bytes, keep, recent: Mutex::new(VecDeque::new()), cached: Mutex::new(None) }
     }
 
-    Ok(())
+    /// Records an event; `fields` is merged into an object with the timestamp and event name.
+    fn record(&self, event: &str, fields: Value) {
+        let mut entry = json!({ "ts": unix_now(), "event": event });
+        if let (Some(entry), Value::Object(fields)) = (entry.as_object_mut(), fields) {
+            entry.extend(fields);
+        }
+
+        if let Some(path) = &self.path {
+            if let Err(e) = self.append(path, &entry) {
+                eprintln!("Failed to write audit log {}: {}", path.display(), e);
+            }
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == AUDIT_RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+
+    /// Records the outcome of an action against a target.
+    fn action<T>(&self, kind: &str, target: &str, result: &Result<T>) {
+        let mut outcome = match result {
+            Ok(_) => json!({ "kind": kind, "target": target, "ok": true }),
+            Err(e) => json!({ "kind": kind, "target": target, "ok": false, "error": format!("{:#}", e) }),
+        };
+        if let Some(cached) = self.cached.lock().unwrap().clone() {
+            outcome["cached"] = cached;
+        }
+        self.record("action", outcome);
+    }
+
+    /// Marks the actions from now on as decided on cached readings, until set to None.
+    fn acting_on_cached(&self, cached: Option<Value>) {
+        *self.cached.lock().unwrap() = cached;
+    }
+
+    fn append(&self, path: &Path, entry: &Value) -> Result<()> {
+        rotate_log(path, self.max_bytes, self.keep)?;
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", entry)?;
+        Ok(())
+    }
+
+    fn last(&self, n: usize) -> Vec<Value> {
+        let recent = self.recent.lock().unwrap();
+        recent.iter().skip(recent.len().saturating_sub(n)).cloned().collect()
+    }
 }
 
-async fn shutdown_server(server: &str, ssh_key_path: &str) -> Result<()> {
-    let output = Command::new("ssh")
-        .args([
-            "-i", ssh_key_path,
-            "-o", "StrictHostKeyChecking=no",
-            server,
-            "sudo poweroff"
-        ])
-        .output()
-        .context("Failed to execute SSH command")?;
+#[derive(Deserialize)]
+struct AuditQuery {
+    limit: Option<usize>,
+}
 
-    if !output.status.success() {
-        let error = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("Failed to shutdown server {}: {}", server, error);
+async fn get_audit(
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    axum::extract::Query(query): axum::extract::Query<AuditQuery>,
+) -> axum::Json<Vec<Value>> {
+    axum::Json(config.audit.last(query.limit.unwrap_or(50)))
+}
+
+/// Whether the request carries `Authorization: Bearer <CONTROL_TOKEN>`; without a token
+/// configured nothing is let through.
+fn authorized(config: &Config, headers: &axum::http::HeaderMap) -> bool {
+    let given = headers.get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (&config.control_token, given) {
+        (Some(token), Some(given)) => secret_matches(given, token),
+        _ => false,
     }
+}
 
-    Ok(())
+fn unauthorized() -> (axum::http::StatusCode, axum::Json<Value>) {
+    (axum::http::StatusCode::UNAUTHORIZED, axum::Json(json!({ "error": "missing or wrong bearer token" })))
 }
 
-async fn power_on_idrac(server: &IdracServer) -> Result<()> {
-    let output = Command::new("sshpass")
-        .args([
-            "-p", &server.password,
-            "ssh",
-            "-o", "StrictHostKeyChecking=no",
-            &format!("{}@{}", server.username, server.ip),
-            "racadm serveraction powerup"
-        ])
-        .output()
-        .context("Failed to execute iDRAC power-on command")?;
+async fn ack_alert(
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> (axum::http::StatusCode, axum::Json<Value>) {
+    if !authorized(&config, &headers) {
+        return unauthorized();
+    }
+    let mut state = config.state.lock().unwrap();
+    let Some(alert) = state.alerts.get_mut(&id) else {
+        return (axum::http::StatusCode::NOT_FOUND, axum::Json(json!({ "error": "no active alert with this id" })));
+    };
+    alert.acknowledged = true;
+    state.save();
+    drop(state);
 
-    if !output.status.success() {
-        let error = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("Failed to power on iDRAC server {}: {}", server.ip, error);
+    println!("Alert {} acknowledged", id);
+    config.audit.record("acknowledged", json!({ "alert": id }));
+    (axum::http::StatusCode::OK, axum::Json(json!({ "acknowledged": id })))
+}
+
+/// Updates what the monitor believes about a server's power, saving the state and keeping
+/// `solax_monitor_servers_down` in step.
+fn believe(config: &Config, target: &str, power: Power, via: &str) {
+    let mut state = config.state.lock().unwrap();
+    if !state.set_power(target, power, via, unix_now()) {
+        return;
     }
+    state.save();
+    drop(state);
 
-    Ok(())
+    let mut metrics = config.metrics.lock().unwrap();
+    if power == Power::Down {
+        metrics.servers_down.insert(target.to_string());
+    } else {
+        metrics.servers_down.remove(target);
+    }
+    drop(metrics);
+    println!("{} is now believed {:?} ({})", target, power, via);
+    config.audit.record("power_state", json!({ "target": target, "power": power, "via": via }));
 }
 
-fn parse_power_value(value: &str) -> f64 {
-    value.trim_end_matches('W')
-        .parse::<f64>()
-        .unwrap_or(0.0)
+async fn get_state(
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> axum::Json<Value> {
+    let state = config.state.lock().unwrap();
+    axum::Json(json!({ "servers": state.servers, "alerts": state.alerts }))
 }
 
-fn parse_battery_percentage(value: &str) -> f64 {
-    value.trim_end_matches('%')
-        .parse::<f64>()
-        .unwrap_or(0.0)
+/// Corrects the believed state of a server, given by target or host, that was started by hand.
+async fn mark_server_up(
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> (axum::http::StatusCode, axum::Json<Value>) {
+    if !authorized(&config, &headers) {
+        return unauthorized();
+    }
+    let Some(server) = config.servers.iter().find(|server| server.target == name || server.host() == name) else {
+        return (axum::http::StatusCode::NOT_FOUND, axum::Json(json!({ "error": "no server with this target or host" })));
+    };
+    believe(&config, &server.target, Power::Up, "manual");
+    (axum::http::StatusCode::OK, axum::Json(json!({ "marked_up": server.target })))
 }
 
-fn load_config() -> Result<Config> {
-    let config_content = fs::read_to_string("/srv/solax-mon/data/secrets.txt")
-        .context("Failed to read config file")?;
-    
-    let mut servers = Vec::new();
-    let mut discord_webhook_url = String::new();
-    let mut have_idrac = false;
-    let mut idrac_servers = Vec::new();
-    
-    for line in config_content.lines() {
-        let line = line.trim();
-        if line.starts_with("SERVER=") {
-            servers.push(line.trim_start_matches("SERVER=").to_string());
-        } else if line.starts_with("DISCORD_WEBHOOK=") {
-            discord_webhook_url = line.trim_start_matches("DISCORD_WEBHOOK=").to_string();
-        } else if line.starts_with("HAVE_IDRAC=") {
-            have_idrac = line.trim_start_matches("HAVE_IDRAC=").to_lowercase() == "true";
-        } else if line.starts_with("IDRAC_SERVER=") {
-            let parts: Vec<&str> = line.trim_start_matches("IDRAC_SERVER=").split(',').collect();
-            if parts.len() == 3 {
-                idrac_servers.push(IdracServer {
-                    ip: parts[0].to_string(),
-                    username: parts[1].to_string(),
-                    password: parts[2].to_string(),
-                });
+/// Battery level below which `solax_monitor_battery_low` reads 1 when no
+/// LOW_BATTERY_WARN_PCT is configured, matching the default rule.
+const BATTERY_LOW_PCT: f64 = 10.0;
+
+/// The monitor's own state as exported on the control endpoint's /metrics.
+#[derive(Debug, Default)]
+struct MonitorMetrics {
+    last_evaluation: Option<u64>,
+    sites: BTreeMap<String, SiteMetrics>,
+    /// Servers whose shutdown was confirmed and that haven't been recovered yet.
+    servers_down: std::collections::BTreeSet<String>,
+    last_shutdown: Option<u64>,
+    last_poweron: Option<u64>,
+    notifications_sent: u64,
+    notifications_failed: u64,
+    notifications_suppressed: u64,
+}
+
+/// The outcome of the last evaluation of one site; conditions are None without fresh data.
+#[derive(Debug, Default, Clone)]
+struct SiteMetrics {
+    rule: Option<bool>,
+    grid_down: Option<bool>,
+    solar_deficit: Option<bool>,
+    battery_low: Option<bool>,
+    shutdown_triggered: bool,
+}
+
+impl SiteMetrics {
+    fn evaluated(readings: Option<&Readings>, rule: Option<bool>, battery_low_pct: f64, shutdown_triggered: bool) -> Self {
+        Self {
+            rule,
+            grid_down: readings.map(|r| r.grid_w == 0.0),
+            solar_deficit: readings.map(|r| r.solar_w < r.load_w),
+            battery_low: readings.map(|r| r.battery_pct < battery_low_pct),
+            shutdown_triggered,
+        }
+    }
+}
+
+impl MonitorMetrics {
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut gauge = |name: &str, help: &str, kind: &str, samples: Vec<(String, f64)>| {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+            for (labels, value) in samples {
+                out.push_str(&format!("{}{} {}\n", name, labels, value));
             }
+        };
+        let timestamp = |ts: Option<u64>| ts.map(|ts| (String::new(), ts as f64)).into_iter().collect();
+        let per_site = |value: fn(&SiteMetrics) -> Option<bool>| -> Vec<(String, f64)> {
+            self.sites.iter()
+                .filter_map(|(site, metrics)| {
+                    value(metrics).map(|v| (format!("{{site=\"{}\"}}", site), f64::from(u8::from(v))))
+                })
+                .collect()
+        };
+
+        gauge("solax_monitor_last_evaluation_timestamp_seconds", "When the rules were last evaluated", "gauge",
+            timestamp(self.last_evaluation));
+        gauge("solax_monitor_rule_result", "Whether the site's shutdown rule is met", "gauge",
+            per_site(|m| m.rule));
+        gauge("solax_monitor_grid_down", "Whether the site's grid power is zero", "gauge",
+            per_site(|m| m.grid_down));
+        gauge("solax_monitor_solar_deficit", "Whether the site's solar output is below its load", "gauge",
+            per_site(|m| m.solar_deficit));
+        gauge("solax_monitor_battery_low", "Whether the site's battery is below the low battery level", "gauge",
+            per_site(|m| m.battery_low));
+        gauge("solax_monitor_shutdown_triggered", "Whether a shutdown sequence has run for the site", "gauge",
+            per_site(|m| Some(m.shutdown_triggered)));
+        gauge("solax_monitor_servers_down", "Servers believed to be down after a shutdown", "gauge",
+            vec![(String::new(), self.servers_down.len() as f64)]);
+        gauge("solax_monitor_last_shutdown_timestamp_seconds", "When a shutdown sequence last ran", "gauge",
+            timestamp(self.last_shutdown));
+        gauge("solax_monitor_last_poweron_timestamp_seconds", "When a recovery sequence last ran", "gauge",
+            timestamp(self.last_poweron));
+        gauge("solax_monitor_notifications_total", "Notifications by outcome", "counter", vec![
+            ("{outcome=\"sent\"}".to_string(), self.notifications_sent as f64),
+            ("{outcome=\"failed\"}".to_string(), self.notifications_failed as f64),
+            ("{outcome=\"suppressed\"}".to_string(), self.notifications_suppressed as f64),
+        ]);
+        out
+    }
+}
+
+async fn get_metrics(
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
+) -> ([(axum::http::header::HeaderName, &'static str); 1], String) {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        config.metrics.lock().unwrap().render(),
+    )
+}
+
+/// The pseudo-site combining every source.
+const TOTAL_SITE: &str = "total";
+
+impl Config {
+    /// Records a step of the sequence running at `site`, editing its message when due.
+    async fn sequence_step(&self, site: &str, key: &str, outcome: Outcome, text: String) {
+        self.sequences.lock().await.step(self, site, key, outcome, text).await;
+    }
+
+    /// Discord stays the default notifier; it is only left out when just others are configured.
+    fn discord_enabled(&self) -> bool {
+        !self.discord_webhook_url.is_empty()
+            || (self.slack_webhook_url.is_none() && self.matrix.is_none() && self.gotify.is_none() && self.pushover.is_none())
+    }
+
+    fn multi_source(&self) -> bool {
+        self.sources.len() > 1
+    }
+
+    /// Every site a rule is evaluated for: each source, plus the combined total
+    /// when there is more than one source.
+    fn sites(&self) -> Vec<String> {
+        let mut sites: Vec<String> = self.sources.iter().map(|s| s.name.clone()).collect();
+        if self.multi_source() {
+            sites.push(TOTAL_SITE.to_string());
         }
+        sites
     }
 
-    Ok(Config {
-        servers,
-        ssh_key_path: "/srv/solax-mon/data/ssh.key".to_string(),
-        discord_webhook_url,
-        idrac: IdracConfig {
-            enabled: have_idrac,
-            servers: idrac_servers,
-        },
-    })
+    /// Servers without a site follow the only source, or the combined total.
+    fn resolve_site(&self, site: &Option<String>) -> String {
+        match site {
+            Some(site) => site.clone(),
+            None if self.multi_source() => TOTAL_SITE.to_string(),
+            None => self.sources[0].name.clone(),
+        }
+    }
+
+    /// Every site has a rule once the config is loaded; missing ones get DEFAULT_RULE.
+    fn rule(&self, site: &str) -> &Rule {
+        self.rules.iter()
+            .find(|rule| rule.site == site)
+            .expect("every site has a rule")
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    println!("Starting power monitoring service...");
-    let config = load_config()?;
-    println!("Loaded configuration with {} servers", config.servers.len());
-    if config.idrac.enabled {
-        println!("iDRAC support enabled with {} servers", config.idrac.servers.len());
+/// Weight of the newest battery power reading in the smoothed value runtime is estimated from.
+const RUNTIME_SMOOTHING: f64 = 0.3;
+
+/// Exponentially smooths battery power, so one spike doesn't swing the runtime estimate.
+fn smooth(previous: Option<f64>, battery_w: f64) -> f64 {
+    previous.map_or(battery_w, |previous| previous + RUNTIME_SMOOTHING * (battery_w - previous))
+}
+
+/// The battery power `runtime_min` is estimated from: `battery_w`, or with RUNTIME_LOAD=essential
+/// what the essential load would take from the battery beyond the solar, as during an outage.
+fn runtime_battery_w(runtime_load: RuntimeLoad, readings: &Readings, battery_w: f64) -> f64 {
+    match runtime_load {
+        RuntimeLoad::Total => battery_w,
+        RuntimeLoad::Essential => readings.solar_w - readings.essential_load_w,
     }
-    
-    let client = reqwest::Client::new();
-    let mut shutdown_triggered = false;
-    let mut iteration = 1;
+}
 
-    loop {
-        println!("\n=== Monitoring Iteration {} ===", iteration);
-        
-        match client.get("http://localhost:3000/status")
-            .send()
-            .await {
-                Ok(response) => {
-                    if let Ok(status) = response.json::<PowerStatus>().await {
-                        // Print current status
-                        println!("Current Power Status:");
-                        println!("├─ Solar Output: {}", status.solar_panels);
-                        println!("├─ Battery Level: {}", status.batteries);
-                        println!("├─ Battery Status: {}", status.battery_status);
-                        println!("├─ Battery Power: {}", status.battery_power);
-                        println!("├─ Grid Status: {}", status.grid_status);
-                        println!("├─ Grid Power: {}", status.grid_power);
-                        println!("└─ Home Consumption: {}", status.home_consumption);
-
-                        let grid_power = parse_power_value(&status.grid_power);
-                        let solar_power = parse_power_value(&status.solar_panels);
-                        let home_power = parse_power_value(&status.home_consumption);
-                        let battery_percentage = parse_battery_percentage(&status.batteries);
-
-                        // Print threshold status
-                        println!("\nThreshold Check:");
-                        println!("├─ Grid Power == 0W: {}", grid_power == 0.0);
-                        println!("├─ Solar Power < Home Consumption ({} < {}): {}", 
-                            solar_power, home_power, solar_power < home_power);
-                        println!("└─ Battery < 10%: {}", battery_percentage < 10.0);
-
-                        let critical_condition = grid_power == 0.0 && 
-                                              solar_power < home_power && 
-                                              battery_percentage < 10.0;
-
-                        if critical_condition {
-                            println!("\n🚨 CRITICAL: All shutdown conditions met!");
-                            if !shutdown_triggered {
-                                println!("Initiating shutdown sequence...");
-                                
-                                // Send Discord alert
-                                let alert_message = format!(
-                                    "🚨 CRITICAL POWER ALERT!\n\
-                                    Grid: {}W (Offline)\n\
-                                    Solar: {}W\n\
-                                    Home Consumption: {}W\n\
-                                    Battery: {}%\n\
-                                    \n\
-                                    ⚠️ Initiating server shutdown sequence...",
-                                    grid_power, solar_power, home_power, battery_percentage
-                                );
-                                
-                                match send_discord_alert(&config.discord_webhook_url, &alert_message).await {
-                                    Ok(_) => println!("Successfully sent Discord alert"),
-                                    Err(e) => {
-                                        eprintln!("Failed to send Discord alert:");
-                                        eprintln!("Error details: {}", e);
-                                        let masked_url = if config.discord_webhook_url.len() > 20 {
-                                            format!("{}...{}", 
-                                                &config.discord_webhook_url[..10],
-                                                &config.discord_webhook_url[config.discord_webhook_url.len()-10..])
-                                        } else {
-                                            "Invalid URL".to_string()
-                                        };
-                                        eprintln!("Webhook URL (masked): {}", masked_url);
-                                    }
-                                }
+/// The sources `rule` for `site` reads that are stood in for by cached readings, with the
+/// age of those; the site's own readings count as read, they go into the alerts.
+fn cached_inputs(rule: &Rule, site: &str, cached: &BTreeMap<String, u64>) -> BTreeMap<String, u64> {
+    let scopes: Vec<&str> = rule.expr.variables().into_iter()
+        .map(|var| var.rsplit_once('.').map_or(site, |(scope, _)| scope))
+        .chain(std::iter::once(site))
+        .collect();
+    cached.iter()
+        .filter(|(source, _)| scopes.iter().any(|scope| scope == source || *scope == TOTAL_SITE))
+        .map(|(source, age)| (source.clone(), *age))
+        .collect()
+}
 
-                                // Shutdown servers
-                                for server in &config.servers {
-                                    match shutdown_server(server, &config.ssh_key_path).await {
-                                        Ok(_) => println!("Successfully initiated shutdown for {}", server),
-                                        Err(e) => eprintln!("Failed to shutdown {}: {}", server, e),
-                                    }
-                                }
-                                
-                                shutdown_triggered = true;
-                            } else {
-                                println!("Shutdown already triggered, waiting for conditions to normalize...");
-                            }
-                        } else {
-                            if shutdown_triggered {
-                                println!("\nConditions normalized, initiating recovery sequence");
-                                
-                                // Send normalization alert
-                                let normal_message = format!(
-                                    "✅ Power conditions normalized!\n\
-                                    Grid: {}W\n\
-                                    Solar: {}W\n\
-                                    Home Consumption: {}W\n\
-                                    Battery: {}%\n",
-                                    grid_power, solar_power, home_power, battery_percentage
-                                );
-
-                                match send_discord_alert(&config.discord_webhook_url, &normal_message).await {
-                                    Ok(_) => println!("Successfully sent normalization alert"),
-                                    Err(e) => eprintln!("Failed to send normalization alert: {}", e),
-                                }
+#[derive(Deserialize)]
+struct WebhookMessage {
+    id: String,
+}
 
-                                // Power on iDRAC servers if enabled
-                                if config.idrac.enabled {
-                                    println!("Initiating iDRAC power-on sequence...");
-                                    for server in &config.idrac.servers {
-                                        match power_on_idrac(server).await {
-                                            Ok(_) => println!("Successfully powered on iDRAC server {}", server.ip),
-                                            Err(e) => eprintln!("Failed to power on iDRAC server {}: {}", server.ip, e),
-                                        }
-                                    }
-                                }
+/// Monitor state kept across restarts.
+const STATE_PATH: &str = "/srv/solax-mon/data/monitor-state.json";
 
-                                shutdown_triggered = false;
-                            } else {
-                                println!("\nOperating within normal parameters");
-                            }
-                        }
-                    }
-                }
-                Err(e) => eprintln!("Failed to fetch power status: {}", e),
-            }
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MonitorState {
+    /// Where the state is saved; unset for state that isn't persisted.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Id of the continuously edited Discord status message.
+    #[serde(default)]
+    status_message_id: Option<String>,
+    /// Notifications held back during quiet hours, oldest first.
+    #[serde(default)]
+    held: Vec<HeldNotification>,
+    /// Held notifications dropped because the buffer was full.
+    #[serde(default)]
+    held_dropped: u32,
+    /// Conditions that raised an alert and haven't cleared yet, by alert id.
+    #[serde(default)]
+    alerts: BTreeMap<String, ActiveAlert>,
+    /// What the monitor believes about each server's power, by target.
+    #[serde(default)]
+    servers: BTreeMap<String, BelievedPower>,
+    /// Left when the monitor powered off its own host, until the next start has read it.
+    #[serde(default)]
+    self_shutdown: Option<SelfShutdown>,
+    /// The last good readings of each source, for bridging a restart of the main service.
+    #[serde(default)]
+    last_readings: BTreeMap<String, CachedReadings>,
+}
 
-        iteration += 1;
-        println!("\nWaiting 30 seconds before next check...");
-        thread::sleep(Duration::from_secs(30));
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct CachedReadings {
+    readings: Readings,
+    at: u64,
+}
+
+/// The monitor's own power-off (SHUTDOWN_SELF).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SelfShutdown {
+    at: u64,
+    /// The sites whose shutdown sequence it followed, still to be recovered.
+    sites: Vec<String>,
+}
+
+/// Whether a server is believed to be running.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Power {
+    Up,
+    Down,
+    /// Not known, e.g. after a recovery of a server without a down check.
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct BelievedPower {
+    power: Power,
+    since: u64,
+    /// What set it: down_check, poweron_check, probe, recovery or manual.
+    via: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct ActiveAlert {
+    acknowledged: bool,
+}
+
+impl MonitorState {
+    fn load(path: &Path) -> Self {
+        let state = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                eprintln!("Ignoring unreadable monitor state {}: {}", path.display(), e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        };
+        Self { path: Some(path.to_path_buf()), ..state }
+    }
+
+    /// Servers without an entry are assumed to be up.
+    fn believed_down(&self, target: &str) -> bool {
+        self.servers.get(target).is_some_and(|believed| believed.power == Power::Down)
+    }
+
+    /// Records what is now believed about a server; returns false when nothing changed.
+    fn set_power(&mut self, target: &str, power: Power, via: &str, now: u64) -> bool {
+        if self.servers.get(target).is_some_and(|believed| believed.power == power) {
+            return false;
+        }
+        self.servers.insert(target.to_string(), BelievedPower { power, since: now, via: via.to_string() });
+        true
+    }
+
+    /// The last readings of `source` and their age, while younger than `trust`.
+    fn trusted_readings(&self, source: &str, now: u64, trust: Duration) -> Option<(Readings, u64)> {
+        let cached = self.last_readings.get(source)?;
+        let age = now.saturating_sub(cached.at);
+        (age <= trust.as_secs()).then_some((cached.readings, age))
+    }
+
+    /// Removes the self-shutdown breadcrumb, saving the state without it.
+    fn take_self_shutdown(&mut self) -> Option<SelfShutdown> {
+        let taken = self.self_shutdown.take();
+        if taken.is_some() {
+            self.save();
+        }
+        taken
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else { return };
+        let result = serde_json::to_string_pretty(self)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| fs::write(path, text).map_err(anyhow::Error::from));
+        if let Err(e) = result {
+            eprintln!("Failed to save monitor state {}: {}", path.display(), e);
+        }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct HeldNotification {
+    timestamp: u64,
+    text: String,
+}
+
+/// Where notification templates are read from unless NOTIFY_TEMPLATES_DIR says otherwise.
+const TEMPLATES_DIR: &str = "/srv/solax-mon/data/templates";
+
+/// Most notifications held for the quiet hours digest.
+const MAX_HELD: usize = 50;
+
+/// Holds non-critical notifications during a daily window in a given timezone.
+#[derive(Debug)]
+struct QuietHours {
+    start: chrono::NaiveTime,
+    end: chrono::NaiveTime,
+    timezone: chrono_tz::Tz,
+    /// Warning alerts are held as well as informational ones when this is Warning.
+    floor: Severity,
+}
+
+impl QuietHours {
+    /// Parses `HH:MM-HH:MM`; the window may wrap around midnight.
+    fn parse_range(value: &str) -> Result<(chrono::NaiveTime, chrono::NaiveTime)> {
+        let (start, end) = value.split_once('-').context("QUIET_HOURS must be HH:MM-HH:MM")?;
+        let parse = |time: &str| chrono::NaiveTime::parse_from_str(time.trim(), "%H:%M")
+            .with_context(|| format!("Invalid time in QUIET_HOURS: {}", time));
+        Ok((parse(start)?, parse(end)?))
+    }
+
+    fn contains(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        let time = now.with_timezone(&self.timezone).time();
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Critical and normalization alerts are never held.
+    fn holds(&self, severity: Severity) -> bool {
+        match severity {
+            Severity::Info => true,
+            Severity::Warning => self.floor == Severity::Warning,
+            Severity::Critical | Severity::Normal => false,
+        }
+    }
+}
+
+/// Adds a notification to the digest buffer, dropping the oldest when it is full.
+fn hold_notification(state: &mut MonitorState, alert: &Alert) {
+    if state.held.len() >= MAX_HELD {
+        state.held.remove(0);
+        state.held_dropped += 1;
+    }
+    state.held.push(HeldNotification { timestamp: alert.timestamp, text: alert.content() });
+}
+
+/// Empties the digest buffer into a single alert.
+fn take_digest(state: &mut MonitorState, timezone: chrono_tz::Tz) -> Option<Alert> {
+    if state.held.is_empty() {
+        return None;
+    }
+    let mut lines: Vec<String> = state.held.drain(..)
+        .map(|held| {
+            let time = chrono::DateTime::from_timestamp(held.timestamp as i64, 0)
+                .map(|t| t.with_timezone(&timezone).format("%H:%M").to_string())
+                .unwrap_or_default();
+            format!("**{}** {}", time, held.text.replace('\n', " · "))
+        })
+        .collect();
+    if state.held_dropped > 0 {
+        lines.push(format!("(and {} older notifications)", state.held_dropped));
+        state.held_dropped = 0;
+    }
+    Some(Alert::new(Severity::Info, "🌙 Quiet hours digest").event("digest").description(lines.join("\n")))
+}
+
+/// The single Discord message that is edited in place with the current readings.
+struct StatusMessage {
+    id: Option<String>,
+    last_update: Option<std::time::Instant>,
+}
+
+impl StatusMessage {
+    fn load(config: &Config) -> Self {
+        Self { id: config.state.lock().unwrap().status_message_id.clone(), last_update: None }
+    }
+
+    fn due(&self, interval: Duration) -> bool {
+        self.last_update.is_none_or(|last| last.elapsed() >= interval)
+    }
+
+    async fn update(&mut self, config: &Config, alert: &Alert) -> Result<()> {
+        self.last_update = Some(std::time::Instant::now());
+        let posted = post_or_edit(config, self.id.as_deref(), &config.templates.apply(alert), "status message").await?;
+        if let Some(id) = posted {
+            let mut state = config.state.lock().unwrap();
+            state.status_message_id = Some(id.clone());
+            state.save();
+            self.id = Some(id);
+        }
+        Ok(())
+    }
+}
+
+/// Edits the webhook message `id` to show `alert`, or posts a new one (with `?wait=true` to
+/// learn its id) when there is none yet or the old one was deleted. Returns the id of a newly
+/// posted message.
+async fn post_or_edit(config: &Config, id: Option<&str>, alert: &Alert, what: &str) -> Result<Option<String>> {
+    let client = config.http.for_url(&config.discord_webhook_url);
+    let payload = alert.payload(config.discord_plain);
+
+    if let Some(id) = id {
+        let response = client.patch(format!("{}/messages/{}", config.discord_webhook_url, id))
+            .json(&payload)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to edit Discord {}: {}", what, config.http.describe_error(&config.discord_webhook_url, &e)))?;
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            response.error_for_status().with_context(|| format!("Failed to edit Discord {}", what))?;
+            return Ok(None);
+        }
+        println!("Discord {} {} is gone, posting a new one", what, id);
+    }
+
+    let message = client.post(format!("{}?wait=true", config.discord_webhook_url))
+        .json(&payload)
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to post Discord {}: {}", what, config.http.describe_error(&config.discord_webhook_url, &e)))?
+        .error_for_status()
+        .with_context(|| format!("Failed to post Discord {}", what))?
+        .json::<WebhookMessage>()
+        .await
+        .with_context(|| format!("Discord didn't return the {} id", what))?;
+    Ok(Some(message.id))
+}
+
+/// How often the message of a running sequence is edited at most. Steps completing in between
+/// are shown by the next edit, so a shutdown of many servers stays within the webhook's rate limit.
+const SEQUENCE_EDIT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Running,
+    Done,
+    Failed,
+    Skipped,
+}
+
+impl Outcome {
+    fn icon(self) -> &'static str {
+        match self {
+            Self::Running => "⏳",
+            Self::Done => "✅",
+            Self::Failed => "❌",
+            Self::Skipped => "⏭️",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Step {
+    /// Identifies the step, so a running step is replaced by its result.
+    key: String,
+    outcome: Outcome,
+    text: String,
+}
+
+/// A shutdown or recovery sequence of a site, shown in a single Discord message that is edited
+/// as its steps complete rather than a message per step.
+#[derive(Debug)]
+struct Sequence {
+    /// `shutdown` or `recovery`.
+    kind: &'static str,
+    site: String,
+    steps: Vec<Step>,
+    /// How the sequence ended, once it has.
+    ended: Option<&'static str>,
+    message_id: Option<String>,
+    last_edit: Option<std::time::Instant>,
+    /// Whether the message is behind the steps.
+    stale: bool,
+}
+
+impl Sequence {
+    fn new(kind: &'static str, site: &str) -> Self {
+        Self {
+            kind,
+            site: site.to_string(),
+            steps: Vec::new(),
+            ended: None,
+            message_id: None,
+            last_edit: None,
+            stale: true,
+        }
+    }
+
+    fn step(&mut self, key: &str, outcome: Outcome, text: String) {
+        let step = Step { key: key.to_string(), outcome, text };
+        match self.steps.iter_mut().find(|existing| existing.key == key) {
+            Some(existing) => *existing = step,
+            None => self.steps.push(step),
+        }
+        self.stale = true;
+    }
+
+    fn end(&mut self, how: &'static str) {
+        self.ended = Some(how);
+        self.stale = true;
+    }
+
+    fn failures(&self) -> Vec<String> {
+        self.steps.iter()
+            .filter(|step| step.outcome == Outcome::Failed)
+            .map(|step| step.text.clone())
+            .collect()
+    }
+
+    /// Whether the message should be edited at `now`: it is behind, and the sequence ended or
+    /// the last edit is long enough ago.
+    fn due(&self, now: std::time::Instant) -> bool {
+        self.stale && (self.ended.is_some() || self.last_edit.is_none_or(|last| now.duration_since(last) >= SEQUENCE_EDIT_INTERVAL))
+    }
+
+    fn alert(&self) -> Alert {
+        let failed = self.failures().len();
+        let state = match (self.ended, failed) {
+            (None, _) => "running".to_string(),
+            (Some(how), 0) => how.to_string(),
+            (Some(how), failed) => format!("{} with {} failure(s)", how, failed),
+        };
+        let severity = if failed > 0 { Severity::Warning } else { Severity::Info };
+        let title = match self.kind {
+            "recovery" => "🔌 Recovery sequence",
+            _ => "🔌 Shutdown sequence",
+        };
+        let steps = self.steps.iter()
+            .map(|step| format!("{} {}", step.outcome.icon(), step.text))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut alert = Alert::new(severity, title)
+            .event("sequence")
+            .site(&self.site)
+            .var("sequence", self.kind)
+            .var("state", state.clone())
+            .var("steps", steps.clone())
+            .field("State", state);
+        if !steps.is_empty() {
+            alert = alert.description(steps);
+        }
+        alert
+    }
+
+    /// Edits the message if it's due, or whenever it is behind with `force`.
+    async fn flush(&mut self, config: &Config, force: bool) {
+        if !config.discord_enabled() || !self.stale || !(force || self.due(std::time::Instant::now())) {
+            return;
+        }
+        self.last_edit = Some(std::time::Instant::now());
+        let alert = config.templates.apply(&self.alert());
+        let result = post_or_edit(config, self.message_id.as_deref(), &alert, "sequence message").await;
+        config.audit.action("sequence_message", &self.site, &result);
+        match result {
+            Ok(posted) => {
+                self.message_id = posted.or(self.message_id.take());
+                self.stale = false;
+            }
+            // Still behind, so the next flush tries again
+            Err(e) => eprintln!("Failed to update the {} sequence message of {}: {:#}", self.kind, self.site, e),
+        }
+    }
+}
+
+/// The sequences whose servers are still awaited, by site.
+#[derive(Debug, Default)]
+struct Sequences {
+    running: BTreeMap<String, Sequence>,
+}
+
+impl Sequences {
+    /// Starts a `kind` sequence at `site` and posts its message, ending the one still running there.
+    async fn start(&mut self, config: &Config, kind: &'static str, site: &str) {
+        if let Some(mut previous) = self.running.remove(site) {
+            previous.end("superseded");
+            previous.flush(config, true).await;
+        }
+        let mut sequence = Sequence::new(kind, site);
+        sequence.flush(config, true).await;
+        self.running.insert(site.to_string(), sequence);
+    }
+
+    async fn step(&mut self, config: &Config, site: &str, key: &str, outcome: Outcome, text: String) {
+        if let Some(sequence) = self.running.get_mut(site) {
+            sequence.step(key, outcome, text);
+            sequence.flush(config, false).await;
+        }
+    }
+
+    fn is_running(&self, site: &str) -> bool {
+        self.running.contains_key(site)
+    }
+
+    fn failures(&self, site: &str) -> Vec<String> {
+        self.running.get(site).map(Sequence::failures).unwrap_or_default()
+    }
+
+    /// Brings the message of the sequence at `site` up to date, throttling aside.
+    async fn flush(&mut self, config: &Config, site: &str) {
+        if let Some(sequence) = self.running.get_mut(site) {
+            sequence.flush(config, true).await;
+        }
+    }
+
+    /// Ends the `kind` sequences none of whose servers is in `awaited` any more.
+    async fn settle(&mut self, config: &Config, kind: &str, awaited: &[String]) {
+        let settled: Vec<String> = self.running.iter()
+            .filter(|(site, sequence)| sequence.kind == kind && !awaited.contains(site))
+            .map(|(site, _)| site.clone())
+            .collect();
+        for site in settled {
+            if let Some(mut sequence) = self.running.remove(&site) {
+                sequence.end("done");
+                sequence.flush(config, true).await;
+            }
+        }
+    }
+
+    /// Ends every running sequence when the monitor stops, so their messages keep every step.
+    async fn stop(&mut self, config: &Config) {
+        for (_, mut sequence) in std::mem::take(&mut self.running) {
+            sequence.end("interrupted, the monitor stopped");
+            sequence.flush(config, true).await;
+        }
+    }
+}
+
+/// The sites with a server in `awaiting`.
+fn awaited_sites(config: &Config, awaiting: &HashMap<String, std::time::Instant>) -> Vec<String> {
+    config.servers.iter()
+        .filter(|server| awaiting.contains_key(&server.target))
+        .map(|server| config.resolve_site(&server.site))
+        .collect()
+}
+
+/// The content of the status message: one field per site with its latest readings.
+fn status_alert(config: &Config, fresh: &HashMap<String, Readings>) -> Alert {
+    let mut alert = Alert::new(Severity::Info, "☀️ Solar status").event("report");
+    for site in config.sites() {
+        let value = match fresh.get(&site) {
+            Some(r) => format!(
+                "Grid {}W • Solar {}W • Load {}W • Battery {}%{}",
+                r.grid_w, r.solar_w, r.load_w, r.battery_pct,
+                r.runtime_min.map_or(String::new(), |minutes| format!(" • Runtime {}", format_runtime(minutes))),
+            ),
+            None => "No fresh data".to_string(),
+        };
+        alert = alert.field(&site, value);
+    }
+    if !config.servers.is_empty() {
+        let state = config.state.lock().unwrap();
+        let power = config.servers.iter()
+            .map(|server| {
+                let power = state.servers.get(&server.target).map_or(Power::Up, |believed| believed.power);
+                format!("{}: {}", server.target, format!("{:?}", power).to_lowercase())
+            })
+            .collect::<Vec<_>>();
+        alert = alert.var("servers", power.join(", "));
+        let servers: Vec<String> = config.servers.iter()
+            .map(|server| match state.servers.get(&server.target) {
+                Some(BelievedPower { power: Power::Down, since, .. }) => {
+                    let since = chrono::DateTime::from_timestamp(*since as i64, 0)
+                        .map(|time| time.format(" since %H:%M UTC").to_string())
+                        .unwrap_or_default();
+                    format!("🔴 {}{}", server.target, since)
+                }
+                Some(BelievedPower { power: Power::Unknown, .. }) => format!("⚪ {}", server.target),
+                _ => format!("🟢 {}", server.target),
+            })
+            .collect();
+        alert = alert.field("Servers", servers.join("\n"));
+    }
+    alert
+}
+
+async fn shutdown_server(server: &Server, ssh_key_path: &str) -> Result<()> {
+    let output = tokio::process::Command::new("ssh")
+        .args(server.ssh_args(ssh_key_path, server.action.remote_command(server.os)))
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to execute SSH command")?;
+
+    // Suspending can drop the connection before ssh sees the exit status
+    let dropped = output.status.code() == Some(255)
+        && matches!(server.action, ServerAction::Suspend | ServerAction::Hibernate);
+    if !output.status.success() && !dropped {
+        return Err(server.ssh_failure("Shutdown", &output).await);
+    }
+
+    Ok(())
+}
+
+/// Powers off the monitor's own host after the shutdown of `sites`: leaves the breadcrumb for
+/// the next start in the saved state, sends a last notification, syncs and runs `command`.
+/// Returns only when the command failed.
+async fn shutdown_self(config: &Config, command: &str, sites: Vec<String>) {
+    {
+        let mut state = config.state.lock().unwrap();
+        state.self_shutdown = Some(SelfShutdown { at: unix_now(), sites: sites.clone() });
+        state.save();
+    }
+    let alert = Alert::new(Severity::Critical, "🔌 Shutting down the monitor host")
+        .description(format!("Every server of {} is down or timed out.", sites.join(", ")))
+        .field("Action", format!("Running {:?}", command));
+    notify(config, &alert, "self shutdown alert").await;
+
+    println!("Powering off this host with {:?}", command);
+    if let Err(e) = tokio::process::Command::new("sync").status().await {
+        eprintln!("Failed to sync: {}", e);
+    }
+    let result = tokio::process::Command::new("sh").args(["-c", command]).output().await
+        .context("Failed to run SHUTDOWN_SELF_COMMAND")
+        .and_then(|output| match output.status.success() {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!("{} ({})", output.status, String::from_utf8_lossy(&output.stderr).trim())),
+        });
+    config.audit.action("self_shutdown", "localhost", &result);
+    if let Err(e) = result {
+        eprintln!("Failed to power off this host: {:#}", e);
+        // Still running, so the sites recover as usual and there is nothing to tell the next start
+        config.state.lock().unwrap().take_self_shutdown();
+        let alert = Alert::new(Severity::Warning, "⚠️ Failed to shut down the monitor host")
+            .description(format!("{:#}", e));
+        notify(config, &alert, "self shutdown failure alert").await;
+    }
+}
+
+/// Stops the server's configured containers, waiting at most the grace period
+/// plus a small margin so a hanging container can't eat the remaining battery.
+async fn stop_containers(server: &Server, docker: &DockerStop, ssh_key_path: &str, http: &Clients) -> Result<()> {
+    let deadline = docker.grace + DOCKER_STOP_MARGIN;
+    let stop = async {
+        match &docker.api {
+            Some(api) => stop_containers_api(api, docker, http).await,
+            None => stop_containers_ssh(server, docker, ssh_key_path).await,
+        }
+    };
+    tokio::time::timeout(deadline, stop)
+        .await
+        .map_err(|_| anyhow::anyhow!("containers still running after {}s", deadline.as_secs()))?
+}
+
+async fn stop_containers_ssh(server: &Server, docker: &DockerStop, ssh_key_path: &str) -> Result<()> {
+    let grace = docker.grace.as_secs();
+    let remote = match &docker.containers {
+        Containers::All => format!("{0} ps -q | xargs -r {0} stop -t {1}", server.os.docker(), grace),
+        Containers::Named(names) => format!("{} stop -t {} {}", server.os.docker(), grace, names.join(" ")),
+    };
+    let output = tokio::process::Command::new("ssh")
+        .args(server.ssh_args(ssh_key_path, &remote))
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to execute SSH command")?;
+
+    if !output.status.success() {
+        return Err(server.ssh_failure("docker stop", &output).await);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct DockerContainer {
+    #[serde(rename = "Id")]
+    id: String,
+}
+
+async fn stop_containers_api(api: &str, docker: &DockerStop, http: &Clients) -> Result<()> {
+    let client = http.for_url(api);
+    let containers = match &docker.containers {
+        Containers::All => client.get(format!("{}/containers/json", api))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<DockerContainer>>()
+            .await?
+            .into_iter()
+            .map(|container| container.id)
+            .collect(),
+        Containers::Named(names) => names.clone(),
+    };
+
+    let mut stops = tokio::task::JoinSet::new();
+    for container in containers {
+        let request = client.post(format!("{}/containers/{}/stop?t={}", api, container, docker.grace.as_secs()));
+        stops.spawn(async move { (container, request.send().await) });
+    }
+
+    let mut failed = Vec::new();
+    while let Some(joined) = stops.join_next().await {
+        let (container, response) = joined?;
+        match response {
+            // 304 means the container had already stopped
+            Ok(response) if response.status().is_success() || response.status().as_u16() == 304 => {}
+            Ok(response) => failed.push(format!("{} ({})", container, response.status())),
+            Err(e) => failed.push(format!("{} ({})", container, e)),
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("failed to stop {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// Whether the server looks down according to its configured check.
+/// Servers behind a bastion are checked from the bastion's side of the network.
+async fn server_is_down(server: &Server, ssh_key_path: &str) -> bool {
+    async fn succeeds(command: &mut tokio::process::Command) -> bool {
+        command.kill_on_drop(true)
+            .output()
+            .await
+            .is_ok_and(|output| output.status.success())
+    }
+    let ping = format!("ping -c 1 -W 2 {}", server.host());
+
+    match (server.check, &server.via) {
+        (DownCheck::SshPort, None) => !tcp_reachable(server.host(), server.port.unwrap_or(22)).await,
+        (DownCheck::SshPort, Some(_)) => !succeeds(tokio::process::Command::new("ssh")
+            .args(["-o", "BatchMode=yes", "-o", "ConnectTimeout=5"])
+            .args(server.ssh_args(ssh_key_path, "true"))).await,
+        (DownCheck::Ping, None) => !succeeds(tokio::process::Command::new("sh").args(["-c", &ping])).await,
+        (DownCheck::Ping, Some(via)) => !succeeds(tokio::process::Command::new("ssh")
+            .args(["-i", ssh_key_path, "-o", "StrictHostKeyChecking=no", "-p", &via.port.to_string()])
+            .arg(via.destination())
+            .arg(&ping)).await,
+        (DownCheck::None, _) => true,
+    }
+}
+
+/// Runs a hook through `sh -c`, passing the site's readings as `SOLAX_*` variables.
+async fn run_hook(hook: &Hook, site: &str, readings: Option<&Readings>) -> Result<()> {
+    let mut command = tokio::process::Command::new("sh");
+    command.args(["-c", &hook.command])
+        .env("SOLAX_HOOK", hook.point.name())
+        .env("SOLAX_SITE", site)
+        .kill_on_drop(true);
+    if let Some(readings) = readings {
+        for field in READING_FIELDS {
+            let value = readings.field(field).unwrap_or_default();
+            command.env(format!("SOLAX_{}", field.to_uppercase()), value.to_string());
+        }
+    }
+
+    let output = tokio::time::timeout(hook.timeout, command.output())
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out after {}s", hook.timeout.as_secs()))?
+        .context("Failed to execute hook")?;
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("exited with {}: {}", output.status, error.trim());
+    }
+    Ok(())
+}
+
+/// Runs every hook for `point` on `site`, recording each as a step of the site's sequence.
+/// Returns false when a required hook failed and the rest of the sequence should be skipped.
+async fn run_hooks(config: &Config, point: HookPoint, site: &str, readings: Option<&Readings>) -> bool {
+    for hook in config.hooks.iter()
+        .filter(|hook| hook.point == point && config.resolve_site(&hook.site) == site)
+    {
+        println!("Running {} hook: {}", point.name(), hook.command);
+        let key = format!("{} {}", point.name(), hook.command);
+        config.sequence_step(site, &key, Outcome::Running, format!("{} hook `{}`", point.name(), hook.command)).await;
+        let result = run_hook(hook, site, readings).await;
+        config.audit.action(point.name(), &hook.command, &result);
+        match result {
+            Ok(()) => config.sequence_step(site, &key, Outcome::Done, format!("{} hook `{}`", point.name(), hook.command)).await,
+            Err(e) => {
+                eprintln!("{} hook {:?} failed: {}", point.name(), hook.command, e);
+                config.sequence_step(site, &key, Outcome::Failed, format!("{} hook `{}` failed: {}", point.name(), hook.command, e)).await;
+                if hook.required {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Forgets an alert condition once it has cleared, so it notifies again if it recurs.
+fn clear_alert(config: &Config, id: &str) {
+    let mut state = config.state.lock().unwrap();
+    if state.alerts.remove(id).is_some() {
+        state.save();
+    }
+}
+
+/// Sends the failures of the shutdown or recovery sequence running at `site`, if there were any,
+/// to the notifiers besides Discord, where the sequence message lists them already.
+async fn report_failures(config: &Config, site: &str, sequence: &str) {
+    let failures = config.sequences.lock().await.failures(site);
+    if failures.is_empty() {
+        return;
+    }
+    let description = failures.iter()
+        .map(|failure| format!("• {}", failure))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let alert = Alert::new(Severity::Warning, format!("⚠️ Failures during {}", sequence))
+        .site(site)
+        .description(description);
+    deliver(config, &alert, "failure summary", false).await;
+}
+
+fn send_wake_on_lan(mac: &[u8; 6]) -> Result<()> {
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(mac);
+    }
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").context("Failed to open WoL socket")?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&packet, "255.255.255.255:9").context("Failed to send WoL packet")?;
+    Ok(())
+}
+
+async fn power_on_idrac(server: &IdracServer) -> Result<()> {
+    let output = tokio::process::Command::new("sshpass")
+        .args([
+            "-p", &server.password,
+            "ssh",
+            "-o", "StrictHostKeyChecking=no",
+            &format!("{}@{}", server.username, server.ip),
+            "racadm serveraction powerup"
+        ])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to execute iDRAC power-on command")?;
+
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("Failed to power on iDRAC server {}: {}", server.ip, error);
+    }
+
+    Ok(())
+}
+
+/// Accepts either a full URL or a bare `host:port` (IPv6 literals bracketed,
+/// e.g. `[::1]:3000`) and returns the URL of the status endpoint.
+fn parse_status_url(value: &str) -> Result<String> {
+    let value = value.trim();
+    let with_scheme = if value.contains("://") {
+        value.to_string()
+    } else {
+        format!("http://{}", value)
+    };
+
+    let mut url = reqwest::Url::parse(&with_scheme)
+        .with_context(|| format!("Invalid STATUS_URL: {}", value))?;
+    if url.host().is_none() {
+        anyhow::bail!("STATUS_URL has no host: {}", value);
+    }
+    if url.path() == "/" {
+        url.set_path("/status");
+    }
+
+    Ok(url.to_string())
+}
+
+/// Parses `user@host[,site=name][,action=...][,wake=...][,check=...][,docker=...][,key=...][,via=...]`.
+fn parse_server(value: &str) -> Result<Server> {
+    let mut parts = value.split(',').map(str::trim);
+    let target = parts.next().unwrap_or_default().to_string();
+    if target.is_empty() {
+        anyhow::bail!("SERVER needs a target: {}", value);
+    }
+
+    let mut server = Server {
+        target,
+        site: None,
+        action: ServerAction::Poweroff,
+        os: HostOs::LinuxSudo,
+        wake: WakeMethod::None,
+        check: DownCheck::SshPort,
+        docker: None,
+        key: None,
+        user: None,
+        port: None,
+        via: None,
+    };
+    let mut docker_grace = Duration::from_secs(120);
+    let mut docker_api = None;
+    for option in parts {
+        match option.split_once('=') {
+            Some(("site", site)) => server.site = Some(site.trim().to_string()),
+            Some(("action", action)) => server.action = ServerAction::parse(action.trim())?,
+            Some(("os", os)) => server.os = HostOs::parse(os.trim())?,
+            Some(("wake", wake)) => server.wake = WakeMethod::parse(wake.trim())?,
+            Some(("check", check)) => server.check = DownCheck::parse(check.trim())?,
+            Some(("docker", containers)) => {
+                let containers = match containers.trim() {
+                    "all" => Containers::All,
+                    names => Containers::Named(names.split('+').map(|name| name.trim().to_string()).collect()),
+                };
+                server.docker = Some(DockerStop { containers, grace: docker_grace, api: None });
+            }
+            Some(("docker_grace", secs)) => docker_grace = Duration::from_secs(secs.trim().parse()
+                .with_context(|| format!("Invalid docker_grace: {}", secs))?),
+            Some(("key", key)) => server.key = Some(key.trim().to_string()),
+            Some(("user", user)) => server.user = Some(user.trim().to_string()),
+            Some(("port", port)) => server.port = Some(port.trim().parse()
+                .with_context(|| format!("Invalid port: {}", port))?),
+            Some(("via", via)) => server.via = Some(JumpHost::parse(via.trim())?),
+            Some(("docker_api", url)) => docker_api = Some(url.trim().trim_end_matches('/').to_string()),
+            _ => anyhow::bail!("Unknown SERVER option {:?} in {}", option, value),
+        }
+    }
+    match &mut server.docker {
+        Some(docker) => {
+            docker.grace = docker_grace;
+            docker.api = docker_api;
+        }
+        None if docker_api.is_some() => anyhow::bail!("docker_api needs docker= in {}", value),
+        None => {}
+    }
+    match (server.os, &server.action) {
+        (HostOs::Custom, ServerAction::Command(_)) => {}
+        (HostOs::Custom, _) => anyhow::bail!("os=custom needs action=command:... in {}", value),
+        (HostOs::Windows, ServerAction::Suspend) => {
+            anyhow::bail!("Windows can't be suspended reliably, use action=hibernate or command:... in {}", value)
+        }
+        _ => {}
+    }
+    Ok(server)
+}
+
+/// Parses `point[,timeout=secs][,required=true][,site=name]: command`.
+fn parse_hook(value: &str) -> Result<Hook> {
+    let (spec, command) = value.split_once(':')
+        .context("HOOK must be point[,options]: command")?;
+    let command = command.trim();
+    if command.is_empty() {
+        anyhow::bail!("HOOK needs a command: {}", value);
+    }
+
+    let mut parts = spec.split(',').map(str::trim);
+    let mut hook = Hook {
+        point: HookPoint::parse(parts.next().unwrap_or_default())?,
+        command: command.to_string(),
+        timeout: Duration::from_secs(60),
+        required: false,
+        site: None,
+    };
+    for option in parts {
+        match option.split_once('=') {
+            Some(("timeout", secs)) => hook.timeout = Duration::from_secs(secs.trim().parse()
+                .with_context(|| format!("Invalid hook timeout: {}", secs))?),
+            Some(("required", required)) => hook.required = required.trim().eq_ignore_ascii_case("true"),
+            Some(("site", site)) => hook.site = Some(site.trim().to_string()),
+            _ => anyhow::bail!("Unknown HOOK option {:?} in {}", option, value),
+        }
+    }
+    Ok(hook)
+}
+
+fn validate_config(config: &Config) -> Result<()> {
+    let sites = config.sites();
+    let scopes: Vec<&str> = config.sources.iter()
+        .map(|source| source.name.as_str())
+        .chain(std::iter::once(TOTAL_SITE))
+        .collect();
+
+    for (i, server) in config.servers.iter().enumerate() {
+        let site = config.resolve_site(&server.site);
+        if !sites.contains(&site) {
+            anyhow::bail!("Server {} references unknown site {}", server.target, site);
+        }
+        if config.servers[..i].iter().any(|other| other.host() == server.host()) {
+            anyhow::bail!("SERVER {} is listed twice", server.host());
+        }
+        let key = server.key.as_deref().unwrap_or(&config.ssh_key_path);
+        if !Path::new(key).exists() {
+            anyhow::bail!("SERVER {} needs the ssh key {}, which doesn't exist", server.target, key);
+        }
+    }
+    for (i, source) in config.sources.iter().enumerate() {
+        if config.sources[..i].iter().any(|other| other.name == source.name) {
+            anyhow::bail!("SOURCE {} is listed twice", source.name);
+        }
+    }
+    for server in &config.idrac.servers {
+        let site = config.resolve_site(&server.site);
+        if !sites.contains(&site) {
+            anyhow::bail!("iDRAC server {} references unknown site {}", server.ip, site);
+        }
+    }
+    if let Some(shed) = &config.evc_shed {
+        let site = config.resolve_site(&shed.site);
+        if !sites.contains(&site) {
+            anyhow::bail!("EVC_SITE references unknown site {}", site);
+        }
+    }
+
+    for hook in &config.hooks {
+        let site = config.resolve_site(&hook.site);
+        if !sites.contains(&site) {
+            anyhow::bail!("{} hook references unknown site {}", hook.point.name(), site);
+        }
+    }
+
+    for rule in &config.rules {
+        if !sites.contains(&rule.site) {
+            anyhow::bail!("RULE for unknown site {}", rule.site);
+        }
+        for var in rule.expr.variables() {
+            let (scope, field) = var.rsplit_once('.').unwrap_or((&rule.site, var));
+            if !scopes.contains(&scope) || !READING_FIELDS.contains(&field) {
+                anyhow::bail!(
+                    "RULE for {} references unknown value {} (fields: {}, sources: {})",
+                    rule.site, var, READING_FIELDS.join(", "), scopes.join(", ")
+                );
+            }
+            if field == "runtime_min" && config.battery_capacity_kwh.is_none() {
+                anyhow::bail!("RULE for {} uses runtime_min, which needs BATTERY_CAPACITY_KWH", rule.site);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the configuration, refusing it when `validate` would find anything wrong with it.
+fn load_config() -> Result<Config> {
+    let text = std::fs::read_to_string(SECRETS_PATH)
+        .with_context(|| format!("Failed to read {}", SECRETS_PATH))?;
+    let problems = secrets::check(&text);
+    if !problems.is_empty() {
+        let problems: Vec<String> = problems.iter().map(|problem| problem.to_string()).collect();
+        anyhow::bail!("Invalid configuration in {}:\n{}", SECRETS_PATH, problems.join("\n"));
+    }
+    parse_config(&text)
+}
+
+fn parse_config(text: &str) -> Result<Config> {
+    let mut servers = Vec::new();
+    let mut discord_webhook_url = String::new();
+    let mut discord_plain = false;
+    let mut slack_webhook_url = None;
+    let (mut matrix_homeserver, mut matrix_access_token, mut matrix_room_id) = (None, None, None);
+    let (mut gotify_url, mut gotify_token) = (None, None);
+    let (mut pushover_user_key, mut pushover_token) = (None, None);
+    let mut pushover_retry = Duration::from_secs(60);
+    let mut pushover_expire = Duration::from_secs(3600);
+    let mut routes = Routes::default();
+    let mut discord_status = false;
+    let mut discord_status_interval = Duration::from_secs(300);
+    let mut status_url = "http://localhost:3000/status".to_string();
+    let mut sources = Vec::new();
+    let mut rules = Vec::new();
+    let mut have_idrac = false;
+    let mut idrac_servers = Vec::new();
+    let mut hooks = Vec::new();
+    let mut audit_path = None;
+    let mut audit_max_bytes = 10 * 1024 * 1024;
+    let mut audit_keep = 5;
+    let mut control_listen = None;
+    let mut control_token = None;
+    let mut control_url = None;
+    let mut low_battery_warn_pct = None;
+    let mut low_battery_warn_repeat = Duration::from_secs(1800);
+    let mut notify_dedup = Duration::from_secs(600);
+    let mut notify_max_per_hour = 20;
+    let mut quiet_range = None;
+    let mut templates_dir = PathBuf::from(TEMPLATES_DIR);
+    let mut outbound = OutboundConfig::default();
+    let mut quiet_timezone = None;
+    let mut timezone = chrono_tz::UTC;
+    let mut quiet_floor = Severity::Warning;
+    let mut evc_url = None;
+    let mut evc_password = None;
+    let mut evc_pause = false;
+    let mut evc_site = None;
+    let mut battery_capacity_kwh = None;
+    let mut runtime_load = RuntimeLoad::Total;
+    let mut server_probe_interval = None;
+    let mut status_trust = Duration::from_secs(300);
+    let mut shutdown_self = false;
+    let mut shutdown_self_command = "poweroff".to_string();
+    
+    for (key, value) in parse_entries(text) {
+        let value = value.as_str();
+        match key.as_str() {
+            "SERVER" => {
+                servers.push(parse_server(value)?);
+            }
+            "DISCORD_WEBHOOK" => {
+                discord_webhook_url = value.to_string();
+            }
+            "DISCORD_PLAIN" => {
+                discord_plain = value.to_lowercase() == "true";
+            }
+            "SLACK_WEBHOOK" => {
+                slack_webhook_url = Some(value.to_string()).filter(|url| !url.is_empty());
+            }
+            "MATRIX_HOMESERVER" => matrix_homeserver = Some(value.trim().to_string()),
+            "MATRIX_ACCESS_TOKEN" => matrix_access_token = Some(value.trim().to_string()),
+            "MATRIX_ROOM_ID" => matrix_room_id = Some(value.trim().to_string()),
+            "GOTIFY_URL" => gotify_url = Some(value.trim().to_string()),
+            "GOTIFY_TOKEN" => gotify_token = Some(value.trim().to_string()),
+            "PUSHOVER_USER_KEY" => pushover_user_key = Some(value.trim().to_string()),
+            "PUSHOVER_TOKEN" => pushover_token = Some(value.trim().to_string()),
+            "PUSHOVER_RETRY_SECS" => {
+                pushover_retry = Duration::from_secs(value.parse().context("Invalid PUSHOVER_RETRY_SECS")?);
+            }
+            "NOTIFY_ROUTE" => routes.add(value).map_err(anyhow::Error::msg)?,
+            "PUSHOVER_EXPIRE_SECS" => {
+                pushover_expire = Duration::from_secs(value.parse().context("Invalid PUSHOVER_EXPIRE_SECS")?);
+            }
+            "DISCORD_STATUS_MESSAGE" => {
+                discord_status = value.to_lowercase() == "true";
+            }
+            "DISCORD_STATUS_INTERVAL_SECS" => {
+                discord_status_interval = Duration::from_secs(value
+                    .parse()
+                    .context("Invalid DISCORD_STATUS_INTERVAL_SECS")?);
+            }
+            "STATUS_URL" => {
+                status_url = parse_status_url(value)?;
+            }
+            "SOURCE" => {
+                let (name, url) = value.split_once(',')
+                    .context("SOURCE must be name,url")?;
+                sources.push(StatusSource {
+                    name: name.trim().to_string(),
+                    url: parse_status_url(url)?,
+                });
+            }
+            "RULE" => {
+                let (site, text) = value.split_once(':')
+                    .context("RULE must be site: expression")?;
+                rules.push(Rule {
+                    site: site.trim().to_string(),
+                    text: text.trim().to_string(),
+                    expr: Expr::parse(text).map_err(anyhow::Error::msg)?,
+                });
+            }
+            "HOOK" => {
+                hooks.push(parse_hook(value)?);
+            }
+            "AUDIT_LOG" => {
+                audit_path = Some(PathBuf::from(value));
+            }
+            "AUDIT_LOG_MAX_BYTES" => {
+                audit_max_bytes = value.parse()
+                    .context("Invalid AUDIT_LOG_MAX_BYTES")?;
+            }
+            "AUDIT_LOG_KEEP" => {
+                audit_keep = value.parse()
+                    .context("Invalid AUDIT_LOG_KEEP")?;
+            }
+            "NOTIFY_DEDUP_SECS" => {
+                notify_dedup = Duration::from_secs(value.parse()
+                    .context("Invalid NOTIFY_DEDUP_SECS")?);
+            }
+            "NOTIFY_MAX_PER_HOUR" => {
+                notify_max_per_hour = value.parse()
+                    .context("Invalid NOTIFY_MAX_PER_HOUR")?;
+            }
+            "TLS_CA_BUNDLE" => {
+                outbound.ca_bundle = Some(PathBuf::from(value.trim()));
+            }
+            "TLS_ACCEPT_INVALID_CERTS" => {
+                outbound.accept_invalid_certs.push(OutboundConfig::parse_destination(value).map_err(anyhow::Error::msg)?);
+            }
+            "HTTP_PROXY" => {
+                outbound.http_proxy = Some(value.trim().to_string());
+            }
+            "HTTPS_PROXY" => {
+                outbound.https_proxy = Some(value.trim().to_string());
+            }
+            "NO_PROXY" => {
+                outbound.no_proxy = Some(value.trim().to_string());
+            }
+            "NOTIFY_TEMPLATES_DIR" => {
+                templates_dir = PathBuf::from(value.trim());
+            }
+            "QUIET_HOURS" => {
+                quiet_range = Some(QuietHours::parse_range(value)?);
+            }
+            "QUIET_HOURS_TZ" => {
+                let name = value.trim();
+                quiet_timezone = Some(name.parse()
+                    .map_err(|_| anyhow::anyhow!("Unknown QUIET_HOURS_TZ: {}", name))?);
+            }
+            "TIMEZONE" => {
+                let name = value.trim();
+                timezone = name.parse()
+                    .map_err(|_| anyhow::anyhow!("Unknown TIMEZONE: {}", name))?;
+            }
+            "QUIET_HOURS_FLOOR" => {
+                quiet_floor = match value.trim() {
+                    "info" => Severity::Info,
+                    "warning" => Severity::Warning,
+                    other => anyhow::bail!("Invalid QUIET_HOURS_FLOOR {:?} (info, warning)", other),
+                };
+            }
+            "CONTROL_URL" => {
+                control_url = Some(value.trim_end_matches('/').to_string());
+            }
+            "LOW_BATTERY_WARN_PCT" => {
+                low_battery_warn_pct = Some(value.parse()
+                    .context("Invalid LOW_BATTERY_WARN_PCT")?);
+            }
+            "LOW_BATTERY_WARN_REPEAT_SECS" => {
+                low_battery_warn_repeat = Duration::from_secs(value
+                    .parse()
+                    .context("Invalid LOW_BATTERY_WARN_REPEAT_SECS")?);
+            }
+            "STATUS_TRUST_SECS" => {
+                status_trust = Duration::from_secs(value.parse().context("Invalid STATUS_TRUST_SECS")?);
+            }
+            "SERVER_PROBE_INTERVAL_SECS" => {
+                server_probe_interval = Some(Duration::from_secs(value
+                    .parse()
+                    .context("Invalid SERVER_PROBE_INTERVAL_SECS")?));
+            }
+            "SHUTDOWN_SELF" => {
+                shutdown_self = value.to_lowercase() == "true";
+            }
+            "SHUTDOWN_SELF_COMMAND" => {
+                shutdown_self_command = value.to_string();
+            }
+            "CONTROL_LISTEN" => {
+                control_listen = Some(value.parse()
+                    .context("Invalid CONTROL_LISTEN (expected ip:port)")?);
+            }
+            "CONTROL_TOKEN" => {
+                control_token = Some(value.to_string()).filter(|token| !token.is_empty());
+            }
+            "EVC_URL" => {
+                evc_url = Some(normalize_inverter_url(value));
+            }
+            "EVC_PASSWORD" => {
+                evc_password = Some(value.to_string());
+            }
+            "EVC_PAUSE_BEFORE_SHUTDOWN" => {
+                evc_pause = value.to_lowercase() == "true";
+            }
+            "EVC_SITE" => {
+                evc_site = Some(value.to_string());
+            }
+            "BATTERY_CAPACITY_KWH" => {
+                battery_capacity_kwh = Some(value.parse::<f64>()
+                    .ok()
+                    .filter(|kwh| *kwh > 0.0)
+                    .with_context(|| format!("Invalid BATTERY_CAPACITY_KWH: {}", value))?);
+            }
+            "RUNTIME_LOAD" => {
+                runtime_load = RuntimeLoad::parse(value).with_context(|| format!("Invalid RUNTIME_LOAD: {}", value))?;
+            }
+            "HAVE_IDRAC" => {
+                have_idrac = value.to_lowercase() == "true";
+            }
+            "IDRAC_SERVER" => {
+                let parts: Vec<&str> = value.split(',').collect();
+                if parts.len() >= 3 {
+                    idrac_servers.push(IdracServer {
+                        ip: parts[0].to_string(),
+                        username: parts[1].to_string(),
+                        password: parts[2].to_string(),
+                        site: parts.get(3)
+                            .and_then(|option| option.trim().strip_prefix("site="))
+                            .map(str::to_string),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // A single STATUS_URL is the original setup, used when no SOURCE lines are given
+    if sources.is_empty() {
+        sources.push(StatusSource { name: "local".to_string(), url: status_url });
+    }
+
+    let evc_shed = match (evc_pause, evc_url, evc_password) {
+        (false, _, _) => None,
+        (true, Some(url), Some(password)) => Some(EvcShed { url, password, site: evc_site }),
+        (true, _, _) => anyhow::bail!("EVC_PAUSE_BEFORE_SHUTDOWN needs EVC_URL and EVC_PASSWORD"),
+    };
+
+    let matrix = match (matrix_homeserver, matrix_access_token, matrix_room_id) {
+        (None, None, None) => None,
+        (Some(homeserver), Some(access_token), Some(room_id)) => Some(MatrixTarget { homeserver, access_token, room_id }),
+        _ => anyhow::bail!("Matrix needs MATRIX_HOMESERVER, MATRIX_ACCESS_TOKEN and MATRIX_ROOM_ID"),
+    };
+    let gotify = match (gotify_url, gotify_token) {
+        (None, None) => None,
+        (Some(url), Some(token)) => Some(GotifyTarget { url, token }),
+        _ => anyhow::bail!("Gotify needs GOTIFY_URL and GOTIFY_TOKEN"),
+    };
+    let pushover = match (pushover_user_key, pushover_token) {
+        (None, None) => None,
+        (Some(user_key), Some(token)) => Some(PushoverTarget { user_key, token, retry: pushover_retry, expire: pushover_expire }),
+        _ => anyhow::bail!("Pushover needs PUSHOVER_USER_KEY and PUSHOVER_TOKEN"),
+    };
+    // Acknowledging alerts and marking servers up change what the monitor does in an outage
+    if control_listen.is_some() && control_token.is_none() {
+        anyhow::bail!("CONTROL_LISTEN requires CONTROL_TOKEN");
+    }
+
+    let mut config = Config {
+        servers,
+        ssh_key_path: "/srv/solax-mon/data/ssh.key".to_string(),
+        discord_webhook_url,
+        discord_plain,
+        slack_webhook_url,
+        matrix,
+        gotify,
+        pushover,
+        routes,
+        discord_status_interval: discord_status.then_some(discord_status_interval),
+        sources,
+        rules,
+        idrac: IdracConfig {
+            enabled: have_idrac,
+            servers: idrac_servers,
+        },
+        hooks,
+        audit: Arc::new(AuditLog::new(audit_path, audit_max_bytes, audit_keep)),
+        governor: Mutex::new(Governor::new(notify_dedup, notify_max_per_hour)),
+        quiet_hours: quiet_range.map(|(start, end)| QuietHours {
+            start,
+            end,
+            timezone: quiet_timezone.unwrap_or(timezone),
+            floor: quiet_floor,
+        }),
+        templates: Templates::load(&templates_dir)?,
+        http: outbound.with_env_proxies().clients().map_err(anyhow::Error::msg)?,
+        state: Mutex::new(MonitorState::load(Path::new(STATE_PATH))),
+        control_url: control_url.or_else(|| control_listen.map(|addr: SocketAddr| format!("http://{}", addr))),
+        control_listen,
+        control_token,
+        low_battery_warn_pct,
+        low_battery_warn_repeat,
+        metrics: Mutex::new(MonitorMetrics::default()),
+        evc_shed,
+        battery_capacity_kwh,
+        runtime_load,
+        server_probe_interval,
+        status_trust,
+        shutdown_self: shutdown_self.then_some(shutdown_self_command),
+        sequences: tokio::sync::Mutex::default(),
+    };
+    validate_config(&config)?;
+
+    for site in config.sites() {
+        if !config.rules.iter().any(|rule| rule.site == site) {
+            config.rules.push(Rule {
+                site,
+                text: DEFAULT_RULE.to_string(),
+                expr: Expr::parse(DEFAULT_RULE).map_err(anyhow::Error::msg)?,
+            });
+        }
+    }
+    Ok(config)
+}
+
+async fn fetch_status(http: &Clients, url: &str) -> Result<StatusOutput> {
+    let status = http.for_url(url).get(url)
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!(http.describe_error(url, &e)))?
+        .json::<StatusOutput>()
+        .await?;
+    Ok(status)
+}
+
+fn print_status(status: &StatusOutput) {
+    println!("Current Power Status:");
+    println!("├─ Solar Output: {}", status.solar_panels);
+    if status.battery_soc_raw.is_empty() || status.battery_soc_raw == status.batteries {
+        println!("├─ Battery Level: {}", status.batteries);
+    } else {
+        println!("├─ Battery Level: {} (raw {})", status.batteries, status.battery_soc_raw);
+    }
+    println!("├─ Battery Status: {}", status.battery_status);
+    println!("├─ Battery Power: {}", status.battery_power);
+    println!("├─ Grid Status: {}", status.grid_status);
+    println!("├─ Grid Power: {}", status.grid_power);
+    if let Some(watts) = status.essential_load_w {
+        println!("├─ Essential Load: {:.1}W", watts);
+    }
+    println!("└─ Home Consumption: {}", status.home_consumption);
+    // Circuits with an external meter, as part of the consumption
+    let mut circuits = status.circuits_w.iter().peekable();
+    while let Some((name, watts)) = circuits.next() {
+        let branch = if circuits.peek().is_some() { "├─" } else { "└─" };
+        println!("   {} {}: {:.1}W", branch, name, watts);
+    }
+}
+
+async fn notify(config: &Config, alert: &Alert, what: &str) {
+    deliver(config, alert, what, true).await;
+}
+
+/// `notify`, leaving out Discord unless `discord`, for what a sequence message already shows there.
+async fn deliver(config: &Config, alert: &Alert, what: &str, discord: bool) {
+    // Acknowledged conditions stay quiet until they clear
+    let mut alert = config.templates.apply(alert);
+    if let Some(id) = alert.id.clone() {
+        let mut state = config.state.lock().unwrap();
+        if state.alerts.get(&id).is_some_and(|active| active.acknowledged) {
+            println!("Not sending {} (alert {} acknowledged)", what, id);
+            return;
+        }
+        if !state.alerts.contains_key(&id) {
+            state.alerts.insert(id.clone(), ActiveAlert::default());
+            state.save();
+        }
+        if let Some(base) = &config.control_url {
+            alert = alert.field("Acknowledge", format!("`curl -X POST -H \"Authorization: Bearer $CONTROL_TOKEN\" {}/alerts/{}/ack`", base, id));
+        }
+    }
+    let alert = &alert;
+
+    if let Some(quiet) = &config.quiet_hours {
+        if quiet.holds(alert.severity) && quiet.contains(chrono::Utc::now()) {
+            println!("Quiet hours, holding {} for the digest", what);
+            let mut state = config.state.lock().unwrap();
+            hold_notification(&mut state, alert);
+            state.save();
+            return;
+        }
+    }
+
+    let key = alert.content();
+    let critical = alert.severity == Severity::Critical;
+    let admission = config.governor.lock().unwrap().admit(&key, critical, std::time::Instant::now());
+    let alert = match admission {
+        Admission::Send { suppressed: 0 } => alert.clone(),
+        Admission::Send { suppressed } => alert.clone().note(format!("(suppressed {} duplicates)", suppressed)),
+        Admission::Duplicate | Admission::OverCap => {
+            let reason = if admission == Admission::Duplicate { "duplicate" } else { "hourly cap reached" };
+            println!("Not sending {} ({})", what, reason);
+            config.metrics.lock().unwrap().notifications_suppressed += 1;
+            config.audit.record("notification", json!({
+                "notifier": "discord",
+                "what": what,
+                "severity": alert.severity.name(),
+                "suppressed": reason,
+            }));
+            return;
+        }
+    };
+
+    if discord && config.discord_enabled() && routed(config, "discord", &alert, what) {
+        let result = send_discord_alert(&config.http, &config.discord_webhook_url, &alert, config.discord_plain).await;
+        record_notification(config, "discord", &config.discord_webhook_url, &alert, what, result);
+    }
+    if let Some(webhook_url) = config.slack_webhook_url.as_ref().filter(|_| routed(config, "slack", &alert, what)) {
+        let result = send_slack_alert(&config.http, webhook_url, &alert).await;
+        record_notification(config, "slack", webhook_url, &alert, what, result);
+    }
+    if let Some(target) = config.matrix.as_ref().filter(|_| routed(config, "matrix", &alert, what)) {
+        let result = send_matrix_alert(&config.http, target, &alert).await;
+        record_notification(config, "matrix", &target.homeserver, &alert, what, result);
+    }
+    if let Some(target) = config.gotify.as_ref().filter(|_| routed(config, "gotify", &alert, what)) {
+        let result = send_gotify_alert(&config.http, target, &alert).await;
+        record_notification(config, "gotify", &target.url, &alert, what, result);
+    }
+    if let Some(target) = config.pushover.as_ref().filter(|_| routed(config, "pushover", &alert, what)) {
+        let result = send_pushover_alert(&config.http, target, &alert).await;
+        record_notification(config, "pushover", solax_mon::notify::PUSHOVER_URL, &alert, what, result);
+    }
+}
+
+/// Whether NOTIFY_ROUTE sends `alert` to `notifier`; a notifier left out is logged and
+/// audited with the reason, so a missing page can be explained.
+fn routed(config: &Config, notifier: &str, alert: &Alert, what: &str) -> bool {
+    let Err(reason) = config.routes.check(notifier, alert) else {
+        return true;
+    };
+    println!("Not sending {} to {} ({})", what, notifier, reason);
+    config.audit.record("notification", json!({
+        "notifier": notifier,
+        "what": what,
+        "severity": alert.severity.name(),
+        "routed": false,
+        "reason": reason,
+    }));
+    false
+}
+
+/// Counts, audits and logs what came of sending `what` through `notifier`.
+fn record_notification(config: &Config, notifier: &str, webhook_url: &str, alert: &Alert, what: &str, result: Result<()>) {
+    {
+        let mut metrics = config.metrics.lock().unwrap();
+        if result.is_ok() {
+            metrics.notifications_sent += 1;
+        } else {
+            metrics.notifications_failed += 1;
+        }
+    }
+    config.audit.record("notification", json!({
+        "notifier": notifier,
+        "what": what,
+        "severity": alert.severity.name(),
+        "ok": result.is_ok(),
+        "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+    }));
+    match result {
+        Ok(_) => println!("Successfully sent {} to {}", what, notifier),
+        Err(e) => {
+            eprintln!("Failed to send {} to {}:", what, notifier);
+            eprintln!("Error details: {}", e);
+            let masked_url = if webhook_url.len() > 20 {
+                format!("{}...{}", 
+                    &webhook_url[..10],
+                    &webhook_url[webhook_url.len()-10..])
+            } else {
+                "Invalid URL".to_string()
+            };
+            eprintln!("Webhook URL (masked): {}", masked_url);
+        }
+    }
+}
+
+fn print_rule_trace(config: &Config, site: &str, rule: &Rule, trace: &[(String, bool)], readings: Option<&Readings>) {
+    let runtime = readings.and_then(|r| r.runtime_min)
+        .map_or(String::new(), |minutes| format!(" (estimated runtime {})", format_runtime(minutes)));
+    if config.multi_source() {
+        println!("\nThreshold Check ({}): {}{}", site, rule.text, runtime);
+    } else {
+        println!("\nThreshold Check: {}{}", rule.text, runtime);
+    }
+    for (i, (check, passed)) in trace.iter().enumerate() {
+        let branch = if i + 1 == trace.len() { "└─" } else { "├─" };
+        println!("{} {}: {}", branch, check, passed);
+    }
+}
+
+fn hook_steps(config: &Config, point: HookPoint, site: &str) -> Vec<String> {
+    config.hooks.iter()
+        .filter(|hook| hook.point == point && config.resolve_site(&hook.site) == site)
+        .map(|hook| format!(
+            "{} hook: {}{}", point.name(), hook.command, if hook.required { " (required)" } else { "" }
+        ))
+        .collect()
+}
+
+/// The steps the main loop takes when a site's rule triggers, in order.
+fn shutdown_plan(config: &Config, site: &str) -> Vec<String> {
+    let mut steps = vec!["Send Discord alert".to_string()];
+    steps.extend(hook_steps(config, HookPoint::PreShutdown, site));
+    for server in config.servers.iter().filter(|server| config.resolve_site(&server.site) == site) {
+        if let Some(docker) = &server.docker {
+            let containers = match &docker.containers {
+                Containers::All => "all containers".to_string(),
+                Containers::Named(names) => names.join(", "),
+            };
+            steps.push(format!(
+                "Stop {} on {} (grace {}s)", containers, server.target, docker.grace.as_secs()
+            ));
+        }
+        steps.push(format!("Run {:?} on {}", server.action.remote_command(server.os), server.destination()));
+    }
+    steps.extend(hook_steps(config, HookPoint::PostShutdown, site));
+    if let Some(command) = &config.shutdown_self {
+        steps.push(format!("Once the servers are down, run {:?} on this host", command));
+    }
+    steps
+}
+
+/// The steps the main loop takes when a triggered site normalizes, in order.
+fn recovery_plan(config: &Config, site: &str) -> Vec<String> {
+    let mut steps = vec!["Send Discord normalization alert".to_string()];
+    steps.extend(hook_steps(config, HookPoint::PrePoweron, site));
+    for server in config.servers.iter().filter(|server| config.resolve_site(&server.site) == site) {
+        if let WakeMethod::WakeOnLan(_) = server.wake {
+            steps.push(format!("Send Wake-on-LAN to {}", server.target));
+        }
+    }
+    if config.idrac.enabled {
+        for server in config.idrac.servers.iter().filter(|server| config.resolve_site(&server.site) == site) {
+            steps.push(format!("Power on iDRAC server {}", server.ip));
+        }
+    }
+    steps.extend(hook_steps(config, HookPoint::PostPoweron, site));
+    steps
+}
+
+/// `ssh test-notify`: sends a test message through every notifier.
+async fn test_notify(config: &Config) -> i32 {
+    let alert = Alert::new(Severity::Info, "🧪 TEST NOTIFICATION from solax-mon")
+        .event("test")
+        .description("This is a test, no action is required.");
+    let alert = config.templates.apply(&alert);
+    let mut results = Vec::new();
+    if config.discord_enabled() {
+        results.push(("discord", send_discord_alert(&config.http, &config.discord_webhook_url, &alert, config.discord_plain).await));
+    }
+    if let Some(webhook_url) = &config.slack_webhook_url {
+        results.push(("slack", send_slack_alert(&config.http, webhook_url, &alert).await));
+    }
+    if let Some(target) = &config.matrix {
+        results.push(("matrix", send_matrix_alert(&config.http, target, &alert).await));
+    }
+    if let Some(target) = &config.gotify {
+        results.push(("gotify", send_gotify_alert(&config.http, target, &alert).await));
+    }
+    if let Some(target) = &config.pushover {
+        results.push(("pushover", send_pushover_alert(&config.http, target, &alert).await));
+    }
+    let mut code = 0;
+    for (notifier, result) in results {
+        // The test goes through whatever the routes say, which are shown for reference
+        let route = config.routes.get(notifier).map(|route| format!(" [{}]", route)).unwrap_or_default();
+        match result {
+            Ok(_) => println!("{}: ok{}", notifier, route),
+            Err(e) => {
+                println!("{}: FAILED ({:#}){}", notifier, e, route);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+fn parse_simulated_readings(args: &[String]) -> Result<Readings> {
+    let mut values: HashMap<&str, f64> = HashMap::new();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let name = match flag.as_str() {
+            "--battery" => "battery_pct",
+            "--grid" => "grid_w",
+            "--solar" => "solar_w",
+            "--load" => "load_w",
+            "--essential-load" => "essential_load_w",
+            "--battery-power" => "battery_w",
+            _ => anyhow::bail!("Unknown argument {}", flag),
+        };
+        let value = args.next().with_context(|| format!("{} needs a value", flag))?;
+        values.insert(name, value.parse().with_context(|| format!("Invalid value for {}: {}", flag, value))?);
+    }
+
+    let get = |name: &str| values.get(name).copied()
+        .with_context(|| "usage: ssh simulate --battery PCT --grid W --solar W --load W [--essential-load W] [--battery-power W]");
+    let load_w = get("load_w")?;
+    Ok(Readings {
+        grid_w: get("grid_w")?,
+        solar_w: get("solar_w")?,
+        load_w,
+        essential_load_w: values.get("essential_load_w").copied().unwrap_or(load_w),
+        battery_pct: get("battery_pct")?,
+        battery_w: values.get("battery_w").copied().unwrap_or_default(),
+        runtime_min: None,
+    })
+}
+
+/// `ssh simulate`: evaluates every site's rule against a synthetic snapshot
+/// reported by all sources and prints the actions that would fire, without running them.
+fn simulate(config: &Config, args: &[String]) -> i32 {
+    let mut readings = match parse_simulated_readings(args) {
+        Ok(readings) => readings,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let battery_w = runtime_battery_w(config.runtime_load, &readings, readings.battery_w);
+    readings.runtime_min = config.battery_capacity_kwh
+        .map(|kwh| runtime_minutes(readings.battery_pct, kwh, battery_w));
+    let mut fresh: HashMap<String, Readings> = config.sources.iter()
+        .map(|source| (source.name.clone(), readings))
+        .collect();
+    if config.multi_source() {
+        let all: Vec<Readings> = fresh.values().copied().collect();
+        fresh.insert(TOTAL_SITE.to_string(), Readings::combine(&all));
+    }
+
+    let mut code = 0;
+    for site in config.sites() {
+        let rule = config.rule(&site);
+        let mut trace = Vec::new();
+        let result = rule.expr.eval(&|name| lookup_reading(name, &site, &fresh), &mut trace);
+        print_rule_trace(config, &site, rule, &trace, fresh.get(&site));
+
+        let (heading, steps) = match result {
+            None => {
+                println!("\n⚠️ Rule for {} could not be evaluated", site);
+                code = 1;
+                continue;
+            }
+            Some(true) => ("🚨 Shutdown would be triggered (dry run):", shutdown_plan(config, &site)),
+            Some(false) => ("Rule not met; after a shutdown the recovery would be (dry run):", recovery_plan(config, &site)),
+        };
+        println!("\n{}", heading);
+        for (i, step) in steps.iter().enumerate() {
+            println!("{}. {}", i + 1, step);
+        }
+    }
+    code
+}
+
+/// `validate`: reads the configuration the way the monitor does and prints either what it
+/// would run with or everything wrong with it.
+fn validate() -> i32 {
+    let text = match std::fs::read_to_string(SECRETS_PATH) {
+        Ok(text) => text,
+        Err(e) => {
+            println!("Failed to read {}: {}", SECRETS_PATH, e);
+            return 1;
+        }
+    };
+    let mut problems = secrets::check(&text);
+    let mut config = None;
+    if problems.is_empty() {
+        match parse_config(&text) {
+            Ok(parsed) => config = Some(parsed),
+            Err(e) => problems.push(secrets::Problem::locate(&text, format!("{:#}", e))),
+        }
+    }
+    let Some(config) = config else {
+        println!("{} problem(s) in {}:", problems.len(), SECRETS_PATH);
+        for problem in &problems {
+            println!("  {}", problem);
+        }
+        return 1;
+    };
+
+    println!("OK: {}", SECRETS_PATH);
+    let servers: Vec<&str> = config.servers.iter().map(|server| server.target.as_str()).collect();
+    println!("Servers: {}", if servers.is_empty() { "none".to_string() } else { servers.join(", ") });
+    println!("Sites: {}", config.sites().join(", "));
+    let notifiers: Vec<&str> = [
+        ("discord", config.discord_enabled() && !config.discord_webhook_url.is_empty()),
+        ("slack", config.slack_webhook_url.is_some()),
+        ("matrix", config.matrix.is_some()),
+        ("gotify", config.gotify.is_some()),
+        ("pushover", config.pushover.is_some()),
+    ].into_iter().filter(|(_, configured)| *configured).map(|(name, _)| name).collect();
+    println!("Notifiers: {}", if notifiers.is_empty() { "none".to_string() } else { notifiers.join(", ") });
+    println!("Rules: {}, hooks: {}", config.rules.len(), config.hooks.len());
+    println!("Entries:");
+    for (key, value) in parse_entries(&text) {
+        println!("  {}", secrets::masked(&key, &value));
+    }
+    0
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("validate") {
+        std::process::exit(validate());
+    }
+    let config = Arc::new(load_config()?);
+    match args.get(1).map(String::as_str) {
+        Some("test-notify") => std::process::exit(test_notify(&config).await),
+        Some("simulate") => std::process::exit(simulate(&config, &args[2..])),
+        Some(other) => anyhow::bail!("Unknown command {} (test-notify, simulate, validate)", other),
+        None => {}
+    }
+
+    println!("Starting power monitoring service...");
+    println!("Loaded configuration with {} servers", config.servers.len());
+    let templates: Vec<&str> = config.templates.events().collect();
+    if !templates.is_empty() {
+        println!("Using notification templates for {}", templates.join(", "));
+    }
+    for (target, believed) in &config.state.lock().unwrap().servers {
+        if believed.power == Power::Down {
+            println!("{} is believed down since {} ({})", target, believed.since, believed.via);
+            config.metrics.lock().unwrap().servers_down.insert(target.clone());
+        }
+    }
+    if config.multi_source() {
+        println!("Monitoring {} sources: {}", config.sources.len(),
+            config.sources.iter().map(|s| s.name.as_str()).collect::<Vec<_>>().join(", "));
+    }
+    if config.idrac.enabled {
+        println!("iDRAC support enabled with {} servers", config.idrac.servers.len());
+    }
+    
+    if let Some(addr) = config.control_listen {
+        let app = axum::Router::new()
+            .route("/audit", axum::routing::get(get_audit))
+            .route("/alerts/:id/ack", axum::routing::post(ack_alert))
+            .route("/metrics", axum::routing::get(get_metrics))
+            .route("/state", axum::routing::get(get_state))
+            .route("/servers/:name/mark-up", axum::routing::post(mark_server_up))
+            .with_state(config.clone());
+        let server = axum::Server::try_bind(&addr)?;
+        println!("Control endpoint listening on {}", addr);
+        tokio::spawn(async move {
+            if let Err(e) = server.serve(app.into_make_service()).await {
+                eprintln!("Control endpoint failed: {}", e);
+            }
+        });
+    }
+
+    // Stopping the monitor mid-sequence still leaves every step so far in the sequence messages
+    let stopping = config.clone();
+    tokio::spawn(async move {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                eprintln!("Failed to listen for SIGTERM: {}", e);
+                return;
+            }
+        };
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        println!("Stopping, updating the messages of running sequences");
+        stopping.sequences.lock().await.stop(&stopping).await;
+        std::process::exit(0);
+    });
+
+    let mut shutdown_triggered: HashMap<String, bool> = HashMap::new();
+    // After powering off its own host the sites are still shut down, so the first iteration
+    // already recovers whichever of them has normalized
+    let previous = config.state.lock().unwrap().take_self_shutdown();
+    if let Some(previous) = previous {
+        println!("This host was powered off by the monitor at {} after the shutdown of {}", previous.at, previous.sites.join(", "));
+        config.audit.record("self_shutdown_seen", json!({ "at": previous.at, "sites": previous.sites }));
+        for site in previous.sites {
+            shutdown_triggered.insert(site, true);
+        }
+    }
+    // Sites whose shutdown sequence ran, for powering off this host once their servers are down
+    let mut self_shutdown_sites: Vec<String> = Vec::new();
+    let mut blind_sources: Vec<String> = Vec::new();
+    // Servers whose action was sent but that haven't been seen down yet
+    let mut awaiting_down: HashMap<String, std::time::Instant> = HashMap::new();
+    // Servers whose site recovered but that haven't been seen up yet
+    let mut awaiting_up: HashMap<String, std::time::Instant> = HashMap::new();
+    let mut last_probe: Option<std::time::Instant> = None;
+    let mut status_message = StatusMessage::load(&config);
+    let mut low_battery_warned: HashMap<String, std::time::Instant> = HashMap::new();
+    // Failures repeating every iteration, logged once while they last
+    let mut warnings = Warnings::new();
+    // Whether the EV charger was paused by the load-shedding step
+    let mut evc_paused = false;
+    // Battery power per source, smoothed for the runtime estimate
+    let mut smoothed_battery_w: HashMap<String, f64> = HashMap::new();
+    let mut iteration = 1;
+
+    loop {
+        println!("\n=== Monitoring Iteration {} ===", iteration);
+
+        // Collect fresh readings from every source
+        let mut fresh: HashMap<String, Readings> = HashMap::new();
+        // Sources stood in for by their cached readings, with the age of those
+        let mut cached: BTreeMap<String, u64> = BTreeMap::new();
+        for source in &config.sources {
+            if config.multi_source() {
+                println!("\n--- Source {} ---", source.name);
+            }
+            match fetch_status(&config.http, &source.url).await {
+                Ok(status) => {
+                    print_status(&status);
+                    warnings.clear(&source.name, format_args!("Fetching power status from {} again", source.name));
+                    config.audit.record("snapshot", json!({
+                        "source": source.name,
+                        "readings": Readings::from_status(&status),
+                        "partial": status.partial,
+                    }));
+                    warnings.track(
+                        &format!("{} partial", source.name),
+                        status.partial,
+                        "⚠️ Partial snapshot (truncated inverter data), not acting on it until it's complete",
+                        format_args!("Snapshot of {} is complete again", source.name),
+                    );
+                    if !status.partial {
+                        let mut readings = Readings::from_status(&status);
+                        let battery_w = smooth(smoothed_battery_w.get(&source.name).copied(), readings.battery_w);
+                        smoothed_battery_w.insert(source.name.clone(), battery_w);
+                        let battery_w = runtime_battery_w(config.runtime_load, &readings, battery_w);
+                        // The battery can't discharge faster than its BMS allows
+                        let draw_w = status.bms_discharge_limit_w().map_or(battery_w, |limit_w| battery_w.max(-limit_w));
+                        readings.runtime_min = config.battery_capacity_kwh
+                            .map(|kwh| runtime_minutes(readings.battery_pct, kwh, draw_w));
+                        fresh.insert(source.name.clone(), readings);
+                        let mut state = config.state.lock().unwrap();
+                        state.last_readings.insert(source.name.clone(), CachedReadings { readings, at: unix_now() });
+                        state.save();
+                    }
+                }
+                Err(e) => {
+                    warnings.warn(&source.name, format_args!("Failed to fetch power status from {}: {}", source.name, e));
+                    config.audit.record("snapshot", json!({ "source": source.name, "error": e.to_string() }));
+                }
+            }
+            if fresh.contains_key(&source.name) {
+                continue;
+            }
+            // A restart of the main service shouldn't leave the monitor blind, for a while
+            let trusted = config.state.lock().unwrap().trusted_readings(&source.name, unix_now(), config.status_trust);
+            if let Some((readings, age)) = trusted {
+                println!(
+                    "⚠️ DEGRADED: no fresh data from {}, acting on its readings from {}s ago (trusted for {}s)",
+                    source.name, age, config.status_trust.as_secs()
+                );
+                config.audit.record("snapshot", json!({
+                    "source": source.name,
+                    "readings": readings,
+                    "cached": true,
+                    "age_secs": age,
+                }));
+                fresh.insert(source.name.clone(), readings);
+                cached.insert(source.name.clone(), age);
+            }
+        }
+
+        // A source without fresh or trusted readings is a blind spot worth alerting about
+        for source in &config.sources {
+            let is_blind = !fresh.contains_key(&source.name);
+            let was_blind = blind_sources.contains(&source.name);
+            if is_blind && !was_blind {
+                blind_sources.push(source.name.clone());
+                let last = config.state.lock().unwrap().last_readings.get(&source.name)
+                    .map_or("never".to_string(), |cached| format!("{}s ago", unix_now().saturating_sub(cached.at)));
+                let alert = Alert::new(Severity::Warning, format!("⚠️ No fresh data from site {}!", source.name))
+                    .id(format!("blind-{}", source.name))
+                    .site(&source.name)
+                    .description(format!("The monitor is blind: actions for {} are on hold until it reports again.", source.name))
+                    .field("Last data", last);
+                notify(&config, &alert, "blind spot alert").await;
+            } else if !is_blind && was_blind {
+                blind_sources.retain(|name| name != &source.name);
+                clear_alert(&config, &format!("blind-{}", source.name));
+                let alert = Alert::new(Severity::Normal, format!("✅ Site {} is reporting again.", source.name))
+                    .site(&source.name);
+                notify(&config, &alert, "blind spot recovery alert").await;
+            }
+        }
+
+        // The combined total only exists when every source reported
+        if fresh.len() == config.sources.len() {
+            let all: Vec<Readings> = fresh.values().copied().collect();
+            fresh.insert(TOTAL_SITE.to_string(), Readings::combine(&all));
+        }
+
+        // Repeat low battery warnings until acknowledged or the battery recovers
+        if let Some(threshold) = config.low_battery_warn_pct {
+            for site in config.sites() {
+                let Some(readings) = fresh.get(&site) else { continue };
+                let id = format!("low-battery-{}", site);
+                if readings.battery_pct >= threshold {
+                    low_battery_warned.remove(&site);
+                    clear_alert(&config, &id);
+                } else if low_battery_warned.get(&site)
+                    .is_none_or(|warned| warned.elapsed() >= config.low_battery_warn_repeat)
+                {
+                    low_battery_warned.insert(site.clone(), std::time::Instant::now());
+                    let alert = Alert::new(Severity::Warning, format!("🔋 Low battery at {}", site))
+                        .id(id)
+                        .site(&site)
+                        .readings(Some(readings), "");
+                    notify(&config, &alert, "low battery warning").await;
+                }
+            }
+        }
+
+        // Deliver what was held back once quiet hours are over
+        if let Some(quiet) = &config.quiet_hours {
+            if !quiet.contains(chrono::Utc::now()) {
+                let digest = {
+                    let mut state = config.state.lock().unwrap();
+                    let digest = take_digest(&mut state, quiet.timezone);
+                    if digest.is_some() {
+                        state.save();
+                    }
+                    digest
+                };
+                if let Some(digest) = digest {
+                    notify(&config, &digest, "quiet hours digest").await;
+                }
+            }
+        }
+
+        if let Some(interval) = config.discord_status_interval {
+            if status_message.due(interval) {
+                let result = status_message.update(&config, &status_alert(&config, &fresh)).await;
+                match &result {
+                    Err(e) => warnings.warn("status message", format_args!("Failed to update Discord status message: {:#}", e)),
+                    Ok(()) => warnings.clear("status message", "Updating the Discord status message again"),
+                }
+                config.audit.action("status_message", "discord", &result);
+            }
+        }
+
+        for site in config.sites() {
+            let readings = fresh.get(&site);
+            let rule = config.rule(&site);
+            let mut trace = Vec::new();
+            let result = rule.expr.eval(&|name| lookup_reading(name, &site, &fresh), &mut trace);
+
+            print_rule_trace(&config, &site, rule, &trace, readings);
+            let inputs = cached_inputs(rule, &site, &cached);
+            let uses_runtime = rule.expr.variables().iter().any(|var| var.rsplit('.').next() == Some("runtime_min"));
+            config.audit.record("evaluation", json!({
+                "site": site,
+                "rule": rule.text,
+                "result": result,
+                "runtime_load": uses_runtime.then_some(config.runtime_load.as_str()),
+                "cached": (!inputs.is_empty()).then_some(&inputs),
+                "trace": trace.iter()
+                    .map(|(check, passed)| json!({ "check": check, "passed": passed }))
+                    .collect::<Vec<_>>(),
+            }));
+
+            let site_triggered = shutdown_triggered.get(&site).copied().unwrap_or(false);
+            let servers: Vec<&Server> = config.servers.iter()
+                .filter(|server| config.resolve_site(&server.site) == site)
+                .collect();
+            let evc_shed = config.evc_shed.as_ref().filter(|shed| config.resolve_site(&shed.site) == site);
+
+            config.audit.acting_on_cached((!inputs.is_empty()).then(|| json!(inputs)));
+            match result {
+                None => {
+                    println!("\n⚠️ No fresh data for {}, holding current state", site);
+                }
+                Some(true) => {
+                    println!("\n🚨 CRITICAL: All shutdown conditions met!");
+                    if let (false, false, Some(shed)) = (site_triggered, evc_paused, evc_shed) {
+                        // Pausing the car comes first; the servers follow if the conditions persist
+                        println!("Pausing EV charging before any shutdown...");
+                        let result = set_evc_charging(shed, false).await;
+                        config.audit.action("evc_pause", &shed.url, &result);
+                        evc_paused = true;
+                        let alert = match &result {
+                            Ok(()) => Alert::new(Severity::Warning, "🚗 EV charging paused")
+                                .site(&site)
+                                .readings(readings, " (Offline)")
+                                .field("Action", "Servers shut down if conditions persist"),
+                            Err(e) => Alert::new(Severity::Warning, "🚗 Failed to pause EV charging")
+                                .site(&site)
+                                .description(format!("{:#}", e))
+                                .field("Action", "Servers shut down if conditions persist"),
+                        };
+                        notify(&config, &alert, "EV charging pause alert").await;
+                    } else if !site_triggered {
+                        println!("Initiating shutdown sequence...");
+                        
+                        // Send Discord alert
+                        let mut alert = Alert::new(Severity::Critical, "🚨 CRITICAL POWER ALERT!")
+                            .site(&site)
+                            .readings(readings, " (Offline)")
+                            .var("servers", servers.iter().map(|server| server.target.as_str()).collect::<Vec<_>>().join(", "))
+                            .field("Action", "⚠️ Initiating server shutdown sequence...");
+                        if let Some(age) = inputs.values().max() {
+                            alert = alert.field("Data", format!("cached, {}s old", age));
+                        }
+                        notify(&config, &alert, "Discord alert").await;
+
+                        // The steps are collected in one message, edited as they complete
+                        config.sequences.lock().await.start(&config, "shutdown", &site).await;
+                        if run_hooks(&config, HookPoint::PreShutdown, &site, readings).await {
+                            // Shutdown servers
+                            for server in &servers {
+                                if config.state.lock().unwrap().believed_down(&server.target) {
+                                    println!("Skipping shutdown of {}, already believed down", server.target);
+                                    config.audit.record("shutdown_skipped", json!({ "target": server.target }));
+                                    config.sequence_step(&site, &server.target, Outcome::Skipped, format!("{} is already believed down", server.target)).await;
+                                    continue;
+                                }
+                                if let Some(docker) = &server.docker {
+                                    println!("Stopping containers on {}...", server.target);
+                                    let key = format!("containers {}", server.target);
+                                    config.sequence_step(&site, &key, Outcome::Running, format!("Stopping containers on {}", server.target)).await;
+                                    let result = stop_containers(server, docker, &config.ssh_key_path, &config.http).await;
+                                    config.audit.action("docker_stop", &server.target, &result);
+                                    match result {
+                                        Ok(()) => config.sequence_step(&site, &key, Outcome::Done, format!("Stopped containers on {}", server.target)).await,
+                                        Err(e) => {
+                                            eprintln!("Failed to stop containers on {}: {}", server.target, e);
+                                            config.sequence_step(&site, &key, Outcome::Failed, format!("Stopping containers on {} failed: {}", server.target, e)).await;
+                                        }
+                                    }
+                                }
+                                let result = shutdown_server(server, &config.ssh_key_path).await;
+                                config.audit.action("shutdown", &server.target, &result);
+                                match result {
+                                    Ok(_) => {
+                                        println!("Successfully initiated shutdown for {}", server.target);
+                                        awaiting_down.insert(server.target.clone(), std::time::Instant::now());
+                                        config.sequence_step(&site, &server.target, Outcome::Running, format!("Waiting for {} to go down", server.target)).await;
+                                    }
+                                    Err(e) => {
+                                        eprintln!("Failed to shutdown {}: {}", server.target, e);
+                                        config.sequence_step(&site, &server.target, Outcome::Failed, e.to_string()).await;
+                                    }
+                                }
+                            }
+
+                            run_hooks(&config, HookPoint::PostShutdown, &site, readings).await;
+                        } else {
+                            config.sequence_step(&site, "aborted", Outcome::Failed, "Required hook failed, shutdown sequence aborted".to_string()).await;
+                        }
+                        report_failures(&config, &site, "shutdown").await;
+                        config.sequences.lock().await.flush(&config, &site).await;
+                        
+                        shutdown_triggered.insert(site.clone(), true);
+                        if config.shutdown_self.is_some() {
+                            self_shutdown_sites.push(site.clone());
+                        }
+                        config.metrics.lock().unwrap().last_shutdown = Some(unix_now());
+                    } else {
+                        println!("Shutdown already triggered, waiting for conditions to normalize...");
+                    }
+                }
+                Some(false) => {
+                    if site_triggered {
+                        println!("\nConditions normalized, initiating recovery sequence");
+                        
+                        // Send normalization alert
+                        let alert = Alert::new(Severity::Normal, "✅ Power conditions normalized!")
+                            .site(&site)
+                            .readings(readings, "")
+                            .var("servers", servers.iter().map(|server| server.target.as_str()).collect::<Vec<_>>().join(", "))
+                            .field("Action", "Starting recovery sequence");
+                        notify(&config, &alert, "normalization alert").await;
+
+                        config.sequences.lock().await.start(&config, "recovery", &site).await;
+                        if run_hooks(&config, HookPoint::PrePoweron, &site, readings).await {
+                            for server in &servers {
+                                awaiting_down.remove(&server.target);
+                                clear_alert(&config, &format!("still-up-{}", server.target));
+                                if let WakeMethod::WakeOnLan(mac) = &server.wake {
+                                    let result = send_wake_on_lan(mac);
+                                    config.audit.action("wake_on_lan", &server.target, &result);
+                                    let key = format!("wake {}", server.target);
+                                    match result {
+                                        Ok(_) => {
+                                            println!("Sent Wake-on-LAN to {}", server.target);
+                                            config.sequence_step(&site, &key, Outcome::Done, format!("Sent Wake-on-LAN to {}", server.target)).await;
+                                        }
+                                        Err(e) => {
+                                            eprintln!("Failed to wake {}: {}", server.target, e);
+                                            config.sequence_step(&site, &key, Outcome::Failed, format!("Waking {} failed: {}", server.target, e)).await;
+                                        }
+                                    }
+                                }
+                                if server.check == DownCheck::None {
+                                    believe(&config, &server.target, Power::Unknown, "recovery");
+                                } else {
+                                    awaiting_up.insert(server.target.clone(), std::time::Instant::now());
+                                    config.sequence_step(&site, &server.target, Outcome::Running, format!("Waiting for {} to come up", server.target)).await;
+                                }
+                            }
+
+                            // Power on iDRAC servers if enabled
+                            if config.idrac.enabled {
+                                println!("Initiating iDRAC power-on sequence...");
+                                for server in config.idrac.servers.iter()
+                                    .filter(|server| config.resolve_site(&server.site) == site)
+                                {
+                                    let result = power_on_idrac(server).await;
+                                    config.audit.action("idrac_power_on", &server.ip, &result);
+                                    let key = format!("idrac {}", server.ip);
+                                    match result {
+                                        Ok(_) => {
+                                            println!("Successfully powered on iDRAC server {}", server.ip);
+                                            config.sequence_step(&site, &key, Outcome::Done, format!("Powered on iDRAC server {}", server.ip)).await;
+                                        }
+                                        Err(e) => {
+                                            eprintln!("Failed to power on iDRAC server {}: {}", server.ip, e);
+                                            config.sequence_step(&site, &key, Outcome::Failed, format!("Powering on iDRAC server {} failed: {}", server.ip, e)).await;
+                                        }
+                                    }
+                                }
+                            }
+
+                            run_hooks(&config, HookPoint::PostPoweron, &site, readings).await;
+                        } else {
+                            config.sequence_step(&site, "aborted", Outcome::Failed, "Required hook failed, recovery sequence aborted".to_string()).await;
+                        }
+                        report_failures(&config, &site, "recovery").await;
+                        config.sequences.lock().await.flush(&config, &site).await;
+
+                        shutdown_triggered.insert(site.clone(), false);
+                        self_shutdown_sites.retain(|name| name != &site);
+                        config.metrics.lock().unwrap().last_poweron = Some(unix_now());
+                    } else {
+                        println!("\nOperating within normal parameters");
+                    }
+
+                    if let (true, Some(shed)) = (evc_paused, evc_shed) {
+                        println!("Resuming EV charging");
+                        let result = set_evc_charging(shed, true).await;
+                        config.audit.action("evc_resume", &shed.url, &result);
+                        if let Err(e) = &result {
+                            eprintln!("Failed to resume EV charging: {:#}", e);
+                        }
+                        evc_paused = false;
+                    }
+                }
+            }
+            config.audit.acting_on_cached(None);
+
+            let battery_low_pct = config.low_battery_warn_pct.unwrap_or(BATTERY_LOW_PCT);
+            let triggered = shutdown_triggered.get(&site).copied().unwrap_or(false);
+            let mut metrics = config.metrics.lock().unwrap();
+            metrics.sites.insert(site.clone(), SiteMetrics::evaluated(readings, result, battery_low_pct, triggered));
+            metrics.last_evaluation = Some(unix_now());
+        }
+
+        // Confirm that servers actually went down after their action
+        let mut confirmed = Vec::new();
+        for (target, sent) in &awaiting_down {
+            let Some(server) = config.servers.iter().find(|server| &server.target == target) else { continue };
+            let down = server_is_down(server, &config.ssh_key_path).await;
+            config.audit.record("down_check", json!({ "target": target, "down": down }));
+            let site = config.resolve_site(&server.site);
+            if down {
+                clear_alert(&config, &format!("still-up-{}", target));
+                println!("Confirmed {} is down", target);
+                believe(&config, target, Power::Down, "down_check");
+                config.sequence_step(&site, target, Outcome::Done, format!("{} is down", target)).await;
+                confirmed.push(target.clone());
+            } else if sent.elapsed() > DOWN_CHECK_TIMEOUT {
+                let action = format!(
+                    "{:?} was sent {} seconds ago", server.action.remote_command(server.os), DOWN_CHECK_TIMEOUT.as_secs()
+                );
+                config.sequence_step(&site, target, Outcome::Failed, format!("{} is still up, {}", target, action)).await;
+                let alert = Alert::new(Severity::Warning, format!("⚠️ Server {} is still up!", target))
+                    .id(format!("still-up-{}", target))
+                    .site(&site)
+                    .field("Action", action);
+                let in_sequence = config.sequences.lock().await.is_running(&site);
+                deliver(&config, &alert, "down check alert", !in_sequence).await;
+                confirmed.push(target.clone());
+            }
+        }
+        for target in confirmed {
+            awaiting_down.remove(&target);
+        }
+        config.sequences.lock().await.settle(&config, "shutdown", &awaited_sites(&config, &awaiting_down)).await;
+
+        // This host goes last, once every server is confirmed down or has timed out
+        if let Some(command) = &config.shutdown_self {
+            if !self_shutdown_sites.is_empty() && awaiting_down.is_empty() {
+                shutdown_self(&config, command, std::mem::take(&mut self_shutdown_sites)).await;
+            }
+        }
+
+        // Confirm that servers came back after a recovery sequence
+        let mut confirmed = Vec::new();
+        for (target, sent) in &awaiting_up {
+            let Some(server) = config.servers.iter().find(|server| &server.target == target) else { continue };
+            let site = config.resolve_site(&server.site);
+            if !server_is_down(server, &config.ssh_key_path).await {
+                believe(&config, target, Power::Up, "poweron_check");
+                config.sequence_step(&site, target, Outcome::Done, format!("{} is up", target)).await;
+                confirmed.push(target.clone());
+            } else if sent.elapsed() > POWERON_CHECK_TIMEOUT {
+                println!("{} not seen up {}s after recovery, keeping it believed down", target, POWERON_CHECK_TIMEOUT.as_secs());
+                config.sequence_step(&site, target, Outcome::Failed, format!("{} not seen up {}s after recovery", target, POWERON_CHECK_TIMEOUT.as_secs())).await;
+                confirmed.push(target.clone());
+            }
+        }
+        for target in confirmed {
+            awaiting_up.remove(&target);
+        }
+        config.sequences.lock().await.settle(&config, "recovery", &awaited_sites(&config, &awaiting_up)).await;
+
+        // Keep the believed state of the other servers current
+        if let Some(interval) = config.server_probe_interval {
+            if last_probe.is_none_or(|probed| probed.elapsed() >= interval) {
+                last_probe = Some(std::time::Instant::now());
+                for server in config.servers.iter().filter(|server| server.check != DownCheck::None) {
+                    if awaiting_down.contains_key(&server.target) || awaiting_up.contains_key(&server.target) {
+                        continue;
+                    }
+                    let power = if server_is_down(server, &config.ssh_key_path).await { Power::Down } else { Power::Up };
+                    believe(&config, &server.target, power, "probe");
+                }
+            }
+        }
+
+        iteration += 1;
+        println!("\nWaiting 30 seconds before next check...");
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings(grid_w: f64, solar_w: f64, load_w: f64, battery_pct: f64) -> Readings {
+        Readings { grid_w, solar_w, load_w, essential_load_w: load_w, battery_pct, ..Readings::default() }
+    }
+
+    fn bearer(token: &str) -> axum::http::HeaderMap {
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(axum::http::header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    fn test_config(sources: &[&str]) -> Config {
+        Config {
+            servers: Vec::new(),
+            ssh_key_path: "/dev/null".to_string(),
+            discord_webhook_url: String::new(),
+            discord_plain: false,
+            slack_webhook_url: None,
+            matrix: None,
+            gotify: None,
+            pushover: None,
+            routes: Routes::default(),
+            discord_status_interval: None,
+            sources: sources.iter()
+                .map(|name| StatusSource { name: name.to_string(), url: String::new() })
+                .collect(),
+            rules: Vec::new(),
+            idrac: IdracConfig { enabled: false, servers: Vec::new() },
+            hooks: Vec::new(),
+            audit: Arc::new(AuditLog::new(None, 0, 0)),
+            governor: Mutex::new(Governor::new(Duration::from_secs(600), 20)),
+            quiet_hours: None,
+            templates: Templates::default(),
+            http: Clients::default(),
+            state: Mutex::new(MonitorState::default()),
+            control_listen: None,
+            control_url: None,
+            control_token: None,
+            low_battery_warn_pct: None,
+            low_battery_warn_repeat: Duration::from_secs(1800),
+            metrics: Mutex::new(MonitorMetrics::default()),
+            evc_shed: None,
+            battery_capacity_kwh: None,
+            runtime_load: RuntimeLoad::Total,
+            server_probe_interval: None,
+            status_trust: Duration::from_secs(300),
+            shutdown_self: None,
+            sequences: tokio::sync::Mutex::default(),
+        }
+    }
+
+    #[test]
+    fn rule_references_other_sources_and_totals() {
+        let rule = Expr::parse("cabin.grid_w == 0 && (total.battery_pct < 15 || house.load_w > 2000)").unwrap();
+        let fresh = HashMap::from([
+            ("cabin".to_string(), readings(0.0, 0.0, 300.0, 40.0)),
+            ("house".to_string(), readings(500.0, 0.0, 2500.0, 60.0)),
+            ("total".to_string(), readings(500.0, 0.0, 2800.0, 40.0)),
+        ]);
+        assert_eq!(rule.eval(&|name| lookup_reading(name, "cabin", &fresh), &mut Vec::new()), Some(true));
+    }
+
+    #[test]
+    fn rule_without_fresh_data_is_undecided() {
+        let rule = Expr::parse("house.grid_w == 0").unwrap();
+        let fresh = HashMap::from([("cabin".to_string(), readings(0.0, 0.0, 300.0, 40.0))]);
+        assert_eq!(rule.eval(&|name| lookup_reading(name, "cabin", &fresh), &mut Vec::new()), None);
+    }
+
+    #[test]
+    fn servers_are_listed_once_with_a_key() {
+        let mut config = test_config(&["local"]);
+        config.servers.push(parse_server("me@desktop,action=suspend").unwrap());
+        validate_config(&config).unwrap();
+        config.servers.push(parse_server("root@desktop").unwrap());
+        assert_eq!(validate_config(&config).unwrap_err().to_string(), "SERVER desktop is listed twice");
+        config.servers[1] = parse_server("root@nas,key=/nonexistent/nas.key").unwrap();
+        assert!(validate_config(&config).unwrap_err().to_string().contains("/nonexistent/nas.key"));
+    }
+
+    #[test]
+    fn runtime_rule_combines_with_battery_level() {
+        let mut config = test_config(&["local"]);
+        let rule = "grid_w == 0 && (runtime_min < 20 || battery_pct < 10)";
+        config.rules.push(Rule { site: "local".to_string(), text: rule.to_string(), expr: Expr::parse(rule).unwrap() });
+        assert!(validate_config(&config).is_err());
+        config.battery_capacity_kwh = Some(10.0);
+        validate_config(&config).unwrap();
+
+        // 15% of 10 kWh lasts hours at 200 W but half an hour at 3 kW
+        let at = |battery_w: f64| Readings {
+            battery_w,
+            runtime_min: Some(runtime_minutes(15.0, 10.0, battery_w)),
+            ..readings(0.0, 0.0, 3000.0, 15.0)
+        };
+        let expr = &config.rules[0].expr;
+        for (battery_w, expected) in [(-200.0, false), (-3000.0, false), (-6000.0, true), (500.0, false)] {
+            let fresh = HashMap::from([("local".to_string(), at(battery_w))]);
+            assert_eq!(expr.eval(&|name| lookup_reading(name, "local", &fresh), &mut Vec::new()), Some(expected));
+        }
+    }
+
+    #[test]
+    fn essential_runtime_load_ignores_the_rest_of_the_house() {
+        // 3 kW of load with 500 W of solar, of which only 900 W is on the EPS circuit
+        let readings = Readings { essential_load_w: 900.0, battery_w: -2500.0, ..readings(0.0, 500.0, 3000.0, 50.0) };
+        assert_eq!(runtime_battery_w(RuntimeLoad::Total, &readings, -2400.0), -2400.0);
+        assert_eq!(runtime_battery_w(RuntimeLoad::Essential, &readings, -2400.0), -400.0);
+        let runtime = |load| runtime_minutes(50.0, 10.0, runtime_battery_w(load, &readings, readings.battery_w));
+        assert_eq!(runtime(RuntimeLoad::Total), 120.0);
+        assert_eq!(runtime(RuntimeLoad::Essential), 750.0);
+
+        // Instances without ESSENTIAL_LOAD report the whole load as essential
+        let status: StatusOutput = serde_json::from_value(json!({
+            "solar_panels": "500.0W",
+            "batteries": "50.0%",
+            "battery_status": "Discharging",
+            "battery_power": "2500.0W",
+            "grid_status": "Off",
+            "grid_power": "0.0W",
+            "home_consumption": "3000.0W",
+        })).unwrap();
+        assert_eq!(Readings::from_status(&status).field("essential_load_w"), Some(3000.0));
+    }
+
+    #[test]
+    fn battery_power_is_smoothed() {
+        assert_eq!(smooth(None, -1000.0), -1000.0);
+        assert_eq!(smooth(Some(-1000.0), 0.0), -700.0);
+    }
+
+    #[test]
+    fn server_options() {
+        let server = parse_server("me@desktop,site=house,action=suspend,wake=wol:aa:bb:cc:dd:ee:0f,check=ping").unwrap();
+        assert_eq!(server.host(), "desktop");
+        assert_eq!(server.action, ServerAction::Suspend);
+        assert_eq!(server.wake, WakeMethod::WakeOnLan([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x0f]));
+        assert_eq!(server.check, DownCheck::Ping);
+
+        let server = parse_server("root@nas,action=command:sudo /usr/local/bin/park").unwrap();
+        assert_eq!(server.action.remote_command(server.os), "sudo /usr/local/bin/park");
+        assert_eq!(server.check, DownCheck::SshPort);
+
+        assert!(parse_server("me@desktop,action=sleep").is_err());
+        assert!(parse_server("me@desktop,wake=wol:aa:bb").is_err());
+        assert!(parse_server("me@desktop,check=arp").is_err());
+    }
+
+    #[test]
+    fn shutdown_commands_per_os() {
+        let command = |value: &str| {
+            let server = parse_server(value).unwrap();
+            server.action.remote_command(server.os).to_string()
+        };
+        assert_eq!(command("me@desktop"), "sudo poweroff");
+        assert_eq!(command("me@desktop,os=linux-systemctl,action=suspend"), "systemctl suspend");
+        assert_eq!(command("admin@gaming-pc,os=windows"), "shutdown /s /t 0");
+        assert_eq!(command("admin@gaming-pc,os=windows,action=hibernate"), "shutdown /h");
+        assert_eq!(command("me@mac,os=custom,action=command:sudo shutdown -h now"), "sudo shutdown -h now");
+        assert!(parse_server("me@mac,os=custom").is_err());
+        assert!(parse_server("admin@gaming-pc,os=windows,action=suspend").is_err());
+        assert!(parse_server("me@desktop,os=bsd").is_err());
+        assert_eq!(parse_server("me@nas,os=linux-systemctl").unwrap().os.docker(), "docker");
+    }
+
+    #[tokio::test]
+    async fn ssh_port_check_follows_the_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = parse_server(&format!("me@127.0.0.1,port={}", port)).unwrap();
+        assert!(!server_is_down(&server, "/nonexistent/key").await);
+        drop(listener);
+        assert!(server_is_down(&server, "/nonexistent/key").await);
+        assert!(server_is_down(&parse_server("me@127.0.0.1,check=none").unwrap(), "/nonexistent/key").await);
+    }
+
+    #[tokio::test]
+    async fn shutdown_failure_hints() {
+        let hint = |stderr: &str, os| failure_hint(stderr, os).unwrap_or_default();
+        assert!(hint("sudo: a terminal is required to read the password; either use the -S option", HostOs::LinuxSudo)
+            .starts_with("sudo asked for a password"));
+        assert!(hint("Failed to power off system via logind: Interactive authentication required.", HostOs::LinuxSystemctl)
+            .contains("polkit rule"));
+        assert_eq!(hint("'sudo' is not recognized as an internal or external command,", HostOs::LinuxSudo),
+            "the host runs Windows; set os=windows");
+        assert_eq!(hint("bash: line 1: sudo: command not found", HostOs::LinuxSudo), "sudo isn't installed; use os=linux-systemctl");
+        assert!(hint("sh: 1: park: not found", HostOs::Custom).starts_with("park isn't installed"));
+        assert!(hint("Access is denied.(5)", HostOs::Windows).contains("Shut down the system"));
+        assert_eq!(failure_hint("Connection to nas closed by remote host.", HostOs::LinuxSudo), None);
+
+        let server = parse_server("me@desktop").unwrap();
+        let output = tokio::process::Command::new("sh").args(["-c", "echo 'sudo: a password is required' >&2; exit 1"]).output().await.unwrap();
+        let error = server.ssh_failure("Shutdown", &output).await.to_string();
+        assert!(error.starts_with("Shutdown failed on me@desktop: sudo asked for a password"));
+        assert!(!error.contains("sudo: a password is required"));
+    }
+
+    #[test]
+    fn server_docker_options() {
+        let server = parse_server("me@nas,docker=db+web,docker_grace=300").unwrap();
+        assert_eq!(server.docker, Some(DockerStop {
+            containers: Containers::Named(vec!["db".to_string(), "web".to_string()]),
+            grace: Duration::from_secs(300),
+            api: None,
+        }));
+
+        let server = parse_server("me@nas,docker_api=http://10.0.0.9:2375/,docker=all").unwrap();
+        let docker = server.docker.unwrap();
+        assert_eq!(docker.containers, Containers::All);
+        assert_eq!(docker.api.as_deref(), Some("http://10.0.0.9:2375"));
+
+        assert!(parse_server("me@nas,docker_api=http://10.0.0.9:2375").is_err());
+    }
+
+    #[test]
+    fn server_ssh_options() {
+        let server = parse_server("me@10.2.0.5,key=/keys/nas,user=admin,port=2222,via=jump@bastion:2200").unwrap();
+        assert_eq!(server.destination(), "admin@10.2.0.5");
+        assert_eq!(server.via, Some(JumpHost { user: Some("jump".to_string()), host: "bastion".to_string(), port: 2200 }));
+
+        let args = server.ssh_args("/default.key", "sudo poweroff");
+        assert_eq!(args[..2], ["-i", "/keys/nas"]);
+        assert!(args.contains(&"2222".to_string()));
+        assert!(args.contains(&"ProxyCommand=ssh -i /default.key -o StrictHostKeyChecking=no -p 2200 -W %h:%p jump@bastion".to_string()));
+        assert_eq!(args[args.len() - 2..], ["admin@10.2.0.5", "sudo poweroff"]);
+
+        assert_eq!(JumpHost::parse("[fd00::1]:22").unwrap().host, "fd00::1");
+        assert_eq!(JumpHost::parse("bastion").unwrap().port, 22);
+        assert!(parse_server("me@host,port=ssh").is_err());
+        assert!(parse_server("me@host,via=jump@:22").is_err());
+    }
+
+    #[test]
+    fn simulated_readings_from_args() {
+        let args: Vec<String> = ["--battery", "8", "--grid", "0", "--solar", "200", "--load", "900"]
+            .iter().map(|arg| arg.to_string()).collect();
+        assert_eq!(parse_simulated_readings(&args).unwrap(), readings(0.0, 200.0, 900.0, 8.0));
+        assert!(parse_simulated_readings(&args[..6]).is_err());
+        assert!(parse_simulated_readings(&["--wind".to_string(), "3".to_string()]).is_err());
+    }
+
+    #[test]
+    fn audit_log_appends_and_rotates() {
+        let dir = std::env::temp_dir().join(format!("solax-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let audit = AuditLog::new(Some(path.clone()), 200, 2);
+
+        for i in 0..10 {
+            audit.record("evaluation", json!({ "site": "house", "i": i }));
+        }
+        let last = audit.last(3);
+        assert_eq!(last.len(), 3);
+        assert_eq!(last[2]["i"], 9);
+        assert_eq!(last[2]["event"], "evaluation");
+
+        assert!(fs::metadata(&path).unwrap().len() < 200);
+        assert!(dir.join("audit.jsonl.1").exists());
+        assert!(dir.join("audit.jsonl.2").exists());
+        assert!(!dir.join("audit.jsonl.3").exists());
+        for line in fs::read_to_string(&path).unwrap().lines() {
+            serde_json::from_str::<Value>(line).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn status_message_fields() {
+        let alert = status_alert(&test_config(&["house", "cabin"]), &HashMap::from([
+            ("house".to_string(), readings(0.0, 200.0, 900.0, 55.0)),
+        ]));
+        assert_eq!(alert.fields, [
+            ("house".to_string(), "Grid 0W • Solar 200W • Load 900W • Battery 55%".to_string()),
+            ("cabin".to_string(), "No fresh data".to_string()),
+            ("total".to_string(), "No fresh data".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn sequence_steps_share_one_throttled_message() {
+        // Only Slack, so nothing is posted to Discord
+        let config = Config { slack_webhook_url: Some("https://hooks.slack.invalid".to_string()), ..test_config(&["house"]) };
+        let mut sequences = Sequences::default();
+        sequences.start(&config, "shutdown", "house").await;
+        for server in ["nas", "db"] {
+            sequences.step(&config, "house", server, Outcome::Running, format!("Waiting for {} to go down", server)).await;
+        }
+        sequences.step(&config, "house", "nas", Outcome::Done, "nas is down".to_string()).await;
+        sequences.step(&config, "house", "db", Outcome::Failed, "db is still up".to_string()).await;
+        sequences.step(&config, "cabin", "nas", Outcome::Done, "nas is down".to_string()).await;
+
+        let alert = sequences.running["house"].alert();
+        assert_eq!(alert.description.as_deref(), Some("✅ nas is down\n❌ db is still up"));
+        assert!(alert.severity == Severity::Warning);
+        assert_eq!(alert.fields, [("State".to_string(), "running".to_string())]);
+        assert_eq!(sequences.failures("house"), ["db is still up"]);
+        assert!(!sequences.is_running("cabin"));
+
+        sequences.settle(&config, "shutdown", &["house".to_string()]).await;
+        assert!(sequences.is_running("house"));
+        sequences.settle(&config, "recovery", &[]).await;
+        assert!(sequences.is_running("house"));
+        sequences.settle(&config, "shutdown", &[]).await;
+        assert!(!sequences.is_running("house"));
+
+        // Edits are spaced out while the sequence runs; the last one goes out right away
+        let mut sequence = Sequence::new("recovery", "house");
+        let now = std::time::Instant::now();
+        assert!(sequence.due(now));
+        sequence.last_edit = Some(now);
+        sequence.stale = false;
+        assert!(!sequence.due(now + SEQUENCE_EDIT_INTERVAL));
+        sequence.step("nas", Outcome::Done, "nas is up".to_string());
+        assert!(!sequence.due(now + Duration::from_secs(1)));
+        assert!(sequence.due(now + SEQUENCE_EDIT_INTERVAL));
+        sequence.end("done");
+        assert!(sequence.due(now + Duration::from_secs(1)));
+        assert_eq!(sequence.alert().fields, [("State".to_string(), "done".to_string())]);
+    }
+
+    #[test]
+    fn quiet_hours_window() {
+        let (start, end) = QuietHours::parse_range("22:00-07:00").unwrap();
+        let quiet = QuietHours { start, end, timezone: chrono_tz::Europe::Prague, floor: Severity::Info };
+        let at = |text: &str| text.parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+        // 06:30 Prague summer time
+        assert!(quiet.contains(at("2024-06-09T04:30:00Z")));
+        assert!(!quiet.contains(at("2024-06-09T05:30:00Z")));
+        assert!(quiet.contains(at("2024-06-09T20:00:00Z")));
+        assert!(!quiet.contains(at("2024-06-09T19:59:00Z")));
+        // An end skipped as the clocks go forward ends the window at the first valid instant
+        let (start, end) = QuietHours::parse_range("01:00-02:30").unwrap();
+        let spring = QuietHours { start, end, timezone: chrono_tz::Europe::Prague, floor: Severity::Info };
+        assert!(spring.contains(at("2024-03-31T00:59:00Z")));
+        assert!(!spring.contains(at("2024-03-31T01:00:00Z")));
+
+        assert!(quiet.holds(Severity::Info));
+        assert!(!quiet.holds(Severity::Warning));
+        assert!(!quiet.holds(Severity::Critical));
+        assert!(QuietHours::parse_range("22:00").is_err());
+    }
+
+    #[test]
+    fn quiet_hours_digest_is_bounded() {
+        let mut state = MonitorState::default();
+        for i in 0..MAX_HELD + 3 {
+            hold_notification(&mut state, &Alert::new(Severity::Info, format!("ping {}", i)));
+        }
+        assert_eq!(state.held.len(), MAX_HELD);
+        assert_eq!(state.held_dropped, 3);
+
+        let restored: MonitorState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(restored.held, state.held);
+
+        let digest = take_digest(&mut state, chrono_tz::UTC).unwrap();
+        let description = digest.description.unwrap();
+        assert!(description.contains("ping 3"));
+        assert!(!description.contains("ping 2\n"));
+        assert!(description.ends_with("(and 3 older notifications)"));
+        assert!(state.held.is_empty());
+        assert!(take_digest(&mut state, chrono_tz::UTC).is_none());
+    }
+
+    #[tokio::test]
+    async fn acknowledged_alert_stays_quiet_until_cleared() {
+        let config = Arc::new(Config {
+            control_url: Some("http://10.0.0.5:3001".to_string()),
+            control_token: Some("s3cret".to_string()),
+            ..test_config(&["house"])
+        });
+        let alert = Alert::new(Severity::Warning, "🔋 Low battery at house").id("low-battery-house".to_string());
+        let path = |id: &str| axum::extract::Path(id.to_string());
+
+        let (status, _) = ack_alert(axum::extract::State(config.clone()), bearer("s3cret"), path("low-battery-house")).await;
+        assert_eq!(status, axum::http::StatusCode::NOT_FOUND);
+
+        // The webhook fails here, but the condition is still recorded as active
+        notify(&config, &alert, "low battery warning").await;
+        assert!(!config.state.lock().unwrap().alerts["low-battery-house"].acknowledged);
+        // Only with the token
+        for headers in [axum::http::HeaderMap::new(), bearer("guess")] {
+            let (status, _) = ack_alert(axum::extract::State(config.clone()), headers, path("low-battery-house")).await;
+            assert_eq!(status, axum::http::StatusCode::UNAUTHORIZED);
+        }
+        assert!(!config.state.lock().unwrap().alerts["low-battery-house"].acknowledged);
+        let (status, _) = ack_alert(axum::extract::State(config.clone()), bearer("s3cret"), path("low-battery-house")).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        assert!(config.state.lock().unwrap().alerts["low-battery-house"].acknowledged);
+
+        clear_alert(&config, "low-battery-house");
+        assert!(config.state.lock().unwrap().alerts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn believed_power_state() {
+        let config = Arc::new(Config {
+            servers: vec![parse_server("me@desktop,site=house").unwrap(), parse_server("root@nas,site=house,check=none").unwrap()],
+            control_token: Some("s3cret".to_string()),
+            ..test_config(&["house"])
+        });
+        believe(&config, "me@desktop", Power::Down, "down_check");
+        assert!(config.state.lock().unwrap().believed_down("me@desktop"));
+        assert!(!config.state.lock().unwrap().believed_down("root@nas"));
+        assert!(config.metrics.lock().unwrap().servers_down.contains("me@desktop"));
+        // Believing the same again keeps when and how it was learnt
+        assert!(!config.state.lock().unwrap().set_power("me@desktop", Power::Down, "probe", 0));
+
+        let alert = status_alert(&config, &HashMap::new());
+        let (_, servers) = alert.fields.iter().find(|(name, _)| name == "Servers").unwrap();
+        assert!(servers.starts_with("🔴 me@desktop since "));
+        assert!(servers.ends_with("\n🟢 root@nas"));
+
+        let path = |name: &str| axum::extract::Path(name.to_string());
+        let (status, _) = mark_server_up(axum::extract::State(config.clone()), bearer("s3cret"), path("printer")).await;
+        assert_eq!(status, axum::http::StatusCode::NOT_FOUND);
+        let (status, _) = mark_server_up(axum::extract::State(config.clone()), axum::http::HeaderMap::new(), path("desktop")).await;
+        assert_eq!(status, axum::http::StatusCode::UNAUTHORIZED);
+        assert!(config.state.lock().unwrap().believed_down("me@desktop"));
+        let (status, _) = mark_server_up(axum::extract::State(config.clone()), bearer("s3cret"), path("desktop")).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        assert_eq!(config.state.lock().unwrap().servers["me@desktop"].via, "manual");
+        assert!(config.metrics.lock().unwrap().servers_down.is_empty());
+
+        let axum::Json(state) = get_state(axum::extract::State(config.clone())).await;
+        assert_eq!(state["servers"]["me@desktop"]["power"], "up");
+    }
+
+    #[test]
+    fn cached_readings_bridge_a_restart_within_the_trust_window() {
+        let path = std::env::temp_dir().join(format!("solax-monitor-cache-{}.json", std::process::id()));
+        let mut state = MonitorState::load(&path);
+        let readings = readings(0.0, 0.0, 900.0, 8.0);
+        state.last_readings.insert("house".to_string(), CachedReadings { readings, at: 1_700_000_000 });
+        state.save();
+
+        // Kept across a restart of the monitor, and only trusted for so long
+        let state = MonitorState::load(&path);
+        let trust = Duration::from_secs(300);
+        assert_eq!(state.trusted_readings("house", 1_700_000_120, trust), Some((readings, 120)));
+        assert_eq!(state.trusted_readings("house", 1_700_000_301, trust), None);
+        assert_eq!(state.trusted_readings("cabin", 1_700_000_120, trust), None);
+        fs::remove_file(&path).unwrap();
+
+        // Only the sources a rule reads make its actions ones on cached data
+        let rule = |text: &str| Rule { site: "cabin".to_string(), text: text.to_string(), expr: Expr::parse(text).unwrap() };
+        let cached = BTreeMap::from([("house".to_string(), 120)]);
+        assert!(cached_inputs(&rule("grid_w == 0"), "cabin", &cached).is_empty());
+        assert_eq!(cached_inputs(&rule("grid_w == 0 && house.battery_pct < 10"), "cabin", &cached), cached);
+        assert_eq!(cached_inputs(&rule("total.battery_pct < 10"), "cabin", &cached), cached);
+        assert_eq!(cached_inputs(&rule("grid_w == 0"), "house", &cached), cached);
+
+        let audit = AuditLog::new(None, 0, 0);
+        audit.acting_on_cached(Some(json!(cached)));
+        audit.action("shutdown", "me@desktop", &Ok(()));
+        audit.acting_on_cached(None);
+        audit.action("wake_on_lan", "me@desktop", &Ok(()));
+        let entries = audit.last(2);
+        assert_eq!(entries[0]["cached"], json!({"house": 120}));
+        assert!(entries[1].get("cached").is_none());
+    }
+
+    #[test]
+    fn self_shutdown_breadcrumb_is_read_once() {
+        let path = std::env::temp_dir().join(format!("solax-monitor-state-{}.json", std::process::id()));
+        let mut state = MonitorState::load(&path);
+        state.self_shutdown = Some(SelfShutdown { at: 1_700_000_000, sites: vec!["house".to_string()] });
+        state.save();
+
+        // The next start finds it, and the one after that doesn't
+        let mut state = MonitorState::load(&path);
+        assert_eq!(state.take_self_shutdown().map(|previous| previous.sites), Some(vec!["house".to_string()]));
+        assert_eq!(MonitorState::load(&path).take_self_shutdown(), None);
+        fs::remove_file(&path).unwrap();
+
+        let config = Config {
+            servers: vec![parse_server("root@nas,site=house").unwrap()],
+            shutdown_self: Some("systemctl poweroff".to_string()),
+            ..test_config(&["house"])
+        };
+        assert_eq!(shutdown_plan(&config, "house").last().unwrap(), "Once the servers are down, run \"systemctl poweroff\" on this host");
+    }
+
+    #[test]
+    fn monitor_metrics_rendering() {
+        let mut metrics = MonitorMetrics::default();
+        let low = readings(0.0, 200.0, 900.0, 8.0);
+        metrics.sites.insert("house".to_string(), SiteMetrics::evaluated(Some(&low), Some(true), BATTERY_LOW_PCT, true));
+        metrics.sites.insert("cabin".to_string(), SiteMetrics::evaluated(None, None, BATTERY_LOW_PCT, false));
+        metrics.servers_down.insert("me@10.0.0.71".to_string());
+        metrics.last_evaluation = Some(1_700_000_000);
+        metrics.notifications_failed = 2;
+
+        let text = metrics.render();
+        assert!(text.contains("solax_monitor_last_evaluation_timestamp_seconds 1700000000\n"));
+        assert!(text.contains("solax_monitor_rule_result{site=\"house\"} 1\n"));
+        assert!(text.contains("solax_monitor_grid_down{site=\"house\"} 1\n"));
+        assert!(text.contains("solax_monitor_solar_deficit{site=\"house\"} 1\n"));
+        assert!(text.contains("solax_monitor_battery_low{site=\"house\"} 1\n"));
+        assert!(!text.contains("solax_monitor_grid_down{site=\"cabin\"}"));
+        assert!(text.contains("solax_monitor_shutdown_triggered{site=\"cabin\"} 0\n"));
+        assert!(text.contains("solax_monitor_servers_down 1\n"));
+        assert!(!text.contains("\nsolax_monitor_last_shutdown_timestamp_seconds "));
+        assert!(text.contains("solax_monitor_notifications_total{outcome=\"failed\"} 2\n"));
+    }
+
+    #[test]
+    fn hook_options() {
+        let hook = parse_hook("pre_shutdown,timeout=30,required=true,site=house: ssh db 'pg_ctl stop'").unwrap();
+        assert_eq!(hook.point, HookPoint::PreShutdown);
+        assert_eq!(hook.command, "ssh db 'pg_ctl stop'");
+        assert_eq!(hook.timeout, Duration::from_secs(30));
+        assert!(hook.required);
+        assert_eq!(hook.site.as_deref(), Some("house"));
+
+        assert!(parse_hook("pre_shutdown").is_err());
+        assert!(parse_hook("before_shutdown: true").is_err());
+        assert!(parse_hook("post_poweron,retries=3: true").is_err());
+    }
+
+    #[tokio::test]
+    async fn hook_gets_readings_and_times_out() {
+        let readings = readings(0.0, 150.0, 900.0, 8.0);
+        let hook = parse_hook("pre_shutdown: test \"$SOLAX_SITE $SOLAX_BATTERY_PCT\" = \"house 8\"").unwrap();
+        assert!(run_hook(&hook, "house", Some(&readings)).await.is_ok());
+
+        let hook = parse_hook("pre_shutdown,timeout=1: sleep 5").unwrap();
+        assert!(run_hook(&hook, "house", Some(&readings)).await.is_err());
+    }
+
+    #[test]
+    fn status_url_from_ipv4_host_port() {
+        assert_eq!(parse_status_url("10.0.0.5:3000").unwrap(), "http://10.0.0.5:3000/status");
+    }
+
+    #[test]
+    fn status_url_from_bracketed_ipv6() {
+        assert_eq!(parse_status_url("[::1]:3000").unwrap(), "http://[::1]:3000/status");
+        assert_eq!(
+            parse_status_url("http://[fd00::10]:3000/status").unwrap(),
+            "http://[fd00::10]:3000/status"
+        );
+    }
+
+    #[test]
+    fn status_url_from_hostname() {
+        assert_eq!(parse_status_url("solax.lan:3000").unwrap(), "http://solax.lan:3000/status");
+        assert_eq!(
+            parse_status_url("https://solax.example.com/v1/status").unwrap(),
+            "https://solax.example.com/v1/status"
+        );
+    }
+
+    #[test]
+    fn status_url_rejects_unbracketed_ipv6() {
+        assert!(parse_status_url("::1:3000").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Turns successive snapshots into the changes an automation reacts to: derived states like
+//! the battery going from charging to discharging, and measurements moving further than
+//! their threshold since they were last reported.
+
+use crate::inverter::{Snapshot, Units};
+use crate::status::{Change, SnapshotDiff};
+use std::collections::{BTreeMap, HashMap};
+
+/// Power below which the battery, grid and solar count as idle. Leaving a flow takes
+/// dropping below half of it, so jitter around the edge doesn't flap the state.
+pub const IDLE_W: f64 = 50.0;
+
+/// How far a measurement has to move to count as a change, by unit. Energy counters grow
+/// every poll and the run mode is covered by its state, so they aren't reported by default.
+pub fn default_threshold(unit: Units) -> Option<f64> {
+    match unit {
+        Units::W => Some(200.0),
+        Units::V => Some(5.0),
+        Units::A => Some(2.0),
+        Units::Hz => Some(0.1),
+        Units::C => Some(2.0),
+        Units::Percent => Some(5.0),
+        Units::Kwh | Units::None => None,
+    }
+}
+
+/// Per-measurement overrides of the default thresholds; None turns a measurement off.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Thresholds {
+    pub overrides: BTreeMap<String, Option<f64>>,
+}
+
+impl Thresholds {
+    /// Parses `<measurement>:<threshold>` or `<measurement>:off`.
+    pub fn parse_override(value: &str) -> Result<(String, Option<f64>), String> {
+        let (metric, threshold) = value.rsplit_once(':')
+            .ok_or_else(|| format!("CHANGE_THRESHOLD must be <measurement>:<threshold|off>: {}", value))?;
+        let threshold = match threshold.trim() {
+            "off" => None,
+            threshold => Some(threshold.parse::<f64>().ok().filter(|t| *t >= 0.0)
+                .ok_or_else(|| format!("Invalid change threshold {:?} in {}", threshold, value))?),
+        };
+        Ok((metric.trim().to_string(), threshold))
+    }
+
+    pub fn for_measurement(&self, name: &str, unit: Units) -> Option<f64> {
+        match self.overrides.get(name) {
+            Some(threshold) => *threshold,
+            None => default_threshold(unit),
+        }
+    }
+}
+
+fn flow_state(power: f64, previous: Option<&str>, positive: &'static str, negative: &'static str) -> &'static str {
+    let stay = IDLE_W / 2.0;
+    match previous {
+        Some(state) if state == positive && power > stay => positive,
+        Some(state) if state == negative && power < -stay => negative,
+        _ if power > IDLE_W => positive,
+        _ if power < -IDLE_W => negative,
+        _ => "idle",
+    }
+}
+
+/// The baseline changes are measured against.
+#[derive(Debug, Default)]
+pub struct ChangeTracker {
+    states: BTreeMap<String, String>,
+    /// The value of each measurement when it was last reported (or first seen).
+    reported: HashMap<String, f64>,
+    previous_seq: Option<u64>,
+}
+
+impl ChangeTracker {
+    fn states(&self, snapshot: &Snapshot) -> Vec<(&'static str, String)> {
+        let previous = |name: &str| self.states.get(name).map(String::as_str);
+        let mut states = Vec::new();
+        if let Some(power) = snapshot.value("Battery Power") {
+            states.push(("battery", flow_state(power, previous("battery"), "charging", "discharging").to_string()));
+        }
+        // Positive Grid Power is export
+        if let Some(power) = snapshot.value("Grid Power") {
+            states.push(("grid", flow_state(power, previous("grid"), "exporting", "importing").to_string()));
+        }
+        if let Some(power) = snapshot.value("Total Solar Power") {
+            states.push(("solar", flow_state(power, previous("solar"), "producing", "idle").to_string()));
+        }
+        if let Some(mode) = snapshot.run_mode() {
+            states.push(("run_mode", format!("{:?}", mode)));
+        }
+        states
+    }
+
+    /// The changes since the previous snapshot, None for the first one, which only sets the
+    /// baseline.
+    pub fn observe(&mut self, snapshot: &Snapshot, thresholds: &Thresholds) -> Option<SnapshotDiff> {
+        let mut changes = Vec::new();
+        for (name, state) in self.states(snapshot) {
+            match self.states.insert(name.to_string(), state.clone()) {
+                Some(from) if from != state => changes.push(Change::State { name: name.to_string(), from, to: state }),
+                _ => {}
+            }
+        }
+
+        let mut names: Vec<&String> = snapshot.measurements.keys().collect();
+        names.sort();
+        for name in names {
+            let measurement = &snapshot.measurements[name];
+            let Some(threshold) = thresholds.for_measurement(name, measurement.unit) else { continue };
+            if !measurement.value.is_finite() {
+                continue;
+            }
+            match self.reported.get(name) {
+                Some(&from) if (measurement.value - from).abs() > threshold => {
+                    changes.push(Change::Value {
+                        metric: name.clone(),
+                        from,
+                        to: measurement.value,
+                        unit: measurement.unit.symbol().to_string(),
+                    });
+                    self.reported.insert(name.clone(), measurement.value);
+                }
+                Some(_) => {}
+                None => {
+                    self.reported.insert(name.clone(), measurement.value);
+                }
+            }
+        }
+
+        let previous_seq = self.previous_seq.replace(snapshot.observed.seq)?;
+        Some(SnapshotDiff { seq: snapshot.observed.seq, previous_seq, time: snapshot.observed.time, changes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inverter::Measurement;
+
+    fn snapshot(seq: u64, values: &[(&str, f64, Units)]) -> Snapshot {
+        let mut snapshot = Snapshot {
+            measurements: values.iter().map(|(name, value, unit)| (name.to_string(), Measurement::new(*value, *unit))).collect(),
+            data_len: 0,
+            partial: false,
+            sn: String::new(),
+            model: String::new(),
+            firmware: String::new(),
+            observed: Default::default(),
+            battery_modules: Vec::new(),
+        };
+        snapshot.observe(seq, 1_700_000_000 + seq * 60);
+        snapshot
+    }
+
+    #[test]
+    fn reports_state_changes_and_significant_moves() {
+        let thresholds = Thresholds::default();
+        let mut tracker = ChangeTracker::default();
+        let poll = |battery_w: f64, grid_w: f64, voltage: f64| vec![
+            ("Battery Power", battery_w, Units::W),
+            ("Grid Power", grid_w, Units::W),
+            ("Grid 1 Voltage", voltage, Units::V),
+            ("Yield Today", grid_w / 100.0, Units::Kwh),
+        ];
+        assert!(tracker.observe(&snapshot(1, &poll(1200.0, -450.0, 230.0)), &thresholds).is_none());
+
+        // Watt-level jitter stays quiet, and the baseline doesn't creep along with it
+        let diff = tracker.observe(&snapshot(2, &poll(1150.0, -320.0, 232.0)), &thresholds).unwrap();
+        assert_eq!((diff.seq, diff.previous_seq, diff.changes.len()), (2, 1, 0));
+        let diff = tracker.observe(&snapshot(3, &poll(1100.0, -240.0, 231.0)), &thresholds).unwrap();
+        assert_eq!(diff.changes, [Change::Value { metric: "Grid Power".to_string(), from: -450.0, to: -240.0, unit: "W".to_string() }]);
+
+        let diff = tracker.observe(&snapshot(4, &poll(-800.0, 600.0, 231.0)), &thresholds).unwrap();
+        assert_eq!(diff.changes[..2], [
+            Change::State { name: "battery".to_string(), from: "charging".to_string(), to: "discharging".to_string() },
+            Change::State { name: "grid".to_string(), from: "importing".to_string(), to: "exporting".to_string() },
+        ]);
+        assert_eq!(diff.changes.len(), 4);
+    }
+
+    #[test]
+    fn idle_band_has_hysteresis() {
+        let mut tracker = ChangeTracker::default();
+        let thresholds = Thresholds { overrides: [("Battery Power".to_string(), None)].into() };
+        let states: Vec<usize> = [60.0, 30.0, 20.0, 40.0, 60.0]
+            .iter()
+            .enumerate()
+            .map(|(seq, &watts)| tracker.observe(&snapshot(seq as u64, &[("Battery Power", watts, Units::W)]), &thresholds)
+                .map_or(0, |diff| diff.changes.len()))
+            .collect();
+        // Charging until below 25 W, then idle until above 50 W again
+        assert_eq!(states, [0, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn parses_overrides() {
+        assert_eq!(Thresholds::parse_override("Load/Generator Power:500"), Ok(("Load/Generator Power".to_string(), Some(500.0))));
+        assert_eq!(Thresholds::parse_override("Grid 1 Voltage: off"), Ok(("Grid 1 Voltage".to_string(), None)));
+        assert!(Thresholds::parse_override("Grid Power").is_err());
+        assert!(Thresholds::parse_override("Grid Power:-5").is_err());
+        let thresholds = Thresholds { overrides: [("Yield Today".to_string(), Some(1.0))].into() };
+        assert_eq!(thresholds.for_measurement("Yield Today", Units::Kwh), Some(1.0));
+        assert_eq!(thresholds.for_measurement("Yield Total", Units::Kwh), None);
+    }
+}
@@ -0,0 +1,95 @@
+//! The time the service runs on. The daemon reads the system clock; the soak run and tests
+//! use a manual clock that only moves when told to, so a simulated day takes no real time and
+//! debounce and hysteresis can be checked to the second.
+
+use chrono::{DateTime, Utc};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    /// Seconds since the Unix epoch.
+    fn unix_now(&self) -> u64;
+
+    /// A monotonic instant, for intervals and deadlines.
+    fn instant(&self) -> Instant;
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.unix_now() as i64, 0).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn unix_now(&self) -> u64 {
+        crate::unix_now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Starts at a given Unix time and moves by `advance` only; its instants move along with it.
+#[derive(Debug)]
+pub struct ManualClock {
+    unix_start: u64,
+    instant_start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new(unix_start: u64) -> Self {
+        Self { unix_start, instant_start: Instant::now(), elapsed: Mutex::new(Duration::ZERO) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner()) += by;
+    }
+
+    /// Moves to `unix`, which must not be before the current time.
+    pub fn advance_to(&self, unix: u64) {
+        let mut elapsed = self.elapsed.lock().unwrap_or_else(|e| e.into_inner());
+        *elapsed = (*elapsed).max(Duration::from_secs(unix.saturating_sub(self.unix_start)));
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Clock for ManualClock {
+    fn unix_now(&self) -> u64 {
+        self.unix_start + self.elapsed().as_secs()
+    }
+
+    fn instant(&self) -> Instant {
+        self.instant_start + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_moves_only_when_told() {
+        let clock = ManualClock::new(1_750_000_000);
+        let (unix, instant) = (clock.unix_now(), clock.instant());
+        assert_eq!((clock.unix_now(), clock.instant()), (unix, instant));
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.unix_now(), 1_750_000_090);
+        assert_eq!(clock.instant() - instant, Duration::from_secs(90));
+        assert_eq!(clock.utc_now().timestamp(), 1_750_000_090);
+        // Never backwards
+        clock.advance_to(1_750_000_000);
+        assert_eq!(clock.unix_now(), 1_750_000_090);
+        clock.advance_to(1_750_003_600);
+        assert_eq!(clock.instant() - instant, Duration::from_secs(3600));
+    }
+}
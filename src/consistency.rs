@@ -0,0 +1,298 @@
+//! Invariants a decoded snapshot holds whatever the inverter is doing. When several of them
+//! keep failing, and especially right after a firmware update, the register map most likely
+//! no longer fits the firmware and the values are garbage rather than readings.
+
+use crate::inverter::{Snapshot, Units};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Multiple of the rated power no power reading should reach.
+pub const POWER_MARGIN: f64 = 1.5;
+/// Invariants that have to fail in the same poll for the map to be suspect.
+pub const MIN_FAILING: usize = 2;
+/// Register values the firmware reports for a reading it doesn't have (-1, and the signed
+/// extremes), which decode to plausible looking numbers such as 6553.5 W.
+const SENTINELS: [u16; 3] = [0xFFFF, 0x7FFF, 0x8000];
+/// Polls a power register may keep the same non-zero value while other power readings change.
+pub const STUCK_POLLS: u32 = 30;
+
+/// The invariants `snapshot` breaks, by name, with the measurements breaking them.
+pub fn failing(snapshot: &Snapshot, rated_power_w: Option<f64>) -> Vec<(&'static str, String)> {
+    let mut names: Vec<&String> = snapshot.measurements.keys().collect();
+    names.sort();
+    let mut failing: Vec<(&'static str, String)> = Vec::new();
+    for name in names {
+        let measurement = &snapshot.measurements[name];
+        let value = measurement.value;
+        // The words of a 32-bit energy counter pass through these on the way up, and a signed
+        // register reads 0xFFFF for -1
+        let sentinel = !matches!(measurement.unit, Units::Kwh)
+            && measurement.raw.is_some_and(|raw| SENTINELS.contains(&(raw as u16)))
+            && value.abs() > 1.0;
+        let invariant = match measurement.unit {
+            _ if sentinel => "sentinel",
+            // PV and battery voltages have ranges of their own
+            Units::V if name.starts_with("Grid") && value != 0.0 && !(150.0..=300.0).contains(&value) => "grid_voltage",
+            Units::Hz if value != 0.0 && (value - 50.0).abs() > 5.0 && (value - 60.0).abs() > 5.0 => "frequency",
+            Units::Percent if name == "Battery Remaining Capacity" && !(0.0..=100.0).contains(&value) => "soc",
+            // The residual is a difference of the others, which covers it
+            Units::W if name != "Power Balance Residual"
+                && rated_power_w.is_some_and(|rated_w| value.abs() > rated_w * POWER_MARGIN) => "power",
+            _ => continue,
+        };
+        let detail = format!("{} {} {}", name, value, measurement.unit.symbol());
+        match failing.iter_mut().find(|(name, _)| *name == invariant) {
+            Some((_, details)) => {
+                details.push_str(", ");
+                details.push_str(&detail);
+            }
+            None => failing.push((invariant, detail)),
+        }
+    }
+    failing
+}
+
+/// A change of the checker's verdict.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transition {
+    /// Several invariants failed for the configured number of polls in a row.
+    Degraded {
+        firmware: String,
+        /// The firmware updated from, when the update came shortly before.
+        previous_firmware: Option<String>,
+        failing: Vec<String>,
+    },
+    Recovered,
+}
+
+/// Follows the invariants from poll to poll. The firmware is kept across restarts, so an
+/// update while the service was down still counts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Checker {
+    firmware: Option<String>,
+    /// The firmware replaced by the last update, until the map has held up under the new one.
+    previous_firmware: Option<String>,
+    #[serde(skip)]
+    failing_polls: u32,
+    #[serde(skip)]
+    passing_polls: u32,
+    /// Each power register's last value and the polls it has kept it while others changed.
+    #[serde(skip)]
+    repeats: HashMap<String, (f64, u32)>,
+    #[serde(skip)]
+    pub degraded: bool,
+    /// The invariants failing in the last poll.
+    #[serde(skip)]
+    pub failing: Vec<String>,
+}
+
+impl Checker {
+    pub fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save the consistency checker to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Checks a poll; `polls` is how many in a row it takes to change the verdict.
+    pub fn observe(&mut self, snapshot: &Snapshot, rated_power_w: Option<f64>, polls: u32) -> Option<Transition> {
+        if !snapshot.firmware.is_empty() {
+            match self.firmware.replace(snapshot.firmware.clone()) {
+                Some(previous) if previous != snapshot.firmware => {
+                    self.previous_firmware = Some(previous);
+                    self.passing_polls = 0;
+                }
+                _ => {}
+            }
+        }
+        let mut failing = failing(snapshot, rated_power_w);
+        if let Some(stuck) = self.track_stuck(snapshot) {
+            failing.push(("stuck", stuck));
+        }
+        self.failing = failing.iter().map(|(invariant, detail)| format!("{}: {}", invariant, detail)).collect();
+        if failing.len() >= MIN_FAILING {
+            self.failing_polls += 1;
+            self.passing_polls = 0;
+        } else {
+            self.failing_polls = 0;
+            self.passing_polls += 1;
+        }
+
+        if !self.degraded && self.failing_polls >= polls {
+            self.degraded = true;
+            return Some(Transition::Degraded {
+                firmware: snapshot.firmware.clone(),
+                previous_firmware: self.previous_firmware.clone(),
+                failing: self.failing.clone(),
+            });
+        }
+        if self.passing_polls >= polls {
+            // The map has held up under the new firmware
+            self.previous_firmware = None;
+            if self.degraded {
+                self.degraded = false;
+                return Some(Transition::Recovered);
+            }
+        }
+        None
+    }
+
+    /// The power registers that have read the same non-zero value for STUCK_POLLS polls in
+    /// which other power readings changed. Readings carried over from an earlier poll repeat
+    /// by design and aren't followed.
+    fn track_stuck(&mut self, snapshot: &Snapshot) -> Option<String> {
+        let mut readings: Vec<(&String, f64)> = snapshot.measurements.iter()
+            .filter(|(_, m)| matches!(m.unit, Units::W) && m.raw.is_some() && !m.observed.carried_over && m.value != 0.0)
+            .map(|(name, m)| (name, m.value))
+            .collect();
+        readings.sort_by(|a, b| a.0.cmp(b.0));
+        let others_moved = readings.iter()
+            .any(|(name, value)| self.repeats.get(*name).is_some_and(|(last, _)| last != value));
+
+        let mut stuck = Vec::new();
+        let mut repeats = HashMap::new();
+        for (name, value) in readings {
+            let polls = match self.repeats.get(name) {
+                Some((last, polls)) if *last == value => polls + u32::from(others_moved),
+                _ => 0,
+            };
+            if polls >= STUCK_POLLS {
+                stuck.push(format!("{} {} W", name, value));
+            }
+            repeats.insert(name.clone(), (value, polls));
+        }
+        self.repeats = repeats;
+        (!stuck.is_empty()).then(|| stuck.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inverter::Measurement;
+
+    fn snapshot(firmware: &str, values: &[(&str, f64, Units)]) -> Snapshot {
+        Snapshot {
+            measurements: values.iter().map(|(name, value, unit)| (name.to_string(), Measurement::new(*value, *unit))).collect(),
+            data_len: 0,
+            partial: false,
+            sn: String::new(),
+            model: String::new(),
+            firmware: firmware.to_string(),
+            observed: Default::default(),
+            battery_modules: Vec::new(),
+        }
+    }
+
+    fn poll(firmware: &str, grid_w: f64, voltage: f64, soc: f64) -> Snapshot {
+        snapshot(firmware, &[
+            ("Grid Power", grid_w, Units::W),
+            ("Grid 1 Voltage", voltage, Units::V),
+            ("Grid 1 Frequency", 50.01, Units::Hz),
+            ("PV1 Voltage", 420.0, Units::V),
+            ("Battery Remaining Capacity", soc, Units::Percent),
+        ])
+    }
+
+    #[test]
+    fn finds_broken_invariants() {
+        assert!(failing(&poll("3.008.10", -450.0, 231.0, 64.0), Some(10_000.0)).is_empty());
+        // A grid that is down reads 0 V, which is fine
+        assert!(failing(&poll("3.008.10", 0.0, 0.0, 64.0), Some(10_000.0)).is_empty());
+        let broken = failing(&poll("3.009.02", 655_350.0, 6553.5, 655.0), Some(10_000.0));
+        assert_eq!(broken.iter().map(|(name, _)| *name).collect::<Vec<_>>(), ["soc", "grid_voltage", "power"]);
+        assert_eq!(broken[1].1, "Grid 1 Voltage 6553.5 V");
+        // Without the rated power the power readings can't be checked
+        assert_eq!(failing(&poll("3.009.02", 655_350.0, 231.0, 64.0), None), []);
+        assert_eq!(failing(&snapshot("", &[("Grid 2 Frequency", 59.9, Units::Hz)]), None), []);
+    }
+
+    /// A snapshot of power registers read as `(name, decoded value, Data entry)`.
+    fn registers(values: &[(&str, f64, i32)]) -> Snapshot {
+        let mut snapshot = snapshot("3.008.10", &[]);
+        for (name, value, raw) in values {
+            snapshot.measurements.insert(name.to_string(), Measurement { raw: Some(*raw), ..Measurement::new(*value, Units::W) });
+        }
+        snapshot
+    }
+
+    #[test]
+    fn finds_sentinel_registers() {
+        // 0xFFFF at a 0.1 W register is well below 1.5 times a 10 kW rating
+        let unset = registers(&[("Battery Power", 6553.5, 0xFFFF), ("Grid Power", -450.0, 65086)]);
+        assert_eq!(failing(&unset, Some(10_000.0)), [("sentinel", "Battery Power 6553.5 W".to_string())]);
+        let mut without_raw = unset.clone();
+        without_raw.measurements.get_mut("Battery Power").unwrap().raw = None;
+        assert_eq!(failing(&without_raw, Some(10_000.0)), []);
+
+        let extremes = registers(&[("PV1 Power", 32767.0, 0x7FFF), ("Grid Power", -32768.0, 0x8000), ("Load Power", 6553.5, -1)]);
+        let broken = failing(&extremes, None);
+        assert_eq!(failing(&registers(&[("Grid Power", -1.0, 0xFFFF)]), None), []);
+        assert_eq!(broken, [("sentinel", "Grid Power -32768 W, Load Power 6553.5 W, PV1 Power 32767 W".to_string())]);
+
+        // A counter's low word passes through 0xFFFF
+        let mut counter = snapshot("3.008.10", &[]);
+        counter.measurements.insert("Feed In Energy".to_string(), Measurement { raw: Some(0xFFFF), ..Measurement::new(6553.5, Units::Kwh) });
+        assert_eq!(failing(&counter, None), []);
+    }
+
+    #[test]
+    fn finds_stuck_registers() {
+        let mut checker = Checker::default();
+        let poll = |grid_w: f64| registers(&[("Grid Power", grid_w, 0), ("PV1 Power", 1234.0, 1234), ("Battery Power", 0.0, 0)]);
+        // Nothing else moving, as at night or with the dongle repeating itself, doesn't count
+        for _ in 0..STUCK_POLLS * 2 {
+            checker.observe(&poll(-450.0), Some(10_000.0), 3);
+        }
+        assert_eq!(checker.failing, Vec::<String>::new());
+        for n in 1..STUCK_POLLS {
+            checker.observe(&poll(-450.0 + n as f64), Some(10_000.0), 3);
+        }
+        assert_eq!(checker.failing, Vec::<String>::new());
+        checker.observe(&poll(-450.0 + STUCK_POLLS as f64), Some(10_000.0), 3);
+        assert_eq!(checker.failing, ["stuck: PV1 Power 1234 W"]);
+
+        // A value read in this poll starts the count again
+        checker.observe(&poll(-450.0), Some(10_000.0), 3);
+        checker.observe(&registers(&[("Grid Power", -400.0, 0), ("PV1 Power", 1240.0, 1240)]), Some(10_000.0), 3);
+        assert_eq!(checker.failing, Vec::<String>::new());
+    }
+
+    #[test]
+    fn blames_a_recent_firmware_update() {
+        let mut checker = Checker::default();
+        for _ in 0..3 {
+            assert_eq!(checker.observe(&poll("3.008.10", -450.0, 231.0, 64.0), Some(10_000.0), 3), None);
+        }
+        assert!(checker.observe(&poll("3.009.02", 655_350.0, 6553.5, 64.0), Some(10_000.0), 3).is_none());
+        // A single failing invariant isn't enough
+        assert!(checker.observe(&poll("3.009.02", -450.0, 6553.5, 64.0), Some(10_000.0), 3).is_none());
+        assert!(checker.observe(&poll("3.009.02", 655_350.0, 6553.5, 64.0), Some(10_000.0), 3).is_none());
+        assert!(checker.observe(&poll("3.009.02", 655_350.0, 6553.5, 64.0), Some(10_000.0), 3).is_none());
+        let Some(Transition::Degraded { firmware, previous_firmware, failing }) =
+            checker.observe(&poll("3.009.02", 655_350.0, 6553.5, 64.0), Some(10_000.0), 3)
+        else {
+            panic!("expected the map to be suspect");
+        };
+        assert_eq!((firmware.as_str(), previous_firmware.as_deref(), failing.len()), ("3.009.02", Some("3.008.10"), 2));
+        assert!(checker.degraded);
+        assert!(checker.observe(&poll("3.009.02", 655_350.0, 6553.5, 64.0), Some(10_000.0), 3).is_none());
+
+        // Fixed by the next update
+        for _ in 0..2 {
+            assert!(checker.observe(&poll("3.009.05", -450.0, 231.0, 64.0), Some(10_000.0), 3).is_none());
+        }
+        assert_eq!(checker.observe(&poll("3.009.05", -450.0, 231.0, 64.0), Some(10_000.0), 3), Some(Transition::Recovered));
+        assert_eq!(checker.previous_firmware, None);
+    }
+}
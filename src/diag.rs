@@ -0,0 +1,244 @@
+//! Bundles for bug reports: one poll as the inverter sent it and as it was decoded, with what
+//! the decoding depends on, so that a maintainer can replay it through the decoder. Serials
+//! and IP addresses are redacted unless asked otherwise.
+
+use crate::inverter::{InverterResponse, LoadSource, PowerSigns, SocCalibration};
+use crate::status::{DecodeOutput, InfoOutput, RawOutput, StatusOutput};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+/// Failed polls kept for the bundle.
+pub const FETCH_ERRORS_KEPT: usize = 20;
+/// What a redacted serial or address reads as.
+pub const REDACTED: &str = "REDACTED";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchError {
+    pub time: u64,
+    pub error: String,
+}
+
+/// The latest failed polls, oldest first, kept across restarts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FetchErrors {
+    pub errors: Vec<FetchError>,
+}
+
+impl FetchErrors {
+    pub fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save the fetch errors to {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn record(&mut self, time: u64, error: String) {
+        self.errors.push(FetchError { time, error });
+        let excess = self.errors.len().saturating_sub(FETCH_ERRORS_KEPT);
+        self.errors.drain(..excess);
+    }
+}
+
+/// The settings decoding depends on besides the response.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Decoding {
+    pub power_signs: PowerSigns,
+    pub soc_calibration: SocCalibration,
+    pub load_source: LoadSource,
+    /// Start of the block of battery modules, None when it isn't decoded.
+    #[serde(default)]
+    pub battery_modules: Option<usize>,
+    /// Where the BMS current limits are read, None when they aren't.
+    #[serde(default)]
+    pub bms_limits: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Redaction {
+    pub serial: bool,
+    pub ips: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    /// The version of solax-mon that made the bundle.
+    pub version: String,
+    pub created: u64,
+    pub redacted: Redaction,
+    pub model: String,
+    pub firmware: String,
+    pub info: Option<InfoOutput>,
+    pub decoding: Decoding,
+    /// The source that answered; null when the poll failed, as are the fields after it.
+    pub source: Option<String>,
+    pub response: Option<InverterResponse>,
+    /// Every measurement, under its canonical name.
+    pub measurements: Option<RawOutput>,
+    pub status: Option<StatusOutput>,
+    pub decode: Option<DecodeOutput>,
+    /// The configuration entries, with secrets masked.
+    pub config: Vec<String>,
+    pub fetch_errors: Vec<FetchError>,
+}
+
+impl Bundle {
+    /// The bundle as JSON, with `serials` and addresses redacted as `redacted` says.
+    pub fn to_json(&self, serials: &[String]) -> Result<String, serde_json::Error> {
+        let mut value = serde_json::to_value(self)?;
+        let serials: &[String] = if self.redacted.serial { serials } else { &[] };
+        redact(&mut value, serials, self.redacted.ips);
+        serde_json::to_string_pretty(&value)
+    }
+}
+
+/// Replaces `serials` and, with `ips`, IPv4 addresses in every string of `value`.
+pub fn redact(value: &mut Value, serials: &[String], ips: bool) {
+    match value {
+        Value::String(text) => {
+            for serial in serials.iter().filter(|serial| !serial.is_empty()) {
+                *text = text.replace(serial.as_str(), REDACTED);
+            }
+            if ips {
+                *text = redact_ips(text);
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|value| redact(value, serials, ips)),
+        Value::Object(fields) => fields.values_mut().for_each(|value| redact(value, serials, ips)),
+        _ => {}
+    }
+}
+
+/// `text` with every dotted IPv4 address replaced. Versions like 3.008.10 have too few parts
+/// to be one.
+pub fn redact_ips(text: &str) -> String {
+    let is_address = |run: &str| {
+        let parts: Vec<&str> = run.split('.').collect();
+        parts.len() == 4 && parts.iter().all(|part| (1..=3).contains(&part.len()) && part.parse::<u8>().is_ok())
+    };
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        let run = &rest[..end];
+        // A trailing dot ends a sentence rather than the address
+        let address = run.trim_end_matches('.');
+        if is_address(address) {
+            out.push_str(REDACTED);
+            out.push_str(&run[address.len()..]);
+        } else {
+            out.push_str(run);
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The measurements decoded differently by the replay, as (name, bundled, replayed).
+pub fn differences(bundled: &RawOutput, replayed: &RawOutput) -> Vec<(String, Option<f64>, Option<f64>)> {
+    let mut names: Vec<&String> = bundled.measurements.keys().chain(replayed.measurements.keys()).collect();
+    names.sort();
+    names.dedup();
+    names.into_iter()
+        .filter_map(|name| {
+            let bundled = bundled.measurements.get(name).map(|m| m.value);
+            let replayed = replayed.measurements.get(name).map(|m| m.value);
+            let same = match (bundled, replayed) {
+                (Some(a), Some(b)) => (a - b).abs() < 1e-6,
+                _ => false,
+            };
+            (!same).then(|| (name.clone(), bundled, replayed))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redacts_serials_and_addresses() {
+        assert_eq!(redact_ips("http://192.168.1.40/ failed"), "http://REDACTED/ failed");
+        assert_eq!(redact_ips("10.0.0.7:80, firmware 3.008.10, 1.2.3.4."), "REDACTED:80, firmware 3.008.10, REDACTED.");
+        assert_eq!(redact_ips("300.1.1.1 and 1.2.3.4.5"), "300.1.1.1 and 1.2.3.4.5");
+
+        let mut value = json!({
+            "sn": "H34A10I1234567",
+            "config": ["INVERTER_URL=http://192.168.1.40", "SERIAL=SWABCDEFGH"],
+            "information": [10.0, 14, "SWABCDEFGH"],
+        });
+        let serials = ["H34A10I1234567".to_string(), "SWABCDEFGH".to_string(), String::new()];
+        redact(&mut value, &serials, false);
+        assert_eq!(value, json!({
+            "sn": "REDACTED",
+            "config": ["INVERTER_URL=http://192.168.1.40", "SERIAL=REDACTED"],
+            "information": [10.0, 14, "REDACTED"],
+        }));
+        redact(&mut value, &[], true);
+        assert_eq!(value["config"][0], "INVERTER_URL=http://REDACTED");
+    }
+
+    #[test]
+    fn bundle_reads_back_redacted() {
+        let bundle = Bundle {
+            version: "0.1.0".to_string(),
+            created: 1_700_000_000,
+            redacted: Redaction { serial: true, ips: true },
+            model: "X3-Hybrid-G4".to_string(),
+            firmware: "3.008.10".to_string(),
+            info: None,
+            decoding: Decoding {
+                power_signs: PowerSigns { grid_import_positive: true, battery_discharge_positive: false },
+                soc_calibration: SocCalibration { floor_pct: 10.0, ceil_pct: 95.0 },
+                load_source: LoadSource::Computed,
+                battery_modules: None,
+                bms_limits: None,
+            },
+            source: Some("http://192.168.1.40".to_string()),
+            response: Some(InverterResponse {
+                inverter_type: 14,
+                sn: "SWABCDEFGH".to_string(),
+                ver: "3.008.10".to_string(),
+                data: vec![2310, 2305, 2298],
+                information: json!([10.0, 14, "H34A10I1234567"]),
+            }),
+            measurements: None,
+            status: None,
+            decode: None,
+            config: vec!["SERIAL=SWABCDEFGH".to_string()],
+            fetch_errors: vec![FetchError { time: 1_699_999_000, error: "http://192.168.1.40/: timed out".to_string() }],
+        };
+        let json = bundle.to_json(&["SWABCDEFGH".to_string(), "H34A10I1234567".to_string()]).unwrap();
+        assert!(!json.contains("SWABCDEFGH") && !json.contains("H34A10I1234567") && !json.contains("192.168"));
+
+        let read: Bundle = serde_json::from_str(&json).unwrap();
+        let response = read.response.unwrap();
+        assert_eq!((response.sn.as_str(), response.data), (REDACTED, vec![2310, 2305, 2298]));
+        assert_eq!(read.decoding.load_source, LoadSource::Computed);
+        assert!(read.decoding.power_signs.grid_import_positive);
+        assert_eq!(read.fetch_errors[0].error, "http://REDACTED/: timed out");
+    }
+
+    #[test]
+    fn keeps_the_latest_fetch_errors() {
+        let mut errors = FetchErrors::default();
+        for time in 0..30 {
+            errors.record(time, format!("timeout {}", time));
+        }
+        assert_eq!(errors.errors.len(), FETCH_ERRORS_KEPT);
+        assert_eq!(errors.errors[0], FetchError { time: 10, error: "timeout 10".to_string() });
+    }
+}
@@ -0,0 +1,263 @@
+//! The one task allowed to talk to the inverter dongle. The dongle firmware falls over on
+//! overlapping requests, so the poller and the control writes send commands over a channel
+//! and this task runs them one at a time, with the inverter's minimum spacing between
+//! any two requests.
+
+use crate::inverter::{BatteryMode, DongleProtocol, InverterInfo, Snapshot, X3HybridG4};
+use crate::status::{DecodeOutput, InverterSettings, SourceHealth, StatusOutput};
+use tokio::sync::{mpsc, oneshot};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Commands queued before the task gets to them; senders wait beyond this.
+const QUEUE: usize = 16;
+
+/// A setting written to the inverter and confirmed by reading it back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Write {
+    ExportLimit(u32),
+    BatteryMode(BatteryMode),
+    ChargePowerLimit(u32),
+}
+
+/// A setting read from the inverter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setting {
+    ExportLimit,
+    BatteryMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SettingValue {
+    /// The export limit in watts; also what a confirmed export limit write returns.
+    Watts(u32),
+    BatteryMode(Option<BatteryMode>),
+    /// A confirmed write without a value to report.
+    Done,
+}
+
+/// A successful poll with what the poller needs from the inverter alongside the snapshot.
+#[derive(Debug)]
+pub struct Polled {
+    pub snapshot: Snapshot,
+    /// URL of the source that answered.
+    pub source: String,
+    pub status: StatusOutput,
+    pub info: Option<InverterInfo>,
+    /// The response register by register, for /debug/decode.
+    pub decode: Option<DecodeOutput>,
+    pub protocol: DongleProtocol,
+}
+
+#[derive(Debug)]
+pub struct PollReply {
+    pub result: Result<Polled, Error>,
+    /// Every source's health after the poll.
+    pub sources: Vec<SourceHealth>,
+}
+
+enum Command {
+    Poll(oneshot::Sender<PollReply>),
+    Write(Write, oneshot::Sender<Result<SettingValue, Error>>),
+    ReadSetting(Setting, oneshot::Sender<Result<SettingValue, Error>>),
+    ReadSettings(oneshot::Sender<Result<InverterSettings, Error>>),
+}
+
+/// Sends commands to the dongle task; cheap to clone.
+#[derive(Clone)]
+pub struct Handle {
+    commands: mpsc::Sender<Command>,
+}
+
+/// Starts the task owning `inverter`. It runs until every handle is dropped.
+pub fn spawn(inverter: X3HybridG4, password: String) -> Handle {
+    let (commands, receiver) = mpsc::channel(QUEUE);
+    tokio::spawn(run(inverter, password, receiver));
+    Handle { commands }
+}
+
+async fn run(mut inverter: X3HybridG4, password: String, mut commands: mpsc::Receiver<Command>) {
+    while let Some(command) = commands.recv().await {
+        // A caller that gave up waiting only loses its reply; the request itself went out
+        match command {
+            Command::Poll(reply) => {
+                let result = inverter.fetch_data(&password).await.map(|(snapshot, source)| Polled {
+                    status: inverter.format_status(&snapshot),
+                    info: inverter.info.clone(),
+                    decode: inverter.last_response.as_ref().map(|response| inverter.explain(response, &snapshot)),
+                    protocol: inverter.active_protocol(),
+                    snapshot,
+                    source,
+                });
+                let _ = reply.send(PollReply { result, sources: inverter.sources.clone() });
+            }
+            Command::Write(write, reply) => {
+                let result = match write {
+                    Write::ExportLimit(watts) => inverter.set_export_limit(&password, watts).await.map(SettingValue::Watts),
+                    Write::BatteryMode(mode) => inverter.set_battery_mode(&password, mode).await.map(|_| SettingValue::Done),
+                    Write::ChargePowerLimit(watts) => {
+                        inverter.set_charge_power_limit(&password, watts).await.map(|_| SettingValue::Done)
+                    }
+                };
+                let _ = reply.send(result);
+            }
+            Command::ReadSetting(setting, reply) => {
+                let result = match setting {
+                    Setting::ExportLimit => inverter.read_export_limit(&password).await.map(SettingValue::Watts),
+                    Setting::BatteryMode => inverter.read_battery_mode(&password).await.map(SettingValue::BatteryMode),
+                };
+                let _ = reply.send(result);
+            }
+            Command::ReadSettings(reply) => {
+                let _ = reply.send(inverter.read_all_settings(&password).await);
+            }
+        }
+    }
+}
+
+impl Handle {
+    async fn request<T>(&self, command: Command, reply: oneshot::Receiver<T>) -> Result<T, Error> {
+        self.commands.send(command).await.map_err(|_| "The inverter task has stopped")?;
+        Ok(reply.await.map_err(|_| "The inverter task dropped the request")?)
+    }
+
+    pub async fn poll(&self) -> PollReply {
+        let (sender, reply) = oneshot::channel();
+        match self.request(Command::Poll(sender), reply).await {
+            Ok(reply) => reply,
+            Err(e) => PollReply { result: Err(e), sources: Vec::new() },
+        }
+    }
+
+    pub async fn write(&self, write: Write) -> Result<SettingValue, Error> {
+        let (sender, reply) = oneshot::channel();
+        self.request(Command::Write(write, sender), reply).await?
+    }
+
+    pub async fn read_setting(&self, setting: Setting) -> Result<SettingValue, Error> {
+        let (sender, reply) = oneshot::channel();
+        self.request(Command::ReadSetting(setting, sender), reply).await?
+    }
+
+    /// Writes the export limit and returns the limit the inverter confirmed.
+    pub async fn set_export_limit(&self, watts: u32) -> Result<u32, Error> {
+        match self.write(Write::ExportLimit(watts)).await? {
+            SettingValue::Watts(watts) => Ok(watts),
+            other => Err(format!("Unexpected reply {:?} to an export limit write", other).into()),
+        }
+    }
+
+    pub async fn read_export_limit(&self) -> Result<u32, Error> {
+        match self.read_setting(Setting::ExportLimit).await? {
+            SettingValue::Watts(watts) => Ok(watts),
+            other => Err(format!("Unexpected reply {:?} to an export limit read", other).into()),
+        }
+    }
+
+    pub async fn set_battery_mode(&self, mode: BatteryMode) -> Result<(), Error> {
+        self.write(Write::BatteryMode(mode)).await.map(|_| ())
+    }
+
+    pub async fn read_battery_mode(&self) -> Result<Option<BatteryMode>, Error> {
+        match self.read_setting(Setting::BatteryMode).await? {
+            SettingValue::BatteryMode(mode) => Ok(mode),
+            other => Err(format!("Unexpected reply {:?} to a battery mode read", other).into()),
+        }
+    }
+
+    pub async fn set_charge_power_limit(&self, watts: u32) -> Result<(), Error> {
+        self.write(Write::ChargePowerLimit(watts)).await.map(|_| ())
+    }
+
+    /// Every decoded setting, for the settings poller.
+    pub async fn read_settings(&self) -> Result<InverterSettings, Error> {
+        let (sender, reply) = oneshot::channel();
+        self.request(Command::ReadSettings(sender), reply).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// When each request arrived and left, and the most requests seen in flight at once.
+    #[derive(Default)]
+    struct Traffic {
+        spans: Mutex<Vec<(Instant, Instant)>>,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    /// A dongle that takes `delay` over every request, answering reads with the fixture
+    /// or a settings array and writes with "Y".
+    async fn slow_dongle(delay: Duration) -> (String, Arc<Traffic>) {
+        let traffic = Arc::new(Traffic::default());
+        let seen = traffic.clone();
+        let app = axum::Router::new().route("/", axum::routing::post(move |form: String| {
+            let traffic = seen.clone();
+            async move {
+                let start = Instant::now();
+                let now = traffic.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                traffic.max_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(delay).await;
+                traffic.in_flight.fetch_sub(1, Ordering::SeqCst);
+                traffic.spans.lock().unwrap().push((start, Instant::now()));
+                if form.contains("optType=ReadRealTimeData") {
+                    include_str!("../tests/fixtures/x3_hybrid_g4.json").to_string()
+                } else if form.contains("optType=ReadSetData") {
+                    serde_json::json!({ "Data": vec![0; 40] }).to_string()
+                } else {
+                    "Y".to_string()
+                }
+            }
+        }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        (url, traffic)
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_are_serialized_and_spaced() {
+        let spacing = Duration::from_millis(50);
+        let (url, traffic) = slow_dongle(Duration::from_millis(20)).await;
+        let handle = spawn(X3HybridG4::new(&[url], spacing), "SXXXXXXXXX".to_string());
+
+        let (first, second, limit, mode) = tokio::join!(
+            handle.poll(),
+            handle.poll(),
+            handle.read_export_limit(),
+            handle.read_battery_mode(),
+        );
+        assert!(first.result.is_ok() && second.result.is_ok());
+        assert_eq!(first.sources[0].up, Some(true));
+        assert_eq!(limit.unwrap(), 0);
+        assert!(mode.is_ok());
+        // A write is a request plus the read back, both spaced like the rest
+        handle.set_charge_power_limit(0).await.unwrap();
+
+        assert_eq!(traffic.max_in_flight.load(Ordering::SeqCst), 1);
+        let mut spans = traffic.spans.lock().unwrap().clone();
+        spans.sort();
+        assert_eq!(spans.len(), 6);
+        for pair in spans.windows(2) {
+            let ((previous_start, previous_end), (start, _)) = (pair[0], pair[1]);
+            assert!(start >= previous_end, "requests overlapped");
+            assert!(start - previous_start >= spacing, "requests {:?} apart", start - previous_start);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_poll_still_reports_the_sources() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let handle = spawn(X3HybridG4::new(std::slice::from_ref(&url), Duration::ZERO), String::new());
+        let reply = handle.poll().await;
+        assert!(reply.result.is_err());
+        assert_eq!(reply.sources, [SourceHealth { url, up: Some(false) }]);
+    }
+}
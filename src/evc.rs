@@ -0,0 +1,180 @@
+//! The Solax EV charger (X1/X3-EVC), which answers the same local API as the inverter dongle.
+
+use crate::inverter::write_registers;
+use crate::status::EvcStatusOutput;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Data index of the charger state (see EvcState).
+const STATE_INDEX: usize = 0;
+/// Data index of the total charging power in watts.
+const POWER_INDEX: usize = 11;
+/// Data index of the energy charged in the current session, in 0.1 kWh.
+const SESSION_ENERGY_INDEX: usize = 12;
+
+/// Holding register that starts (1) or stops (2) charging.
+const CHARGE_CONTROL_REGISTER: u16 = 0x0627;
+const CHARGE_START: u32 = 1;
+const CHARGE_STOP: u32 = 2;
+
+#[derive(Debug, Deserialize)]
+pub struct EvcResponse {
+    pub sn: String,
+    #[serde(rename = "Data")]
+    pub data: Vec<i32>,
+}
+
+/// What the charger reports it is doing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvcState {
+    Available,
+    Preparing,
+    Charging,
+    Finishing,
+    Faulted,
+    Unavailable,
+    Reserved,
+    SuspendedEv,
+    SuspendedEvse,
+    Unknown(i32),
+}
+
+impl EvcState {
+    pub fn from_value(value: i32) -> Self {
+        match value {
+            0 => EvcState::Available,
+            1 => EvcState::Preparing,
+            2 => EvcState::Charging,
+            3 => EvcState::Finishing,
+            4 => EvcState::Faulted,
+            5 => EvcState::Unavailable,
+            6 => EvcState::Reserved,
+            7 => EvcState::SuspendedEv,
+            8 => EvcState::SuspendedEvse,
+            other => EvcState::Unknown(other),
+        }
+    }
+
+    /// The coarse state published on /evc/status: idle, charging, fault or unavailable.
+    pub fn summary(self) -> &'static str {
+        match self {
+            EvcState::Charging => "charging",
+            EvcState::Faulted => "fault",
+            EvcState::Unavailable | EvcState::Unknown(_) => "unavailable",
+            _ => "idle",
+        }
+    }
+
+    /// Whether a car is connected, charging or not.
+    pub fn plugged(self) -> bool {
+        matches!(
+            self,
+            EvcState::Preparing | EvcState::Charging | EvcState::Finishing | EvcState::SuspendedEv | EvcState::SuspendedEvse
+        )
+    }
+}
+
+/// The decoded result of one charger poll.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvcSnapshot {
+    pub sn: String,
+    pub state: EvcState,
+    pub charging_power_w: f64,
+    pub session_energy_kwh: f64,
+}
+
+impl EvcSnapshot {
+    pub fn decode(response: &EvcResponse) -> Result<Self, String> {
+        let value = |index: usize| response.data.get(index)
+            .copied()
+            .ok_or_else(|| format!("Charger Data array has only {} entries", response.data.len()));
+        Ok(Self {
+            sn: response.sn.clone(),
+            state: EvcState::from_value(value(STATE_INDEX)?),
+            charging_power_w: f64::from(value(POWER_INDEX)?),
+            session_energy_kwh: f64::from(value(SESSION_ENERGY_INDEX)?) / 10.0,
+        })
+    }
+
+    /// The published view, with the home consumption split into the car and the rest.
+    pub fn to_output(&self, home_consumption_w: Option<f64>) -> EvcStatusOutput {
+        EvcStatusOutput {
+            labels: BTreeMap::from([("device".to_string(), "evc".to_string()), ("sn".to_string(), self.sn.clone())]),
+            state: self.state.summary().to_string(),
+            plugged: self.state.plugged(),
+            charging_power_w: self.charging_power_w,
+            session_energy_kwh: self.session_energy_kwh,
+            home_consumption_w,
+            home_consumption_without_evc_w: home_consumption_w.map(|load| (load - self.charging_power_w).max(0.0)),
+        }
+    }
+}
+
+pub struct EvCharger {
+    url: String,
+    client: Client,
+    pub request_timeout: Duration,
+}
+
+impl EvCharger {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_string(), client: Client::builder().no_proxy().build().unwrap_or_default(), request_timeout: Duration::from_secs(10) }
+    }
+
+    pub async fn fetch(&self, password: &str) -> Result<EvcSnapshot, Box<dyn std::error::Error + Send + Sync>> {
+        let params = [("optType", "ReadRealTimeData"), ("pwd", password)];
+        let response: EvcResponse = self.client.post(&self.url)
+            .form(&params)
+            .timeout(self.request_timeout)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(EvcSnapshot::decode(&response)?)
+    }
+
+    /// Pauses (`false`) or resumes (`true`) charging.
+    pub async fn set_charging(&self, password: &str, charging: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let command = if charging { CHARGE_START } else { CHARGE_STOP };
+        write_registers(&self.client, &self.url, password, self.request_timeout, &[(CHARGE_CONTROL_REGISTER, command)]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_charger_fixture() {
+        let response: EvcResponse = serde_json::from_str(include_str!("../tests/fixtures/x3_evc.json")).unwrap();
+        let snapshot = EvcSnapshot::decode(&response).unwrap();
+        assert_eq!(snapshot.state, EvcState::Charging);
+        assert_eq!(snapshot.charging_power_w, 11030.0);
+        assert_eq!(snapshot.session_energy_kwh, 12.5);
+
+        let output = snapshot.to_output(Some(12_000.0));
+        assert_eq!(output.state, "charging");
+        assert!(output.plugged);
+        assert_eq!(output.home_consumption_without_evc_w, Some(970.0));
+        assert_eq!(output.labels["device"], "evc");
+    }
+
+    #[test]
+    fn charger_states() {
+        assert_eq!(EvcState::from_value(0).summary(), "idle");
+        assert!(!EvcState::from_value(0).plugged());
+        assert_eq!(EvcState::from_value(7).summary(), "idle");
+        assert!(EvcState::from_value(7).plugged());
+        assert_eq!(EvcState::from_value(4).summary(), "fault");
+        assert_eq!(EvcState::from_value(99).summary(), "unavailable");
+    }
+
+    #[test]
+    fn short_data_array_is_an_error() {
+        let response = EvcResponse { sn: "C3".to_string(), data: vec![2, 0, 0] };
+        assert!(EvcSnapshot::decode(&response).is_err());
+    }
+}
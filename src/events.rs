@@ -0,0 +1,202 @@
+//! Measurement, fault and monitor action events published to NATS (or Kafka with the
+//! `kafka` feature), kept in a persisted outbox until the broker has acknowledged them.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Duration;
+
+/// What an event is about; `type` in the JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A successful poll, with the snapshot as published on /status/raw.
+    Poll,
+    /// The inverter entering or leaving a fault run mode.
+    Fault,
+    /// Something the monitor did, as written to the control audit log.
+    Action,
+    /// An inverter reboot noticed between two polls.
+    Restart,
+    /// A state change or significant measurement move, one entry of /status/changes.
+    Change,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub sn: String,
+    /// Increases by one per event and carries on across restarts, so consumers can spot
+    /// duplicates and gaps.
+    pub seq: u64,
+    pub time: u64,
+    pub data: serde_json::Value,
+}
+
+/// Events not acknowledged yet, oldest first. Persisted after every change.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Outbox {
+    next_seq: u64,
+    pending: VecDeque<Event>,
+}
+
+impl Outbox {
+    pub fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        // Written aside and renamed, so a crash never leaves half an outbox
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_vec(self)?)?;
+        std::fs::rename(temp, path)
+    }
+
+    /// Queues an event, dropping the oldest pending ones beyond `max`; returns how many were dropped.
+    pub fn push(&mut self, kind: EventKind, sn: &str, time: u64, data: serde_json::Value, max: usize) -> usize {
+        self.pending.push_back(Event { kind, sn: sn.to_string(), seq: self.next_seq, time, data });
+        self.next_seq += 1;
+        let excess = self.pending.len().saturating_sub(max.max(1));
+        self.pending.drain(..excess);
+        excess
+    }
+
+    pub fn front(&self) -> Option<&Event> {
+        self.pending.front()
+    }
+
+    /// Removes the oldest event once the broker has acknowledged it.
+    pub fn acknowledge(&mut self) {
+        self.pending.pop_front();
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn last_seq(&self) -> Option<u64> {
+        self.next_seq.checked_sub(1)
+    }
+}
+
+/// Where events are published: `nats://...`, or `kafka://host:port` with the `kafka` feature.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BrokerTarget {
+    Nats(crate::nats::Target),
+    Kafka(String),
+}
+
+impl BrokerTarget {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        match url.trim().strip_prefix("kafka://") {
+            Some(_) if !cfg!(feature = "kafka") => Err("Kafka needs solax-mon built with the kafka feature".to_string()),
+            Some(brokers) if brokers.trim_end_matches('/').is_empty() => Err(format!("Missing Kafka broker in {:?}", url)),
+            Some(brokers) => Ok(BrokerTarget::Kafka(brokers.trim_end_matches('/').to_string())),
+            None => crate::nats::Target::parse(url).map(BrokerTarget::Nats),
+        }
+    }
+}
+
+pub enum Broker {
+    Nats(crate::nats::Connection),
+    #[cfg(feature = "kafka")]
+    Kafka(rskafka::client::partition::PartitionClient),
+}
+
+impl Broker {
+    pub async fn connect(target: &BrokerTarget, topic: &str, timeout: Duration) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        match target {
+            BrokerTarget::Nats(target) => Ok(Broker::Nats(crate::nats::Connection::connect(target, timeout).await?)),
+            #[cfg(feature = "kafka")]
+            BrokerTarget::Kafka(brokers) => {
+                use rskafka::client::{partition::UnknownTopicHandling, ClientBuilder};
+                let connect = async {
+                    let client = ClientBuilder::new(brokers.split(',').map(str::to_string).collect()).build().await?;
+                    client.partition_client(topic, 0, UnknownTopicHandling::Retry).await
+                };
+                let partition = tokio::time::timeout(timeout, connect).await
+                    .map_err(|_| format!("Kafka broker {} didn't answer within {}s", brokers, timeout.as_secs()))??;
+                Ok(Broker::Kafka(partition))
+            }
+            #[cfg(not(feature = "kafka"))]
+            BrokerTarget::Kafka(_) => {
+                let _ = topic;
+                Err("Kafka needs solax-mon built with the kafka feature".into())
+            }
+        }
+    }
+
+    /// Publishes one event and returns once the broker has stored it.
+    pub async fn publish(&mut self, topic: &str, event: &Event, jetstream: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let payload = serde_json::to_vec(event)?;
+        match self {
+            Broker::Nats(connection) => connection.publish(topic, &payload, jetstream).await,
+            #[cfg(feature = "kafka")]
+            Broker::Kafka(partition) => {
+                let record = rskafka::record::Record {
+                    key: Some(event.sn.clone().into_bytes()),
+                    value: Some(payload),
+                    headers: Default::default(),
+                    timestamp: chrono::DateTime::from_timestamp(event.time as i64, 0).unwrap_or_default(),
+                };
+                partition.produce(vec![record], rskafka::client::partition::Compression::NoCompression).await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outbox_keeps_the_sequence_and_drops_the_oldest() {
+        let mut outbox = Outbox::default();
+        assert_eq!(outbox.last_seq(), None);
+        for n in 0..3 {
+            assert_eq!(outbox.push(EventKind::Poll, "X3", 100 + n, serde_json::json!({"n": n}), 2), usize::from(n == 2));
+        }
+        assert_eq!((outbox.len(), outbox.front().unwrap().seq, outbox.last_seq()), (2, 1, Some(2)));
+        outbox.acknowledge();
+        outbox.acknowledge();
+        assert!(outbox.is_empty());
+
+        // The sequence carries on after a restart
+        let path = std::env::temp_dir().join(format!("solax-outbox-{}.json", std::process::id()));
+        outbox.push(EventKind::Fault, "X3", 200, serde_json::json!({"state": "appeared"}), 10);
+        outbox.save(&path).unwrap();
+        let mut loaded = Outbox::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.front().unwrap().seq, 3);
+        loaded.push(EventKind::Action, "X3", 201, serde_json::json!({}), 10);
+        assert_eq!(loaded.last_seq(), Some(4));
+    }
+
+    #[test]
+    fn event_schema() {
+        let event = Event { kind: EventKind::Fault, sn: "X3".to_string(), seq: 7, time: 1_700_000_000, data: serde_json::json!({"state": "appeared"}) };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({"type": "fault", "sn": "X3", "seq": 7, "time": 1_700_000_000, "data": {"state": "appeared"}}),
+        );
+    }
+
+    #[test]
+    fn parses_broker_targets() {
+        assert!(matches!(BrokerTarget::parse("nats://10.0.0.5:4222"), Ok(BrokerTarget::Nats(_))));
+        if cfg!(feature = "kafka") {
+            assert_eq!(BrokerTarget::parse("kafka://k1:9092,k2:9092"), Ok(BrokerTarget::Kafka("k1:9092,k2:9092".to_string())));
+        } else {
+            assert!(BrokerTarget::parse("kafka://k1:9092").is_err());
+        }
+    }
+}
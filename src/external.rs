@@ -0,0 +1,255 @@
+//! Readings pushed by meters outside the inverter, like clamps on single circuits. Each one is
+//! published as a measurement of its own until it hasn't been pushed for its stale time.
+//! They also tell the essential load, the part of the house on the EPS-backed circuits.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How long a meter's reading counts without a new push, unless configured.
+pub const DEFAULT_STALE: Duration = Duration::from_secs(120);
+/// How far ahead of the local clock a timestamp may be, for meters with a drifting clock.
+pub const MAX_CLOCK_AHEAD_SECS: u64 = 60;
+
+/// One EXTERNAL_METER: `<source>[,name=<measurement>][,stale=<secs>]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Meter {
+    /// What the meter pushes as `source`.
+    pub source: String,
+    /// The measurement it is published as; the source unless given.
+    pub name: String,
+    pub stale_after: Duration,
+}
+
+impl Meter {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = value.split(',').map(str::trim);
+        let source = parts.next().filter(|source| !source.is_empty() && !source.contains('='))
+            .ok_or_else(|| format!("External meter {:?} must start with its source", value))?;
+        let mut meter = Meter { source: source.to_string(), name: source.to_string(), stale_after: DEFAULT_STALE };
+        for option in parts {
+            let (key, setting) = option.split_once('=')
+                .ok_or_else(|| format!("Invalid option {:?} for external meter {}", option, source))?;
+            match key.trim() {
+                "name" if !setting.trim().is_empty() => meter.name = setting.trim().to_string(),
+                "stale" => meter.stale_after = setting.trim().parse().ok()
+                    .filter(|secs| *secs > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| format!("Invalid stale time {:?} for external meter {}", setting, source))?,
+                _ => return Err(format!("Unknown option {:?} for external meter {}", option, source)),
+            }
+        }
+        Ok(meter)
+    }
+}
+
+/// The body of `POST /v1/ingest/external`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Reading {
+    pub source: String,
+    pub watts: f64,
+    /// Unix time of the reading; when it arrived if missing.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+/// The configured meters with their latest readings.
+#[derive(Debug, Clone, Default)]
+pub struct Meters {
+    pub meters: Vec<Meter>,
+    /// Watts and unix time by source.
+    latest: HashMap<String, (f64, u64)>,
+}
+
+impl Meters {
+    pub fn new(meters: Vec<Meter>) -> Self {
+        Self { meters, latest: HashMap::new() }
+    }
+
+    /// Takes a pushed reading; one older than the meter's latest is ignored.
+    pub fn record(&mut self, reading: Reading, now: u64) -> Result<(), String> {
+        if !self.meters.iter().any(|meter| meter.source == reading.source) {
+            return Err(format!("Unknown external meter {:?}", reading.source));
+        }
+        if !reading.watts.is_finite() {
+            return Err(format!("Invalid watts for external meter {}", reading.source));
+        }
+        let timestamp = reading.timestamp.unwrap_or(now);
+        if timestamp > now + MAX_CLOCK_AHEAD_SECS {
+            return Err(format!("Timestamp {} of external meter {} is in the future", timestamp, reading.source));
+        }
+        match self.latest.get(&reading.source) {
+            Some((_, latest)) if *latest > timestamp => {}
+            _ => {
+                self.latest.insert(reading.source, (reading.watts, timestamp));
+            }
+        }
+        Ok(())
+    }
+
+    /// The watts `source` read, unless it's stale.
+    pub fn watts(&self, source: &str, now: u64) -> Option<f64> {
+        let meter = self.meters.iter().find(|meter| meter.source == source)?;
+        let (watts, timestamp) = *self.latest.get(source)?;
+        (now.saturating_sub(timestamp) <= meter.stale_after.as_secs()).then_some(watts)
+    }
+
+    /// The readings not older than their meter's stale time, as measurement name, watts and
+    /// unix time, in the configured order.
+    pub fn fresh(&self, now: u64) -> Vec<(String, f64, u64)> {
+        self.meters.iter()
+            .filter_map(|meter| {
+                let (watts, timestamp) = *self.latest.get(&meter.source)?;
+                (now.saturating_sub(timestamp) <= meter.stale_after.as_secs()).then(|| (meter.name.clone(), watts, timestamp))
+            })
+            .collect()
+    }
+}
+
+/// ESSENTIAL_LOAD: how much of the house load is on the EPS-backed circuits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EssentialLoad {
+    /// `fraction=<0..1>` of the total load.
+    Fraction(f64),
+    /// `meter=<source>`: what one external meter reads.
+    Meter(String),
+    /// `total_minus=<source>+<source>`: the total load without the listed meters.
+    TotalMinus(Vec<String>),
+}
+
+impl EssentialLoad {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (kind, setting) = value.split_once('=')
+            .map(|(kind, setting)| (kind.trim(), setting.trim()))
+            .ok_or_else(|| format!("Essential load {:?} must be fraction=, meter= or total_minus=", value))?;
+        match kind {
+            "fraction" => setting.parse().ok()
+                .filter(|fraction| (0.0..=1.0).contains(fraction))
+                .map(Self::Fraction)
+                .ok_or_else(|| format!("Invalid fraction {:?} for the essential load, expected 0 to 1", setting)),
+            "meter" if !setting.is_empty() => Ok(Self::Meter(setting.to_string())),
+            "total_minus" => {
+                let sources: Vec<String> = setting.split('+').map(|source| source.trim().to_string()).collect();
+                if sources.iter().any(String::is_empty) {
+                    return Err(format!("Invalid meter list {:?} for the essential load", setting));
+                }
+                Ok(Self::TotalMinus(sources))
+            }
+            _ => Err(format!("Essential load {:?} must be fraction=, meter= or total_minus=", value)),
+        }
+    }
+
+    /// The external meter sources it reads.
+    pub fn sources(&self) -> &[String] {
+        match self {
+            Self::Fraction(_) => &[],
+            Self::Meter(source) => std::slice::from_ref(source),
+            Self::TotalMinus(sources) => sources,
+        }
+    }
+
+    /// The essential load from the total load and the meters; None when a reading it needs is
+    /// missing or stale.
+    pub fn watts(&self, total_w: Option<f64>, meters: &Meters, now: u64) -> Option<f64> {
+        match self {
+            Self::Fraction(fraction) => Some(total_w?.max(0.0) * fraction),
+            Self::Meter(source) => meters.watts(source, now),
+            Self::TotalMinus(sources) => {
+                let metered: f64 = sources.iter().map(|source| meters.watts(source, now)).sum::<Option<f64>>()?;
+                Some((total_w? - metered).max(0.0))
+            }
+        }
+    }
+}
+
+/// RUNTIME_LOAD: the load runtime estimates assume the battery has to carry.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RuntimeLoad {
+    /// The whole house, as the inverter measures it.
+    #[default]
+    Total,
+    /// Only the essential load (ESSENTIAL_LOAD).
+    Essential,
+}
+
+impl RuntimeLoad {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "total" => Some(Self::Total),
+            "essential" => Some(Self::Essential),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Total => "total",
+            Self::Essential => "essential",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_meters() {
+        let meter = Meter::parse("heat_pump, name=Heat Pump Power, stale=300").unwrap();
+        assert_eq!(meter, Meter {
+            source: "heat_pump".to_string(),
+            name: "Heat Pump Power".to_string(),
+            stale_after: Duration::from_secs(300),
+        });
+        assert_eq!(Meter::parse("kitchen").unwrap().name, "kitchen");
+        assert!(Meter::parse("name=Kitchen").is_err());
+        assert!(Meter::parse("kitchen,stale=0").is_err());
+        assert!(Meter::parse("kitchen,interval=60").is_err());
+    }
+
+    #[test]
+    fn stale_readings_drop_out() {
+        let mut meters = Meters::new(vec![
+            Meter::parse("heat_pump,name=Heat Pump Power,stale=60").unwrap(),
+            Meter::parse("kitchen").unwrap(),
+        ]);
+        let reading = |source: &str, watts: f64, timestamp: Option<u64>| Reading { source: source.to_string(), watts, timestamp };
+        meters.record(reading("heat_pump", 1600.0, Some(1_000)), 1_005).unwrap();
+        meters.record(reading("kitchen", 300.0, None), 1_005).unwrap();
+        assert!(meters.record(reading("garage", 50.0, None), 1_005).is_err());
+        assert!(meters.record(reading("kitchen", f64::NAN, None), 1_005).is_err());
+        assert!(meters.record(reading("kitchen", 300.0, Some(1_100)), 1_005).is_err());
+        // A late push of an older reading doesn't replace the newer one
+        meters.record(reading("heat_pump", 900.0, Some(990)), 1_006).unwrap();
+        assert_eq!(meters.fresh(1_010), [
+            ("Heat Pump Power".to_string(), 1600.0, 1_000),
+            ("kitchen".to_string(), 300.0, 1_005),
+        ]);
+        assert_eq!(meters.fresh(1_061), [("kitchen".to_string(), 300.0, 1_005)]);
+        assert_eq!(meters.fresh(1_200), []);
+    }
+
+    #[test]
+    fn essential_load_takes_the_configured_part() {
+        let mut meters = Meters::new(vec![Meter::parse("heat_pump").unwrap(), Meter::parse("evse,stale=60").unwrap()]);
+        let reading = |source: &str, watts: f64| Reading { source: source.to_string(), watts, timestamp: Some(1_000) };
+        meters.record(reading("heat_pump", 1500.0), 1_000).unwrap();
+        meters.record(reading("evse", 700.0), 1_000).unwrap();
+
+        let fraction = EssentialLoad::parse("fraction=0.4").unwrap();
+        assert_eq!(fraction.watts(Some(2500.0), &meters, 1_010), Some(1000.0));
+        assert_eq!(fraction.watts(None, &meters, 1_010), None);
+        let meter = EssentialLoad::parse("meter=heat_pump").unwrap();
+        assert_eq!(meter.sources(), ["heat_pump"]);
+        assert_eq!(meter.watts(None, &meters, 1_010), Some(1500.0));
+        let minus = EssentialLoad::parse("total_minus=heat_pump + evse").unwrap();
+        assert_eq!(minus.watts(Some(3000.0), &meters, 1_010), Some(800.0));
+        assert_eq!(minus.watts(Some(2000.0), &meters, 1_010), Some(0.0));
+        // A stale meter leaves the essential load unknown rather than wrong
+        assert_eq!(minus.watts(Some(3000.0), &meters, 1_100), None);
+
+        assert!(EssentialLoad::parse("fraction=1.5").is_err());
+        assert!(EssentialLoad::parse("total_minus=heat_pump+").is_err());
+        assert!(EssentialLoad::parse("0.4").is_err());
+    }
+}
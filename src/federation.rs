@@ -0,0 +1,165 @@
+//! Other solax-mon instances whose `/v1/status/raw` is polled and combined on
+//! `/v1/federation/status`.
+
+use crate::config::normalize_inverter_url;
+use crate::status::{FederationSite, RawMeasurement, RawOutput};
+use crate::outbound::Clients;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Units whose values add up across sites; voltages and percentages don't.
+const ADDITIVE_UNITS: [&str; 2] = ["W", "kWh"];
+
+/// One peer instance (FEDERATION_PEER).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    pub name: String,
+    pub url: String,
+    /// Bearer token sent to the peer, for instances behind an authenticating proxy.
+    pub token: Option<String>,
+}
+
+impl Peer {
+    /// Parses `name,url=http://host:3000,token=abc`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = value.split(',').map(str::trim);
+        let name = parts.next().filter(|name| !name.is_empty() && !name.contains('='))
+            .ok_or_else(|| format!("Federation peer {:?} must start with a name", value))?;
+        let mut peer = Peer { name: name.to_string(), url: String::new(), token: None };
+        for option in parts {
+            let (key, setting) = option.split_once('=')
+                .ok_or_else(|| format!("Invalid option {:?} for federation peer {}", option, name))?;
+            match key {
+                "url" => peer.url = normalize_inverter_url(setting).trim_end_matches('/').to_string(),
+                "token" => peer.token = Some(setting.to_string()).filter(|token| !token.is_empty()),
+                _ => return Err(format!("Unknown option {:?} for federation peer {}", key, name)),
+            }
+        }
+        if peer.url.is_empty() {
+            return Err(format!("Federation peer {} needs a url", name));
+        }
+        Ok(peer)
+    }
+
+    pub async fn fetch(&self, http: &Clients, timeout: Duration) -> Result<RawOutput, Box<dyn std::error::Error + Send + Sync>> {
+        let url = format!("{}/v1/status/raw", self.url);
+        let mut request = http.for_url(&url).get(&url).timeout(timeout);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|e| http.describe_error(&url, &e))?;
+        Ok(response.error_for_status()?.json().await?)
+    }
+}
+
+/// What is known about one peer; kept apart from the local poller's health.
+#[derive(Debug, Clone, Default)]
+pub struct PeerState {
+    pub last: Option<RawOutput>,
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+}
+
+impl PeerState {
+    pub fn succeeded(&mut self, raw: RawOutput, now: u64) {
+        self.last = Some(raw);
+        self.last_success = Some(now);
+        self.last_error = None;
+        self.consecutive_failures = 0;
+    }
+
+    /// Keeps the last snapshot, which is shown as stale once it is old enough.
+    pub fn failed(&mut self, error: String) {
+        self.last_error = Some(error);
+        self.consecutive_failures += 1;
+    }
+
+    pub fn output(&self, peer: &Peer, now: u64, stale_after: Duration) -> FederationSite {
+        let age_secs = self.last_success.map(|last| now.saturating_sub(last));
+        FederationSite {
+            name: peer.name.clone(),
+            url: peer.url.clone(),
+            stale: age_secs.is_none_or(|age| age > stale_after.as_secs()),
+            age_secs,
+            consecutive_failures: self.consecutive_failures,
+            last_error: self.last_error.clone(),
+            snapshot: self.last.clone(),
+        }
+    }
+}
+
+/// Sums the power and energy measurements of the sites that aren't stale.
+pub fn combined_totals(sites: &[FederationSite]) -> BTreeMap<String, RawMeasurement> {
+    let mut totals: BTreeMap<String, RawMeasurement> = BTreeMap::new();
+    for snapshot in sites.iter().filter(|site| !site.stale).filter_map(|site| site.snapshot.as_ref()) {
+        for (name, measurement) in &snapshot.measurements {
+            if !ADDITIVE_UNITS.contains(&measurement.unit.as_str()) {
+                continue;
+            }
+            match totals.get_mut(name) {
+                Some(total) if total.unit == measurement.unit => total.value += measurement.value,
+                Some(_) => {}
+                None => {
+                    totals.insert(name.clone(), measurement.clone());
+                }
+            }
+        }
+    }
+    totals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(measurements: &[(&str, f64, &str)]) -> RawOutput {
+        RawOutput {
+            measurements: measurements.iter()
+                .map(|(name, value, unit)| (name.to_string(), RawMeasurement::new(*value, unit)))
+                .collect(),
+            ..RawOutput::default()
+        }
+    }
+
+    #[test]
+    fn parses_peers() {
+        let peer = Peer::parse("cabin, url=10.0.0.7:3000/, token=s3cret").unwrap();
+        assert_eq!(peer, Peer { name: "cabin".to_string(), url: "http://10.0.0.7:3000".to_string(), token: Some("s3cret".to_string()) });
+        assert_eq!(Peer::parse("home,url=https://home.lan").unwrap().token, None);
+        assert!(Peer::parse("cabin").is_err());
+        assert!(Peer::parse("url=http://x").is_err());
+        assert!(Peer::parse("cabin,url=http://x,pwd=1").is_err());
+    }
+
+    #[test]
+    fn offline_peers_go_stale_and_leave_the_totals() {
+        let home = Peer::parse("home,url=http://home").unwrap();
+        let cabin = Peer::parse("cabin,url=http://cabin").unwrap();
+        let stale_after = Duration::from_secs(300);
+        let mut home_state = PeerState::default();
+        home_state.succeeded(raw(&[("Grid Power", 1500.0, "W"), ("Battery Remaining Capacity", 80.0, "%")]), 1000);
+        let mut cabin_state = PeerState::default();
+        cabin_state.succeeded(raw(&[("Grid Power", -500.0, "W"), ("Grid Exported Today", 2.5, "kWh")]), 1000);
+
+        let sites = [home_state.output(&home, 1100, stale_after), cabin_state.output(&cabin, 1100, stale_after)];
+        let totals = combined_totals(&sites);
+        assert_eq!(totals["Grid Power"].value, 1000.0);
+        assert_eq!(totals["Grid Exported Today"].value, 2.5);
+        assert!(!totals.contains_key("Battery Remaining Capacity"));
+
+        // The cabin keeps failing: its last snapshot is shown, but no longer counted
+        cabin_state.failed("connection refused".to_string());
+        let cabin_site = cabin_state.output(&cabin, 1400, stale_after);
+        assert!(cabin_site.stale);
+        assert_eq!(cabin_site.age_secs, Some(400));
+        assert!(cabin_site.snapshot.is_some());
+        let totals = combined_totals(&[home_state.output(&home, 1200, stale_after), cabin_site]);
+        assert_eq!(totals["Grid Power"].value, 1500.0);
+
+        // Never reached at all
+        let site = PeerState::default().output(&cabin, 1400, stale_after);
+        assert!(site.stale);
+        assert_eq!(site.age_secs, None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Daily solar production forecasts from Forecast.Solar, used to skip charge windows ahead of
+//! a sunny day.
+
+use crate::outbound::Clients;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub const DEFAULT_URL: &str = "https://api.forecast.solar";
+
+/// One array of panels (FORECAST_PLANE); a site with several orientations has one per roof face.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plane {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Tilt from horizontal, 0-90°.
+    pub declination: f64,
+    /// -180 to 180°, 0 facing south, -90 east and 90 west.
+    pub azimuth: f64,
+    pub kwp: f64,
+}
+
+impl Plane {
+    /// Parses `latitude,longitude,declination,azimuth,kwp`, e.g. `50.08,14.42,35,0,8.2`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let numbers = value.split(',')
+            .map(|part| part.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .filter(|numbers| numbers.len() == 5)
+            .ok_or_else(|| format!("Forecast plane {:?} must be latitude,longitude,declination,azimuth,kwp", value))?;
+        let plane = Plane {
+            latitude: numbers[0],
+            longitude: numbers[1],
+            declination: numbers[2],
+            azimuth: numbers[3],
+            kwp: numbers[4],
+        };
+        let valid = (-90.0..=90.0).contains(&plane.latitude)
+            && (-180.0..=180.0).contains(&plane.longitude)
+            && (0.0..=90.0).contains(&plane.declination)
+            && (-180.0..=180.0).contains(&plane.azimuth)
+            && plane.kwp > 0.0;
+        if !valid {
+            return Err(format!("Forecast plane {:?} is out of range", value));
+        }
+        Ok(plane)
+    }
+
+    fn url(&self, base: &str) -> String {
+        format!(
+            "{}/estimate/watthours/day/{}/{}/{}/{}/{}",
+            base, self.latitude, self.longitude, self.declination, self.azimuth, self.kwp
+        )
+    }
+
+    /// Production per local date in kWh for today and the next day(s), as far as the API goes.
+    pub async fn fetch(&self, http: &Clients, base: &str, timeout: Duration) -> Result<BTreeMap<chrono::NaiveDate, f64>, Box<dyn std::error::Error + Send + Sync>> {
+        let url = self.url(base);
+        let response = http.for_url(&url).get(&url)
+            .timeout(timeout)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .send()
+            .await
+            .map_err(|e| http.describe_error(&url, &e))?;
+        Ok(parse_daily(&response.error_for_status()?.text().await?)?)
+    }
+}
+
+/// Reads the `result` of `/estimate/watthours/day`, a map of local dates to Wh.
+pub fn parse_daily(body: &str) -> Result<BTreeMap<chrono::NaiveDate, f64>, String> {
+    #[derive(serde::Deserialize)]
+    struct Response {
+        result: BTreeMap<String, f64>,
+    }
+    let response: Response = serde_json::from_str(body).map_err(|e| format!("Unexpected forecast response: {}", e))?;
+    response.result.into_iter()
+        .map(|(date, wh)| {
+            let date = chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+                .map_err(|_| format!("Unexpected forecast date {:?}", date))?;
+            Ok((date, wh / 1000.0))
+        })
+        .collect()
+}
+
+/// Adds up the planes' forecasts, keeping only the dates every plane has a value for.
+pub fn combine(forecasts: &[BTreeMap<chrono::NaiveDate, f64>]) -> BTreeMap<chrono::NaiveDate, f64> {
+    let Some((first, rest)) = forecasts.split_first() else {
+        return BTreeMap::new();
+    };
+    first.iter()
+        .filter_map(|(date, kwh)| {
+            rest.iter()
+                .map(|forecast| forecast.get(date))
+                .try_fold(*kwh, |total, kwh| kwh.map(|kwh| total + kwh))
+                .map(|total| (*date, total))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_planes() {
+        let plane = Plane::parse("50.08, 14.42, 35, -90, 8.2").unwrap();
+        assert_eq!(plane, Plane { latitude: 50.08, longitude: 14.42, declination: 35.0, azimuth: -90.0, kwp: 8.2 });
+        assert_eq!(plane.url(DEFAULT_URL), "https://api.forecast.solar/estimate/watthours/day/50.08/14.42/35/-90/8.2");
+        assert!(Plane::parse("50.08,14.42,35,0").is_err());
+        assert!(Plane::parse("50.08,14.42,95,0,8").is_err());
+        assert!(Plane::parse("50.08,14.42,35,0,0").is_err());
+    }
+
+    #[test]
+    fn reads_and_combines_daily_forecasts() {
+        let date = |day| chrono::NaiveDate::from_ymd_opt(2026, 10, day).unwrap();
+        let east = parse_daily(r#"{"result":{"2026-10-15":6120,"2026-10-16":14500},"message":{"code":0,"type":"success"}}"#).unwrap();
+        assert_eq!(east, BTreeMap::from([(date(15), 6.12), (date(16), 14.5)]));
+        let west = parse_daily(r#"{"result":{"2026-10-16":3500}}"#).unwrap();
+        assert_eq!(combine(&[east, west]), BTreeMap::from([(date(16), 18.0)]));
+        assert!(parse_daily(r#"{"result":null,"message":{"code":429,"type":"error"}}"#).is_err());
+        assert!(parse_daily(r#"{"result":{"tomorrow":1}}"#).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+//! The latest poll, published whole to any number of readers. A reader takes a cheap `Arc`
+//! clone and never waits on the poller, and `subscribe()` wakes it on the next poll.
+
+use std::sync::Arc;
+use tokio::sync::watch;
+
+#[derive(Debug)]
+pub struct Latest<T> {
+    sender: watch::Sender<Arc<T>>,
+}
+
+impl<T> Latest<T> {
+    pub fn new(initial: T) -> Self {
+        Self { sender: watch::Sender::new(Arc::new(initial)) }
+    }
+
+    /// Replaces the published value and wakes the subscribers.
+    pub fn publish(&self, value: T) {
+        self.sender.send_replace(Arc::new(value));
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.sender.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<T>> {
+        self.sender.subscribe()
+    }
+}
+
+impl<T: Clone> Latest<T> {
+    /// Changes part of the published value, copying it first if a reader still holds it.
+    pub fn modify(&self, change: impl FnOnce(&mut T)) {
+        self.sender.send_modify(|value| change(Arc::make_mut(value)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn readers_keep_what_they_took() {
+        let latest = Latest::new(vec![1]);
+        let mut changes = latest.subscribe();
+        let taken = latest.get();
+        latest.modify(|value| value.push(2));
+        assert_eq!(*taken, vec![1]);
+        assert_eq!(*latest.get(), vec![1, 2]);
+        changes.changed().await.unwrap();
+        latest.publish(vec![3]);
+        assert_eq!(**changes.borrow_and_update(), vec![3]);
+    }
+}
@@ -0,0 +1,38 @@
+//! Shared code of the solax-mon service and the ssh monitor.
+
+pub mod anomaly;
+pub mod changes;
+pub mod clock;
+pub mod config;
+pub mod consistency;
+pub mod diag;
+pub mod dongle;
+pub mod evc;
+pub mod events;
+pub mod external;
+pub mod federation;
+pub mod forecast;
+pub mod inverter;
+pub mod latest;
+pub mod logfile;
+pub mod mqtt;
+pub mod nats;
+pub mod notify;
+pub mod outbound;
+pub mod parquet;
+pub mod postgres;
+pub mod redis;
+pub mod rollup;
+pub mod rule;
+pub mod signing;
+pub mod simulator;
+pub mod statsd;
+pub mod status;
+pub mod warnings;
+pub mod zabbix;
+
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,62 @@
+//! Size-based rotation of the append-only logs the service and the ssh monitor write.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Renames `path` to `path.1` (shifting older files up to `path.<keep>`) once it
+/// has grown past `max_bytes`.
+pub fn rotate_log(path: &Path, max_bytes: u64, keep: usize) -> io::Result<()> {
+    let size = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return Ok(()),
+    };
+    if size < max_bytes {
+        return Ok(());
+    }
+
+    let numbered = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+    if keep == 0 {
+        fs::remove_file(path)?;
+        return Ok(());
+    }
+    let _ = fs::remove_file(numbered(keep));
+    for n in (1..keep).rev() {
+        if numbered(n).exists() {
+            fs::rename(numbered(n), numbered(n + 1))?;
+        }
+    }
+    fs::rename(path, numbered(1))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_past_the_size_and_keeps_the_newest() {
+        let dir = std::env::temp_dir().join(format!("solax-logfile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.log");
+        assert!(rotate_log(&path, 10, 2).is_ok());
+
+        for generation in ["first", "second", "third"] {
+            fs::write(&path, generation.repeat(3)).unwrap();
+            rotate_log(&path, 10, 2).unwrap();
+        }
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(dir.join("control.log.1")).unwrap(), "thirdthirdthird");
+        assert_eq!(fs::read_to_string(dir.join("control.log.2")).unwrap(), "secondsecondsecond");
+        assert!(!dir.join("control.log.3").exists());
+
+        // Below the size it stays where it is
+        fs::write(&path, "short").unwrap();
+        rotate_log(&path, 10, 2).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "short");
+
+        rotate_log(&path, 1, 0).unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,289 +1,9319 @@
+use solax_mon::config::{self as secrets, normalize_inverter_url, parse_entries, LabelsConfig, PublishConfig, SECRETS_PATH};
+use solax_mon::evc::EvCharger;
+use solax_mon::events::{Broker, BrokerTarget, EventKind, Outbox};
+use solax_mon::federation::{self, PeerState};
+use solax_mon::latest::Latest;
+use solax_mon::logfile::rotate_log;
+use solax_mon::inverter::{BatteryMode, ChargeTemperatures, DongleProtocol, LoadSource, PowerSigns, RunMode, Snapshot, SocCalibration, X3HybridG4};
+use solax_mon::mqtt::{self, Command, CommandRequest};
+use solax_mon::notify::{
+    send_discord_alert, send_gotify_alert, send_matrix_alert, send_pushover_alert, send_slack_alert, Alert, GotifyTarget,
+    MatrixTarget, PushoverTarget, Routes, Severity,
+};
+use solax_mon::status::{
+    parse_power_value, runtime_minutes, AvailabilityOutput, AvailabilitySummary, BackoffHealth, BatteryModeOutput, BatteryModule, BatteryModulesOutput, BatteryStatsOutput,
+    BatteryThroughputSummary, CapacityOutput, CatalogOutput, ChargeAdvisory, CommandResult, ComplianceDay, ComplianceOutput, CurtailmentDay, CurtailmentOutput, DecodeOutput, EvcStatusOutput, EventStatsOutput, ExportLimitOutput, FederationOutput, HealthOutput, HttpStatsOutput,
+    InfoOutput, PostgresStatsOutput, SettingsOutput, RawMeasurement, RawOutput, Readings, RedisStatsOutput, ReportDay, ReportOutput, RouteStats, SourceHealth, StatusOutput, SurplusDeviceStatus, SurplusOutput,
+    InverterRestart, SnapshotDiff, ThresholdEvent, ZabbixStatsOutput,
+};
+use solax_mon::clock::{Clock, ManualClock, SystemClock};
+use solax_mon::unix_now;
+use solax_mon::anomaly::{self, median};
+use solax_mon::external::{EssentialLoad, RuntimeLoad};
+use solax_mon::{changes, consistency, diag, dongle, external, forecast, outbound, parquet, postgres, redis, signing, simulator, statsd, zabbix};
+use solax_mon::rule::{lookup_reading, Expr, DEFAULT_RULE};
+use solax_mon::rollup::{split_energy_kwh, DayRollup, Flows, Period, Rollups};
+use solax_mon::warnings::Warnings;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use axum::{
     Router,
     routing::get,
     extract::State,
+    http::StatusCode,
     response::Json,
 };
-use reqwest::Client;
-use serde_json::Value;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use rand::Rng;
 
-#[derive(Debug, Deserialize)]
-struct InverterResponse {
-    #[serde(rename = "type")]
-    inverter_type: i32,
-    sn: String,
-    ver: String,
-    #[serde(rename = "Data")]
-    data: Vec<i32>,
-    #[serde(rename = "Information")]
-    information: Vec<Value>,
-}
-
-#[derive(Debug, Clone, Copy)]
-enum Units {
-    V,
-    A,
-    W,
-    HZ,
-    C,
-    KWH,
-    PERCENT,
-    NONE,
+/// Where poll availability is kept across restarts.
+const AVAILABILITY_PATH: &str = "/srv/solax-mon/data/availability.json";
+
+/// Days of availability history that are kept.
+const AVAILABILITY_DAYS: usize = 30;
+
+/// Poll counts for one local day.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct DayAvailability {
+    date: chrono::NaiveDate,
+    attempted: u32,
+    succeeded: u32,
+    /// Longest time between two successful polls that ended on this day.
+    longest_gap_secs: u64,
 }
 
-#[derive(Debug)]
-struct Measurement {
-    value: f64,
-    unit: Units,
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct Availability {
+    days: Vec<DayAvailability>,
+    last_success: Option<u64>,
 }
 
-#[derive(Debug, Serialize, Clone)]
-struct StatusOutput {
-    solar_panels: String,
-    batteries: String,
-    battery_status: String,
-    battery_power: String,
-    grid_status: String,
-    grid_power: String,
-    home_consumption: String,
+impl Availability {
+    fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save availability to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Counts a poll on `date` (the local day of `now`) and keeps the last 30 days.
+    fn record(&mut self, date: chrono::NaiveDate, now: u64, success: bool) {
+        if self.days.last().is_none_or(|day| day.date != date) {
+            self.days.push(DayAvailability { date, attempted: 0, succeeded: 0, longest_gap_secs: 0 });
+            let excess = self.days.len().saturating_sub(AVAILABILITY_DAYS);
+            self.days.drain(..excess);
+        }
+        let day = self.days.last_mut().expect("today was just added");
+        day.attempted += 1;
+        if success {
+            day.succeeded += 1;
+            if let Some(last) = self.last_success {
+                day.longest_gap_secs = day.longest_gap_secs.max(now.saturating_sub(last));
+            }
+            self.last_success = Some(now);
+        }
+    }
+
+    fn output(&self) -> AvailabilityOutput {
+        let days = self.days.iter()
+            .map(|day| AvailabilitySummary::new(Some(day.date), day.attempted, day.succeeded, day.longest_gap_secs))
+            .collect();
+        let overall = AvailabilitySummary::new(
+            None,
+            self.days.iter().map(|day| day.attempted).sum(),
+            self.days.iter().map(|day| day.succeeded).sum(),
+            self.days.iter().map(|day| day.longest_gap_secs).max().unwrap_or(0),
+        );
+        AvailabilityOutput { days, overall }
+    }
 }
 
-type TransformFn = fn(f64, Option<&[i32]>) -> f64;
+/// Where the battery energy counters are kept across restarts.
+const BATTERY_STATS_PATH: &str = "/srv/solax-mon/data/battery.json";
 
-struct X3HybridG4 {
-    response_map: HashMap<String, (usize, Units, Option<TransformFn>)>,
+/// Energy charged into and discharged from the battery on one local day.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct DayThroughput {
+    date: chrono::NaiveDate,
+    charged_kwh: f64,
+    discharged_kwh: f64,
 }
 
-fn read_secrets() -> Result<(String, String), Box<dyn std::error::Error + Send + Sync>> {
-    let mut ip = String::new();
-    let mut serial = String::new();
-    
-    let file = File::open(Path::new("/srv/solax-mon/data/secrets.txt"))?;
-    let reader = BufReader::new(file);
-    
-    for line in reader.lines() {
-        let line = line?;
-        if let Some((key, value)) = line.split_once('=') {
-            match key.trim() {
-                "INVERTER_IP" => ip = value.trim().to_string(),
-                "SERIAL" => serial = value.trim().to_string(),
-                _ => (),
-            }
+/// Daily and lifetime battery energy, from the inverter's charge and discharge counters where
+/// it reports them and from the battery power integrated over time where it doesn't.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct BatteryThroughput {
+    days: Vec<DayThroughput>,
+    lifetime_charged_kwh: f64,
+    lifetime_discharged_kwh: f64,
+    /// Time and battery power (W, positive when charging) of the previous poll.
+    last_sample: Option<(u64, f64)>,
+    /// The inverter's charged and discharged totals (kWh) at the previous poll.
+    last_counters: Option<(f64, f64)>,
+}
+
+impl BatteryThroughput {
+    fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save battery counters to {}: {}", path.display(), e);
         }
     }
-    
-    if ip.is_empty() || serial.is_empty() {
-        return Err("Missing required secrets".into());
+
+    /// Adds the energy since the previous poll to `date`, the local day of `now`: what the
+    /// charged and discharged `counters` moved by, or else the battery power integrated
+    /// (trapezoidal). Intervals longer than `max_gap` aren't interpolated across, and the
+    /// counters' energy over one only goes into the lifetime totals. Returns the charged and
+    /// discharged energy added to the day.
+    fn record(&mut self, date: chrono::NaiveDate, now: u64, battery_power_w: f64, counters: Option<(f64, f64)>, max_gap: Duration) -> Option<(f64, f64)> {
+        if self.days.last().is_none_or(|day| day.date != date) {
+            self.days.push(DayThroughput { date, charged_kwh: 0.0, discharged_kwh: 0.0 });
+            let excess = self.days.len().saturating_sub(AVAILABILITY_DAYS);
+            self.days.drain(..excess);
+        }
+
+        let previous = self.last_sample.replace((now, battery_power_w));
+        let previous_counters = std::mem::replace(&mut self.last_counters, counters);
+        let (last, last_power_w) = previous?;
+        let elapsed = now.saturating_sub(last);
+        let gap = elapsed == 0 || elapsed > max_gap.as_secs();
+        let (charged, discharged) = match counter_deltas(previous_counters, counters) {
+            Some(deltas) => deltas,
+            None if gap => return None,
+            None => split_energy_kwh(last_power_w, battery_power_w, elapsed as f64 / 3600.0),
+        };
+        self.lifetime_charged_kwh += charged;
+        self.lifetime_discharged_kwh += discharged;
+        if gap {
+            return None;
+        }
+        let day = self.days.last_mut().expect("today was just added");
+        day.charged_kwh += charged;
+        day.discharged_kwh += discharged;
+        Some((charged, discharged))
     }
-    
-    Ok((ip, serial))
-}
-
-impl X3HybridG4 {
-    fn new() -> Self {
-        let mut response_map: HashMap<String, (usize, Units, Option<TransformFn>)> = HashMap::new();
-        
-        fn div10(x: f64, _: Option<&[i32]>) -> f64 { x / 10.0 }
-        fn div100(x: f64, _: Option<&[i32]>) -> f64 { x / 100.0 }
-        fn to_signed(x: f64, _: Option<&[i32]>) -> f64 { 
-            let x = x as i32;
-            f64::from(if x > 32767 { x - 65536 } else { x })
-        }
-        fn calculate_grid_power(_x: f64, data: Option<&[i32]>) -> f64 {
-            if let Some(data) = data {
-                if let (Some(&high), Some(&low)) = (data.get(34), data.get(35)) {
-                    let combined = ((high as i64) << 16) | ((low as i64) & 0xFFFF);
-                    if combined > 2147483647 {
-                        (combined - 4294967296) as f64
-                    } else {
-                        combined as f64
-                    }
-                } else {
-                    0.0
+
+    fn output(&self, capacity_kwh: Option<f64>) -> BatteryStatsOutput {
+        let summary = |date, charged_kwh: f64, discharged_kwh: f64| BatteryThroughputSummary {
+            date,
+            charged_kwh,
+            discharged_kwh,
+            // One full cycle is a capacity's worth charged and discharged again
+            cycles: capacity_kwh
+                .filter(|kwh| *kwh > 0.0)
+                .map(|kwh| (charged_kwh + discharged_kwh) / 2.0 / kwh),
+        };
+        BatteryStatsOutput {
+            days: self.days.iter()
+                .map(|day| summary(Some(day.date), day.charged_kwh, day.discharged_kwh))
+                .collect(),
+            lifetime: summary(None, self.lifetime_charged_kwh, self.lifetime_discharged_kwh),
+            capacity: CapacityOutput::default(),
+        }
+    }
+}
+
+/// Two of the inverter's lifetime energy counters (kWh), None unless it reports both. A counter
+/// reading 0 isn't kept by the firmware.
+fn energy_counters(snapshot: &Snapshot, first: &str, second: &str) -> Option<(f64, f64)> {
+    let counter = |name| snapshot.value(name).filter(|kwh| *kwh > 0.0);
+    Some((counter(first)?, counter(second)?))
+}
+
+/// What a pair of energy counters moved by between two polls. A counter going down was reset,
+/// by a rollover or a restart of the inverter, which gives None: that interval is integrated
+/// from the power instead, and the counters go on from the new reading.
+fn counter_deltas(previous: Option<(f64, f64)>, current: Option<(f64, f64)>) -> Option<(f64, f64)> {
+    let ((first_before, second_before), (first, second)) = (previous?, current?);
+    (first >= first_before && second >= second_before).then_some((first - first_before, second - second_before))
+}
+
+/// Where the grid energy counters are kept across restarts.
+const GRID_STATS_PATH: &str = "/srv/solax-mon/data/grid.json";
+
+/// Energy imported from and exported to the grid on the current local day and over the
+/// counters' lifetime, from the inverter's consumption and feed-in counters where it reports
+/// them and from the grid power (positive when exporting) integrated where it doesn't.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct GridEnergy {
+    date: Option<chrono::NaiveDate>,
+    imported_today_kwh: f64,
+    exported_today_kwh: f64,
+    lifetime_imported_kwh: f64,
+    lifetime_exported_kwh: f64,
+    /// Time and grid power (W) of the previous poll.
+    last_sample: Option<(u64, f64)>,
+    /// The inverter's feed-in and consumption totals (kWh) at the previous poll.
+    last_counters: Option<(f64, f64)>,
+}
+
+impl GridEnergy {
+    fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save grid counters to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Adds the energy since the previous poll to `date`, starting the day's counters over at
+    /// local midnight. The feed-in and consumption `counters` and intervals longer than
+    /// `max_gap` are handled as for the battery. Returns the exported and imported energy added
+    /// to the day.
+    fn record(&mut self, date: chrono::NaiveDate, now: u64, grid_power_w: f64, counters: Option<(f64, f64)>, max_gap: Duration) -> Option<(f64, f64)> {
+        if self.date != Some(date) {
+            self.date = Some(date);
+            self.imported_today_kwh = 0.0;
+            self.exported_today_kwh = 0.0;
+        }
+
+        let previous = self.last_sample.replace((now, grid_power_w));
+        let previous_counters = std::mem::replace(&mut self.last_counters, counters);
+        let (last, last_power_w) = previous?;
+        let elapsed = now.saturating_sub(last);
+        let gap = elapsed == 0 || elapsed > max_gap.as_secs();
+        let (exported, imported) = match counter_deltas(previous_counters, counters) {
+            Some(deltas) => deltas,
+            None if gap => return None,
+            None => split_energy_kwh(last_power_w, grid_power_w, elapsed as f64 / 3600.0),
+        };
+        self.lifetime_imported_kwh += imported;
+        self.lifetime_exported_kwh += exported;
+        if gap {
+            return None;
+        }
+        self.imported_today_kwh += imported;
+        self.exported_today_kwh += exported;
+        Some((exported, imported))
+    }
+
+    /// Today's counters as measurements for /status/raw.
+    fn measurements(&self) -> [(&'static str, f64); 2] {
+        [("Grid Imported Today", self.imported_today_kwh), ("Grid Exported Today", self.exported_today_kwh)]
+    }
+}
+
+fn render_grid_metrics(grid: &GridEnergy) -> String {
+    let mut out = String::new();
+    let counters = [
+        ("solax_grid_imported_kwh_total", "Energy imported from the grid", grid.lifetime_imported_kwh),
+        ("solax_grid_exported_kwh_total", "Energy exported to the grid", grid.lifetime_exported_kwh),
+    ];
+    for (metric, help, value) in counters {
+        out.push_str(&format!("# HELP {} {}\n", metric, help));
+        out.push_str(&format!("# TYPE {} counter\n", metric));
+        out.push_str(&format!("{} {}\n", metric, value));
+    }
+    out
+}
+
+/// The median of `secs`, which it sorts.
+fn median_secs(secs: &mut [u64]) -> Option<u64> {
+    secs.sort_unstable();
+    secs.get(secs.len() / 2).copied()
+}
+
+/// The export compliance of the month starting on `month` from the grid power readings
+/// (unix time and W, positive when exporting) in time order. Export is integrated between two
+/// readings at most `max_gap` apart, and a reading above the limit counts for the time since
+/// the previous one.
+fn export_compliance(
+    readings: &[(u64, f64)],
+    month: chrono::NaiveDate,
+    config: &ComplianceConfig,
+) -> ComplianceOutput {
+    use chrono::Datelike;
+
+    let mut days: Vec<ComplianceDay> = month.iter_days()
+        .take_while(|date| date.month() == month.month())
+        .map(|date| ComplianceDay {
+            date,
+            samples: 0,
+            sampling_interval_secs: None,
+            longest_gap_secs: 0,
+            max_export_w: None,
+            minutes_above_limit: 0.0,
+            exported_kwh: 0.0,
+        })
+        .collect();
+    let mut gaps: Vec<Vec<u64>> = vec![Vec::new(); days.len()];
+    let mut previous: Option<(u64, f64)> = None;
+    for &(time, watts) in readings {
+        let Some(date) = chrono::DateTime::from_timestamp(time as i64, 0)
+            .map(|at| at.with_timezone(&config.timezone).date_naive())
+        else {
+            continue;
+        };
+        let Some(index) = days.iter().position(|day| day.date == date) else { continue };
+        let day = &mut days[index];
+        day.samples += 1;
+        if watts > 0.0 {
+            day.max_export_w = Some(day.max_export_w.map_or(watts, |max| max.max(watts)));
+        }
+        if let Some((last, last_watts)) = previous.replace((time, watts)) {
+            let elapsed = time.saturating_sub(last);
+            gaps[index].push(elapsed);
+            day.longest_gap_secs = day.longest_gap_secs.max(elapsed);
+            if elapsed <= config.max_gap.as_secs() {
+                day.exported_kwh += split_energy_kwh(last_watts, watts, elapsed as f64 / 3600.0).0;
+                if watts > config.limit_w {
+                    day.minutes_above_limit += elapsed as f64 / 60.0;
                 }
-            } else {
-                0.0
-            }
-        }
-        
-        // Grid measurements
-        response_map.insert("Grid 1 Voltage".to_string(), (0, Units::V, Some(div10)));
-        response_map.insert("Grid 2 Voltage".to_string(), (1, Units::V, Some(div10)));
-        response_map.insert("Grid 3 Voltage".to_string(), (2, Units::V, Some(div10)));
-        response_map.insert("Grid 1 Current".to_string(), (3, Units::A, Some(div10)));
-        response_map.insert("Grid 2 Current".to_string(), (4, Units::A, Some(div10)));
-        response_map.insert("Grid 3 Current".to_string(), (5, Units::A, Some(div10)));
-        response_map.insert("Grid 1 Power".to_string(), (6, Units::W, Some(to_signed)));
-        response_map.insert("Grid 2 Power".to_string(), (7, Units::W, Some(to_signed)));
-        response_map.insert("Grid 3 Power".to_string(), (8, Units::W, Some(to_signed)));
-        
-        // Solar panel measurements
-        response_map.insert("PV1 Voltage".to_string(), (10, Units::V, Some(div10)));
-        response_map.insert("PV2 Voltage".to_string(), (11, Units::V, Some(div10)));
-        response_map.insert("PV1 Current".to_string(), (12, Units::A, Some(div10)));
-        response_map.insert("PV2 Current".to_string(), (13, Units::A, Some(div10)));
-        response_map.insert("PV1 Power".to_string(), (14, Units::W, None));
-        response_map.insert("PV2 Power".to_string(), (15, Units::W, None));
-
-        // Battery measurements
-        response_map.insert("Battery Power".to_string(), (41, Units::W, Some(to_signed)));
-        response_map.insert("Battery Remaining Capacity".to_string(), (103, Units::PERCENT, None));
-        
-        // Home consumption
-        response_map.insert("Load/Generator Power".to_string(), (47, Units::W, Some(to_signed)));
-
-        // Grid total power (using indexes 34 and 35)
-        response_map.insert("Grid Power".to_string(), (34, Units::W, Some(to_signed)));
-
-        Self { response_map }
-    }
-
-    async fn fetch_data(&self, url: &str, password: &str) -> Result<HashMap<String, Measurement>, Box<dyn std::error::Error + Send + Sync>> {
-        let client = Client::new();
-        let params = [("optType", "ReadRealTimeData"), ("pwd", password)];
-        
-        let response: InverterResponse = client.post(url)
-            .form(&params)
-            .send()
-            .await?
-            .json()
-            .await?;
+            }
+        }
+    }
+    for (day, gaps) in days.iter_mut().zip(&mut gaps) {
+        day.sampling_interval_secs = median_secs(gaps);
+    }
 
-        let mut measurements = HashMap::new();
+    let sampling_interval_secs = median_secs(&mut gaps.concat());
+    let note = match sampling_interval_secs {
+        Some(secs) => format!(
+            "Readings are {}s apart (median). Export above the limit for less than that, between two readings, can't be seen, \
+             and each reading above the limit counts for the time since the previous one.",
+            secs,
+        ),
+        None => "No grid power readings stored for this month.".to_string(),
+    };
+    ComplianceOutput {
+        month: month.format("%Y-%m").to_string(),
+        limit_w: config.limit_w,
+        measurement: config.measurement.clone(),
+        sampling_interval_secs,
+        note,
+        compliant: days.iter().all(|day| day.max_export_w.is_none_or(|watts| watts <= config.limit_w)),
+        days,
+    }
+}
 
-        for (key, (index, unit, transform_fn)) in &self.response_map {
-            if let Some(value) = response.data.get(*index) {
-                let value = f64::from(*value);
-                let final_value = if let Some(transform) = transform_fn {
-                    transform(value, Some(&response.data))
-                } else {
-                    value
-                };
+fn render_compliance_csv(output: &ComplianceOutput) -> String {
+    let mut out = String::from("date,samples,sampling_interval_secs,longest_gap_secs,max_export_w,minutes_above_limit,exported_kwh,limit_w\n");
+    for day in &output.days {
+        out.push_str(&format!(
+            "{},{},{},{},{},{:.1},{:.3},{}\n",
+            day.date,
+            day.samples,
+            day.sampling_interval_secs.map(|secs| secs.to_string()).unwrap_or_default(),
+            day.longest_gap_secs,
+            day.max_export_w.map(|watts| format!("{:.0}", watts)).unwrap_or_default(),
+            day.minutes_above_limit,
+            day.exported_kwh,
+            output.limit_w,
+        ));
+    }
+    out
+}
 
-                measurements.insert(key.clone(), Measurement {
-                    value: final_value,
-                    unit: *unit,
-                });
+/// Where the daily rollups behind the monthly and yearly reports are kept.
+const DAILY_ROLLUPS_PATH: &str = "/srv/solax-mon/data/daily.json";
+
+/// The lines of a monthly or yearly report, as label and value.
+fn report_rows(output: &ReportOutput) -> Vec<(&'static str, String)> {
+    let kwh = |kwh: f64| format!("{:.1} kWh", kwh);
+    let day = |day: &Option<ReportDay>| match day {
+        Some(day) => format!("{} ({:.1} kWh)", day.date, day.generated_kwh),
+        None => "-".to_string(),
+    };
+    let availability = &output.availability;
+    vec![
+        ("Period", if output.partial { format!("{} (partial)", output.period) } else { output.period.clone() }),
+        ("Days recorded", format!("{} of {}", output.days_recorded, output.days_in_period)),
+        ("Generated", kwh(output.generated_kwh)),
+        ("Consumed", kwh(output.consumed_kwh)),
+        ("Imported", kwh(output.imported_kwh)),
+        ("Exported", kwh(output.exported_kwh)),
+        ("Battery charged", kwh(output.battery_charged_kwh)),
+        ("Battery discharged", kwh(output.battery_discharged_kwh)),
+        ("Best day", day(&output.best_day)),
+        ("Worst day", day(&output.worst_day)),
+        ("Availability", match availability.ratio {
+            Some(ratio) => format!("{:.2}% ({} of {} polls)", ratio * 100.0, availability.succeeded, availability.attempted),
+            None => "-".to_string(),
+        }),
+    ]
+}
+
+fn render_report_text(output: &ReportOutput) -> String {
+    report_rows(output).into_iter()
+        .map(|(label, value)| format!("{:<20}{}\n", label, value))
+        .collect()
+}
+
+/// A report as a page with one table; nothing in it comes from the request but the parsed period.
+fn render_report_html(output: &ReportOutput) -> String {
+    let rows: String = report_rows(output).into_iter()
+        .map(|(label, value)| format!("<tr><th align=\"left\">{}</th><td>{}</td></tr>\n", label, value))
+        .collect();
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>solax-mon report {0}</title></head>\n\
+         <body><h1>solax-mon report {0}</h1>\n<table>\n{1}</table></body></html>\n",
+        output.period, rows,
+    )
+}
+
+/// `YYYY` as a year.
+fn parse_year(year: &str) -> Result<i32, String> {
+    year.trim().parse().ok()
+        .filter(|year| (1000..=9999).contains(year))
+        .ok_or_else(|| format!("Invalid year {:?}, expected YYYY", year))
+}
+
+/// `YYYY-MM` as the first day of the month.
+fn parse_month(month: &str) -> Result<chrono::NaiveDate, String> {
+    chrono::NaiveDate::parse_from_str(&format!("{}-01", month.trim()), "%Y-%m-%d")
+        .map_err(|_| format!("Invalid month {:?}, expected YYYY-MM", month))
+}
+
+/// `YYYY-MM-DD` as a date.
+fn parse_date(date: &str) -> Result<chrono::NaiveDate, String> {
+    chrono::NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d")
+        .map_err(|_| format!("Invalid date {:?}, expected YYYY-MM-DD", date))
+}
+
+/// The month `now` is in, in `timezone`.
+fn current_month(timezone: chrono_tz::Tz) -> chrono::NaiveDate {
+    use chrono::Datelike;
+    let today = chrono::Utc::now().with_timezone(&timezone).date_naive();
+    today.with_day(1).unwrap_or(today)
+}
+
+/// Reads the month's grid power from PostgreSQL and computes its export compliance.
+async fn export_compliance_report(
+    config: &ComplianceConfig,
+    month: chrono::NaiveDate,
+) -> Result<ComplianceOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let client = connect_postgres(&config.postgres, postgres_tls(&config.postgres)?).await?;
+    let next_month = month.checked_add_months(chrono::Months::new(1)).ok_or("Month out of range")?;
+    let start = local_instant(config.timezone, month, chrono::NaiveTime::MIN).with_timezone(&chrono::Utc);
+    let end = local_instant(config.timezone, next_month, chrono::NaiveTime::MIN).with_timezone(&chrono::Utc);
+    let sql = postgres::series_sql(&config.postgres.table, config.postgres.layout, &config.measurement);
+    let rows = match config.postgres.layout {
+        postgres::Layout::Narrow => client.query(&sql, &[&start, &end, &config.measurement]).await?,
+        postgres::Layout::Wide => client.query(&sql, &[&start, &end]).await?,
+    };
+    let readings: Vec<(u64, f64)> = rows.iter()
+        .map(|row| (row.get::<_, chrono::DateTime<chrono::Utc>>(0).timestamp() as u64, row.get::<_, f64>(1)))
+        .collect();
+    Ok(export_compliance(&readings, month, config))
+}
+
+/// Where the curtailment estimates are kept across restarts.
+const CURTAILMENT_STATS_PATH: &str = "/srv/solax-mon/data/curtailment.json";
+/// Battery state of charge from which the battery can't take the surplus any more.
+const CURTAILMENT_FULL_SOC_PCT: f64 = 98.0;
+/// How far the PV power has to stay below the expected power to count as curtailed.
+const CURTAILMENT_MIN_W: f64 = 100.0;
+/// Length of the time-of-day slots the `same_time` estimate keeps the highest PV power of.
+const CURTAILMENT_SLOT_SECS: u32 = 900;
+/// Days of slots the `same_time` estimate looks back on.
+const CURTAILMENT_DAYS: usize = 7;
+
+/// How the PV power the inverter would produce without its export limit is estimated
+/// (CURTAILMENT_ESTIMATE).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CurtailmentEstimate {
+    /// The highest PV power over the last CURTAILMENT_WINDOW_SECS. Overestimates while the
+    /// sun goes down and underestimates a whole day at the limit.
+    RecentMax(Duration),
+    /// The highest PV power at the same time of day over the last week, which a cloudy week
+    /// keeps low.
+    SameTime,
+}
+
+impl CurtailmentEstimate {
+    fn name(&self) -> &'static str {
+        match self {
+            CurtailmentEstimate::RecentMax(_) => "recent_max",
+            CurtailmentEstimate::SameTime => "same_time",
+        }
+    }
+}
+
+/// The PV power lost to the export limit, integrated into an estimated energy per local day.
+/// It counts as curtailed while the export is at the limit, the battery is full and the PV
+/// power is below the expected power.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct Curtailment {
+    days: Vec<CurtailmentDay>,
+    /// The highest PV power of each time-of-day slot, per day.
+    slots: Vec<(chrono::NaiveDate, Vec<f64>)>,
+    /// Time and PV power of the polls within the `recent_max` window.
+    #[serde(skip)]
+    recent: VecDeque<(u64, f64)>,
+    /// Time and curtailed power (W) of the previous poll.
+    last_sample: Option<(u64, f64)>,
+    #[serde(skip)]
+    export_limit_w: Option<f64>,
+    #[serde(skip)]
+    export_headroom_w: Option<f64>,
+    #[serde(skip)]
+    expected_solar_w: Option<f64>,
+    #[serde(skip)]
+    curtailing: bool,
+}
+
+impl Curtailment {
+    fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save the curtailment estimates to {}: {}", path.display(), e);
+        }
+    }
+
+    /// The expected PV power at local time `at` (unix time `now`), from the PV power seen so far.
+    fn expected_w(&mut self, estimate: CurtailmentEstimate, at: chrono::NaiveDateTime, now: u64) -> Option<f64> {
+        match estimate {
+            CurtailmentEstimate::RecentMax(window) => {
+                while self.recent.front().is_some_and(|(time, _)| now.saturating_sub(*time) > window.as_secs()) {
+                    self.recent.pop_front();
+                }
+                self.recent.iter().map(|(_, solar_w)| *solar_w).reduce(f64::max)
+            }
+            CurtailmentEstimate::SameTime => {
+                let slot = (chrono::Timelike::num_seconds_from_midnight(&at.time()) / CURTAILMENT_SLOT_SECS) as usize;
+                self.slots.iter()
+                    .filter(|(date, _)| *date != at.date())
+                    .map(|(_, slots)| slots[slot])
+                    .reduce(f64::max)
             }
         }
+    }
 
-        if let (Some(pv1), Some(pv2)) = (
-            measurements.get("PV1 Power"),
-            measurements.get("PV2 Power")
-        ) {
-            measurements.insert("Total Solar Power".to_string(), Measurement {
-                value: pv1.value + pv2.value,
-                unit: Units::W,
-            });
+    /// Takes the poll at local time `at` (unix time `now`). `export_limit_w` is the
+    /// inverter's limit as last read; intervals longer than `max_gap` aren't integrated.
+    #[allow(clippy::too_many_arguments)]
+    fn record(
+        &mut self,
+        estimate: CurtailmentEstimate,
+        at: chrono::NaiveDateTime,
+        now: u64,
+        solar_w: f64,
+        grid_w: f64,
+        soc_pct: Option<f64>,
+        export_limit_w: Option<f64>,
+        max_gap: Duration,
+    ) {
+        let date = at.date();
+        if self.days.last().is_none_or(|day| day.date != date) {
+            self.days.push(CurtailmentDay { date, estimated_kwh: 0.0, curtailed_secs: 0 });
+            let excess = self.days.len().saturating_sub(AVAILABILITY_DAYS);
+            self.days.drain(..excess);
+        }
+
+        let expected_w = self.expected_w(estimate, at, now);
+        self.recent.push_back((now, solar_w));
+        if self.slots.last().is_none_or(|(day, _)| *day != date) {
+            self.slots.push((date, vec![0.0; (86_400 / CURTAILMENT_SLOT_SECS) as usize]));
+            let excess = self.slots.len().saturating_sub(CURTAILMENT_DAYS + 1);
+            self.slots.drain(..excess);
+        }
+        let slot = (chrono::Timelike::num_seconds_from_midnight(&at.time()) / CURTAILMENT_SLOT_SECS) as usize;
+        let today = &mut self.slots.last_mut().expect("today was just added").1[slot];
+        *today = today.max(solar_w);
+
+        // Grid power is positive while exporting
+        let limit_w = export_limit_w.filter(|limit| *limit > 0.0);
+        self.export_limit_w = limit_w;
+        self.export_headroom_w = limit_w.map(|limit| limit - grid_w);
+        self.expected_solar_w = expected_w;
+        let lost_w = expected_w.map_or(0.0, |expected| expected - solar_w);
+        self.curtailing = limit_w.is_some_and(|limit| grid_w >= limit * 0.95)
+            && soc_pct.is_none_or(|soc| soc >= CURTAILMENT_FULL_SOC_PCT)
+            && lost_w > CURTAILMENT_MIN_W;
+        let curtailed_w = if self.curtailing { lost_w } else { 0.0 };
+
+        let previous = self.last_sample.replace((now, curtailed_w));
+        let Some((last, last_curtailed_w)) = previous else {
+            return;
+        };
+        let elapsed = now.saturating_sub(last);
+        if elapsed == 0 || elapsed > max_gap.as_secs() {
+            return;
+        }
+        let day = self.days.last_mut().expect("today was just added");
+        day.estimated_kwh += (last_curtailed_w + curtailed_w) / 2.0 * elapsed as f64 / 3_600_000.0;
+        if self.curtailing {
+            day.curtailed_secs += elapsed;
+        }
+    }
+
+    fn output(&self, estimate: CurtailmentEstimate) -> CurtailmentOutput {
+        CurtailmentOutput {
+            estimate: estimate.name().to_string(),
+            export_limit_w: self.export_limit_w,
+            export_headroom_w: self.export_headroom_w,
+            expected_solar_w: self.expected_solar_w,
+            curtailing: self.curtailing,
+            estimated_today_kwh: self.days.last().map_or(0.0, |day| day.estimated_kwh),
+            days: self.days.clone(),
         }
+    }
+}
 
-        Ok(measurements)
+fn render_curtailment_metrics(output: &CurtailmentOutput) -> String {
+    let mut out = String::new();
+    let gauges = [
+        ("solax_export_headroom_watts", "Export left below the inverter's export limit", output.export_headroom_w),
+        ("solax_curtailing", "Whether the export limit is curtailing the PV power", Some(f64::from(u8::from(output.curtailing)))),
+        ("solax_curtailed_kwh_today_estimate", "Estimated PV energy lost to the export limit today", Some(output.estimated_today_kwh)),
+    ];
+    for (metric, help, value) in gauges {
+        let Some(value) = value else { continue };
+        out.push_str(&format!("# HELP {} {}\n", metric, help));
+        out.push_str(&format!("# TYPE {} gauge\n", metric));
+        out.push_str(&format!("{} {}\n", metric, value));
     }
+    out
+}
 
-    fn format_status(&self, measurements: &HashMap<String, Measurement>) -> StatusOutput {
-        let solar_power = measurements.get("Total Solar Power")
-            .map_or(0.0, |m| m.value);
+/// Where the learned overnight load is kept across restarts.
+const OVERNIGHT_LOAD_PATH: &str = "/srv/solax-mon/data/overnight-load.json";
+/// Nights the typical overnight load is learned from.
+const OVERNIGHT_NIGHTS: usize = 7;
+/// Nights needed before the learned load replaces the current one.
+const OVERNIGHT_MIN_NIGHTS: usize = 3;
 
-        let battery_power = measurements.get("Battery Power")
-            .map_or(0.0, |m| m.value);
-        let battery_status = if battery_power < 0.0 {
-            "Discharging"
-        } else if battery_power > 0.0 {
-            "Charging"
-        } else {
-            "Idle"
-        };
-        
-        let battery_capacity = measurements.get("Battery Remaining Capacity")
-            .map_or(0.0, |m| m.value);
-
-        let grid_power = measurements.get("Grid Power")
-            .map_or(0.0, |m| m.value);
-        let grid_status = if grid_power < 0.0 {
-            "Importing"
-        } else if grid_power > 0.0 {
-            "Exporting"
-        } else {
-            "Idle"
+/// The house load between 22:00 and 06:00 local time: the median of each of the last nights,
+/// and the samples of the night in progress.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct OvernightLoad {
+    nights: Vec<(chrono::NaiveDate, f64)>,
+    /// The date the night in progress started on.
+    current: Option<chrono::NaiveDate>,
+    samples: Vec<f64>,
+}
+
+impl OvernightLoad {
+    fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save the overnight load to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Adds a load sample taken at local time `at`; returns true when a night was completed.
+    fn record(&mut self, at: chrono::NaiveDateTime, load_w: f64) -> bool {
+        let hour = chrono::Timelike::hour(&at.time());
+        let night = match hour {
+            22.. => Some(at.date()),
+            ..6 => at.date().pred_opt(),
+            _ => None,
         };
+        let completed = self.current.is_some() && night != self.current;
+        if completed {
+            self.finish_night();
+        }
+        if let Some(night) = night {
+            self.current = Some(night);
+            self.samples.push(load_w);
+        }
+        completed
+    }
 
-        let consumption = measurements.get("Load/Generator Power")
-            .map_or(0.0, |m| m.value);
+    fn finish_night(&mut self) {
+        let (Some(date), Some(load_w)) = (self.current.take(), median(&self.samples)) else {
+            return;
+        };
+        self.samples.clear();
+        self.nights.push((date, load_w));
+        let excess = self.nights.len().saturating_sub(OVERNIGHT_NIGHTS);
+        self.nights.drain(..excess);
+    }
 
-        StatusOutput {
-            solar_panels: format!("{:.1}W", solar_power),
-            batteries: format!("{:.1}%", battery_capacity),
-            battery_status: battery_status.to_string(),
-            battery_power: format!("{:.1}W", battery_power.abs()),
-            grid_status: grid_status.to_string(),
-            grid_power: format!("{:.1}W", grid_power.abs()),
-            home_consumption: format!("{:.1}W", consumption),
+    /// The median of the last nights, once there are enough of them.
+    fn typical_w(&self) -> Option<f64> {
+        if self.nights.len() < OVERNIGHT_MIN_NIGHTS {
+            return None;
         }
+        median(&self.nights.iter().map(|(_, load_w)| *load_w).collect::<Vec<_>>())
     }
 }
 
-async fn get_status(
-    State(state): State<Arc<RwLock<StatusOutput>>>,
-) -> Json<StatusOutput> {
-    let status = state.read().await.clone();
-    Json(status)
+/// Where the hourly load averages of the consumption anomaly alert are kept.
+const CONSUMPTION_HISTORY_PATH: &str = "/srv/solax-mon/data/consumption.json";
+/// How often the usual load per hour is recomputed from the history.
+const CONSUMPTION_BASELINE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Where the learned battery capacity is kept across restarts.
+const BATTERY_CAPACITY_PATH: &str = "/srv/solax-mon/data/battery-capacity.json";
+/// Smallest SoC drop a discharge has to span to be learned from.
+const CAPACITY_MIN_DROP_PCT: f64 = 20.0;
+/// Weight of a new discharge in the smoothed capacity.
+const CAPACITY_SMOOTHING: f64 = 0.3;
+/// Discharges learned before the learned capacity replaces the configured one.
+const CAPACITY_MIN_SEGMENTS: u32 = 2;
+
+/// A discharge in progress. The energy is counted from the first SoC step down, and the
+/// estimate taken at the last one, so the whole-percent SoC doesn't skew it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct DischargeSegment {
+    soc: f64,
+    /// The SoC at the first step down, None until it has stepped.
+    start_soc: Option<f64>,
+    discharged_kwh: f64,
+    /// SoC and energy at the last step down.
+    step_soc: f64,
+    step_kwh: f64,
+    last_time: u64,
+    last_power_w: f64,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let inverter = X3HybridG4::new();
-    
-    // Read secrets from file
-    let (ip, serial) = read_secrets()?;
-    let url = format!("http://{}", ip);
+/// The usable battery capacity learned from discharges without solar: the energy that
+/// left the battery over the SoC it took, smoothed over the discharges seen.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct CapacityLearner {
+    learned_kwh: Option<f64>,
+    segments: u32,
+    last_updated: Option<u64>,
+    /// BATTERY_CAPACITY_KWH when the capacity was last learned. A changed setting is used
+    /// until the next discharge has been learned.
+    configured_kwh: Option<f64>,
+    segment: Option<DischargeSegment>,
+}
 
-    // Create shared state for the web server
-    let shared_status = Arc::new(RwLock::new(StatusOutput {
-        solar_panels: "0.0W".to_string(),
-        batteries: "0.0%".to_string(),
-        battery_status: "Unknown".to_string(),
-        battery_power: "0.0W".to_string(),
-        grid_status: "Unknown".to_string(),
-        grid_power: "0.0W".to_string(),
-        home_consumption: "0.0W".to_string(),
-    }));
+impl CapacityLearner {
+    fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
 
-    // Clone the shared state for the background task
-    let status_clone = shared_status.clone();
+    fn save(&self, path: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save the learned battery capacity to {}: {}", path.display(), e);
+        }
+    }
 
-    // Spawn the data collection task
-    tokio::spawn(async move {
-        loop {
-            match inverter.fetch_data(&url, &serial).await {
-                Ok(measurements) => {
-                    let status = inverter.format_status(&measurements);
-                    *status_clone.write().await = status;
-                    println!("Data updated successfully");
-                },
-                Err(e) => eprintln!("Error fetching data: {}", e),
+    /// Follows a discharge while the battery discharges and there is no solar. It is learned
+    /// from once that ends, and dropped on a gap in the data or a rising SoC, either of
+    /// which would pollute the estimate. Returns the new capacity when one was learned.
+    fn record(&mut self, now: u64, soc: f64, battery_power_w: f64, solar_w: f64, max_gap: Duration, configured_kwh: Option<f64>) -> Option<f64> {
+        let discharging = battery_power_w < -changes::IDLE_W && solar_w < changes::IDLE_W;
+        let start = DischargeSegment {
+            soc,
+            start_soc: None,
+            discharged_kwh: 0.0,
+            step_soc: soc,
+            step_kwh: 0.0,
+            last_time: now,
+            last_power_w: battery_power_w,
+        };
+        let Some(mut segment) = self.segment.take() else {
+            self.segment = discharging.then_some(start);
+            return None;
+        };
+        let elapsed = now.saturating_sub(segment.last_time);
+        if elapsed == 0 || elapsed > max_gap.as_secs() || soc > segment.soc {
+            self.segment = discharging.then_some(start);
+            return None;
+        }
+        if !discharging {
+            return self.learn(&segment, now, configured_kwh);
+        }
+
+        let (_, discharged) = split_energy_kwh(segment.last_power_w, battery_power_w, elapsed as f64 / 3600.0);
+        segment.discharged_kwh += discharged;
+        if soc < segment.soc {
+            match segment.start_soc {
+                None => {
+                    segment.start_soc = Some(soc);
+                    segment.discharged_kwh = 0.0;
+                }
+                Some(_) => {
+                    segment.step_soc = soc;
+                    segment.step_kwh = segment.discharged_kwh;
+                }
             }
-            tokio::time::sleep(Duration::from_secs(60)).await;
         }
-    });
+        segment.soc = soc;
+        segment.last_time = now;
+        segment.last_power_w = battery_power_w;
+        self.segment = Some(segment);
+        None
+    }
 
-    // Create the router
-    let app = Router::new()
-        .route("/status", get(get_status))
-        .with_state(shared_status);
+    fn learn(&mut self, segment: &DischargeSegment, now: u64, configured_kwh: Option<f64>) -> Option<f64> {
+        let drop = segment.start_soc? - segment.step_soc;
+        if drop < CAPACITY_MIN_DROP_PCT || segment.step_kwh <= 0.0 {
+            return None;
+        }
+        let estimate = segment.step_kwh / (drop / 100.0);
+        let learned = match self.learned_kwh {
+            Some(learned) => learned + CAPACITY_SMOOTHING * (estimate - learned),
+            None => estimate,
+        };
+        self.learned_kwh = Some(learned);
+        self.segments += 1;
+        self.last_updated = Some(now);
+        self.configured_kwh = configured_kwh;
+        Some(learned)
+    }
 
-    // Start the server
-    println!("Starting server on http://localhost:3000");
-    axum::Server::bind(&"0.0.0.0:3000".parse()?)
-        .serve(app.into_make_service())
-        .await?;
+    /// The learned capacity once enough discharges were seen since BATTERY_CAPACITY_KWH was
+    /// last changed, otherwise the configured one.
+    fn capacity_kwh(&self, configured_kwh: Option<f64>) -> Option<f64> {
+        match self.learned_kwh {
+            Some(learned) if self.segments >= CAPACITY_MIN_SEGMENTS && self.configured_kwh == configured_kwh => Some(learned),
+            _ => configured_kwh,
+        }
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    fn output(&self, configured_kwh: Option<f64>) -> CapacityOutput {
+        let confidence = match self.segments {
+            0 => "none",
+            segments if segments < CAPACITY_MIN_SEGMENTS => "low",
+            segments if segments < 5 => "medium",
+            _ => "high",
+        };
+        CapacityOutput {
+            configured_kwh,
+            learned_kwh: self.learned_kwh,
+            in_use_kwh: self.capacity_kwh(configured_kwh),
+            segments: self.segments,
+            confidence: confidence.to_string(),
+            last_updated: self.last_updated,
+        }
+    }
+}
+
+fn render_battery_metrics(stats: &BatteryStatsOutput) -> String {
+    let mut out = String::new();
+    let counters = [
+        ("solax_battery_charged_kwh_total", "Energy charged into the battery", Some(stats.lifetime.charged_kwh)),
+        ("solax_battery_discharged_kwh_total", "Energy discharged from the battery", Some(stats.lifetime.discharged_kwh)),
+        ("solax_battery_cycles_total", "Equivalent full battery cycles", stats.lifetime.cycles),
+    ];
+    for (metric, help, value) in counters {
+        let Some(value) = value else { continue };
+        out.push_str(&format!("# HELP {} {}\n", metric, help));
+        out.push_str(&format!("# TYPE {} counter\n", metric));
+        out.push_str(&format!("{} {}\n", metric, value));
+    }
+    out
+}
+
+/// Where changes made through the control endpoints are recorded, one JSON object per line.
+const CONTROL_AUDIT_PATH: &str = "/srv/solax-mon/data/control-audit.log";
+
+/// Size past which a JSON lines log is rotated, and how many rotated files are kept.
+const JSON_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const JSON_LOG_KEEP: usize = 5;
+
+/// The battery mode generation and a forced mode's pending revert, so the revert still
+/// happens after a restart.
+const CONTROL_STATE_PATH: &str = "/srv/solax-mon/data/control.json";
+
+/// Where detected inverter reboots are recorded, one JSON object per line, so the kinks they
+/// leave in the graphs can be explained later.
+const INVERTER_RESTARTS_PATH: &str = "/srv/solax-mon/data/inverter-restarts.log";
+
+/// The firmware the consistency checker last saw, kept so an update across a restart counts.
+const CONSISTENCY_PATH: &str = "/srv/solax-mon/data/consistency.json";
+
+/// The latest failed polls, for `diag`.
+const FETCH_ERRORS_PATH: &str = "/srv/solax-mon/data/fetch-errors.json";
+
+/// Raises the register map alert once the checker suspects the map, and clears it.
+async fn report_register_map(discord: &ControlConfig, transition: consistency::Transition) {
+    let alert = match transition {
+        consistency::Transition::Degraded { firmware, previous_firmware, failing } => {
+            let summary = match &previous_firmware {
+                Some(_) => format!("Register map likely incompatible with firmware {}", firmware),
+                None => format!("Register map likely wrong for firmware {}", firmware),
+            };
+            eprintln!("{}: {}", summary, failing.join("; "));
+            let mut alert = Alert::new(Severity::Critical, format!("🧩 {}", summary))
+                .id("register-map".to_string())
+                .description("Several readings are impossible poll after poll, so the decoded values are most likely garbage. Check the register map against this firmware before trusting the data.")
+                .field("Failing", failing.join("\n"));
+            if let Some(previous) = previous_firmware {
+                alert = alert.field("Updated from", previous);
+            }
+            alert
+        }
+        consistency::Transition::Recovered => {
+            println!("Readings are consistent again");
+            Alert::new(Severity::Normal, "🧩 Readings are consistent again").id("register-map".to_string())
+        }
+    };
+    discord.send_alert(&alert, "the register map alert").await;
+}
+
+/// What the last successful poll showed of the inverter's counters and firmware.
+#[derive(Debug, Clone)]
+struct RestartReading {
+    at: chrono::NaiveDateTime,
+    time: u64,
+    firmware: String,
+    yield_today: Option<f64>,
+    yield_total: Option<f64>,
+}
+
+/// Spots inverter reboots (firmware updates, fault recovery) from one poll to the next.
+#[derive(Debug, Default)]
+struct RestartCheck {
+    last: Option<RestartReading>,
+}
+
+impl RestartCheck {
+    /// Compares a poll taken at local time `at` with the one before; returns the reboot it
+    /// gives away, if any.
+    fn observe(&mut self, at: chrono::NaiveDateTime, time: u64, snapshot: &Snapshot) -> Option<InverterRestart> {
+        use chrono::Timelike;
+
+        let reading = RestartReading {
+            at,
+            time,
+            firmware: snapshot.firmware.clone(),
+            yield_today: snapshot.value("Yield Today"),
+            yield_total: snapshot.value("Yield Total"),
+        };
+        let last = self.last.replace(reading.clone())?;
+        // The inverter's own midnight may be off from TIMEZONE, so a daily reset close to it is expected
+        let near_midnight = !(1..23).contains(&reading.at.hour());
+        let reason = match (last.yield_total, reading.yield_total, last.yield_today, reading.yield_today) {
+            _ if !last.firmware.is_empty() && reading.firmware != last.firmware => {
+                format!("Firmware changed from {} to {}", last.firmware, reading.firmware)
+            }
+            (Some(before), Some(now), _, _) if now < before => format!("Yield Total went down from {} to {} kWh", before, now),
+            (_, _, Some(before), Some(now)) if now < before && last.at.date() == reading.at.date() && !near_midnight => {
+                format!("Yield Today went down from {} to {} kWh during the day", before, now)
+            }
+            _ => return None,
+        };
+        Some(InverterRestart {
+            reason,
+            firmware: reading.firmware,
+            previous_firmware: last.firmware,
+            time,
+            previous_time: last.time,
+        })
+    }
+}
+
+/// Logs a detected inverter reboot, counts it, records it in the restart log and reports it
+/// to Discord and as an event.
+async fn report_inverter_restart(state: &AppState, discord: &ControlConfig, restart: InverterRestart) {
+    println!("Inverter restart detected: {}", restart.reason);
+    state.inverter_restarts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let entry = serde_json::to_value(&restart).unwrap_or_default();
+    append_json_line(Path::new(INVERTER_RESTARTS_PATH), &entry);
+    state.emit(EventKind::Restart, entry).await;
+    let alert = Alert::new(Severity::Warning, "🔄 Inverter restarted")
+        .id("inverter-restart".to_string())
+        .field("Reason", restart.reason.clone());
+    discord.send_alert(&alert, "the inverter restart").await;
+}
+
+/// BMS_LIMIT_ALERT as a threshold rule on how much of the load the battery could not carry.
+fn bms_limit_rule(sustain: Duration) -> ThresholdRule {
+    ThresholdRule {
+        name: "bms_discharge_limit".to_string(),
+        metric: "BMS Discharge Power Limit".to_string(),
+        comparison: Comparison::Above,
+        threshold: 0.0,
+        sustain,
+        cooldown: Duration::ZERO,
+    }
+}
+
+/// How far the load is above what the BMS lets the battery deliver; positive when an outage
+/// right now couldn't be bridged.
+fn bms_shortfall_w(snapshot: &Snapshot) -> Option<f64> {
+    Some(snapshot.value("Load/Generator Power")? - snapshot.value("BMS Discharge Power Limit")?)
+}
+
+/// Warns on Discord that the battery couldn't carry the house, or that it could again.
+async fn report_bms_limit(discord: &ControlConfig, fired: bool, snapshot: &Snapshot) {
+    let watts = |name: &str| snapshot.value(name).map_or("unknown".to_string(), |watts| format!("{:.0} W", watts));
+    let alert = if fired {
+        println!("BMS discharge limit {} is below the {} load", watts("BMS Discharge Power Limit"), watts("Load/Generator Power"));
+        Alert::new(Severity::Warning, "🥶 Battery couldn't carry the house")
+            .description("The BMS limits discharging below the current load, so an outage right now couldn't be bridged whatever the charge. Cold cells are the usual cause.")
+            .field("Discharge limit", watts("BMS Discharge Power Limit"))
+            .field("Load", watts("Load/Generator Power"))
+            .field("Battery", snapshot.value("Battery Remaining Capacity").map_or("unknown".to_string(), |soc| format!("{:.0}%", soc)))
+    } else {
+        println!("BMS discharge limit covers the load again");
+        Alert::new(Severity::Normal, "✅ Battery could carry the house again")
+            .field("Discharge limit", watts("BMS Discharge Power Limit"))
+    };
+    discord.send_alert(&alert.id("bms-discharge-limit".to_string()), "the BMS limit alert").await;
+}
+
+/// How long the module SoCs have to stay apart before BATTERY_MODULE_DRIFT_PCT warns; they
+/// drift over days, so a glitchy read shouldn't count.
+const MODULE_DRIFT_SUSTAIN: Duration = Duration::from_secs(900);
+
+/// BATTERY_MODULE_DRIFT_PCT as a threshold rule on the spread of the module SoCs.
+fn module_drift_rule(delta_pct: f64) -> ThresholdRule {
+    ThresholdRule {
+        name: "battery_module_drift".to_string(),
+        metric: "Battery Module SoC Spread".to_string(),
+        comparison: Comparison::Above,
+        threshold: delta_pct,
+        sustain: MODULE_DRIFT_SUSTAIN,
+        cooldown: Duration::ZERO,
+    }
+}
+
+/// Warns on Discord that a battery module drifted away from the others, or that they're back together.
+async fn report_module_drift(discord: &ControlConfig, fired: bool, snapshot: &Snapshot) {
+    let spread = snapshot.value("Battery Module SoC Spread").map_or("unknown".to_string(), |pct| format!("{:.0} points", pct));
+    let modules = snapshot.battery_modules.iter()
+        .map(|module| format!("#{}: {:.0}% at {:.1} V, {:.0} °C", module.module, module.soc_pct, module.voltage_v, module.temperature_c))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let alert = if fired {
+        println!("Battery module SoCs are {} apart", spread);
+        Alert::new(Severity::Warning, "🔋 Battery modules drifting apart")
+            .description("The modules' SoC differ so much that the pack's SoC is misleading: the lowest module runs empty first. A full charge usually balances them again; if it doesn't, a module may be failing.")
+            .field("Spread", spread)
+            .field("Modules", modules)
+    } else {
+        println!("Battery module SoCs are back together");
+        Alert::new(Severity::Normal, "✅ Battery modules balanced again")
+            .field("Spread", spread)
+    };
+    discord.send_alert(&alert.id("battery-module-drift".to_string()), "the battery module drift alert").await;
+}
+
+/// Recomputes the usual load per hour from the consumption history every hour. Until every
+/// hour of the day has enough history the alert stays off, which is logged when it changes.
+async fn run_consumption_baseline(state: Arc<AppState>) {
+    let mut ready = None;
+    loop {
+        let baseline = state.consumption.read().await.baseline();
+        let now_ready = baseline.is_ready();
+        if ready != Some(now_ready) {
+            if now_ready {
+                println!("Consumption anomaly alert armed with {} days of history", baseline.fewest_days());
+            } else {
+                println!(
+                    "Consumption anomaly alert off until every hour of the day has {} days of history ({} so far)",
+                    anomaly::MIN_DAYS,
+                    baseline.fewest_days()
+                );
+            }
+            ready = Some(now_ready);
+        }
+        *state.consumption_baseline.write().await = now_ready.then_some(baseline);
+        tokio::time::sleep(CONSUMPTION_BASELINE_INTERVAL).await;
+    }
+}
+
+/// Reports the load going above or back within its usual band to Discord.
+async fn report_consumption_anomaly(discord: &ControlConfig, transition: anomaly::Transition, now: u64) {
+    let kw = |watts: f64| format!("{:.1} kW", watts / 1000.0);
+    let alert = match transition {
+        anomaly::Transition::Fired(anomaly) => {
+            println!(
+                "Consumption anomaly: {} W against a usual {} W at {:02}:00",
+                anomaly.load_w.round(),
+                anomaly.typical_w.round(),
+                anomaly.hour
+            );
+            Alert::new(Severity::Warning, "🔥 Unusual consumption")
+                .description(format!("{} above normal for {:02}:00", kw(anomaly.excess_w()), anomaly.hour))
+                .field("Load", kw(anomaly.load_w))
+                .field("Usual", kw(anomaly.typical_w))
+                .field("Alert above", kw(anomaly.limit_w))
+                .field("For", format!("{} min", now.saturating_sub(anomaly.since) / 60))
+        }
+        anomaly::Transition::Cleared => {
+            println!("Consumption back within its usual band");
+            Alert::new(Severity::Normal, "✅ Consumption back to normal")
+        }
+    };
+    discord.send_alert(&alert.id("consumption-anomaly".to_string()), "the consumption anomaly").await;
+}
+
+/// Keeps a poll's changes for /status/changes and publishes each of them as a `change`
+/// event and on the MQTT event topic.
+async fn publish_changes(state: &AppState, diff: SnapshotDiff) {
+    for change in &diff.changes {
+        let mut entry = serde_json::to_value(change).unwrap_or_default();
+        if let Some(entry) = entry.as_object_mut() {
+            entry.insert("seq".to_string(), diff.seq.into());
+            entry.insert("previous_seq".to_string(), diff.previous_seq.into());
+        }
+        state.emit(EventKind::Change, entry.clone()).await;
+        if let Some((client, topic)) = state.mqtt.get() {
+            if let Some(entry) = entry.as_object_mut() {
+                entry.insert("type".to_string(), "change".into());
+                entry.insert("time".to_string(), diff.time.into());
+            }
+            let payload = serde_json::to_vec(&entry).unwrap_or_default();
+            if let Err(e) = client.publish(topic, rumqttc::QoS::AtLeastOnce, false, payload).await {
+                eprintln!("Failed to publish a change over MQTT: {}", e);
+            }
+        }
+    }
+    *state.changes.write().await = Some(diff);
+}
+
+/// Inverter writes over HTTP, only set up with CONTROL_ENABLED=true.
+struct Control {
+    /// Queues writes behind the poller's requests on the one task talking to the dongle.
+    inverter: dongle::Handle,
+    /// Held across a read and a write of the same setting, so two control requests
+    /// can't interleave theirs.
+    writing: tokio::sync::Mutex<()>,
+    config: ControlConfig,
+    /// Bumped (under `writing`) on every battery mode change, so a revert timer only
+    /// fires if nothing else was set since it was started.
+    battery_mode_generation: std::sync::atomic::AtomicU64,
+    /// The revert of a forced mode, saved with the generation to `state_path` on every change.
+    revert: std::sync::Mutex<Option<PendingRevert>>,
+    /// Wakes run_battery_revert when a revert is scheduled.
+    revert_scheduled: tokio::sync::Notify,
+    state_path: PathBuf,
+}
+
+impl Control {
+    fn new(inverter: dongle::Handle, config: ControlConfig) -> Self {
+        Self {
+            inverter,
+            writing: tokio::sync::Mutex::new(()),
+            config,
+            battery_mode_generation: std::sync::atomic::AtomicU64::new(0),
+            revert: std::sync::Mutex::new(None),
+            revert_scheduled: tokio::sync::Notify::new(),
+            state_path: PathBuf::from(CONTROL_STATE_PATH),
+        }
+    }
+
+    /// Picks up the generation and pending revert of the previous run.
+    fn load_state(&self) {
+        let saved: ControlState = std::fs::read_to_string(&self.state_path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        self.battery_mode_generation.store(saved.generation, std::sync::atomic::Ordering::SeqCst);
+        *self.revert.lock().unwrap_or_else(|e| e.into_inner()) = saved.revert;
+    }
+
+    /// Replaces the pending revert and saves it with the current generation.
+    fn set_revert(&self, revert: Option<PendingRevert>) {
+        let mut pending = self.revert.lock().unwrap_or_else(|e| e.into_inner());
+        *pending = revert;
+        let saved = ControlState {
+            generation: self.battery_mode_generation.load(std::sync::atomic::Ordering::SeqCst),
+            revert,
+        };
+        let result = serde_json::to_string(&saved)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(&self.state_path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save the control state to {}: {}", self.state_path.display(), e);
+        }
+    }
+
+    fn pending_revert(&self) -> Option<PendingRevert> {
+        *self.revert.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// What CONTROL_STATE_PATH holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+struct ControlState {
+    generation: u64,
+    revert: Option<PendingRevert>,
+}
+
+/// A forced battery mode's way back to self use.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct PendingRevert {
+    /// The generation the forced mode was set in; any later change cancels the revert.
+    generation: u64,
+    revert_at: u64,
+    /// Failed writes so far, and when the next one is due.
+    #[serde(default)]
+    attempts: u32,
+    #[serde(default)]
+    retry_at: Option<u64>,
+}
+
+/// A failed revert is retried after REVERT_RETRY_SECS, doubling each time, and given up with
+/// a critical alert after REVERT_ATTEMPTS writes.
+const REVERT_ATTEMPTS: u32 = 6;
+const REVERT_RETRY_SECS: u64 = 30;
+
+impl PendingRevert {
+    fn due_at(&self) -> u64 {
+        self.retry_at.unwrap_or(self.revert_at)
+    }
+}
+
+/// What one successful poll produced, published as a whole so readers never see half of it.
+#[derive(Debug, Clone)]
+struct Published {
+    status: StatusOutput,
+    raw: RawOutput,
+    /// The decoded snapshot under canonical measurement names, for internal consumers.
+    snapshot: Option<Snapshot>,
+}
+
+struct AppState {
+    /// The latest poll.
+    latest: Latest<Published>,
+    health: RwLock<HealthOutput>,
+    availability: RwLock<Availability>,
+    battery: RwLock<BatteryThroughput>,
+    grid: RwLock<GridEnergy>,
+    overnight: RwLock<OvernightLoad>,
+    /// Hourly load averages for the consumption anomaly alert, and the usual load learned
+    /// from them once there is enough history.
+    consumption: RwLock<anomaly::History>,
+    consumption_baseline: RwLock<Option<anomaly::Baseline>>,
+    battery_capacity_kwh: Option<f64>,
+    /// CHARGE_TEMP_FLOOR_C and CHARGE_TEMP_REDUCED_C, for the charge advisory.
+    charge_temperatures: ChargeTemperatures,
+    /// BATTERY_MODULE_DRIFT_PCT, for /status/battery/modules.
+    battery_module_drift_pct: Option<f64>,
+    /// The threshold rules as of the last poll, and the hash of their configuration; None
+    /// without rules.
+    rules: RwLock<Vec<RuleMetrics>>,
+    rules_hash: Option<String>,
+    /// Signs /status/raw and is published on /info, with STATUS_SIGNING_KEY.
+    signing: Option<signing::SigningKey>,
+    /// DEBUG_TOKEN, without which /debug/decode is off, and the latest poll for it.
+    debug_token: Option<String>,
+    /// The measurements of the inverter profile, for /measurements.
+    catalog: CatalogOutput,
+    /// INGEST_TOKEN, without which /ingest/external is off, and the EXTERNAL_METER readings.
+    ingest_token: Option<String>,
+    external: RwLock<external::Meters>,
+    decode: RwLock<Option<DecodeOutput>>,
+    http_stats: RwLock<HttpStatsOutput>,
+    info: RwLock<Option<InfoOutput>>,
+    evc: RwLock<Option<EvcStatusOutput>>,
+    surplus: RwLock<SurplusOutput>,
+    /// The inverter settings as last read; None while the settings poller is off.
+    settings: RwLock<Option<SettingsOutput>>,
+    /// CURTAILMENT_ESTIMATE, without which /stats/curtailment is off, and the estimates.
+    curtailment_estimate: Option<CurtailmentEstimate>,
+    curtailment: RwLock<Curtailment>,
+    /// EXPORT_COMPLIANCE_LIMIT_W, without which /reports/export-compliance is off.
+    compliance: Option<ComplianceConfig>,
+    /// The daily rollups behind /reports/monthly and /reports/yearly, and the reports of
+    /// periods that have ended, which don't change any more.
+    rollups: RwLock<Rollups>,
+    reports: RwLock<HashMap<String, ReportOutput>>,
+    /// TIMEZONE, which the days of the reports are in.
+    timezone: chrono_tz::Tz,
+    zabbix: RwLock<ZabbixStatsOutput>,
+    /// Failures of the Zabbix pushes, which are spawned per poll.
+    zabbix_warnings: std::sync::Mutex<Warnings>,
+    redis: RwLock<RedisStatsOutput>,
+    postgres: RwLock<PostgresStatsOutput>,
+    /// The queue to run_event_publisher, once it has been started.
+    events: std::sync::OnceLock<tokio::sync::mpsc::Sender<(EventKind, serde_json::Value)>>,
+    event_stats: RwLock<EventStatsOutput>,
+    control: Option<Control>,
+    /// Wakes the poll loop early (the MQTT `poll_now` command).
+    poll_now: tokio::sync::Notify,
+    /// Set by the MQTT `pause_monitor` command; the poll loop idles until it's cleared.
+    polling_paused: std::sync::atomic::AtomicBool,
+    stale_after: Duration,
+    /// The staleness window while night mode stretches the poll interval.
+    night_stale_after: Option<Duration>,
+    night_mode: std::sync::atomic::AtomicBool,
+    /// The staleness window while power-save mode stretches the poll interval.
+    power_save_stale_after: Option<Duration>,
+    /// Set during an outage with a low battery; the push sinks and peer polling pause meanwhile.
+    power_save: std::sync::atomic::AtomicBool,
+    /// Inverter reboots detected since the service started.
+    inverter_restarts: std::sync::atomic::AtomicU64,
+    capacity: RwLock<CapacityLearner>,
+    /// The last poll that changed something, for /status/changes.
+    changes: RwLock<Option<SnapshotDiff>>,
+    /// The MQTT connection with its event topic, once run_mqtt_commands has set it up.
+    mqtt: std::sync::OnceLock<(rumqttc::AsyncClient, String)>,
+    /// The peer instances with what their poller last saw, in FEDERATION_PEER order.
+    federation: RwLock<Vec<(federation::Peer, PeerState)>>,
+    federation_stale_after: Duration,
+    /// The system clock, or a manual one in tests.
+    clock: Arc<dyn Clock>,
+}
+
+impl AppState {
+    /// The state of the service for `config`, before anything is loaded from disk.
+    fn from_config(config: &Config, inverter: &X3HybridG4) -> Self {
+        let mut state = AppState::new(inverter.sources.clone(), config.polling.stale_after());
+        state.night_stale_after = config.night.as_ref().map(|night| night.stale_after(&config.polling));
+        state.power_save_stale_after = config.power_save.as_ref().map(|power_save| power_save.stale_after(&config.polling));
+        state.battery_capacity_kwh = config.battery_capacity_kwh;
+        state.charge_temperatures = config.charge_temperatures;
+        state.battery_module_drift_pct = config.battery_module_drift_pct;
+        state.signing = config.signing.clone();
+        state.debug_token = config.debug_token.clone();
+        state.ingest_token = config.ingest_token.clone();
+        state.external = RwLock::new(external::Meters::new(config.external_meters.clone()));
+        state.catalog = CatalogOutput { measurements: inverter.catalog(&config.publish) };
+        state.curtailment_estimate = config.curtailment;
+        state.compliance = config.compliance.clone();
+        state.timezone = config.timezone;
+        let rules: Vec<ThresholdRule> = config.thresholds.rules.iter().cloned()
+            .chain(config.bms_limit_alert.map(bms_limit_rule))
+            .chain(config.battery_module_drift_pct.map(module_drift_rule))
+            .collect();
+        state.rules_hash = (!rules.is_empty()).then(|| rules_hash(&rules));
+        state.federation = RwLock::new(config.federation.peers.iter().map(|peer| (peer.clone(), PeerState::default())).collect());
+        state.federation_stale_after = config.federation.stale_after;
+        state
+    }
+
+    fn new(sources: Vec<SourceHealth>, stale_after: Duration) -> Self {
+        Self {
+            latest: Latest::new(Published {
+                status: StatusOutput {
+                    labels: BTreeMap::new(),
+                    solar_panels: "0.0W".to_string(),
+                    batteries: "0.0%".to_string(),
+                    battery_soc_raw: "0.0%".to_string(),
+                    battery_status: "Unknown".to_string(),
+                    battery_power: "0.0W".to_string(),
+                    grid_status: "Unknown".to_string(),
+                    grid_power: "0.0W".to_string(),
+                    home_consumption: "0.0W".to_string(),
+                    bms_discharge_limit: String::new(),
+                    partial: false,
+                    battery_power_w: None,
+                    battery_direction: None,
+                    grid_power_w: None,
+                    grid_direction: None,
+                    circuits_w: BTreeMap::new(),
+                    essential_load_w: None,
+                },
+                raw: RawOutput::default(),
+                snapshot: None,
+            }),
+            health: RwLock::new(HealthOutput {
+                sources,
+                ..HealthOutput::default()
+            }),
+            availability: RwLock::new(Availability::default()),
+            battery: RwLock::new(BatteryThroughput::default()),
+            grid: RwLock::new(GridEnergy::default()),
+            overnight: RwLock::new(OvernightLoad::default()),
+            consumption: RwLock::new(anomaly::History::default()),
+            consumption_baseline: RwLock::new(None),
+            battery_capacity_kwh: None,
+            charge_temperatures: ChargeTemperatures::default(),
+            battery_module_drift_pct: None,
+            rules: RwLock::new(Vec::new()),
+            rules_hash: None,
+            signing: None,
+            debug_token: None,
+            catalog: CatalogOutput::default(),
+            ingest_token: None,
+            external: RwLock::new(external::Meters::default()),
+            decode: RwLock::new(None),
+            http_stats: RwLock::new(HttpStatsOutput::default()),
+            info: RwLock::new(None),
+            evc: RwLock::new(None),
+            surplus: RwLock::new(SurplusOutput::default()),
+            settings: RwLock::new(None),
+            curtailment_estimate: None,
+            curtailment: RwLock::new(Curtailment::default()),
+            compliance: None,
+            rollups: RwLock::new(Rollups::default()),
+            reports: RwLock::new(HashMap::new()),
+            timezone: chrono_tz::UTC,
+            zabbix: RwLock::new(ZabbixStatsOutput::default()),
+            zabbix_warnings: std::sync::Mutex::new(Warnings::new()),
+            redis: RwLock::new(RedisStatsOutput::default()),
+            postgres: RwLock::new(PostgresStatsOutput::default()),
+            events: std::sync::OnceLock::new(),
+            event_stats: RwLock::new(EventStatsOutput::default()),
+            control: None,
+            poll_now: tokio::sync::Notify::new(),
+            polling_paused: std::sync::atomic::AtomicBool::new(false),
+            clock: Arc::new(SystemClock),
+            stale_after,
+            night_stale_after: None,
+            night_mode: std::sync::atomic::AtomicBool::new(false),
+            power_save_stale_after: None,
+            power_save: std::sync::atomic::AtomicBool::new(false),
+            inverter_restarts: std::sync::atomic::AtomicU64::new(0),
+            capacity: RwLock::new(CapacityLearner::default()),
+            changes: RwLock::new(None),
+            mqtt: std::sync::OnceLock::new(),
+            federation: RwLock::new(Vec::new()),
+            federation_stale_after: Duration::from_secs(300),
+        }
+    }
+
+    /// The battery capacity for runtime estimates: learned or BATTERY_CAPACITY_KWH.
+    async fn capacity_kwh(&self) -> Option<f64> {
+        self.capacity.read().await.capacity_kwh(self.battery_capacity_kwh)
+    }
+
+    /// The report of `period` so far, computed once for a period that has ended.
+    async fn report(&self, period: Period) -> ReportOutput {
+        let label = period.label();
+        if let Some(report) = self.reports.read().await.get(&label) {
+            return report.clone();
+        }
+        let today = self.clock.utc_now().with_timezone(&self.timezone).date_naive();
+        let report = self.rollups.read().await.report(period, today);
+        if !report.partial {
+            self.reports.write().await.insert(label, report.clone());
+        }
+        report
+    }
+
+    /// Queues an event for the broker without waiting; dropped if the queue is full.
+    async fn emit(&self, kind: EventKind, data: serde_json::Value) {
+        let Some(events) = self.events.get() else { return };
+        if events.try_send((kind, data)).is_err() {
+            self.event_stats.write().await.dropped += 1;
+        }
+    }
+
+    /// Writes a control audit entry and publishes it as an action event.
+    async fn audit(&self, entry: serde_json::Value) {
+        append_json_line(Path::new(CONTROL_AUDIT_PATH), &entry);
+        self.emit(EventKind::Action, entry).await;
+    }
+
+    /// How old the last successful poll may get, stretched while in night or power-save mode.
+    fn stale_window(&self) -> Duration {
+        let night = match self.night_stale_after {
+            Some(night) if self.night_mode.load(std::sync::atomic::Ordering::SeqCst) => night,
+            _ => self.stale_after,
+        };
+        match self.power_save_stale_after {
+            Some(power_save) if self.power_save.load(std::sync::atomic::Ordering::SeqCst) => night.max(power_save),
+            _ => night,
+        }
+    }
+
+    /// The latest snapshot, unless it is older than the staleness window.
+    async fn fresh_snapshot(&self) -> Option<Snapshot> {
+        let last_success = self.health.read().await.last_success?;
+        if self.clock.unix_now().saturating_sub(last_success) > self.stale_window().as_secs() {
+            return None;
+        }
+        self.latest().snapshot.clone()
+    }
+
+    fn latest(&self) -> Arc<Published> {
+        self.latest.get()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct NutConfig {
+    listen_addrs: Vec<SocketAddr>,
+    ups_name: String,
+    username: Option<String>,
+    password: Option<String>,
+    low_battery_pct: f64,
+}
+
+impl Default for NutConfig {
+    fn default() -> Self {
+        Self {
+            listen_addrs: Vec::new(),
+            ups_name: "solax".to_string(),
+            username: None,
+            password: None,
+            low_battery_pct: 10.0,
+        }
+    }
+}
+
+/// Maps a snapshot onto the NUT variables upsmon and upsc expect.
+fn nut_variables(snapshot: &Snapshot, config: &NutConfig) -> Vec<(&'static str, String)> {
+    let charge = snapshot.value("Battery Remaining Capacity").unwrap_or(0.0);
+    let battery_power = snapshot.value("Battery Power").unwrap_or(0.0);
+
+    let mut status = Vec::new();
+    if snapshot.on_grid() {
+        status.push("OL");
+        if battery_power > 0.0 {
+            status.push("CHRG");
+        }
+    } else {
+        status.push("OB");
+        status.push("DISCHRG");
+    }
+    if charge < config.low_battery_pct {
+        status.push("LB");
+    }
+
+    let mut vars = vec![
+        ("battery.charge", format!("{:.0}", charge)),
+        ("battery.charge.low", format!("{:.0}", config.low_battery_pct)),
+        ("device.mfr", "SolaX Power".to_string()),
+        ("device.model", snapshot.model.clone()),
+        ("device.serial", snapshot.sn.clone()),
+        ("device.type", "ups".to_string()),
+        ("driver.name", "solax-mon".to_string()),
+        ("ups.mfr", "SolaX Power".to_string()),
+        ("ups.model", snapshot.model.clone()),
+        ("ups.serial", snapshot.sn.clone()),
+        ("ups.status", status.join(" ")),
+    ];
+    if let Some(voltage) = snapshot.value("Grid 1 Voltage") {
+        vars.push(("input.voltage", format!("{:.1}", voltage)));
+    }
+    if let Some(load) = snapshot.value("Load/Generator Power") {
+        vars.push(("ups.realpower", format!("{:.0}", load)));
+    }
+    vars
+}
+
+/// Splits a NUT protocol line into words, honouring double quotes.
+fn nut_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => current.extend(chars.next()),
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    words.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+    words
+}
+
+#[derive(Default)]
+struct NutSession {
+    username: Option<String>,
+    password: Option<String>,
+    logged_in: bool,
+}
+
+impl NutSession {
+    fn authorized(&self, config: &NutConfig) -> bool {
+        config.username.is_none()
+            || (self.username == config.username && self.password == config.password)
+    }
+}
+
+/// Answers one NUT command. Returns None when the client should be disconnected.
+fn nut_response(
+    line: &str,
+    session: &mut NutSession,
+    config: &NutConfig,
+    snapshot: Option<&Snapshot>,
+    logins: usize,
+) -> Option<String> {
+    let words = nut_words(line);
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let ups = config.ups_name.as_str();
+    let unknown_ups = |name: &str| name != ups;
+
+    let response = match words.as_slice() {
+        ["VER"] => format!("solax-mon {}", env!("CARGO_PKG_VERSION")),
+        ["NETVER"] => "1.3".to_string(),
+        ["HELP"] => "Commands: HELP VER NETVER GET LIST USERNAME PASSWORD LOGIN LOGOUT".to_string(),
+        ["STARTTLS"] => "ERR FEATURE-NOT-CONFIGURED".to_string(),
+        ["USERNAME", name] => {
+            session.username = Some(name.to_string());
+            "OK".to_string()
+        }
+        ["PASSWORD", password] => {
+            session.password = Some(password.to_string());
+            "OK".to_string()
+        }
+        ["LOGIN", name] | ["PRIMARY", name] | ["MASTER", name] => {
+            if unknown_ups(name) {
+                "ERR UNKNOWN-UPS".to_string()
+            } else if !session.authorized(config) {
+                "ERR ACCESS-DENIED".to_string()
+            } else {
+                if words[0] == "LOGIN" {
+                    session.logged_in = true;
+                }
+                "OK".to_string()
+            }
+        }
+        ["LOGOUT"] => return None,
+        ["LIST", "UPS"] => format!(
+            "BEGIN LIST UPS\nUPS {} \"SolaX inverter battery\"\nEND LIST UPS",
+            ups
+        ),
+        ["LIST", "VAR", name] if !unknown_ups(name) => match snapshot {
+            Some(snapshot) => {
+                let mut lines = vec![format!("BEGIN LIST VAR {}", ups)];
+                lines.extend(nut_variables(snapshot, config).into_iter()
+                    .map(|(var, value)| format!("VAR {} {} \"{}\"", ups, var, value)));
+                lines.push(format!("END LIST VAR {}", ups));
+                lines.join("\n")
+            }
+            None => "ERR DATA-STALE".to_string(),
+        },
+        ["LIST", "CMD" | "RW" | "CLIENT", name] if !unknown_ups(name) => {
+            format!("BEGIN LIST {} {}\nEND LIST {} {}", words[1], ups, words[1], ups)
+        }
+        ["GET", "VAR", name, var] if !unknown_ups(name) => match snapshot {
+            Some(snapshot) => nut_variables(snapshot, config).into_iter()
+                .find(|(name, _)| name == var)
+                .map_or("ERR VAR-NOT-SUPPORTED".to_string(), |(_, value)| {
+                    format!("VAR {} {} \"{}\"", ups, var, value)
+                }),
+            None => "ERR DATA-STALE".to_string(),
+        },
+        ["GET", "UPSDESC", name] if !unknown_ups(name) => format!("UPSDESC {} \"SolaX inverter battery\"", ups),
+        ["GET", "NUMLOGINS", name] if !unknown_ups(name) => format!("NUMLOGINS {} {}", ups, logins),
+        ["GET" | "LIST", ..] => "ERR UNKNOWN-UPS".to_string(),
+        _ => "ERR UNKNOWN-COMMAND".to_string(),
+    };
+    Some(response)
+}
+
+async fn serve_nut(listener: tokio::net::TcpListener, state: Arc<AppState>, config: NutConfig) {
+    let logins = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("NUT server accept failed: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        let config = config.clone();
+        let logins = logins.clone();
+        tokio::spawn(async move {
+            use std::sync::atomic::Ordering;
+            use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = tokio::io::BufReader::new(reader).lines();
+            let mut session = NutSession::default();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let snapshot = state.fresh_snapshot().await;
+                let was_logged_in = session.logged_in;
+                let response = nut_response(&line, &mut session, &config, snapshot.as_ref(), logins.load(Ordering::Relaxed));
+                if session.logged_in && !was_logged_in {
+                    logins.fetch_add(1, Ordering::Relaxed);
+                }
+                let Some(response) = response else {
+                    let _ = writer.write_all(b"OK Goodbye\n").await;
+                    break;
+                };
+                if writer.write_all(format!("{}\n", response).as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            if session.logged_in {
+                logins.fetch_sub(1, Ordering::Relaxed);
+            }
+            println!("NUT client {} disconnected", peer);
+        });
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ApcupsdConfig {
+    listen_addrs: Vec<SocketAddr>,
+    ups_name: String,
+    low_battery_pct: f64,
+}
+
+impl Default for ApcupsdConfig {
+    fn default() -> Self {
+        Self {
+            listen_addrs: Vec::new(),
+            ups_name: "solax".to_string(),
+            low_battery_pct: 10.0,
+        }
+    }
+}
+
+/// Builds the `apcaccess status` records for a snapshot, each formatted as `KEY      : value`.
+fn apcupsd_status_records(
+    snapshot: &Snapshot,
+    config: &ApcupsdConfig,
+    battery_capacity_kwh: Option<f64>,
+    now: u64,
+) -> Vec<String> {
+    let charge = snapshot.value("Battery Remaining Capacity").unwrap_or(0.0);
+    let mut status = if snapshot.on_grid() { "ONLINE" } else { "ONBATT" }.to_string();
+    if charge < config.low_battery_pct {
+        status.push_str(" LOWBATT");
+    }
+
+    let mut fields = vec![
+        ("DATE", format!("{}", now)),
+        ("HOSTNAME", "solax-mon".to_string()),
+        ("VERSION", format!("solax-mon {}", env!("CARGO_PKG_VERSION"))),
+        ("UPSNAME", config.ups_name.clone()),
+        ("DRIVER", "solax-mon".to_string()),
+        ("UPSMODE", "Stand Alone".to_string()),
+        ("MODEL", snapshot.model.clone()),
+        ("STATUS", status),
+        ("BCHARGE", format!("{:.1} Percent", charge)),
+        ("MBATTCHG", format!("{:.0} Percent", config.low_battery_pct)),
+        ("SERIALNO", snapshot.sn.clone()),
+    ];
+    if let Some(voltage) = snapshot.value("Grid 1 Voltage") {
+        fields.push(("LINEV", format!("{:.1} Volts", voltage)));
+    }
+    if let Some(minutes) = battery_capacity_kwh.and_then(|kwh| snapshot.time_to_empty_minutes(kwh)) {
+        fields.push(("TIMELEFT", format!("{:.1} Minutes", minutes)));
+    }
+
+    let mut records: Vec<String> = fields.into_iter()
+        .map(|(key, value)| format!("{:<9}: {}\n", key, value))
+        .collect();
+    let length: usize = records.iter().map(String::len).sum();
+    records.insert(0, format!("{:<9}: 001,{:03},{:04}\n", "APC", records.len() + 2, length));
+    records.push(format!("{:<9}: {}\n", "END APC", now));
+    records
+}
+
+/// Speaks the apcupsd NIS protocol: every message is prefixed with a big-endian
+/// u16 length, and a zero-length message ends a response.
+async fn serve_apcupsd(
+    listener: tokio::net::TcpListener,
+    state: Arc<AppState>,
+    config: ApcupsdConfig,
+) {
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("apcupsd NIS accept failed: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            while let Ok(length) = stream.read_u16().await {
+                let mut command = vec![0; length as usize];
+                if stream.read_exact(&mut command).await.is_err() {
+                    break;
+                }
+
+                let records = match command.as_slice() {
+                    b"status" => match state.fresh_snapshot().await {
+                        Some(snapshot) => apcupsd_status_records(&snapshot, &config, state.capacity_kwh().await, state.clock.unix_now()),
+                        None => vec![format!("{:<9}: {}\n", "STATUS", "COMMLOST")],
+                    },
+                    b"events" => Vec::new(),
+                    _ => break,
+                };
+
+                let mut response = Vec::new();
+                for record in records {
+                    response.extend_from_slice(&(record.len() as u16).to_be_bytes());
+                    response.extend_from_slice(record.as_bytes());
+                }
+                response.extend_from_slice(&0u16.to_be_bytes());
+                if stream.write_all(&response).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Warns when the power balance residual stays above a threshold for several
+/// consecutive polls, which usually means the register map doesn't fit the firmware.
+struct BalanceCheck {
+    config: BalanceConfig,
+    consecutive: u32,
+}
+
+#[derive(Debug, Clone)]
+struct BalanceConfig {
+    threshold_w: f64,
+    polls: u32,
+}
+
+impl BalanceCheck {
+    fn new(config: BalanceConfig) -> Self {
+        Self { config, consecutive: 0 }
+    }
+
+    /// Follows the residual; returns true when the warning fires and false when it clears.
+    fn observe(&mut self, snapshot: &Snapshot) -> Option<bool> {
+        let residual = snapshot.measurements.get("Power Balance Residual")?;
+
+        if residual.value.abs() > self.config.threshold_w {
+            self.consecutive += 1;
+            if self.consecutive == self.config.polls {
+                eprintln!(
+                    "Power balance residual of {:.0}W exceeded {:.0}W for {} consecutive polls; \
+                    the register map may be wrong for this inverter firmware",
+                    residual.value, self.config.threshold_w, self.consecutive
+                );
+                return Some(true);
+            }
+        } else {
+            let warned = self.consecutive >= self.config.polls;
+            self.consecutive = 0;
+            if warned {
+                println!("Power balance residual back within {:.0}W", self.config.threshold_w);
+                return Some(false);
+            }
+        }
+        None
+    }
+}
+
+/// How close the house may get to the EPS output limit during an outage.
+#[derive(Debug, Clone)]
+struct EpsConfig {
+    /// EPS_LIMIT_W; the rated power from the Information array when None.
+    limit_w: Option<f64>,
+    /// Headroom below which the overload warning fires; 0 turns it off.
+    margin_w: f64,
+}
+
+/// Headroom the warning needs back, above the margin, before it clears.
+const EPS_HYSTERESIS_W: f64 = 200.0;
+
+/// Warns while the load on the EPS output comes close to what the inverter can deliver off
+/// grid, before it trips and drops everything.
+#[derive(Debug)]
+struct EpsCheck {
+    config: EpsConfig,
+    overloaded: bool,
+}
+
+impl EpsCheck {
+    fn new(config: EpsConfig) -> Self {
+        Self { config, overloaded: false }
+    }
+
+    fn limit_w(&self, rated_power_kw: Option<f64>) -> Option<f64> {
+        self.config.limit_w.or(rated_power_kw.map(|kw| kw * 1000.0))
+    }
+
+    /// What the EPS output has left; only while the inverter runs in EPS mode.
+    fn headroom_w(&self, snapshot: &Snapshot, rated_power_kw: Option<f64>) -> Option<f64> {
+        if snapshot.run_mode() != Some(RunMode::Eps) {
+            return None;
+        }
+        Some(self.limit_w(rated_power_kw)? - snapshot.value("Load/Generator Power")?)
+    }
+
+    /// Follows the headroom; returns true when the warning fires and false when it clears.
+    fn observe(&mut self, headroom_w: Option<f64>) -> Option<bool> {
+        if self.config.margin_w <= 0.0 {
+            return None;
+        }
+        let overloaded = match headroom_w {
+            Some(headroom_w) if self.overloaded => headroom_w < self.config.margin_w + EPS_HYSTERESIS_W,
+            Some(headroom_w) => headroom_w < self.config.margin_w,
+            None => false,
+        };
+        if overloaded == self.overloaded {
+            return None;
+        }
+        self.overloaded = overloaded;
+        Some(overloaded)
+    }
+}
+
+/// Asks for loads to be switched off before the EPS output trips, or says it's safe again.
+async fn report_eps_overload(discord: &ControlConfig, fired: bool, snapshot: &Snapshot, limit_w: Option<f64>, headroom_w: Option<f64>) {
+    let watts = |watts: Option<f64>| watts.map_or("unknown".to_string(), |watts| format!("{:.0} W", watts));
+    let alert = if fired {
+        println!("EPS load {} is within {} of the {} limit", watts(snapshot.value("Load/Generator Power")), watts(headroom_w), watts(limit_w));
+        Alert::new(Severity::Critical, "🔌 EPS output close to overload")
+            .description("The house runs on the battery and is about to exceed what the inverter can deliver off grid. Switch heavy loads off now, or the EPS output trips and everything goes dark.")
+            .field("Load", watts(snapshot.value("Load/Generator Power")))
+            .field("Limit", watts(limit_w))
+            .field("Headroom", watts(headroom_w))
+            .field("Solar", watts(snapshot.value("Total Solar Power")))
+            .field("Battery", snapshot.value("Battery Remaining Capacity").map_or("unknown".to_string(), |soc| format!("{:.0}%", soc)))
+    } else {
+        println!("EPS load back within its margin");
+        Alert::new(Severity::Normal, "✅ EPS load back within its margin")
+            .field("Load", watts(snapshot.value("Load/Generator Power")))
+    };
+    discord.send_alert(&alert.id("eps-overload".to_string()), "the EPS overload warning").await;
+}
+
+#[derive(Debug, Clone)]
+struct PollingConfig {
+    interval: Duration,
+    jitter: Duration,
+    min_spacing: Duration,
+    request_timeout: Duration,
+    cooldown_after: u32,
+    cooldown: Duration,
+}
+
+impl PollingConfig {
+    /// How old the last successful poll may get before the service reports itself unhealthy.
+    fn stale_after(&self) -> Duration {
+        self.interval * 3 + self.jitter
+    }
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            jitter: Duration::from_secs(5),
+            min_spacing: Duration::from_secs(2),
+            request_timeout: Duration::from_secs(10),
+            cooldown_after: 5,
+            cooldown: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Slower polling while the dongle sleeps at night (NIGHT_WINDOW).
+#[derive(Debug, Clone)]
+struct NightConfig {
+    /// Local time window, which may wrap around midnight.
+    start: chrono::NaiveTime,
+    end: chrono::NaiveTime,
+    /// How long solar power has to be zero before night mode starts.
+    idle_after: Duration,
+    interval: Duration,
+    /// A successful poll with a PV string above this voltage ends night mode early.
+    wake_pv_voltage: f64,
+}
+
+impl NightConfig {
+    fn parse_window(value: &str) -> Result<(chrono::NaiveTime, chrono::NaiveTime), String> {
+        let (start, end) = value.split_once('-').ok_or("NIGHT_WINDOW must be HH:MM-HH:MM")?;
+        let time = |text: &str| chrono::NaiveTime::parse_from_str(text.trim(), "%H:%M")
+            .map_err(|_| format!("Invalid time in NIGHT_WINDOW: {}", text));
+        Ok((time(start)?, time(end)?))
+    }
+
+    fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// The staleness window while polling at the night interval.
+    fn stale_after(&self, polling: &PollingConfig) -> Duration {
+        self.interval.max(polling.cooldown) * 3 + polling.jitter
+    }
+}
+
+/// Tracks whether polling is in night mode.
+struct NightMode {
+    config: NightConfig,
+    active: bool,
+    /// Since when successful polls have shown no solar power.
+    dark_since: Option<u64>,
+}
+
+impl NightMode {
+    fn new(config: NightConfig) -> Self {
+        Self { config, active: false, dark_since: None }
+    }
+
+    /// Updates the mode after a poll (None when it failed); returns the new mode when it changed.
+    fn observe(&mut self, time: chrono::NaiveTime, now: u64, snapshot: Option<&Snapshot>) -> Option<bool> {
+        let in_window = self.config.contains(time);
+        let solar_w = snapshot.map(|snapshot| {
+            snapshot.value("PV1 Power").unwrap_or_default() + snapshot.value("PV2 Power").unwrap_or_default()
+        });
+        match solar_w {
+            Some(watts) if watts > 0.0 => self.dark_since = None,
+            Some(_) => {
+                self.dark_since.get_or_insert(now);
+            }
+            // A failed poll neither starts nor ends the dark period
+            None => {}
+        }
+
+        let active = if self.active {
+            let pv_voltage = snapshot.map(|snapshot| {
+                f64::max(snapshot.value("PV1 Voltage").unwrap_or_default(), snapshot.value("PV2 Voltage").unwrap_or_default())
+            });
+            in_window && !pv_voltage.is_some_and(|volts| volts > self.config.wake_pv_voltage)
+        } else {
+            in_window && self.dark_since.is_some_and(|since| now.saturating_sub(since) >= self.config.idle_after.as_secs())
+        };
+        if active == self.active {
+            return None;
+        }
+        self.active = active;
+        if !active {
+            self.dark_since = None;
+        }
+        Some(active)
+    }
+}
+
+/// Longest poll interval power-save mode may use. The ssh monitor decides on shutdowns from
+/// the SoC read here, so polling slower than this would let it act on a stale battery level.
+const POWER_SAVE_MAX_INTERVAL: Duration = Duration::from_secs(120);
+/// How far the SoC must recover above POWER_SAVE_BELOW_SOC to leave power-save mode while
+/// the grid is still down, so it doesn't flap around the threshold.
+const POWER_SAVE_HYSTERESIS_PCT: f64 = 5.0;
+
+/// Saving the Pi's own power during a long outage (POWER_SAVE_BELOW_SOC).
+#[derive(Debug, Clone)]
+struct PowerSaveConfig {
+    /// Power-save mode starts when the grid is down and the SoC is below this.
+    below_soc: f64,
+    /// Poll interval meanwhile, at most POWER_SAVE_MAX_INTERVAL.
+    interval: Duration,
+}
+
+impl PowerSaveConfig {
+    /// The staleness window while polling at the power-save interval.
+    fn stale_after(&self, polling: &PollingConfig) -> Duration {
+        self.interval.max(polling.cooldown) * 3 + polling.jitter
+    }
+}
+
+/// Tracks whether the service is in power-save mode.
+struct PowerSave {
+    config: PowerSaveConfig,
+    active: bool,
+}
+
+impl PowerSave {
+    fn new(config: PowerSaveConfig) -> Self {
+        Self { config, active: false }
+    }
+
+    /// Updates the mode after a poll (None when it failed); returns the new mode when it changed.
+    fn observe(&mut self, snapshot: Option<&Snapshot>) -> Option<bool> {
+        // Without a reading the mode stays as it is
+        let snapshot = snapshot?;
+        let soc = snapshot.value("Battery Remaining Capacity")?;
+        let active = !snapshot.grid_present() && if self.active {
+            soc < self.config.below_soc + POWER_SAVE_HYSTERESIS_PCT
+        } else {
+            soc < self.config.below_soc
+        };
+        if active == self.active {
+            return None;
+        }
+        self.active = active;
+        Some(active)
+    }
+}
+
+/// Fast polling while something interesting happens (BURST_TRIGGER).
+#[derive(Debug, Clone)]
+struct BurstConfig {
+    /// Measurements with the change between two polls that starts a burst; a change of 0
+    /// means any change.
+    triggers: Vec<(String, f64)>,
+    interval: Duration,
+    /// How long a burst polls at `interval` before decaying back to the normal interval.
+    window: Duration,
+    /// Fast polls allowed in any hour.
+    budget: u32,
+}
+
+/// Tracks the burst polling: a trigger starts `window` of fast polls, then the interval
+/// doubles every poll until it is back at the normal one.
+struct Burst {
+    config: BurstConfig,
+    previous: HashMap<String, f64>,
+    started: Option<Instant>,
+    /// The next interval while decaying.
+    decaying: Option<Duration>,
+    /// When the fast polls of the last hour were scheduled.
+    fast_polls: VecDeque<Instant>,
+}
+
+impl Burst {
+    fn new(config: BurstConfig) -> Self {
+        Self { config, previous: HashMap::new(), started: None, decaying: None, fast_polls: VecDeque::new() }
+    }
+
+    /// Whether the poll just taken was scheduled at a shortened interval.
+    fn active(&self) -> bool {
+        self.started.is_some() || self.decaying.is_some()
+    }
+
+    /// Whether the fast polls of the last hour leave room for another.
+    fn budget_left(&mut self, now: Instant) -> bool {
+        while self.fast_polls.front().is_some_and(|at| now.duration_since(*at) >= Duration::from_secs(3600)) {
+            self.fast_polls.pop_front();
+        }
+        self.fast_polls.len() < self.config.budget as usize
+    }
+
+    /// Compares a successful poll with the previous one; returns what started a burst.
+    fn observe(&mut self, snapshot: &Snapshot, now: Instant) -> Option<String> {
+        let mut trigger = None;
+        for (metric, delta) in &self.config.triggers {
+            let Some(value) = snapshot.value(metric) else { continue };
+            if let Some(previous) = self.previous.insert(metric.clone(), value) {
+                if trigger.is_none() && (value - previous).abs() > *delta {
+                    trigger = Some(format!("{} went from {} to {}", metric, previous, value));
+                }
+            }
+        }
+        // A burst runs its window; triggers meanwhile don't stretch it
+        if self.started.is_some() || !self.budget_left(now) {
+            return None;
+        }
+        let trigger = trigger?;
+        self.started = Some(now);
+        self.decaying = None;
+        Some(trigger)
+    }
+
+    /// The delay before the next poll when shorter than `normal`, counted against the budget.
+    fn next_delay(&mut self, normal: Duration, now: Instant) -> Option<Duration> {
+        if !self.budget_left(now) {
+            if self.active() {
+                println!("Burst polling budget of {} polls an hour used up, back to the normal interval", self.config.budget);
+            }
+            self.started = None;
+            self.decaying = None;
+            return None;
+        }
+        let delay = match self.started {
+            Some(started) if now.duration_since(started) < self.config.window => self.config.interval,
+            Some(_) => {
+                self.started = None;
+                self.config.interval * 2
+            }
+            None => self.decaying?,
+        };
+        if delay >= normal {
+            self.decaying = None;
+            return None;
+        }
+        if self.started.is_none() {
+            self.decaying = Some(delay * 2);
+        }
+        self.fast_polls.push_back(now);
+        Some(delay)
+    }
+}
+
+/// Decides how long to wait before the next poll, backing off into an
+/// extended cooldown after a burst of consecutive failures.
+struct PollSchedule {
+    config: PollingConfig,
+    consecutive_failures: u32,
+}
+
+impl PollSchedule {
+    fn new(config: PollingConfig) -> Self {
+        Self { config, consecutive_failures: 0 }
+    }
+
+    fn next_delay(&mut self, success: bool, now: u64) -> (Duration, BackoffHealth) {
+        if success {
+            self.consecutive_failures = 0;
+        } else {
+            self.consecutive_failures += 1;
+        }
+
+        let in_cooldown = self.config.cooldown_after > 0
+            && self.consecutive_failures >= self.config.cooldown_after;
+        let delay = if in_cooldown {
+            self.config.cooldown
+        } else {
+            self.jittered_interval()
+        };
+
+        let backoff = BackoffHealth {
+            state: if in_cooldown { "cooldown" } else { "normal" }.to_string(),
+            consecutive_failures: self.consecutive_failures,
+            next_poll: Some(now + delay.as_secs()),
+        };
+        (delay, backoff)
+    }
+
+    fn jittered_interval(&self) -> Duration {
+        let jitter = self.config.jitter.as_millis() as i64;
+        let offset = if jitter > 0 {
+            rand::thread_rng().gen_range(-jitter..=jitter)
+        } else {
+            0
+        };
+        let millis = (self.config.interval.as_millis() as i64 + offset).max(0);
+        Duration::from_millis(millis as u64)
+    }
+}
+
+struct Config {
+    inverter_urls: Vec<String>,
+    serial: String,
+    polling: PollingConfig,
+    night: Option<NightConfig>,
+    power_save: Option<PowerSaveConfig>,
+    burst: Option<BurstConfig>,
+    listen_addrs: Vec<ListenAddr>,
+    socket: SocketConfig,
+    balance: BalanceConfig,
+    /// Polls in a row with impossible readings before the register map is suspect; 0 is off.
+    consistency_polls: u32,
+    load_source: LoadSource,
+    soc_calibration: SocCalibration,
+    charge_temperatures: ChargeTemperatures,
+    power_signs: PowerSigns,
+    dongle_protocol: DongleProtocol,
+    publish: PublishConfig,
+    labels: LabelsConfig,
+    nut: NutConfig,
+    apcupsd: ApcupsdConfig,
+    battery_capacity_kwh: Option<f64>,
+    /// State of charge the inverter keeps back, left out of the backup runtime estimate.
+    backup_reserve_pct: f64,
+    /// ESSENTIAL_LOAD, and whether the backup runtime estimate runs on it (RUNTIME_LOAD).
+    essential_load: Option<EssentialLoad>,
+    runtime_load: RuntimeLoad,
+    /// Longest poll interval the battery energy counters integrate across.
+    battery_max_gap: Duration,
+    /// Local timezone for daily counters.
+    timezone: chrono_tz::Tz,
+    http_limits: HttpLimits,
+    /// Log every HTTP request with its latency.
+    http_log: bool,
+    control: ControlConfig,
+    charge_windows: Vec<ChargeWindow>,
+    /// FORECAST_PLANE lines, whose forecast can skip charge windows.
+    forecast_planes: Vec<forecast::Plane>,
+    evc: Option<EvcConfig>,
+    surplus: SurplusConfig,
+    mqtt: Option<MqttConfig>,
+    events: Option<EventsConfig>,
+    /// node_exporter textfile collector file rewritten after every poll.
+    textfile: Option<PathBuf>,
+    zabbix: Option<ZabbixConfig>,
+    redis: Option<RedisConfig>,
+    postgres: Option<PostgresConfig>,
+    compliance: Option<ComplianceConfig>,
+    statsd: Option<StatsdConfig>,
+    thresholds: ThresholdConfig,
+    /// CHANGE_THRESHOLD overrides of how far a measurement has to move for /status/changes.
+    change_thresholds: changes::Thresholds,
+    /// How long the BMS discharge limit has to stay below the load before BMS_LIMIT_ALERT
+    /// warns, None when it's off.
+    bms_limit_alert: Option<Duration>,
+    /// Where the BMS current limits are read (BMS_LIMITS_INDEX), None when they aren't.
+    bms_limits: Option<usize>,
+    /// SPIKE_FILTER steps by measurement.
+    spike_limits: HashMap<String, f64>,
+    /// Start of the block of battery modules (BATTERY_MODULES_INDEX), None when it isn't decoded.
+    battery_modules: Option<usize>,
+    /// BATTERY_MODULE_DRIFT_PCT, None when the drift alert is off.
+    battery_module_drift_pct: Option<f64>,
+    eps: EpsConfig,
+    /// CONSUMPTION_ANOMALY_FACTOR and friends, None when the alert is off.
+    anomaly: Option<anomaly::AnomalyConfig>,
+    federation: FederationConfig,
+    /// Outbound HTTP clients trusting TLS_CA_BUNDLE and skipping the certificate checks of
+    /// the TLS_ACCEPT_INVALID_CERTS destinations.
+    http: outbound::Clients,
+    signing: Option<signing::SigningKey>,
+    debug_token: Option<String>,
+    /// Token external meters push with (INGEST_TOKEN), and the meters (EXTERNAL_METER).
+    ingest_token: Option<String>,
+    external_meters: Vec<external::Meter>,
+    /// How often the inverter settings are read (SETTINGS_INTERVAL_SECS), zero when never.
+    settings_interval: Duration,
+    /// How the PV power lost to the export limit is estimated, None when it isn't.
+    curtailment: Option<CurtailmentEstimate>,
+}
+
+/// Other solax-mon instances polled for /federation/status (FEDERATION_PEER).
+#[derive(Debug, Clone)]
+struct FederationConfig {
+    peers: Vec<federation::Peer>,
+    interval: Duration,
+    /// A peer whose last successful poll is older than this is marked stale.
+    stale_after: Duration,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self { peers: Vec::new(), interval: Duration::from_secs(30), stale_after: Duration::from_secs(300) }
+    }
+}
+
+/// The Zabbix server or proxy every poll is pushed to (ZABBIX_SERVER).
+#[derive(Debug, Clone)]
+struct ZabbixConfig {
+    server: String,
+    /// Host name the items belong to, as configured in Zabbix.
+    host: String,
+    key_prefix: String,
+}
+
+/// The Redis server every poll is written to (REDIS_URL).
+#[derive(Debug, Clone)]
+struct RedisConfig {
+    target: redis::Target,
+    /// Hash the measurements are written to, `solax:<SERIAL>` by default.
+    key: String,
+    channel: String,
+    /// The hash expires this long after the last poll that wrote it.
+    ttl: Duration,
+}
+
+/// The StatsD or DogStatsD agent every poll is sent to (STATSD_ADDR).
+#[derive(Debug, Clone)]
+struct StatsdConfig {
+    target: statsd::Target,
+    /// Put in front of every metric name, e.g. `solax.grid_power`.
+    prefix: String,
+    tags: Vec<(String, String)>,
+    tag_style: statsd::TagStyle,
+    /// Largest datagram sent; keep it under the path MTU so nothing is fragmented.
+    max_packet: usize,
+}
+
+/// The PostgreSQL table every poll is inserted into (POSTGRES_URL).
+#[derive(Debug, Clone)]
+struct PostgresConfig {
+    /// libpq connection string or postgres:// URL; its sslmode decides whether TLS is used.
+    url: String,
+    table: String,
+    layout: postgres::Layout,
+    hypertable: bool,
+    /// Most rows kept while the database can't be reached.
+    buffer_rows: usize,
+    /// Extra CA certificate (PEM) trusted for the TLS connection.
+    ca_file: Option<PathBuf>,
+}
+
+/// The export compliance report, read from the PostgreSQL history (EXPORT_COMPLIANCE_LIMIT_W).
+#[derive(Debug, Clone)]
+struct ComplianceConfig {
+    /// The contracted export limit.
+    limit_w: f64,
+    postgres: PostgresConfig,
+    /// The name Grid Power is stored under.
+    measurement: String,
+    timezone: chrono_tz::Tz,
+    /// Longest time between two readings the export is integrated across.
+    max_gap: Duration,
+}
+
+/// The broker poll, fault and action events are published to (EVENTS_URL).
+#[derive(Debug, Clone)]
+struct EventsConfig {
+    target: BrokerTarget,
+    /// NATS subject or Kafka topic.
+    subject: String,
+    /// Wait for the JetStream acknowledgement rather than only the NATS server's.
+    jetstream: bool,
+    /// Most events kept in the outbox while the broker can't be reached.
+    outbox_max: usize,
+}
+
+/// The broker whose command topic is subscribed to (MQTT_URL).
+#[derive(Debug, Clone)]
+struct MqttConfig {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    /// Shared secret commands must carry; control commands are refused without one.
+    secret: Option<String>,
+    topic_prefix: String,
+}
+
+/// The Solax EV charger, polled alongside the inverter.
+#[derive(Debug, Clone)]
+struct EvcConfig {
+    url: String,
+    password: String,
+}
+
+#[derive(Debug, Clone)]
+struct ControlConfig {
+    enabled: bool,
+    /// Bearer token the control endpoints require.
+    token: Option<String>,
+    /// Forced charging or discharging reverts to self use after at most this long.
+    force_max: Duration,
+    /// Control changes are announced here (the ssh monitor's DISCORD_WEBHOOK).
+    discord_webhook_url: Option<String>,
+    discord_plain: bool,
+    /// The ssh monitor's SLACK_WEBHOOK, sent the same alerts as Discord.
+    slack_webhook_url: Option<String>,
+    /// The ssh monitor's Matrix room, Gotify server and Pushover user, likewise.
+    matrix: Option<MatrixTarget>,
+    gotify: Option<GotifyTarget>,
+    pushover: Option<PushoverTarget>,
+    /// The ssh monitor's NOTIFY_ROUTE lines.
+    routes: Routes,
+    /// Outbound HTTP clients, the same as `Config::http`.
+    http: outbound::Clients,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            token: None,
+            force_max: Duration::from_secs(3 * 3600),
+            discord_webhook_url: None,
+            discord_plain: false,
+            slack_webhook_url: None,
+            matrix: None,
+            gotify: None,
+            pushover: None,
+            routes: Routes::default(),
+            http: outbound::Clients::default(),
+        }
+    }
+}
+
+impl ControlConfig {
+    /// Sends `alert` to every configured notifier its route lets it through; `what` names it
+    /// in the log.
+    async fn send_alert(&self, alert: &Alert, what: &str) {
+        if let Some(webhook_url) = self.discord_webhook_url.as_ref().filter(|_| self.routed("discord", alert, what)) {
+            if let Err(e) = send_discord_alert(&self.http, webhook_url, alert, self.discord_plain).await {
+                eprintln!("Failed to send {} to Discord: {}", what, e);
+            }
+        }
+        if let Some(webhook_url) = self.slack_webhook_url.as_ref().filter(|_| self.routed("slack", alert, what)) {
+            if let Err(e) = send_slack_alert(&self.http, webhook_url, alert).await {
+                eprintln!("Failed to send {} to Slack: {}", what, e);
+            }
+        }
+        if let Some(target) = self.matrix.as_ref().filter(|_| self.routed("matrix", alert, what)) {
+            if let Err(e) = send_matrix_alert(&self.http, target, alert).await {
+                eprintln!("Failed to send {} to Matrix: {:#}", what, e);
+            }
+        }
+        if let Some(target) = self.gotify.as_ref().filter(|_| self.routed("gotify", alert, what)) {
+            if let Err(e) = send_gotify_alert(&self.http, target, alert).await {
+                eprintln!("Failed to send {} to Gotify: {}", what, e);
+            }
+        }
+        if let Some(target) = self.pushover.as_ref().filter(|_| self.routed("pushover", alert, what)) {
+            if let Err(e) = send_pushover_alert(&self.http, target, alert).await {
+                eprintln!("Failed to send {} to Pushover: {}", what, e);
+            }
+        }
+    }
+
+    /// Whether NOTIFY_ROUTE sends `alert` to `notifier`, logging why not.
+    fn routed(&self, notifier: &str, alert: &Alert, what: &str) -> bool {
+        match self.routes.check(notifier, alert) {
+            Ok(()) => true,
+            Err(reason) => {
+                println!("Not sending {} to {} ({})", what, notifier, reason);
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Ownership and permissions applied to unix socket listeners.
+#[derive(Debug, Clone)]
+struct SocketConfig {
+    mode: u32,
+    group: Option<String>,
+}
+
+/// Reads the configuration, refusing it when `validate` would find anything wrong with it.
+fn read_secrets() -> Result<Config, Box<dyn std::error::Error + Send + Sync>> {
+    let text = std::fs::read_to_string(SECRETS_PATH).map_err(|e| format!("Failed to read {}: {}", SECRETS_PATH, e))?;
+    let problems = secrets::check(&text);
+    if !problems.is_empty() {
+        let problems: Vec<String> = problems.iter().map(|problem| problem.to_string()).collect();
+        return Err(format!("Invalid configuration in {}:\n{}", SECRETS_PATH, problems.join("\n")).into());
+    }
+    let config = parse_secrets(&text)?;
+    check_measurements(&config)?;
+    Ok(config)
+}
+
+fn parse_secrets(text: &str) -> Result<Config, Box<dyn std::error::Error + Send + Sync>> {
+    let mut ip = String::new();
+    let mut urls = Vec::new();
+    let mut serial = String::new();
+    let mut polling = PollingConfig::default();
+    let mut night_window = None;
+    let mut night_idle = Duration::from_secs(1800);
+    let mut night_interval = Duration::from_secs(600);
+    let mut night_wake_pv_voltage = 100.0;
+    let mut power_save_below_soc = None;
+    let mut power_save_interval = Duration::from_secs(60);
+    let mut burst_triggers = Vec::new();
+    let mut burst_interval = Duration::from_secs(5);
+    let mut burst_window = Duration::from_secs(120);
+    let mut burst_budget = 240;
+    let mut listen_addrs = vec![ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 3000)))];
+    let mut socket = SocketConfig { mode: 0o660, group: None };
+    let mut balance = BalanceConfig { threshold_w: 500.0, polls: 3 };
+    let mut consistency_polls = 5;
+    let mut load_source = LoadSource::Register;
+    let mut soc_calibration = SocCalibration::default();
+    let mut charge_temperatures = ChargeTemperatures::default();
+    let mut power_signs = PowerSigns::default();
+    let mut dongle_protocol = DongleProtocol::Auto;
+    let mut publish = PublishConfig::default();
+    let mut labels = LabelsConfig::default();
+    let mut nut = NutConfig::default();
+    let mut apcupsd = ApcupsdConfig::default();
+    let mut battery_capacity_kwh = None;
+    let mut signing = None;
+    let mut debug_token = None;
+    let mut ingest_token = None;
+    let mut external_meters: Vec<external::Meter> = Vec::new();
+    let mut settings_interval = Duration::from_secs(900);
+    let mut curtailment_estimate = Some("recent_max".to_string());
+    let mut curtailment_window = Duration::from_secs(3600);
+    let mut backup_reserve_pct = 10.0;
+    let mut essential_load = None;
+    let mut runtime_load = RuntimeLoad::Total;
+    let mut federation = FederationConfig::default();
+    let mut battery_max_gap = Duration::from_secs(300);
+    let mut export_compliance_limit_w = None;
+    let mut timezone = chrono_tz::UTC;
+    let mut http_limits = HttpLimits::default();
+    let mut http_log = false;
+    let mut control = ControlConfig::default();
+    let (mut matrix_homeserver, mut matrix_access_token, mut matrix_room_id) = (None, None, None);
+    let (mut gotify_url, mut gotify_token) = (None, None);
+    let (mut pushover_user_key, mut pushover_token) = (None, None);
+    let mut pushover_retry = Duration::from_secs(60);
+    let mut pushover_expire = Duration::from_secs(3600);
+    let mut outbound = outbound::OutboundConfig::default();
+    let mut charge_windows = Vec::new();
+    let mut forecast_planes = Vec::new();
+    let mut evc_url = None;
+    let mut mqtt_url = None;
+    let mut mqtt_username = None;
+    let mut mqtt_password = None;
+    let mut mqtt_secret = None;
+    let mut mqtt_topic_prefix = "solax".to_string();
+    let mut zabbix_server = None;
+    let mut zabbix_host = None;
+    let mut zabbix_key_prefix = "solax.".to_string();
+    let mut redis_target = None;
+    let mut events_target = None;
+    let mut textfile = None;
+    let mut events_subject = "solax.events".to_string();
+    let mut events_jetstream = true;
+    let mut events_outbox_max = 1000;
+    let mut postgres_url = None;
+    let mut postgres_table = "solax_measurements".to_string();
+    let mut postgres_layout = postgres::Layout::Narrow;
+    let mut postgres_hypertable = false;
+    let mut postgres_buffer_rows = 10_000;
+    let mut postgres_ca_file = None;
+    let mut redis_key = None;
+    let mut statsd_target = None;
+    let mut statsd_prefix = "solax.".to_string();
+    let mut statsd_tags = Vec::new();
+    let mut statsd_tag_style = statsd::TagStyle::Dogstatsd;
+    let mut statsd_max_packet = statsd::DEFAULT_MAX_PACKET;
+    let mut redis_channel = "solax.status".to_string();
+    let mut redis_ttl = Duration::from_secs(300);
+    let mut thresholds = ThresholdConfig::default();
+    let mut change_thresholds = changes::Thresholds::default();
+    let mut anomaly_factor = None;
+    let mut bms_limit_alert = false;
+    let mut bms_limit_alert_secs = Duration::from_secs(300);
+    let mut bms_limits = None;
+    let mut spike_limits = HashMap::new();
+    let mut battery_modules = None;
+    let mut battery_module_drift_pct = None;
+    let mut eps = EpsConfig { limit_w: None, margin_w: 1000.0 };
+    let mut anomaly_sustain = None;
+    let mut anomaly_weeks = None;
+    let mut surplus = SurplusConfig::default();
+    let mut evc_password = None;
+    
+    for (key, value) in parse_entries(text) {
+        let (key, value) = (key.as_str(), value.as_str());
+        match key {
+            "INVERTER_IP" => ip = value.trim().to_string(),
+            "INVERTER_URL" => {
+                urls = value.split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(normalize_inverter_url)
+                    .collect();
+            }
+            "SERIAL" => serial = value.trim().to_string(),
+            "POLL_INTERVAL_SECS" => polling.interval = parse_secs(key, value)?,
+            "POLL_JITTER_SECS" => polling.jitter = parse_secs(key, value)?,
+            "MIN_REQUEST_SPACING_SECS" => polling.min_spacing = parse_secs(key, value)?,
+            "INVERTER_TIMEOUT_SECS" => polling.request_timeout = parse_secs(key, value)?,
+            "COOLDOWN_AFTER_FAILURES" => polling.cooldown_after = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "COOLDOWN_SECS" => polling.cooldown = parse_secs(key, value)?,
+            "LISTEN_ADDR" => listen_addrs = parse_listen_addrs(value)?,
+            "LISTEN_SOCKET_MODE" => socket.mode = u32::from_str_radix(value.trim(), 8)
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "LISTEN_SOCKET_GROUP" => socket.group = Some(value.trim().to_string()),
+            "BALANCE_WARN_W" => balance.threshold_w = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "BALANCE_WARN_POLLS" => balance.polls = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "CONSISTENCY_POLLS" => consistency_polls = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "DONGLE_PROTOCOL" => dongle_protocol = DongleProtocol::parse(value)
+                .ok_or_else(|| format!("Invalid value for {}: {}", key, value))?,
+            "SOC_FLOOR_PCT" => soc_calibration.floor_pct = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "SOC_CEIL_PCT" => soc_calibration.ceil_pct = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "CHARGE_TEMP_FLOOR_C" => charge_temperatures.floor_c = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "CHARGE_TEMP_REDUCED_C" => charge_temperatures.reduced_c = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "PUBLISH" => publish.parse_entry(value),
+            "LABEL" => labels.parse_entry(value)?,
+            "NUT_LISTEN" => nut.listen_addrs = parse_listen_addrs(value)?
+                .into_iter()
+                .map(|addr| match addr {
+                    ListenAddr::Tcp(addr) => Ok(addr),
+                    ListenAddr::Unix(_) => Err(format!("NUT_LISTEN only supports TCP addresses: {}", value)),
+                })
+                .collect::<Result<_, _>>()?,
+            "APCUPSD_LISTEN" => apcupsd.listen_addrs = parse_listen_addrs(value)?
+                .into_iter()
+                .map(|addr| match addr {
+                    ListenAddr::Tcp(addr) => Ok(addr),
+                    ListenAddr::Unix(_) => Err(format!("APCUPSD_LISTEN only supports TCP addresses: {}", value)),
+                })
+                .collect::<Result<_, _>>()?,
+            "SURPLUS_DEVICE" => surplus.devices.push(SurplusDevice::parse(value)?),
+            "SURPLUS_ON_EXPORT_W" => surplus.on_w = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "SURPLUS_OFF_EXPORT_W" => surplus.off_w = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "SURPLUS_BASIS" => surplus.basis = SurplusBasis::parse(value)
+                .ok_or_else(|| format!("Invalid value for {}: {}", key, value))?,
+            "ESSENTIAL_LOAD" => essential_load = Some(EssentialLoad::parse(value)?),
+            "RUNTIME_LOAD" => runtime_load = RuntimeLoad::parse(value)
+                .ok_or_else(|| format!("Invalid value for {}: {}", key, value))?,
+            "FEDERATION_PEER" => federation.peers.push(federation::Peer::parse(value)?),
+            "FEDERATION_POLL_SECS" => federation.interval = parse_secs(key, value)?,
+            "FEDERATION_STALE_SECS" => federation.stale_after = parse_secs(key, value)?,
+            "MQTT_URL" => mqtt_url = Some(mqtt::broker_address(value)?),
+            "MQTT_USERNAME" => mqtt_username = Some(value.trim().to_string()),
+            "MQTT_PASSWORD" => mqtt_password = Some(value.trim().to_string()),
+            "MQTT_COMMAND_SECRET" => mqtt_secret = Some(value.trim().to_string()).filter(|secret| !secret.is_empty()),
+            "MQTT_TOPIC_PREFIX" => mqtt_topic_prefix = value.trim().trim_end_matches('/').to_string(),
+            "ZABBIX_SERVER" => zabbix_server = Some(match value.trim() {
+                server if server.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) => server.to_string(),
+                server => format!("{}:{}", server, zabbix::DEFAULT_PORT),
+            }),
+            "PROMETHEUS_TEXTFILE" => textfile = Some(PathBuf::from(value.trim())),
+            "EVENTS_URL" => events_target = Some(BrokerTarget::parse(value)?),
+            "EVENTS_SUBJECT" => events_subject = value.trim().to_string(),
+            "EVENTS_JETSTREAM" => events_jetstream = value.trim() != "false",
+            "EVENTS_OUTBOX_MAX" => events_outbox_max = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "REDIS_URL" => redis_target = Some(redis::Target::parse(value)?),
+            "REDIS_KEY" => redis_key = Some(value.trim().to_string()).filter(|key| !key.is_empty()),
+            "REDIS_CHANNEL" => redis_channel = value.trim().to_string(),
+            "REDIS_TTL_SECS" => redis_ttl = parse_secs(key, value)?,
+            "STATSD_ADDR" => statsd_target = Some(statsd::Target::parse(value)?),
+            "STATSD_PREFIX" => statsd_prefix = value.trim().to_string(),
+            "STATSD_TAGS" => statsd_tags = statsd::parse_tags(value),
+            "STATSD_TAG_STYLE" => statsd_tag_style = statsd::TagStyle::parse(value)?,
+            "STATSD_MAX_PACKET" => statsd_max_packet = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "POSTGRES_URL" => postgres_url = Some(value.trim().to_string()),
+            "EXPORT_COMPLIANCE_LIMIT_W" => export_compliance_limit_w = Some(value.trim().parse::<f64>().ok()
+                .filter(|watts| *watts >= 0.0)
+                .ok_or_else(|| format!("Invalid value for {}: {}", key, value))?),
+            "POSTGRES_TABLE" => postgres_table = value.trim().to_string(),
+            "POSTGRES_LAYOUT" => postgres_layout = postgres::Layout::parse(value)?,
+            "POSTGRES_HYPERTABLE" => postgres_hypertable = value.trim() == "true",
+            "POSTGRES_BUFFER_ROWS" => postgres_buffer_rows = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "POSTGRES_CA_FILE" => postgres_ca_file = Some(PathBuf::from(value.trim())),
+            "ZABBIX_HOST" => zabbix_host = Some(value.trim().to_string()),
+            "ZABBIX_KEY_PREFIX" => zabbix_key_prefix = value.trim().to_string(),
+            "POWER_SAVE_BELOW_SOC" => power_save_below_soc = Some(value.trim().parse::<f64>()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?),
+            "POWER_SAVE_POLL_INTERVAL_SECS" => power_save_interval = parse_secs(key, value)?,
+            "BURST_TRIGGER" => {
+                let (metric, delta) = value.rsplit_once(':')
+                    .ok_or_else(|| format!("BURST_TRIGGER must be <measurement>:<change>: {}", value))?;
+                let delta = delta.trim().parse::<f64>().ok().filter(|delta| *delta >= 0.0)
+                    .ok_or_else(|| format!("Invalid value for {}: {}", key, value))?;
+                burst_triggers.push((metric.trim().to_string(), delta));
+            }
+            "BURST_INTERVAL_SECS" => burst_interval = parse_secs(key, value)?,
+            "BURST_WINDOW_SECS" => burst_window = parse_secs(key, value)?,
+            "BURST_BUDGET_PER_HOUR" => burst_budget = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "NIGHT_WINDOW" => night_window = Some(NightConfig::parse_window(value)?),
+            "NIGHT_IDLE_SECS" => night_idle = parse_secs(key, value)?,
+            "NIGHT_POLL_INTERVAL_SECS" => night_interval = parse_secs(key, value)?,
+            "NIGHT_WAKE_PV_VOLTAGE" => night_wake_pv_voltage = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "THRESHOLD_ALERT" => thresholds.rules.push(ThresholdRule::parse(value)?),
+            "SPIKE_FILTER" => {
+                let (metric, limit) = solax_mon::inverter::parse_spike_limit(value)?;
+                spike_limits.insert(metric, limit);
+            }
+            "CHANGE_THRESHOLD" => {
+                let (metric, threshold) = changes::Thresholds::parse_override(value)?;
+                change_thresholds.overrides.insert(metric, threshold);
+            }
+            "BMS_LIMIT_ALERT" => bms_limit_alert = value.trim().eq_ignore_ascii_case("true"),
+            "BMS_LIMIT_ALERT_SECS" => bms_limit_alert_secs = parse_secs(key, value)?,
+            "BMS_LIMITS_INDEX" => bms_limits = Some(value.trim().parse::<usize>()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?),
+            "BATTERY_MODULES_INDEX" => battery_modules = Some(value.trim().parse::<usize>()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?),
+            "BATTERY_MODULE_DRIFT_PCT" => battery_module_drift_pct = Some(value.trim().parse::<f64>().ok().filter(|pct| *pct > 0.0)
+                .ok_or_else(|| format!("Invalid value for {}: {}", key, value))?),
+            "EPS_LIMIT_W" => eps.limit_w = Some(value.trim().parse::<f64>().ok().filter(|watts| *watts > 0.0)
+                .ok_or_else(|| format!("Invalid value for {}: {}", key, value))?),
+            "EPS_MARGIN_W" => eps.margin_w = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "CONSUMPTION_ANOMALY_FACTOR" => anomaly_factor = Some(value.trim().parse::<f64>().ok().filter(|factor| *factor > 0.0)
+                .ok_or_else(|| format!("Invalid value for {}: {}", key, value))?),
+            "CONSUMPTION_ANOMALY_SECS" => anomaly_sustain = Some(parse_secs(key, value)?),
+            "CONSUMPTION_ANOMALY_WEEKS" => anomaly_weeks = Some(value.trim().parse::<u32>().ok().filter(|weeks| *weeks > 0)
+                .ok_or_else(|| format!("Invalid value for {}: {}", key, value))?),
+            "THRESHOLD_WEBHOOK" => thresholds.webhook_url = Some(value.trim().to_string()).filter(|url| !url.is_empty()),
+            "BACKUP_RESERVE_PCT" => backup_reserve_pct = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "EVC_URL" => evc_url = Some(normalize_inverter_url(value)),
+            "EVC_PASSWORD" => evc_password = Some(value.trim().to_string()),
+            "APCUPSD_UPS_NAME" => apcupsd.ups_name = value.trim().to_string(),
+            "APCUPSD_LOW_BATTERY_PCT" => apcupsd.low_battery_pct = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "BATTERY_CAPACITY_KWH" => battery_capacity_kwh = Some(value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?),
+            "BATTERY_MAX_GAP_SECS" => battery_max_gap = parse_secs(key, value)?,
+            "STATUS_SIGNING_KEY" => signing = Some(signing::SigningKey::load(Path::new(value.trim()))?),
+            "DEBUG_TOKEN" => debug_token = Some(value.trim().to_string()).filter(|token| !token.is_empty()),
+            "INGEST_TOKEN" => ingest_token = Some(value.trim().to_string()).filter(|token| !token.is_empty()),
+            "EXTERNAL_METER" => {
+                let meter = external::Meter::parse(value)?;
+                if let Some(other) = external_meters.iter().find(|other| other.source == meter.source || other.name == meter.name) {
+                    return Err(format!("EXTERNAL_METER {} and {} share a source or name", other.source, meter.source).into());
+                }
+                external_meters.push(meter);
+            }
+            "SETTINGS_INTERVAL_SECS" => settings_interval = parse_secs(key, value)?,
+            "CURTAILMENT_ESTIMATE" => curtailment_estimate = Some(value.trim().to_string()).filter(|estimate| estimate != "off"),
+            "CURTAILMENT_WINDOW_SECS" => curtailment_window = parse_secs(key, value)?,
+            "NUT_UPS_NAME" => nut.ups_name = value.trim().to_string(),
+            "NUT_USER" => nut.username = Some(value.trim().to_string()),
+            "NUT_PASSWORD" => nut.password = Some(value.trim().to_string()),
+            "NUT_LOW_BATTERY_PCT" => nut.low_battery_pct = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "LABELS_AUTO" => labels.auto = value.trim().eq_ignore_ascii_case("true"),
+            "HTTP_LOG" => http_log = value.trim().eq_ignore_ascii_case("true"),
+            "CONTROL_ENABLED" => control.enabled = value.trim().eq_ignore_ascii_case("true"),
+            "CONTROL_TOKEN" => control.token = Some(value.trim().to_string()).filter(|token| !token.is_empty()),
+            "CONTROL_FORCE_MAX_SECS" => control.force_max = parse_secs(key, value)?,
+            "CHARGE_WINDOW" => charge_windows.push(ChargeWindow::parse(value)?),
+            "FORECAST_PLANE" => forecast_planes.push(forecast::Plane::parse(value)?),
+            "DISCORD_WEBHOOK" => control.discord_webhook_url = Some(value.trim().to_string()).filter(|url| !url.is_empty()),
+            "DISCORD_PLAIN" => control.discord_plain = value.trim().eq_ignore_ascii_case("true"),
+            "SLACK_WEBHOOK" => control.slack_webhook_url = Some(value.trim().to_string()).filter(|url| !url.is_empty()),
+            "MATRIX_HOMESERVER" => matrix_homeserver = Some(value.trim().to_string()),
+            "MATRIX_ACCESS_TOKEN" => matrix_access_token = Some(value.trim().to_string()),
+            "MATRIX_ROOM_ID" => matrix_room_id = Some(value.trim().to_string()),
+            "GOTIFY_URL" => gotify_url = Some(value.trim().to_string()),
+            "GOTIFY_TOKEN" => gotify_token = Some(value.trim().to_string()),
+            "PUSHOVER_USER_KEY" => pushover_user_key = Some(value.trim().to_string()),
+            "PUSHOVER_TOKEN" => pushover_token = Some(value.trim().to_string()),
+            "PUSHOVER_RETRY_SECS" => pushover_retry = parse_secs(key, value)?,
+            "PUSHOVER_EXPIRE_SECS" => pushover_expire = parse_secs(key, value)?,
+            "NOTIFY_ROUTE" => control.routes.add(value)?,
+            "TLS_CA_BUNDLE" => outbound.ca_bundle = Some(PathBuf::from(value.trim())),
+            "TLS_ACCEPT_INVALID_CERTS" => outbound.accept_invalid_certs.push(outbound::OutboundConfig::parse_destination(value)?),
+            "HTTP_PROXY" => outbound.http_proxy = Some(value.trim().to_string()),
+            "HTTPS_PROXY" => outbound.https_proxy = Some(value.trim().to_string()),
+            "NO_PROXY" => outbound.no_proxy = Some(value.trim().to_string()),
+            "HTTP_RATE_LIMIT" => http_limits.rate = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "HTTP_RATE_LIMIT_PER_IP" => http_limits.rate_per_ip = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "HTTP_HEALTH_RATE_LIMIT" => http_limits.health_rate = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "HTTP_MAX_CONNECTIONS" => http_limits.max_connections = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "TIMEZONE" => timezone = value.trim().parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            "GRID_SIGN" => power_signs.grid_import_positive = match value.trim() {
+                "export_positive" => false,
+                "import_positive" => true,
+                _ => return Err(format!("Invalid value for {}: {} (export_positive, import_positive)", key, value).into()),
+            },
+            "BATTERY_SIGN" => power_signs.battery_discharge_positive = match value.trim() {
+                "charge_positive" => false,
+                "discharge_positive" => true,
+                _ => return Err(format!("Invalid value for {}: {} (charge_positive, discharge_positive)", key, value).into()),
+            },
+            "LOAD_SOURCE" => load_source = match value.trim() {
+                "register" => LoadSource::Register,
+                "computed" => LoadSource::Computed,
+                _ => return Err(format!("Invalid value for {}: {}", key, value).into()),
+            },
+            _ => (),
+        }
+    }
+
+    // INVERTER_IP is the original single-address key, only used when no URL list is given
+    if urls.is_empty() && !ip.is_empty() {
+        urls.push(normalize_inverter_url(&ip));
+    }
+    
+    if urls.is_empty() || serial.is_empty() {
+        return Err("Missing required secrets INVERTER_URL and SERIAL".into());
+    }
+    
+    if soc_calibration.ceil_pct <= soc_calibration.floor_pct {
+        return Err("SOC_CEIL_PCT must be greater than SOC_FLOOR_PCT".into());
+    }
+    if charge_temperatures.reduced_c < charge_temperatures.floor_c {
+        return Err("CHARGE_TEMP_REDUCED_C can't be below CHARGE_TEMP_FLOOR_C".into());
+    }
+    if bms_limit_alert && bms_limits.is_none() {
+        return Err("BMS_LIMIT_ALERT needs BMS_LIMITS_INDEX".into());
+    }
+    if battery_module_drift_pct.is_some() && battery_modules.is_none() {
+        return Err("BATTERY_MODULE_DRIFT_PCT needs BATTERY_MODULES_INDEX".into());
+    }
+
+    control.matrix = match (matrix_homeserver, matrix_access_token, matrix_room_id) {
+        (None, None, None) => None,
+        (Some(homeserver), Some(access_token), Some(room_id)) => Some(MatrixTarget { homeserver, access_token, room_id }),
+        _ => return Err("Matrix needs MATRIX_HOMESERVER, MATRIX_ACCESS_TOKEN and MATRIX_ROOM_ID".into()),
+    };
+    control.gotify = match (gotify_url, gotify_token) {
+        (None, None) => None,
+        (Some(url), Some(token)) => Some(GotifyTarget { url, token }),
+        _ => return Err("Gotify needs GOTIFY_URL and GOTIFY_TOKEN".into()),
+    };
+    control.pushover = match (pushover_user_key, pushover_token) {
+        (None, None) => None,
+        (Some(user_key), Some(token)) => Some(PushoverTarget { user_key, token, retry: pushover_retry, expire: pushover_expire }),
+        _ => return Err("Pushover needs PUSHOVER_USER_KEY and PUSHOVER_TOKEN".into()),
+    };
+    if control.enabled && control.token.is_none() {
+        return Err("CONTROL_ENABLED requires CONTROL_TOKEN".into());
+    }
+    if !charge_windows.is_empty() && !control.enabled {
+        return Err("CHARGE_WINDOW requires CONTROL_ENABLED=true".into());
+    }
+    validate_charge_windows(&charge_windows)?;
+    if forecast_planes.is_empty() && charge_windows.iter().any(|window| window.skip_forecast_kwh.is_some()) {
+        return Err("skip_forecast_kwh in CHARGE_WINDOW requires FORECAST_PLANE".into());
+    }
+
+    if federation.interval.is_zero() {
+        return Err("FEDERATION_POLL_SECS must be above 0".into());
+    }
+
+    if surplus.off_w >= surplus.on_w {
+        return Err("SURPLUS_OFF_EXPORT_W must be below SURPLUS_ON_EXPORT_W".into());
+    }
+
+    let curtailment = match curtailment_estimate.as_deref() {
+        None => None,
+        Some("recent_max") if curtailment_window.is_zero() => return Err("CURTAILMENT_WINDOW_SECS must be above 0".into()),
+        Some("recent_max") => Some(CurtailmentEstimate::RecentMax(curtailment_window)),
+        Some("same_time") => Some(CurtailmentEstimate::SameTime),
+        Some(other) => return Err(format!("Unknown CURTAILMENT_ESTIMATE {:?} (recent_max, same_time, off)", other).into()),
+    };
+
+    let evc = match (evc_url, evc_password) {
+        (Some(url), Some(password)) => Some(EvcConfig { url, password }),
+        (None, None) => None,
+        _ => return Err("EVC_URL and EVC_PASSWORD must be set together".into()),
+    };
+
+    let mqtt = mqtt_url.map(|(host, port)| MqttConfig {
+        host,
+        port,
+        username: mqtt_username,
+        password: mqtt_password,
+        secret: mqtt_secret,
+        topic_prefix: mqtt_topic_prefix,
+    });
+
+    let zabbix = match (zabbix_server, zabbix_host) {
+        (Some(server), Some(host)) => Some(ZabbixConfig { server, host, key_prefix: zabbix_key_prefix }),
+        (None, None) => None,
+        _ => return Err("ZABBIX_SERVER and ZABBIX_HOST must be set together".into()),
+    };
+
+    let events = events_target.map(|target| EventsConfig {
+        target,
+        subject: events_subject,
+        jetstream: events_jetstream,
+        outbox_max: events_outbox_max,
+    });
+
+    let redis = redis_target.map(|target| RedisConfig {
+        target,
+        key: redis_key.unwrap_or_else(|| format!("solax:{}", serial)),
+        channel: redis_channel,
+        ttl: redis_ttl,
+    });
+
+    let statsd = statsd_target.map(|target| StatsdConfig {
+        target,
+        prefix: statsd_prefix,
+        tags: statsd_tags,
+        tag_style: statsd_tag_style,
+        max_packet: statsd_max_packet,
+    });
+
+    if let Some(url) = &postgres_url {
+        url.parse::<tokio_postgres::Config>().map_err(|e| format!("Invalid POSTGRES_URL: {}", e))?;
+    }
+    if postgres_table.is_empty() {
+        return Err("POSTGRES_TABLE must not be empty".into());
+    }
+    let postgres = postgres_url.map(|url| PostgresConfig {
+        url,
+        table: postgres_table,
+        layout: postgres_layout,
+        hypertable: postgres_hypertable,
+        buffer_rows: postgres_buffer_rows,
+        ca_file: postgres_ca_file,
+    });
+
+    if power_save_interval > POWER_SAVE_MAX_INTERVAL {
+        return Err(format!(
+            "POWER_SAVE_POLL_INTERVAL_SECS may be at most {} so shutdowns still see a current SoC",
+            POWER_SAVE_MAX_INTERVAL.as_secs(),
+        ).into());
+    }
+    let power_save = power_save_below_soc.map(|below_soc| PowerSaveConfig { below_soc, interval: power_save_interval });
+
+    if !burst_triggers.is_empty() && (burst_interval < polling.min_spacing || burst_interval >= polling.interval) {
+        return Err(format!(
+            "BURST_INTERVAL_SECS must be at least MIN_REQUEST_SPACING_SECS ({}) and below POLL_INTERVAL_SECS ({})",
+            polling.min_spacing.as_secs(),
+            polling.interval.as_secs(),
+        ).into());
+    }
+    let burst = (!burst_triggers.is_empty()).then_some(BurstConfig {
+        triggers: burst_triggers,
+        interval: burst_interval,
+        window: burst_window,
+        budget: burst_budget,
+    });
+
+    if !external_meters.is_empty() && ingest_token.is_none() {
+        return Err("EXTERNAL_METER needs INGEST_TOKEN, which the meters push with".into());
+    }
+    let unmetered = essential_load.iter()
+        .flat_map(|essential| essential.sources())
+        .find(|source| !external_meters.iter().any(|meter| &meter.source == *source));
+    if let Some(source) = unmetered {
+        return Err(format!("ESSENTIAL_LOAD reads {}, which isn't an EXTERNAL_METER", source).into());
+    }
+    if essential_load.is_none() && (runtime_load == RuntimeLoad::Essential || surplus.basis == SurplusBasis::Essential) {
+        return Err("RUNTIME_LOAD=essential and SURPLUS_BASIS=essential need ESSENTIAL_LOAD".into());
+    }
+
+    let compliance = match (export_compliance_limit_w, &postgres) {
+        (Some(limit_w), Some(postgres)) => Some(ComplianceConfig {
+            limit_w,
+            postgres: postgres.clone(),
+            measurement: publish.output_name("Grid Power")
+                .ok_or("EXPORT_COMPLIANCE_LIMIT_W needs Grid Power published, the report reads it from PostgreSQL")?
+                .to_string(),
+            timezone,
+            max_gap: battery_max_gap,
+        }),
+        (Some(_), None) => return Err("EXPORT_COMPLIANCE_LIMIT_W needs POSTGRES_URL, the report reads its history".into()),
+        (None, _) => None,
+    };
+
+    let anomaly = anomaly_factor.map(|factor| {
+        let defaults = anomaly::AnomalyConfig::new(factor);
+        anomaly::AnomalyConfig {
+            factor,
+            sustain: anomaly_sustain.unwrap_or(defaults.sustain),
+            weeks: anomaly_weeks.unwrap_or(defaults.weeks),
+        }
+    });
+
+    let http = outbound.with_env_proxies().clients()?;
+    control.http = http.clone();
+
+    let night = night_window.map(|(start, end)| NightConfig {
+        start,
+        end,
+        idle_after: night_idle,
+        interval: night_interval,
+        wake_pv_voltage: night_wake_pv_voltage,
+    });
+
+    Ok(Config {
+        inverter_urls: urls,
+        serial,
+        polling,
+        night,
+        power_save,
+        burst,
+        listen_addrs,
+        socket,
+        balance,
+        consistency_polls,
+        load_source,
+        soc_calibration,
+        charge_temperatures,
+        dongle_protocol,
+        power_signs,
+        publish,
+        labels,
+        nut,
+        apcupsd,
+        battery_capacity_kwh,
+        backup_reserve_pct,
+        essential_load,
+        runtime_load,
+        battery_max_gap,
+        timezone,
+        http_limits,
+        http_log,
+        control,
+        charge_windows,
+        forecast_planes,
+        evc,
+        surplus,
+        mqtt,
+        zabbix,
+        redis,
+        postgres,
+        compliance,
+        statsd,
+        events,
+        textfile,
+        thresholds,
+        change_thresholds,
+        bms_limit_alert: bms_limit_alert.then_some(bms_limit_alert_secs),
+        bms_limits,
+        spike_limits,
+        battery_modules,
+        battery_module_drift_pct,
+        eps,
+        anomaly,
+        federation,
+        http,
+        signing,
+        debug_token,
+        ingest_token,
+        external_meters,
+        settings_interval,
+        curtailment,
+    })
+}
+
+/// Parses a comma-separated list of `host:port` listen addresses. IPv6 literals
+/// must be bracketed (`[::]:3000`); hostnames are resolved to all their addresses
+/// and `unix:/path` entries listen on a unix domain socket.
+fn parse_listen_addrs(value: &str) -> Result<Vec<ListenAddr>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut addrs = Vec::new();
+    for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        if let Some(path) = entry.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("Unix listen address needs a socket path".into());
+            }
+            addrs.push(ListenAddr::Unix(PathBuf::from(path)));
+            continue;
+        }
+
+        match entry.parse::<SocketAddr>() {
+            Ok(addr) => addrs.push(ListenAddr::Tcp(addr)),
+            Err(_) => {
+                let host = entry.rsplit_once(':').map_or(entry, |(host, _)| host);
+                if host.contains(':') {
+                    return Err(format!("IPv6 listen address must be bracketed: {}", entry).into());
+                }
+                let resolved = entry.to_socket_addrs()
+                    .map_err(|e| format!("Invalid listen address {}: {}", entry, e))?;
+                addrs.extend(resolved.map(ListenAddr::Tcp));
+            }
+        }
+    }
+
+    if addrs.is_empty() {
+        return Err("LISTEN_ADDR does not contain any addresses".into());
+    }
+    Ok(addrs)
+}
+
+/// Binds a unix domain socket listener, replacing a stale socket file left
+/// behind by a previous run and applying the configured mode and group.
+fn bind_unix_socket(path: &Path, socket: &SocketConfig) -> Result<tokio::net::UnixListener, Box<dyn std::error::Error + Send + Sync>> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", path.display()).into());
+        }
+        std::fs::remove_file(path)?;
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(socket.mode))?;
+    if let Some(group) = &socket.group {
+        std::os::unix::fs::chown(path, None, Some(resolve_group(group)?))?;
+    }
+
+    Ok(listener)
+}
+
+fn resolve_group(group: &str) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let groups = std::fs::read_to_string("/etc/group")?;
+    groups.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() > 2 && fields[0] == group)
+        .and_then(|fields| fields[2].parse().ok())
+        .ok_or_else(|| format!("Unknown group: {}", group).into())
+}
+
+fn parse_secs(key: &str, value: &str) -> Result<Duration, Box<dyn std::error::Error + Send + Sync>> {
+    value.trim().parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|_| format!("Invalid value for {}: {}", key, value).into())
+}
+
+/// The representation of /status a client asked for in its Accept header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StatusFormat {
+    Json,
+    /// One `key=value` line per field.
+    Text,
+    /// The /metrics rendering.
+    OpenMetrics,
+}
+
+impl StatusFormat {
+    /// Picks the supported media type with the highest q-value, preferring earlier
+    /// entries on ties. Anything unrecognised falls back to JSON.
+    fn from_accept(accept: &str) -> Self {
+        let mut best = (StatusFormat::Json, 0.0);
+        for entry in accept.split(',') {
+            let mut params = entry.split(';').map(str::trim);
+            let format = match params.next().unwrap_or_default().to_ascii_lowercase().as_str() {
+                "application/json" | "application/*" | "*/*" => StatusFormat::Json,
+                "text/plain" | "text/*" => StatusFormat::Text,
+                "application/openmetrics-text" => StatusFormat::OpenMetrics,
+                _ => continue,
+            };
+            let q = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f64>().ok())
+                .unwrap_or(1.0);
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for StatusFormat {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let accept = parts.headers.get(axum::http::header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        Ok(StatusFormat::from_accept(accept))
+    }
+}
+
+fn render_status_text(status: &StatusOutput) -> String {
+    let mut out = String::new();
+    for (name, value) in &status.labels {
+        out.push_str(&format!("label_{}={}\n", name, value));
+    }
+    let fields = [
+        ("solar_panels", status.solar_panels.as_str()),
+        ("batteries", &status.batteries),
+        ("battery_soc_raw", &status.battery_soc_raw),
+        ("battery_status", &status.battery_status),
+        ("battery_power", &status.battery_power),
+        ("grid_status", &status.grid_status),
+        ("grid_power", &status.grid_power),
+        ("home_consumption", &status.home_consumption),
+    ];
+    for (name, value) in fields {
+        out.push_str(&format!("{}={}\n", name, value));
+    }
+    for (name, watts) in [("battery_power_w", status.battery_power_w), ("grid_power_w", status.grid_power_w)] {
+        if let Some(watts) = watts {
+            out.push_str(&format!("{}={:.1}\n", name, watts));
+        }
+    }
+    for (name, watts) in &status.circuits_w {
+        out.push_str(&format!("{}={:.1}\n", zabbix::item_key("circuit_", name), watts));
+    }
+    if let Some(watts) = status.essential_load_w {
+        out.push_str(&format!("essential_load_w={:.1}\n", watts));
+    }
+    out.push_str(&format!("partial={}\n", status.partial));
+    out
+}
+
+async fn get_status(
+    format: StatusFormat,
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let vary = (axum::http::header::VARY, "Accept");
+    match format {
+        StatusFormat::Json => ([vary], Json(state.latest().status.clone())).into_response(),
+        StatusFormat::Text => (
+            [vary, (axum::http::header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            render_status_text(&state.latest().status),
+        ).into_response(),
+        StatusFormat::OpenMetrics => (
+            [vary, (axum::http::header::CONTENT_TYPE, "application/openmetrics-text; version=1.0.0; charset=utf-8")],
+            format!("{}# EOF\n", metrics_text(&state).await),
+        ).into_response(),
+    }
+}
+
+/// Longest a `/status/raw?wait=` request is held open.
+const RAW_WAIT_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+struct RawQuery {
+    /// Seconds to wait for the next poll before answering with the latest one.
+    wait: Option<u64>,
+}
+
+async fn get_raw_status(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Query(query): axum::extract::Query<RawQuery>,
+) -> Result<Json<RawOutput>, StatusCode> {
+    let mut latest = state.latest.subscribe();
+    if let Some(wait) = query.wait {
+        let wait = Duration::from_secs(wait).min(RAW_WAIT_MAX);
+        let _ = tokio::time::timeout(wait, latest.changed()).await;
+    }
+    let raw = latest.borrow().raw.clone();
+    match &state.signing {
+        Some(key) => key.sign(raw, state.clock.unix_now()).map(Json).map_err(|e| {
+            eprintln!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }),
+        None => Ok(Json(raw)),
+    }
+}
+
+/// Converts a measurement name like "Load/Generator Power" into a Prometheus
+/// metric name like `solax_load_generator_power`.
+fn metric_name(name: &str) -> String {
+    let mut metric = String::from("solax_");
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            metric.push(c.to_ascii_lowercase());
+        } else if !metric.ends_with('_') {
+            metric.push('_');
+        }
+    }
+    metric.trim_end_matches('_').to_string()
+}
+
+/// Renders a Prometheus label set like `{site="cabin",sn="X"}`, or nothing when empty.
+fn render_labels(labels: &BTreeMap<String, String>) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels.iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn render_metrics(raw: &RawOutput) -> String {
+    let labels = render_labels(&raw.labels);
+    let mut out = String::new();
+    for (name, measurement) in &raw.measurements {
+        let metric = metric_name(name);
+        out.push_str(&format!("# HELP {} {} ({})\n", metric, name, measurement.unit));
+        out.push_str(&format!("# TYPE {} gauge\n", metric));
+        out.push_str(&format!("{}{} {}\n", metric, labels, measurement.value));
+    }
+    out.push_str("# HELP solax_snapshot_partial Whether the last snapshot was decoded from a truncated Data array\n");
+    out.push_str("# TYPE solax_snapshot_partial gauge\n");
+    out.push_str(&format!("solax_snapshot_partial{} {}\n", labels, u8::from(raw.partial)));
+    if let Some(hours) = raw.backup_runtime_estimate_hours {
+        out.push_str("# HELP solax_backup_runtime_estimate_hours How long the battery would run the house if the grid failed now\n");
+        out.push_str("# TYPE solax_backup_runtime_estimate_hours gauge\n");
+        out.push_str(&format!("solax_backup_runtime_estimate_hours{} {}\n", labels, hours));
+    }
+    out
+}
+
+/// One series per battery module, labelled with its position in the stack.
+type ModuleGauge = fn(&BatteryModule) -> f64;
+
+fn render_module_metrics(labels: &BTreeMap<String, String>, modules: &[BatteryModule]) -> String {
+    let gauges: [(&str, &str, ModuleGauge); 3] = [
+        ("solax_battery_module_soc_percent", "State of charge of each battery module", |module| module.soc_pct),
+        ("solax_battery_module_voltage_volts", "Voltage of each battery module", |module| module.voltage_v),
+        ("solax_battery_module_temperature_celsius", "Temperature of each battery module", |module| module.temperature_c),
+    ];
+    let mut out = String::new();
+    for (metric, help, value) in gauges {
+        out.push_str(&format!("# HELP {} {}\n", metric, help));
+        out.push_str(&format!("# TYPE {} gauge\n", metric));
+        for module in modules {
+            let mut labels = labels.clone();
+            labels.insert("module".to_string(), module.module.to_string());
+            out.push_str(&format!("{}{} {}\n", metric, render_labels(&labels), value(module)));
+        }
+    }
+    out
+}
+
+type AvailabilityGauge = fn(&AvailabilitySummary) -> Option<f64>;
+
+fn render_availability_metrics(availability: &AvailabilityOutput) -> String {
+    let mut out = String::new();
+    let gauges: [(&str, &str, AvailabilityGauge); 4] = [
+        ("solax_poll_attempts", "Inverter polls attempted", |day| Some(f64::from(day.attempted))),
+        ("solax_poll_successes", "Inverter polls that succeeded", |day| Some(f64::from(day.succeeded))),
+        ("solax_poll_success_ratio", "Share of inverter polls that succeeded", |day| day.ratio),
+        ("solax_poll_longest_gap_seconds", "Longest time between successful polls", |day| Some(day.longest_gap_secs as f64)),
+    ];
+    for (metric, help, value) in gauges {
+        out.push_str(&format!("# HELP {} {}, per day and over the last {} days\n", metric, help, AVAILABILITY_DAYS));
+        out.push_str(&format!("# TYPE {} gauge\n", metric));
+        for day in &availability.days {
+            if let (Some(date), Some(value)) = (day.date, value(day)) {
+                out.push_str(&format!("{}{{day=\"{}\"}} {}\n", metric, date, value));
+            }
+        }
+        if let Some(value) = value(&availability.overall) {
+            out.push_str(&format!("{}{{day=\"all\"}} {}\n", metric, value));
+        }
+    }
+    out
+}
+
+async fn metrics_text(state: &AppState) -> String {
+    let latest = state.latest();
+    let mut metrics = render_metrics(&latest.raw);
+    if let Some(snapshot) = latest.snapshot.as_ref().filter(|snapshot| !snapshot.battery_modules.is_empty()) {
+        metrics.push_str(&render_module_metrics(&latest.raw.labels, &snapshot.battery_modules));
+    }
+    if let Some(last) = state.health.read().await.last_success {
+        metrics.push_str("# HELP solax_last_success_timestamp_seconds Unix time of the last successful poll\n");
+        metrics.push_str("# TYPE solax_last_success_timestamp_seconds gauge\n");
+        metrics.push_str(&format!("solax_last_success_timestamp_seconds {}\n", last));
+    }
+    metrics.push_str("# HELP solax_inverter_restarts_total Inverter reboots detected since the service started\n");
+    metrics.push_str("# TYPE solax_inverter_restarts_total counter\n");
+    metrics.push_str(&format!("solax_inverter_restarts_total {}\n", state.inverter_restarts.load(std::sync::atomic::Ordering::SeqCst)));
+    metrics.push_str(&render_availability_metrics(&state.availability.read().await.output()));
+    metrics.push_str(&render_battery_metrics(&state.battery.read().await.output(state.capacity_kwh().await)));
+    metrics.push_str(&render_grid_metrics(&*state.grid.read().await));
+    if let Some(estimate) = state.curtailment_estimate {
+        metrics.push_str(&render_curtailment_metrics(&state.curtailment.read().await.output(estimate)));
+    }
+    metrics.push_str(&render_http_metrics(&*state.http_stats.read().await));
+    if let Some(evc) = &*state.evc.read().await {
+        metrics.push_str(&render_evc_metrics(evc));
+    }
+    if let Some(hash) = &state.rules_hash {
+        metrics.push_str(&render_rule_metrics(&state.rules.read().await, hash));
+    }
+    if let Some(settings) = &*state.settings.read().await {
+        metrics.push_str(&render_settings_metrics(settings));
+    }
+    metrics
+}
+
+/// Replaces the node_exporter textfile in one step: the text goes to a hidden file next to
+/// it, which the collector ignores, and is renamed over it.
+fn write_textfile(path: &Path, text: &str) -> std::io::Result<()> {
+    let name = path.file_name().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file path"))?;
+    let temp = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    std::fs::write(&temp, text)?;
+    std::fs::rename(&temp, path)
+}
+
+async fn get_metrics(
+    State(state): State<Arc<AppState>>,
+) -> ([(axum::http::header::HeaderName, &'static str); 1], String) {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics_text(&state).await,
+    )
+}
+
+fn render_evc_metrics(evc: &EvcStatusOutput) -> String {
+    let labels = render_labels(&evc.labels);
+    let gauges = [
+        ("solax_evc_charging_power_watts", "EV charger charging power", evc.charging_power_w),
+        ("solax_evc_session_energy_kwh", "Energy charged in the current EV session", evc.session_energy_kwh),
+        ("solax_evc_plugged", "Whether a car is plugged into the EV charger", f64::from(u8::from(evc.plugged))),
+        ("solax_evc_charging", "Whether the EV charger is charging", f64::from(u8::from(evc.state == "charging"))),
+    ];
+    let mut out = String::new();
+    for (metric, help, value) in gauges {
+        out.push_str(&format!("# HELP {} {}\n", metric, help));
+        out.push_str(&format!("# TYPE {} gauge\n", metric));
+        out.push_str(&format!("{}{} {}\n", metric, labels, value));
+    }
+    out
+}
+
+fn render_http_metrics(stats: &HttpStatsOutput) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP solax_http_requests_total HTTP requests served, by path\n");
+    out.push_str("# TYPE solax_http_requests_total counter\n");
+    for (route, counts) in &stats.routes {
+        out.push_str(&format!("solax_http_requests_total{{route=\"{}\"}} {}\n", route, counts.requests));
+    }
+    out.push_str("# HELP solax_http_errors_total HTTP requests answered with a 4xx or 5xx status, by path\n");
+    out.push_str("# TYPE solax_http_errors_total counter\n");
+    for (route, counts) in &stats.routes {
+        out.push_str(&format!("solax_http_errors_total{{route=\"{}\"}} {}\n", route, counts.errors));
+    }
+    out
+}
+
+async fn get_http_stats(
+    State(state): State<Arc<AppState>>,
+) -> Json<HttpStatsOutput> {
+    Json(state.http_stats.read().await.clone())
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportLimitRequest {
+    watts: u32,
+}
+
+type ControlError = (StatusCode, String);
+
+/// Whether the request carries `Authorization: Bearer <token>`, compared in constant time.
+fn bearer_matches(headers: &axum::http::HeaderMap, token: &str) -> bool {
+    let Some(given) = headers.get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    mqtt::secret_matches(given, token)
+}
+
+/// The control settings, once the request has shown the right bearer token.
+fn authorize<'a>(state: &'a AppState, headers: &axum::http::HeaderMap) -> Result<&'a Control, ControlError> {
+    let Some(control) = &state.control else {
+        return Err((StatusCode::NOT_FOUND, "Control endpoints are disabled".to_string()));
+    };
+    match &control.config.token {
+        Some(token) if bearer_matches(headers, token) => Ok(control),
+        _ => Err((StatusCode::UNAUTHORIZED, "Missing or wrong bearer token".to_string())),
+    }
+}
+
+/// Refuses any write while the inverter reports a fault.
+fn check_not_faulted(run_mode: Option<RunMode>) -> Result<(), ControlError> {
+    match run_mode {
+        Some(mode) if mode.is_fault() => Err((
+            StatusCode::CONFLICT,
+            format!("Inverter is in {:?} mode, refusing to write", mode),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Why the battery mustn't be charged now, if it mustn't.
+fn charge_inhibited_reason(snapshot: &Snapshot, temperatures: ChargeTemperatures) -> Option<String> {
+    if snapshot.charge_advisory(temperatures) != Some(ChargeAdvisory::Inhibited) {
+        return None;
+    }
+    Some(match snapshot.value("Battery Temperature") {
+        Some(celsius) if celsius < temperatures.floor_c => {
+            format!("battery at {:.1} °C, below CHARGE_TEMP_FLOOR_C of {:.1} °C", celsius, temperatures.floor_c)
+        }
+        _ => "the BMS allows no charge current".to_string(),
+    })
+}
+
+/// Refuses limits above the rated power, and any write while the inverter reports a fault.
+fn check_export_limit(watts: u32, rated_power_kw: Option<f64>, run_mode: Option<RunMode>) -> Result<(), ControlError> {
+    let Some(rated_kw) = rated_power_kw else {
+        return Err((StatusCode::CONFLICT, "Rated power is unknown, refusing to write".to_string()));
+    };
+    if f64::from(watts) > rated_kw * 1000.0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{} W is above the rated power of {} kW", watts, rated_kw),
+        ));
+    }
+    check_not_faulted(run_mode)
+}
+
+/// The latest snapshot, for checking a write against the inverter's current state.
+async fn snapshot_for_write(state: &AppState) -> Result<Snapshot, ControlError> {
+    state.fresh_snapshot().await
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "No recent inverter data".to_string()))
+}
+
+fn remote_addr(peer: Option<axum::extract::ConnectInfo<Peer>>) -> String {
+    peer.and_then(|info| info.0.addr).map_or("-".to_string(), |addr| addr.to_string())
+}
+
+/// Appends one entry to a JSON-lines log such as the control audit log.
+fn append_json_line(path: &Path, entry: &serde_json::Value) {
+    use std::io::Write;
+
+    let result = rotate_log(path, JSON_LOG_MAX_BYTES, JSON_LOG_KEEP)
+        .and_then(|()| std::fs::OpenOptions::new().create(true).append(true).open(path))
+        .and_then(|mut file| writeln!(file, "{}", entry));
+    if let Err(e) = result {
+        eprintln!("Failed to append to {}: {}", path.display(), e);
+    }
+}
+
+/// Posts a control change to the configured notifiers in the background.
+fn announce(control: &Control, alert: Alert) {
+    let config = control.config.clone();
+    tokio::spawn(async move { config.send_alert(&alert, "the control change").await });
+}
+
+async fn set_export_limit(
+    State(state): State<Arc<AppState>>,
+    peer: Option<axum::extract::ConnectInfo<Peer>>,
+    headers: axum::http::HeaderMap,
+    Json(request): Json<ExportLimitRequest>,
+) -> Result<Json<ExportLimitOutput>, ControlError> {
+    let control = authorize(&state, &headers)?;
+    apply_export_limit(&state, control, request.watts, &remote_addr(peer)).await.map(Json)
+}
+
+/// Checks and writes the export limit, then audits and announces it.
+async fn apply_export_limit(state: &AppState, control: &Control, watts: u32, by: &str) -> Result<ExportLimitOutput, ControlError> {
+    let snapshot = snapshot_for_write(state).await?;
+    let rated_power_kw = state.info.read().await.as_ref().and_then(|info| info.rated_power_kw);
+    check_export_limit(watts, rated_power_kw, snapshot.run_mode())?;
+
+    let writing = control.writing.lock().await;
+    let previous_watts = control.inverter.read_export_limit().await.ok();
+    let result = control.inverter.set_export_limit(watts).await;
+    drop(writing);
+
+    state.audit(serde_json::json!({
+        "time": state.clock.unix_now(),
+        "remote": by,
+        "setting": "export_limit",
+        "previous_watts": previous_watts,
+        "watts": watts,
+        "error": result.as_ref().err().map(|e| e.to_string()),
+    })).await;
+    match result {
+        Ok(watts) => {
+            println!("Export limit set to {} W (was {:?}) by {}", watts, previous_watts, by);
+            announce(control, Alert::new(Severity::Info, format!("⚡ Export limit set to {} W", watts))
+                .field("Previous", previous_watts.map_or("unknown".to_string(), |watts| format!("{} W", watts)))
+                .field("By", by));
+            Ok(ExportLimitOutput { watts, previous_watts })
+        }
+        Err(e) => {
+            eprintln!("Setting the export limit to {} W failed: {}", watts, e);
+            Err((StatusCode::BAD_GATEWAY, e.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatteryModeRequest {
+    mode: BatteryMode,
+    /// Seconds until a forced mode reverts to self use, capped at CONTROL_FORCE_MAX_SECS.
+    revert_after_secs: Option<u64>,
+    /// Force charges even while the charge advisory is `inhibited`.
+    #[serde(default)]
+    override_temperature: bool,
+}
+
+/// Writes the battery mode, audits and announces it, and returns the new generation.
+/// With `only_if` nothing is written if another mode was set since that generation.
+async fn apply_battery_mode(state: &AppState, control: &Control, mode: BatteryMode, by: &str, only_if: Option<u64>) -> Result<Option<u64>, String> {
+    use std::sync::atomic::Ordering;
+
+    let writing = control.writing.lock().await;
+    if only_if.is_some_and(|generation| generation != control.battery_mode_generation.load(Ordering::SeqCst)) {
+        return Ok(None);
+    }
+    let result = control.inverter.set_battery_mode(mode).await.map_err(|e| e.to_string());
+    // A failed write leaves any pending revert in place
+    let generation = match result {
+        Ok(()) => {
+            let generation = control.battery_mode_generation.fetch_add(1, Ordering::SeqCst) + 1;
+            control.set_revert(None);
+            generation
+        }
+        Err(_) => control.battery_mode_generation.load(Ordering::SeqCst),
+    };
+    drop(writing);
+
+    state.audit(serde_json::json!({
+        "time": state.clock.unix_now(),
+        "remote": by,
+        "setting": "battery_mode",
+        "mode": mode.as_str(),
+        "error": result.as_ref().err(),
+    })).await;
+    match &result {
+        Ok(()) => {
+            println!("Battery mode set to {} by {}", mode.as_str(), by);
+            announce(control, Alert::new(Severity::Info, format!("🔋 Battery mode set to {}", mode.as_str()))
+                .field("By", by));
+        }
+        Err(e) => {
+            eprintln!("Setting the battery mode to {} failed: {}", mode.as_str(), e);
+            announce(control, Alert::new(Severity::Warning, format!("🔋 Battery mode change to {} failed", mode.as_str()))
+                .description(e.clone())
+                .field("By", by));
+        }
+    }
+    result.map(|()| Some(generation))
+}
+
+async fn set_battery_mode(
+    State(state): State<Arc<AppState>>,
+    peer: Option<axum::extract::ConnectInfo<Peer>>,
+    headers: axum::http::HeaderMap,
+    Json(request): Json<BatteryModeRequest>,
+) -> Result<Json<BatteryModeOutput>, ControlError> {
+    let control = authorize(&state, &headers)?;
+    start_battery_mode(&state, control, request.mode, request.revert_after_secs, request.override_temperature, &remote_addr(peer)).await.map(Json)
+}
+
+/// Checks and writes the battery mode, starting the revert timer of a forced mode.
+async fn start_battery_mode(
+    state: &Arc<AppState>,
+    control: &Control,
+    mode: BatteryMode,
+    revert_after_secs: Option<u64>,
+    override_temperature: bool,
+    by: &str,
+) -> Result<BatteryModeOutput, ControlError> {
+    let revert_after = match (mode.is_forced(), revert_after_secs) {
+        (true, requested) => Some(requested.map_or(control.config.force_max, Duration::from_secs).min(control.config.force_max)),
+        (false, None) => None,
+        (false, Some(_)) => return Err((
+            StatusCode::BAD_REQUEST,
+            "revert_after_secs only applies to force_charge and force_discharge".to_string(),
+        )),
+    };
+    let snapshot = snapshot_for_write(state).await?;
+    check_not_faulted(snapshot.run_mode())?;
+    if let Some(reason) = charge_inhibited_reason(&snapshot, state.charge_temperatures).filter(|_| mode == BatteryMode::ForceCharge) {
+        if !override_temperature {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("Charging is inhibited ({}), set override_temperature to force charge anyway", reason),
+            ));
+        }
+        eprintln!("Force charging although charging is inhibited ({}), overridden by {}", reason, by);
+    }
+
+    let generation = apply_battery_mode(state, control, mode, by, None).await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e))?;
+    let revert_at = revert_after.map(|after| state.clock.unix_now() + after.as_secs());
+    if let (Some(revert_at), Some(generation)) = (revert_at, generation) {
+        let writing = control.writing.lock().await;
+        // Unless another change got in first
+        if control.battery_mode_generation.load(std::sync::atomic::Ordering::SeqCst) == generation {
+            control.set_revert(Some(PendingRevert { generation, revert_at, attempts: 0, retry_at: None }));
+            control.revert_scheduled.notify_one();
+        }
+        drop(writing);
+    }
+    Ok(BatteryModeOutput { mode: mode.as_str().to_string(), revert_at })
+}
+
+/// Makes the pending revert's write if it's due, rescheduling it with backoff when the write
+/// fails. Returns when it's due next, None when nothing is pending.
+async fn step_battery_revert(state: &AppState, control: &Control) -> Option<u64> {
+    let pending = control.pending_revert()?;
+    let now = state.clock.unix_now();
+    if now < pending.due_at() {
+        return Some(pending.due_at());
+    }
+    let result = apply_battery_mode(state, control, BatteryMode::SelfUse, "revert timer", Some(pending.generation)).await;
+
+    let writing = control.writing.lock().await;
+    if control.pending_revert() != Some(pending) {
+        // Done, or replaced by a later change
+        return control.pending_revert().map(|revert| revert.due_at());
+    }
+    let next = match result {
+        // Superseded without the write clearing it
+        Ok(_) => None,
+        Err(e) if pending.attempts + 1 >= REVERT_ATTEMPTS => {
+            eprintln!("Giving up reverting the battery to self use after {} attempts: {}", REVERT_ATTEMPTS, e);
+            announce(control, Alert::new(Severity::Critical, "🔋 Battery is stuck in a forced mode".to_string())
+                .description(format!("Reverting to self use failed {} times, the last time with: {}", REVERT_ATTEMPTS, e))
+                .field("Due since", pending.revert_at.to_string()));
+            None
+        }
+        Err(_) => {
+            let wait = REVERT_RETRY_SECS << pending.attempts;
+            eprintln!("Retrying the revert to self use in {} s", wait);
+            Some(PendingRevert { attempts: pending.attempts + 1, retry_at: Some(now + wait), ..pending })
+        }
+    };
+    control.set_revert(next);
+    drop(writing);
+    next.map(|revert| revert.due_at())
+}
+
+/// Carries out forced battery modes' reverts as they come due, starting with one that came
+/// due while the service was down.
+async fn run_battery_revert(state: Arc<AppState>) {
+    let Some(control) = &state.control else { return };
+    loop {
+        match step_battery_revert(&state, control).await {
+            Some(due_at) => {
+                let wait = Duration::from_secs(due_at.saturating_sub(state.clock.unix_now()));
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = control.revert_scheduled.notified() => {}
+                }
+            }
+            None => control.revert_scheduled.notified().await,
+        }
+    }
+}
+
+/// When local `time` on `date` happens in `timezone`. A time that happens twice as the clocks
+/// go back is its first occurrence, and one skipped as they go forward is the first valid
+/// instant after it.
+fn local_instant(timezone: chrono_tz::Tz, date: chrono::NaiveDate, time: chrono::NaiveTime) -> chrono::DateTime<chrono_tz::Tz> {
+    use chrono::TimeZone;
+    let mut local = date.and_time(time);
+    loop {
+        if let Some(instant) = timezone.from_local_datetime(&local).earliest() {
+            return instant;
+        }
+        local += chrono::Duration::minutes(1);
+    }
+}
+
+/// A weekly period in which the battery is force-charged from the grid (CHARGE_WINDOW).
+#[derive(Debug, Clone, PartialEq)]
+struct ChargeWindow {
+    /// Days on which the window starts; a window ending before it starts runs past midnight.
+    days: Vec<chrono::Weekday>,
+    start: chrono::NaiveTime,
+    end: chrono::NaiveTime,
+    /// Charging stops early once the battery reaches this SoC.
+    target_soc: f64,
+    max_power_w: Option<u32>,
+    /// The window is skipped when the solar forecast for the day after it exceeds this.
+    skip_forecast_kwh: Option<f64>,
+}
+
+impl ChargeWindow {
+    /// Parses `02:00-05:00,days=mon-fri,soc=90,power=3000,skip_forecast_kwh=25`; days default
+    /// to every day and the target SoC to 100%.
+    fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = value.split(',').map(str::trim);
+        let times = parts.next().unwrap_or_default();
+        let (start, end) = times.split_once('-')
+            .ok_or_else(|| format!("Charge window {:?} must start with HH:MM-HH:MM", value))?;
+        let time = |text: &str| chrono::NaiveTime::parse_from_str(text.trim(), "%H:%M")
+            .map_err(|_| format!("Invalid time {:?} in charge window {:?}", text, value));
+        let mut window = ChargeWindow {
+            days: ALL_WEEKDAYS.to_vec(),
+            start: time(start)?,
+            end: time(end)?,
+            target_soc: 100.0,
+            max_power_w: None,
+            skip_forecast_kwh: None,
+        };
+        if window.start == window.end {
+            return Err(format!("Charge window {:?} is empty", value));
+        }
+
+        for option in parts {
+            let (name, setting) = option.split_once('=')
+                .ok_or_else(|| format!("Invalid option {:?} in charge window {:?}", option, value))?;
+            match name {
+                "days" => window.days = parse_weekdays(setting)?,
+                "soc" => window.target_soc = setting.parse()
+                    .ok()
+                    .filter(|soc| (0.0..=100.0).contains(soc))
+                    .ok_or_else(|| format!("Invalid soc {:?} in charge window {:?}", setting, value))?,
+                "power" => window.max_power_w = Some(setting.parse()
+                    .map_err(|_| format!("Invalid power {:?} in charge window {:?}", setting, value))?),
+                "skip_forecast_kwh" => window.skip_forecast_kwh = Some(setting.parse()
+                    .ok()
+                    .filter(|kwh: &f64| *kwh >= 0.0)
+                    .ok_or_else(|| format!("Invalid skip_forecast_kwh {:?} in charge window {:?}", setting, value))?),
+                _ => return Err(format!("Unknown option {:?} in charge window {:?}", name, value)),
+            }
+        }
+        Ok(window)
+    }
+
+    fn minutes(time: chrono::NaiveTime) -> u32 {
+        use chrono::Timelike;
+        time.hour() * 60 + time.minute()
+    }
+
+    fn length_minutes(&self) -> u32 {
+        (Self::minutes(self.end) + 1440 - Self::minutes(self.start)) % 1440
+    }
+
+    /// Minute-of-week ranges covered by the window, starting Monday 00:00.
+    fn week_ranges(&self) -> Vec<(u32, u32)> {
+        self.days.iter()
+            .map(|day| {
+                let start = day.num_days_from_monday() * 1440 + Self::minutes(self.start);
+                (start, start + self.length_minutes())
+            })
+            .collect()
+    }
+
+    /// The date the window started on, if `now` falls inside it. Start and end are wall-clock
+    /// times, so the window is an hour shorter or longer when the clocks change during it.
+    fn occurrence(&self, now: chrono::DateTime<chrono_tz::Tz>) -> Option<chrono::NaiveDate> {
+        use chrono::Datelike;
+        let today = now.date_naive();
+        [Some(today), today.pred_opt()].into_iter().flatten().find(|date| {
+            let end_date = if self.end > self.start { Some(*date) } else { date.succ_opt() };
+            let Some(end_date) = end_date else { return false };
+            self.days.contains(&date.weekday())
+                && local_instant(now.timezone(), *date, self.start) <= now
+                && now < local_instant(now.timezone(), end_date, self.end)
+        })
+    }
+
+    /// The day whose sun the window's charge competes with: the day it ends on, or the next
+    /// one for a window ending in the afternoon or evening.
+    fn forecast_date(&self, date: chrono::NaiveDate) -> Option<chrono::NaiveDate> {
+        let end_date = if self.end > self.start { Some(date) } else { date.succ_opt() };
+        if self.end < chrono::NaiveTime::from_hms_opt(12, 0, 0)? { end_date } else { end_date?.succ_opt() }
+    }
+
+    fn describe(&self) -> String {
+        format!("{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+const ALL_WEEKDAYS: [chrono::Weekday; 7] = [
+    chrono::Weekday::Mon,
+    chrono::Weekday::Tue,
+    chrono::Weekday::Wed,
+    chrono::Weekday::Thu,
+    chrono::Weekday::Fri,
+    chrono::Weekday::Sat,
+    chrono::Weekday::Sun,
+];
+
+/// Parses `mon-fri`, `sat+sun` or a mix like `mon+wed-fri`.
+fn parse_weekdays(value: &str) -> Result<Vec<chrono::Weekday>, String> {
+    let day = |name: &str| name.trim().parse::<chrono::Weekday>()
+        .map_err(|_| format!("Invalid day {:?}", name));
+    let mut days = Vec::new();
+    for part in value.split('+') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (mut current, last) = (day(first)?, day(last)?);
+                days.push(current);
+                while current != last {
+                    current = current.succ();
+                    days.push(current);
+                }
+            }
+            None => days.push(day(part)?),
+        }
+    }
+    days.dedup();
+    Ok(days)
+}
+
+/// Rejects charge windows that overlap, including across midnight and the end of the week.
+fn validate_charge_windows(windows: &[ChargeWindow]) -> Result<(), String> {
+    const WEEK: u32 = 7 * 1440;
+    let ranges: Vec<(usize, (u32, u32))> = windows.iter()
+        .enumerate()
+        .flat_map(|(index, window)| window.week_ranges().into_iter().map(move |range| (index, range)))
+        .collect();
+    for (i, (first, (a_start, a_end))) in ranges.iter().enumerate() {
+        for (second, (b_start, b_end)) in &ranges[i + 1..] {
+            // Shift by a week both ways so a Sunday night window meets Monday morning
+            let overlaps = [0, WEEK].iter().any(|shift| {
+                (a_start + shift < *b_end && *b_start < a_end + shift)
+                    || (b_start + shift < *a_end && *a_start < b_end + shift)
+            });
+            if overlaps {
+                return Err(format!(
+                    "Charge windows {} and {} overlap",
+                    windows[*first].describe(),
+                    windows[*second].describe()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum ChargeTransition {
+    Start(usize),
+    Stop { window: usize, reason: &'static str },
+    /// The window is due but charging is inhibited; it starts once that lifts, if still due.
+    Held(usize),
+    /// This occurrence is left out because enough sun is forecast.
+    Skipped { window: usize, forecast_kwh: f64 },
+}
+
+/// Decides when charge windows start and stop; the writes are left to the caller.
+#[derive(Debug, Default)]
+struct ChargeScheduler {
+    windows: Vec<ChargeWindow>,
+    /// The running window and the date it started on.
+    active: Option<(usize, chrono::NaiveDate)>,
+    /// The last occurrence that was stopped or skipped, so it isn't started again.
+    finished: Option<(usize, chrono::NaiveDate)>,
+    /// Forecast solar production per local date in kWh; windows charge as usual without one.
+    forecast: BTreeMap<chrono::NaiveDate, f64>,
+}
+
+impl ChargeScheduler {
+    fn new(windows: Vec<ChargeWindow>) -> Self {
+        Self { windows, ..Self::default() }
+    }
+
+    fn step(&mut self, now: chrono::DateTime<chrono_tz::Tz>, soc: Option<f64>, inhibited: bool) -> Option<ChargeTransition> {
+        if let Some((index, date)) = self.active {
+            let window = &self.windows[index];
+            if inhibited && window.occurrence(now) == Some(date) {
+                // Not finished, so the window resumes once the battery may be charged again
+                self.active = None;
+                return Some(ChargeTransition::Stop { window: index, reason: "charging inhibited" });
+            }
+            let reason = if window.occurrence(now) != Some(date) {
+                "window ended"
+            } else if soc.is_some_and(|soc| soc >= window.target_soc) {
+                "target SoC reached"
+            } else {
+                return None;
+            };
+            self.active = None;
+            self.finished = Some((index, date));
+            return Some(ChargeTransition::Stop { window: index, reason });
+        }
+
+        // Without a current SoC there is no telling whether charging is needed
+        let soc = soc?;
+        for (index, window) in self.windows.iter().enumerate() {
+            let Some(date) = window.occurrence(now) else { continue };
+            if self.finished == Some((index, date)) {
+                continue;
+            }
+            if soc >= window.target_soc {
+                self.finished = Some((index, date));
+                continue;
+            }
+            let forecast_kwh = window.forecast_date(date).and_then(|day| self.forecast.get(&day).copied());
+            if let Some(forecast_kwh) = forecast_kwh.filter(|kwh| window.skip_forecast_kwh.is_some_and(|limit| *kwh > limit)) {
+                self.finished = Some((index, date));
+                return Some(ChargeTransition::Skipped { window: index, forecast_kwh });
+            }
+            if inhibited {
+                return Some(ChargeTransition::Held(index));
+            }
+            self.active = Some((index, date));
+            return Some(ChargeTransition::Start(index));
+        }
+        None
+    }
+
+    /// Gives up on the running window, e.g. when starting it failed.
+    fn abandon(&mut self) {
+        self.finished = self.active.take();
+    }
+}
+
+/// Refreshes the combined solar forecast of the FORECAST_PLANE lines every hour, within the
+/// 12 calls an hour Forecast.Solar allows without a key. A failed refresh keeps the last one.
+async fn run_forecast(
+    planes: Vec<forecast::Plane>,
+    http: outbound::Clients,
+    sender: tokio::sync::watch::Sender<BTreeMap<chrono::NaiveDate, f64>>,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(3600));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut warnings = Warnings::new();
+    loop {
+        ticker.tick().await;
+        let mut forecasts = Vec::with_capacity(planes.len());
+        for plane in &planes {
+            match plane.fetch(&http, forecast::DEFAULT_URL, Duration::from_secs(10)).await {
+                Ok(forecast) => forecasts.push(forecast),
+                Err(e) => {
+                    warnings.warn("forecast", format_args!("Fetching the solar forecast failed: {}", e));
+                    break;
+                }
+            }
+        }
+        if forecasts.len() == planes.len() {
+            warnings.clear("forecast", "Fetching the solar forecast works again");
+            sender.send_replace(forecast::combine(&forecasts));
+        }
+    }
+}
+
+/// Runs the charge windows against the local time, the latest SoC and the solar forecast.
+async fn run_charge_windows(
+    state: Arc<AppState>,
+    windows: Vec<ChargeWindow>,
+    timezone: chrono_tz::Tz,
+    forecast: Option<tokio::sync::watch::Receiver<BTreeMap<chrono::NaiveDate, f64>>>,
+) {
+    let Some(control) = &state.control else {
+        return;
+    };
+    let mut scheduler = ChargeScheduler::new(windows);
+    // Generation of the force-charge write, so the stop doesn't undo a later manual change
+    let mut started = None;
+    let mut warnings = Warnings::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        ticker.tick().await;
+        let snapshot = state.fresh_snapshot().await;
+        let soc = snapshot.as_ref().and_then(|snapshot| snapshot.value("Battery Remaining Capacity"));
+        let inhibited = snapshot.as_ref().and_then(|snapshot| charge_inhibited_reason(snapshot, state.charge_temperatures));
+        if let Some(forecast) = &forecast {
+            scheduler.forecast = forecast.borrow().clone();
+        }
+        let transition = scheduler.step(state.clock.utc_now().with_timezone(&timezone), soc, inhibited.is_some());
+        if inhibited.is_none() {
+            warnings.clear("inhibited", "Charging is no longer inhibited, charge windows run again");
+        }
+        match transition {
+            Some(ChargeTransition::Held(index)) => {
+                let reason = inhibited.unwrap_or_default();
+                warnings.warn("inhibited", format_args!("Not starting charge window {}: charging is inhibited ({})", scheduler.windows[index].describe(), reason));
+            }
+            Some(ChargeTransition::Skipped { window, forecast_kwh }) => {
+                let by = format!("charge window {}", scheduler.windows[window].describe());
+                println!("Skipping {}: {:.1} kWh of solar forecast", by, forecast_kwh);
+                state.audit(serde_json::json!({
+                    "time": state.clock.unix_now(),
+                    "remote": by,
+                    "setting": "charge_window",
+                    "skipped": true,
+                    "forecast_kwh": forecast_kwh,
+                })).await;
+                announce(control, Alert::new(Severity::Info, format!("🔋 Skipping {}", by))
+                    .field("Solar forecast", format!("{:.1} kWh", forecast_kwh)));
+            }
+            Some(ChargeTransition::Start(index)) => {
+                let window = &scheduler.windows[index];
+                let by = format!("charge window {}", window.describe());
+                if let Some(watts) = window.max_power_w {
+                    let result = control.inverter.set_charge_power_limit(watts).await;
+                    state.audit(serde_json::json!({
+                        "time": state.clock.unix_now(),
+                        "remote": by,
+                        "setting": "charge_power_limit",
+                        "watts": watts,
+                        "error": result.as_ref().err().map(|e| e.to_string()),
+                    })).await;
+                    if let Err(e) = result {
+                        eprintln!("Setting the charge power limit for {} failed, skipping it: {}", by, e);
+                        scheduler.abandon();
+                        continue;
+                    }
+                }
+                match apply_battery_mode(&state, control, BatteryMode::ForceCharge, &by, None).await {
+                    Ok(generation) => started = generation,
+                    Err(_) => scheduler.abandon(),
+                }
+            }
+            Some(ChargeTransition::Stop { window, reason }) => {
+                if let Some(inhibited) = &inhibited {
+                    println!("Stopping charge window {}: charging is inhibited ({})", scheduler.windows[window].describe(), inhibited);
+                }
+                let by = format!("charge window {} ({})", scheduler.windows[window].describe(), reason);
+                let _ = apply_battery_mode(&state, control, BatteryMode::SelfUse, &by, started.take()).await;
+            }
+            None => (),
+        }
+    }
+}
+
+/// Reads the inverter settings every `interval` and logs and audits the ones that changed
+/// since the last read, like a limit changed from the SolaX app. Failures are only counted on
+/// /settings; polling carries on regardless.
+async fn run_settings_poller(state: Arc<AppState>, inverter: dongle::Handle, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    let mut warnings = Warnings::new();
+    loop {
+        ticker.tick().await;
+        let result = inverter.read_settings().await;
+        let now = state.clock.unix_now();
+        let mut changes = Vec::new();
+        if let Some(output) = state.settings.write().await.as_mut() {
+            output.last_attempt = Some(now);
+            match result {
+                Ok(settings) => {
+                    if let Some(previous) = &output.settings {
+                        changes = solax_mon::inverter::settings_changes(previous, &settings);
+                    }
+                    warnings.clear("settings", "Reading the inverter settings works again");
+                    output.settings = Some(settings);
+                    output.read_at = Some(now);
+                    output.last_error = None;
+                    output.consecutive_failures = 0;
+                }
+                Err(e) => {
+                    warnings.warn("settings", format_args!("Failed to read the inverter settings: {}", e));
+                    output.last_error = Some(e.to_string());
+                    output.consecutive_failures += 1;
+                }
+            }
+        }
+        for (setting, from, to) in changes {
+            println!("Inverter setting {} changed from {} to {}", setting, from, to);
+            state.audit(serde_json::json!({
+                "time": now,
+                "remote": "settings poller",
+                "setting": setting,
+                "from": from,
+                "to": to,
+            })).await;
+        }
+    }
+}
+
+async fn get_settings(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SettingsOutput>, StatusCode> {
+    state.settings.read().await.clone().map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug, Deserialize)]
+struct ComplianceQuery {
+    /// `YYYY-MM`, the current month when missing.
+    month: Option<String>,
+    /// `json` (default) or `csv`.
+    format: Option<String>,
+}
+
+async fn get_export_compliance(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Query(query): axum::extract::Query<ComplianceQuery>,
+) -> Result<axum::response::Response, (StatusCode, String)> {
+    use axum::response::IntoResponse;
+
+    let config = state.compliance.as_ref()
+        .ok_or((StatusCode::NOT_FOUND, "EXPORT_COMPLIANCE_LIMIT_W isn't set".to_string()))?;
+    let month = match &query.month {
+        Some(month) => parse_month(month).map_err(|e| (StatusCode::BAD_REQUEST, e))?,
+        None => current_month(config.timezone),
+    };
+    let csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(other) => return Err((StatusCode::BAD_REQUEST, format!("Unknown format {:?} (json, csv)", other))),
+    };
+    let output = export_compliance_report(config, month).await
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, format!("Failed to read the history: {}", e)))?;
+    Ok(if csv {
+        ([(axum::http::header::CONTENT_TYPE, "text/csv; charset=utf-8")], render_compliance_csv(&output)).into_response()
+    } else {
+        Json(output).into_response()
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ReportQuery {
+    /// `YYYY-MM` of /reports/monthly, the current month when missing.
+    month: Option<String>,
+    /// `YYYY` of /reports/yearly, the current year when missing.
+    year: Option<String>,
+    /// `json` (default) or `html`.
+    format: Option<String>,
+}
+
+async fn get_monthly_report(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Query(query): axum::extract::Query<ReportQuery>,
+) -> Result<axum::response::Response, (StatusCode, String)> {
+    let month = match &query.month {
+        Some(month) => parse_month(month).map_err(|e| (StatusCode::BAD_REQUEST, e))?,
+        None => current_month(state.timezone),
+    };
+    report_response(&state, Period::Month(month), query.format.as_deref()).await
+}
+
+async fn get_yearly_report(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Query(query): axum::extract::Query<ReportQuery>,
+) -> Result<axum::response::Response, (StatusCode, String)> {
+    use chrono::Datelike;
+
+    let year = match &query.year {
+        Some(year) => parse_year(year).map_err(|e| (StatusCode::BAD_REQUEST, e))?,
+        None => state.clock.utc_now().with_timezone(&state.timezone).year(),
+    };
+    report_response(&state, Period::Year(year), query.format.as_deref()).await
+}
+
+async fn report_response(
+    state: &AppState,
+    period: Period,
+    format: Option<&str>,
+) -> Result<axum::response::Response, (StatusCode, String)> {
+    use axum::response::IntoResponse;
+
+    let html = match format {
+        None | Some("json") => false,
+        Some("html") => true,
+        Some(other) => return Err((StatusCode::BAD_REQUEST, format!("Unknown format {:?} (json, html)", other))),
+    };
+    let output = state.report(period).await;
+    Ok(if html {
+        axum::response::Html(render_report_html(&output)).into_response()
+    } else {
+        Json(output).into_response()
+    })
+}
+
+/// The modules of a stacked battery as of the last poll; 404 without BATTERY_MODULES_INDEX or
+/// while the inverter reports none.
+async fn get_battery_modules(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<BatteryModulesOutput>, StatusCode> {
+    let latest = state.latest();
+    let snapshot = latest.snapshot.as_ref().filter(|snapshot| !snapshot.battery_modules.is_empty()).ok_or(StatusCode::NOT_FOUND)?;
+    let drifting = state.rules.read().await.iter().any(|rule| rule.name == "battery_module_drift" && rule.tier == 2);
+    Ok(Json(BatteryModulesOutput {
+        modules: snapshot.battery_modules.clone(),
+        soc_spread_pct: snapshot.value("Battery Module SoC Spread"),
+        drift_alert_pct: state.battery_module_drift_pct,
+        drifting,
+    }))
+}
+
+async fn get_curtailment(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<CurtailmentOutput>, StatusCode> {
+    let estimate = state.curtailment_estimate.ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(state.curtailment.read().await.output(estimate)))
+}
+
+fn render_settings_metrics(output: &SettingsOutput) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP solax_settings_read_failures Consecutive failed reads of the inverter settings\n");
+    out.push_str("# TYPE solax_settings_read_failures gauge\n");
+    out.push_str(&format!("solax_settings_read_failures {}\n", output.consecutive_failures));
+    let Some(settings) = &output.settings else {
+        return out;
+    };
+    let gauges = [
+        ("solax_setting_export_limit_watts", "Export limit set on the inverter", settings.export_limit_w),
+        ("solax_setting_charge_power_limit_watts", "Battery charge power limit set on the inverter", settings.charge_power_limit_w),
+    ];
+    for (metric, help, value) in gauges {
+        out.push_str(&format!("# HELP {} {}\n", metric, help));
+        out.push_str(&format!("# TYPE {} gauge\n", metric));
+        out.push_str(&format!("{} {}\n", metric, value));
+    }
+    out.push_str("# HELP solax_setting_work_mode Work mode set on the inverter\n");
+    out.push_str("# TYPE solax_setting_work_mode gauge\n");
+    out.push_str(&format!("solax_setting_work_mode{{mode=\"{}\"}} 1\n", settings.work_mode));
+    out
+}
+
+/// How a surplus device is switched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlugKind {
+    /// `GET /cm?cmnd=Power%20On`
+    Tasmota,
+    /// `GET /relay/0?turn=on`
+    Shelly,
+}
+
+/// A smart plug switched on when there is solar to spare (SURPLUS_DEVICE).
+#[derive(Debug, Clone, PartialEq)]
+struct SurplusDevice {
+    name: String,
+    kind: PlugKind,
+    url: String,
+    /// Shortest time the plug stays on, and off, before being switched again.
+    min_on: Duration,
+    min_off: Duration,
+}
+
+impl SurplusDevice {
+    /// Parses `name,type=tasmota|shelly,url=http://host,min_on=300,min_off=300`.
+    fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = value.split(',').map(str::trim);
+        let name = parts.next().filter(|name| !name.is_empty() && !name.contains('='))
+            .ok_or_else(|| format!("Surplus device {:?} must start with a name", value))?;
+        let mut device = SurplusDevice {
+            name: name.to_string(),
+            kind: PlugKind::Tasmota,
+            url: String::new(),
+            min_on: Duration::from_secs(300),
+            min_off: Duration::from_secs(300),
+        };
+        for option in parts {
+            let (key, setting) = option.split_once('=')
+                .ok_or_else(|| format!("Invalid option {:?} for surplus device {}", option, name))?;
+            let secs = || setting.parse().map(Duration::from_secs)
+                .map_err(|_| format!("Invalid {} {:?} for surplus device {}", key, setting, name));
+            match key {
+                "type" => device.kind = match setting {
+                    "tasmota" => PlugKind::Tasmota,
+                    "shelly" => PlugKind::Shelly,
+                    _ => return Err(format!("Unknown type {:?} for surplus device {} (tasmota, shelly)", setting, name)),
+                },
+                "url" => device.url = normalize_inverter_url(setting).trim_end_matches('/').to_string(),
+                "min_on" => device.min_on = secs()?,
+                "min_off" => device.min_off = secs()?,
+                _ => return Err(format!("Unknown option {:?} for surplus device {}", key, name)),
+            }
+        }
+        if device.url.is_empty() {
+            return Err(format!("Surplus device {} needs a url", name));
+        }
+        Ok(device)
+    }
+
+    fn command_url(&self, on: bool) -> String {
+        match (self.kind, on) {
+            (PlugKind::Tasmota, true) => format!("{}/cm?cmnd=Power%20On", self.url),
+            (PlugKind::Tasmota, false) => format!("{}/cm?cmnd=Power%20Off", self.url),
+            (PlugKind::Shelly, true) => format!("{}/relay/0?turn=on", self.url),
+            (PlugKind::Shelly, false) => format!("{}/relay/0?turn=off", self.url),
+        }
+    }
+}
+
+/// What the surplus controller counts as surplus (SURPLUS_BASIS).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SurplusBasis {
+    /// The export to the grid.
+    Export,
+    /// The solar power beyond the essential load, which is what's left for plugs on the
+    /// EPS-backed circuits while the grid is down and nothing is exported.
+    Essential,
+}
+
+impl SurplusBasis {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "export" => Some(Self::Export),
+            "essential" => Some(Self::Essential),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Export => "export",
+            Self::Essential => "essential",
+        }
+    }
+
+    /// How the surplus is named in the reasons of the switches.
+    fn describe(self) -> &'static str {
+        match self {
+            Self::Export => "export",
+            Self::Essential => "solar over essential load",
+        }
+    }
+}
+
+/// Export thresholds of the surplus controller: above `on_w` the next device in priority
+/// order is switched on, below `off_w` the last one that is on is switched off.
+#[derive(Debug, Clone)]
+struct SurplusConfig {
+    devices: Vec<SurplusDevice>,
+    on_w: f64,
+    off_w: f64,
+    basis: SurplusBasis,
+}
+
+impl Default for SurplusConfig {
+    fn default() -> Self {
+        Self { devices: Vec::new(), on_w: 1000.0, off_w: 100.0, basis: SurplusBasis::Export }
+    }
+}
+
+/// Decides which plug to switch, one per step so the export can settle in between.
+#[derive(Debug)]
+struct SurplusController {
+    config: SurplusConfig,
+    /// Whether each device is on, and when it was last switched.
+    states: Vec<(bool, Option<Instant>)>,
+    /// The inverter's export limit, from the settings poller.
+    export_limit_w: Option<f64>,
+}
+
+impl SurplusController {
+    fn new(config: SurplusConfig) -> Self {
+        let states = vec![(false, None); config.devices.len()];
+        Self { config, states, export_limit_w: None }
+    }
+
+    fn dwelled(&self, index: usize, now: Instant) -> bool {
+        let (on, since) = self.states[index];
+        let device = &self.config.devices[index];
+        let dwell = if on { device.min_on } else { device.min_off };
+        since.is_none_or(|since| now.duration_since(since) >= dwell)
+    }
+
+    /// The surplus above which a device is switched on. An export limit below it would keep
+    /// the export from ever getting there, so then an export close to the limit counts.
+    fn on_w(&self) -> f64 {
+        match self.export_limit_w.filter(|limit| *limit > 0.0 && self.config.basis == SurplusBasis::Export) {
+            Some(limit) => self.config.on_w.min(limit * 0.95),
+            None => self.config.on_w,
+        }
+    }
+
+    /// The device to switch and whether to switch it on, with the reason.
+    fn decide(&self, surplus_w: f64, now: Instant) -> Option<(usize, bool, String)> {
+        let on_w = self.on_w();
+        let basis = self.config.basis.describe();
+        if surplus_w > on_w {
+            let index = self.states.iter().position(|(on, _)| !on)?;
+            return self.dwelled(index, now)
+                .then(|| (index, true, format!("{} {:.0} W above {:.0} W", basis, surplus_w, on_w)));
+        }
+        if surplus_w < self.config.off_w {
+            let index = self.states.iter().rposition(|(on, _)| *on)?;
+            return self.dwelled(index, now)
+                .then(|| (index, false, format!("{} {:.0} W below {:.0} W", basis, surplus_w, self.config.off_w)));
+        }
+        None
+    }
+
+    fn switched(&mut self, index: usize, on: bool, now: Instant) {
+        self.states[index] = (on, Some(now));
+    }
+}
+
+/// Attempts per plug command before the failure is reported.
+const PLUG_ATTEMPTS: u32 = 3;
+
+async fn send_plug_command(http: &outbound::Clients, device: &SurplusDevice, on: bool) -> Result<(), String> {
+    let url = device.command_url(on);
+    let mut last_error = String::new();
+    for attempt in 1..=PLUG_ATTEMPTS {
+        match http.for_url(&url).get(&url).timeout(Duration::from_secs(5)).send().await.and_then(|r| r.error_for_status()) {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = http.describe_error(&url, &e),
+        }
+        if attempt < PLUG_ATTEMPTS {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+    }
+    Err(last_error)
+}
+
+/// Switches the surplus devices on the grid export of the latest snapshot. Runs apart from
+/// the poll loop, so a plug that doesn't answer never delays polling.
+async fn run_surplus_controller(state: Arc<AppState>, config: SurplusConfig, http: outbound::Clients) {
+    let mut controller = SurplusController::new(config);
+    *state.surplus.write().await = SurplusOutput {
+        export_w: None,
+        export_limit_w: None,
+        devices: controller.config.devices.iter()
+            .map(|device| SurplusDeviceStatus {
+                name: device.name.clone(),
+                on: false,
+                since: None,
+                switches: 0,
+                last_decision: None,
+                last_error: None,
+            })
+            .collect(),
+    };
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        ticker.tick().await;
+        let snapshot = state.fresh_snapshot().await;
+        // Grid power is positive while exporting
+        let export_w = snapshot.as_ref().and_then(|snapshot| snapshot.value("Grid Power"));
+        let essential_w = snapshot.as_ref().and(state.latest().status.essential_load_w);
+        let surplus_w = match controller.config.basis {
+            SurplusBasis::Export => export_w,
+            SurplusBasis::Essential => snapshot.as_ref().and_then(|snapshot| snapshot.value("Total Solar Power"))
+                .zip(essential_w)
+                .map(|(solar_w, essential_w)| solar_w - essential_w),
+        };
+        controller.export_limit_w = state.settings.read().await.as_ref()
+            .and_then(|output| output.settings.as_ref())
+            .map(|settings| settings.export_limit_w as f64);
+        let mut surplus = state.surplus.write().await;
+        surplus.export_w = export_w;
+        surplus.export_limit_w = controller.export_limit_w;
+        drop(surplus);
+        let Some(surplus_w) = surplus_w else { continue };
+        let now = state.clock.instant();
+        let Some((index, on, reason)) = controller.decide(surplus_w, now) else { continue };
+
+        let device = controller.config.devices[index].clone();
+        let result = send_plug_command(&http, &device, on).await;
+        state.audit(serde_json::json!({
+            "time": state.clock.unix_now(),
+            "remote": "surplus controller",
+            "setting": "surplus_device",
+            "device": device.name,
+            "on": on,
+            "reason": reason,
+            "basis": controller.config.basis.as_str(),
+            "essential_load_w": (controller.config.basis == SurplusBasis::Essential).then_some(essential_w).flatten(),
+            "error": result.as_ref().err(),
+        })).await;
+
+        let mut surplus = state.surplus.write().await;
+        let status = &mut surplus.devices[index];
+        status.last_decision = Some(reason.clone());
+        match result {
+            Ok(()) => {
+                println!("Switched {} {} ({})", device.name, if on { "on" } else { "off" }, reason);
+                controller.switched(index, on, now);
+                status.on = on;
+                status.since = Some(state.clock.unix_now());
+                status.switches += 1;
+                status.last_error = None;
+            }
+            Err(e) => {
+                eprintln!("Failed to switch {} {}: {}", device.name, if on { "on" } else { "off" }, e);
+                status.last_error = Some(e);
+            }
+        }
+    }
+}
+
+async fn get_surplus_stats(
+    State(state): State<Arc<AppState>>,
+) -> Json<SurplusOutput> {
+    Json(state.surplus.read().await.clone())
+}
+
+/// Pushes the published measurements of one poll to Zabbix and counts the outcome.
+async fn push_to_zabbix(state: &AppState, config: &ZabbixConfig, raw: &RawOutput) {
+    let clock = state.clock.unix_now();
+    let items: Vec<zabbix::Item> = raw.measurements.iter()
+        .map(|(name, measurement)| zabbix::Item {
+            host: config.host.clone(),
+            key: zabbix::item_key(&config.key_prefix, name),
+            value: measurement.value.to_string(),
+            clock: measurement.observed_at.unwrap_or(clock),
+        })
+        .collect();
+    let result = zabbix::send(&config.server, &items, Duration::from_secs(10)).await;
+
+    let mut stats = state.zabbix.write().await;
+    stats.sends += 1;
+    stats.last_send = Some(clock);
+    let mut warnings = state.zabbix_warnings.lock().unwrap();
+    match result {
+        Ok(acceptance) => {
+            warnings.clear("push", format_args!("Pushing to Zabbix server {} again", config.server));
+            warnings.track(
+                "rejected",
+                acceptance.failed > 0,
+                format_args!("Zabbix rejected {} of {} items", acceptance.failed, acceptance.total),
+                "Zabbix accepts every item again",
+            );
+            stats.items_processed += acceptance.processed;
+            stats.items_failed += acceptance.failed;
+            stats.last_error = None;
+        }
+        Err(e) => {
+            warnings.warn("push", format_args!("Failed to push to Zabbix server {}: {}", config.server, e));
+            stats.failed_sends += 1;
+            stats.last_error = Some(e.to_string());
+        }
+    }
+}
+
+async fn get_zabbix_stats(
+    State(state): State<Arc<AppState>>,
+) -> Json<ZabbixStatsOutput> {
+    Json(state.zabbix.read().await.clone())
+}
+
+/// Shortest time between two logged Redis failures while it stays unreachable.
+const REDIS_LOG_INTERVAL: Duration = Duration::from_secs(600);
+/// Longest wait between reconnection attempts.
+const REDIS_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Writes every poll sent over `polls` to Redis. Reconnects with a growing delay after a
+/// failure; polls arriving in the meantime are skipped rather than queued.
+async fn run_redis_sink(state: Arc<AppState>, config: RedisConfig, mut polls: tokio::sync::watch::Receiver<Option<RawOutput>>) {
+    let timeout = Duration::from_secs(5);
+    let mut connection: Option<redis::Connection> = None;
+    let mut backoff = Duration::from_secs(1);
+    let mut retry_at = state.clock.instant();
+    let mut warnings = Warnings::repeating(REDIS_LOG_INTERVAL);
+    while polls.changed().await.is_ok() {
+        let Some(raw) = polls.borrow_and_update().clone() else { continue };
+        if connection.is_none() {
+            if state.clock.instant() < retry_at {
+                continue;
+            }
+            match redis::Connection::connect(&config.target, timeout).await {
+                Ok(connected) => connection = Some(connected),
+                Err(e) => {
+                    let mut stats = state.redis.write().await;
+                    stats.connection_failures += 1;
+                    stats.last_error = Some(e.to_string());
+                }
+            }
+        }
+        let result = match (&mut connection, redis::snapshot_commands(&config.key, &config.channel, config.ttl, &raw, raw.observed_at().unwrap_or_else(unix_now))) {
+            (Some(connected), Ok(commands)) => connected.pipeline(&commands).await.map_err(|e| e.to_string()),
+            (Some(_), Err(e)) => Err(e.to_string()),
+            (None, _) => Err(state.redis.read().await.last_error.clone().unwrap_or_default()),
+        };
+
+        let mut stats = state.redis.write().await;
+        match result {
+            Ok(()) => {
+                warnings.clear("redis", "Writing to Redis again");
+                stats.connected = true;
+                stats.writes += 1;
+                stats.last_write = Some(state.clock.unix_now());
+                stats.last_error = None;
+                backoff = Duration::from_secs(1);
+            }
+            Err(e) => {
+                if connection.take().is_some() {
+                    stats.failed_writes += 1;
+                }
+                stats.connected = false;
+                stats.last_error = Some(e.clone());
+                retry_at = state.clock.instant() + backoff;
+                backoff = (backoff * 2).min(REDIS_MAX_BACKOFF);
+                warnings.warn("redis", format_args!("Failed to write to Redis: {}", e));
+            }
+        }
+    }
+}
+
+async fn get_redis_stats(
+    State(state): State<Arc<AppState>>,
+) -> Json<RedisStatsOutput> {
+    Json(state.redis.read().await.clone())
+}
+
+/// Where events wait until the broker has acknowledged them.
+const EVENTS_OUTBOX_PATH: &str = "/srv/solax-mon/data/events-outbox.json";
+/// Events queued for the publisher before emit starts dropping them.
+const EVENTS_QUEUE: usize = 64;
+
+/// Moves queued events into the outbox and publishes it oldest first. An event leaves the
+/// outbox only once the broker has acknowledged it, so a restart of either end resends
+/// rather than loses it.
+async fn run_event_publisher(
+    state: Arc<AppState>,
+    config: EventsConfig,
+    serial: String,
+    mut events: tokio::sync::mpsc::Receiver<(EventKind, serde_json::Value)>,
+) {
+    let path = Path::new(EVENTS_OUTBOX_PATH);
+    let mut outbox = Outbox::load(path);
+    let mut broker: Option<Broker> = None;
+    let mut backoff = Duration::from_secs(1);
+    let mut retry_at = state.clock.instant();
+    let queue = |outbox: &mut Outbox, (kind, data): (EventKind, serde_json::Value)| {
+        outbox.push(kind, &serial, state.clock.unix_now(), data, config.outbox_max) as u64
+    };
+    loop {
+        let mut dropped = 0;
+        tokio::select! {
+            received = events.recv() => match received {
+                Some(event) => dropped += queue(&mut outbox, event),
+                None => return,
+            },
+            _ = tokio::time::sleep_until(retry_at.into()), if !outbox.is_empty() && broker.is_none() => {}
+        }
+        while let Ok(event) = events.try_recv() {
+            dropped += queue(&mut outbox, event);
+        }
+        if let Err(e) = outbox.save(path) {
+            eprintln!("Failed to save the event outbox {}: {}", path.display(), e);
+        }
+
+        let mut error = None;
+        if broker.is_none() && state.clock.instant() >= retry_at {
+            match Broker::connect(&config.target, &config.subject, Duration::from_secs(10)).await {
+                Ok(connected) => {
+                    println!("Connected to the event broker, {} event(s) pending", outbox.len());
+                    broker = Some(connected);
+                    backoff = Duration::from_secs(1);
+                }
+                Err(e) => error = Some(e.to_string()),
+            }
+        }
+        let mut published = 0;
+        if let Some(connected) = &mut broker {
+            while let Some(event) = outbox.front() {
+                match connected.publish(&config.subject, event, config.jetstream).await {
+                    Ok(()) => {
+                        outbox.acknowledge();
+                        published += 1;
+                    }
+                    Err(e) => {
+                        error = Some(e.to_string());
+                        broker = None;
+                        break;
+                    }
+                }
+            }
+            if published > 0 {
+                if let Err(e) = outbox.save(path) {
+                    eprintln!("Failed to save the event outbox {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        let mut stats = state.event_stats.write().await;
+        stats.dropped += dropped;
+        stats.published += published;
+        stats.pending = outbox.len();
+        stats.last_seq = outbox.last_seq();
+        if published > 0 {
+            stats.last_publish = Some(state.clock.unix_now());
+        }
+        match error {
+            Some(e) => {
+                // Logged when the broker goes away, not on every retry
+                if stats.connected || stats.failed_publishes == 0 {
+                    eprintln!("Failed to publish events, {} pending: {}", outbox.len(), e);
+                }
+                stats.connected = false;
+                stats.failed_publishes += 1;
+                stats.last_error = Some(e);
+                retry_at = state.clock.instant() + backoff;
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+            }
+            None => {
+                stats.connected = broker.is_some();
+                if broker.is_some() {
+                    stats.last_error = None;
+                }
+            }
+        }
+    }
+}
+
+async fn get_event_stats(
+    State(state): State<Arc<AppState>>,
+) -> Json<EventStatsOutput> {
+    Json(state.event_stats.read().await.clone())
+}
+
+/// Polls queued for the PostgreSQL sink before the poll loop starts dropping them.
+const POSTGRES_QUEUE: usize = 64;
+
+fn postgres_tls(config: &PostgresConfig) -> Result<postgres_native_tls::MakeTlsConnector, Box<dyn std::error::Error + Send + Sync>> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(path) = &config.ca_file {
+        builder.add_root_certificate(native_tls::Certificate::from_pem(&std::fs::read(path)?)?);
+    }
+    Ok(postgres_native_tls::MakeTlsConnector::new(builder.build()?))
+}
+
+/// Connects and creates the table if it is missing.
+async fn connect_postgres(
+    config: &PostgresConfig,
+    tls: postgres_native_tls::MakeTlsConnector,
+) -> Result<tokio_postgres::Client, Box<dyn std::error::Error + Send + Sync>> {
+    let (client, connection) = tokio::time::timeout(Duration::from_secs(10), tokio_postgres::connect(&config.url, tls)).await
+        .map_err(|_| "PostgreSQL didn't answer within 10s")??;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("PostgreSQL connection closed: {}", e);
+        }
+    });
+    postgres::prepare_table(&client, &config.table, config.layout, config.hypertable).await?;
+    Ok(client)
+}
+
+/// Inserts the polls sent over `polls`. Polls are buffered up to POSTGRES_BUFFER_ROWS while
+/// the database is unreachable and inserted together once it is back.
+async fn run_postgres_sink(state: Arc<AppState>, config: PostgresConfig, mut polls: tokio::sync::mpsc::Receiver<postgres::Poll>) {
+    let tls = match postgres_tls(&config) {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("PostgreSQL sink disabled, TLS setup failed: {}", e);
+            return;
+        }
+    };
+    let mut buffer = postgres::Buffer::new(config.layout, config.buffer_rows);
+    let mut client: Option<tokio_postgres::Client> = None;
+    let mut columns = Vec::new();
+    let mut backoff = Duration::from_secs(1);
+    let mut retry_at = state.clock.instant();
+    let mut warnings = Warnings::new();
+    while let Some(poll) = polls.recv().await {
+        let dropped = buffer.push(poll);
+        if dropped > 0 {
+            state.postgres.write().await.rows_dropped += dropped as u64;
+        }
+        if client.as_ref().is_some_and(|client| client.is_closed()) {
+            client = None;
+        }
+        if client.is_none() && state.clock.instant() >= retry_at {
+            match connect_postgres(&config, tls.clone()).await {
+                Ok(connected) => {
+                    warnings.clear("connect", "PostgreSQL is reachable again");
+                    println!("Connected to PostgreSQL, inserting into {}", config.table);
+                    client = Some(connected);
+                    columns.clear();
+                    backoff = Duration::from_secs(1);
+                }
+                Err(e) => {
+                    warnings.warn("connect", format_args!("Failed to connect to PostgreSQL: {}", e));
+                    let mut stats = state.postgres.write().await;
+                    stats.connected = false;
+                    stats.connection_failures += 1;
+                    stats.last_error = Some(e.to_string());
+                    retry_at = state.clock.instant() + backoff;
+                    backoff = (backoff * 2).min(Duration::from_secs(300));
+                }
+            }
+        }
+
+        if let Some(connected) = &mut client {
+            let started = Instant::now();
+            let result = postgres::insert(connected, &config.table, config.layout, buffer.polls(), &mut columns).await;
+            let mut stats = state.postgres.write().await;
+            stats.last_insert_latency_ms = Some(started.elapsed().as_millis() as u64);
+            match result {
+                Ok(()) => {
+                    stats.connected = true;
+                    stats.inserts += 1;
+                    stats.rows_inserted += buffer.rows() as u64;
+                    stats.last_insert = Some(state.clock.unix_now());
+                    stats.last_error = None;
+                    buffer.clear();
+                    warnings.clear("insert", "Inserting into PostgreSQL again");
+                }
+                Err(e) => {
+                    // The rows stay buffered; a broken connection is replaced on the next poll
+                    warnings.warn("insert", format_args!("Failed to insert into PostgreSQL: {}", e));
+                    stats.failed_inserts += 1;
+                    stats.last_error = Some(e.to_string());
+                    if connected.is_closed() {
+                        stats.connected = false;
+                        client = None;
+                    }
+                }
+            }
+        }
+        state.postgres.write().await.rows_buffered = buffer.rows();
+    }
+}
+
+async fn get_postgres_stats(
+    State(state): State<Arc<AppState>>,
+) -> Json<PostgresStatsOutput> {
+    Json(state.postgres.read().await.clone())
+}
+
+/// Polls every peer's /status/raw. A peer's failures only mark that peer stale; they never
+/// touch the local poller's health or backoff.
+async fn run_federation(state: Arc<AppState>, interval: Duration, http: outbound::Clients) {
+    let peers: Vec<federation::Peer> = state.federation.read().await.iter().map(|(peer, _)| peer.clone()).collect();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut warnings = Warnings::new();
+    loop {
+        ticker.tick().await;
+        // Peers are left alone while saving power; their last snapshots go stale meanwhile
+        if state.power_save.load(std::sync::atomic::Ordering::SeqCst) {
+            continue;
+        }
+        let timeout = interval.min(Duration::from_secs(10));
+        // Fetched side by side, so one peer that doesn't answer doesn't hold up the others
+        let fetches: Vec<_> = peers.iter()
+            .map(|peer| {
+                let (peer, http) = (peer.clone(), http.clone());
+                tokio::spawn(async move { peer.fetch(&http, timeout).await.map_err(|e| e.to_string()) })
+            })
+            .collect();
+        let mut results = Vec::with_capacity(fetches.len());
+        for fetch in fetches {
+            results.push(fetch.await.unwrap_or_else(|e| Err(e.to_string())));
+        }
+        let now = state.clock.unix_now();
+        let mut federation = state.federation.write().await;
+        for ((peer, peer_state), result) in federation.iter_mut().zip(results) {
+            match result {
+                Ok(raw) => {
+                    warnings.clear(&peer.name, format_args!("Federation peer {} is reachable again", peer.name));
+                    peer_state.succeeded(raw, now);
+                }
+                Err(e) => {
+                    warnings.warn(&peer.name, format_args!("Federation peer {} is unreachable: {}", peer.name, e));
+                    peer_state.failed(e);
+                }
+            }
+        }
+    }
+}
+
+async fn get_federation_status(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<FederationOutput>, StatusCode> {
+    let federation = state.federation.read().await;
+    if federation.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let now = state.clock.unix_now();
+    let sites: Vec<_> = federation.iter()
+        .map(|(peer, peer_state)| peer_state.output(peer, now, state.federation_stale_after))
+        .collect();
+    Ok(Json(FederationOutput { totals: federation::combined_totals(&sites), sites }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Above,
+    AtLeast,
+    Below,
+    AtMost,
+    /// Outside the band from the rule's threshold up to this value.
+    Outside(f64),
+}
+
+impl Comparison {
+    fn symbol(self) -> &'static str {
+        match self {
+            Comparison::Above => ">",
+            Comparison::AtLeast => ">=",
+            Comparison::Below => "<",
+            Comparison::AtMost => "<=",
+            Comparison::Outside(_) => "outside",
+        }
+    }
+
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Above => value > threshold,
+            Comparison::AtLeast => value >= threshold,
+            Comparison::Below => value < threshold,
+            Comparison::AtMost => value <= threshold,
+            Comparison::Outside(high) => value < threshold || value > high,
+        }
+    }
+}
+
+/// A "notify me when" rule on one measurement (THRESHOLD_ALERT).
+#[derive(Debug, Clone, PartialEq)]
+struct ThresholdRule {
+    name: String,
+    metric: String,
+    comparison: Comparison,
+    threshold: f64,
+    /// How long the condition has to hold before the rule fires.
+    sustain: Duration,
+    /// Shortest time between two firings of the rule.
+    cooldown: Duration,
+}
+
+impl ThresholdRule {
+    /// Parses `name: Metric Name > 5000, for=300, cooldown=1800`, or a band like
+    /// `name: Grid 1 Frequency outside 49.8-50.2, for=30`.
+    fn parse(value: &str) -> Result<Self, String> {
+        let (name, rest) = value.split_once(':')
+            .ok_or_else(|| format!("Threshold alert {:?} must start with a name and a colon", value))?;
+        let name = name.trim();
+        let mut parts = rest.split(',');
+        let condition = parts.next().unwrap_or_default().trim();
+        let number = |text: &str| text.trim().parse::<f64>().map_err(|_| format!("Invalid value {:?} in threshold alert {}", text.trim(), name));
+        let (metric, comparison, threshold) = match condition.split_once(" outside ") {
+            Some((metric, band)) => {
+                // The separating dash comes after the first character, which may be a minus sign
+                let band = band.trim();
+                let split = band.get(1..).and_then(|rest| rest.find('-')).map(|at| at + 1)
+                    .ok_or_else(|| format!("Threshold alert {} needs a band like `outside 49.8-50.2`", name))?;
+                let (low, high) = (number(&band[..split])?, number(&band[split + 1..])?);
+                if low > high {
+                    return Err(format!("Band of threshold alert {} runs from {} down to {}", name, low, high));
+                }
+                (metric.trim(), Comparison::Outside(high), low)
+            }
+            None => {
+                let (metric, comparison, threshold) = [(">=", Comparison::AtLeast), ("<=", Comparison::AtMost), (">", Comparison::Above), ("<", Comparison::Below)]
+                    .into_iter()
+                    .find_map(|(symbol, comparison)| {
+                        let (metric, threshold) = condition.split_once(symbol)?;
+                        Some((metric.trim(), comparison, threshold))
+                    })
+                    .ok_or_else(|| format!("Threshold alert {} needs a condition like `Grid Power > 4000`", name))?;
+                (metric, comparison, number(threshold)?)
+            }
+        };
+        let mut rule = ThresholdRule {
+            name: name.to_string(),
+            metric: metric.to_string(),
+            comparison,
+            threshold,
+            sustain: Duration::ZERO,
+            cooldown: Duration::ZERO,
+        };
+        for option in parts.map(str::trim) {
+            let (key, setting) = option.split_once('=')
+                .ok_or_else(|| format!("Invalid option {:?} for threshold alert {}", option, name))?;
+            let secs = setting.parse().map(Duration::from_secs)
+                .map_err(|_| format!("Invalid {} {:?} for threshold alert {}", key, setting, name))?;
+            match key {
+                "for" => rule.sustain = secs,
+                "cooldown" => rule.cooldown = secs,
+                _ => return Err(format!("Unknown option {:?} for threshold alert {}", key, name)),
+            }
+        }
+        if name.is_empty() || rule.metric.is_empty() {
+            return Err(format!("Threshold alert {:?} needs a name and a measurement", value));
+        }
+        Ok(rule)
+    }
+
+    fn condition(&self) -> String {
+        match self.comparison {
+            Comparison::Outside(high) => format!("outside {}-{}", self.threshold, high),
+            comparison => format!("{} {}", comparison.symbol(), self.threshold),
+        }
+    }
+}
+
+/// Threshold alerts with where their events go.
+#[derive(Debug, Clone, Default)]
+struct ThresholdConfig {
+    rules: Vec<ThresholdRule>,
+    webhook_url: Option<String>,
+}
+
+/// Where one rule stands between polls.
+#[derive(Debug, Default)]
+struct ThresholdState {
+    active: bool,
+    /// Since when the condition has held.
+    since: Option<u64>,
+    last_fired: Option<u64>,
+}
+
+impl ThresholdState {
+    /// Returns true when the rule fires and false when it clears. A missing value changes nothing.
+    fn observe(&mut self, rule: &ThresholdRule, value: Option<f64>, now: u64) -> Option<bool> {
+        let holds = rule.comparison.holds(value?, rule.threshold);
+        if !holds {
+            self.since = None;
+            return std::mem::take(&mut self.active).then_some(false);
+        }
+        let since = *self.since.get_or_insert(now);
+        let cooled_down = self.last_fired.is_none_or(|fired| now.saturating_sub(fired) >= rule.cooldown.as_secs());
+        if self.active || now.saturating_sub(since) < rule.sustain.as_secs() || !cooled_down {
+            return None;
+        }
+        self.active = true;
+        self.last_fired = Some(now);
+        Some(true)
+    }
+
+    fn metrics(&self, rule: &ThresholdRule, now: u64) -> RuleMetrics {
+        let debounce = match self.since {
+            _ if self.active => 1.0,
+            Some(_) if rule.sustain.is_zero() => 1.0,
+            Some(since) => (now.saturating_sub(since) as f64 / rule.sustain.as_secs_f64()).min(1.0),
+            None => 0.0,
+        };
+        RuleMetrics {
+            name: rule.name.clone(),
+            holds: self.since.is_some(),
+            debounce,
+            tier: if self.active { 2 } else { u8::from(self.since.is_some()) },
+        }
+    }
+}
+
+/// Where a rule stood at the last poll, for the `solax_monitor_condition` metrics.
+#[derive(Debug, Clone, PartialEq)]
+struct RuleMetrics {
+    name: String,
+    holds: bool,
+    /// How far into its `for=` period the condition is, from 0 to 1.
+    debounce: f64,
+    /// 0 while the condition doesn't hold, 1 while it holds but the rule hasn't fired (the
+    /// `for=` period or the cooldown), 2 while it has fired.
+    tier: u8,
+}
+
+/// Identifies the configured rules, so a renamed or changed rule shows on dashboards.
+fn rules_hash(rules: &[ThresholdRule]) -> String {
+    let text: Vec<String> = rules.iter()
+        .map(|rule| format!("{}: {} {}, for={}, cooldown={}", rule.name, rule.metric, rule.condition(), rule.sustain.as_secs(), rule.cooldown.as_secs()))
+        .collect();
+    openssl::sha::sha256(text.join("\n").as_bytes()).iter().take(8).map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn render_rule_metrics(rules: &[RuleMetrics], hash: &str) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP solax_monitor_rules_hash Hash of the configured alert rules, changes when one is renamed or edited\n");
+    out.push_str("# TYPE solax_monitor_rules_hash gauge\n");
+    out.push_str(&format!("solax_monitor_rules_hash{{hash=\"{}\"}} 1\n", hash));
+    let gauges = [
+        ("solax_monitor_condition", "Whether the rule's condition held at the last poll",
+            rules.iter().map(|rule| f64::from(u8::from(rule.holds))).collect::<Vec<_>>()),
+        ("solax_monitor_condition_debounce_ratio", "How far into its for= period the rule's condition is, 0 to 1",
+            rules.iter().map(|rule| rule.debounce).collect()),
+        ("solax_monitor_condition_tier", "0 clear, 1 pending (held, not fired yet), 2 fired",
+            rules.iter().map(|rule| f64::from(rule.tier)).collect()),
+    ];
+    for (metric, help, values) in gauges {
+        out.push_str(&format!("# HELP {} {}\n", metric, help));
+        out.push_str(&format!("# TYPE {} gauge\n", metric));
+        for (rule, value) in rules.iter().zip(values) {
+            out.push_str(&format!("{}{{rule=\"{}\"}} {}\n", metric, rule.name, value));
+        }
+    }
+    out
+}
+
+/// Posts a threshold event to the notifiers, the webhook and MQTT, whichever are configured.
+async fn send_threshold_event(state: &AppState, config: &ThresholdConfig, discord: &ControlConfig, event: ThresholdEvent) {
+    let fired = event.state == "fired";
+    println!("Threshold alert {} {}: {} is {}", event.rule, event.state, event.metric, event.value);
+    let alert = if fired {
+        Alert::new(Severity::Warning, format!("📈 {}: {} {}", event.rule, event.metric, event.condition))
+    } else {
+        Alert::new(Severity::Normal, format!("✅ {} cleared", event.rule))
+    };
+    let alert = alert.id(format!("threshold-{}", event.rule)).field("Value", event.value.to_string());
+    discord.send_alert(&alert, &format!("threshold alert {}", event.rule)).await;
+    if let Some(webhook_url) = &config.webhook_url {
+        let result = discord.http.for_url(webhook_url).post(webhook_url)
+            .json(&event)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            eprintln!("Failed to post threshold alert {} to {}: {}", event.rule, webhook_url, discord.http.describe_error(webhook_url, &e));
+        }
+    }
+    if let Some((client, topic)) = state.mqtt.get() {
+        let payload = serde_json::to_vec(&event).unwrap_or_default();
+        if let Err(e) = client.publish(topic, rumqttc::QoS::AtLeastOnce, false, payload).await {
+            eprintln!("Failed to publish threshold alert {} over MQTT: {}", event.rule, e);
+        }
+    }
+}
+
+/// Runs one MQTT command against the service.
+async fn handle_mqtt_command(state: &Arc<AppState>, payload: &[u8], secret: Option<&str>) -> CommandResult {
+    use std::sync::atomic::Ordering;
+
+    let request = match CommandRequest::authenticate(payload, secret) {
+        Ok(request) => request,
+        Err(result) => return result,
+    };
+    let name = request.command.name();
+    let outcome = match request.command {
+        Command::PollNow if state.polling_paused.load(Ordering::SeqCst) => Err("Monitor is paused".to_string()),
+        Command::PollNow => {
+            state.poll_now.notify_one();
+            Ok(())
+        }
+        Command::PauseMonitor => {
+            println!("Polling paused over MQTT");
+            state.polling_paused.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+        Command::ResumeMonitor => {
+            println!("Polling resumed over MQTT");
+            state.polling_paused.store(false, Ordering::SeqCst);
+            state.poll_now.notify_one();
+            Ok(())
+        }
+        Command::SetExportLimit { watts } => match &state.control {
+            Some(control) => apply_export_limit(state, control, watts, "mqtt").await.map(|_| ()).map_err(|(_, e)| e),
+            None => Err("Control commands are disabled".to_string()),
+        },
+        Command::SetBatteryMode { mode, revert_after_secs, override_temperature } => match &state.control {
+            Some(control) => start_battery_mode(state, control, mode, revert_after_secs, override_temperature, "mqtt").await
+                .map(|_| ())
+                .map_err(|(_, e)| e),
+            None => Err("Control commands are disabled".to_string()),
+        },
+    };
+    match outcome {
+        Ok(()) => CommandResult::succeeded(request.id, name),
+        Err(e) => CommandResult::failed(request.id, Some(name), e),
+    }
+}
+
+/// Subscribes to the command topic and answers every command on the result topic.
+async fn run_mqtt_commands(state: Arc<AppState>, config: MqttConfig, serial: String) {
+    use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+
+    let mut options = MqttOptions::new(format!("solax-mon-{}", serial), &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let command_topic = mqtt::command_topic(&config.topic_prefix, &serial);
+    let result_topic = mqtt::result_topic(&config.topic_prefix, &serial);
+    let _ = state.mqtt.set((client.clone(), mqtt::event_topic(&config.topic_prefix, &serial)));
+    let secret: Option<Arc<str>> = config.secret.as_deref().map(Arc::from);
+    let mut warnings = Warnings::new();
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                warnings.clear("connection", "MQTT connection is back");
+                // The session is clean, so the subscription is made again on every connect
+                println!("Connected to MQTT broker {}:{}, listening on {}", config.host, config.port, command_topic);
+                if let Err(e) = client.try_subscribe(&command_topic, QoS::AtLeastOnce) {
+                    eprintln!("Failed to subscribe to {}: {}", command_topic, e);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == command_topic => {
+                // Handled apart from the event loop, so a slow inverter write doesn't stall keepalives
+                let (state, client, secret, result_topic) = (state.clone(), client.clone(), secret.clone(), result_topic.clone());
+                tokio::spawn(async move {
+                    let result = handle_mqtt_command(&state, &publish.payload, secret.as_deref()).await;
+                    state.audit(serde_json::json!({
+                        "time": state.clock.unix_now(),
+                        "remote": "mqtt",
+                        "setting": "mqtt_command",
+                        "id": result.id,
+                        "command": result.command,
+                        "error": result.error,
+                    })).await;
+                    if let Some(e) = &result.error {
+                        eprintln!("MQTT command {:?} failed: {}", result.command, e);
+                    }
+                    let payload = serde_json::to_vec(&result).unwrap_or_default();
+                    if let Err(e) = client.publish(result_topic, QoS::AtLeastOnce, false, payload).await {
+                        eprintln!("Failed to publish MQTT command result: {}", e);
+                    }
+                });
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warnings.warn("connection", format_args!("MQTT connection error: {}", e));
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// 503 unless the last EV charger poll succeeded.
+async fn get_evc_status(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<EvcStatusOutput>, StatusCode> {
+    state.evc.read().await.clone().map(Json).ok_or(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// 503 until the first successful poll has read the inverter's details.
+/// The changes of the last poll that brought any; 204 until one has.
+async fn get_changes(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SnapshotDiff>, StatusCode> {
+    state.changes.read().await.clone().map(Json).ok_or(StatusCode::NO_CONTENT)
+}
+
+async fn get_info(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<InfoOutput>, StatusCode> {
+    let mut info = state.info.read().await.clone().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    info.signing_public_key = state.signing.as_ref().map(|key| key.public_key().to_string());
+    Ok(Json(info))
+}
+
+/// What the inverter profile provides, for tooling that shouldn't hard-code measurement names.
+async fn get_measurements(State(state): State<Arc<AppState>>) -> Json<CatalogOutput> {
+    Json(state.catalog.clone())
+}
+
+/// The latest poll register by register, for finding where a value moved after a firmware
+/// update. Behind DEBUG_TOKEN, as it shows the serial number and the whole Data array.
+async fn get_debug_decode(
+    State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+) -> Result<Json<DecodeOutput>, (StatusCode, String)> {
+    match &state.debug_token {
+        None => return Err((StatusCode::NOT_FOUND, "Debug endpoints are disabled".to_string())),
+        Some(token) if !bearer_matches(&headers, token) => {
+            return Err((StatusCode::UNAUTHORIZED, "Missing or wrong bearer token".to_string()));
+        }
+        Some(_) => {}
+    }
+    state.decode.read().await.clone()
+        .map(Json)
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "No successful poll yet".to_string()))
+}
+
+async fn post_external(
+    State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    Json(reading): Json<external::Reading>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match &state.ingest_token {
+        None => return Err((StatusCode::NOT_FOUND, "Ingestion is disabled".to_string())),
+        Some(token) if !bearer_matches(&headers, token) => {
+            return Err((StatusCode::UNAUTHORIZED, "Missing or wrong bearer token".to_string()));
+        }
+        Some(_) => {}
+    }
+    state.external.write().await.record(reading, state.clock.unix_now())
+        .map(|()| StatusCode::NO_CONTENT)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))
+}
+
+async fn get_battery_stats(
+    State(state): State<Arc<AppState>>,
+) -> Json<BatteryStatsOutput> {
+    let mut stats = state.battery.read().await.output(state.capacity_kwh().await);
+    stats.capacity = state.capacity.read().await.output(state.battery_capacity_kwh);
+    Json(stats)
+}
+
+async fn get_availability(
+    State(state): State<Arc<AppState>>,
+) -> Json<AvailabilityOutput> {
+    Json(state.availability.read().await.output())
+}
+
+async fn get_health(
+    State(state): State<Arc<AppState>>,
+) -> (StatusCode, Json<HealthOutput>) {
+    let mut health = state.health.read().await.clone();
+    health.healthy = health.last_success
+        .is_some_and(|last| state.clock.unix_now().saturating_sub(last) <= state.stale_window().as_secs())
+        && health.textfile_error.is_none();
+    let code = if health.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (code, Json(health))
+}
+
+/// The endpoints of one API version, served under `/v1` and at the unversioned aliases.
+fn api_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/status", get(get_status))
+        .route("/status/raw", get(get_raw_status))
+        .route("/status/changes", get(get_changes))
+        .route("/status/battery/modules", get(get_battery_modules))
+        .route("/metrics", get(get_metrics))
+        .route("/health", get(get_health))
+        .route("/info", get(get_info))
+        .route("/measurements", get(get_measurements))
+        .route("/evc/status", get(get_evc_status))
+        .route("/stats/availability", get(get_availability))
+        .route("/stats/battery", get(get_battery_stats))
+        .route("/stats/surplus", get(get_surplus_stats))
+        .route("/stats/curtailment", get(get_curtailment))
+        .route("/reports/export-compliance", get(get_export_compliance))
+        .route("/reports/monthly", get(get_monthly_report))
+        .route("/reports/yearly", get(get_yearly_report))
+        .route("/stats/zabbix", get(get_zabbix_stats))
+        .route("/stats/redis", get(get_redis_stats))
+        .route("/stats/postgres", get(get_postgres_stats))
+        .route("/stats/events", get(get_event_stats))
+        .route("/stats/http", get(get_http_stats))
+        .route("/federation/status", get(get_federation_status))
+        .route("/settings", get(get_settings))
+        .route("/debug/decode", get(get_debug_decode))
+        .route("/ingest/external", axum::routing::post(post_external))
+        .route("/control/export-limit", axum::routing::post(set_export_limit))
+        .route("/control/battery-mode", axum::routing::post(set_battery_mode))
+}
+
+/// Request rate and connection limits of the HTTP server. A limit of zero disables it.
+#[derive(Debug, Clone)]
+struct HttpLimits {
+    /// Requests per second across all clients.
+    rate: f64,
+    /// Requests per second from one remote address.
+    rate_per_ip: f64,
+    /// Requests per second to /health, which is exempt from the other limits.
+    health_rate: f64,
+    max_connections: usize,
+}
+
+impl Default for HttpLimits {
+    fn default() -> Self {
+        Self { rate: 100.0, rate_per_ip: 20.0, health_rate: 50.0, max_connections: 256 }
+    }
+}
+
+/// Allows `rate` requests per second with bursts of up to one second's worth.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self { rate, tokens: rate.max(1.0), updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.updated = now;
+    }
+
+    /// Takes one token, or returns how long until one is available.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate.max(1.0)
+    }
+}
+
+/// Per-IP buckets are pruned of idle clients once there are this many.
+const RATE_LIMIT_MAX_CLIENTS: usize = 1024;
+
+struct RateLimiter {
+    limits: HttpLimits,
+    global: std::sync::Mutex<TokenBucket>,
+    health: std::sync::Mutex<TokenBucket>,
+    per_ip: std::sync::Mutex<HashMap<std::net::IpAddr, TokenBucket>>,
+}
+
+impl RateLimiter {
+    fn new(limits: HttpLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            global: std::sync::Mutex::new(TokenBucket::new(limits.rate, now)),
+            health: std::sync::Mutex::new(TokenBucket::new(limits.health_rate, now)),
+            per_ip: std::sync::Mutex::new(HashMap::new()),
+            limits,
+        }
+    }
+
+    /// Admits a request, or returns how long the client should wait before retrying.
+    fn check(&self, ip: Option<std::net::IpAddr>, health: bool, now: Instant) -> Result<(), Duration> {
+        if health {
+            return self.health.lock().unwrap().take(now);
+        }
+        if let Some(ip) = ip.filter(|_| self.limits.rate_per_ip > 0.0) {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            if per_ip.len() >= RATE_LIMIT_MAX_CLIENTS {
+                per_ip.retain(|_, bucket| !bucket.is_full(now));
+            }
+            per_ip.entry(ip)
+                .or_insert_with(|| TokenBucket::new(self.limits.rate_per_ip, now))
+                .take(now)?;
+        }
+        self.global.lock().unwrap().take(now)
+    }
+}
+
+/// The remote end of an HTTP connection, and whether it got one of the connection slots.
+#[derive(Debug, Clone)]
+struct Peer {
+    addr: Option<SocketAddr>,
+    admitted: bool,
+}
+
+/// An accepted connection that holds one of the HTTP server's connection slots until it is
+/// dropped. Connections beyond the cap are still accepted so they can be answered with a 429.
+struct LimitedConnection<S> {
+    stream: S,
+    peer: Option<SocketAddr>,
+    admitted: bool,
+    _permit: Option<tokio::sync::OwnedSemaphorePermit>,
+}
+
+impl<S> LimitedConnection<S> {
+    /// `slots` is None when the number of connections is unlimited.
+    fn new(stream: S, peer: Option<SocketAddr>, slots: Option<&Arc<tokio::sync::Semaphore>>) -> Self {
+        let permit = slots.and_then(|slots| slots.clone().try_acquire_owned().ok());
+        let admitted = slots.is_none() || permit.is_some();
+        Self { stream, peer, admitted, _permit: permit }
+    }
+}
+
+impl<S> axum::extract::connect_info::Connected<&LimitedConnection<S>> for Peer {
+    fn connect_info(connection: &LimitedConnection<S>) -> Self {
+        Peer { addr: connection.peer, admitted: connection.admitted }
+    }
+}
+
+impl<S: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for LimitedConnection<S> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for LimitedConnection<S> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+fn too_many_requests(retry_after: Duration, close: bool) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(axum::http::header::RETRY_AFTER, secs.to_string())],
+        "Too many requests\n",
+    ).into_response();
+    if close {
+        response.headers_mut().insert(axum::http::header::CONNECTION, axum::http::HeaderValue::from_static("close"));
+    }
+    response
+}
+
+/// Rejects requests over the rate limits and requests on connections beyond the
+/// connection cap. /health has its own limit so probes keep working under load.
+async fn rate_limit<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let peer = request.extensions().get::<axum::extract::ConnectInfo<Peer>>().map(|info| info.0.clone());
+    let health = matches!(request.uri().path(), "/health" | "/v1/health");
+    if !health && peer.as_ref().is_some_and(|peer| !peer.admitted) {
+        return too_many_requests(Duration::from_secs(1), true);
+    }
+    let ip = peer.and_then(|peer| peer.addr).map(|addr| addr.ip());
+    match limiter.check(ip, health, Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => too_many_requests(retry_after, false),
+    }
+}
+
+/// Query parameters whose values are replaced in logged request paths.
+const SENSITIVE_PARAMS: [&str; 6] = ["token", "access_token", "key", "password", "pwd", "secret"];
+
+/// The request path and query with the values of sensitive parameters redacted.
+fn redacted_path(uri: &axum::http::Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.path().to_string();
+    };
+    let params: Vec<String> = query.split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if SENSITIVE_PARAMS.iter().any(|s| name.eq_ignore_ascii_case(s)) => format!("{}=REDACTED", name),
+            _ => param.to_string(),
+        })
+        .collect();
+    format!("{}?{}", uri.path(), params.join("&"))
+}
+
+/// State of the request logging middleware; counting is always on, logging only with HTTP_LOG.
+#[derive(Clone)]
+struct RequestLog {
+    state: Arc<AppState>,
+    enabled: bool,
+}
+
+/// Counts every request per path and, when enabled, logs its method, path, status,
+/// remote address and latency.
+async fn log_request<B>(
+    State(log): State<RequestLog>,
+    request: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let path = redacted_path(request.uri());
+    let route = request.uri().path().to_string();
+    let remote = match request.extensions().get::<axum::extract::ConnectInfo<Peer>>() {
+        Some(info) => info.0.addr.map_or("unix".to_string(), |addr| addr.to_string()),
+        None => "-".to_string(),
+    };
+
+    let response = next.run(request).await;
+    let status = response.status();
+    // Paths are only used as keys when they matched a route, to keep the counters bounded
+    let route = if status == StatusCode::NOT_FOUND { "unmatched".to_string() } else { route };
+    {
+        let mut stats = log.state.http_stats.write().await;
+        let counts = stats.routes.entry(route).or_insert_with(RouteStats::default);
+        counts.requests += 1;
+        if status.is_client_error() || status.is_server_error() {
+            counts.errors += 1;
+        }
+    }
+    if log.enabled {
+        println!(
+            "HTTP {} {} {} from {} in {:.1}ms",
+            method, path, status.as_u16(), remote, start.elapsed().as_secs_f64() * 1000.0
+        );
+    }
+    response
+}
+
+/// The inverter client as configured, shared by the daemon and the `status` subcommand.
+fn build_inverter(config: &Config) -> X3HybridG4 {
+    let mut inverter = X3HybridG4::new(&config.inverter_urls, config.polling.min_spacing);
+    inverter.request_timeout = config.polling.request_timeout;
+    inverter.load_source = config.load_source;
+    inverter.soc_calibration = config.soc_calibration;
+    inverter.power_signs = config.power_signs;
+    inverter.protocol = config.dongle_protocol;
+    inverter.battery_modules = config.battery_modules;
+    inverter.spike_limits = config.spike_limits.clone();
+    if let Some(index) = config.bms_limits {
+        inverter.map_bms_limits(index);
+    }
+    inverter
+}
+
+/// Fetches the inverter once and prints its measurements, as a table or with `--json` as
+/// the /status/raw map; returns the process exit code.
+/// The measurements PUBLISH, THRESHOLD_ALERT, CHANGE_THRESHOLD, SPIKE_FILTER and BURST_TRIGGER name have
+/// to be ones the inverter has.
+fn check_measurements(config: &Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let known = build_inverter(config).measurement_names();
+    config.publish.validate(&known)?;
+    if let Some(rule) = config.thresholds.rules.iter().find(|rule| !known.contains(&rule.metric)) {
+        return Err(format!(
+            "THRESHOLD_ALERT {} references unknown measurement {:?}; valid names: {}",
+            rule.name, rule.metric, known.join(", ")
+        ).into());
+    }
+    if let Some(metric) = config.change_thresholds.overrides.keys().find(|metric| !known.contains(metric)) {
+        return Err(format!(
+            "CHANGE_THRESHOLD references unknown measurement {:?}; valid names: {}", metric, known.join(", ")
+        ).into());
+    }
+    if let Some(metric) = config.spike_limits.keys().find(|metric| !known.contains(metric)) {
+        return Err(format!(
+            "SPIKE_FILTER references unknown measurement {:?}; valid names: {}", metric, known.join(", ")
+        ).into());
+    }
+    if let Some(meter) = config.external_meters.iter().find(|meter| known.contains(&meter.name)) {
+        return Err(format!("EXTERNAL_METER {} is named {:?} like a measurement of the inverter", meter.source, meter.name).into());
+    }
+    let burst_triggers = config.burst.iter().flat_map(|burst| &burst.triggers);
+    if let Some((metric, _)) = burst_triggers.into_iter().find(|(metric, _)| !known.contains(metric)) {
+        return Err(format!(
+            "BURST_TRIGGER references unknown measurement {:?}; valid names: {}", metric, known.join(", ")
+        ).into());
+    }
+    Ok(())
+}
+
+/// `validate`: reads the configuration the way the service does and prints either what it
+/// would run with or everything wrong with it.
+fn validate_command() -> i32 {
+    let text = match std::fs::read_to_string(SECRETS_PATH) {
+        Ok(text) => text,
+        Err(e) => {
+            println!("Failed to read {}: {}", SECRETS_PATH, e);
+            return 1;
+        }
+    };
+    let mut problems = secrets::check(&text);
+    // Values are only read once the keys are known, the way the service starts
+    let mut config = None;
+    if problems.is_empty() {
+        match parse_secrets(&text).and_then(|config| check_measurements(&config).map(|()| config)) {
+            Ok(parsed) => config = Some(parsed),
+            Err(e) => problems.push(secrets::Problem::locate(&text, e.to_string())),
+        }
+    }
+    let Some(config) = config else {
+        println!("{} problem(s) in {}:", problems.len(), SECRETS_PATH);
+        for problem in &problems {
+            println!("  {}", problem);
+        }
+        return 1;
+    };
+
+    println!("OK: {}", SECRETS_PATH);
+    println!("Inverters: {} (serial {})", config.inverter_urls.join(", "), config.serial);
+    let listen: Vec<String> = config.listen_addrs.iter()
+        .map(|addr| match addr {
+            ListenAddr::Tcp(addr) => addr.to_string(),
+            ListenAddr::Unix(path) => path.display().to_string(),
+        })
+        .collect();
+    println!("Listening on: {}", listen.join(", "));
+    let control = &config.control;
+    let notifiers: Vec<&str> = [
+        ("discord", control.discord_webhook_url.is_some()),
+        ("slack", control.slack_webhook_url.is_some()),
+        ("matrix", control.matrix.is_some()),
+        ("gotify", control.gotify.is_some()),
+        ("pushover", control.pushover.is_some()),
+    ].into_iter().filter(|(_, configured)| *configured).map(|(name, _)| name).collect();
+    println!("Notifiers: {}", if notifiers.is_empty() { "none".to_string() } else { notifiers.join(", ") });
+    println!("Threshold alerts: {}", config.thresholds.rules.len());
+    println!("Entries:");
+    for (key, value) in parse_entries(&text) {
+        println!("  {}", secrets::masked(&key, &value));
+    }
+    0
+}
+
+async fn status_command(config: &Config, json: bool) -> i32 {
+    let mut inverter = build_inverter(config);
+    let (snapshot, source) = match inverter.fetch_data(&config.serial).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            eprintln!("Error fetching data: {}", e);
+            return 1;
+        }
+    };
+    let mut raw = snapshot.to_raw(&config.publish);
+    raw.labels = config.labels.for_snapshot(&snapshot);
+    if json {
+        match serde_json::to_string_pretty(&raw) {
+            Ok(text) => println!("{}", text),
+            Err(e) => {
+                eprintln!("Error encoding data: {}", e);
+                return 1;
+            }
+        }
+    } else {
+        println!("Source: {}\n", source);
+        print!("{}", status_table(&raw, &inverter.format_status(&snapshot)));
+    }
+    0
+}
+
+/// `export-compliance [--month YYYY-MM] [--csv]`: prints the export compliance report of a
+/// month, the current one by default; returns the process exit code.
+async fn export_compliance_command(config: &Config, args: &[String]) -> i32 {
+    let usage = "Usage: solax-mon export-compliance [--month YYYY-MM] [--csv]";
+    let Some(compliance) = &config.compliance else {
+        eprintln!("export-compliance needs EXPORT_COMPLIANCE_LIMIT_W and POSTGRES_URL");
+        return 2;
+    };
+    let (mut month, mut csv) = (current_month(compliance.timezone), false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.as_slice().first()) {
+            ("--csv", _) => csv = true,
+            ("--month", Some(value)) => match parse_month(value) {
+                Ok(parsed) => {
+                    month = parsed;
+                    args.next();
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+            },
+            _ => {
+                eprintln!("{}", usage);
+                return 2;
+            }
+        }
+    }
+    let output = match export_compliance_report(compliance, month).await {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Failed to read the history: {}", e);
+            return 1;
+        }
+    };
+    if csv {
+        print!("{}", render_compliance_csv(&output));
+    } else {
+        match serde_json::to_string_pretty(&output) {
+            Ok(text) => println!("{}", text),
+            Err(e) => {
+                eprintln!("Error encoding the report: {}", e);
+                return 1;
+            }
+        }
+    }
+    0
+}
+
+/// `report [--month YYYY-MM | --year YYYY] [--json]`: prints the monthly (by default, the
+/// current month) or yearly report from the daily rollups the service keeps; returns the
+/// process exit code.
+fn report_command(config: &Config, args: &[String]) -> i32 {
+    let usage = "Usage: solax-mon report [--month YYYY-MM | --year YYYY] [--json]";
+    let (mut period, mut json) = (Period::Month(current_month(config.timezone)), false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let parsed = match (arg.as_str(), args.as_slice().first()) {
+            ("--json", _) => {
+                json = true;
+                continue;
+            }
+            ("--month", Some(value)) => parse_month(value).map(Period::Month),
+            ("--year", Some(value)) => parse_year(value).map(Period::Year),
+            _ => {
+                eprintln!("{}", usage);
+                return 2;
+            }
+        };
+        match parsed {
+            Ok(parsed) => {
+                period = parsed;
+                args.next();
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2;
+            }
+        }
+    }
+    let today = chrono::Utc::now().with_timezone(&config.timezone).date_naive();
+    let output = Rollups::load(Path::new(DAILY_ROLLUPS_PATH)).report(period, today);
+    if !json {
+        print!("{}", render_report_text(&output));
+        return 0;
+    }
+    match serde_json::to_string_pretty(&output) {
+        Ok(text) => println!("{}", text),
+        Err(e) => {
+            eprintln!("Error encoding the report: {}", e);
+            return 1;
+        }
+    }
+    0
+}
+
+/// What `export` writes: the PostgreSQL history as inserted, or the daily rollups.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExportResolution {
+    Raw,
+    Day,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ExportArgs {
+    from: chrono::NaiveDate,
+    /// The last local day exported.
+    to: chrono::NaiveDate,
+    out: PathBuf,
+    /// Measurements (`Grid Power`) or columns (`grid_power`) to export, all when empty.
+    metrics: Vec<String>,
+    resolution: ExportResolution,
+}
+
+const EXPORT_USAGE: &str =
+    "Usage: solax-mon export --from YYYY-MM-DD --to YYYY-MM-DD --out <file> [--metrics <name>,...] [--resolution raw|day]";
+
+fn parse_export_args(args: &[String]) -> Result<ExportArgs, String> {
+    let (mut from, mut to, mut out) = (None, None, None);
+    let (mut metrics, mut resolution) = (Vec::new(), ExportResolution::Raw);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(EXPORT_USAGE)?;
+        match arg.as_str() {
+            "--from" => from = Some(parse_date(value)?),
+            "--to" => to = Some(parse_date(value)?),
+            "--out" => out = Some(PathBuf::from(value)),
+            "--metrics" => metrics = value.split(',').map(str::trim).filter(|name| !name.is_empty()).map(String::from).collect(),
+            "--resolution" => resolution = match value.trim() {
+                "raw" => ExportResolution::Raw,
+                "day" => ExportResolution::Day,
+                other => return Err(format!("Unknown resolution {:?} (raw, day)", other)),
+            },
+            _ => return Err(EXPORT_USAGE.to_string()),
+        }
+    }
+    let (Some(from), Some(to), Some(out)) = (from, to, out) else {
+        return Err(EXPORT_USAGE.to_string());
+    };
+    if to < from {
+        return Err(format!("--to {} is before --from {}", to, from));
+    }
+    Ok(ExportArgs { from, to, out, metrics, resolution })
+}
+
+/// The `available` columns `wanted` asks for (all of them when it's empty), in order. A
+/// wanted metric that matches none is an error, rather than a file quietly missing it, and so
+/// are two metrics that would be written as the same column.
+fn export_columns(wanted: &[String], available: Vec<String>) -> Result<Vec<String>, String> {
+    let matches = |wanted: &String, column: &String| postgres::column_name(wanted) == postgres::column_name(column);
+    let missing: Vec<&str> = wanted.iter()
+        .filter(|wanted| !available.iter().any(|column| matches(wanted, column)))
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("Nothing to export for {}", missing.join(", ")));
+    }
+    let columns: Vec<String> = available.into_iter()
+        .filter(|column| wanted.is_empty() || wanted.iter().any(|wanted| matches(wanted, column)))
+        .collect();
+    for (index, column) in columns.iter().enumerate() {
+        if let Some(other) = columns[..index].iter().find(|other| matches(other, column)) {
+            return Err(format!("{:?} and {:?} would both be exported as {}", other, column, postgres::column_name(column)));
+        }
+    }
+    Ok(columns)
+}
+
+/// Readings pivoted into the columns of one row group: the time, then one per metric, with a
+/// row per distinct time.
+struct ExportRows {
+    times: Vec<Option<i64>>,
+    columns: Vec<Vec<Option<f64>>>,
+}
+
+impl ExportRows {
+    fn new(columns: usize) -> Self {
+        Self { times: Vec::new(), columns: vec![Vec::new(); columns] }
+    }
+
+    /// Sets a reading, starting a row when the time differs from the previous reading's.
+    fn set(&mut self, time_ms: i64, column: usize, value: Option<f64>) {
+        if self.times.last() != Some(&Some(time_ms)) {
+            self.times.push(Some(time_ms));
+            self.columns.iter_mut().for_each(|values| values.push(None));
+        }
+        if let Some(slot) = self.columns.get_mut(column).and_then(|values| values.last_mut()) {
+            *slot = value;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    fn into_values(self) -> Vec<parquet::Values> {
+        std::iter::once(parquet::Values::Int(self.times))
+            .chain(self.columns.into_iter().map(parquet::Values::Double))
+            .collect()
+    }
+}
+
+/// Writes the PostgreSQL history of the local days `from` to `to` with a column per metric;
+/// returns the rows written. Each day is read and written as its own row group, so a year
+/// takes no more memory than a day.
+async fn export_history(
+    config: &PostgresConfig,
+    timezone: chrono_tz::Tz,
+    args: &ExportArgs,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let client = connect_postgres(config, postgres_tls(config)?).await?;
+    let midnight = |date| local_instant(timezone, date, chrono::NaiveTime::MIN).with_timezone(&chrono::Utc);
+    let end = midnight(args.to.succ_opt().ok_or("Date out of range")?);
+    let available: Vec<String> = match config.layout {
+        postgres::Layout::Narrow => client.query(&postgres::metrics_sql(&config.table), &[&midnight(args.from), &end]).await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect(),
+        postgres::Layout::Wide => client.prepare(&postgres::columns_sql(&config.table)).await?
+            .columns()
+            .iter()
+            .map(|column| column.name().to_string())
+            .filter(|name| name != "time")
+            .collect(),
+    };
+    let metrics = export_columns(&args.metrics, available)?;
+    let schema = std::iter::once(parquet::Column::new("time", parquet::Kind::TimestampMillis))
+        .chain(metrics.iter().map(|metric| parquet::Column::new(postgres::column_name(metric), parquet::Kind::Double)))
+        .collect();
+    let mut writer = parquet::Writer::new(std::io::BufWriter::new(std::fs::File::create(&args.out)?), schema)?;
+    let sql = postgres::export_sql(&config.table, config.layout, &metrics);
+    let (mut date, mut written) = (args.from, 0);
+    while date <= args.to {
+        let next = date.succ_opt().ok_or("Date out of range")?;
+        let (start, end) = (midnight(date), midnight(next));
+        let mut rows = ExportRows::new(metrics.len());
+        match config.layout {
+            postgres::Layout::Narrow => {
+                for row in client.query(&sql, &[&start, &end, &metrics]).await? {
+                    let time = row.get::<_, chrono::DateTime<chrono::Utc>>(0).timestamp_millis();
+                    if let Some(column) = metrics.iter().position(|metric| metric == row.get::<_, &str>(1)) {
+                        rows.set(time, column, Some(row.get(2)));
+                    }
+                }
+            }
+            postgres::Layout::Wide => {
+                for row in client.query(&sql, &[&start, &end]).await? {
+                    let time = row.get::<_, chrono::DateTime<chrono::Utc>>(0).timestamp_millis();
+                    for column in 0..metrics.len() {
+                        rows.set(time, column, row.get(column + 1));
+                    }
+                }
+            }
+        }
+        written += rows.len();
+        writer.write_row_group(&rows.into_values())?;
+        date = next;
+    }
+    writer.finish()?;
+    Ok(written)
+}
+
+/// Writes the daily rollups of `from` to `to` as one row group; returns the rows written.
+fn write_rollups<W: std::io::Write>(out: W, days: &[DayRollup], args: &ExportArgs) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let days: Vec<&DayRollup> = days.iter().filter(|day| (args.from..=args.to).contains(&day.date)).collect();
+    let epoch = chrono::NaiveDate::from_ymd_opt(1970, 1, 1).expect("valid date");
+    let double = |name: &str, value: fn(&DayRollup) -> f64| {
+        (parquet::Column::new(name, parquet::Kind::Double), parquet::Values::Double(days.iter().map(|day| Some(value(day))).collect()))
+    };
+    let int = |name: &str, value: fn(&DayRollup) -> i64| {
+        (parquet::Column::new(name, parquet::Kind::Int64), parquet::Values::Int(days.iter().map(|day| Some(value(day))).collect()))
+    };
+    let mut columns = vec![
+        double("generated_kwh", |day| day.generated_kwh),
+        double("consumed_kwh", |day| day.consumed_kwh),
+        double("imported_kwh", |day| day.imported_kwh),
+        double("exported_kwh", |day| day.exported_kwh),
+        double("charged_kwh", |day| day.charged_kwh),
+        double("discharged_kwh", |day| day.discharged_kwh),
+        int("attempted", |day| day.attempted.into()),
+        int("succeeded", |day| day.succeeded.into()),
+        int("longest_gap_secs", |day| day.longest_gap_secs as i64),
+    ];
+    let wanted = export_columns(&args.metrics, columns.iter().map(|(column, _)| column.name.clone()).collect())?;
+    columns.retain(|(column, _)| wanted.contains(&column.name));
+    let dates = days.iter().map(|day| Some((day.date - epoch).num_days())).collect();
+    columns.insert(0, (parquet::Column::new("date", parquet::Kind::Date), parquet::Values::Int(dates)));
+    let (schema, values): (Vec<_>, Vec<_>) = columns.into_iter().unzip();
+    let mut writer = parquet::Writer::new(out, schema)?;
+    writer.write_row_group(&values)?;
+    writer.finish()?;
+    Ok(days.len())
+}
+
+/// `export --from YYYY-MM-DD --to YYYY-MM-DD --out <file> [--metrics <name>,...]
+/// [--resolution raw|day]`: writes the PostgreSQL history, or with `day` the daily rollups, to
+/// a Parquet file; returns the process exit code.
+async fn export_command(config: &Config, args: &[String]) -> i32 {
+    let args = match parse_export_args(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    let result = match (args.resolution, &config.postgres) {
+        (ExportResolution::Raw, Some(postgres)) => export_history(postgres, config.timezone, &args).await,
+        (ExportResolution::Raw, None) => {
+            eprintln!("export needs POSTGRES_URL for the history, or --resolution day for the daily rollups");
+            return 2;
+        }
+        (ExportResolution::Day, _) => std::fs::File::create(&args.out)
+            .map_err(Into::into)
+            .and_then(|file| write_rollups(std::io::BufWriter::new(file), &Rollups::load(Path::new(DAILY_ROLLUPS_PATH)).days, &args)),
+    };
+    match result {
+        Ok(rows) => {
+            println!("Wrote {} rows to {}", rows, args.out.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("Export failed: {}", e);
+            1
+        }
+    }
+}
+
+/// `diag --out <file> [--keep-serial] [--keep-ips]`: polls the inverter once and writes a
+/// bundle for a bug report, see `diag`; returns the process exit code.
+async fn diag_command(config: &Config, args: &[String]) -> i32 {
+    let Some(out) = args.iter().position(|arg| arg == "--out").and_then(|index| args.get(index + 1)) else {
+        eprintln!("Usage: solax-mon diag --out <file> [--keep-serial] [--keep-ips]");
+        return 2;
+    };
+    let redacted = diag::Redaction {
+        serial: !args.iter().any(|arg| arg == "--keep-serial"),
+        ips: !args.iter().any(|arg| arg == "--keep-ips"),
+    };
+    let text = std::fs::read_to_string(SECRETS_PATH).unwrap_or_default();
+    let mut inverter = build_inverter(config);
+    let mut fetch_errors = diag::FetchErrors::load(Path::new(FETCH_ERRORS_PATH)).errors;
+    let polled = match inverter.fetch_data(&config.serial).await {
+        Ok(polled) => Some(polled),
+        Err(e) => {
+            eprintln!("Error fetching data, the bundle has no poll: {}", e);
+            fetch_errors.push(diag::FetchError { time: unix_now(), error: e.to_string() });
+            None
+        }
+    };
+    let response = inverter.last_response.take();
+    let info = inverter.info.clone();
+    let mut bundle = diag::Bundle {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        created: unix_now(),
+        redacted,
+        model: info.as_ref().map(|info| info.model.clone()).unwrap_or_default(),
+        firmware: response.as_ref().map(|response| response.ver.clone()).unwrap_or_default(),
+        info: info.as_ref().map(|info| info.to_output()),
+        decoding: diag::Decoding {
+            power_signs: config.power_signs,
+            soc_calibration: config.soc_calibration,
+            load_source: config.load_source,
+            battery_modules: config.battery_modules,
+            bms_limits: config.bms_limits,
+        },
+        source: None,
+        response: None,
+        measurements: None,
+        status: None,
+        decode: None,
+        config: parse_entries(&text).iter().map(|(key, value)| secrets::masked(key, value)).collect(),
+        fetch_errors,
+    };
+    if let (Some((snapshot, source)), Some(response)) = (polled, response) {
+        bundle.source = Some(source);
+        bundle.measurements = Some(snapshot.to_raw(&PublishConfig::default()));
+        bundle.status = Some(inverter.format_status(&snapshot));
+        bundle.decode = Some(inverter.explain(&response, &snapshot));
+        bundle.response = Some(response);
+    }
+
+    // The dongle's registration number is the SERIAL of the config
+    let mut serials = vec![config.serial.clone()];
+    serials.extend(info.iter().flat_map(|info| [Some(info.sn.clone()), info.module_sn.clone()]).flatten());
+    let result = bundle.to_json(&serials)
+        .map_err(|e| e.to_string())
+        .and_then(|json| std::fs::write(out, json).map_err(|e| e.to_string()));
+    match result {
+        Ok(()) => {
+            println!(
+                "Wrote {} (serial {}, IP addresses {})",
+                out,
+                if redacted.serial { "redacted" } else { "kept" },
+                if redacted.ips { "redacted" } else { "kept" },
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to write {}: {}", out, e);
+            1
+        }
+    }
+}
+
+/// `diag --load <file>`: decodes the response of a bundle with the decoder of this build and
+/// the bundle's settings, and prints the measurements and where they differ from the bundle's.
+fn replay_command(args: &[String]) -> i32 {
+    let Some(path) = args.iter().position(|arg| arg == "--load").and_then(|index| args.get(index + 1)) else {
+        eprintln!("Usage: solax-mon diag --load <file>");
+        return 2;
+    };
+    let bundle: diag::Bundle = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+    {
+        Ok(bundle) => bundle,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path, e);
+            return 1;
+        }
+    };
+    println!("Bundle of solax-mon {}, {} firmware {}", bundle.version, bundle.model, bundle.firmware);
+    for error in &bundle.fetch_errors {
+        println!("Fetch error at {}: {}", error.time, error.error);
+    }
+    let Some(response) = bundle.response else {
+        println!("The bundle has no poll to replay");
+        return 1;
+    };
+    let mut inverter = X3HybridG4::new(&[], Duration::ZERO);
+    inverter.power_signs = bundle.decoding.power_signs;
+    inverter.soc_calibration = bundle.decoding.soc_calibration;
+    inverter.load_source = bundle.decoding.load_source;
+    inverter.battery_modules = bundle.decoding.battery_modules;
+    if let Some(index) = bundle.decoding.bms_limits {
+        inverter.map_bms_limits(index);
+    }
+    let snapshot = inverter.ingest(response);
+    let replayed = snapshot.to_raw(&PublishConfig::default());
+    println!();
+    print!("{}", status_table(&replayed, &inverter.format_status(&snapshot)));
+
+    let differences = bundle.measurements.as_ref()
+        .map(|bundled| diag::differences(bundled, &replayed))
+        .unwrap_or_default();
+    if differences.is_empty() {
+        println!("\nDecoded the same as in the bundle");
+        return 0;
+    }
+    let value = |value: Option<f64>| value.map_or("-".to_string(), |value| format!("{:.2}", value));
+    println!("\nDecoded differently from the bundle:");
+    for (name, bundled, replayed) in &differences {
+        println!("  {}: {} -> {}", name, value(*bundled), value(*replayed));
+    }
+    0
+}
+
+/// The measurements with their values aligned on the decimal point, then the summary.
+fn status_table(raw: &RawOutput, status: &StatusOutput) -> String {
+    let values: Vec<(&String, String, &str)> = raw.measurements.iter()
+        .map(|(name, measurement)| (name, format!("{:.2}", measurement.value), measurement.unit.as_str()))
+        .collect();
+    let name_width = values.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+    let value_width = values.iter().map(|(_, value, _)| value.len()).max().unwrap_or(0);
+    let mut table = String::new();
+    for (name, value, unit) in &values {
+        table += format!("{:<name_width$}  {:>value_width$} {}", name, value, unit).trim_end();
+        table.push('\n');
+    }
+    if raw.partial {
+        table += "(partial response)\n";
+    }
+
+    let summary = [
+        ("Solar panels", &status.solar_panels),
+        ("Batteries", &status.batteries),
+        ("Battery status", &status.battery_status),
+        ("Battery power", &status.battery_power),
+        ("Grid status", &status.grid_status),
+        ("Grid power", &status.grid_power),
+        ("Home consumption", &status.home_consumption),
+    ];
+    table.push('\n');
+    for (label, value) in summary {
+        table += &format!("{:<16}  {}\n", label, value);
+    }
+    table
+}
+
+/// `verify <file|-> --key <public key> [--max-age <secs>]`: checks the signature of a saved
+/// /status/raw response against the public key from /info, prints a one-line result and
+/// returns the process exit code.
+fn verify_command(args: &[String]) -> i32 {
+    let (mut path, mut key, mut max_age) = (None, None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => key = args.next(),
+            "--max-age" => match args.next().map(|secs| secs.parse::<u64>()) {
+                Some(Ok(secs)) => max_age = Some(secs),
+                _ => {
+                    eprintln!("--max-age needs a number of seconds");
+                    return 2;
+                }
+            },
+            _ => path = Some(arg),
+        }
+    }
+    let (Some(path), Some(key)) = (path, key) else {
+        eprintln!("Usage: solax-mon verify <file|-> --key <public key from /info> [--max-age <secs>]");
+        return 2;
+    };
+    let response = if path == "-" {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(path)
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path, e);
+            return 2;
+        }
+    };
+    match signing::verify(&response, key) {
+        Ok(signed_at) => {
+            let age = unix_now().saturating_sub(signed_at);
+            if let Some(max_age) = max_age.filter(|max_age| age > *max_age) {
+                println!("INVALID: signature valid but signed {}s ago, older than {}s", age, max_age);
+                1
+            } else {
+                println!("OK: signature valid, signed {}s ago", age);
+                0
+            }
+        }
+        Err(e) => {
+            println!("INVALID: {}", e);
+            1
+        }
+    }
+}
+
+/// Where the service keeps its state between restarts.
+const DATA_DIR: &str = "/srv/solax-mon/data";
+
+/// Everything the poll loop carries from one poll to the next. The service runs it on the
+/// dongle's polls; the `soak` subcommand runs it on simulated time, as a dry run.
+struct PollLoop {
+    schedule: PollSchedule,
+    balance: BalanceCheck,
+    eps: EpsCheck,
+    consistency: Option<consistency::Checker>,
+    consistency_polls: u32,
+    night: Option<NightMode>,
+    power_save: Option<PowerSave>,
+    burst: Option<Burst>,
+    thresholds: Arc<ThresholdConfig>,
+    threshold_states: Vec<ThresholdState>,
+    bms_limit: Option<(ThresholdRule, ThresholdState)>,
+    module_drift: Option<(ThresholdRule, ThresholdState)>,
+    threshold_discord: Arc<ControlConfig>,
+    restarts: RestartCheck,
+    changes: changes::ChangeTracker,
+    change_thresholds: changes::Thresholds,
+    /// Failures repeating every poll, logged once while they last.
+    warnings: Warnings,
+    /// Whether the last run mode seen was a fault, for the fault events.
+    faulted: bool,
+    fetch_errors: diag::FetchErrors,
+    publish: PublishConfig,
+    labels_config: LabelsConfig,
+    timezone: chrono_tz::Tz,
+    battery_max_gap: Duration,
+    battery_capacity_kwh: Option<f64>,
+    backup_reserve_pct: f64,
+    essential_load: Option<EssentialLoad>,
+    runtime_load: RuntimeLoad,
+    consumption_anomaly: Option<anomaly::Detector>,
+    zabbix: Option<ZabbixConfig>,
+    textfile: Option<PathBuf>,
+    evc: Option<(EvCharger, String)>,
+    statsd: Option<(StatsdConfig, statsd::Socket)>,
+    postgres_polls: Option<(tokio::sync::mpsc::Sender<postgres::Poll>, postgres::Layout)>,
+    /// Only the latest poll is kept for the Redis sink; nobody listens without REDIS_URL.
+    redis_polls: tokio::sync::watch::Sender<Option<RawOutput>>,
+    /// Holds the files of the `*_PATH` constants, DATA_DIR for the service.
+    data_dir: PathBuf,
+    /// Set for a dry run: the alerts are collected here (time, rule name and whether it
+    /// fired or cleared) instead of being sent.
+    dry_run: Option<Vec<(u64, String, bool)>>,
+}
+
+impl PollLoop {
+    /// The loop for `config` without any sinks, which the service adds.
+    fn new(config: &Config, data_dir: PathBuf) -> Self {
+        let thresholds = Arc::new(config.thresholds.clone());
+        let consistency = (config.consistency_polls > 0)
+            .then(|| consistency::Checker::load(&data_dir.join(file_name(CONSISTENCY_PATH))));
+        Self {
+            schedule: PollSchedule::new(config.polling.clone()),
+            balance: BalanceCheck::new(config.balance.clone()),
+            eps: EpsCheck::new(config.eps.clone()),
+            consistency,
+            consistency_polls: config.consistency_polls,
+            night: config.night.clone().map(NightMode::new),
+            power_save: config.power_save.clone().map(PowerSave::new),
+            burst: config.burst.clone().map(Burst::new),
+            threshold_states: thresholds.rules.iter().map(|_| ThresholdState::default()).collect(),
+            thresholds,
+            bms_limit: config.bms_limit_alert.map(|sustain| (bms_limit_rule(sustain), ThresholdState::default())),
+            module_drift: config.battery_module_drift_pct.map(|pct| (module_drift_rule(pct), ThresholdState::default())),
+            threshold_discord: Arc::new(config.control.clone()),
+            restarts: RestartCheck::default(),
+            changes: changes::ChangeTracker::default(),
+            change_thresholds: config.change_thresholds.clone(),
+            warnings: Warnings::new(),
+            faulted: false,
+            fetch_errors: diag::FetchErrors::load(&data_dir.join(file_name(FETCH_ERRORS_PATH))),
+            publish: config.publish.clone(),
+            labels_config: config.labels.clone(),
+            timezone: config.timezone,
+            battery_max_gap: config.battery_max_gap,
+            battery_capacity_kwh: config.battery_capacity_kwh,
+            backup_reserve_pct: config.backup_reserve_pct,
+            essential_load: config.essential_load.clone(),
+            runtime_load: config.runtime_load,
+            consumption_anomaly: config.anomaly.clone().map(anomaly::Detector::new),
+            zabbix: None,
+            textfile: None,
+            evc: None,
+            statsd: None,
+            postgres_polls: None,
+            redis_polls: tokio::sync::watch::channel(None).0,
+            data_dir,
+            dry_run: None,
+        }
+    }
+
+    /// Handles the reply to one poll, returning how long to wait for the next one.
+    async fn poll(&mut self, app_state: &Arc<AppState>, reply: dongle::PollReply) -> Duration {
+        let PollLoop {
+            schedule, balance, eps, consistency, consistency_polls, night, power_save, burst,
+            thresholds, threshold_states, bms_limit, module_drift, threshold_discord,
+            restarts, changes, change_thresholds, warnings, faulted, fetch_errors,
+            publish, labels_config, timezone, battery_max_gap, battery_capacity_kwh,
+            backup_reserve_pct, essential_load, runtime_load, consumption_anomaly,
+            zabbix, textfile, evc, statsd, postgres_polls, redis_polls, data_dir, dry_run,
+        } = self;
+        let (timezone, battery_max_gap, consistency_polls) = (*timezone, *battery_max_gap, *consistency_polls);
+        let (battery_capacity_kwh, backup_reserve_pct, runtime_load) = (*battery_capacity_kwh, *backup_reserve_pct, *runtime_load);
+        let data_path = |path: &str| data_dir.join(file_name(path));
+        let clock = app_state.clock.clone();
+        let result = reply.result;
+        let (mut delay, mut backoff) = schedule.next_delay(result.is_ok(), clock.unix_now());
+        let was_night = night.as_ref().is_some_and(|night| night.active);
+        if let Some(night) = night {
+            let now = clock.utc_now();
+            let snapshot = result.as_ref().ok().map(|polled| &polled.snapshot);
+            match night.observe(now.with_timezone(&timezone).time(), now.timestamp() as u64, snapshot) {
+                Some(true) => println!("No solar power for a while, polling every {}s for the night", night.config.interval.as_secs()),
+                Some(false) => println!("Leaving night mode, back to the normal poll interval"),
+                None => {}
+            }
+            app_state.night_mode.store(night.active, std::sync::atomic::Ordering::SeqCst);
+            if night.active {
+                delay = night.config.interval;
+                backoff.state = "night".to_string();
+                backoff.next_poll = Some(clock.unix_now() + delay.as_secs());
+            }
+        }
+        if let Some(power_save) = power_save {
+            let snapshot = result.as_ref().ok().map(|polled| &polled.snapshot);
+            match power_save.observe(snapshot) {
+                Some(true) => println!(
+                    "Grid down and battery below {}%, saving power: polling every {}s, push sinks paused",
+                    power_save.config.below_soc,
+                    power_save.config.interval.as_secs(),
+                ),
+                Some(false) => println!("Leaving power-save mode, resuming normal polling and the push sinks"),
+                None => {}
+            }
+            app_state.power_save.store(power_save.active, std::sync::atomic::Ordering::SeqCst);
+            // Replaces the night interval too, which would be far slower, but not a failure cooldown
+            if power_save.active && backoff.state != "cooldown" {
+                delay = power_save.config.interval;
+                backoff.state = "power_save".to_string();
+                backoff.next_poll = Some(clock.unix_now() + delay.as_secs());
+            }
+        }
+        // Whether this poll was taken at a burst interval, for the "Burst Poll" measurement
+        let burst_poll = burst.as_ref().map(Burst::active);
+        if let Some(burst) = burst {
+            let now = clock.instant();
+            if let Some(trigger) = result.as_ref().ok().and_then(|polled| burst.observe(&polled.snapshot, now)) {
+                println!(
+                    "{}, polling every {}s for {}s",
+                    trigger,
+                    burst.config.interval.as_secs(),
+                    burst.config.window.as_secs(),
+                );
+            }
+            // Night, power-save and cooldown intervals win over a burst
+            if backoff.state == "normal" {
+                if let Some(fast) = burst.next_delay(delay, now) {
+                    delay = fast;
+                    backoff.state = "burst".to_string();
+                    backoff.next_poll = Some(clock.unix_now() + delay.as_secs());
+                }
+            }
+        }
+        let saving_power = power_save.as_ref().is_some_and(|power_save| power_save.active);
+        // The dongle is expected to sleep at night, so its failures don't count against it
+        if result.is_ok() || !was_night {
+            let now = clock.utc_now();
+            let date = now.with_timezone(&timezone).date_naive();
+            let mut availability = app_state.availability.write().await;
+            availability.record(date, now.timestamp() as u64, result.is_ok());
+            availability.save(&data_path(AVAILABILITY_PATH));
+            let today = availability.days.last().cloned();
+            drop(availability);
+            let mut rollups = app_state.rollups.write().await;
+            if let Some(day) = today {
+                rollups.set_availability(day.date, day.attempted, day.succeeded, day.longest_gap_secs);
+            }
+            if let Ok(polled) = &result {
+                rollups.record(date, now.timestamp() as u64, Flows::of(&polled.snapshot), battery_max_gap);
+            }
+            rollups.save(&data_path(DAILY_ROLLUPS_PATH));
+        }
+        let mut health = app_state.health.write().await;
+        health.sources = reply.sources;
+        if backoff.state == "cooldown" && health.backoff.state != "cooldown" {
+            eprintln!(
+                "{} consecutive fetch failures, cooling down for {}s",
+                backoff.consecutive_failures,
+                delay.as_secs()
+            );
+        }
+        health.backoff = backoff;
+        // The snapshot of this poll, if it succeeded
+        let mut polled = None;
+        match result {
+            Ok(dongle::Polled { snapshot, source, status, info, decode, protocol }) => {
+                balance.observe(&snapshot);
+                let rated_kw = match &info {
+                    Some(info) => info.rated_power_kw,
+                    None => app_state.info.read().await.as_ref().and_then(|info| info.rated_power_kw),
+                };
+                if let Some(checker) = consistency {
+                    let transition = checker.observe(&snapshot, rated_kw.map(|kw| kw * 1000.0), consistency_polls);
+                    checker.save(&data_path(CONSISTENCY_PATH));
+                    health.quality = if checker.degraded { "degraded" } else { "ok" }.to_string();
+                    health.failing_invariants = checker.failing.clone();
+                    let now = clock.unix_now();
+                    let degraded = matches!(transition, Some(consistency::Transition::Degraded { .. }));
+                    if let Some(transition) = transition.filter(|_| !dry_run_alert(dry_run, now, "register_map", degraded)) {
+                        let discord = threshold_discord.clone();
+                        tokio::spawn(async move { report_register_map(&discord, transition).await });
+                    }
+                }
+                let now = clock.unix_now();
+                for (rule, threshold) in thresholds.rules.iter().zip(threshold_states.iter_mut()) {
+                    let value = snapshot.value(&rule.metric);
+                    let Some(fired) = threshold.observe(rule, value, now) else { continue };
+                    if dry_run_alert(dry_run, now, &rule.name, fired) {
+                        continue;
+                    }
+                    let event = ThresholdEvent {
+                        rule: rule.name.clone(),
+                        state: if fired { "fired" } else { "cleared" }.to_string(),
+                        metric: rule.metric.clone(),
+                        condition: rule.condition(),
+                        value: value.unwrap_or_default(),
+                        time: now,
+                    };
+                    let (state, thresholds, discord) = (app_state.clone(), thresholds.clone(), threshold_discord.clone());
+                    tokio::spawn(async move { send_threshold_event(&state, &thresholds, &discord, event).await });
+                }
+                let eps_limit_w = eps.limit_w(rated_kw);
+                let eps_headroom_w = eps.headroom_w(&snapshot, rated_kw);
+                if let Some(fired) = eps.observe(eps_headroom_w).filter(|fired| !dry_run_alert(dry_run, now, "eps_overload", *fired)) {
+                    let (discord, snapshot) = (threshold_discord.clone(), snapshot.clone());
+                    tokio::spawn(async move { report_eps_overload(&discord, fired, &snapshot, eps_limit_w, eps_headroom_w).await });
+                }
+                if let Some((rule, state)) = bms_limit {
+                    let fired = state.observe(rule, bms_shortfall_w(&snapshot), now);
+                    if let Some(fired) = fired.filter(|fired| !dry_run_alert(dry_run, now, &rule.name, *fired)) {
+                        let (discord, snapshot) = (threshold_discord.clone(), snapshot.clone());
+                        tokio::spawn(async move { report_bms_limit(&discord, fired, &snapshot).await });
+                    }
+                }
+                if let Some((rule, state)) = module_drift {
+                    let fired = state.observe(rule, snapshot.value(&rule.metric), now);
+                    if let Some(fired) = fired.filter(|fired| !dry_run_alert(dry_run, now, &rule.name, *fired)) {
+                        let (discord, snapshot) = (threshold_discord.clone(), snapshot.clone());
+                        tokio::spawn(async move { report_module_drift(&discord, fired, &snapshot).await });
+                    }
+                }
+                *app_state.rules.write().await = thresholds.rules.iter().zip(threshold_states.iter())
+                    .chain(bms_limit.iter().map(|(rule, state)| (rule, state)))
+                    .chain(module_drift.iter().map(|(rule, state)| (rule, state)))
+                    .map(|(rule, state)| state.metrics(rule, now))
+                    .collect();
+                let restart = restarts.observe(clock.utc_now().with_timezone(&timezone).naive_local(), now, &snapshot);
+                if let Some(restart) = restart.filter(|_| !dry_run_alert(dry_run, now, "inverter_restart", true)) {
+                    let (state, discord) = (app_state.clone(), threshold_discord.clone());
+                    tokio::spawn(async move { report_inverter_restart(&state, &discord, restart).await });
+                }
+                if let Some(diff) = changes.observe(&snapshot, change_thresholds).filter(|diff| !diff.changes.is_empty()) {
+                    if dry_run.is_some() {
+                        publish_changes(app_state, diff).await;
+                    } else {
+                        let state = app_state.clone();
+                        tokio::spawn(async move { publish_changes(&state, diff).await });
+                    }
+                }
+                if let Some(battery_power) = snapshot.value("Battery Power") {
+                    let now = clock.utc_now();
+                    let mut battery = app_state.battery.write().await;
+                    let date = now.with_timezone(&timezone).date_naive();
+                    let counters = energy_counters(&snapshot, "Battery Charged Total", "Battery Discharged Total");
+                    let added = battery.record(date, now.timestamp() as u64, battery_power, counters, battery_max_gap);
+                    battery.save(&data_path(BATTERY_STATS_PATH));
+                    if let Some((charged, discharged)) = added {
+                        app_state.rollups.write().await.add_battery(date, charged, discharged);
+                    }
+                    if let Some(soc) = snapshot.value("Battery Remaining Capacity") {
+                        let solar_w = snapshot.value("Total Solar Power").unwrap_or(0.0);
+                        let mut capacity = app_state.capacity.write().await;
+                        let learned = capacity.record(now.timestamp() as u64, soc, battery_power, solar_w, battery_max_gap, battery_capacity_kwh);
+                        if let Some(kwh) = learned {
+                            println!("Learned battery capacity is now {:.2} kWh from {} discharges", kwh, capacity.segments);
+                        }
+                        capacity.save(&data_path(BATTERY_CAPACITY_PATH));
+                    }
+                }
+                let grid_today = match snapshot.value("Grid Power") {
+                    Some(grid_power) => {
+                        let now = clock.utc_now();
+                        let mut grid = app_state.grid.write().await;
+                        let counters = energy_counters(&snapshot, "Feed-in Energy Total", "Consumption Energy Total");
+                        let date = now.with_timezone(&timezone).date_naive();
+                        let added = grid.record(date, now.timestamp() as u64, grid_power, counters, battery_max_gap);
+                        grid.save(&data_path(GRID_STATS_PATH));
+                        if let Some((exported, imported)) = added {
+                            app_state.rollups.write().await.add_grid(date, imported, exported);
+                        }
+                        Some(grid.measurements())
+                    }
+                    None => None,
+                };
+                if let (Some(estimate), Some(solar_w), Some(grid_w)) =
+                    (app_state.curtailment_estimate, snapshot.value("Total Solar Power"), snapshot.value("Grid Power"))
+                {
+                    let now = clock.utc_now();
+                    let export_limit_w = app_state.settings.read().await.as_ref()
+                        .and_then(|output| output.settings.as_ref())
+                        .map(|settings| settings.export_limit_w as f64);
+                    let mut curtailment = app_state.curtailment.write().await;
+                    curtailment.record(
+                        estimate,
+                        now.with_timezone(&timezone).naive_local(),
+                        now.timestamp() as u64,
+                        solar_w,
+                        grid_w,
+                        snapshot.value("Battery Remaining Capacity"),
+                        export_limit_w,
+                        battery_max_gap,
+                    );
+                    curtailment.save(&data_path(CURTAILMENT_STATS_PATH));
+                }
+                let labels = labels_config.for_snapshot(&snapshot);
+                let mut status = status;
+                status.labels = labels.clone();
+                let mut raw = snapshot.to_raw(publish);
+                raw.labels = labels;
+                raw.battery_charge_advisory = snapshot.charge_advisory(app_state.charge_temperatures);
+                for (name, kwh) in grid_today.into_iter().flatten() {
+                    raw.measurements.insert(name.to_string(), RawMeasurement {
+                        seq: Some(snapshot.observed.seq),
+                        observed_at: Some(snapshot.observed.time),
+                        ..RawMeasurement::new(kwh, "kWh")
+                    });
+                }
+                // Stale meters drop out rather than repeating their last reading
+                let external = app_state.external.read().await;
+                let circuits = external.fresh(clock.unix_now());
+                status.essential_load_w = essential_load.as_ref()
+                    .and_then(|essential| essential.watts(snapshot.value("Load/Generator Power"), &external, clock.unix_now()));
+                drop(external);
+                for (name, watts, timestamp) in &circuits {
+                    raw.measurements.insert(name.clone(), RawMeasurement {
+                        observed_at: Some(*timestamp),
+                        ..RawMeasurement::new(*watts, "W")
+                    });
+                }
+                status.circuits_w = circuits.into_iter().map(|(name, watts, _)| (name, watts)).collect();
+                if let Some(burst_poll) = burst_poll {
+                    raw.measurements.insert("Burst Poll".to_string(), RawMeasurement {
+                        seq: Some(snapshot.observed.seq),
+                        observed_at: Some(snapshot.observed.time),
+                        ..RawMeasurement::new(f64::from(u8::from(burst_poll)), "")
+                    });
+                }
+                let eps_watts = [("EPS Power Limit", eps_limit_w), ("EPS Headroom", eps_headroom_w)];
+                for (name, watts) in eps_watts.into_iter().filter_map(|(name, watts)| Some((name, watts?))) {
+                    raw.measurements.insert(name.to_string(), RawMeasurement {
+                        seq: Some(snapshot.observed.seq),
+                        observed_at: Some(snapshot.observed.time),
+                        ..RawMeasurement::new(watts, "W")
+                    });
+                }
+                // With RUNTIME_LOAD=essential the overnight load is learned from the essential load
+                let runtime_load_w = match runtime_load {
+                    RuntimeLoad::Total => snapshot.value("Load/Generator Power"),
+                    RuntimeLoad::Essential => status.essential_load_w,
+                };
+                if let Some(load_w) = runtime_load_w {
+                    let mut overnight = app_state.overnight.write().await;
+                    if overnight.record(clock.utc_now().with_timezone(&timezone).naive_local(), load_w) {
+                        println!("Typical overnight load is now {:?} W", overnight.typical_w());
+                    }
+                    overnight.save(&data_path(OVERNIGHT_LOAD_PATH));
+                    let typical_w = overnight.typical_w().unwrap_or(load_w);
+                    raw.backup_runtime_estimate_hours = app_state.capacity_kwh().await
+                        .and_then(|kwh| snapshot.backup_runtime_hours(kwh, backup_reserve_pct, typical_w));
+                }
+                if let Some(load_w) = snapshot.value("Load/Generator Power") {
+                    if let Some(detector) = consumption_anomaly {
+                        let at = clock.utc_now().with_timezone(&timezone).naive_local();
+                        let mut consumption = app_state.consumption.write().await;
+                        consumption.record(at, load_w, detector.config.weeks);
+                        consumption.save(&data_path(CONSUMPTION_HISTORY_PATH));
+                        drop(consumption);
+                        let hour = chrono::Timelike::hour(&at.time());
+                        let transition = app_state.consumption_baseline.read().await.as_ref()
+                            .and_then(|baseline| detector.observe(baseline, hour, load_w, clock.unix_now()));
+                        let now = clock.unix_now();
+                        let fired = matches!(transition, Some(anomaly::Transition::Fired(_)));
+                        if let Some(transition) = transition.filter(|_| !dry_run_alert(dry_run, now, "consumption_anomaly", fired)) {
+                            let discord = threshold_discord.clone();
+                            tokio::spawn(async move { report_consumption_anomaly(&discord, transition, now).await });
+                        }
+                    }
+                }
+                if let Some(config) = zabbix.as_ref().filter(|_| !saving_power) {
+                    // Sent in the background, so a slow server doesn't hold up polling
+                    let (state, config, raw) = (app_state.clone(), config.clone(), raw.clone());
+                    tokio::spawn(async move { push_to_zabbix(&state, &config, &raw).await });
+                }
+                // Only the latest poll is kept for the Redis sink; nobody listens without REDIS_URL
+                if !saving_power {
+                    redis_polls.send_replace(Some(raw.clone()));
+                }
+                if let Some(mode) = snapshot.run_mode() {
+                    if mode.is_fault() != *faulted {
+                        *faulted = mode.is_fault();
+                        let state = if *faulted { "appeared" } else { "cleared" };
+                        app_state.emit(EventKind::Fault, serde_json::json!({ "state": state, "run_mode": format!("{:?}", mode) })).await;
+                    }
+                }
+                // Fault and action events still go out while saving power
+                if !saving_power {
+                    app_state.emit(EventKind::Poll, serde_json::to_value(&raw).unwrap_or_default()).await;
+                }
+                if let Some((sender, layout)) = postgres_polls.as_ref().filter(|_| !saving_power) {
+                    if let Err(e) = sender.try_send(postgres::Poll::from_raw(&raw, clock.utc_now())) {
+                        app_state.postgres.write().await.rows_dropped += e.into_inner().rows(*layout) as u64;
+                    }
+                }
+                if statsd.is_some() {
+                    polled = Some(raw.clone());
+                }
+                app_state.latest.publish(Published { status, raw, snapshot: Some(snapshot.clone()) });
+                *app_state.decode.write().await = decode;
+                if let Some(info) = &info {
+                    let mut output = info.to_output();
+                    output.labels = labels_config.for_snapshot(&snapshot);
+                    output.dongle_protocol = Some(protocol.as_str().to_string());
+                    *app_state.info.write().await = Some(output);
+                }
+                health.partial = snapshot.partial;
+                health.source = Some(source);
+                health.last_success = Some(clock.unix_now());
+                health.last_error = None;
+                warnings.clear("fetch", "Fetching data again");
+                if dry_run.is_none() {
+                    println!("Data updated successfully");
+                }
+            },
+            Err(e) => {
+                // There are no log levels, so failures of a sleeping dongle only show on /health
+                if !was_night {
+                    warnings.warn("fetch", format_args!("Error fetching data: {}", e));
+                    fetch_errors.record(clock.unix_now(), e.to_string());
+                    fetch_errors.save(&data_path(FETCH_ERRORS_PATH));
+                }
+                health.last_error = Some(e.to_string());
+            }
+        }
+        drop(health);
+
+        if let Some((charger, password)) = evc.as_ref().filter(|_| !saving_power) {
+            match charger.fetch(password).await {
+                Ok(snapshot) => {
+                    let home_consumption = if app_state.fresh_snapshot().await.is_some() {
+                        Some(parse_power_value(&app_state.latest().status.home_consumption))
+                    } else {
+                        None
+                    };
+                    *app_state.evc.write().await = Some(snapshot.to_output(home_consumption));
+                    warnings.clear("evc", "Fetching EV charger data again");
+                }
+                Err(e) => {
+                    warnings.warn("evc", format_args!("Error fetching EV charger data: {}", e));
+                    *app_state.evc.write().await = None;
+                }
+            }
+        }
+        if let Some((config, socket)) = statsd.as_ref().filter(|_| !saving_power) {
+            let lines = statsd::poll_lines(&config.prefix, &config.tags, config.tag_style, polled.as_ref());
+            let result = socket.send(&statsd::packets(&lines, config.max_packet)).await;
+            match &result {
+                Err(e) => warnings.warn("statsd", format_args!("Failed to send to StatsD: {}", e)),
+                Ok(()) => warnings.clear("statsd", "Sending to StatsD again"),
+            }
+        }
+        if let Some(path) = &textfile {
+            let result = write_textfile(path, &metrics_text(app_state).await).map_err(|e| e.to_string());
+            let mut health = app_state.health.write().await;
+            match &result {
+                Err(e) => warnings.warn("textfile", format_args!("Failed to write {}: {}", path.display(), e)),
+                Ok(()) => warnings.clear("textfile", format_args!("Writing {} again", path.display())),
+            }
+            health.textfile_error = result.err();
+        }
+        delay
+    }
+}
+
+/// The file name of one of the `*_PATH` constants.
+fn file_name(path: &str) -> &std::ffi::OsStr {
+    Path::new(path).file_name().unwrap_or_default()
+}
+
+/// Collects the alert when `dry_run` is set; true when it then shouldn't be sent.
+fn dry_run_alert(dry_run: &mut Option<Vec<(u64, String, bool)>>, now: u64, name: &str, fired: bool) -> bool {
+    match dry_run {
+        Some(alerts) => {
+            alerts.push((now, name.to_string(), fired));
+            true
+        }
+        None => false,
+    }
+}
+
+/// A soak run: the service's poll loop as a dry run on a manual clock, polling a simulated
+/// dongle, and the ssh monitor's rule evaluated on every complete poll.
+struct Soak {
+    state: Arc<AppState>,
+    clock: Arc<ManualClock>,
+    poll_loop: PollLoop,
+    dongle: dongle::Handle,
+    /// What the simulated dongle answers the next poll with.
+    response: Arc<std::sync::Mutex<String>>,
+    monitor_rule: Expr,
+    /// Whether the monitor rule held the last time it could be decided.
+    shutdown: bool,
+    /// Simulated time and whether the monitor would have shut down (true) or recovered.
+    monitor_actions: Vec<(u64, bool)>,
+    polls: u64,
+    failed: u64,
+    partial: u64,
+    changes: u64,
+}
+
+impl Soak {
+    /// Starts the simulated dongle and the service state for `config` at `start`, keeping
+    /// the state files in `data_dir`.
+    fn start(config: &Config, monitor_rule: Expr, start: u64, data_dir: PathBuf) -> Result<Self, String> {
+        let response = Arc::new(std::sync::Mutex::new(String::new()));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").map_err(|e| e.to_string())?;
+        let addr = listener.local_addr().map_err(|e| e.to_string())?;
+        let dongle = Router::new()
+            .route("/", axum::routing::post(|State(response): State<Arc<std::sync::Mutex<String>>>| async move {
+                response.lock().map(|response| response.clone()).unwrap_or_default()
+            }))
+            .with_state(response.clone());
+        let server = axum::Server::from_tcp(listener).map_err(|e| e.to_string())?;
+        tokio::spawn(server.serve(dongle.into_make_service()));
+
+        let inverter = X3HybridG4::new(&[format!("http://{}/", addr)], Duration::ZERO);
+        let clock = Arc::new(ManualClock::new(start));
+        let mut state = AppState::from_config(config, &inverter);
+        state.clock = clock.clone();
+        let mut poll_loop = PollLoop::new(config, data_dir);
+        poll_loop.dry_run = Some(Vec::new());
+        Ok(Self {
+            state: Arc::new(state),
+            clock,
+            poll_loop,
+            dongle: dongle::spawn(inverter, config.serial.clone()),
+            response,
+            monitor_rule,
+            shutdown: false,
+            monitor_actions: Vec::new(),
+            polls: 0,
+            failed: 0,
+            partial: 0,
+            changes: 0,
+        })
+    }
+
+    /// Polls the dongle answering with `response` at `time`; the error when the poll failed.
+    async fn poll(&mut self, time: u64, response: String) -> Option<String> {
+        self.clock.advance_to(time);
+        if let Ok(mut current) = self.response.lock() {
+            *current = response;
+        }
+        self.polls += 1;
+        let reply = self.dongle.poll().await;
+        let (error, complete) = match &reply.result {
+            Ok(polled) => (None, !polled.snapshot.partial),
+            Err(e) => (Some(e.to_string()), false),
+        };
+        self.failed += u64::from(error.is_some());
+        self.partial += u64::from(error.is_none() && !complete);
+        self.poll_loop.poll(&self.state, reply).await;
+        if let Some(diff) = self.state.changes.write().await.take() {
+            self.changes += diff.changes.len() as u64;
+        }
+
+        // The monitor acts on complete snapshots only, with the runtime from the capacity
+        if complete {
+            let mut readings = Readings::from_status(&self.state.latest().status);
+            readings.runtime_min = self.state.capacity_kwh().await
+                .map(|kwh| runtime_minutes(readings.battery_pct, kwh, readings.battery_w));
+            let fresh = HashMap::from([(SOAK_SITE.to_string(), readings)]);
+            let holds = self.monitor_rule.eval(&|name| lookup_reading(name, SOAK_SITE, &fresh), &mut Vec::new());
+            if let Some(holds) = holds.filter(|holds| *holds != self.shutdown) {
+                self.shutdown = holds;
+                self.monitor_actions.push((time, holds));
+            }
+        }
+        error
+    }
+
+    fn alerts(&self) -> &[(u64, String, bool)] {
+        self.poll_loop.dry_run.as_deref().unwrap_or_default()
+    }
+}
+
+/// The site the monitor rule of a soak is evaluated for.
+const SOAK_SITE: &str = "local";
+
+/// An integrated total against what the simulator says flowed; true when within 1% (or
+/// 50 Wh, for small totals).
+fn soak_check(name: &str, measured_kwh: f64, expected_kwh: Option<f64>) -> (String, bool) {
+    let Some(expected_kwh) = expected_kwh else {
+        return (format!("{:<16} {:>9.3} kWh", name, measured_kwh), true);
+    };
+    let deviation = measured_kwh - expected_kwh;
+    let ok = deviation.abs() <= (expected_kwh.abs() * 0.01).max(0.05);
+    let line = format!(
+        "{:<16} {:>9.3} kWh, expected {:.3} ({:+.3}){}",
+        name, measured_kwh, expected_kwh, deviation, if ok { "" } else { "  <-- off" }
+    );
+    (line, ok)
+}
+
+/// `soak [--days <n>] [--interval <secs>] [--speed <factor>] [--capture <file>] [--rule <alert>]... [--monitor-rule <rule>] [--out <dir>]`:
+/// polls a simulated inverter (or replays a capture of dongle responses, one per line) over
+/// HTTP at `speed` times real time, runs the polls through the service's poll loop on
+/// simulated time and the ssh monitor's rule, writes the counters and metrics to a scratch
+/// directory and prints a report; returns the process exit code.
+async fn soak_command(args: &[String], config: Option<Config>) -> i32 {
+    let usage = "Usage: solax-mon soak [--days <n>] [--interval <secs>] [--speed <factor>] [--capture <file>] [--rule <THRESHOLD_ALERT>]... [--monitor-rule <RULE>] [--out <dir>]";
+    let (mut days, mut interval, mut speed) = (1u64, 10u64, 1440.0f64);
+    let (mut capture, mut out, mut rules) = (None, None, Vec::new());
+    let mut monitor_rule = DEFAULT_RULE.to_string();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next();
+        let parsed = match (arg.as_str(), value) {
+            ("--days", Some(value)) => value.parse().map(|n| days = n).is_ok(),
+            ("--interval", Some(value)) => value.parse().ok().filter(|secs| *secs > 0).map(|secs| interval = secs).is_some(),
+            ("--speed", Some(value)) => value.parse().ok().filter(|factor: &f64| *factor >= 0.0).map(|factor| speed = factor).is_some(),
+            ("--capture", Some(value)) => {
+                capture = Some(PathBuf::from(value));
+                true
+            }
+            ("--out", Some(value)) => {
+                out = Some(PathBuf::from(value));
+                true
+            }
+            ("--monitor-rule", Some(value)) => {
+                monitor_rule = value.clone();
+                true
+            }
+            ("--rule", Some(value)) => match ThresholdRule::parse(value) {
+                Ok(rule) => {
+                    rules.push(rule);
+                    true
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+            },
+            _ => false,
+        };
+        if !parsed {
+            eprintln!("{}", usage);
+            return 2;
+        }
+    }
+    let monitor = match Expr::parse(&monitor_rule) {
+        Ok(expr) => expr,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    // A capture is replayed line by line; otherwise the simulator runs for `days`
+    let captured: Option<Vec<String>> = match &capture {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => Some(text.lines().filter(|line| !line.trim().is_empty()).map(str::to_string).collect()),
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", path.display(), e);
+                return 2;
+            }
+        },
+        None => None,
+    };
+    let mut simulator = captured.is_none().then(|| simulator::Simulator::new(simulator::DayConfig::default()));
+    let steps = match &captured {
+        Some(lines) => lines.len() as u64,
+        None => days * 86_400 / interval,
+    };
+    let out = out.unwrap_or_else(|| std::env::temp_dir().join(format!("solax-soak-{}", std::process::id())));
+    if let Err(e) = std::fs::create_dir_all(&out) {
+        eprintln!("Failed to create {}: {}", out.display(), e);
+        return 2;
+    }
+
+    let config = config.map_or_else(|| parse_secrets("INVERTER_URL=http://127.0.0.1/\nSERIAL=SIMULATED\n"), Ok);
+    let mut config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    config.thresholds.rules.extend(rules);
+    config.battery_capacity_kwh = config.battery_capacity_kwh.or(simulator.as_ref().map(|simulator| simulator.config.battery_kwh));
+    // The energy counters have to integrate across every poll of the soak
+    config.battery_max_gap = config.battery_max_gap.max(Duration::from_secs(interval * 3));
+
+    let start_date = chrono::Utc::now().date_naive();
+    let start = start_date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc().timestamp() as u64;
+    let mut soak = match Soak::start(&config, monitor, start, out.clone()) {
+        Ok(soak) => soak,
+        Err(e) => {
+            eprintln!("Failed to start the simulated dongle: {}", e);
+            return 2;
+        }
+    };
+    let started = Instant::now();
+    println!(
+        "Soaking {} polls {}s apart from {}, at {}, writing to {}",
+        steps,
+        interval,
+        capture.as_ref().map_or("the simulator".to_string(), |path| path.display().to_string()),
+        if speed > 0.0 { format!("{}x real time", speed) } else { "full speed".to_string() },
+        out.display(),
+    );
+    for step in 0..steps {
+        let elapsed = step * interval;
+        let body = match (&mut simulator, &captured) {
+            (Some(simulator), _) => {
+                simulator.advance(elapsed);
+                serde_json::to_string(&simulator.response()).unwrap_or_default()
+            }
+            (None, Some(lines)) => lines[step as usize].clone(),
+            (None, None) => unreachable!("either the simulator or a capture"),
+        };
+        if let Some(e) = soak.poll(start + elapsed, body).await {
+            if soak.failed <= 5 {
+                eprintln!("Poll {} failed: {}", step + 1, e);
+            }
+        }
+        if speed > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(interval as f64 / speed)).await;
+        }
+    }
+    soak.clock.advance_to(start + steps * interval);
+    if let Some(simulator) = &mut simulator {
+        simulator.advance(steps * interval);
+    }
+
+    // The metrics, as the service would serve them
+    if let Err(e) = write_textfile(&out.join("solax.prom"), &metrics_text(&soak.state).await) {
+        eprintln!("Failed to write the metrics to {}: {}", out.display(), e);
+    }
+
+    let rules_checked = soak.state.rules.read().await.len();
+    let alerts = soak.alerts();
+    println!("\nSimulated {:.1} h in {:.1} s", (steps * interval) as f64 / 3600.0, started.elapsed().as_secs_f64());
+    println!("Polls            {:>9} ({} failed, {} partial)", soak.polls, soak.failed, soak.partial);
+    println!("Changes          {:>9}", soak.changes);
+    println!("Alerts           {:>9} ({} rules checked)", alerts.len(), rules_checked);
+    let day_time = |time: u64| {
+        let at = time - start;
+        format!("day {} {:02}:{:02}", at / 86_400 + 1, at % 86_400 / 3600, at % 3600 / 60)
+    };
+    for (time, rule, fired) in alerts.iter().take(20) {
+        println!("  {}  {} {}", day_time(*time), rule, if *fired { "fired" } else { "cleared" });
+    }
+    if alerts.len() > 20 {
+        println!("  ... and {} more", alerts.len() - 20);
+    }
+    println!("Monitor rule     {:>9} ({})", soak.monitor_actions.len(), monitor_rule);
+    for (time, shutdown) in soak.monitor_actions.iter().take(20) {
+        println!("  {}  {}", day_time(*time), if *shutdown { "shutdown" } else { "recovery" });
+    }
+    if soak.monitor_actions.len() > 20 {
+        println!("  ... and {} more", soak.monitor_actions.len() - 20);
+    }
+    let totals = simulator.as_ref().map(|simulator| simulator.totals.clone());
+    let (grid, battery) = (soak.state.grid.read().await, soak.state.battery.read().await);
+    let checks = [
+        soak_check("Grid imported", grid.lifetime_imported_kwh, totals.as_ref().map(|totals| totals.imported_kwh)),
+        soak_check("Grid exported", grid.lifetime_exported_kwh, totals.as_ref().map(|totals| totals.exported_kwh)),
+        soak_check("Battery charged", battery.lifetime_charged_kwh, totals.as_ref().map(|totals| totals.charged_kwh)),
+        soak_check("Battery drained", battery.lifetime_discharged_kwh, totals.as_ref().map(|totals| totals.discharged_kwh)),
+    ];
+    for (line, _) in &checks {
+        println!("{}", line);
+    }
+    let capacity = soak.state.capacity.read().await;
+    if let Some(kwh) = capacity.learned_kwh {
+        println!("Learned capacity {:>9.3} kWh from {} discharges", kwh, capacity.segments);
+    }
+    println!("Counters and metrics are in {}", out.display());
+
+    // A simulated inverter answers every poll in full, so anything else is a regression
+    let off = checks.iter().filter(|(_, ok)| !ok).count();
+    let broken = if simulator.is_some() { soak.failed + soak.partial } else { 0 };
+    if off > 0 || broken > 0 {
+        println!("FAILED: {} totals off, {} failed or partial polls", off, broken);
+        1
+    } else {
+        println!("OK: {} polls, {} alerts", soak.polls, alerts.len());
+        0
+    }
+}
+
+/// Performs a GET against the local /health endpoint on the first configured
+/// listen address, prints a one-line result and returns the process exit code.
+async fn healthcheck(config: &Config) -> i32 {
+    let now = chrono::Utc::now().timestamp() as u64;
+    let (code, message) = check_health(config, Path::new(AVAILABILITY_PATH), now).await;
+    println!("{}", message);
+    code
+}
+
+/// Asks `/health`, falling back to the age of the last successful poll in the persisted
+/// availability when the service can't be reached (a hung HTTP server with a live poller).
+async fn check_health(config: &Config, availability_path: &Path, now: u64) -> (i32, String) {
+    let Some(addr) = config.listen_addrs.first() else {
+        return (1, "UNHEALTHY: no listen address configured".to_string());
+    };
+
+    let request = tokio::time::timeout(Duration::from_secs(3), request_health(addr)).await;
+    let unreachable = match request {
+        Ok(Ok(status_line)) if status_line.split_whitespace().nth(1) == Some("200") => {
+            return (0, format!("OK: {}", status_line));
+        }
+        Ok(Ok(status_line)) => return (1, format!("UNHEALTHY: {}", status_line)),
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => "timed out waiting for /health".to_string(),
+    };
+
+    // The check can't tell whether the service is in night or power-save mode, so the widest
+    // window applies
+    let stale_after = [
+        Some(config.polling.stale_after()),
+        config.night.as_ref().map(|night| night.stale_after(&config.polling)),
+        config.power_save.as_ref().map(|power_save| power_save.stale_after(&config.polling)),
+    ].into_iter().flatten().max().unwrap_or_default();
+    match Availability::load(availability_path).last_success {
+        Some(last_success) if now.saturating_sub(last_success) <= stale_after.as_secs() => (
+            0,
+            format!("OK: {}, last poll {}s ago", unreachable, now.saturating_sub(last_success)),
+        ),
+        Some(last_success) => (
+            1,
+            format!("UNHEALTHY: {}, last poll {}s ago", unreachable, now.saturating_sub(last_success)),
+        ),
+        None => (1, format!("UNHEALTHY: {}, no persisted poll", unreachable)),
+    }
+}
+
+/// Sends a minimal HTTP/1.0 request so the same code works over TCP and unix sockets.
+async fn request_health(addr: &ListenAddr) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let request = b"GET /health HTTP/1.0\r\nHost: localhost\r\n\r\n";
+    let mut response = Vec::new();
+    match addr {
+        ListenAddr::Tcp(addr) => {
+            // A wildcard listen address is reachable through the loopback of the same family
+            let mut addr = *addr;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(if addr.is_ipv6() {
+                    std::net::Ipv6Addr::LOCALHOST.into()
+                } else {
+                    std::net::Ipv4Addr::LOCALHOST.into()
+                });
+            }
+            let mut stream = tokio::net::TcpStream::connect(addr).await?;
+            stream.write_all(request).await?;
+            stream.read_to_end(&mut response).await?;
+        }
+        ListenAddr::Unix(path) => {
+            let mut stream = tokio::net::UnixStream::connect(path).await?;
+            stream.write_all(request).await?;
+            stream.read_to_end(&mut response).await?;
+        }
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default().trim().to_string();
+    if status_line.is_empty() {
+        return Err("empty response from /health".into());
+    }
+    Ok(status_line)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Checking a signature happens wherever the response ended up, without a config
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("verify") {
+        std::process::exit(verify_command(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("validate") {
+        std::process::exit(validate_command());
+    }
+    // Replaying a bundle happens on a maintainer's machine, without the reporter's config
+    if args.get(1).map(String::as_str) == Some("diag") && args.iter().any(|arg| arg == "--load") {
+        std::process::exit(replay_command(&args[2..]));
+    }
+    // A soak runs without a config too, then only with the alerts given on the command line
+    if args.get(1).map(String::as_str) == Some("soak") {
+        let config = read_secrets()
+            .map_err(|e| println!("No config read ({}), checking only --rule alerts", e))
+            .ok();
+        std::process::exit(soak_command(&args[2..], config).await);
+    }
+
+    // Read secrets from file
+    let config = read_secrets()?;
+
+    match std::env::args().nth(1).as_deref() {
+        Some("healthcheck") => std::process::exit(healthcheck(&config).await),
+        Some("status") => std::process::exit(status_command(&config, std::env::args().any(|arg| arg == "--json")).await),
+        Some("diag") => std::process::exit(diag_command(&config, &args[2..]).await),
+        Some("export-compliance") => std::process::exit(export_compliance_command(&config, &args[2..]).await),
+        Some("report") => std::process::exit(report_command(&config, &args[2..])),
+        Some("export") => std::process::exit(export_command(&config, &args[2..]).await),
+        _ => {}
+    }
+
+    let inverter = build_inverter(&config);
+    let serial = config.serial.clone();
+    let mut poll_loop = PollLoop::new(&config, PathBuf::from(DATA_DIR));
+
+    // Create shared state for the web server
+    let mut state = AppState::from_config(&config, &inverter);
+    // Every request to the dongle goes through this one task, polls and control writes alike
+    let inverter = dongle::spawn(inverter, serial.clone());
+    let settings_inverter = inverter.clone();
+    if config.control.enabled {
+        println!("Control endpoints are enabled");
+        let control = Control::new(inverter.clone(), config.control.clone());
+        control.load_state();
+        state.control = Some(control);
+    }
+    let shared_status = Arc::new(state);
+    *shared_status.availability.write().await = Availability::load(Path::new(AVAILABILITY_PATH));
+    *shared_status.battery.write().await = BatteryThroughput::load(Path::new(BATTERY_STATS_PATH));
+    *shared_status.grid.write().await = GridEnergy::load(Path::new(GRID_STATS_PATH));
+    *shared_status.rollups.write().await = Rollups::load(Path::new(DAILY_ROLLUPS_PATH));
+    *shared_status.curtailment.write().await = Curtailment::load(Path::new(CURTAILMENT_STATS_PATH));
+    *shared_status.overnight.write().await = OvernightLoad::load(Path::new(OVERNIGHT_LOAD_PATH));
+    *shared_status.capacity.write().await = CapacityLearner::load(Path::new(BATTERY_CAPACITY_PATH));
+    if poll_loop.consumption_anomaly.is_some() {
+        *shared_status.consumption.write().await = anomaly::History::load(Path::new(CONSUMPTION_HISTORY_PATH));
+        tokio::spawn(run_consumption_baseline(shared_status.clone()));
+    }
+    poll_loop.evc = config.evc.clone().map(|evc| {
+        let mut charger = EvCharger::new(&evc.url);
+        charger.request_timeout = config.polling.request_timeout;
+        (charger, evc.password)
+    });
+    poll_loop.zabbix = config.zabbix.clone();
+    poll_loop.textfile = config.textfile.clone();
+    poll_loop.statsd = match config.statsd.clone() {
+        Some(statsd) => {
+            println!("Sending every poll to StatsD at {}", statsd.target);
+            let socket = statsd::Socket::open(&statsd.target).await?;
+            Some((statsd, socket))
+        }
+        None => None,
+    };
+    poll_loop.postgres_polls = config.postgres.clone().map(|postgres| {
+        let (sender, receiver) = tokio::sync::mpsc::channel(POSTGRES_QUEUE);
+        println!("Inserting every poll into PostgreSQL table {}", postgres.table);
+        let layout = postgres.layout;
+        tokio::spawn(run_postgres_sink(shared_status.clone(), postgres, receiver));
+        (sender, layout)
+    });
+    if let Some(redis) = config.redis.clone() {
+        println!("Writing every poll to Redis at {}:{} as {}", redis.target.host, redis.target.port, redis.key);
+        tokio::spawn(run_redis_sink(shared_status.clone(), redis, poll_loop.redis_polls.subscribe()));
+    }
+
+    if let Some(events) = config.events.clone() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(EVENTS_QUEUE);
+        let _ = shared_status.events.set(sender);
+        println!("Publishing events to {}", events.subject);
+        tokio::spawn(run_event_publisher(shared_status.clone(), events, serial.clone(), receiver));
+    }
+
+    // Clone the shared state for the background task
+    let status_clone = shared_status.clone();
+
+    // Spawn the data collection task
+    tokio::spawn(async move {
+        loop {
+            if status_clone.polling_paused.load(std::sync::atomic::Ordering::SeqCst) {
+                {
+                    let mut health = status_clone.health.write().await;
+                    health.backoff.state = "paused".to_string();
+                    health.backoff.next_poll = None;
+                }
+                status_clone.poll_now.notified().await;
+                continue;
+            }
+
+            let delay = poll_loop.poll(&status_clone, inverter.poll().await).await;
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = status_clone.poll_now.notified() => {}
+            }
+        }
+    });
+
+    if shared_status.control.is_some() {
+        tokio::spawn(run_battery_revert(shared_status.clone()));
+    }
+
+    if !config.charge_windows.is_empty() {
+        println!("Scheduling {} charge window(s)", config.charge_windows.len());
+        let forecast = (!config.forecast_planes.is_empty()).then(|| {
+            let (sender, receiver) = tokio::sync::watch::channel(BTreeMap::new());
+            tokio::spawn(run_forecast(config.forecast_planes.clone(), config.http.clone(), sender));
+            receiver
+        });
+        tokio::spawn(run_charge_windows(shared_status.clone(), config.charge_windows.clone(), config.timezone, forecast));
+    }
+
+    if !config.settings_interval.is_zero() {
+        println!("Reading the inverter settings every {}s", config.settings_interval.as_secs());
+        *shared_status.settings.write().await = Some(SettingsOutput::default());
+        tokio::spawn(run_settings_poller(shared_status.clone(), settings_inverter, config.settings_interval));
+    }
+
+    if !config.surplus.devices.is_empty() {
+        println!("Surplus controller switching {} device(s)", config.surplus.devices.len());
+        tokio::spawn(run_surplus_controller(shared_status.clone(), config.surplus.clone(), config.http.clone()));
+    }
+
+    if !config.federation.peers.is_empty() {
+        println!("Polling {} federation peer(s)", config.federation.peers.len());
+        tokio::spawn(run_federation(shared_status.clone(), config.federation.interval, config.http.clone()));
+    }
+
+    if let Some(mqtt) = config.mqtt.clone() {
+        tokio::spawn(run_mqtt_commands(shared_status.clone(), mqtt, serial));
+    }
+
+    // Present the battery as a UPS to NUT clients
+    for addr in &config.nut.listen_addrs {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        println!("Starting NUT server on {}", addr);
+        tokio::spawn(serve_nut(listener, shared_status.clone(), config.nut.clone()));
+    }
+
+    // Answer `apcaccess status` for hosts that only ship apcupsd
+    for addr in &config.apcupsd.listen_addrs {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        println!("Starting apcupsd NIS server on {}", addr);
+        tokio::spawn(serve_apcupsd(listener, shared_status.clone(), config.apcupsd.clone()));
+    }
+
+    // Create the router; the unversioned paths are aliases of /v1
+    let limiter = Arc::new(RateLimiter::new(config.http_limits.clone()));
+    let app = Router::new()
+        .nest("/v1", api_routes())
+        .merge(api_routes())
+        .with_state(shared_status.clone())
+        .layer(axum::middleware::from_fn_with_state(limiter, rate_limit))
+        .layer(axum::middleware::from_fn_with_state(
+            RequestLog { state: shared_status, enabled: config.http_log },
+            log_request,
+        ));
+    let connection_slots = (config.http_limits.max_connections > 0)
+        .then(|| Arc::new(tokio::sync::Semaphore::new(config.http_limits.max_connections)));
+
+    // Start one server per listen address, all sharing the same router and connection cap
+    let mut servers = tokio::task::JoinSet::new();
+    for addr in &config.listen_addrs {
+        let slots = connection_slots.clone();
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                let listener = tokio::net::TcpListener::bind(addr).await?;
+                let accept = hyper::server::accept::poll_fn(move |cx| {
+                    listener.poll_accept(cx).map(|result| {
+                        Some(result.map(|(stream, peer)| LimitedConnection::new(stream, Some(peer), slots.as_ref())))
+                    })
+                });
+                let server = axum::Server::builder(accept)
+                    .serve(app.clone().into_make_service_with_connect_info::<Peer>());
+                println!("Starting server on http://{}", addr);
+                servers.spawn(server);
+            }
+            ListenAddr::Unix(path) => {
+                let listener = bind_unix_socket(path, &config.socket)?;
+                let accept = hyper::server::accept::poll_fn(move |cx| {
+                    listener.poll_accept(cx).map(|result| {
+                        Some(result.map(|(stream, _)| LimitedConnection::new(stream, None, slots.as_ref())))
+                    })
+                });
+                let server = axum::Server::builder(accept)
+                    .serve(app.clone().into_make_service_with_connect_info::<Peer>());
+                println!("Starting server on unix:{}", path.display());
+                servers.spawn(server);
+            }
+        }
+    }
+
+    // The servers only return on error, so the first one to finish stops the service
+    if let Some(result) = servers.join_next().await {
+        result??;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solax_mon::inverter::InverterResponse;
+
+    fn decode_fixture(json: &str) -> Snapshot {
+        let response: InverterResponse = serde_json::from_str(json).unwrap();
+        X3HybridG4::new(&[], Duration::ZERO).decode(&response)
+    }
+
+    /// Reads one NIS response the way apcaccess does: length-prefixed records until a
+    /// zero-length one, each split into a key and value at the first colon.
+    async fn apcaccess_status(addr: SocketAddr) -> HashMap<String, String> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&6u16.to_be_bytes()).await.unwrap();
+        stream.write_all(b"status").await.unwrap();
+
+        let mut fields = HashMap::new();
+        loop {
+            let length = stream.read_u16().await.unwrap();
+            if length == 0 {
+                break;
+            }
+            let mut record = vec![0; length as usize];
+            stream.read_exact(&mut record).await.unwrap();
+            let record = String::from_utf8(record).unwrap();
+            let (key, value) = record.split_once(':').unwrap();
+            fields.insert(key.trim().to_string(), value.trim().to_string());
+        }
+        fields
+    }
+
+    /// The fixture with the given readings replaced; a `None` run mode removes it.
+    fn ups_snapshot(run_mode: Option<f64>, grid_w: f64, battery_w: f64, charge: f64) -> Snapshot {
+        use solax_mon::inverter::{Measurement, Units};
+
+        let mut snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+        let measurements = &mut snapshot.measurements;
+        match run_mode {
+            Some(mode) => measurements.insert("Run Mode".to_string(), Measurement::new(mode, Units::None)),
+            None => measurements.remove("Run Mode"),
+        };
+        measurements.insert("Grid Power".to_string(), Measurement::new(grid_w, Units::W));
+        measurements.insert("Battery Power".to_string(), Measurement::new(battery_w, Units::W));
+        measurements.insert("Battery Remaining Capacity".to_string(), Measurement::new(charge, Units::Percent));
+        snapshot
+    }
+
+    #[test]
+    fn nut_words_honour_quotes() {
+        assert_eq!(nut_words("  GET   VAR solax ups.status "), ["GET", "VAR", "solax", "ups.status"]);
+        assert_eq!(
+            nut_words(r#"PASSWORD "two words" "say \"hi\"\\""#),
+            ["PASSWORD", "two words", r#"say "hi"\"#]
+        );
+        assert_eq!(nut_words(r#"USERNAME a"b c"d"#), ["USERNAME", "ab cd"]);
+        assert!(nut_words("   ").is_empty());
+    }
+
+    #[test]
+    fn nut_answers_each_command() {
+        let config = NutConfig {
+            username: Some("monuser".to_string()),
+            password: Some("secret".to_string()),
+            ..NutConfig::default()
+        };
+        let snapshot = ups_snapshot(Some(2.0), -300.0, 0.0, 55.0);
+        let mut session = NutSession::default();
+        let mut ask = |line: &str, snapshot: Option<&Snapshot>| nut_response(line, &mut session, &config, snapshot, 1);
+
+        assert_eq!(ask("LIST UPS", None).unwrap(), "BEGIN LIST UPS\nUPS solax \"SolaX inverter battery\"\nEND LIST UPS");
+        let vars = ask("LIST VAR solax", Some(&snapshot)).unwrap();
+        let lines: Vec<&str> = vars.lines().collect();
+        assert_eq!(lines[0], "BEGIN LIST VAR solax");
+        assert_eq!(lines[lines.len() - 1], "END LIST VAR solax");
+        assert!(lines.contains(&"VAR solax battery.charge \"55\""), "{}", vars);
+        assert!(lines.contains(&"VAR solax ups.status \"OL\""), "{}", vars);
+        assert_eq!(ask("LIST VAR solax", None).unwrap(), "ERR DATA-STALE");
+        assert_eq!(ask("LIST VAR other", Some(&snapshot)).unwrap(), "ERR UNKNOWN-UPS");
+
+        assert_eq!(ask("GET VAR solax battery.charge", Some(&snapshot)).unwrap(), "VAR solax battery.charge \"55\"");
+        assert_eq!(ask("GET VAR solax \"ups.status\"", Some(&snapshot)).unwrap(), "VAR solax ups.status \"OL\"");
+        assert_eq!(ask("GET VAR solax ups.beeper.status", Some(&snapshot)).unwrap(), "ERR VAR-NOT-SUPPORTED");
+        assert_eq!(ask("GET VAR other battery.charge", Some(&snapshot)).unwrap(), "ERR UNKNOWN-UPS");
+        assert_eq!(ask("GET NUMLOGINS solax", None).unwrap(), "NUMLOGINS solax 1");
+
+        assert_eq!(ask("LOGIN solax", None).unwrap(), "ERR ACCESS-DENIED");
+        assert_eq!(ask("USERNAME monuser", None).unwrap(), "OK");
+        assert_eq!(ask("PASSWORD wrong", None).unwrap(), "OK");
+        assert_eq!(ask("LOGIN solax", None).unwrap(), "ERR ACCESS-DENIED");
+        assert_eq!(ask("PASSWORD secret", None).unwrap(), "OK");
+        assert_eq!(ask("LOGIN other", None).unwrap(), "ERR UNKNOWN-UPS");
+        assert_eq!(ask("LOGIN solax", None).unwrap(), "OK");
+        assert_eq!(ask("SHUTDOWN now", None).unwrap(), "ERR UNKNOWN-COMMAND");
+        assert_eq!(ask("LOGOUT", None), None);
+        assert!(session.logged_in);
+    }
+
+    #[test]
+    fn nut_status_follows_the_run_mode() {
+        let config = NutConfig::default();
+        let status = |snapshot: Snapshot| {
+            nut_variables(&snapshot, &config).into_iter()
+                .find(|(var, _)| *var == "ups.status")
+                .unwrap().1
+        };
+        // A house in balance draws nothing from the grid but is still on it
+        assert_eq!(status(ups_snapshot(Some(2.0), 0.0, 0.0, 55.0)), "OL");
+        assert_eq!(status(ups_snapshot(Some(2.0), -300.0, 1200.0, 55.0)), "OL CHRG");
+        assert_eq!(status(ups_snapshot(Some(2.0), 300.0, 0.0, 5.0)), "OL LB");
+        assert_eq!(status(ups_snapshot(Some(7.0), 0.0, -800.0, 55.0)), "OB DISCHRG");
+        assert_eq!(status(ups_snapshot(Some(7.0), 0.0, -800.0, 9.0)), "OB DISCHRG LB");
+        // Without a run mode, no grid power is the only sign of an outage
+        assert_eq!(status(ups_snapshot(None, 0.0, -800.0, 55.0)), "OB DISCHRG");
+        assert_eq!(status(ups_snapshot(None, 250.0, 0.0, 55.0)), "OL");
+    }
+
+    #[test]
+    fn apcupsd_status_follows_the_run_mode() {
+        let config = ApcupsdConfig::default();
+        let status = |snapshot: Snapshot| {
+            apcupsd_status_records(&snapshot, &config, None, 1_750_000_000).into_iter()
+                .find_map(|record| record.strip_prefix("STATUS   : ").map(|value| value.trim_end().to_string()))
+                .unwrap()
+        };
+        // On the grid with the house in balance: no grid power, but not on battery
+        assert_eq!(status(ups_snapshot(Some(2.0), 0.0, 0.0, 55.0)), "ONLINE");
+        assert_eq!(status(ups_snapshot(Some(2.0), 0.0, 0.0, 5.0)), "ONLINE LOWBATT");
+        assert_eq!(status(ups_snapshot(Some(7.0), 0.0, -800.0, 55.0)), "ONBATT");
+        assert_eq!(status(ups_snapshot(None, 0.0, -800.0, 55.0)), "ONBATT");
+        assert_eq!(status(ups_snapshot(None, 250.0, 0.0, 55.0)), "ONLINE");
+    }
+
+    #[tokio::test]
+    async fn apcupsd_nis_status_is_parseable() {
+        let mut state = AppState::new(Vec::new(), Duration::from_secs(180));
+        state.battery_capacity_kwh = Some(10.0);
+        let state = Arc::new(state);
+        state.health.write().await.last_success = Some(unix_now());
+        let snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+        state.latest.modify(|latest| latest.snapshot = Some(snapshot));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_apcupsd(listener, state, ApcupsdConfig::default()));
+
+        let fields = apcaccess_status(addr).await;
+        assert_eq!(fields["STATUS"], "ONLINE");
+        assert_eq!(fields["BCHARGE"], "55.0 Percent");
+        assert_eq!(fields["LINEV"], "230.1 Volts");
+        // 5.5 kWh left at a 200 W discharge
+        assert_eq!(fields["TIMELEFT"], "1650.0 Minutes");
+        assert!(fields["APC"].starts_with("001,"));
+        assert!(fields.contains_key("END APC"));
+    }
+
+    #[tokio::test]
+    async fn snapshots_go_stale_on_the_clock() {
+        let clock = Arc::new(ManualClock::new(1_750_000_000));
+        let mut state = AppState::new(Vec::new(), Duration::from_secs(180));
+        state.clock = clock.clone();
+        let state = Arc::new(state);
+        state.health.write().await.last_success = Some(clock.unix_now());
+        let snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+        state.latest.modify(|latest| latest.snapshot = Some(snapshot));
+
+        clock.advance(Duration::from_secs(180));
+        assert!(state.fresh_snapshot().await.is_some());
+        assert!(get_health(State(state.clone())).await.1.healthy);
+        clock.advance(Duration::from_secs(1));
+        assert!(state.fresh_snapshot().await.is_none());
+        assert!(!get_health(State(state)).await.1.healthy);
+    }
+
+    #[tokio::test]
+    async fn apcupsd_nis_reports_commlost_without_data() {
+        let state = Arc::new(AppState::new(Vec::new(), Duration::from_secs(180)));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_apcupsd(listener, state, ApcupsdConfig::default()));
+
+        assert_eq!(apcaccess_status(addr).await["STATUS"], "COMMLOST");
+    }
+
+    fn publish_grid_power(state: &AppState, value: f64) {
+        let mut raw = RawOutput::default();
+        raw.measurements.insert("Grid Power".to_string(), RawMeasurement::new(value, "W"));
+        state.latest.modify(|latest| latest.raw = raw);
+    }
+
+    async fn read_raw(state: &Arc<AppState>, wait: Option<u64>) -> RawOutput {
+        let query = axum::extract::Query(RawQuery { wait });
+        get_raw_status(State(state.clone()), query).await.unwrap().0
+    }
+
+    #[tokio::test]
+    async fn raw_status_waits_for_the_next_poll() {
+        let state = Arc::new(AppState::new(Vec::new(), Duration::from_secs(180)));
+        publish_grid_power(&state, 1.0);
+        let waiting = tokio::spawn({
+            let state = state.clone();
+            async move { read_raw(&state, Some(30)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        publish_grid_power(&state, 2.0);
+        let raw = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert_eq!(raw.measurements["Grid Power"].value, 2.0);
+        assert_eq!(read_raw(&state, None).await.measurements["Grid Power"].value, 2.0);
+    }
+
+    /// Many readers against a poller publishing as fast as it can: no read waits on the writer.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn readers_are_not_held_up_by_a_publishing_poller() {
+        let state = Arc::new(AppState::new(Vec::new(), Duration::from_secs(180)));
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = tokio::spawn({
+            let (state, done) = (state.clone(), done.clone());
+            async move {
+                let mut published = 0;
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    publish_grid_power(&state, published as f64);
+                    published += 1;
+                    tokio::task::yield_now().await;
+                }
+                published
+            }
+        });
+        let readers: Vec<_> = (0..64).map(|_| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let mut slowest = Duration::ZERO;
+                for _ in 0..200 {
+                    let started = Instant::now();
+                    read_raw(&state, None).await;
+                    slowest = slowest.max(started.elapsed());
+                }
+                slowest
+            })
+        }).collect();
+        let mut slowest = Duration::ZERO;
+        for reader in readers {
+            slowest = slowest.max(reader.await.unwrap());
+        }
+        done.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(writer.await.unwrap() > 0);
+        // A generous bound for a loaded debug build; a starved reader would take far longer
+        assert!(slowest < Duration::from_millis(250), "slowest read took {:?}", slowest);
+    }
+
+    #[test]
+    fn export_limit_checks() {
+        let mut headers = axum::http::HeaderMap::new();
+        assert!(!bearer_matches(&headers, "s3cret"));
+        headers.insert(axum::http::header::AUTHORIZATION, "Bearer s3cre".parse().unwrap());
+        assert!(!bearer_matches(&headers, "s3cret"));
+        headers.insert(axum::http::header::AUTHORIZATION, "Bearer s3cret".parse().unwrap());
+        assert!(bearer_matches(&headers, "s3cret"));
+
+        assert!(check_export_limit(4000, Some(10.0), Some(RunMode::Normal)).is_ok());
+        assert!(check_export_limit(10000, Some(10.0), None).is_ok());
+        assert_eq!(check_export_limit(10001, Some(10.0), Some(RunMode::Normal)).unwrap_err().0, StatusCode::BAD_REQUEST);
+        assert_eq!(check_export_limit(4000, None, Some(RunMode::Normal)).unwrap_err().0, StatusCode::CONFLICT);
+        assert_eq!(check_export_limit(4000, Some(10.0), Some(RunMode::Fault)).unwrap_err().0, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn debug_decode_is_behind_its_token() {
+        let decode = |state: &Arc<AppState>, token: Option<&str>| {
+            let mut headers = axum::http::HeaderMap::new();
+            if let Some(token) = token {
+                headers.insert(axum::http::header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+            }
+            get_debug_decode(State(state.clone()), headers)
+        };
+        let disabled = Arc::new(AppState::new(Vec::new(), Duration::from_secs(180)));
+        assert_eq!(decode(&disabled, Some("s3cret")).await.unwrap_err().0, StatusCode::NOT_FOUND);
+
+        let mut state = AppState::new(Vec::new(), Duration::from_secs(180));
+        state.debug_token = Some("s3cret".to_string());
+        let state = Arc::new(state);
+        assert_eq!(decode(&state, None).await.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        assert_eq!(decode(&state, Some("s3cret")).await.unwrap_err().0, StatusCode::SERVICE_UNAVAILABLE);
+        *state.decode.write().await = Some(DecodeOutput { seq: 3, data_len: 300, ..DecodeOutput::default() });
+        let Json(output) = decode(&state, Some("s3cret")).await.unwrap();
+        assert_eq!((output.seq, output.data_len), (3, 300));
+    }
+
+    #[tokio::test]
+    async fn external_readings_are_behind_their_token() {
+        let push = |state: &Arc<AppState>, token: Option<&str>, source: &str| {
+            let mut headers = axum::http::HeaderMap::new();
+            if let Some(token) = token {
+                headers.insert(axum::http::header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+            }
+            let reading = external::Reading { source: source.to_string(), watts: 1600.0, timestamp: None };
+            post_external(State(state.clone()), headers, Json(reading))
+        };
+        let disabled = Arc::new(AppState::new(Vec::new(), Duration::from_secs(180)));
+        assert_eq!(push(&disabled, Some("s3cret"), "heat_pump").await.unwrap_err().0, StatusCode::NOT_FOUND);
+
+        let mut state = AppState::new(Vec::new(), Duration::from_secs(180));
+        state.ingest_token = Some("s3cret".to_string());
+        state.external = RwLock::new(external::Meters::new(vec![external::Meter::parse("heat_pump,name=Heat Pump Power").unwrap()]));
+        let state = Arc::new(state);
+        assert_eq!(push(&state, Some("wrong"), "heat_pump").await.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        assert_eq!(push(&state, Some("s3cret"), "garage").await.unwrap_err().0, StatusCode::BAD_REQUEST);
+        assert_eq!(push(&state, Some("s3cret"), "heat_pump").await.unwrap(), StatusCode::NO_CONTENT);
+        let fresh = state.external.read().await.fresh(unix_now());
+        assert_eq!(fresh.iter().map(|(name, watts, _)| (name.as_str(), *watts)).collect::<Vec<_>>(), [("Heat Pump Power", 1600.0)]);
+    }
+
+    #[tokio::test]
+    async fn ended_periods_are_reported_from_the_cache() {
+        let state = Arc::new(AppState::new(Vec::new(), Duration::from_secs(180)));
+        state.rollups.write().await.days.push(solax_mon::rollup::DayRollup {
+            date: chrono::NaiveDate::from_ymd_opt(2025, 6, 21).unwrap(),
+            generated_kwh: 41.3,
+            ..Default::default()
+        });
+        let query = |month: &str, format: Option<&str>| axum::extract::Query(ReportQuery {
+            month: Some(month.to_string()),
+            year: None,
+            format: format.map(str::to_string),
+        });
+        let body = |response: axum::response::Response| async {
+            String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap()
+        };
+
+        let response = get_monthly_report(State(state.clone()), query("2025-06", None)).await.unwrap();
+        let report: ReportOutput = serde_json::from_str(&body(response).await).unwrap();
+        assert!(!report.partial);
+        assert_eq!(report.best_day, Some(ReportDay { date: chrono::NaiveDate::from_ymd_opt(2025, 6, 21).unwrap(), generated_kwh: 41.3 }));
+        // Later rollups don't change a period that has ended
+        state.rollups.write().await.days.clear();
+        let html = body(get_monthly_report(State(state.clone()), query("2025-06", Some("html"))).await.unwrap()).await;
+        assert!(html.contains("<tr><th align=\"left\">Generated</th><td>41.3 kWh</td></tr>"), "{}", html);
+
+        let error = get_monthly_report(State(state.clone()), query("June", None)).await.unwrap_err();
+        assert_eq!(error.0, StatusCode::BAD_REQUEST);
+        let error = get_monthly_report(State(state.clone()), query("2025-06", Some("pdf"))).await.unwrap_err();
+        assert_eq!(error.0, StatusCode::BAD_REQUEST);
+        let text = render_report_text(&state.report(Period::Month(parse_month("2099-01").unwrap())).await);
+        assert!(text.starts_with("Period              2099-01 (partial)\n"), "{}", text);
+        assert!(text.contains("Best day            -\n"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn changes_keep_the_last_diff() {
+        use solax_mon::status::Change;
+
+        let state = Arc::new(AppState::new(Vec::new(), Duration::from_secs(180)));
+        assert_eq!(get_changes(State(state.clone())).await.unwrap_err(), StatusCode::NO_CONTENT);
+        let change = Change::State { name: "grid".to_string(), from: "importing".to_string(), to: "exporting".to_string() };
+        publish_changes(&state, SnapshotDiff { seq: 5, previous_seq: 4, time: 1_700_000_000, changes: vec![change.clone()] }).await;
+        let Json(diff) = get_changes(State(state)).await.unwrap();
+        assert_eq!((diff.seq, diff.changes), (5, vec![change]));
+    }
+
+    /// A dongle keeping the work mode settings written to it and answering reads with them,
+    /// which refuses as many writes as the returned counter says.
+    async fn settings_dongle() -> (String, Arc<std::sync::atomic::AtomicUsize>, Arc<std::sync::Mutex<Vec<i64>>>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let refusals = Arc::new(AtomicUsize::new(0));
+        let settings = Arc::new(std::sync::Mutex::new(vec![0; 40]));
+        let (left, held) = (refusals.clone(), settings.clone());
+        let app = axum::Router::new().route("/", axum::routing::post(move |axum::Form(form): axum::Form<HashMap<String, String>>| {
+            let (refusals, settings) = (left.clone(), held.clone());
+            async move {
+                if form["optType"] == "ReadSetData" {
+                    return serde_json::json!({ "Data": *settings.lock().unwrap() }).to_string();
+                }
+                if refusals.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                    return "N".to_string();
+                }
+                let data: serde_json::Value = serde_json::from_str(&form["data"]).unwrap();
+                for write in data["Data"].as_array().unwrap() {
+                    let index = match write["reg"].as_u64() {
+                        Some(0x1F) => 7,
+                        Some(0x20) => 8,
+                        _ => continue,
+                    };
+                    settings.lock().unwrap()[index] = write["val"].as_str().unwrap().parse().unwrap();
+                }
+                "Y".to_string()
+            }
+        }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        (url, refusals, settings)
+    }
+
+    #[tokio::test]
+    async fn forced_battery_modes_revert_across_restarts_and_failures() {
+        use std::sync::atomic::Ordering;
+
+        let (url, refusals, settings) = settings_dongle().await;
+        let path = std::env::temp_dir().join(format!("solax-control-{}.json", std::process::id()));
+        let clock = Arc::new(ManualClock::new(1_750_000_000));
+        // A fresh service on the saved control state
+        let start = || {
+            let mut state = AppState::new(Vec::new(), Duration::from_secs(180));
+            state.clock = clock.clone();
+            let mut control = Control::new(
+                dongle::spawn(X3HybridG4::new(std::slice::from_ref(&url), Duration::ZERO), "SXXXXXXXXX".to_string()),
+                ControlConfig { enabled: true, ..ControlConfig::default() },
+            );
+            control.state_path = path.clone();
+            control.load_state();
+            state.control = Some(control);
+            state.latest.modify(|latest| latest.snapshot = Some(decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"))));
+            Arc::new(state)
+        };
+        let work_mode = || settings.lock().unwrap()[7..9].to_vec();
+
+        let state = start();
+        state.health.write().await.last_success = Some(clock.unix_now());
+        let control = state.control.as_ref().unwrap();
+        let output = start_battery_mode(&state, control, BatteryMode::ForceCharge, Some(600), false, "test").await.unwrap();
+        assert_eq!(output.revert_at, Some(1_750_000_600));
+        assert_eq!(step_battery_revert(&state, control).await, Some(1_750_000_600));
+        assert_eq!(work_mode(), [3, 1]);
+
+        // Restarted after the revert was due, with the inverter refusing the first two tries
+        clock.advance_to(1_750_000_900);
+        refusals.store(2, Ordering::SeqCst);
+        let state = start();
+        let control = state.control.as_ref().unwrap();
+        assert_eq!(control.pending_revert().map(|revert| revert.revert_at), Some(1_750_000_600));
+        assert_eq!(step_battery_revert(&state, control).await, Some(1_750_000_930));
+        assert_eq!(step_battery_revert(&state, control).await, Some(1_750_000_930));
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(step_battery_revert(&state, control).await, Some(1_750_000_990));
+        assert_eq!(work_mode(), [3, 1]);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(step_battery_revert(&state, control).await, None);
+        assert_eq!(work_mode(), [0, 0]);
+        assert_eq!(start().control.as_ref().unwrap().pending_revert(), None);
+
+        // A mode set in between cancels the revert
+        state.health.write().await.last_success = Some(clock.unix_now());
+        start_battery_mode(&state, control, BatteryMode::ForceDischarge, Some(60), false, "test").await.unwrap();
+        start_battery_mode(&state, control, BatteryMode::StopForce, None, false, "test").await.unwrap();
+        assert_eq!(step_battery_revert(&state, control).await, None);
+        assert_eq!(work_mode(), [3, 0]);
+
+        // Given up on after REVERT_ATTEMPTS refused writes
+        start_battery_mode(&state, control, BatteryMode::ForceDischarge, Some(60), false, "test").await.unwrap();
+        refusals.store(REVERT_ATTEMPTS as usize, Ordering::SeqCst);
+        let mut due_at = step_battery_revert(&state, control).await;
+        for _ in 0..REVERT_ATTEMPTS {
+            clock.advance_to(due_at.unwrap());
+            due_at = step_battery_revert(&state, control).await;
+        }
+        assert_eq!(due_at, None);
+        assert_eq!(refusals.load(Ordering::SeqCst), 0);
+        assert_eq!(work_mode(), [3, 2]);
+        assert_eq!(start().control.as_ref().unwrap().pending_revert(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn export_limit_requires_control_and_token() {
+        let request = || Json(ExportLimitRequest { watts: 4000 });
+        let state = Arc::new(AppState::new(Vec::new(), Duration::from_secs(180)));
+        let error = set_export_limit(State(state), None, axum::http::HeaderMap::new(), request()).await.unwrap_err();
+        assert_eq!(error.0, StatusCode::NOT_FOUND);
+
+        let mut state = AppState::new(Vec::new(), Duration::from_secs(180));
+        state.control = Some(Control::new(
+            dongle::spawn(X3HybridG4::new(&[], Duration::ZERO), "SXXXXXXXXX".to_string()),
+            ControlConfig { enabled: true, token: Some("s3cret".to_string()), ..ControlConfig::default() },
+        ));
+        let state = Arc::new(state);
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(axum::http::header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        let error = set_export_limit(State(state.clone()), None, headers, request()).await.unwrap_err();
+        assert_eq!(error.0, StatusCode::UNAUTHORIZED);
+
+        // Authorized, but there is nothing to check the request against yet
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(axum::http::header::AUTHORIZATION, "Bearer s3cret".parse().unwrap());
+        let error = set_export_limit(State(state.clone()), None, headers.clone(), request()).await.unwrap_err();
+        assert_eq!(error.0, StatusCode::SERVICE_UNAVAILABLE);
+
+        let battery = |mode, revert_after_secs| Json(BatteryModeRequest { mode, revert_after_secs, override_temperature: false });
+        let error = set_battery_mode(State(state.clone()), None, headers.clone(), battery(BatteryMode::SelfUse, Some(60)))
+            .await.unwrap_err();
+        assert_eq!(error.0, StatusCode::BAD_REQUEST);
+        let error = set_battery_mode(State(state.clone()), None, headers.clone(), battery(BatteryMode::ForceCharge, None))
+            .await.unwrap_err();
+        assert_eq!(error.0, StatusCode::SERVICE_UNAVAILABLE);
+
+        // A battery below CHARGE_TEMP_FLOOR_C isn't force-charged without the override
+        let cold = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4_boundaries.json"));
+        state.latest.publish(Published { snapshot: Some(cold), ..(*state.latest()).clone() });
+        state.health.write().await.last_success = Some(unix_now());
+        let (status, error) = set_battery_mode(State(state.clone()), None, headers, battery(BatteryMode::ForceCharge, None))
+            .await.unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(error.contains("battery at -5.0 °C"), "{}", error);
+
+        // A revert timer from before another mode change doesn't touch the inverter
+        let control = state.control.as_ref().unwrap();
+        control.battery_mode_generation.store(2, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(apply_battery_mode(&state, control, BatteryMode::SelfUse, "revert timer", Some(1)).await, Ok(None));
+    }
+
+    #[test]
+    fn evc_metrics_carry_the_device_label() {
+        let response: solax_mon::evc::EvcResponse =
+            serde_json::from_str(include_str!("../tests/fixtures/x3_evc.json")).unwrap();
+        let evc = solax_mon::evc::EvcSnapshot::decode(&response).unwrap().to_output(None);
+        let metrics = render_evc_metrics(&evc);
+        assert!(metrics.contains("solax_evc_charging_power_watts{device=\"evc\",sn=\"C3XXXXXXXX\"} 11030\n"));
+        assert!(metrics.contains("solax_evc_charging{device=\"evc\",sn=\"C3XXXXXXXX\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn battery_modules_need_decoded_modules() {
+        let state = Arc::new(AppState::new(Vec::new(), Duration::from_secs(180)));
+        assert_eq!(get_battery_modules(State(state.clone())).await.unwrap_err(), StatusCode::NOT_FOUND);
+
+        let mut snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+        snapshot.battery_modules = vec![
+            BatteryModule { module: 1, soc_pct: 62.0, voltage_v: 51.2, temperature_c: 21.0 },
+            BatteryModule { module: 2, soc_pct: 48.0, voltage_v: 50.8, temperature_c: -3.0 },
+        ];
+        state.latest.publish(Published { snapshot: Some(snapshot.clone()), ..(*state.latest()).clone() });
+        let Json(output) = get_battery_modules(State(state)).await.unwrap();
+        assert_eq!(output.modules.len(), 2);
+        assert!(!output.drifting);
+
+        let labels = BTreeMap::from([("sn".to_string(), "H34XXXXXXX".to_string())]);
+        let metrics = render_module_metrics(&labels, &snapshot.battery_modules);
+        assert!(metrics.contains("solax_battery_module_soc_percent{module=\"1\",sn=\"H34XXXXXXX\"} 62\n"), "{}", metrics);
+        assert!(metrics.contains("solax_battery_module_temperature_celsius{module=\"2\",sn=\"H34XXXXXXX\"} -3\n"), "{}", metrics);
+    }
+
+    #[test]
+    fn charge_window_parsing_and_overlaps() {
+        let window = ChargeWindow::parse("02:00-05:00,days=mon-fri,soc=90,power=3000").unwrap();
+        assert_eq!(window.days.len(), 5);
+        assert_eq!((window.target_soc, window.max_power_w), (90.0, Some(3000)));
+        let weekend = ChargeWindow::parse("23:00-01:00,days=sat+sun").unwrap();
+        assert_eq!(weekend.days, vec![chrono::Weekday::Sat, chrono::Weekday::Sun]);
+        assert_eq!(weekend.target_soc, 100.0);
+        assert!(ChargeWindow::parse("02:00").is_err());
+        assert!(ChargeWindow::parse("02:00-02:00").is_err());
+        assert!(ChargeWindow::parse("02:00-05:00,soc=120").is_err());
+        assert!(ChargeWindow::parse("02:00-05:00,days=someday").is_err());
+
+        assert!(validate_charge_windows(&[window.clone(), weekend.clone()]).is_ok());
+        // Sunday 23:00 runs into Monday morning
+        let monday = ChargeWindow::parse("00:30-02:00,days=mon").unwrap();
+        assert!(validate_charge_windows(&[weekend.clone(), monday]).is_err());
+        let late = ChargeWindow::parse("04:00-06:00,days=fri").unwrap();
+        let error = validate_charge_windows(&[window, late]).unwrap_err();
+        assert_eq!(error, "Charge windows 02:00-05:00 and 04:00-06:00 overlap");
+    }
+
+    #[test]
+    fn charge_windows_hold_while_charging_is_inhibited() {
+        let at = |time: &str| local_instant(
+            chrono_tz::UTC,
+            chrono::NaiveDate::from_ymd_opt(2026, 1, 14).unwrap(),
+            chrono::NaiveTime::parse_from_str(time, "%H:%M").unwrap(),
+        );
+        let mut scheduler = ChargeScheduler::new(vec![ChargeWindow::parse("02:00-05:00").unwrap()]);
+        assert_eq!(scheduler.step(at("02:00"), Some(40.0), true), Some(ChargeTransition::Held(0)));
+        assert_eq!(scheduler.step(at("02:30"), Some(40.0), false), Some(ChargeTransition::Start(0)));
+        assert_eq!(
+            scheduler.step(at("03:00"), Some(50.0), true),
+            Some(ChargeTransition::Stop { window: 0, reason: "charging inhibited" })
+        );
+        // Resumes once the battery has warmed up, while the window lasts
+        assert_eq!(scheduler.step(at("03:30"), Some(50.0), true), Some(ChargeTransition::Held(0)));
+        assert_eq!(scheduler.step(at("04:00"), Some(50.0), false), Some(ChargeTransition::Start(0)));
+        assert_eq!(
+            scheduler.step(at("05:00"), Some(60.0), true),
+            Some(ChargeTransition::Stop { window: 0, reason: "window ended" })
+        );
+        assert_eq!(scheduler.step(at("05:30"), Some(60.0), true), None);
+    }
+
+    #[test]
+    fn charge_scheduler_transitions() {
+        let at = |day: u32, time: &str| local_instant(
+            chrono_tz::UTC,
+            chrono::NaiveDate::from_ymd_opt(2026, 1, day).unwrap(),
+            chrono::NaiveTime::parse_from_str(time, "%H:%M").unwrap(),
+        );
+        // 2026-01-03 is a Saturday
+        let window = ChargeWindow::parse("23:00-02:00,days=sat,soc=90").unwrap();
+        let mut scheduler = ChargeScheduler::new(vec![window]);
+
+        assert_eq!(scheduler.step(at(3, "22:59"), Some(40.0), false), None);
+        assert_eq!(scheduler.step(at(3, "23:00"), None, false), None);
+        assert_eq!(scheduler.step(at(3, "23:00"), Some(40.0), false), Some(ChargeTransition::Start(0)));
+        assert_eq!(scheduler.step(at(4, "01:30"), Some(80.0), false), None);
+        assert_eq!(
+            scheduler.step(at(4, "02:00"), Some(85.0), false),
+            Some(ChargeTransition::Stop { window: 0, reason: "window ended" })
+        );
+        // Not on Sunday night
+        assert_eq!(scheduler.step(at(4, "23:30"), Some(40.0), false), None);
+
+        assert_eq!(scheduler.step(at(10, "23:00"), Some(40.0), false), Some(ChargeTransition::Start(0)));
+        assert_eq!(
+            scheduler.step(at(10, "23:50"), Some(90.0), false),
+            Some(ChargeTransition::Stop { window: 0, reason: "target SoC reached" })
+        );
+        // The same night doesn't start again when the SoC drops back
+        assert_eq!(scheduler.step(at(11, "00:30"), Some(85.0), false), None);
+
+        // Already full at the start: skipped entirely
+        assert_eq!(scheduler.step(at(17, "23:00"), Some(95.0), false), None);
+        assert_eq!(scheduler.step(at(17, "23:30"), Some(60.0), false), None);
+    }
+
+    #[test]
+    fn charge_windows_skip_sunny_days() {
+        let date = |day| chrono::NaiveDate::from_ymd_opt(2026, 1, day).unwrap();
+        let at = |day, time: &str| local_instant(chrono_tz::UTC, date(day), chrono::NaiveTime::parse_from_str(time, "%H:%M").unwrap());
+        let window = ChargeWindow::parse("02:00-05:00,skip_forecast_kwh=20").unwrap();
+        assert_eq!(window.skip_forecast_kwh, Some(20.0));
+        assert!(ChargeWindow::parse("02:00-05:00,skip_forecast_kwh=-1").is_err());
+        // Morning windows look at the same day, evening ones and those ending later at the next
+        assert_eq!(window.forecast_date(date(14)), Some(date(14)));
+        assert_eq!(ChargeWindow::parse("22:00-06:00").unwrap().forecast_date(date(14)), Some(date(15)));
+        assert_eq!(ChargeWindow::parse("13:00-16:00").unwrap().forecast_date(date(14)), Some(date(15)));
+
+        let mut scheduler = ChargeScheduler::new(vec![window]);
+        scheduler.forecast = BTreeMap::from([(date(14), 24.5), (date(15), 8.0)]);
+        assert_eq!(scheduler.step(at(14, "02:00"), Some(40.0), false), Some(ChargeTransition::Skipped { window: 0, forecast_kwh: 24.5 }));
+        assert_eq!(scheduler.step(at(14, "02:30"), Some(40.0), false), None);
+        assert_eq!(scheduler.step(at(15, "02:00"), Some(40.0), false), Some(ChargeTransition::Start(0)));
+        // Without a forecast for the day the window charges as usual
+        scheduler.step(at(15, "05:00"), Some(60.0), false);
+        assert_eq!(scheduler.step(at(16, "02:00"), Some(40.0), false), Some(ChargeTransition::Start(0)));
+    }
+
+    #[test]
+    fn charge_windows_across_dst_changes() {
+        let prague = chrono_tz::Europe::Prague;
+        let utc = |text: &str| chrono::DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&prague);
+        let time = |text: &str| chrono::NaiveTime::parse_from_str(text, "%H:%M").unwrap();
+        let spring = chrono::NaiveDate::from_ymd_opt(2026, 3, 29).unwrap();
+        let autumn = chrono::NaiveDate::from_ymd_opt(2026, 10, 25).unwrap();
+        // 02:30 doesn't exist on the spring day and happens twice on the autumn one
+        assert_eq!(local_instant(prague, spring, time("02:30")), utc("2026-03-29T01:00:00Z"));
+        assert_eq!(local_instant(prague, autumn, time("02:30")), utc("2026-10-25T00:30:00Z"));
+
+        // A window starting in the skipped hour starts as the clocks jump to 03:00
+        let mut scheduler = ChargeScheduler::new(vec![ChargeWindow::parse("02:30-05:00").unwrap()]);
+        assert_eq!(scheduler.step(utc("2026-03-29T00:59:00Z"), Some(40.0), false), None);
+        assert_eq!(scheduler.step(utc("2026-03-29T01:00:00Z"), Some(40.0), false), Some(ChargeTransition::Start(0)));
+        // and ends at 05:00 local, two hours later
+        assert_eq!(scheduler.step(utc("2026-03-29T02:59:00Z"), Some(40.0), false), None);
+        assert_eq!(
+            scheduler.step(utc("2026-03-29T03:00:00Z"), Some(40.0), false),
+            Some(ChargeTransition::Stop { window: 0, reason: "window ended" })
+        );
+
+        // On the 25-hour day a window in the repeated hour runs once
+        let mut scheduler = ChargeScheduler::new(vec![ChargeWindow::parse("02:15-02:45").unwrap()]);
+        assert_eq!(scheduler.step(utc("2026-10-25T00:15:00Z"), Some(40.0), false), Some(ChargeTransition::Start(0)));
+        assert_eq!(
+            scheduler.step(utc("2026-10-25T00:45:00Z"), Some(40.0), false),
+            Some(ChargeTransition::Stop { window: 0, reason: "window ended" })
+        );
+        assert_eq!(scheduler.step(utc("2026-10-25T01:20:00Z"), Some(40.0), false), None);
+        // A window over midnight keeps its wall-clock end: 8 hours instead of 7
+        let mut scheduler = ChargeScheduler::new(vec![ChargeWindow::parse("23:00-06:00").unwrap()]);
+        assert_eq!(scheduler.step(utc("2026-10-24T21:00:00Z"), Some(40.0), false), Some(ChargeTransition::Start(0)));
+        assert_eq!(scheduler.step(utc("2026-10-25T04:59:00Z"), Some(40.0), false), None);
+        assert!(scheduler.step(utc("2026-10-25T05:00:00Z"), Some(40.0), false).is_some());
+    }
+
+    #[test]
+    fn daily_energy_counters_follow_dst_days() {
+        let prague = chrono_tz::Europe::Prague;
+        let max_gap = Duration::from_secs(300);
+        // A steady 1 kW around the days the clocks change and the days before them
+        for (day, hours) in [((3, 28), 24.0), ((3, 29), 23.0), ((10, 24), 24.0), ((10, 25), 25.0)] {
+            let date = chrono::NaiveDate::from_ymd_opt(2026, day.0, day.1).unwrap();
+            let start = local_instant(prague, date, chrono::NaiveTime::MIN).timestamp() as u64;
+            let end = local_instant(prague, date.succ_opt().unwrap(), chrono::NaiveTime::MIN).timestamp() as u64;
+            let mut battery = BatteryThroughput::default();
+            let mut grid = GridEnergy::default();
+            let mut imported_today = 0.0;
+            for now in (start - 3600..end + 3600).step_by(60) {
+                let date = chrono::DateTime::from_timestamp(now as i64, 0).unwrap().with_timezone(&prague).date_naive();
+                battery.record(date, now, 1000.0, None, max_gap);
+                grid.record(date, now, -1000.0, None, max_gap);
+                if now == end - 60 {
+                    imported_today = grid.imported_today_kwh;
+                }
+            }
+            let day = battery.days.iter().find(|day| day.date == date).unwrap();
+            assert!((day.charged_kwh - hours).abs() < 1e-9, "{}: {}", date, day.charged_kwh);
+            assert!((imported_today - hours).abs() < 1e-9, "{}: {}", date, imported_today);
+        }
+    }
+
+    #[test]
+    fn curtailment_counts_pv_lost_at_the_export_limit() {
+        let noon = chrono::NaiveDate::from_ymd_opt(2026, 6, 21).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        let max_gap = Duration::from_secs(300);
+        let estimate = CurtailmentEstimate::RecentMax(Duration::from_secs(3600));
+        let mut curtailment = Curtailment::default();
+        let poll = |curtailment: &mut Curtailment, minute: u64, solar_w: f64, grid_w: f64, soc: f64, limit_w: Option<f64>| {
+            let at = noon + chrono::Duration::minutes(minute as i64);
+            curtailment.record(estimate, at, 1_700_000_000 + minute * 60, solar_w, grid_w, Some(soc), limit_w, max_gap);
+        };
+        // Ten minutes of 6 kW under the limit, then half an hour pinned at the 4 kW limit with 4.5 kW PV
+        for minute in 0..10 {
+            poll(&mut curtailment, minute, 6000.0, 1500.0, 100.0, Some(4000.0));
+        }
+        assert!(!curtailment.curtailing);
+        for minute in 10..=40 {
+            poll(&mut curtailment, minute, 4500.0, 3900.0, 100.0, Some(4000.0));
+        }
+        let output = curtailment.output(estimate);
+        assert!(output.curtailing);
+        assert_eq!((output.export_headroom_w, output.expected_solar_w), (Some(100.0), Some(6000.0)));
+        // The battery can take the surplus again
+        poll(&mut curtailment, 41, 4500.0, 3900.0, 90.0, Some(4000.0));
+        let output = curtailment.output(estimate);
+        assert!(!output.curtailing);
+        assert!((output.estimated_today_kwh - 0.775).abs() < 1e-9, "{}", output.estimated_today_kwh);
+        assert_eq!(output.days[0].curtailed_secs, 31 * 60);
+        // Without a known limit there is nothing to be pinned at
+        poll(&mut curtailment, 42, 4500.0, 3900.0, 100.0, None);
+        assert_eq!((curtailment.curtailing, curtailment.export_headroom_w), (false, None));
+
+        // The same time of day on the previous days, not today's
+        let mut curtailment = Curtailment::default();
+        let yesterday = noon - chrono::Duration::days(1);
+        curtailment.record(CurtailmentEstimate::SameTime, yesterday, 0, 6000.0, 1500.0, Some(100.0), Some(4000.0), max_gap);
+        curtailment.record(CurtailmentEstimate::SameTime, noon + chrono::Duration::minutes(5), 86_700, 4500.0, 3900.0, Some(100.0), Some(4000.0), max_gap);
+        assert_eq!((curtailment.curtailing, curtailment.expected_solar_w), (true, Some(6000.0)));
+        curtailment.record(CurtailmentEstimate::SameTime, noon + chrono::Duration::minutes(20), 87_600, 4500.0, 3900.0, Some(100.0), Some(4000.0), max_gap);
+        // Yesterday had no PV in this slot
+        assert_eq!((curtailment.curtailing, curtailment.expected_solar_w), (false, Some(0.0)));
+        assert!(render_curtailment_metrics(&curtailment.output(CurtailmentEstimate::SameTime)).contains("solax_export_headroom_watts 100\n"));
+    }
+
+    #[test]
+    fn battery_throughput_integrates_between_polls() {
+        let day = |n: u64| chrono::NaiveDate::from_ymd_opt(2026, 1, 1).unwrap() + chrono::Days::new(n);
+        let max_gap = Duration::from_secs(300);
+        let mut battery = BatteryThroughput::default();
+        battery.record(day(0), 0, 3000.0, None, max_gap);
+        // 3 kW for an hour, in 60 s polls
+        for minute in 1..=60 {
+            battery.record(day(0), minute * 60, 3000.0, None, max_gap);
+        }
+        // Ramping from 3 kW charge to 1 kW discharge: 0.75 h above zero, 0.25 h below
+        battery.record(day(0), 3600 + 3600, -1000.0, None, Duration::from_secs(7200));
+        // A poll after a long outage doesn't add the hours in between
+        battery.record(day(1), 30_000, -1000.0, None, max_gap);
+        battery.record(day(1), 30_060, -1000.0, None, max_gap);
+
+        let output = battery.output(Some(5.0));
+        assert_eq!(output.days.len(), 2);
+        assert!((output.days[0].charged_kwh - (3.0 + 1.125)).abs() < 1e-9);
+        assert!((output.days[0].discharged_kwh - 0.125).abs() < 1e-9);
+        assert!((output.days[1].discharged_kwh - 1.0 / 60.0).abs() < 1e-9);
+        assert_eq!(output.days[1].charged_kwh, 0.0);
+        assert!((output.lifetime.charged_kwh - 4.125).abs() < 1e-9);
+        let cycles = output.lifetime.cycles.unwrap();
+        assert!((cycles - (4.125 + 0.125 + 1.0 / 60.0) / 2.0 / 5.0).abs() < 1e-9);
+        assert_eq!(battery.output(None).lifetime.cycles, None);
+
+        let metrics = render_battery_metrics(&battery.output(None));
+        assert!(metrics.contains("solax_battery_charged_kwh_total "));
+        assert!(!metrics.contains("solax_battery_cycles_total"));
+    }
+
+    #[test]
+    fn battery_throughput_follows_the_inverter_counters() {
+        let day = |n: u64| chrono::NaiveDate::from_ymd_opt(2026, 1, 1).unwrap() + chrono::Days::new(n);
+        let max_gap = Duration::from_secs(300);
+        let mut battery = BatteryThroughput::default();
+        // The counters are taken over the power, which would integrate to 0.05 kWh a poll
+        assert_eq!(battery.record(day(0), 0, 3000.0, Some((100.0, 80.0)), max_gap), None);
+        battery.record(day(0), 60, 3000.0, Some((100.1, 80.0)), max_gap);
+        // What was added to the day goes into the rollups as well
+        let (charged, discharged) = battery.record(day(0), 120, 3000.0, Some((100.2, 80.05)), max_gap).unwrap();
+        assert!((charged - 0.1).abs() < 1e-9 && (discharged - 0.05).abs() < 1e-9);
+        assert!((battery.days[0].charged_kwh - 0.2).abs() < 1e-9);
+        assert!((battery.days[0].discharged_kwh - 0.05).abs() < 1e-9);
+
+        // A counter going down was reset: the interval is integrated and the counters go on from there
+        battery.record(day(0), 180, 3000.0, Some((0.4, 80.05)), max_gap);
+        battery.record(day(0), 240, 3000.0, Some((0.5, 80.05)), max_gap);
+        assert!((battery.days[0].charged_kwh - 0.35).abs() < 1e-9);
+        // Without the counters the power is integrated again
+        battery.record(day(0), 300, 3000.0, None, max_gap);
+        assert!((battery.days[0].charged_kwh - 0.4).abs() < 1e-9);
+
+        // Across an outage the counters still count for the lifetime, not for the day
+        battery.record(day(0), 360, 0.0, Some((1.0, 81.0)), max_gap);
+        assert_eq!(battery.record(day(1), 90_000, 0.0, Some((5.0, 85.0)), max_gap), None);
+        assert_eq!((battery.days[1].charged_kwh, battery.days[1].discharged_kwh), (0.0, 0.0));
+        assert!((battery.days[0].charged_kwh - 0.425).abs() < 1e-9);
+        assert!((battery.lifetime_charged_kwh - 4.425).abs() < 1e-9);
+        assert!((battery.lifetime_discharged_kwh - 4.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn capacity_is_learned_from_clean_discharges() {
+        let max_gap = Duration::from_secs(300);
+        // An 8 kWh battery discharging at 2 kW for `minutes`, with the SoC in whole percent
+        let discharge = |capacity: &mut CapacityLearner, start: u64, minutes: u64, solar_w: f64| {
+            let mut learned = None;
+            for minute in 0..=minutes {
+                let soc = (90.0 - 2.0 * minute as f64 / 60.0 / 8.0 * 100.0).round();
+                learned = learned.or(capacity.record(start + minute * 60, soc, -2000.0, solar_w, max_gap, Some(10.0)));
+            }
+            learned.or(capacity.record(start + (minutes + 1) * 60, 65.0, 1500.0, solar_w, max_gap, Some(10.0)))
+        };
+
+        let mut capacity = CapacityLearner::default();
+        // Too shallow, and a discharge alongside solar, teach nothing
+        assert_eq!(discharge(&mut capacity, 0, 20, 0.0), None);
+        assert_eq!(discharge(&mut capacity, 10_000, 60, 800.0), None);
+        assert_eq!(capacity.capacity_kwh(Some(10.0)), Some(10.0));
+
+        let learned = discharge(&mut capacity, 20_000, 60, 0.0).unwrap();
+        assert!((learned - 8.0).abs() < 0.3, "learned {}", learned);
+        assert_eq!(capacity.output(Some(10.0)).confidence, "low");
+        // One discharge isn't trusted yet
+        assert_eq!(capacity.capacity_kwh(Some(10.0)), Some(10.0));
+        discharge(&mut capacity, 30_000, 60, 0.0).unwrap();
+        let in_use = capacity.capacity_kwh(Some(10.0)).unwrap();
+        assert!((in_use - 8.0).abs() < 0.3);
+        assert_eq!(capacity.output(Some(10.0)).in_use_kwh, Some(in_use));
+        // A changed BATTERY_CAPACITY_KWH wins until the next discharge
+        assert_eq!(capacity.capacity_kwh(Some(12.0)), Some(12.0));
+
+        // A gap in the data drops the discharge in progress
+        let mut capacity = CapacityLearner::default();
+        capacity.record(0, 90.0, -2000.0, 0.0, max_gap, None);
+        capacity.record(60, 89.0, -2000.0, 0.0, max_gap, None);
+        capacity.record(3600, 75.0, -2000.0, 0.0, max_gap, None);
+        assert_eq!(capacity.record(3660, 66.0, 0.0, 0.0, max_gap, None), None);
+        assert_eq!(capacity.segments, 0);
+    }
+
+    #[test]
+    fn availability_rolls_over_daily_and_keeps_30_days() {
+        let day = |n: u64| chrono::NaiveDate::from_ymd_opt(2026, 1, 1).unwrap() + chrono::Days::new(n);
+        let mut availability = Availability::default();
+        availability.record(day(0), 1_000, true);
+        availability.record(day(0), 1_060, false);
+        availability.record(day(0), 1_120, false);
+        availability.record(day(0), 1_180, true);
+        availability.record(day(1), 90_000, true);
+
+        let output = availability.output();
+        assert_eq!(output.days.len(), 2);
+        assert_eq!((output.days[0].attempted, output.days[0].succeeded), (4, 2));
+        assert_eq!(output.days[0].ratio, Some(0.5));
+        assert_eq!(output.days[0].longest_gap_secs, 180);
+        assert_eq!(output.days[1].longest_gap_secs, 90_000 - 1_180);
+        assert_eq!(output.overall.ratio, Some(0.6));
+
+        for n in 2..40 {
+            availability.record(day(n), 100_000 + n * 86_400, true);
+        }
+        let output = availability.output();
+        assert_eq!(output.days.len(), AVAILABILITY_DAYS);
+        assert_eq!(output.days[0].date, Some(day(10)));
+
+        let metrics = render_availability_metrics(&output);
+        assert!(metrics.contains("solax_poll_success_ratio{day=\"2026-01-11\"} 1\n"));
+        assert!(metrics.contains("solax_poll_attempts{day=\"all\"} 30\n"));
+    }
+
+    /// A config listening on `addr`, polling every 60 s with no jitter (a 180 s stale window).
+    fn health_config(addr: SocketAddr) -> Config {
+        let text = format!("INVERTER_URL=http://127.0.0.1/\nSERIAL=SIMULATED\nLISTEN_ADDR={}\nPOLL_INTERVAL_SECS=60\nPOLL_JITTER_SECS=0\n", addr);
+        parse_secrets(&text).unwrap()
+    }
+
+    #[tokio::test]
+    async fn healthcheck_uses_the_http_status_when_reachable() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("solax-health-http-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for (response, expected) in [("200 OK", 0), ("503 Service Unavailable", 1)] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let config = health_config(listener.local_addr().unwrap());
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                stream.write_all(format!("HTTP/1.0 {}\r\n\r\n", response).as_bytes()).await.unwrap();
+            });
+            // No persisted poll, so the answer can only have come from /health
+            let (code, message) = check_health(&config, &path, 1_000).await;
+            assert_eq!(code, expected);
+            assert!(message.ends_with(&format!(": HTTP/1.0 {}", response)), "{}", message);
+            server.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn healthcheck_falls_back_to_the_persisted_poll_age() {
+        // A port nothing listens on
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = health_config(addr);
+        let path = std::env::temp_dir().join(format!("solax-health-fallback-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (code, message) = check_health(&config, &path, 10_000).await;
+        assert_eq!(code, 1);
+        assert!(message.ends_with(", no persisted poll"), "{}", message);
+
+        let date = chrono::NaiveDate::from_ymd_opt(2026, 1, 1).unwrap();
+        let mut availability = Availability::default();
+        availability.record(date, 9_900, true);
+        availability.save(&path);
+        let (code, message) = check_health(&config, &path, 10_000).await;
+        assert_eq!(code, 0);
+        assert!(message.starts_with("OK: ") && message.ends_with(", last poll 100s ago"), "{}", message);
+
+        // Past three intervals the persisted poll is stale
+        let (code, message) = check_health(&config, &path, 10_200).await;
+        assert_eq!(code, 1);
+        assert!(message.ends_with(", last poll 300s ago"), "{}", message);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn status_format_follows_accept_header() {
+        assert_eq!(StatusFormat::from_accept(""), StatusFormat::Json);
+        assert_eq!(StatusFormat::from_accept("application/json"), StatusFormat::Json);
+        assert_eq!(StatusFormat::from_accept("text/plain"), StatusFormat::Text);
+        assert_eq!(StatusFormat::from_accept("Text/Plain; charset=utf-8"), StatusFormat::Text);
+        assert_eq!(
+            StatusFormat::from_accept("application/openmetrics-text; version=1.0.0"),
+            StatusFormat::OpenMetrics
+        );
+        assert_eq!(StatusFormat::from_accept("text/html, */*;q=0.8"), StatusFormat::Json);
+        assert_eq!(StatusFormat::from_accept("application/json;q=0.5, text/plain"), StatusFormat::Text);
+        assert_eq!(StatusFormat::from_accept("image/png"), StatusFormat::Json);
+    }
+
+    #[tokio::test]
+    async fn status_format_extractor_reads_accept() {
+        use axum::extract::FromRequestParts;
+
+        let (mut parts, _) = axum::http::Request::builder()
+            .header("Accept", "text/plain")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let format = StatusFormat::from_request_parts(&mut parts, &()).await.unwrap();
+        assert_eq!(format, StatusFormat::Text);
+    }
+
+    #[test]
+    fn status_text_layout() {
+        let snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+        let mut status = X3HybridG4::new(&[], Duration::ZERO).format_status(&snapshot);
+        status.labels.insert("site".to_string(), "cabin".to_string());
+        status.circuits_w.insert("Heat Pump Power".to_string(), 1600.0);
+        let text = render_status_text(&status);
+        assert!(text.starts_with("label_site=cabin\nsolar_panels=2800.0W\n"));
+        assert!(text.contains("circuit_heat_pump_power=1600.0\n"));
+        assert!(text.contains("battery_power=200.0W\n") && text.contains("battery_power_w=-200.0\n"));
+        assert!(text.ends_with("partial=false\n"));
+    }
+
+    #[test]
+    fn rate_limiter_limits_per_ip_and_globally() {
+        let limiter = RateLimiter::new(HttpLimits { rate: 3.0, rate_per_ip: 2.0, health_rate: 1.0, max_connections: 0 });
+        let now = Instant::now();
+        let a = Some("10.0.0.1".parse().unwrap());
+        let b = Some("10.0.0.2".parse().unwrap());
+
+        assert!(limiter.check(a, false, now).is_ok());
+        assert!(limiter.check(a, false, now).is_ok());
+        let retry_after = limiter.check(a, false, now).unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_millis(500));
+        assert!(limiter.check(b, false, now).is_ok());
+        // The global bucket is exhausted even though b has tokens left
+        assert!(limiter.check(b, false, now).is_err());
+
+        // /health has its own bucket
+        assert!(limiter.check(a, true, now).is_ok());
+        assert!(limiter.check(a, true, now).is_err());
+
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check(a, false, later).is_ok());
+        assert!(limiter.check(a, true, later).is_ok());
+    }
+
+    #[test]
+    fn zero_rate_limit_is_unlimited() {
+        let limiter = RateLimiter::new(HttpLimits { rate: 0.0, rate_per_ip: 0.0, health_rate: 0.0, max_connections: 0 });
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert!(limiter.check(Some("10.0.0.1".parse().unwrap()), false, now).is_ok());
+        }
+    }
+
+    #[test]
+    fn connections_beyond_the_cap_are_not_admitted() {
+        let slots = Arc::new(tokio::sync::Semaphore::new(1));
+        let first = LimitedConnection::new((), None, Some(&slots));
+        let second = LimitedConnection::new((), None, Some(&slots));
+        assert!(first.admitted && !second.admitted);
+        drop(first);
+        assert!(LimitedConnection::new((), None, Some(&slots)).admitted);
+        assert!(LimitedConnection::new((), None, None).admitted);
+    }
+
+    #[test]
+    fn logged_paths_redact_sensitive_params() {
+        let uri: axum::http::Uri = "/v1/status?token=abc&format=json&PWD=x".parse().unwrap();
+        assert_eq!(redacted_path(&uri), "/v1/status?token=REDACTED&format=json&PWD=REDACTED");
+        assert_eq!(redacted_path(&"/health".parse().unwrap()), "/health");
+    }
+
+    #[test]
+    fn http_metrics_per_route() {
+        let mut stats = HttpStatsOutput::default();
+        stats.routes.insert("/v1/status".to_string(), RouteStats { requests: 3, errors: 1 });
+        let metrics = render_http_metrics(&stats);
+        assert!(metrics.contains("solax_http_requests_total{route=\"/v1/status\"} 3\n"));
+        assert!(metrics.contains("solax_http_errors_total{route=\"/v1/status\"} 1\n"));
+    }
+
+    #[test]
+    fn parses_ipv4_listen_addr() {
+        let addrs = parse_listen_addrs("0.0.0.0:3000").unwrap();
+        assert_eq!(addrs, vec![ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 3000)))]);
+    }
+
+    #[test]
+    fn parses_bracketed_ipv6_listen_addrs() {
+        let addrs = parse_listen_addrs("[::]:3000, [::1]:8080").unwrap();
+        assert_eq!(addrs, vec![
+            ListenAddr::Tcp("[::]:3000".parse().unwrap()),
+            ListenAddr::Tcp("[::1]:8080".parse().unwrap()),
+        ]);
+    }
+
+    #[test]
+    fn parses_mixed_listen_addr_list() {
+        let addrs = parse_listen_addrs("[::1]:3000,127.0.0.1:3000").unwrap();
+        assert!(matches!(addrs[..], [ListenAddr::Tcp(v6), ListenAddr::Tcp(v4)] if v6.is_ipv6() && v4.is_ipv4()));
+    }
+
+    #[test]
+    fn parses_unix_listen_addr_alongside_tcp() {
+        let addrs = parse_listen_addrs("unix:/run/solax-mon/http.sock,127.0.0.1:3000").unwrap();
+        assert_eq!(addrs, vec![
+            ListenAddr::Unix(PathBuf::from("/run/solax-mon/http.sock")),
+            ListenAddr::Tcp("127.0.0.1:3000".parse().unwrap()),
+        ]);
+        assert!(parse_listen_addrs("unix:").is_err());
+    }
+
+    #[test]
+    fn resolves_hostname_listen_addr() {
+        let addrs = parse_listen_addrs("localhost:3000").unwrap();
+        assert!(!addrs.is_empty());
+        assert!(addrs.iter().all(|addr| matches!(addr, ListenAddr::Tcp(addr) if addr.ip().is_loopback() && addr.port() == 3000)));
+    }
+
+    #[test]
+    fn rejects_invalid_listen_addrs() {
+        assert!(parse_listen_addrs("").is_err());
+        assert!(parse_listen_addrs("::1:3000").is_err());
+        assert!(parse_listen_addrs("0.0.0.0").is_err());
+    }
+
+    fn plug(name: &str) -> SurplusDevice {
+        SurplusDevice::parse(&format!("{},type=shelly,url=10.0.0.{},min_on=600,min_off=60", name, name.len())).unwrap()
+    }
+
+    #[test]
+    fn parses_surplus_devices() {
+        let device = SurplusDevice::parse("boiler,type=tasmota,url=http://10.0.0.80/").unwrap();
+        assert_eq!(device.kind, PlugKind::Tasmota);
+        assert_eq!(device.min_on, Duration::from_secs(300));
+        assert_eq!(device.command_url(true), "http://10.0.0.80/cm?cmnd=Power%20On");
+        assert_eq!(plug("heater").command_url(false), "http://10.0.0.6/relay/0?turn=off");
+
+        assert!(SurplusDevice::parse("boiler,type=tasmota").is_err());
+        assert!(SurplusDevice::parse("boiler,type=sonoff,url=10.0.0.80").is_err());
+        assert!(SurplusDevice::parse("url=10.0.0.80").is_err());
+    }
+
+    #[test]
+    fn surplus_switches_in_priority_order_one_at_a_time() {
+        let mut controller = SurplusController::new(SurplusConfig {
+            devices: vec![plug("boiler"), plug("heater")],
+            ..SurplusConfig::default()
+        });
+        let start = Instant::now();
+
+        let (index, on, _) = controller.decide(2500.0, start).unwrap();
+        assert_eq!((index, on), (0, true));
+        controller.switched(index, on, start);
+        assert_eq!(controller.decide(2500.0, start).map(|(index, on, _)| (index, on)), Some((1, true)));
+        controller.switched(1, true, start);
+        assert!(controller.decide(500.0, start).is_none());
+
+        // The last device switched on goes off first, once it has been on for min_on
+        assert!(controller.decide(0.0, start + Duration::from_secs(60)).is_none());
+        let later = start + Duration::from_secs(600);
+        assert_eq!(controller.decide(0.0, later).map(|(index, on, _)| (index, on)), Some((1, false)));
+        controller.switched(1, false, later);
+
+        // And stays off for min_off
+        assert_eq!(controller.decide(2500.0, later + Duration::from_secs(30)), None);
+        assert!(controller.decide(2500.0, later + Duration::from_secs(60)).is_some());
+    }
+
+    #[test]
+    fn surplus_counts_an_export_pinned_at_the_limit() {
+        let mut controller = SurplusController::new(SurplusConfig { devices: vec![plug("boiler")], ..SurplusConfig::default() });
+        let now = Instant::now();
+        assert!(controller.decide(590.0, now).is_none());
+        // With a 600 W export limit the export never reaches 1000 W
+        controller.export_limit_w = Some(600.0);
+        let (_, on, reason) = controller.decide(590.0, now).unwrap();
+        assert!(on);
+        assert_eq!(reason, "export 590 W above 570 W");
+        controller.export_limit_w = Some(0.0);
+        assert!(controller.decide(590.0, now).is_none());
+
+        let mut output = SettingsOutput { consecutive_failures: 2, ..SettingsOutput::default() };
+        assert!(!render_settings_metrics(&output).contains("solax_setting_export_limit_watts"));
+        output.settings = Some(solax_mon::inverter::decode_settings(&[0; 40]).unwrap());
+        let text = render_settings_metrics(&output);
+        assert!(text.contains("solax_settings_read_failures 2\n"));
+        assert!(text.contains("solax_setting_export_limit_watts 0\n"));
+        assert!(text.contains("solax_setting_work_mode{mode=\"self_use\"} 1\n"));
+    }
+
+    #[test]
+    fn surplus_can_count_solar_over_the_essential_load() {
+        let mut controller = SurplusController::new(SurplusConfig {
+            devices: vec![plug("boiler")],
+            basis: SurplusBasis::Essential,
+            ..SurplusConfig::default()
+        });
+        // The export limit curtails the export, not the solar left over by the essential load
+        controller.export_limit_w = Some(600.0);
+        let now = Instant::now();
+        assert!(controller.decide(800.0, now).is_none());
+        let (_, on, reason) = controller.decide(1500.0, now).unwrap();
+        assert!(on);
+        assert_eq!(reason, "solar over essential load 1500 W above 1000 W");
+        assert_eq!(SurplusBasis::parse("essential"), Some(SurplusBasis::Essential));
+        assert_eq!(SurplusBasis::parse("solar"), None);
+    }
+
+    #[tokio::test]
+    async fn mqtt_commands_pause_and_resume_polling() {
+        use std::sync::atomic::Ordering;
+
+        let state = Arc::new(AppState::new(Vec::new(), Duration::from_secs(180)));
+        let result = handle_mqtt_command(&state, br#"{"command": "pause_monitor", "id": "1"}"#, None).await;
+        assert!(result.ok);
+        assert!(state.polling_paused.load(Ordering::SeqCst));
+        let result = handle_mqtt_command(&state, br#"{"command": "poll_now"}"#, None).await;
+        assert_eq!(result.error.as_deref(), Some("Monitor is paused"));
+
+        assert!(handle_mqtt_command(&state, br#"{"command": "resume_monitor"}"#, None).await.ok);
+        assert!(!state.polling_paused.load(Ordering::SeqCst));
+
+        let payload = br#"{"command": "set_export_limit", "watts": 4000, "secret": "s3cret"}"#;
+        let result = handle_mqtt_command(&state, payload, Some("s3cret")).await;
+        assert_eq!(result.error.as_deref(), Some("Control commands are disabled"));
+    }
+
+    #[test]
+    fn night_mode_stretches_polling_while_dark() {
+        use solax_mon::inverter::{Measurement, Units};
+
+        let (start, end) = NightConfig::parse_window("21:00-07:00").unwrap();
+        let mut night = NightMode::new(NightConfig {
+            start,
+            end,
+            idle_after: Duration::from_secs(1800),
+            interval: Duration::from_secs(600),
+            wake_pv_voltage: 100.0,
+        });
+        let pv = |power: f64, volts: f64| {
+            let mut snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+            for (name, value, unit) in [("PV1 Power", power, Units::W), ("PV2 Power", 0.0, Units::W),
+                ("PV1 Voltage", volts, Units::V), ("PV2 Voltage", 0.0, Units::V)]
+            {
+                snapshot.measurements.insert(name.to_string(), Measurement::new(value, unit));
+            }
+            snapshot
+        };
+        let at = |time: &str| chrono::NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        let dark = pv(0.0, 20.0);
+
+        // Dark before the window doesn't count until the idle time has passed inside it
+        assert_eq!(night.observe(at("20:50"), 0, Some(&dark)), None);
+        assert_eq!(night.observe(at("21:10"), 1200, None), None);
+        assert_eq!(night.observe(at("21:20"), 1800, Some(&dark)), Some(true));
+
+        // Failures and low voltages keep it, a PV voltage above the threshold ends it
+        assert_eq!(night.observe(at("02:00"), 20_000, None), None);
+        assert_eq!(night.observe(at("05:30"), 30_000, Some(&pv(0.0, 80.0))), None);
+        assert_eq!(night.observe(at("05:40"), 30_600, Some(&pv(0.0, 180.0))), Some(false));
+
+        // As does the end of the window
+        assert_eq!(night.observe(at("06:00"), 31_800, Some(&dark)), None);
+        assert_eq!(night.observe(at("06:40"), 34_000, Some(&dark)), Some(true));
+        assert_eq!(night.observe(at("07:00"), 35_000, None), Some(false));
+        assert!(NightConfig::parse_window("21:00").is_err());
+    }
+
+    #[test]
+    fn status_table_aligns_values() {
+        let snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+        let mut raw = RawOutput::default();
+        raw.measurements.insert("Grid Power".to_string(), RawMeasurement::new(-450.0, "W"));
+        raw.measurements.insert("Battery Remaining Capacity".to_string(), RawMeasurement::new(55.0, "%"));
+        let status = X3HybridG4::new(&[], Duration::ZERO).format_status(&snapshot);
+        let table = status_table(&raw, &status);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "Battery Remaining Capacity    55.00 %");
+        assert_eq!(lines[1], "Grid Power                  -450.00 W");
+        assert_eq!(lines[3], format!("Solar panels      {}", status.solar_panels));
+    }
+
+    #[test]
+    fn restart_check_spots_counter_resets_and_firmware_changes() {
+        use solax_mon::inverter::{Measurement, Units};
+
+        let reading = |firmware: &str, today: f64, total: f64| {
+            let mut snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+            snapshot.firmware = firmware.to_string();
+            snapshot.measurements.insert("Yield Today".to_string(), Measurement::new(today, Units::Kwh));
+            snapshot.measurements.insert("Yield Total".to_string(), Measurement::new(total, Units::Kwh));
+            snapshot
+        };
+        let at = |text: &str| chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap();
+        let mut check = RestartCheck::default();
+
+        assert_eq!(check.observe(at("2026-06-01 12:00"), 100, &reading("3.008.10", 8.2, 5000.0)), None);
+        assert_eq!(check.observe(at("2026-06-01 12:10"), 700, &reading("3.008.10", 8.9, 5000.7)), None);
+        let restart = check.observe(at("2026-06-01 12:20"), 1300, &reading("3.008.10", 0.0, 5000.7)).unwrap();
+        assert_eq!(restart.reason, "Yield Today went down from 8.9 to 0 kWh during the day");
+        assert_eq!((restart.time, restart.previous_time), (1300, 700));
+
+        // The daily reset around midnight isn't a reboot, a lower total is
+        assert_eq!(check.observe(at("2026-06-01 23:50"), 2000, &reading("3.008.10", 20.0, 5020.0)), None);
+        assert_eq!(check.observe(at("2026-06-02 00:05"), 3000, &reading("3.008.10", 0.0, 5020.0)), None);
+        assert!(check.observe(at("2026-06-02 00:15"), 3600, &reading("3.008.10", 0.0, 5019.9)).unwrap().reason.starts_with("Yield Total"));
+
+        let restart = check.observe(at("2026-06-02 09:00"), 4000, &reading("3.009.02", 1.0, 5021.0)).unwrap();
+        assert_eq!((restart.previous_firmware.as_str(), restart.firmware.as_str()), ("3.008.10", "3.009.02"));
+    }
+
+    #[test]
+    fn power_save_needs_an_outage_and_a_low_battery() {
+        use solax_mon::inverter::{Measurement, Units};
+
+        let mut power_save = PowerSave::new(PowerSaveConfig { below_soc: 40.0, interval: Duration::from_secs(120) });
+        let reading = |grid_w: f64, soc: f64| {
+            let mut snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+            snapshot.measurements.insert("Grid Power".to_string(), Measurement::new(grid_w, Units::W));
+            snapshot.measurements.insert("Battery Remaining Capacity".to_string(), Measurement::new(soc, Units::Percent));
+            snapshot
+        };
+
+        // A low battery with the grid up, or an outage with a full one, isn't enough
+        assert_eq!(power_save.observe(Some(&reading(-300.0, 20.0))), None);
+        assert_eq!(power_save.observe(Some(&reading(0.0, 80.0))), None);
+        assert_eq!(power_save.observe(Some(&reading(0.0, 39.0))), Some(true));
+
+        // Failed polls and a SoC inside the hysteresis keep it
+        assert_eq!(power_save.observe(None), None);
+        assert_eq!(power_save.observe(Some(&reading(0.0, 43.0))), None);
+        assert_eq!(power_save.observe(Some(&reading(0.0, 45.0))), Some(false));
+        assert_eq!(power_save.observe(Some(&reading(0.0, 30.0))), Some(true));
+        assert_eq!(power_save.observe(Some(&reading(150.0, 30.0))), Some(false));
+    }
+
+    #[test]
+    fn publish_config_is_parsed_checked_and_applied() {
+        let required = "INVERTER_URL=10.0.0.50\nSERIAL=SXXXXXXXXX\n";
+        let config = parse_secrets(&format!("{}PUBLISH=Total Solar Power : pv_total\nPUBLISH=Grid Power\nPUBLISH=Battery Power:\n", required)).unwrap();
+        assert_eq!(config.publish.entries, [
+            ("Total Solar Power".to_string(), Some("pv_total".to_string())),
+            ("Grid Power".to_string(), None),
+            ("Battery Power".to_string(), None),
+        ]);
+        assert!(check_measurements(&config).is_ok());
+
+        // Only the listed measurements are published, under their aliases
+        let snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+        let raw = snapshot.to_raw(&config.publish);
+        let names: Vec<&str> = raw.measurements.keys().map(String::as_str).collect();
+        assert_eq!(names, ["Battery Power", "Grid Power", "pv_total"]);
+        assert_eq!(raw.measurements["pv_total"].value, 2800.0);
+        assert_eq!(snapshot.to_raw(&parse_secrets(required).unwrap().publish).measurements.len(), snapshot.measurements.len());
+
+        let typo = parse_secrets(&format!("{}PUBLISH=Grid Powr\n", required)).unwrap();
+        let error = check_measurements(&typo).unwrap_err().to_string();
+        assert!(error.starts_with("PUBLISH references unknown measurement \"Grid Powr\""), "{}", error);
+    }
+
+    #[test]
+    fn balance_check_warns_after_consecutive_polls_beyond_the_tolerance() {
+        use solax_mon::inverter::{Measurement, Units};
+
+        let mut check = BalanceCheck::new(BalanceConfig { threshold_w: 500.0, polls: 3 });
+        let residual = |watts: f64| {
+            let mut snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+            snapshot.measurements.insert("Power Balance Residual".to_string(), Measurement::new(watts, Units::W));
+            snapshot
+        };
+        // Within the tolerance either way, and a poll without a residual changes nothing
+        assert_eq!(check.observe(&residual(500.0)), None);
+        assert_eq!(check.observe(&residual(-500.0)), None);
+        let mut missing = residual(0.0);
+        missing.measurements.remove("Power Balance Residual");
+        assert_eq!(check.observe(&missing), None);
+
+        // A good poll in between starts the count over
+        assert_eq!(check.observe(&residual(800.0)), None);
+        assert_eq!(check.observe(&residual(-800.0)), None);
+        assert_eq!(check.observe(&residual(100.0)), None);
+        let warnings: Vec<Option<bool>> = [800.0, -900.0, 700.0, 1200.0].iter().map(|watts| check.observe(&residual(*watts))).collect();
+        assert_eq!(warnings, [None, None, Some(true), None]);
+        assert_eq!(check.observe(&residual(200.0)), Some(false));
+        assert_eq!(check.observe(&residual(200.0)), None);
+    }
+
+    #[test]
+    fn poll_schedule_jitters_and_cools_down_after_failures() {
+        let config = PollingConfig {
+            interval: Duration::from_secs(60),
+            jitter: Duration::from_secs(5),
+            cooldown_after: 3,
+            cooldown: Duration::from_secs(600),
+            ..PollingConfig::default()
+        };
+        let mut schedule = PollSchedule::new(config.clone());
+        for _ in 0..200 {
+            let (delay, backoff) = schedule.next_delay(true, 1_750_000_000);
+            assert!((Duration::from_secs(55)..=Duration::from_secs(65)).contains(&delay), "{:?}", delay);
+            assert_eq!((backoff.state.as_str(), backoff.consecutive_failures), ("normal", 0));
+        }
+
+        // Two failures keep the normal interval, the third backs off until a poll succeeds
+        for failures in 1..=2 {
+            let (delay, backoff) = schedule.next_delay(false, 1_750_000_000);
+            assert!(delay <= Duration::from_secs(65));
+            assert_eq!((backoff.state.as_str(), backoff.consecutive_failures), ("normal", failures));
+        }
+        for failures in 3..=4 {
+            let (delay, backoff) = schedule.next_delay(false, 1_750_000_000);
+            assert_eq!(delay, Duration::from_secs(600));
+            assert_eq!((backoff.state.as_str(), backoff.consecutive_failures), ("cooldown", failures));
+            assert_eq!(backoff.next_poll, Some(1_750_000_600));
+        }
+        let (delay, backoff) = schedule.next_delay(true, 1_750_000_000);
+        assert!(delay <= Duration::from_secs(65));
+        assert_eq!((backoff.state.as_str(), backoff.consecutive_failures), ("normal", 0));
+
+        // No jitter is the plain interval, and a cooldown_after of 0 never cools down
+        let mut steady = PollSchedule::new(PollingConfig { jitter: Duration::ZERO, cooldown_after: 0, ..config });
+        for _ in 0..10 {
+            assert_eq!(steady.next_delay(false, 1_750_000_000).0, Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn burst_polls_fast_then_decays_within_its_budget() {
+        use solax_mon::inverter::{Measurement, Units};
+
+        let mut burst = Burst::new(BurstConfig {
+            triggers: vec![("Grid Power".to_string(), 1000.0), ("Run Mode".to_string(), 0.0)],
+            interval: Duration::from_secs(5),
+            window: Duration::from_secs(10),
+            budget: 6,
+        });
+        let reading = |grid_w: f64, run_mode: f64| {
+            let mut snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+            snapshot.measurements.insert("Grid Power".to_string(), Measurement::new(grid_w, Units::W));
+            snapshot.measurements.insert("Run Mode".to_string(), Measurement::new(run_mode, Units::None));
+            snapshot
+        };
+        let normal = Duration::from_secs(60);
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        assert_eq!(burst.observe(&reading(-300.0, 2.0), at(0)), None);
+        assert_eq!(burst.next_delay(normal, at(0)), None);
+        assert_eq!(burst.observe(&reading(-1200.0, 2.0), at(60)), None);
+        assert!(burst.observe(&reading(900.0, 2.0), at(120)).is_some_and(|trigger| trigger.starts_with("Grid Power")));
+        assert_eq!(burst.next_delay(normal, at(120)), Some(Duration::from_secs(5)));
+        assert!(burst.active());
+        // Triggers during the window don't stretch it
+        assert_eq!(burst.observe(&reading(900.0, 4.0), at(125)), None);
+        assert_eq!(burst.next_delay(normal, at(125)), Some(Duration::from_secs(5)));
+        // Then the interval doubles back up to the normal one
+        let delays: Vec<Option<Duration>> = [130, 140, 160, 200].iter().map(|secs| burst.next_delay(normal, at(*secs))).collect();
+        assert_eq!(delays, [Some(Duration::from_secs(10)), Some(Duration::from_secs(20)), Some(Duration::from_secs(40)), None]);
+        assert!(!burst.active());
+
+        // Five fast polls so far, so the next burst is cut short by the budget
+        assert!(burst.observe(&reading(900.0, 2.0), at(260)).is_some_and(|trigger| trigger.starts_with("Run Mode")));
+        assert_eq!(burst.next_delay(normal, at(260)), Some(Duration::from_secs(5)));
+        assert_eq!(burst.next_delay(normal, at(265)), None);
+        assert!(!burst.active());
+        assert_eq!(burst.observe(&reading(-900.0, 2.0), at(270)), None);
+        // An hour later the budget is back
+        assert!(burst.observe(&reading(900.0, 2.0), at(3800)).is_some());
+        assert_eq!(burst.next_delay(normal, at(3800)), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn parses_threshold_rules() {
+        let rule = ThresholdRule::parse("high_load: Load/Generator Power > 5000, for=300, cooldown=1800").unwrap();
+        assert_eq!(rule.metric, "Load/Generator Power");
+        assert_eq!(rule.comparison, Comparison::Above);
+        assert_eq!(rule.sustain, Duration::from_secs(300));
+        assert_eq!(rule.condition(), "> 5000");
+        assert_eq!(ThresholdRule::parse("low: Battery Remaining Capacity <= 2").unwrap().comparison, Comparison::AtMost);
+
+        assert!(ThresholdRule::parse("Grid Power > 4000").is_err());
+        assert!(ThresholdRule::parse("export: Grid Power = 4000").is_err());
+        assert!(ThresholdRule::parse("export: Grid Power > lots").is_err());
+        assert!(ThresholdRule::parse("export: Grid Power > 4000, every=60").is_err());
+    }
+
+    #[test]
+    fn parses_band_threshold_rules() {
+        let band = ThresholdRule::parse("frequency: Grid 1 Frequency outside 49.8-50.2, for=30").unwrap();
+        assert_eq!((band.metric.as_str(), band.threshold, band.comparison), ("Grid 1 Frequency", 49.8, Comparison::Outside(50.2)));
+        assert_eq!(band.condition(), "outside 49.8-50.2");
+        assert!([49.7, 50.25].iter().all(|&hz| band.comparison.holds(hz, band.threshold)));
+        assert!(!band.comparison.holds(50.0, band.threshold));
+        assert_eq!(ThresholdRule::parse("cold: Battery Temperature outside -10-45").unwrap().threshold, -10.0);
+        assert!(ThresholdRule::parse("frequency: Grid 1 Frequency outside 50.2-49.8").is_err());
+        assert!(ThresholdRule::parse("frequency: Grid 1 Frequency outside 50").is_err());
+    }
+
+    #[test]
+    fn threshold_fires_after_sustain_and_respects_cooldown() {
+        let rule = ThresholdRule::parse("high_load: Load/Generator Power > 5000, for=300, cooldown=1800").unwrap();
+        let mut state = ThresholdState::default();
+        assert_eq!(state.observe(&rule, Some(6000.0), 0), None);
+        assert_eq!(state.observe(&rule, None, 200), None);
+        assert_eq!(state.observe(&rule, Some(6000.0), 300), Some(true));
+        assert_eq!(state.observe(&rule, Some(6000.0), 400), None);
+        assert_eq!(state.observe(&rule, Some(4000.0), 500), Some(false));
+
+        // Held again long enough, but still within the cooldown of the first firing
+        assert_eq!(state.observe(&rule, Some(6000.0), 600), None);
+        assert_eq!(state.observe(&rule, Some(6000.0), 1000), None);
+        assert_eq!(state.observe(&rule, Some(6000.0), 2100), Some(true));
+    }
+
+    #[test]
+    fn band_threshold_fires_on_either_side() {
+        let rule = ThresholdRule::parse("frequency: Grid 1 Frequency outside 49.8-50.2, for=30").unwrap();
+        let mut state = ThresholdState::default();
+        assert_eq!(state.observe(&rule, Some(49.7), 0), None);
+        assert_eq!(state.observe(&rule, Some(49.75), 30), Some(true));
+        assert_eq!(state.observe(&rule, Some(50.0), 40), Some(false));
+
+        // Above the band counts the same, the edges are inside it
+        assert_eq!(state.observe(&rule, Some(50.3), 50), None);
+        assert_eq!(state.observe(&rule, Some(50.2), 60), None);
+        assert_eq!(state.observe(&rule, Some(50.3), 70), None);
+        assert_eq!(state.observe(&rule, Some(50.3), 100), Some(true));
+        assert_eq!(state.observe(&rule, Some(49.8), 110), Some(false));
+    }
+
+    #[test]
+    fn rule_metrics_follow_the_debounce() {
+        let rule = ThresholdRule::parse("high_load: Load/Generator Power > 5000, for=300").unwrap();
+        let mut state = ThresholdState::default();
+        state.observe(&rule, Some(6000.0), 0);
+        let pending = state.metrics(&rule, 150);
+        assert_eq!((pending.holds, pending.debounce, pending.tier), (true, 0.5, 1));
+        state.observe(&rule, Some(6000.0), 300);
+        assert_eq!(state.metrics(&rule, 300).tier, 2);
+        state.observe(&rule, Some(4000.0), 360);
+        let clear = state.metrics(&rule, 360);
+
+        let text = render_rule_metrics(&[pending, clear], &rules_hash(std::slice::from_ref(&rule)));
+        assert!(text.contains("solax_monitor_condition{rule=\"high_load\"} 1\n"));
+        assert!(text.contains("solax_monitor_condition_debounce_ratio{rule=\"high_load\"} 0.5\n"));
+        assert!(text.contains("solax_monitor_condition_tier{rule=\"high_load\"} 0\n"));
+
+        // Renaming a rule changes the hash, re-reading the same config doesn't
+        let renamed = ThresholdRule { name: "heavy_load".to_string(), ..rule.clone() };
+        assert_eq!(rules_hash(std::slice::from_ref(&rule)), rules_hash(&[ThresholdRule::parse("high_load: Load/Generator Power > 5000, for=300").unwrap()]));
+        assert_ne!(rules_hash(&[rule]), rules_hash(&[renamed]));
+    }
+
+    #[tokio::test]
+    async fn soak_runs_a_simulated_day_through_the_poll_loop() {
+        let config = parse_secrets(concat!(
+            "INVERTER_URL=http://127.0.0.1/\nSERIAL=SIMULATED\nBATTERY_CAPACITY_KWH=10\n",
+            "THRESHOLD_ALERT=evening_import: Grid Power < -1000, for=300\n",
+        )).unwrap();
+        let dir = std::env::temp_dir().join(format!("solax-soak-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let monitor_rule = Expr::parse("battery_pct < 15").unwrap();
+        // 2026-06-21 00:00 UTC
+        let start = 1_782_000_000;
+        let mut soak = Soak::start(&config, monitor_rule, start, dir.clone()).unwrap();
+        let mut simulator = simulator::Simulator::new(simulator::DayConfig::default());
+        let mut importing_since = None;
+        for time in (0..86_400).step_by(60) {
+            simulator.advance(time);
+            assert_eq!(soak.poll(start + time, serde_json::to_string(&simulator.response()).unwrap()).await, None);
+            let grid_w = soak.state.latest().snapshot.as_ref().and_then(|snapshot| snapshot.value("Grid Power"));
+            if grid_w.is_some_and(|grid_w| grid_w < -1000.0) {
+                importing_since.get_or_insert(start + time);
+            }
+        }
+        assert_eq!((soak.polls, soak.failed, soak.partial), (1440, 0, 0));
+        // The loop kept its counters in the scratch directory, not the service's
+        assert!(dir.join("grid.json").exists() && dir.join("battery.json").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let totals = &simulator.totals;
+        let (grid, battery) = (soak.state.grid.read().await, soak.state.battery.read().await);
+        for (measured, expected) in [
+            (grid.lifetime_imported_kwh, totals.imported_kwh),
+            (grid.lifetime_exported_kwh, totals.exported_kwh),
+            (battery.lifetime_charged_kwh, totals.charged_kwh),
+            (battery.lifetime_discharged_kwh, totals.discharged_kwh),
+        ] {
+            assert!(soak_check("", measured, Some(expected)).1, "{} kWh integrated, {} kWh flowed", measured, expected);
+        }
+        assert!(!soak_check("", 1.2, Some(1.0)).1);
+        // The battery runs empty in the evening peak, and the grid takes over until it ends
+        let alerts: Vec<(u64, &str, bool)> = soak.alerts().iter()
+            .map(|(time, rule, fired)| ((time - start) / 3600, rule.as_str(), *fired))
+            .collect();
+        assert_eq!(alerts, [(21, "evening_import", true), (22, "evening_import", false)]);
+        // Fired on the first poll after the for= period on the clock
+        assert_eq!(importing_since.map(|since| since + 300), Some(soak.alerts()[0].0));
+        // The monitor would shut down overnight and in the evening, and recover once solar charges
+        let monitor: Vec<(u64, bool)> = soak.monitor_actions.iter().map(|(time, shutdown)| ((time - start) / 3600, *shutdown)).collect();
+        assert_eq!(monitor, [(3, true), (8, false), (21, true)]);
+    }
+
+    #[test]
+    fn grid_energy_splits_import_and_export_per_day() {
+        let day = |n: u64| chrono::NaiveDate::from_ymd_opt(2026, 1, 1).unwrap() + chrono::Days::new(n);
+        let max_gap = Duration::from_secs(300);
+        let mut grid = GridEnergy::default();
+        // Exporting 2 kW for half an hour, then importing 1 kW for half an hour
+        for minute in 0..=30 {
+            grid.record(day(0), minute * 60, 2000.0, None, max_gap);
+        }
+        grid.record(day(0), 1860, -1000.0, None, max_gap);
+        for minute in 32..=61 {
+            grid.record(day(0), minute * 60, -1000.0, None, max_gap);
+        }
+        assert!((grid.exported_today_kwh - (1.0 + 2.0 / 3.0 / 60.0)).abs() < 1e-9);
+        assert!((grid.imported_today_kwh - (0.5 + 0.5 / 3.0 / 60.0)).abs() < 1e-9);
+
+        // Midnight starts the day over, the lifetime counters keep going
+        grid.record(day(1), 3720, -1000.0, None, max_gap);
+        let [(imported, imported_kwh), (exported, exported_kwh)] = grid.measurements();
+        assert_eq!((imported, exported, exported_kwh), ("Grid Imported Today", "Grid Exported Today", 0.0));
+        assert!((imported_kwh - 1.0 / 60.0).abs() < 1e-9);
+        assert!((grid.lifetime_imported_kwh - (0.5 + 0.5 / 3.0 / 60.0 + 1.0 / 60.0)).abs() < 1e-9);
+        assert!(render_grid_metrics(&grid).contains("# TYPE solax_grid_exported_kwh_total counter"));
+    }
+
+    #[test]
+    fn grid_energy_takes_counter_resets_without_negative_deltas() {
+        let day = |n: u64| chrono::NaiveDate::from_ymd_opt(2026, 1, 1).unwrap() + chrono::Days::new(n);
+        let max_gap = Duration::from_secs(300);
+        let mut grid = GridEnergy::default();
+        // (exported, imported) totals of the inverter; the power would integrate to much less
+        grid.record(day(0), 0, 100.0, Some((1200.0, 3400.0)), max_gap);
+        grid.record(day(0), 60, 100.0, Some((1200.5, 3400.0)), max_gap);
+        grid.record(day(0), 120, 100.0, Some((1201.0, 3400.25)), max_gap);
+        assert!((grid.exported_today_kwh - 1.0).abs() < 1e-9);
+        assert!((grid.imported_today_kwh - 0.25).abs() < 1e-9);
+
+        // The inverter restarted with its counters at 0.3 kWh: that poll is integrated instead
+        grid.record(day(0), 180, 100.0, Some((0.3, 0.1)), max_gap);
+        grid.record(day(0), 240, -600.0, Some((0.8, 0.1)), max_gap);
+        let integrated = 100.0 / 60.0 / 1000.0;
+        assert!((grid.exported_today_kwh - (1.5 + integrated)).abs() < 1e-9);
+        assert!((grid.imported_today_kwh - 0.25).abs() < 1e-9);
+        assert!(grid.lifetime_imported_kwh >= 0.0 && grid.lifetime_exported_kwh >= 0.0);
+
+        // A counter at 0 isn't reported, so the power is integrated
+        assert_eq!(energy_counters(&decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json")), "Feed-in Energy Total", "Consumption Energy Total"), None);
+        grid.record(day(0), 300, -600.0, None, max_gap);
+        assert!((grid.imported_today_kwh - (0.25 + 0.01)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn export_compliance_counts_readings_above_the_limit() {
+        let config = ComplianceConfig {
+            limit_w: 5000.0,
+            postgres: PostgresConfig {
+                url: String::new(),
+                table: "solax".to_string(),
+                layout: postgres::Layout::Narrow,
+                hypertable: false,
+                buffer_rows: 0,
+                ca_file: None,
+            },
+            measurement: "Grid Power".to_string(),
+            timezone: chrono_tz::Europe::Prague,
+            max_gap: Duration::from_secs(300),
+        };
+        let month = parse_month("2025-06").unwrap();
+        // 2025-06-02 00:00 in Prague
+        let start = 1_748_815_200;
+        let mut readings: Vec<(u64, f64)> = (0..=60).map(|minute| (start + minute * 60, 4000.0)).collect();
+        readings[30].1 = 5200.0;
+        readings[31].1 = 5100.0;
+        // A gap of an hour isn't integrated, but its reading still counts for the maximum
+        readings.push((start + 7200, 5400.0));
+        readings.push((start + 7260, -300.0));
+        // Late on the 30th, before midnight in Prague, and the next month
+        readings.push((1_751_320_800 - 60, 1000.0));
+        readings.push((1_751_320_800, 1000.0));
+
+        let output = export_compliance(&readings, month, &config);
+        assert_eq!((output.month.as_str(), output.days.len(), output.compliant), ("2025-06", 30, false));
+        assert_eq!(output.sampling_interval_secs, Some(60));
+        assert!(output.note.starts_with("Readings are 60s apart"));
+        let day = &output.days[1];
+        assert_eq!((day.samples, day.max_export_w, day.sampling_interval_secs, day.longest_gap_secs), (63, Some(5400.0), Some(60), 3600));
+        assert!((day.minutes_above_limit - 2.0).abs() < 1e-9);
+        let exported_kwh = 4.0 + (0.6 + 1.15 + 0.55) / 60.0 + 5400.0 / 5700.0 * 2.7 / 60.0;
+        assert!((day.exported_kwh - exported_kwh).abs() < 1e-9, "{}", day.exported_kwh);
+        assert_eq!((output.days[0].samples, output.days[0].max_export_w, output.days[0].sampling_interval_secs), (0, None, None));
+        assert_eq!(output.days[29].samples, 1);
+
+        let csv = render_compliance_csv(&output);
+        assert_eq!(csv.lines().count(), 31);
+        assert_eq!(csv.lines().next().unwrap(), "date,samples,sampling_interval_secs,longest_gap_secs,max_export_w,minutes_above_limit,exported_kwh,limit_w");
+        assert_eq!(csv.lines().nth(1).unwrap(), "2025-06-01,0,,0,,0.0,0.000,5000");
+        assert!(parse_month("2025-13").is_err());
+    }
+
+    #[test]
+    fn export_writes_the_asked_for_columns() {
+        let args = |line: &str| parse_export_args(&line.split(' ').map(String::from).collect::<Vec<_>>());
+        let parsed = args("--from 2025-06-01 --to 2025-06-02 --out data.parquet --metrics Grid Power,consumed_kwh").unwrap_err();
+        assert_eq!(parsed, EXPORT_USAGE);
+        let parsed = args("--from 2025-06-01 --to 2025-06-02 --out data.parquet --metrics grid_power,consumed_kwh --resolution day").unwrap();
+        assert_eq!((parsed.metrics.len(), parsed.resolution, parsed.out), (2, ExportResolution::Day, PathBuf::from("data.parquet")));
+        assert!(args("--from 2025-06-02 --to 2025-06-01 --out data.parquet").is_err());
+        assert!(args("--from 2025-06-01 --to 2025-06-02").is_err());
+        assert!(args("--from 2025-06-01 --to 2025-06-02 --out data.parquet --resolution hour").is_err());
+
+        let available = vec!["Grid Power".to_string(), "Load/Generator Power".to_string()];
+        assert_eq!(export_columns(&["load_generator_power".to_string()], available.clone()), Ok(vec!["Load/Generator Power".to_string()]));
+        assert_eq!(export_columns(&[], available.clone()), Ok(available.clone()));
+        assert!(export_columns(&["Battery Power".to_string()], available).is_err());
+        // Two measurements that sanitize to the same column would make an invalid file
+        let colliding = vec!["Grid Power".to_string(), "Grid-Power".to_string(), "Battery Power".to_string()];
+        assert!(export_columns(&[], colliding.clone()).unwrap_err().contains("grid_power"));
+        assert_eq!(export_columns(&["battery_power".to_string()], colliding), Ok(vec!["Battery Power".to_string()]));
+
+        // Readings of the same time go into one row
+        let mut rows = ExportRows::new(2);
+        rows.set(1000, 0, Some(-450.0));
+        rows.set(1000, 1, Some(800.0));
+        rows.set(2000, 1, Some(750.0));
+        assert_eq!(rows.into_values(), vec![
+            parquet::Values::Int(vec![Some(1000), Some(2000)]),
+            parquet::Values::Double(vec![Some(-450.0), None]),
+            parquet::Values::Double(vec![Some(800.0), Some(750.0)]),
+        ]);
+
+        let day = |date: &str| DayRollup { date: parse_date(date).unwrap(), consumed_kwh: 12.5, ..DayRollup::default() };
+        let days = [day("2025-05-31"), day("2025-06-01"), day("2025-06-02"), day("2025-06-03")];
+        let mut args = args("--from 2025-06-01 --to 2025-06-02 --out data.parquet --metrics consumed_kwh --resolution day").unwrap();
+        let mut file = Vec::new();
+        assert_eq!(write_rollups(&mut file, &days, &args).unwrap(), 2);
+        let text = String::from_utf8_lossy(&file);
+        assert!(file.starts_with(b"PAR1") && file.ends_with(b"PAR1"));
+        assert!(text.contains("date") && text.contains("consumed_kwh") && !text.contains("generated_kwh"));
+        args.metrics = vec!["grid_power".to_string()];
+        assert!(write_rollups(Vec::new(), &days, &args).is_err());
+    }
+
+    #[test]
+    fn textfile_is_replaced_whole() {
+        let dir = std::env::temp_dir().join(format!("solax-textfile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("solax.prom");
+        write_textfile(&path, "solax_up 1\n").unwrap();
+        write_textfile(&path, "solax_up 0\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "solax_up 0\n");
+        // Only the .prom file is left for the collector to read
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(write_textfile(&dir.join("missing").join("solax.prom"), "").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overnight_load_is_the_median_of_recent_nights() {
+        let at = |day: u32, hour: u32| chrono::NaiveDate::from_ymd_opt(2026, 1, day).unwrap().and_hms_opt(hour, 0, 0).unwrap();
+        let mut overnight = OvernightLoad::default();
+        for (day, load_w) in [(1, 300.0), (2, 500.0), (3, 400.0), (4, 2000.0)] {
+            // The first daytime sample ends the previous night
+            assert_eq!(overnight.record(at(day, 12), 5000.0), day > 1);
+            overnight.record(at(day, 22), load_w);
+            overnight.record(at(day, 23), load_w + 100.0);
+            overnight.record(at(day + 1, 3), load_w);
+            if day == 2 {
+                // Too few nights to trust yet
+                assert_eq!(overnight.typical_w(), None);
+            }
+        }
+        // The night of the 4th is still in progress until the morning
+        assert_eq!(overnight.nights.len(), 3);
+        assert!(overnight.record(at(5, 7), 900.0));
+        assert_eq!(overnight.nights[3], (chrono::NaiveDate::from_ymd_opt(2026, 1, 4).unwrap(), 2000.0));
+        assert_eq!(overnight.typical_w(), Some(450.0));
+        assert!(overnight.samples.is_empty());
+
+        for day in 5..=12 {
+            overnight.record(at(day, 23), 250.0);
+        }
+        overnight.record(at(13, 12), 250.0);
+        assert_eq!(overnight.nights.len(), OVERNIGHT_NIGHTS);
+        assert_eq!(overnight.typical_w(), Some(250.0));
+    }
+
+    #[test]
+    fn eps_overload_warns_before_the_limit() {
+        use solax_mon::inverter::{Measurement, Units};
+
+        let mut eps = EpsCheck::new(EpsConfig { limit_w: None, margin_w: 1000.0 });
+        let poll = |run_mode: f64, load_w: f64| {
+            let mut snapshot = decode_fixture(include_str!("../tests/fixtures/x3_hybrid_g4.json"));
+            snapshot.measurements.insert("Run Mode".to_string(), Measurement::new(run_mode, Units::None));
+            snapshot.measurements.insert("Load/Generator Power".to_string(), Measurement::new(load_w, Units::W));
+            snapshot
+        };
+
+        // On the grid the load can be anything
+        assert_eq!(eps.headroom_w(&poll(2.0, 9500.0), Some(10.0)), None);
+        assert_eq!(eps.observe(None), None);
+        // Off grid, with the rated power as the limit
+        assert_eq!(eps.headroom_w(&poll(7.0, 9500.0), Some(10.0)), Some(500.0));
+        assert_eq!(eps.headroom_w(&poll(7.0, 9500.0), None), None);
+        assert_eq!(eps.observe(Some(6000.0)), None);
+        assert_eq!(eps.observe(Some(500.0)), Some(true));
+        // Just above the margin isn't enough to clear it
+        assert_eq!(eps.observe(Some(1100.0)), None);
+        assert_eq!(eps.observe(Some(1300.0)), Some(false));
+        assert_eq!(eps.observe(Some(800.0)), Some(true));
+        // The grid coming back clears it
+        assert_eq!(eps.observe(None), Some(false));
+
+        let configured = EpsCheck::new(EpsConfig { limit_w: Some(6000.0), margin_w: 0.0 });
+        assert_eq!(configured.headroom_w(&poll(7.0, 5800.0), Some(10.0)), Some(200.0));
+        assert_eq!(EpsCheck::new(configured.config.clone()).observe(Some(200.0)), None);
+    }
+}
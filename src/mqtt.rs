@@ -0,0 +1,147 @@
+//! Commands received over MQTT (`solax/<sn>/cmd`), answered on `solax/<sn>/cmd/result`,
+//! and the topic threshold alerts are published on (`solax/<sn>/event`).
+
+use crate::inverter::BatteryMode;
+use crate::status::CommandResult;
+use serde::Deserialize;
+
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// What a command asks for; the JSON `command` field picks the variant.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    PollNow,
+    PauseMonitor,
+    ResumeMonitor,
+    SetExportLimit { watts: u32 },
+    SetBatteryMode {
+        mode: BatteryMode,
+        revert_after_secs: Option<u64>,
+        /// Force charges even while charging is inhibited by a cold battery.
+        #[serde(default)]
+        override_temperature: bool,
+    },
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::PollNow => "poll_now",
+            Command::PauseMonitor => "pause_monitor",
+            Command::ResumeMonitor => "resume_monitor",
+            Command::SetExportLimit { .. } => "set_export_limit",
+            Command::SetBatteryMode { .. } => "set_battery_mode",
+        }
+    }
+
+    /// Whether the command writes to the inverter.
+    pub fn is_control(&self) -> bool {
+        matches!(self, Command::SetExportLimit { .. } | Command::SetBatteryMode { .. })
+    }
+}
+
+/// One command message; `id` is echoed back in the result so a caller can match them up.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CommandRequest {
+    pub id: Option<String>,
+    pub secret: Option<String>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+impl CommandRequest {
+    /// Parses a payload and checks its secret. Write commands are refused unless a secret is
+    /// configured, since anyone who can publish to the broker could send them otherwise.
+    pub fn authenticate(payload: &[u8], secret: Option<&str>) -> Result<Self, CommandResult> {
+        let request: CommandRequest = serde_json::from_slice(payload).map_err(|e| {
+            // Still echo the id of a payload that is JSON but not a valid command
+            let id = serde_json::from_slice::<serde_json::Value>(payload).ok()
+                .and_then(|value| value.get("id")?.as_str().map(str::to_string));
+            CommandResult::failed(id, None, format!("Invalid command: {}", e))
+        })?;
+        let refuse = |error: &str| Err(CommandResult::failed(request.id.clone(), Some(request.command.name()), error.to_string()));
+        match (secret, request.secret.as_deref()) {
+            (Some(expected), Some(given)) if secret_matches(given, expected) => Ok(request),
+            (Some(_), _) => refuse("Missing or wrong secret"),
+            (None, _) if request.command.is_control() => refuse("Control commands need MQTT_COMMAND_SECRET"),
+            (None, _) => Ok(request),
+        }
+    }
+}
+
+/// Compares in constant time, so the secret can't be guessed byte by byte.
+pub fn secret_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Splits `mqtt://host:port`, `host:port` or `host` into host and port.
+pub fn broker_address(url: &str) -> Result<(String, u16), String> {
+    let address = url.trim().strip_prefix("mqtt://").unwrap_or(url.trim()).trim_end_matches('/');
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| format!("Invalid MQTT port in {:?}", url))?),
+        None => (address, DEFAULT_PORT),
+    };
+    if host.is_empty() {
+        return Err(format!("Missing MQTT host in {:?}", url));
+    }
+    Ok((host.to_string(), port))
+}
+
+pub fn command_topic(prefix: &str, sn: &str) -> String {
+    format!("{}/{}/cmd", prefix, sn)
+}
+
+pub fn result_topic(prefix: &str, sn: &str) -> String {
+    format!("{}/{}/cmd/result", prefix, sn)
+}
+
+/// Where threshold alerts are published.
+pub fn event_topic(prefix: &str, sn: &str) -> String {
+    format!("{}/{}/event", prefix, sn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let request = CommandRequest::authenticate(br#"{"command": "poll_now", "id": "a1"}"#, None).unwrap();
+        assert_eq!(request.command, Command::PollNow);
+        assert_eq!(request.id.as_deref(), Some("a1"));
+
+        let payload = br#"{"command": "set_battery_mode", "mode": "force_charge", "revert_after_secs": 600, "secret": "s3cret"}"#;
+        let request = CommandRequest::authenticate(payload, Some("s3cret")).unwrap();
+        assert_eq!(request.command, Command::SetBatteryMode { mode: BatteryMode::ForceCharge, revert_after_secs: Some(600), override_temperature: false });
+
+        let result = CommandRequest::authenticate(br#"{"command": "reboot", "id": "a2"}"#, None).unwrap_err();
+        assert_eq!(result.id.as_deref(), Some("a2"));
+        assert!(!result.ok);
+        assert!(CommandRequest::authenticate(br#"{"command": "set_export_limit"}"#, None).is_err());
+    }
+
+    #[test]
+    fn checks_the_secret() {
+        let payload = br#"{"command": "pause_monitor", "secret": "wrong"}"#;
+        assert_eq!(
+            CommandRequest::authenticate(payload, Some("s3cret")).unwrap_err().error.as_deref(),
+            Some("Missing or wrong secret"),
+        );
+        assert!(CommandRequest::authenticate(br#"{"command": "pause_monitor"}"#, Some("s3cret")).is_err());
+
+        // Without a configured secret only the monitoring commands are accepted
+        assert!(CommandRequest::authenticate(br#"{"command": "pause_monitor"}"#, None).is_ok());
+        let result = CommandRequest::authenticate(br#"{"command": "set_export_limit", "watts": 4000}"#, None).unwrap_err();
+        assert_eq!(result.command.as_deref(), Some("set_export_limit"));
+    }
+
+    #[test]
+    fn parses_broker_addresses() {
+        assert_eq!(broker_address("mqtt://10.0.0.5:1884").unwrap(), ("10.0.0.5".to_string(), 1884));
+        assert_eq!(broker_address("broker.lan").unwrap(), ("broker.lan".to_string(), 1883));
+        assert!(broker_address("mqtt://:1883").is_err());
+        assert!(broker_address("broker.lan:x").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! A minimal NATS client: publishes and, for JetStream subjects, waits for the stream's
+//! acknowledgement, so an event only leaves the outbox once it is stored.
+
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+pub const DEFAULT_PORT: u16 = 4222;
+
+/// Longest message read back; acknowledgements are a short JSON object.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// The server from `nats://[user:password@|token@]host[:port]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+}
+
+impl Target {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let rest = url.trim().strip_prefix("nats://").unwrap_or(url.trim()).trim_end_matches('/');
+        let (credentials, address) = match rest.rsplit_once('@') {
+            Some((credentials, address)) => (Some(credentials), address),
+            None => (None, rest),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("Invalid NATS port in {:?}", url))?),
+            None => (address, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(format!("Missing NATS host in {:?}", url));
+        }
+        let mut target = Target { host: host.to_string(), port, user: None, password: None, token: None };
+        match credentials.map(|credentials| (credentials, credentials.split_once(':'))) {
+            Some((_, Some((user, password)))) => {
+                target.user = Some(user.to_string());
+                target.password = Some(password.to_string());
+            }
+            Some((token, None)) => target.token = Some(token.to_string()),
+            None => {}
+        }
+        Ok(target)
+    }
+
+    fn connect_options(&self) -> serde_json::Value {
+        serde_json::json!({
+            "verbose": false,
+            "pedantic": false,
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "name": "solax-mon",
+            "protocol": 1,
+            "user": self.user,
+            "pass": self.password,
+            "auth_token": self.token,
+        })
+    }
+}
+
+/// Checks a JetStream publish acknowledgement, e.g. `{"stream":"SOLAX","seq":12}`.
+pub fn check_ack(payload: &[u8]) -> Result<(), String> {
+    let ack: serde_json::Value = serde_json::from_slice(payload)
+        .map_err(|_| format!("Unexpected JetStream reply {:?}", String::from_utf8_lossy(payload)))?;
+    match ack.get("error") {
+        Some(error) => Err(format!(
+            "JetStream refused the message: {}",
+            error.get("description").and_then(|description| description.as_str()).unwrap_or("unknown error"),
+        )),
+        None if ack.get("stream").is_some() => Ok(()),
+        None => Err(format!("Unexpected JetStream reply {}", ack)),
+    }
+}
+
+pub struct Connection {
+    stream: BufReader<TcpStream>,
+    inbox: String,
+    next_reply: u64,
+    timeout: Duration,
+}
+
+impl Connection {
+    /// Connects, authenticates and subscribes to the inbox acknowledgements arrive on.
+    pub async fn connect(target: &Target, timeout: Duration) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let handshake = async {
+            let stream = TcpStream::connect((target.host.as_str(), target.port)).await?;
+            let mut connection = Connection {
+                stream: BufReader::new(stream),
+                inbox: format!("_INBOX.solax.{:016x}", rand::random::<u64>()),
+                next_reply: 0,
+                timeout,
+            };
+            let info = connection.read_line().await?;
+            if !info.starts_with("INFO ") {
+                return Err(format!("Expected INFO from the NATS server, got {:?}", info).into());
+            }
+            let setup = format!("CONNECT {}\r\nSUB {}.* 1\r\nPING\r\n", target.connect_options(), connection.inbox);
+            connection.stream.get_mut().write_all(setup.as_bytes()).await?;
+            connection.wait_for_pong().await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(connection)
+        };
+        tokio::time::timeout(timeout, handshake).await
+            .map_err(|_| format!("NATS server {}:{} didn't answer within {}s", target.host, target.port, timeout.as_secs()))?
+    }
+
+    async fn read_line(&mut self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err("NATS server closed the connection".into());
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    /// Reads until the server's PONG, answering its PINGs on the way.
+    async fn wait_for_pong(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            match self.read_line().await?.as_str() {
+                "PONG" => return Ok(()),
+                "PING" => self.stream.get_mut().write_all(b"PONG\r\n").await?,
+                line if line.starts_with("-ERR") => return Err(format!("NATS error: {}", &line[4..].trim()).into()),
+                line if line.starts_with("MSG ") => {
+                    // A late acknowledgement of an earlier publish that timed out
+                    self.read_payload(line).await?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads the payload announced by a `MSG <subject> <sid> [reply] <len>` line.
+    async fn read_payload(&mut self, line: &str) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let len: usize = line.rsplit(' ').next().and_then(|len| len.parse().ok())
+            .ok_or_else(|| format!("Invalid NATS message line {:?}", line))?;
+        if len > MAX_MESSAGE_LEN {
+            return Err(format!("NATS message of {} bytes is too long", len).into());
+        }
+        let mut payload = vec![0u8; len + 2];
+        self.stream.read_exact(&mut payload).await?;
+        payload.truncate(len);
+        Ok(payload)
+    }
+
+    /// Publishes one message. With `jetstream` the stream's acknowledgement is awaited;
+    /// otherwise a PING round trip confirms the server has taken it.
+    pub async fn publish(&mut self, subject: &str, payload: &[u8], jetstream: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let timeout = self.timeout;
+        let exchange = async {
+            if !jetstream {
+                let mut packet = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+                packet.extend_from_slice(payload);
+                packet.extend_from_slice(b"\r\nPING\r\n");
+                self.stream.get_mut().write_all(&packet).await?;
+                return self.wait_for_pong().await;
+            }
+
+            self.next_reply += 1;
+            let reply = format!("{}.{}", self.inbox, self.next_reply);
+            let mut packet = format!("PUB {} {} {}\r\n", subject, reply, payload.len()).into_bytes();
+            packet.extend_from_slice(payload);
+            packet.extend_from_slice(b"\r\n");
+            self.stream.get_mut().write_all(&packet).await?;
+            loop {
+                let line = self.read_line().await?;
+                match line.as_str() {
+                    "PING" => self.stream.get_mut().write_all(b"PONG\r\n").await?,
+                    line if line.starts_with("-ERR") => return Err(format!("NATS error: {}", line[4..].trim()).into()),
+                    line if line.starts_with("MSG ") => {
+                        let payload = self.read_payload(line).await?;
+                        if line.split(' ').nth(1) == Some(reply.as_str()) {
+                            return Ok(check_ack(&payload)?);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        };
+        tokio::time::timeout(timeout, exchange).await
+            .map_err(|_| format!("No acknowledgement from NATS within {}s", timeout.as_secs()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_urls() {
+        let target = Target::parse("nats://solax:pw@10.0.0.5:4223").unwrap();
+        assert_eq!((target.host.as_str(), target.port), ("10.0.0.5", 4223));
+        assert_eq!((target.user.as_deref(), target.password.as_deref()), (Some("solax"), Some("pw")));
+        assert_eq!(Target::parse("s3cret@nats.lan").unwrap().token.as_deref(), Some("s3cret"));
+        assert_eq!(Target::parse("nats.lan").unwrap().port, DEFAULT_PORT);
+        assert!(Target::parse("nats://:4222").is_err());
+    }
+
+    #[test]
+    fn checks_acknowledgements() {
+        assert!(check_ack(br#"{"stream":"SOLAX","seq":12}"#).is_ok());
+        assert_eq!(
+            check_ack(br#"{"error":{"code":503,"description":"no responders"}}"#).unwrap_err(),
+            "JetStream refused the message: no responders",
+        );
+        assert!(check_ack(b"+OK").is_err());
+    }
+
+    #[tokio::test]
+    async fn publishes_and_waits_for_the_ack() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream.get_mut().write_all(b"INFO {\"server_id\":\"test\"}\r\n").await.unwrap();
+            let mut lines = Vec::new();
+            for _ in 0..3 {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                lines.push(line.trim_end().to_string());
+            }
+            stream.get_mut().write_all(b"PONG\r\n").await.unwrap();
+
+            let mut publish = String::new();
+            stream.read_line(&mut publish).await.unwrap();
+            let parts: Vec<&str> = publish.split_whitespace().collect();
+            let mut payload = vec![0u8; parts[3].parse::<usize>().unwrap() + 2];
+            stream.read_exact(&mut payload).await.unwrap();
+            let ack = br#"{"stream":"SOLAX","seq":1}"#;
+            let reply = format!("PING\r\nMSG {} 1 {}\r\n", parts[2], ack.len());
+            stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            stream.get_mut().write_all(ack).await.unwrap();
+            stream.get_mut().write_all(b"\r\n").await.unwrap();
+            let mut pong = String::new();
+            stream.read_line(&mut pong).await.unwrap();
+            (lines, parts[1].to_string(), payload, pong)
+        });
+
+        let target = Target::parse(&format!("nats://solax:pw@127.0.0.1:{}", port)).unwrap();
+        let mut connection = Connection::connect(&target, Duration::from_secs(5)).await.unwrap();
+        connection.publish("solax.events", br#"{"type":"poll"}"#, true).await.unwrap();
+
+        let (lines, subject, payload, pong) = server.await.unwrap();
+        assert!(lines[0].starts_with("CONNECT ") && lines[0].contains(r#""user":"solax""#));
+        assert!(lines[1].starts_with("SUB _INBOX.solax."));
+        assert_eq!(lines[2], "PING");
+        assert_eq!(subject, "solax.events");
+        assert_eq!(&payload[..payload.len() - 2], br#"{"type":"poll"}"#);
+        assert_eq!(pong.trim_end(), "PONG");
+    }
+}
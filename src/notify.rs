@@ -0,0 +1,1012 @@
+//! Notifications: alerts rendered as Discord embeds, Slack Block Kit messages, Matrix room
+//! messages or Gotify and Pushover pushes, user templates replacing their wording, and the
+//! governor that rate limits them.
+
+use crate::status::{Readings, MAX_RUNTIME_MIN};
+use crate::unix_now;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Critical,
+    Normal,
+    Warning,
+    Info,
+}
+
+impl Severity {
+    /// The name in configuration and in the audit log.
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Critical => "critical",
+            Severity::Normal => "normal",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim() {
+            "critical" => Ok(Severity::Critical),
+            "normal" | "normalized" => Ok(Severity::Normal),
+            "warning" => Ok(Severity::Warning),
+            "info" => Ok(Severity::Info),
+            other => Err(format!("Unknown severity {:?} (info, normal, warning, critical)", other)),
+        }
+    }
+
+    /// Ordering for minimum severities: info, normal, warning, critical.
+    pub fn rank(self) -> u8 {
+        match self {
+            Severity::Info => 0,
+            Severity::Normal => 1,
+            Severity::Warning => 2,
+            Severity::Critical => 3,
+        }
+    }
+
+    /// The event type an alert of this severity is templated as, unless it names its own.
+    pub fn event(self) -> &'static str {
+        match self {
+            Severity::Critical => "critical",
+            Severity::Normal => "normalized",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+        }
+    }
+
+    /// Gotify priorities: 8 and up are high, 4 to 7 normal and 1 to 3 low.
+    pub fn gotify_priority(self) -> u8 {
+        match self {
+            Severity::Critical => 10,
+            Severity::Warning => 7,
+            Severity::Normal => 5,
+            Severity::Info => 2,
+        }
+    }
+
+    /// Pushover priorities: critical alerts are emergencies, repeated until acknowledged,
+    /// and informational ones arrive without a sound.
+    pub fn pushover_priority(self) -> i8 {
+        match self {
+            Severity::Critical => 2,
+            Severity::Warning | Severity::Normal => 0,
+            Severity::Info => -1,
+        }
+    }
+
+    /// Embed colors: red, green, amber and Discord blurple.
+    pub fn color(self) -> u32 {
+        match self {
+            Severity::Critical => 0xE74C3C,
+            Severity::Normal => 0x2ECC71,
+            Severity::Warning => 0xF1C40F,
+            Severity::Info => 0x5865F2,
+        }
+    }
+}
+
+/// A notification, rendered as a Discord embed or as plain text.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub severity: Severity,
+    pub title: String,
+    pub description: Option<String>,
+    pub fields: Vec<(String, String)>,
+    pub site: Option<String>,
+    pub timestamp: u64,
+    /// Identifies the condition behind a repeating warning so it can be acknowledged.
+    pub id: Option<String>,
+    /// The template the alert is rendered with, when not the one of its severity.
+    pub event: Option<String>,
+    /// Template variables beyond the fields, such as the readings as plain numbers.
+    pub vars: BTreeMap<String, String>,
+}
+
+impl Alert {
+    pub fn new(severity: Severity, title: impl Into<String>) -> Self {
+        Self {
+            severity,
+            title: title.into(),
+            description: None,
+            fields: Vec::new(),
+            site: None,
+            timestamp: unix_now(),
+            id: None,
+            event: None,
+            vars: BTreeMap::new(),
+        }
+    }
+
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    pub fn var(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.vars.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn id(mut self, id: String) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn field(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.fields.push((name.to_string(), value.into()));
+        self
+    }
+
+    /// Appends a line to the description.
+    pub fn note(mut self, note: String) -> Self {
+        self.description = Some(match self.description {
+            Some(description) => format!("{}\n{}", description, note),
+            None => note,
+        });
+        self
+    }
+
+    pub fn site(mut self, site: &str) -> Self {
+        self.site = Some(site.to_string());
+        self
+    }
+
+    /// Adds the Grid/Solar/Load/Battery fields, or a note that there are no readings.
+    pub fn readings(self, readings: Option<&Readings>, grid_suffix: &str) -> Self {
+        match readings {
+            Some(r) => self
+                .var("grid_w", r.grid_w.to_string())
+                .var("solar_w", r.solar_w.to_string())
+                .var("load_w", r.load_w.to_string())
+                .var("essential_load_w", r.essential_load_w.to_string())
+                .var("battery_pct", r.battery_pct.to_string())
+                .var("battery_w", r.battery_w.to_string())
+                .var("runtime_min", r.runtime_min.map_or(String::new(), |minutes| format!("{:.0}", minutes)))
+                .field("Grid", format!("{}W{}", r.grid_w, grid_suffix))
+                .field("Solar", format!("{}W", r.solar_w))
+                .field("Load", format!("{}W", r.load_w))
+                .field("Battery", format!("{}%", r.battery_pct))
+                .runtime(r.runtime_min),
+            None => self.description("No readings available for this site"),
+        }
+    }
+
+    fn runtime(self, runtime_min: Option<f64>) -> Self {
+        match runtime_min {
+            Some(minutes) => self.field("Runtime", format_runtime(minutes)),
+            None => self,
+        }
+    }
+
+    pub fn content(&self) -> String {
+        let body = self.body();
+        if body.is_empty() {
+            self.title.clone()
+        } else {
+            format!("{}\n{}", self.title, body)
+        }
+    }
+
+    /// The plain text below the title, for services that show the title on its own.
+    pub fn body(&self) -> String {
+        let mut lines = Vec::new();
+        if let Some(site) = &self.site {
+            lines.push(format!("Site: {}", site));
+        }
+        lines.extend(self.description.clone());
+        for (name, value) in &self.fields {
+            lines.push(format!("{}: {}", name, value));
+        }
+        lines.join("\n")
+    }
+
+    pub fn payload(&self, plain: bool) -> Value {
+        if plain {
+            return json!({ "content": self.content() });
+        }
+
+        let mut embed = json!({
+            "title": self.title,
+            "color": self.severity.color(),
+            "timestamp": iso8601_utc(self.timestamp),
+            "fields": self.fields.iter()
+                .map(|(name, value)| json!({ "name": name, "value": value, "inline": name != "Action" }))
+                .collect::<Vec<_>>(),
+            "footer": {
+                "text": match &self.site {
+                    Some(site) => format!("solax-mon • site {}", site),
+                    None => "solax-mon".to_string(),
+                },
+            },
+        });
+        if let Some(description) = &self.description {
+            embed["description"] = json!(description);
+        }
+        json!({ "embeds": [embed] })
+    }
+
+    /// The alert as a Slack incoming-webhook message: Block Kit sections in an attachment of
+    /// the severity's color, with the title as the notification text.
+    pub fn slack_payload(&self) -> Value {
+        let mut text = format!("*{}*", self.title);
+        if let Some(description) = &self.description {
+            text.push_str(&format!("\n{}", description));
+        }
+        let mut blocks = vec![json!({ "type": "section", "text": { "type": "mrkdwn", "text": text } })];
+        // A section holds at most 10 fields
+        for fields in self.fields.chunks(10) {
+            blocks.push(json!({
+                "type": "section",
+                "fields": fields.iter()
+                    .map(|(name, value)| json!({ "type": "mrkdwn", "text": format!("*{}*\n{}", name, value) }))
+                    .collect::<Vec<_>>(),
+            }));
+        }
+        let footer = match &self.site {
+            Some(site) => format!("solax-mon • site {}", site),
+            None => "solax-mon".to_string(),
+        };
+        blocks.push(json!({
+            "type": "context",
+            "elements": [{
+                "type": "mrkdwn",
+                "text": format!("{} • <!date^{}^{{date_short_pretty}} {{time}}|{}>", footer, self.timestamp, iso8601_utc(self.timestamp)),
+            }],
+        }));
+        json!({
+            "text": self.title,
+            "attachments": [{ "color": format!("#{:06X}", self.severity.color()), "blocks": blocks }],
+        })
+    }
+
+    /// The alert as the content of an `m.room.message` event: HTML for clients that render
+    /// it and the plain text of `content()` for the rest.
+    pub fn matrix_content(&self) -> Value {
+        let color = format!("#{:06X}", self.severity.color());
+        let mut html = format!("<h4><font color=\"{}\">{}</font></h4>", color, escape_html(&self.title));
+        if let Some(site) = &self.site {
+            html.push_str(&format!("<p>Site: {}</p>", escape_html(site)));
+        }
+        if let Some(description) = &self.description {
+            html.push_str(&format!("<p>{}</p>", escape_html(description).replace('\n', "<br>")));
+        }
+        if !self.fields.is_empty() {
+            html.push_str("<ul>");
+            for (name, value) in &self.fields {
+                html.push_str(&format!("<li><b>{}</b>: {}</li>", escape_html(name), escape_html(value)));
+            }
+            html.push_str("</ul>");
+        }
+        json!({
+            "msgtype": "m.text",
+            "body": self.content(),
+            "format": "org.matrix.custom.html",
+            "formatted_body": html,
+        })
+    }
+
+    /// The alert as a Gotify message.
+    pub fn gotify_message(&self) -> Value {
+        json!({
+            "title": self.title,
+            "message": self.body(),
+            "priority": self.severity.gotify_priority(),
+        })
+    }
+}
+
+/// Escapes text for the HTML body of a Matrix message.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Fills `{{ name }}` placeholders from `vars`; an unknown name or an unclosed placeholder
+/// is an error.
+pub fn render_template(template: &str, vars: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or_else(|| format!("Unclosed {{{{ at {:?}", &rest[start..].lines().next().unwrap_or_default()))?;
+        let name = after[..end].trim();
+        out.push_str(vars.get(name).ok_or_else(|| format!("Unknown variable {:?}", name))?);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Message templates from NOTIFY_TEMPLATES_DIR, one `<event>.txt` per event type. The first
+/// line of a rendered template is the title and the rest the description; the fields are
+/// left out, being available to the template as variables.
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    templates: BTreeMap<String, String>,
+}
+
+impl Templates {
+    /// Reads every `.txt` file in `dir`. A missing directory means the built-in messages only.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut templates = BTreeMap::new();
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read templates from {}", dir.display())),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let Some(event) = path.file_stem().filter(|_| path.extension().is_some_and(|ext| ext == "txt")) else {
+                continue;
+            };
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read template {}", path.display()))?;
+            templates.insert(event.to_string_lossy().to_string(), text.trim_end().to_string());
+        }
+        Ok(Self { templates })
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &str> {
+        self.templates.keys().map(String::as_str)
+    }
+
+    /// The variables a template of `alert` can use: its own, its fields under snake_case
+    /// names, and the built-in title, description and site.
+    pub fn variables(alert: &Alert) -> BTreeMap<String, String> {
+        let mut vars = BTreeMap::new();
+        for (name, value) in &alert.fields {
+            let name: String = name.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+                .collect();
+            vars.insert(name, value.clone());
+        }
+        // The alert's own variables are plainer than its fields
+        vars.extend(alert.vars.clone());
+        vars.insert("title".to_string(), alert.title.clone());
+        vars.insert("description".to_string(), alert.description.clone().unwrap_or_default());
+        vars.insert("site".to_string(), alert.site.clone().unwrap_or_default());
+        vars.insert("time".to_string(), iso8601_utc(alert.timestamp));
+        vars
+    }
+
+    /// The alert as its template words it, or unchanged without one. A template that fails
+    /// to render is logged and the built-in message sent instead.
+    pub fn apply(&self, alert: &Alert) -> Alert {
+        let event = alert.event.as_deref().unwrap_or(alert.severity.event());
+        let Some(template) = self.templates.get(event) else {
+            return alert.clone();
+        };
+        match render_template(template, &Self::variables(alert)) {
+            Ok(text) => {
+                let (title, description) = text.split_once('\n').unwrap_or((&text, ""));
+                Alert {
+                    title: title.trim().to_string(),
+                    description: Some(description.trim().to_string()).filter(|description| !description.is_empty()),
+                    fields: Vec::new(),
+                    ..alert.clone()
+                }
+            }
+            Err(e) => {
+                eprintln!("Template {}.txt failed, sending the built-in message: {}", event, e);
+                alert.clone()
+            }
+        }
+    }
+}
+
+/// Formats a runtime estimate like `42 min`, or `over 24 h` at the cap.
+pub fn format_runtime(minutes: f64) -> String {
+    if minutes >= MAX_RUNTIME_MIN {
+        "over 24 h".to_string()
+    } else {
+        format!("{:.0} min", minutes)
+    }
+}
+
+/// Formats a unix timestamp as an ISO 8601 UTC date-time, as Discord expects.
+pub fn iso8601_utc(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+pub async fn send_discord_alert(http: &crate::outbound::Clients, webhook_url: &str, alert: &Alert, plain: bool) -> Result<()> {
+    let payload = alert.payload(plain);
+    send_with_retry(http, webhook_url, "Discord webhook", |client| client.post(webhook_url).json(&payload)).await
+}
+
+/// Posts an alert to a Slack incoming webhook.
+pub async fn send_slack_alert(http: &crate::outbound::Clients, webhook_url: &str, alert: &Alert) -> Result<()> {
+    let payload = alert.slack_payload();
+    send_with_retry(http, webhook_url, "Slack webhook", |client| client.post(webhook_url).json(&payload)).await
+}
+
+/// Sends the request `build` makes to `url`, waiting out the rate limits of `service`:
+/// Discord, Slack, Gotify and Pushover all answer 429, most with a Retry-After.
+async fn send_with_retry(
+    http: &crate::outbound::Clients,
+    url: &str,
+    service: &str,
+    build: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+) -> Result<()> {
+    let client = http.for_url(url);
+
+    let mut attempt = 1;
+    let response = loop {
+        let response = build(client)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to send {} request: {}", service, http.describe_error(url, &e.without_url())))?;
+
+        // The service asks us to back off; wait as long as it says and send again
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS && attempt < DISCORD_MAX_ATTEMPTS {
+            let wait = retry_after(response.headers()).min(DISCORD_MAX_RETRY_AFTER);
+            println!("{} rate limited us, retrying in {:.1}s", service, wait.as_secs_f64());
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+            continue;
+        }
+        break response;
+    };
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        anyhow::bail!(
+            "{} failed with status {}: {}", 
+            service,
+            status,
+            error_text
+        );
+    }
+
+    Ok(())
+}
+
+/// A Matrix room alerts are posted to through the client-server API, as the user the access
+/// token belongs to.
+#[derive(Clone)]
+pub struct MatrixTarget {
+    /// `https://matrix.example.org`, without the `/_matrix` path.
+    pub homeserver: String,
+    pub access_token: String,
+    /// `!abcdef:example.org`
+    pub room_id: String,
+}
+
+impl std::fmt::Debug for MatrixTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MatrixTarget")
+            .field("homeserver", &self.homeserver)
+            .field("room_id", &self.room_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MatrixTarget {
+    /// The URL a message with transaction id `txn_id` is PUT to.
+    pub fn send_url(&self, txn_id: &str) -> Result<String> {
+        let mut url = reqwest::Url::parse(&self.homeserver)
+            .with_context(|| format!("Invalid MATRIX_HOMESERVER {:?}", self.homeserver))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid MATRIX_HOMESERVER {:?}", self.homeserver))?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3", "rooms", &self.room_id, "send", "m.room.message", txn_id]);
+        Ok(url.to_string())
+    }
+}
+
+/// A transaction id no other message of this or an earlier run has.
+fn matrix_txn_id() -> String {
+    static SENT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos();
+    format!("solax-mon-{}-{}", nanos, SENT.fetch_add(1, std::sync::atomic::Ordering::SeqCst))
+}
+
+/// Posts an alert to a Matrix room. Every attempt reuses one transaction id, so the
+/// homeserver drops a retry of a message that did arrive before the timeout.
+pub async fn send_matrix_alert(http: &crate::outbound::Clients, target: &MatrixTarget, alert: &Alert) -> Result<()> {
+    let url = target.send_url(&matrix_txn_id())?;
+    let client = http.for_url(&url);
+    let content = alert.matrix_content();
+
+    let mut attempt = 1;
+    loop {
+        let result = client.put(&url)
+            .bearer_auth(&target.access_token)
+            .json(&content)
+            .send()
+            .await;
+        let response = match result {
+            Ok(response) => response,
+            Err(e) if attempt < DISCORD_MAX_ATTEMPTS => {
+                println!("Matrix request failed ({}), retrying", http.describe_error(&target.homeserver, &e));
+                tokio::time::sleep(Duration::from_secs(attempt.into())).await;
+                attempt += 1;
+                continue;
+            }
+            Err(e) => anyhow::bail!("Failed to send Matrix message: {}", http.describe_error(&target.homeserver, &e)),
+        };
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS && attempt < DISCORD_MAX_ATTEMPTS {
+            let header_wait = retry_after(response.headers());
+            let body: Value = response.json().await.unwrap_or_default();
+            let wait = body["retry_after_ms"].as_u64().map_or(header_wait, Duration::from_millis).min(DISCORD_MAX_RETRY_AFTER);
+            println!("Matrix rate limited us, retrying in {:.1}s", wait.as_secs_f64());
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+            continue;
+        }
+        let body: Value = response.json().await.unwrap_or_default();
+        let reason = format!("{} {}", body["errcode"].as_str().unwrap_or_default(), body["error"].as_str().unwrap_or_default());
+        match status {
+            reqwest::StatusCode::UNAUTHORIZED => anyhow::bail!(
+                "Matrix rejected MATRIX_ACCESS_TOKEN ({}); it has expired or been logged out, configure a new one",
+                reason.trim(),
+            ),
+            reqwest::StatusCode::FORBIDDEN => anyhow::bail!(
+                "Matrix refused to post to {} ({}); invite the token's user to the room",
+                target.room_id,
+                reason.trim(),
+            ),
+            _ => anyhow::bail!("Matrix message failed with status {}: {}", status, reason.trim()),
+        }
+    }
+}
+
+/// A Gotify server and the token of the application alerts are sent as.
+#[derive(Clone)]
+pub struct GotifyTarget {
+    pub url: String,
+    pub token: String,
+}
+
+impl std::fmt::Debug for GotifyTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("GotifyTarget").field("url", &self.url).finish_non_exhaustive()
+    }
+}
+
+/// Pushes an alert to a Gotify server.
+pub async fn send_gotify_alert(http: &crate::outbound::Clients, target: &GotifyTarget, alert: &Alert) -> Result<()> {
+    let url = format!("{}/message", target.url.trim_end_matches('/'));
+    let message = alert.gotify_message();
+    send_with_retry(http, &url, "Gotify", |client| {
+        client.post(&url).header("X-Gotify-Key", &target.token).json(&message)
+    }).await
+}
+
+pub const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+/// Pushover refuses longer messages.
+const PUSHOVER_MAX_MESSAGE: usize = 1024;
+
+/// A Pushover user (or group) and the application alerts are sent as.
+#[derive(Clone)]
+pub struct PushoverTarget {
+    pub user_key: String,
+    pub token: String,
+    /// How often an emergency alert is repeated until acknowledged (at least 30 s).
+    pub retry: Duration,
+    /// How long an emergency alert is repeated for (at most 3 hours).
+    pub expire: Duration,
+}
+
+impl std::fmt::Debug for PushoverTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PushoverTarget")
+            .field("retry", &self.retry)
+            .field("expire", &self.expire)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PushoverTarget {
+    /// The form fields of a Pushover message for `alert`.
+    pub fn form(&self, alert: &Alert) -> Vec<(&'static str, String)> {
+        let mut message = alert.body();
+        if message.is_empty() {
+            // Pushover needs a message
+            message = alert.title.clone();
+        }
+        if message.len() > PUSHOVER_MAX_MESSAGE {
+            let mut end = PUSHOVER_MAX_MESSAGE - 3;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message = format!("{}...", &message[..end]);
+        }
+        let priority = alert.severity.pushover_priority();
+        let mut form = vec![
+            ("token", self.token.clone()),
+            ("user", self.user_key.clone()),
+            ("title", alert.title.clone()),
+            ("message", message),
+            ("priority", priority.to_string()),
+            ("timestamp", alert.timestamp.to_string()),
+        ];
+        if priority == 2 {
+            form.push(("retry", self.retry.as_secs().max(30).to_string()));
+            form.push(("expire", self.expire.as_secs().min(10_800).to_string()));
+        }
+        form
+    }
+}
+
+/// Pushes an alert through Pushover.
+pub async fn send_pushover_alert(http: &crate::outbound::Clients, target: &PushoverTarget, alert: &Alert) -> Result<()> {
+    let form = target.form(alert);
+    send_with_retry(http, PUSHOVER_URL, "Pushover", |client| client.post(PUSHOVER_URL).form(&form)).await
+}
+
+pub const DISCORD_MAX_ATTEMPTS: u32 = 5;
+pub const DISCORD_MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Reads the Retry-After header of a 429 (seconds, possibly fractional).
+pub fn retry_after(headers: &reqwest::header::HeaderMap) -> Duration {
+    headers.get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .unwrap_or(Duration::from_secs(1))
+}
+
+/// The notifiers alerts can be routed to, by their name in NOTIFY_ROUTE.
+pub const NOTIFIERS: [&str; 5] = ["discord", "slack", "matrix", "gotify", "pushover"];
+
+/// Which alerts one notifier gets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub min_severity: Severity,
+    /// Event types (as templated, e.g. `critical`, `digest`) the notifier gets; all when empty.
+    pub events: Vec<String>,
+}
+
+impl std::fmt::Display for Route {
+    /// The options of the NOTIFY_ROUTE line.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "min={}", self.min_severity.name())?;
+        if !self.events.is_empty() {
+            write!(f, ",events={}", self.events.join("+"))?;
+        }
+        Ok(())
+    }
+}
+
+/// NOTIFY_ROUTE lines, one per notifier; a notifier without one gets every alert.
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    routes: BTreeMap<String, Route>,
+}
+
+impl Routes {
+    /// Parses `<notifier>,min=<severity>,events=<event>+<event>`.
+    pub fn add(&mut self, line: &str) -> Result<(), String> {
+        let mut parts = line.split(',').map(str::trim);
+        let notifier = parts.next().unwrap_or_default().to_lowercase();
+        if !NOTIFIERS.contains(&notifier.as_str()) {
+            return Err(format!("Unknown notifier {:?} in NOTIFY_ROUTE ({})", notifier, NOTIFIERS.join(", ")));
+        }
+        let mut route = Route { min_severity: Severity::Info, events: Vec::new() };
+        for option in parts {
+            match option.split_once('=') {
+                Some(("min", severity)) => route.min_severity = Severity::parse(severity)?,
+                Some(("events", events)) => route.events = events.split('+').map(|event| event.trim().to_string()).collect(),
+                _ => return Err(format!("Invalid NOTIFY_ROUTE option {:?} (min=, events=)", option)),
+            }
+        }
+        self.routes.insert(notifier, route);
+        Ok(())
+    }
+
+    pub fn get(&self, notifier: &str) -> Option<&Route> {
+        self.routes.get(notifier)
+    }
+
+    /// Whether `notifier` gets `alert`, or why not.
+    pub fn check(&self, notifier: &str, alert: &Alert) -> Result<(), String> {
+        let Some(route) = self.routes.get(notifier) else {
+            return Ok(());
+        };
+        if alert.severity.rank() < route.min_severity.rank() {
+            return Err(format!("{} is below its minimum {}", alert.severity.name(), route.min_severity.name()));
+        }
+        let event = alert.event.as_deref().unwrap_or(alert.severity.event());
+        if !route.events.is_empty() && !route.events.iter().any(|allowed| allowed == event) {
+            return Err(format!("event {} is not among {}", event, route.events.join("+")));
+        }
+        Ok(())
+    }
+}
+
+/// Suppresses duplicate notifications within a window and caps how many are sent per hour.
+/// Critical alerts always go through.
+#[derive(Debug)]
+pub struct Governor {
+    dedup_window: Duration,
+    max_per_hour: usize,
+    last_sent: HashMap<String, std::time::Instant>,
+    suppressed: HashMap<String, u32>,
+    sent: VecDeque<std::time::Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    /// Send it, mentioning how many identical ones were held back before it.
+    Send { suppressed: u32 },
+    Duplicate,
+    OverCap,
+}
+
+impl Governor {
+    pub fn new(dedup_window: Duration, max_per_hour: usize) -> Self {
+        Self {
+            dedup_window,
+            max_per_hour,
+            last_sent: HashMap::new(),
+            suppressed: HashMap::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    pub fn admit(&mut self, key: &str, critical: bool, now: std::time::Instant) -> Admission {
+        let hour = Duration::from_secs(3600);
+        while self.sent.front().is_some_and(|sent| now.duration_since(*sent) >= hour) {
+            self.sent.pop_front();
+        }
+
+        if !critical {
+            let duplicate = self.last_sent.get(key)
+                .is_some_and(|last| now.duration_since(*last) < self.dedup_window);
+            let held_back = if duplicate {
+                Some(Admission::Duplicate)
+            } else if self.sent.len() >= self.max_per_hour {
+                Some(Admission::OverCap)
+            } else {
+                None
+            };
+            if let Some(admission) = held_back {
+                *self.suppressed.entry(key.to_string()).or_default() += 1;
+                return admission;
+            }
+        }
+
+        self.last_sent.insert(key.to_string(), now);
+        self.sent.push_back(now);
+        Admission::Send { suppressed: self.suppressed.remove(key).unwrap_or(0) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn critical_alert_embed() {
+        let alert = Alert::new(Severity::Critical, "🚨 CRITICAL POWER ALERT!")
+            .site("house")
+            .readings(Some(&Readings { grid_w: 0.0, solar_w: 200.0, load_w: 900.0, battery_pct: 8.0, ..Readings::default() }), " (Offline)")
+            .field("Action", "Initiating server shutdown sequence...");
+        let alert = Alert { timestamp: 1_700_000_000, ..alert };
+        let payload = alert.payload(false);
+
+        assert!(payload.get("content").is_none());
+        let embed = &payload["embeds"][0];
+        assert_eq!(embed["title"], "🚨 CRITICAL POWER ALERT!");
+        assert_eq!(embed["color"], 0xE74C3C);
+        assert_eq!(embed["timestamp"], "2023-11-14T22:13:20Z");
+        assert_eq!(embed["footer"]["text"], "solax-mon • site house");
+        let names: Vec<&str> = embed["fields"].as_array().unwrap().iter()
+            .map(|field| field["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["Grid", "Solar", "Load", "Battery", "Action"]);
+        assert_eq!(embed["fields"][0]["value"], "0W (Offline)");
+        assert_eq!(embed["fields"][3]["value"], "8%");
+        assert_eq!(embed["fields"][4]["inline"], false);
+    }
+
+    #[test]
+    fn alert_colors_and_plain_fallback() {
+        let normal = Alert::new(Severity::Normal, "✅ Power conditions normalized!").readings(None, "");
+        assert_eq!(normal.payload(false)["embeds"][0]["color"], 0x2ECC71);
+        assert_eq!(normal.payload(false)["embeds"][0]["description"], "No readings available for this site");
+        assert_eq!(Alert::new(Severity::Warning, "w").payload(false)["embeds"][0]["color"], 0xF1C40F);
+
+        let plain = Alert::new(Severity::Critical, "🚨 CRITICAL POWER ALERT!")
+            .site("house")
+            .field("Battery", "8%")
+            .payload(true);
+        assert_eq!(plain, json!({ "content": "🚨 CRITICAL POWER ALERT!\nSite: house\nBattery: 8%" }));
+    }
+
+    #[test]
+    fn slack_block_kit_message() {
+        let alert = Alert::new(Severity::Critical, "🚨 CRITICAL POWER ALERT!")
+            .site("house")
+            .description("Grid is down")
+            .readings(Some(&Readings { grid_w: 0.0, solar_w: 200.0, load_w: 900.0, battery_pct: 8.0, ..Readings::default() }), " (Offline)");
+        let payload = Alert { timestamp: 1_700_000_000, ..alert }.slack_payload();
+
+        assert_eq!(payload["text"], "🚨 CRITICAL POWER ALERT!");
+        let attachment = &payload["attachments"][0];
+        assert_eq!(attachment["color"], "#E74C3C");
+        let blocks = attachment["blocks"].as_array().unwrap();
+        assert_eq!(blocks[0]["text"]["text"], "*🚨 CRITICAL POWER ALERT!*\nGrid is down");
+        assert_eq!(blocks[1]["fields"][0], json!({ "type": "mrkdwn", "text": "*Grid*\n0W (Offline)" }));
+        assert_eq!(blocks[1]["fields"].as_array().unwrap().len(), 4);
+        assert_eq!(
+            blocks[2]["elements"][0]["text"],
+            "solax-mon • site house • <!date^1700000000^{date_short_pretty} {time}|2023-11-14T22:13:20Z>",
+        );
+
+        // No more than 10 fields per section
+        let many = (0..12).fold(Alert::new(Severity::Info, "i"), |alert, n| alert.field(&n.to_string(), "x"));
+        let blocks = many.slack_payload()["attachments"][0]["blocks"].as_array().unwrap().clone();
+        assert_eq!((blocks.len(), blocks[2]["fields"].as_array().unwrap().len()), (4, 2));
+    }
+
+    #[test]
+    fn matrix_message() {
+        let alert = Alert::new(Severity::Warning, "⚠️ Low battery <house>")
+            .description("Battery is low\nGrid is up")
+            .field("Battery", "18%");
+        let content = alert.matrix_content();
+        assert_eq!(content["msgtype"], "m.text");
+        assert_eq!(content["body"], "⚠️ Low battery <house>\nBattery is low\nGrid is up\nBattery: 18%");
+        assert_eq!(content["format"], "org.matrix.custom.html");
+        assert_eq!(
+            content["formatted_body"],
+            "<h4><font color=\"#F1C40F\">⚠️ Low battery &lt;house&gt;</font></h4><p>Battery is low<br>Grid is up</p><ul><li><b>Battery</b>: 18%</li></ul>",
+        );
+
+        let target = MatrixTarget {
+            homeserver: "https://matrix.example.org/".to_string(),
+            access_token: "syt_secret".to_string(),
+            room_id: "!abc:example.org".to_string(),
+        };
+        assert_eq!(
+            target.send_url("solax-mon-1").unwrap(),
+            "https://matrix.example.org/_matrix/client/v3/rooms/!abc:example.org/send/m.room.message/solax-mon-1",
+        );
+        assert!(!format!("{:?}", target).contains("syt_secret"));
+        assert_ne!(matrix_txn_id(), matrix_txn_id());
+    }
+
+    #[test]
+    fn gotify_and_pushover_messages() {
+        let alert = Alert::new(Severity::Critical, "🚨 CRITICAL POWER ALERT!")
+            .site("house")
+            .field("Battery", "8%");
+        assert_eq!(alert.gotify_message()["message"], "Site: house\nBattery: 8%");
+        assert_eq!(alert.gotify_message()["priority"], 10);
+        assert_eq!(Alert::new(Severity::Info, "i").gotify_message()["priority"], 2);
+
+        let target = PushoverTarget {
+            user_key: "u".to_string(),
+            token: "t".to_string(),
+            retry: Duration::from_secs(10),
+            expire: Duration::from_secs(3600),
+        };
+        let form: BTreeMap<&str, String> = target.form(&alert).into_iter().collect();
+        assert_eq!(form["priority"], "2");
+        // Pushover's bounds on the emergency repeats
+        assert_eq!((form["retry"].as_str(), form["expire"].as_str()), ("30", "3600"));
+        assert_eq!(form["title"], "🚨 CRITICAL POWER ALERT!");
+
+        let warning: BTreeMap<&str, String> = target.form(&Alert::new(Severity::Warning, "⚠️ w")).into_iter().collect();
+        assert_eq!((warning["priority"].as_str(), warning["message"].as_str()), ("0", "⚠️ w"));
+        assert!(!warning.contains_key("retry"));
+        let long = Alert::new(Severity::Info, "i").description("é".repeat(1000));
+        let message = &target.form(&long)[3].1;
+        assert!(message.len() <= 1024 && message.ends_with("..."));
+        assert!(!format!("{:?}", target).contains("\"t\""));
+    }
+
+    #[test]
+    fn routes_filter_by_severity_and_event() {
+        let mut routes = Routes::default();
+        routes.add("pushover,min=critical").unwrap();
+        routes.add("Slack, min=warning, events=warning+digest").unwrap();
+        assert!(routes.add("email,min=info").is_err());
+        assert!(routes.add("gotify,min=loud").is_err());
+        assert!(routes.add("gotify,max=info").is_err());
+
+        let critical = Alert::new(Severity::Critical, "c");
+        let warning = Alert::new(Severity::Warning, "w");
+        let digest = Alert::new(Severity::Info, "d").event("digest");
+        assert_eq!(routes.check("pushover", &critical), Ok(()));
+        assert_eq!(routes.check("pushover", &warning), Err("warning is below its minimum critical".to_string()));
+        assert_eq!(routes.check("slack", &warning), Ok(()));
+        assert_eq!(routes.check("slack", &critical), Err("event critical is not among warning+digest".to_string()));
+        assert!(routes.check("slack", &digest).is_err());
+        // No route, every alert
+        assert_eq!(routes.check("discord", &digest), Ok(()));
+        assert_eq!(routes.get("slack").unwrap().to_string(), "min=warning,events=warning+digest");
+    }
+
+    #[test]
+    fn templates_reword_alerts() {
+        let dir = std::env::temp_dir().join(format!("solax-templates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("critical.txt"), "POWER ALERT at {{ site }}\nBattery {{battery_pct}}%, about {{ runtime_min }} min left.\nShutting down: {{ servers }}\n").unwrap();
+        std::fs::write(dir.join("warning.txt"), "{{ nonsense }}").unwrap();
+        std::fs::write(dir.join("notes.md"), "not a template").unwrap();
+        let templates = Templates::load(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(templates.events().collect::<Vec<_>>(), ["critical", "warning"]);
+
+        let readings = Readings { battery_pct: 8.0, runtime_min: Some(41.6), ..Readings::default() };
+        let alert = Alert::new(Severity::Critical, "🚨 CRITICAL POWER ALERT!")
+            .site("house")
+            .readings(Some(&readings), " (Offline)")
+            .var("servers", "nas, backup")
+            .field("Action", "⚠️ Initiating server shutdown sequence...");
+        let templated = templates.apply(&alert);
+        assert_eq!(templated.title, "POWER ALERT at house");
+        assert_eq!(templated.description.as_deref(), Some("Battery 8%, about 42 min left.\nShutting down: nas, backup"));
+        assert!(templated.fields.is_empty());
+        assert_eq!(templated.severity, Severity::Critical);
+
+        // A broken template falls back to the built-in message, and other events keep theirs
+        let warning = Alert::new(Severity::Warning, "🔋 Low battery at house").field("Battery", "18%");
+        assert_eq!(templates.apply(&warning).content(), warning.content());
+        let normal = Alert::new(Severity::Normal, "✅ Power conditions normalized!");
+        assert_eq!(templates.apply(&normal).title, normal.title);
+        assert_eq!(Templates::variables(&warning)["battery"], "18%");
+
+        assert!(render_template("{{ site", &BTreeMap::new()).is_err());
+        assert!(Templates::load(Path::new("/nonexistent/templates")).unwrap().events().next().is_none());
+    }
+
+    #[test]
+    fn governor_suppresses_duplicates() {
+        let mut governor = Governor::new(Duration::from_secs(600), 20);
+        let start = std::time::Instant::now();
+        assert_eq!(governor.admit("a", false, start), Admission::Send { suppressed: 0 });
+        for i in 1..=4 {
+            assert_eq!(governor.admit("a", false, start + Duration::from_secs(i * 60)), Admission::Duplicate);
+        }
+        assert_eq!(governor.admit("b", false, start), Admission::Send { suppressed: 0 });
+        assert_eq!(governor.admit("a", false, start + Duration::from_secs(601)), Admission::Send { suppressed: 4 });
+    }
+
+    #[test]
+    fn governor_caps_all_but_critical() {
+        let mut governor = Governor::new(Duration::ZERO, 2);
+        let start = std::time::Instant::now();
+        assert_eq!(governor.admit("a", false, start), Admission::Send { suppressed: 0 });
+        assert_eq!(governor.admit("b", false, start), Admission::Send { suppressed: 0 });
+        assert_eq!(governor.admit("c", false, start), Admission::OverCap);
+        assert_eq!(governor.admit("shutdown", true, start), Admission::Send { suppressed: 0 });
+        assert_eq!(governor.admit("c", false, start + Duration::from_secs(3600)), Admission::Send { suppressed: 1 });
+    }
+
+    #[test]
+    fn retry_after_header() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(retry_after(&headers), Duration::from_secs(1));
+        headers.insert(reqwest::header::RETRY_AFTER, "2.5".parse().unwrap());
+        assert_eq!(retry_after(&headers), Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn iso8601_timestamps() {
+        assert_eq!(iso8601_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601_utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(iso8601_utc(1_792_035_905), "2026-10-15T03:45:05Z");
+    }
+}
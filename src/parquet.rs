@@ -0,0 +1,388 @@
+//! A minimal Parquet writer for the `export` subcommand. Columns are flat and nullable, PLAIN
+//! encoded and uncompressed, with one data page per column in each row group, which is what
+//! pandas, Polars, DuckDB and Spark all read.
+
+use std::io::{self, Write};
+
+/// What a column holds, which decides its physical and converted type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Milliseconds since the Unix epoch, UTC (INT64 TIMESTAMP_MILLIS).
+    TimestampMillis,
+    /// Days since the Unix epoch (INT32 DATE).
+    Date,
+    Int64,
+    Double,
+}
+
+impl Kind {
+    fn physical_type(self) -> i32 {
+        match self {
+            Kind::Date => 1,
+            Kind::TimestampMillis | Kind::Int64 => 2,
+            Kind::Double => 5,
+        }
+    }
+
+    fn converted_type(self) -> Option<i32> {
+        match self {
+            Kind::Date => Some(6),
+            Kind::TimestampMillis => Some(9),
+            Kind::Int64 | Kind::Double => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub kind: Kind,
+}
+
+impl Column {
+    pub fn new(name: impl Into<String>, kind: Kind) -> Self {
+        Self { name: name.into(), kind }
+    }
+}
+
+/// The values of one column in a row group, `None` for a null. Dates and timestamps are `Int`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Values {
+    Int(Vec<Option<i64>>),
+    Double(Vec<Option<f64>>),
+}
+
+impl Values {
+    pub fn len(&self) -> usize {
+        match self {
+            Values::Int(values) => values.len(),
+            Values::Double(values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn present(&self) -> Vec<bool> {
+        match self {
+            Values::Int(values) => values.iter().map(Option::is_some).collect(),
+            Values::Double(values) => values.iter().map(Option::is_some).collect(),
+        }
+    }
+
+    /// The non-null values, PLAIN encoded for `kind`.
+    fn plain(&self, kind: Kind) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        match (self, kind) {
+            (Values::Int(values), Kind::Date) => {
+                for value in values.iter().flatten() {
+                    let days = i32::try_from(*value).map_err(|_| invalid(format!("Date {} out of range", value)))?;
+                    bytes.extend(days.to_le_bytes());
+                }
+            }
+            (Values::Int(values), Kind::TimestampMillis | Kind::Int64) => {
+                values.iter().flatten().for_each(|value| bytes.extend(value.to_le_bytes()));
+            }
+            (Values::Double(values), Kind::Double) => {
+                values.iter().flatten().for_each(|value| bytes.extend(value.to_le_bytes()));
+            }
+            _ => return Err(invalid(format!("{:?} values can't go into a {:?} column", self, kind))),
+        }
+        Ok(bytes)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Thrift compact protocol, which the page headers and the footer are written in.
+struct Compact {
+    bytes: Vec<u8>,
+    /// The last field id written in each struct being written, innermost last.
+    last_ids: Vec<i16>,
+}
+
+const I32: u8 = 5;
+const I64: u8 = 6;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+impl Compact {
+    fn new() -> Self {
+        Self { bytes: Vec::new(), last_ids: vec![0] }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    fn zigzag(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        let last = self.last_ids.last_mut().expect("inside a struct");
+        let delta = id - *last;
+        *last = id;
+        if (1..=15).contains(&delta) {
+            self.bytes.push((delta as u8) << 4 | kind);
+        } else {
+            self.bytes.push(kind);
+            self.zigzag(id.into());
+        }
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, I32);
+        self.zigzag(value.into());
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, I64);
+        self.zigzag(value);
+    }
+
+    fn binary(&mut self, id: i16, value: &[u8]) {
+        self.field(id, BINARY);
+        self.varint(value.len() as u64);
+        self.bytes.extend(value);
+    }
+
+    fn list(&mut self, id: i16, element: u8, size: usize) {
+        self.field(id, LIST);
+        if size < 15 {
+            self.bytes.push((size as u8) << 4 | element);
+        } else {
+            self.bytes.push(0xf0 | element);
+            self.varint(size as u64);
+        }
+    }
+
+    fn begin_struct(&mut self, id: i16) {
+        self.field(id, STRUCT);
+        self.last_ids.push(0);
+    }
+
+    /// Starts a struct that is an element of a list, which has no field header.
+    fn begin_element(&mut self) {
+        self.last_ids.push(0);
+    }
+
+    fn end_struct(&mut self) {
+        self.bytes.push(0);
+        self.last_ids.pop();
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.end_struct();
+        self.bytes
+    }
+}
+
+/// Definition levels (1 for a value, 0 for a null) as RLE runs, with their length in front.
+fn definition_levels(present: &[bool]) -> Vec<u8> {
+    let mut runs = Compact::new();
+    let mut rest = present;
+    while let Some(&first) = rest.first() {
+        let run = rest.iter().take_while(|&&value| value == first).count();
+        runs.varint((run as u64) << 1);
+        runs.bytes.push(u8::from(first));
+        rest = &rest[run..];
+    }
+    let mut bytes = (runs.bytes.len() as u32).to_le_bytes().to_vec();
+    bytes.extend(runs.bytes);
+    bytes
+}
+
+/// A column's data page: its header and the levels and values.
+fn data_page(kind: Kind, values: &Values) -> io::Result<Vec<u8>> {
+    let mut data = definition_levels(&values.present());
+    data.extend(values.plain(kind)?);
+    let size = i32::try_from(data.len()).map_err(|_| invalid("Row group too large for one page".to_string()))?;
+    let mut header = Compact::new();
+    // DATA_PAGE, PLAIN values, RLE definition and repetition levels
+    header.i32(1, 0);
+    header.i32(2, size);
+    header.i32(3, size);
+    header.begin_struct(5);
+    header.i32(1, values.len() as i32);
+    header.i32(2, 0);
+    header.i32(3, 3);
+    header.i32(4, 3);
+    header.end_struct();
+    let mut page = header.finish();
+    page.extend(data);
+    Ok(page)
+}
+
+struct ChunkMeta {
+    offset: u64,
+    size: u64,
+    values: usize,
+}
+
+struct RowGroupMeta {
+    rows: usize,
+    chunks: Vec<ChunkMeta>,
+}
+
+/// Writes row groups as they come, so only one is held in memory, and the footer on `finish`.
+pub struct Writer<W: Write> {
+    out: W,
+    columns: Vec<Column>,
+    offset: u64,
+    row_groups: Vec<RowGroupMeta>,
+}
+
+impl<W: Write> Writer<W> {
+    /// Starts a file with the given columns, whose names must be unique.
+    pub fn new(mut out: W, columns: Vec<Column>) -> io::Result<Self> {
+        for (index, column) in columns.iter().enumerate() {
+            if columns[..index].iter().any(|other| other.name == column.name) {
+                return Err(invalid(format!("Duplicate column {:?}", column.name)));
+            }
+        }
+        out.write_all(b"PAR1")?;
+        Ok(Self { out, columns, offset: 4, row_groups: Vec::new() })
+    }
+
+    /// Appends a row group, one `Values` per column in schema order. An empty one is skipped.
+    pub fn write_row_group(&mut self, values: &[Values]) -> io::Result<()> {
+        if values.len() != self.columns.len() {
+            return Err(invalid(format!("{} columns given for a schema of {}", values.len(), self.columns.len())));
+        }
+        let rows = values.first().map_or(0, Values::len);
+        if values.iter().any(|column| column.len() != rows) {
+            return Err(invalid("Columns of different lengths".to_string()));
+        }
+        if rows == 0 {
+            return Ok(());
+        }
+        let pages = self.columns.iter().zip(values)
+            .map(|(column, values)| data_page(column.kind, values))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut chunks = Vec::new();
+        for page in pages {
+            self.out.write_all(&page)?;
+            chunks.push(ChunkMeta { offset: self.offset, size: page.len() as u64, values: rows });
+            self.offset += page.len() as u64;
+        }
+        self.row_groups.push(RowGroupMeta { rows, chunks });
+        Ok(())
+    }
+
+    /// Writes the footer and hands back the output.
+    pub fn finish(mut self) -> io::Result<W> {
+        let footer = self.footer();
+        self.out.write_all(&footer)?;
+        self.out.write_all(&(footer.len() as u32).to_le_bytes())?;
+        self.out.write_all(b"PAR1")?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn footer(&self) -> Vec<u8> {
+        let mut meta = Compact::new();
+        meta.i32(1, 1);
+        meta.list(2, STRUCT, self.columns.len() + 1);
+        meta.begin_element();
+        meta.binary(4, b"schema");
+        meta.i32(5, self.columns.len() as i32);
+        meta.end_struct();
+        for column in &self.columns {
+            meta.begin_element();
+            meta.i32(1, column.kind.physical_type());
+            // OPTIONAL
+            meta.i32(3, 1);
+            meta.binary(4, column.name.as_bytes());
+            if let Some(converted) = column.kind.converted_type() {
+                meta.i32(6, converted);
+            }
+            meta.end_struct();
+        }
+        meta.i64(3, self.row_groups.iter().map(|group| group.rows as i64).sum());
+        meta.list(4, STRUCT, self.row_groups.len());
+        for group in &self.row_groups {
+            meta.begin_element();
+            meta.list(1, STRUCT, group.chunks.len());
+            for (column, chunk) in self.columns.iter().zip(&group.chunks) {
+                meta.begin_element();
+                meta.i64(2, chunk.offset as i64);
+                meta.begin_struct(3);
+                meta.i32(1, column.kind.physical_type());
+                meta.list(2, I32, 2);
+                meta.zigzag(0);
+                meta.zigzag(3);
+                meta.list(3, BINARY, 1);
+                meta.varint(column.name.len() as u64);
+                meta.bytes.extend(column.name.as_bytes());
+                // UNCOMPRESSED
+                meta.i32(4, 0);
+                meta.i64(5, chunk.values as i64);
+                meta.i64(6, chunk.size as i64);
+                meta.i64(7, chunk.size as i64);
+                meta.i64(9, chunk.offset as i64);
+                meta.end_struct();
+                meta.end_struct();
+            }
+            meta.i64(2, group.chunks.iter().map(|chunk| chunk.size as i64).sum());
+            meta.i64(3, group.rows as i64);
+            meta.end_struct();
+        }
+        meta.binary(6, concat!("solax-mon version ", env!("CARGO_PKG_VERSION")).as_bytes());
+        meta.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_pages_and_footer_in_the_parquet_layout() {
+        let mut writer = Writer::new(Vec::new(), vec![Column::new("value", Kind::Double)]).unwrap();
+        writer.write_row_group(&[Values::Double(vec![Some(1.5), None])]).unwrap();
+        writer.write_row_group(&[Values::Double(Vec::new())]).unwrap();
+        let file = writer.finish().unwrap();
+
+        let page = [
+            // Page header: DATA_PAGE of 16 bytes, 2 values, PLAIN, RLE levels
+            0x15, 0x00, 0x15, 0x20, 0x15, 0x20, 0x2c, 0x15, 0x04, 0x15, 0x00, 0x15, 0x06, 0x15, 0x06, 0x00, 0x00,
+            // Definition levels: one present, one null
+            0x04, 0x00, 0x00, 0x00, 0x02, 0x01, 0x02, 0x00,
+            // 1.5
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf8, 0x3f,
+        ];
+        assert_eq!(&file[..4], b"PAR1");
+        assert_eq!(&file[4..4 + page.len()], &page);
+        assert_eq!(&file[file.len() - 4..], b"PAR1");
+        let footer_len = u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap()) as usize;
+        assert_eq!(4 + page.len() + footer_len + 8, file.len());
+        let footer = &file[4 + page.len()..file.len() - 8];
+        // version 1, then a schema of the root and one column
+        assert_eq!(&footer[..4], &[0x15, 0x02, 0x19, 0x2c]);
+        assert_eq!(footer.last(), Some(&0x00));
+    }
+
+    #[test]
+    fn encodes_long_runs_and_refuses_mismatched_columns() {
+        let levels = definition_levels(&[true; 200]);
+        assert_eq!(levels, vec![0x03, 0x00, 0x00, 0x00, 0x90, 0x03, 0x01]);
+
+        assert!(Writer::new(Vec::new(), vec![Column::new("a", Kind::Double), Column::new("a", Kind::Int64)]).is_err());
+        let columns = vec![Column::new("time", Kind::TimestampMillis), Column::new("date", Kind::Date)];
+        let mut writer = Writer::new(Vec::new(), columns).unwrap();
+        assert!(writer.write_row_group(&[Values::Int(vec![Some(1)])]).is_err());
+        assert!(writer.write_row_group(&[Values::Int(vec![Some(1)]), Values::Int(vec![])]).is_err());
+        assert!(writer.write_row_group(&[Values::Int(vec![Some(1)]), Values::Double(vec![Some(1.0)])]).is_err());
+        assert!(writer.write_row_group(&[Values::Int(vec![Some(1)]), Values::Int(vec![Some(i64::MAX)])]).is_err());
+        writer.write_row_group(&[Values::Int(vec![Some(1)]), Values::Int(vec![Some(20_000)])]).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+//! Inserts every poll into a PostgreSQL (or TimescaleDB) table, buffering polls while the
+//! database can't be reached.
+
+use crate::status::RawOutput;
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use tokio_postgres::Client;
+
+/// How the measurements are laid out in the table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// One row per measurement: `time`, `metric`, `value`.
+    Narrow,
+    /// One row per poll with a column per measurement, added as measurements appear.
+    Wide,
+}
+
+impl Layout {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "narrow" => Ok(Layout::Narrow),
+            "wide" => Ok(Layout::Wide),
+            other => Err(format!("Unknown table layout {:?} (narrow, wide)", other)),
+        }
+    }
+}
+
+/// The measurements of one poll waiting to be inserted, each with the time it was read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Poll {
+    /// The time of the wide row: when the newest measurement was read.
+    pub time: DateTime<Utc>,
+    pub values: Vec<(String, f64, DateTime<Utc>)>,
+}
+
+impl Poll {
+    /// Takes the observation times from the measurements, `received` where there is none.
+    pub fn from_raw(raw: &RawOutput, received: DateTime<Utc>) -> Self {
+        let at = |secs: Option<u64>| secs.and_then(|secs| DateTime::from_timestamp(secs as i64, 0)).unwrap_or(received);
+        Poll {
+            time: at(raw.observed_at()),
+            // A carried over value is already stored at the time it was read
+            values: raw.measurements.iter()
+                .filter(|(_, measurement)| !measurement.carried_over)
+                .map(|(name, measurement)| (name.clone(), measurement.value, at(measurement.observed_at)))
+                .collect(),
+        }
+    }
+
+    /// Rows the poll takes up in the table.
+    pub fn rows(&self, layout: Layout) -> usize {
+        match layout {
+            Layout::Narrow => self.values.len(),
+            Layout::Wide => 1,
+        }
+    }
+}
+
+/// Polls not inserted yet, oldest first, holding at most `max_rows` table rows.
+#[derive(Debug)]
+pub struct Buffer {
+    layout: Layout,
+    max_rows: usize,
+    polls: VecDeque<Poll>,
+    rows: usize,
+}
+
+impl Buffer {
+    pub fn new(layout: Layout, max_rows: usize) -> Self {
+        Buffer { layout, max_rows, polls: VecDeque::new(), rows: 0 }
+    }
+
+    /// Adds a poll, dropping the oldest ones to make room; returns how many rows were dropped.
+    pub fn push(&mut self, poll: Poll) -> usize {
+        self.rows += poll.rows(self.layout);
+        self.polls.push_back(poll);
+        let mut dropped = 0;
+        while self.rows > self.max_rows {
+            let Some(oldest) = self.polls.pop_front() else { break };
+            let rows = oldest.rows(self.layout);
+            self.rows -= rows;
+            dropped += rows;
+        }
+        dropped
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn polls(&self) -> &VecDeque<Poll> {
+        &self.polls
+    }
+
+    pub fn clear(&mut self) {
+        self.polls.clear();
+        self.rows = 0;
+    }
+}
+
+/// Quotes a possibly schema-qualified name like `metrics.solax` as `"metrics"."solax"`.
+pub fn quote_table(table: &str) -> String {
+    table.split('.').map(quote_identifier).collect::<Vec<_>>().join(".")
+}
+
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// The wide-layout column of a measurement, e.g. "Load/Generator Power" is `load_generator_power`.
+pub fn column_name(measurement: &str) -> String {
+    crate::zabbix::item_key("", measurement)
+}
+
+pub fn create_table_sql(table: &str, layout: Layout) -> String {
+    match layout {
+        Layout::Narrow => format!(
+            "CREATE TABLE IF NOT EXISTS {} (time timestamptz NOT NULL, metric text NOT NULL, value double precision NOT NULL)",
+            quote_table(table),
+        ),
+        Layout::Wide => format!("CREATE TABLE IF NOT EXISTS {} (time timestamptz NOT NULL)", quote_table(table)),
+    }
+}
+
+/// Turns the table into a TimescaleDB hypertable partitioned on `time`.
+pub fn create_hypertable_sql(table: &str) -> String {
+    format!("SELECT create_hypertable('{}', 'time', if_not_exists => TRUE)", quote_table(table).replace('\'', "''"))
+}
+
+/// The statement inserting a whole batch of narrow rows, given as three arrays.
+pub fn narrow_insert_sql(table: &str) -> String {
+    format!(
+        "INSERT INTO {} (time, metric, value) SELECT * FROM UNNEST($1::timestamptz[], $2::text[], $3::float8[])",
+        quote_table(table),
+    )
+}
+
+/// The readings of `metric` between `$1` and `$2` as `time` and `value`, oldest first. The
+/// narrow layout takes the metric as `$3`.
+pub fn series_sql(table: &str, layout: Layout, metric: &str) -> String {
+    match layout {
+        Layout::Narrow => format!(
+            "SELECT time, value FROM {} WHERE metric = $3 AND time >= $1 AND time < $2 ORDER BY time",
+            quote_table(table),
+        ),
+        Layout::Wide => format!(
+            "SELECT time, {0} FROM {1} WHERE {0} IS NOT NULL AND time >= $1 AND time < $2 ORDER BY time",
+            quote_identifier(&column_name(metric)),
+            quote_table(table),
+        ),
+    }
+}
+
+/// The narrow-layout metrics with readings between `$1` and `$2`.
+pub fn metrics_sql(table: &str) -> String {
+    format!("SELECT DISTINCT metric FROM {} WHERE time >= $1 AND time < $2 ORDER BY metric", quote_table(table))
+}
+
+/// A statement without rows whose columns are the table's, for the wide-layout columns.
+pub fn columns_sql(table: &str) -> String {
+    format!("SELECT * FROM {} LIMIT 0", quote_table(table))
+}
+
+/// The readings between `$1` and `$2`, oldest first: `time`, `metric` and `value` of the metrics
+/// in `$3` for the narrow layout, `time` and the given columns for the wide one.
+pub fn export_sql(table: &str, layout: Layout, columns: &[String]) -> String {
+    match layout {
+        Layout::Narrow => format!(
+            "SELECT time, metric, value FROM {} WHERE metric = ANY($3) AND time >= $1 AND time < $2 ORDER BY time, metric",
+            quote_table(table),
+        ),
+        Layout::Wide => format!(
+            "SELECT {} FROM {} WHERE time >= $1 AND time < $2 ORDER BY time",
+            std::iter::once("time".to_string()).chain(columns.iter().map(|column| quote_identifier(column))).collect::<Vec<_>>().join(", "),
+            quote_table(table),
+        ),
+    }
+}
+
+/// The statement inserting one wide row: `time`, then the given columns in order.
+pub fn wide_insert_sql(table: &str, columns: &[String]) -> String {
+    let names: Vec<String> = std::iter::once("time".to_string()).chain(columns.iter().map(|column| quote_identifier(column))).collect();
+    let params: Vec<String> = (1..=names.len()).map(|n| format!("${}", n)).collect();
+    format!("INSERT INTO {} ({}) VALUES ({})", quote_table(table), names.join(", "), params.join(", "))
+}
+
+/// Creates the table, and the hypertable if asked for.
+pub async fn prepare_table(client: &Client, table: &str, layout: Layout, hypertable: bool) -> Result<(), tokio_postgres::Error> {
+    client.batch_execute(&create_table_sql(table, layout)).await?;
+    if hypertable {
+        client.batch_execute(&create_hypertable_sql(table)).await?;
+    }
+    Ok(())
+}
+
+/// Inserts every buffered poll in one transaction. `columns` holds the wide-layout columns
+/// known to exist and gains the ones added here.
+pub async fn insert(
+    client: &mut Client,
+    table: &str,
+    layout: Layout,
+    polls: &VecDeque<Poll>,
+    columns: &mut Vec<String>,
+) -> Result<(), tokio_postgres::Error> {
+    let transaction = client.transaction().await?;
+    // Columns added in this transaction only count as existing once it is committed
+    let mut added = Vec::new();
+    match layout {
+        Layout::Narrow => {
+            let mut times = Vec::new();
+            let mut metrics = Vec::new();
+            let mut values = Vec::new();
+            for poll in polls {
+                for (name, value, time) in &poll.values {
+                    times.push(*time);
+                    metrics.push(name.as_str());
+                    values.push(*value);
+                }
+            }
+            transaction.execute(&narrow_insert_sql(table), &[&times, &metrics, &values]).await?;
+        }
+        Layout::Wide => {
+            for poll in polls {
+                let poll_columns: Vec<String> = poll.values.iter().map(|(name, _, _)| column_name(name)).collect();
+                for column in &poll_columns {
+                    if !columns.contains(column) && !added.contains(column) {
+                        transaction.batch_execute(&format!(
+                            "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} double precision",
+                            quote_table(table),
+                            quote_identifier(column),
+                        )).await?;
+                        added.push(column.clone());
+                    }
+                }
+                let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = vec![&poll.time];
+                params.extend(poll.values.iter().map(|(_, value, _)| value as &(dyn tokio_postgres::types::ToSql + Sync)));
+                transaction.execute(&wide_insert_sql(table, &poll_columns), &params).await?;
+            }
+        }
+    }
+    transaction.commit().await?;
+    columns.extend(added);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll(secs: i64, count: usize) -> Poll {
+        let time = DateTime::from_timestamp(secs, 0).unwrap();
+        Poll { time, values: (0..count).map(|n| (format!("Metric {}", n), n as f64, time)).collect() }
+    }
+
+    #[test]
+    fn buffer_drops_the_oldest_polls() {
+        let mut buffer = Buffer::new(Layout::Narrow, 10);
+        assert_eq!(buffer.push(poll(1, 4)), 0);
+        assert_eq!(buffer.push(poll(2, 4)), 0);
+        assert_eq!(buffer.push(poll(3, 4)), 4);
+        assert_eq!(buffer.rows(), 8);
+        assert_eq!(buffer.polls().front().unwrap().time.timestamp(), 2);
+
+        // A wide row is one row however many measurements it has
+        let mut buffer = Buffer::new(Layout::Wide, 2);
+        for secs in 1..=3 {
+            buffer.push(poll(secs, 40));
+        }
+        assert_eq!((buffer.rows(), buffer.polls().len()), (2, 2));
+        buffer.clear();
+        assert_eq!(buffer.rows(), 0);
+    }
+
+    #[test]
+    fn rows_keep_their_observation_time() {
+        let mut raw = RawOutput::default();
+        raw.measurements.insert("Grid Power".to_string(), crate::status::RawMeasurement {
+            observed_at: Some(1_700_000_000),
+            ..crate::status::RawMeasurement::new(-450.0, "W")
+        });
+        raw.measurements.insert("Grid Imported Today".to_string(), crate::status::RawMeasurement::new(3.5, "kWh"));
+        let received = DateTime::from_timestamp(1_700_000_030, 0).unwrap();
+        let poll = Poll::from_raw(&raw, received);
+        assert_eq!(poll.time.timestamp(), 1_700_000_000);
+        assert_eq!(poll.values[0].2, received);
+        assert_eq!(poll.values[1].2.timestamp(), 1_700_000_000);
+
+        // Values repeated from an earlier poll were inserted with it
+        raw.measurements.get_mut("Grid Power").unwrap().carried_over = true;
+        let poll = Poll::from_raw(&raw, received);
+        assert_eq!(poll.values.iter().map(|(name, _, _)| name.as_str()).collect::<Vec<_>>(), ["Grid Imported Today"]);
+    }
+
+    #[test]
+    fn builds_statements() {
+        assert_eq!(
+            create_table_sql("metrics.solax", Layout::Narrow),
+            r#"CREATE TABLE IF NOT EXISTS "metrics"."solax" (time timestamptz NOT NULL, metric text NOT NULL, value double precision NOT NULL)"#,
+        );
+        assert_eq!(create_hypertable_sql("solax"), r#"SELECT create_hypertable('"solax"', 'time', if_not_exists => TRUE)"#);
+        let columns = [column_name("Grid Power"), column_name("Load/Generator Power")];
+        assert_eq!(
+            wide_insert_sql("solax", &columns),
+            r#"INSERT INTO "solax" (time, "grid_power", "load_generator_power") VALUES ($1, $2, $3)"#,
+        );
+        assert_eq!(
+            series_sql("solax", Layout::Narrow, "Grid Power"),
+            r#"SELECT time, value FROM "solax" WHERE metric = $3 AND time >= $1 AND time < $2 ORDER BY time"#,
+        );
+        assert_eq!(
+            series_sql("solax", Layout::Wide, "Grid Power"),
+            r#"SELECT time, "grid_power" FROM "solax" WHERE "grid_power" IS NOT NULL AND time >= $1 AND time < $2 ORDER BY time"#,
+        );
+        assert_eq!(
+            export_sql("solax", Layout::Narrow, &[]),
+            r#"SELECT time, metric, value FROM "solax" WHERE metric = ANY($3) AND time >= $1 AND time < $2 ORDER BY time, metric"#,
+        );
+        assert_eq!(
+            export_sql("solax", Layout::Wide, &columns),
+            r#"SELECT time, "grid_power", "load_generator_power" FROM "solax" WHERE time >= $1 AND time < $2 ORDER BY time"#,
+        );
+        assert_eq!(quote_identifier(r#"a"b"#), r#""a""b""#);
+        assert_eq!(Layout::parse("wide"), Ok(Layout::Wide));
+        assert!(Layout::parse("tall").is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! Writes each poll to Redis as a hash and publishes it on a channel, speaking RESP directly
+//! since only a handful of commands are needed.
+
+use crate::status::RawOutput;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+pub const DEFAULT_PORT: u16 = 6379;
+
+/// Longest bulk reply read back; none of the commands used return more than a short string.
+const MAX_BULK_LEN: usize = 64 * 1024;
+
+/// The server from `redis://[[user]:password@]host[:port][/db]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub db: Option<u32>,
+}
+
+impl Target {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let rest = url.trim().strip_prefix("redis://").unwrap_or(url.trim());
+        let (credentials, rest) = match rest.rsplit_once('@') {
+            Some((credentials, rest)) => (Some(credentials), rest),
+            None => (None, rest),
+        };
+        let (address, db) = match rest.split_once('/') {
+            Some((address, "")) => (address, None),
+            Some((address, db)) => (address, Some(db.parse().map_err(|_| format!("Invalid Redis database in {:?}", url))?)),
+            None => (rest, None),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("Invalid Redis port in {:?}", url))?),
+            None => (address, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(format!("Missing Redis host in {:?}", url));
+        }
+        let (username, password) = match credentials.map(|credentials| credentials.split_once(':')) {
+            Some(Some((user, password))) => (Some(user.to_string()).filter(|user| !user.is_empty()), Some(password.to_string())),
+            Some(None) => (None, credentials.map(str::to_string)),
+            None => (None, None),
+        };
+        Ok(Target { host: host.to_string(), port, username, password, db })
+    }
+}
+
+/// Encodes one command as a RESP array of bulk strings.
+pub fn encode(args: &[Vec<u8>]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+fn command(args: &[&[u8]]) -> Vec<Vec<u8>> {
+    args.iter().map(|arg| arg.to_vec()).collect()
+}
+
+/// The commands writing one poll: the measurements as a hash that expires after `ttl`
+/// unless refreshed, and the whole snapshot as JSON on `channel`.
+pub fn snapshot_commands(key: &str, channel: &str, ttl: Duration, raw: &RawOutput, time: u64) -> Result<Vec<Vec<Vec<u8>>>, serde_json::Error> {
+    let mut hset = command(&[b"HSET", key.as_bytes(), b"time", time.to_string().as_bytes()]);
+    for (name, measurement) in &raw.measurements {
+        hset.push(name.as_bytes().to_vec());
+        hset.push(measurement.value.to_string().into_bytes());
+    }
+    Ok(vec![
+        hset,
+        command(&[b"EXPIRE", key.as_bytes(), ttl.as_secs().max(1).to_string().as_bytes()]),
+        command(&[b"PUBLISH", channel.as_bytes(), &serde_json::to_vec(raw)?]),
+    ])
+}
+
+/// Reads one reply, failing on an error reply. Arrays aren't expected from these commands.
+async fn read_reply<R: tokio::io::AsyncBufRead + Unpin>(reader: &mut R) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err("Redis closed the connection".into());
+    }
+    let line = line.trim_end();
+    match line.split_at_checked(1) {
+        Some(("+" | ":", _)) => Ok(()),
+        Some(("-", error)) => Err(format!("Redis error: {}", error).into()),
+        Some(("$", "-1")) => Ok(()),
+        Some(("$", len)) => {
+            let len: usize = len.parse().map_err(|_| format!("Invalid Redis bulk length {:?}", len))?;
+            if len > MAX_BULK_LEN {
+                return Err(format!("Redis reply of {} bytes is too long", len).into());
+            }
+            let mut bulk = vec![0u8; len + 2];
+            reader.read_exact(&mut bulk).await?;
+            Ok(())
+        }
+        _ => Err(format!("Unexpected Redis reply {:?}", line).into()),
+    }
+}
+
+pub struct Connection {
+    stream: BufReader<TcpStream>,
+    timeout: Duration,
+}
+
+impl Connection {
+    /// Connects, then authenticates and selects the database if the target asks for it.
+    pub async fn connect(target: &Target, timeout: Duration) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let stream = tokio::time::timeout(timeout, TcpStream::connect((target.host.as_str(), target.port))).await
+            .map_err(|_| format!("Redis server {}:{} didn't answer within {}s", target.host, target.port, timeout.as_secs()))??;
+        let mut connection = Connection { stream: BufReader::new(stream), timeout };
+        let mut setup = Vec::new();
+        match (&target.username, &target.password) {
+            (Some(user), Some(password)) => setup.push(command(&[b"AUTH", user.as_bytes(), password.as_bytes()])),
+            (None, Some(password)) => setup.push(command(&[b"AUTH", password.as_bytes()])),
+            _ => {}
+        }
+        if let Some(db) = target.db {
+            setup.push(command(&[b"SELECT", db.to_string().as_bytes()]));
+        }
+        connection.pipeline(&setup).await?;
+        Ok(connection)
+    }
+
+    /// Sends all commands at once, then reads their replies in order.
+    pub async fn pipeline(&mut self, commands: &[Vec<Vec<u8>>]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let exchange = async {
+            let packet: Vec<u8> = commands.iter().flat_map(|args| encode(args)).collect();
+            self.stream.get_mut().write_all(&packet).await?;
+            let mut first_error = None;
+            for _ in commands {
+                // Every reply is read, even after an error, so the connection stays in step
+                if let Err(e) = read_reply(&mut self.stream).await {
+                    first_error.get_or_insert(e);
+                }
+            }
+            first_error.map_or(Ok(()), Err)
+        };
+        tokio::time::timeout(self.timeout, exchange).await
+            .map_err(|_| format!("Redis didn't answer within {}s", self.timeout.as_secs()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::RawMeasurement;
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(
+            Target::parse("redis://:s3cret@10.0.0.5:6380/2").unwrap(),
+            Target { host: "10.0.0.5".to_string(), port: 6380, username: None, password: Some("s3cret".to_string()), db: Some(2) },
+        );
+        let target = Target::parse("redis://solax:pw@redis.lan").unwrap();
+        assert_eq!((target.username.as_deref(), target.port, target.db), (Some("solax"), 6379, None));
+        assert_eq!(Target::parse("redis.lan/").unwrap().password, None);
+        assert!(Target::parse("redis://:6379").is_err());
+        assert!(Target::parse("redis://redis.lan/x").is_err());
+    }
+
+    #[test]
+    fn encodes_commands() {
+        assert_eq!(encode(&command(&[b"EXPIRE", b"solax:X3", b"120"])), b"*3\r\n$6\r\nEXPIRE\r\n$8\r\nsolax:X3\r\n$3\r\n120\r\n");
+    }
+
+    #[tokio::test]
+    async fn pipelines_a_snapshot() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut received = Vec::new();
+            // AUTH, then HSET, EXPIRE and PUBLISH
+            for reply in [&b"+OK\r\n"[..], b":3\r\n", b":1\r\n", b"-ERR no subscribers allowed\r\n"] {
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                let count: usize = header.trim_end()[1..].parse().unwrap();
+                let mut args = Vec::new();
+                for _ in 0..count {
+                    let mut len = String::new();
+                    stream.read_line(&mut len).await.unwrap();
+                    let mut arg = vec![0u8; len.trim_end()[1..].parse::<usize>().unwrap() + 2];
+                    stream.read_exact(&mut arg).await.unwrap();
+                    args.push(String::from_utf8_lossy(&arg[..arg.len() - 2]).to_string());
+                }
+                received.push(args);
+                stream.get_mut().write_all(reply).await.unwrap();
+            }
+            received
+        });
+
+        let target = Target::parse(&format!("redis://:pw@127.0.0.1:{}", port)).unwrap();
+        let mut connection = Connection::connect(&target, Duration::from_secs(5)).await.unwrap();
+        let raw = RawOutput {
+            measurements: [("Grid Power".to_string(), RawMeasurement::new(-450.5, "W"))].into(),
+            ..RawOutput::default()
+        };
+        let commands = snapshot_commands("solax:X3", "solax.status", Duration::from_secs(120), &raw, 1_700_000_000).unwrap();
+        let error = connection.pipeline(&commands).await.unwrap_err();
+        assert_eq!(error.to_string(), "Redis error: ERR no subscribers allowed");
+
+        let received = server.await.unwrap();
+        assert_eq!(received[0], ["AUTH", "pw"]);
+        assert_eq!(received[1], ["HSET", "solax:X3", "time", "1700000000", "Grid Power", "-450.5"]);
+        assert_eq!(received[2], ["EXPIRE", "solax:X3", "120"]);
+        assert_eq!(received[3][..2], ["PUBLISH", "solax.status"]);
+        assert!(received[3][2].contains(r#""Grid Power":{"value":-450.5,"unit":"W","#));
+    }
+}
@@ -0,0 +1,280 @@
+//! Daily rollups of the energy flows and poll counts, kept for good, and the monthly and yearly
+//! reports summed from them. The grid and battery energy and the poll counts are taken over from
+//! the service's own counters, so the reports agree with /stats; only the solar and load power
+//! are integrated here, as nothing else counts them.
+
+use crate::inverter::Snapshot;
+use crate::status::{AvailabilitySummary, ReportDay, ReportOutput};
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+
+/// Positive and negative energy (kWh), e.g. charged and discharged, for a linear change from
+/// `from_w` to `to_w` over `hours`. When the sign flips, the interval is split at the zero crossing.
+pub fn split_energy_kwh(from_w: f64, to_w: f64, hours: f64) -> (f64, f64) {
+    let area = |a: f64, b: f64, h: f64| (a + b) / 2.0 * h / 1000.0;
+    let (area_from, area_to) = if from_w * to_w < 0.0 {
+        let crossing = from_w / (from_w - to_w) * hours;
+        (area(from_w, 0.0, crossing), area(0.0, to_w, hours - crossing))
+    } else {
+        (area(from_w, to_w, hours), 0.0)
+    };
+    [area_from, area_to].iter().fold((0.0, 0.0), |(charged, discharged), kwh| {
+        if *kwh > 0.0 {
+            (charged + kwh, discharged)
+        } else {
+            (charged, discharged - kwh)
+        }
+    })
+}
+
+/// The solar and load power of one poll (W), each null when the inverter didn't report it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct Flows {
+    pub solar_w: Option<f64>,
+    pub load_w: Option<f64>,
+}
+
+impl Flows {
+    pub fn of(snapshot: &Snapshot) -> Self {
+        Self {
+            solar_w: snapshot.value("Total Solar Power"),
+            load_w: snapshot.value("Load/Generator Power"),
+        }
+    }
+}
+
+/// The energy and polls of one local day.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DayRollup {
+    pub date: NaiveDate,
+    pub generated_kwh: f64,
+    pub consumed_kwh: f64,
+    pub imported_kwh: f64,
+    pub exported_kwh: f64,
+    pub charged_kwh: f64,
+    pub discharged_kwh: f64,
+    pub attempted: u32,
+    pub succeeded: u32,
+    /// Longest time between two successful polls that ended on this day.
+    pub longest_gap_secs: u64,
+}
+
+/// A calendar month or year of local days.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    /// Starting on the given first of the month.
+    Month(NaiveDate),
+    Year(i32),
+}
+
+impl Period {
+    /// `YYYY-MM` or `YYYY`.
+    pub fn label(self) -> String {
+        match self {
+            Self::Month(first) => first.format("%Y-%m").to_string(),
+            Self::Year(year) => year.to_string(),
+        }
+    }
+
+    /// The first day and the day after the last.
+    fn bounds(self) -> Option<(NaiveDate, NaiveDate)> {
+        let start = match self {
+            Self::Month(first) => first.with_day(1)?,
+            Self::Year(year) => NaiveDate::from_ymd_opt(year, 1, 1)?,
+        };
+        let months = match self {
+            Self::Month(_) => 1,
+            Self::Year(_) => 12,
+        };
+        Some((start, start.checked_add_months(chrono::Months::new(months))?))
+    }
+}
+
+/// The daily rollups, oldest first. Nothing is dropped: a day is a few hundred bytes.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Rollups {
+    pub days: Vec<DayRollup>,
+    /// Time and solar and load power of the previous successful poll.
+    last_sample: Option<(u64, Flows)>,
+}
+
+impl Rollups {
+    pub fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save daily rollups to {}: {}", path.display(), e);
+        }
+    }
+
+    /// The rollup of `date`, added after the last one if it's a new day.
+    fn day(&mut self, date: NaiveDate) -> &mut DayRollup {
+        let index = match self.days.iter().rposition(|day| day.date == date) {
+            Some(index) => index,
+            None => {
+                let index = self.days.partition_point(|day| day.date < date);
+                self.days.insert(index, DayRollup { date, ..DayRollup::default() });
+                index
+            }
+        };
+        &mut self.days[index]
+    }
+
+    /// Adds the solar and load energy since the previous successful poll to `date`, the local
+    /// day of `now`; intervals longer than `max_gap` are skipped, as for the battery counters.
+    pub fn record(&mut self, date: NaiveDate, now: u64, flows: Flows, max_gap: Duration) {
+        let Some((last, previous)) = self.last_sample.replace((now, flows)) else {
+            self.day(date);
+            return;
+        };
+        let elapsed = now.saturating_sub(last);
+        let day = self.day(date);
+        if elapsed == 0 || elapsed > max_gap.as_secs() {
+            return;
+        }
+        let hours = elapsed as f64 / 3600.0;
+        let energy = |from: Option<f64>, to: Option<f64>| {
+            from.zip(to).map_or(0.0, |(from_w, to_w)| split_energy_kwh(from_w, to_w, hours).0)
+        };
+        day.generated_kwh += energy(previous.solar_w, flows.solar_w);
+        day.consumed_kwh += energy(previous.load_w, flows.load_w);
+    }
+
+    /// Adds what the battery counters counted for `date` on one poll.
+    pub fn add_battery(&mut self, date: NaiveDate, charged_kwh: f64, discharged_kwh: f64) {
+        let day = self.day(date);
+        day.charged_kwh += charged_kwh;
+        day.discharged_kwh += discharged_kwh;
+    }
+
+    /// Adds what the grid counters counted for `date` on one poll.
+    pub fn add_grid(&mut self, date: NaiveDate, imported_kwh: f64, exported_kwh: f64) {
+        let day = self.day(date);
+        day.imported_kwh += imported_kwh;
+        day.exported_kwh += exported_kwh;
+    }
+
+    /// Takes over the poll counts of `date` from the availability tracker.
+    pub fn set_availability(&mut self, date: NaiveDate, attempted: u32, succeeded: u32, longest_gap_secs: u64) {
+        let day = self.day(date);
+        day.attempted = attempted;
+        day.succeeded = succeeded;
+        day.longest_gap_secs = longest_gap_secs;
+    }
+
+    /// The totals of `period` as of `today`. A period that hasn't ended is `partial`, and today
+    /// doesn't compete for the best and worst day.
+    pub fn report(&self, period: Period, today: NaiveDate) -> ReportOutput {
+        let (start, end) = period.bounds().unwrap_or((today, today));
+        let days: Vec<&DayRollup> = self.days.iter().filter(|day| day.date >= start && day.date < end).collect();
+        let total = |kwh: fn(&DayRollup) -> f64| days.iter().map(|day| kwh(day)).sum::<f64>();
+        let complete = || days.iter().filter(|day| day.date < today);
+        let report_day = |day: &&&DayRollup| ReportDay { date: day.date, generated_kwh: day.generated_kwh };
+        ReportOutput {
+            period: period.label(),
+            partial: today < end,
+            days_in_period: (end - start).num_days() as usize,
+            days_recorded: days.len(),
+            generated_kwh: total(|day| day.generated_kwh),
+            consumed_kwh: total(|day| day.consumed_kwh),
+            imported_kwh: total(|day| day.imported_kwh),
+            exported_kwh: total(|day| day.exported_kwh),
+            battery_charged_kwh: total(|day| day.charged_kwh),
+            battery_discharged_kwh: total(|day| day.discharged_kwh),
+            best_day: complete().max_by(|a, b| a.generated_kwh.total_cmp(&b.generated_kwh)).map(|day| report_day(&day)),
+            worst_day: complete().min_by(|a, b| a.generated_kwh.total_cmp(&b.generated_kwh)).map(|day| report_day(&day)),
+            availability: AvailabilitySummary::new(
+                None,
+                days.iter().map(|day| day.attempted).sum(),
+                days.iter().map(|day| day.succeeded).sum(),
+                days.iter().map(|day| day.longest_gap_secs).max().unwrap_or(0),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, month, day).unwrap()
+    }
+
+    fn flows(solar_w: f64, load_w: f64) -> Flows {
+        Flows { solar_w: Some(solar_w), load_w: Some(load_w) }
+    }
+
+    #[test]
+    fn polls_roll_up_into_days() {
+        let max_gap = Duration::from_secs(600);
+        let mut rollups = Rollups::default();
+        // An hour at 4 kW of solar, with the load going from 0 to 2 kW
+        for minute in 0..=60 {
+            rollups.record(date(6, 1), minute * 60, flows(4000.0, 2000.0 * minute as f64 / 60.0), max_gap);
+        }
+        // Too long after the last poll to integrate across
+        rollups.record(date(6, 2), 7200, flows(4000.0, 1000.0), max_gap);
+        // Grid, battery and polls come from the service's counters, whatever day they're for
+        rollups.add_grid(date(6, 1), 0.25, 0.5);
+        rollups.add_battery(date(6, 1), 2.0, 0.0);
+        rollups.set_availability(date(6, 1), 62, 61, 120);
+        rollups.add_grid(date(5, 31), 1.5, 0.0);
+
+        assert_eq!(rollups.days.iter().map(|day| day.date).collect::<Vec<_>>(), [date(5, 31), date(6, 1), date(6, 2)]);
+        let day = &rollups.days[1];
+        assert!((day.generated_kwh - 4.0).abs() < 1e-9);
+        assert!((day.consumed_kwh - 1.0).abs() < 1e-9);
+        assert_eq!((day.imported_kwh, day.exported_kwh, day.charged_kwh, day.discharged_kwh), (0.25, 0.5, 2.0, 0.0));
+        assert_eq!((day.attempted, day.succeeded, day.longest_gap_secs), (62, 61, 120));
+        assert_eq!(rollups.days[2], DayRollup { date: date(6, 2), ..DayRollup::default() });
+    }
+
+    #[test]
+    fn reports_sum_the_days_of_the_period() {
+        let mut rollups = Rollups::default();
+        for (month, day, generated_kwh) in [(5, 31, 50.0), (6, 1, 30.0), (6, 2, 12.5), (6, 3, 41.0), (6, 4, 5.0), (7, 1, 20.0)] {
+            rollups.days.push(DayRollup {
+                date: date(month, day),
+                generated_kwh,
+                exported_kwh: 1.0,
+                attempted: 10,
+                succeeded: 9,
+                ..DayRollup::default()
+            });
+        }
+
+        let june = rollups.report(Period::Month(date(6, 1)), date(6, 4));
+        assert_eq!(june.period, "2026-06");
+        assert!(june.partial);
+        assert_eq!((june.days_in_period, june.days_recorded), (30, 4));
+        assert_eq!(june.generated_kwh, 88.5);
+        assert_eq!(june.exported_kwh, 4.0);
+        // Today is still going, so it's not the worst day
+        assert_eq!(june.best_day, Some(ReportDay { date: date(6, 3), generated_kwh: 41.0 }));
+        assert_eq!(june.worst_day, Some(ReportDay { date: date(6, 2), generated_kwh: 12.5 }));
+        assert_eq!(june.availability.ratio, Some(0.9));
+
+        let finished = rollups.report(Period::Month(date(6, 1)), date(7, 2));
+        assert!(!finished.partial);
+        assert_eq!(finished.worst_day.map(|day| day.date), Some(date(6, 4)));
+
+        let year = rollups.report(Period::Year(2026), date(7, 2));
+        assert!(year.partial);
+        assert_eq!((year.days_in_period, year.days_recorded), (365, 6));
+        assert_eq!(year.best_day.map(|day| day.date), Some(date(5, 31)));
+        let past = rollups.report(Period::Year(2025), date(7, 2));
+        assert!(!past.partial && past.days_recorded == 0 && past.best_day.is_none());
+        assert_eq!(past.availability.ratio, None);
+    }
+}
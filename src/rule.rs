@@ -0,0 +1,273 @@
+//! The rule language of the ssh monitor: comparisons of readings combined with `&&`, `||`
+//! and parentheses, such as DEFAULT_RULE.
+
+use crate::status::Readings;
+use std::collections::HashMap;
+
+/// Used for every site without a RULE of its own.
+pub const DEFAULT_RULE: &str = "grid_w == 0 && solar_w < load_w && battery_pct < 10";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl CmpOp {
+    pub fn apply(self, left: f64, right: f64) -> bool {
+        match self {
+            CmpOp::Lt => left < right,
+            CmpOp::Le => left <= right,
+            CmpOp::Gt => left > right,
+            CmpOp::Ge => left >= right,
+            CmpOp::Eq => left == right,
+            CmpOp::Ne => left != right,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Number(f64),
+    Var(String),
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Number(n) => write!(f, "{}", n),
+            Operand::Var(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// A rule expression: comparisons combined with `&&`, `||` and parentheses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Compare(Operand, CmpOp, Operand),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Parses a rule such as DEFAULT_RULE.
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let tokens = tokenize(text)?;
+        let mut pos = 0;
+        let expr = parse_or(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return Err(format!("Unexpected {:?} in rule: {}", tokens[pos], text));
+        }
+        Ok(expr)
+    }
+
+    /// Every variable the expression reads, in order.
+    pub fn variables(&self) -> Vec<&str> {
+        match self {
+            Expr::Compare(left, _, right) => [left, right].into_iter()
+                .filter_map(|operand| match operand {
+                    Operand::Var(name) => Some(name.as_str()),
+                    Operand::Number(_) => None,
+                })
+                .collect(),
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                let mut vars = left.variables();
+                vars.extend(right.variables());
+                vars
+            }
+        }
+    }
+
+    /// Evaluates the expression, recording every comparison in `trace`.
+    /// Returns None when a referenced value isn't available.
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<f64>, trace: &mut Vec<(String, bool)>) -> Option<bool> {
+        match self {
+            Expr::Compare(left, op, right) => {
+                let value = |operand: &Operand| match operand {
+                    Operand::Number(n) => Some(*n),
+                    Operand::Var(name) => lookup(name),
+                };
+                let (l, r) = (value(left)?, value(right)?);
+                let result = op.apply(l, r);
+                trace.push((format!("{} {} {} ({} {} {})", left, op.symbol(), right, l, op.symbol(), r), result));
+                Some(result)
+            }
+            Expr::And(left, right) => {
+                let (l, r) = (left.eval(lookup, trace), right.eval(lookup, trace));
+                Some(l? && r?)
+            }
+            Expr::Or(left, right) => {
+                let (l, r) = (left.eval(lookup, trace), right.eval(lookup, trace));
+                Some(l? || r?)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(CmpOp),
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            tokens.push(Token::Number(number.parse().map_err(|_| format!("Invalid number {} in rule", number))?));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.' || chars[i] == '-') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let (token, len) = match (c, next) {
+                ('&', Some('&')) => (Token::And, 2),
+                ('|', Some('|')) => (Token::Or, 2),
+                ('<', Some('=')) => (Token::Op(CmpOp::Le), 2),
+                ('>', Some('=')) => (Token::Op(CmpOp::Ge), 2),
+                ('=', Some('=')) => (Token::Op(CmpOp::Eq), 2),
+                ('!', Some('=')) => (Token::Op(CmpOp::Ne), 2),
+                ('<', _) => (Token::Op(CmpOp::Lt), 1),
+                ('>', _) => (Token::Op(CmpOp::Gt), 1),
+                ('(', _) => (Token::Open, 1),
+                (')', _) => (Token::Close, 1),
+                _ => return Err(format!("Unexpected character '{}' in rule: {}", c, text)),
+            };
+            tokens.push(token);
+            i += len;
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_or(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    let mut expr = parse_and(tokens, pos)?;
+    while tokens.get(*pos) == Some(&Token::Or) {
+        *pos += 1;
+        expr = Expr::Or(Box::new(expr), Box::new(parse_and(tokens, pos)?));
+    }
+    Ok(expr)
+}
+
+fn parse_and(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    let mut expr = parse_term(tokens, pos)?;
+    while tokens.get(*pos) == Some(&Token::And) {
+        *pos += 1;
+        expr = Expr::And(Box::new(expr), Box::new(parse_term(tokens, pos)?));
+    }
+    Ok(expr)
+}
+
+fn parse_term(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    if tokens.get(*pos) == Some(&Token::Open) {
+        *pos += 1;
+        let expr = parse_or(tokens, pos)?;
+        if tokens.get(*pos) != Some(&Token::Close) {
+            return Err("Missing closing parenthesis in rule".to_string());
+        }
+        *pos += 1;
+        return Ok(expr);
+    }
+
+    let left = parse_operand(tokens, pos)?;
+    let op = match tokens.get(*pos) {
+        Some(Token::Op(op)) => *op,
+        other => return Err(format!("Expected a comparison operator in rule, found {:?}", other)),
+    };
+    *pos += 1;
+    let right = parse_operand(tokens, pos)?;
+    Ok(Expr::Compare(left, op, right))
+}
+
+fn parse_operand(tokens: &[Token], pos: &mut usize) -> Result<Operand, String> {
+    let operand = match tokens.get(*pos) {
+        Some(Token::Number(n)) => Operand::Number(*n),
+        Some(Token::Ident(name)) => Operand::Var(name.clone()),
+        other => return Err(format!("Expected a value in rule, found {:?}", other)),
+    };
+    *pos += 1;
+    Ok(operand)
+}
+
+/// Resolves a rule variable for `site`: bare fields refer to the site itself,
+/// `<source>.<field>` and `total.<field>` to other sources or the combined total.
+pub fn lookup_reading(name: &str, site: &str, fresh: &HashMap<String, Readings>) -> Option<f64> {
+    let (scope, field) = name.rsplit_once('.').unwrap_or((site, name));
+    fresh.get(scope)?.field(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings(grid_w: f64, solar_w: f64, load_w: f64, battery_pct: f64) -> Readings {
+        Readings { grid_w, solar_w, load_w, essential_load_w: load_w, battery_pct, ..Readings::default() }
+    }
+
+    #[test]
+    fn default_rule_matches_original_conditions() {
+        let rule = Expr::parse(DEFAULT_RULE).unwrap();
+        let fresh = HashMap::from([("local".to_string(), readings(0.0, 200.0, 900.0, 8.0))]);
+        let mut trace = Vec::new();
+        assert_eq!(rule.eval(&|name| lookup_reading(name, "local", &fresh), &mut trace), Some(true));
+        assert_eq!(trace.len(), 3);
+
+        let fresh = HashMap::from([("local".to_string(), readings(0.0, 200.0, 900.0, 12.0))]);
+        assert_eq!(rule.eval(&|name| lookup_reading(name, "local", &fresh), &mut Vec::new()), Some(false));
+    }
+
+    #[test]
+    fn rules_see_signed_grid_and_battery_power() {
+        let rule = Expr::parse("grid_w < -100 || battery_w < -100").unwrap();
+        let eval = |readings: Readings| {
+            let fresh = HashMap::from([("local".to_string(), readings)]);
+            rule.eval(&|name| lookup_reading(name, "local", &fresh), &mut Vec::new())
+        };
+        assert_eq!(eval(readings(-500.0, 0.0, 500.0, 50.0)), Some(true));
+        assert_eq!(eval(readings(500.0, 1000.0, 500.0, 50.0)), Some(false));
+        assert_eq!(eval(Readings { battery_w: -500.0, ..readings(0.0, 0.0, 500.0, 50.0) }), Some(true));
+        assert_eq!(eval(Readings { battery_w: 500.0, ..readings(0.0, 1000.0, 500.0, 50.0) }), Some(false));
+    }
+
+    #[test]
+    fn rule_parse_errors() {
+        assert!(Expr::parse("grid_w ==").is_err());
+        assert!(Expr::parse("grid_w = 0").is_err());
+        assert!(Expr::parse("(grid_w == 0").is_err());
+        assert!(Expr::parse("grid_w == 0 battery_pct < 10").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! Ed25519 signatures on /status/raw (STATUS_SIGNING_KEY), so a response relayed through
+//! an untrusted host can be checked against the instance's public key.
+//!
+//! The signed message is the `signed_at` unix time, a newline, and the response without its
+//! `signature` as canonical JSON: object keys sorted, no whitespace.
+
+use crate::status::{RawOutput, Signature};
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::{Signer, Verifier};
+use serde_json::Value;
+use std::path::Path;
+
+pub const ALGORITHM: &str = "ed25519";
+
+#[derive(Clone)]
+pub struct SigningKey {
+    key: PKey<Private>,
+    /// The raw public key, base64.
+    public_key: String,
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SigningKey").field("public_key", &self.public_key).finish_non_exhaustive()
+    }
+}
+
+impl SigningKey {
+    /// Reads a PEM ed25519 private key, as written by `openssl genpkey -algorithm ed25519`.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let pem = std::fs::read(path).map_err(|e| format!("Failed to read STATUS_SIGNING_KEY {}: {}", path.display(), e))?;
+        let key = PKey::private_key_from_pem(&pem)
+            .map_err(|e| format!("Invalid STATUS_SIGNING_KEY {}: {}", path.display(), e))?;
+        if key.id() != Id::ED25519 {
+            return Err(format!("STATUS_SIGNING_KEY {} is not an ed25519 key", path.display()));
+        }
+        let public_key = key.raw_public_key()
+            .map_err(|e| format!("Invalid STATUS_SIGNING_KEY {}: {}", path.display(), e))?;
+        Ok(Self { key, public_key: openssl::base64::encode_block(&public_key) })
+    }
+
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// `raw` with a signature made at `now`.
+    pub fn sign(&self, mut raw: RawOutput, now: u64) -> Result<RawOutput, String> {
+        raw.signature = None;
+        let document = serde_json::to_value(&raw).map_err(|e| e.to_string())?;
+        let value = Signer::new_without_digest(&self.key)
+            .and_then(|mut signer| signer.sign_oneshot_to_vec(&message(&document, now)))
+            .map_err(|e| format!("Failed to sign the status: {}", e))?;
+        raw.signature = Some(Signature {
+            algorithm: ALGORITHM.to_string(),
+            signed_at: now,
+            public_key: self.public_key.clone(),
+            value: openssl::base64::encode_block(&value),
+        });
+        Ok(raw)
+    }
+}
+
+/// Writes `value` as canonical JSON.
+fn canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                canonical(&map[key], out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical(item, out);
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+/// The bytes signed for `document` (without its signature) at `signed_at`.
+fn message(document: &Value, signed_at: u64) -> Vec<u8> {
+    let mut text = format!("{}\n", signed_at);
+    canonical(document, &mut text);
+    text.into_bytes()
+}
+
+/// Checks the signature of a saved /status/raw response against the base64 `public_key`
+/// and returns when it was signed.
+pub fn verify(response: &str, public_key: &str) -> Result<u64, String> {
+    let mut document: Value = serde_json::from_str(response).map_err(|e| format!("Not a JSON response: {}", e))?;
+    let signature = document.as_object_mut()
+        .and_then(|object| object.remove("signature"))
+        .filter(|signature| !signature.is_null())
+        .ok_or("The response isn't signed")?;
+    let signature: Signature = serde_json::from_value(signature).map_err(|e| format!("Malformed signature: {}", e))?;
+    if signature.algorithm != ALGORITHM {
+        return Err(format!("Unsupported signature algorithm {:?}", signature.algorithm));
+    }
+    let decode = |text: &str, what: &str| {
+        openssl::base64::decode_block(text.trim()).map_err(|_| format!("The {} isn't valid base64", what))
+    };
+    let key = PKey::public_key_from_raw_bytes(&decode(public_key, "public key")?, Id::ED25519)
+        .map_err(|_| "The public key isn't an ed25519 key".to_string())?;
+    let value = decode(&signature.value, "signature")?;
+    let valid = Verifier::new_without_digest(&key)
+        .and_then(|mut verifier| verifier.verify_oneshot(&value, &message(&document, signature.signed_at)))
+        .unwrap_or(false);
+    if !valid {
+        let hint = if signature.public_key.trim() == public_key.trim() { "" } else { " (signed with a different key)" };
+        return Err(format!("Invalid signature{}", hint));
+    }
+    Ok(signature.signed_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::RawMeasurement;
+
+    fn key() -> SigningKey {
+        let key = PKey::generate_ed25519().unwrap();
+        let path = std::env::temp_dir().join(format!("solax-signing-{}.pem", std::process::id()));
+        std::fs::write(&path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let loaded = SigningKey::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap()
+    }
+
+    #[test]
+    fn signed_responses_verify_and_tampering_is_caught() {
+        let key = key();
+        let mut raw = RawOutput::default();
+        raw.labels.insert("site".to_string(), "house".to_string());
+        raw.measurements.insert("Battery Capacity".to_string(), RawMeasurement::new(87.0, "%"));
+        let signed = serde_json::to_string_pretty(&key.sign(raw, 1_700_000_000).unwrap()).unwrap();
+        assert_eq!(verify(&signed, key.public_key()), Ok(1_700_000_000));
+
+        // Key order and whitespace don't matter, the values do
+        let reordered = serde_json::to_string(&serde_json::from_str::<Value>(&signed).unwrap()).unwrap();
+        assert_eq!(verify(&reordered, key.public_key()), Ok(1_700_000_000));
+        assert!(verify(&signed.replace("87.0", "97.0"), key.public_key()).is_err());
+        assert!(verify(&signed.replace("1700000000", "1800000000"), key.public_key()).is_err());
+        assert_eq!(verify(&signed, self::key().public_key()), Err("Invalid signature (signed with a different key)".to_string()));
+        assert!(verify(&serde_json::to_string(&RawOutput::default()).unwrap(), key.public_key()).is_err());
+    }
+}
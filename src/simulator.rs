@@ -0,0 +1,235 @@
+//! A simulated X3 Hybrid G4 for the `soak` subcommand: clear-sky solar, a house load with
+//! morning and evening peaks and a battery covering the difference, answered as dongle
+//! responses. It keeps the true energy totals, for checking what the pipeline integrates.
+//! `DongleAuth` authenticates requests like either generation of the dongle firmware.
+
+use crate::inverter::InverterResponse;
+
+/// Length of the simulated Data array, as returned by a G4 dongle.
+pub const DATA_LEN: usize = 150;
+/// The battery stops discharging here, like the inverter's minimum SoC.
+pub const MIN_SOC_PCT: f64 = 10.0;
+
+/// The simulated house.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DayConfig {
+    /// Solar power at noon.
+    pub peak_solar_w: f64,
+    /// Load around the clock, before the morning and evening peaks.
+    pub base_load_w: f64,
+    pub battery_kwh: f64,
+    /// Most the battery charges or discharges with.
+    pub max_battery_w: f64,
+    pub start_soc_pct: f64,
+}
+
+impl Default for DayConfig {
+    fn default() -> Self {
+        Self { peak_solar_w: 5000.0, base_load_w: 500.0, battery_kwh: 10.0, max_battery_w: 5000.0, start_soc_pct: 30.0 }
+    }
+}
+
+/// Power flows at one instant. Grid power is positive when exporting, battery power when charging.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flows {
+    pub solar_w: f64,
+    pub load_w: f64,
+    pub battery_w: f64,
+    pub grid_w: f64,
+}
+
+/// Energy that actually flowed, integrated second by second.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Totals {
+    pub solar_kwh: f64,
+    pub load_kwh: f64,
+    pub imported_kwh: f64,
+    pub exported_kwh: f64,
+    pub charged_kwh: f64,
+    pub discharged_kwh: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    pub config: DayConfig,
+    /// Seconds since the simulation started, at local midnight.
+    pub time: u64,
+    pub soc_pct: f64,
+    pub totals: Totals,
+    yield_today_kwh: f64,
+}
+
+impl Simulator {
+    pub fn new(config: DayConfig) -> Self {
+        Self { soc_pct: config.start_soc_pct, config, time: 0, totals: Totals::default(), yield_today_kwh: 0.0 }
+    }
+
+    /// The flows at `time` with the battery at the current SoC.
+    pub fn flows(&self, time: u64) -> Flows {
+        let hour = (time % 86_400) as f64 / 3600.0;
+        // Sunrise at 6, sunset at 20
+        let solar_w = if (6.0..20.0).contains(&hour) {
+            self.config.peak_solar_w * (std::f64::consts::PI * (hour - 6.0) / 14.0).sin()
+        } else {
+            0.0
+        };
+        let peak_w = match hour {
+            hour if (7.0..8.0).contains(&hour) => 1500.0,
+            hour if (18.0..22.0).contains(&hour) => 2500.0,
+            _ => 0.0,
+        };
+        let load_w = self.config.base_load_w + peak_w;
+        let max_w = self.config.max_battery_w;
+        let battery_w = match (solar_w - load_w).clamp(-max_w, max_w) {
+            w if w > 0.0 && self.soc_pct >= 100.0 => 0.0,
+            w if w < 0.0 && self.soc_pct <= MIN_SOC_PCT => 0.0,
+            w => w,
+        };
+        Flows { solar_w, load_w, battery_w, grid_w: solar_w - load_w - battery_w }
+    }
+
+    /// Runs the house up to `time`, one second at a time.
+    pub fn advance(&mut self, time: u64) {
+        while self.time < time {
+            let flows = self.flows(self.time);
+            let kwh = |w: f64| w / 3_600_000.0;
+            self.totals.solar_kwh += kwh(flows.solar_w);
+            self.totals.load_kwh += kwh(flows.load_w);
+            self.totals.exported_kwh += kwh(flows.grid_w.max(0.0));
+            self.totals.imported_kwh += kwh((-flows.grid_w).max(0.0));
+            self.totals.charged_kwh += kwh(flows.battery_w.max(0.0));
+            self.totals.discharged_kwh += kwh((-flows.battery_w).max(0.0));
+            self.soc_pct = (self.soc_pct + kwh(flows.battery_w) / self.config.battery_kwh * 100.0).clamp(0.0, 100.0);
+            self.yield_today_kwh += kwh(flows.solar_w);
+            self.time += 1;
+            if self.time.is_multiple_of(86_400) {
+                self.yield_today_kwh = 0.0;
+            }
+        }
+    }
+
+    /// What the dongle would answer now.
+    pub fn response(&self) -> InverterResponse {
+        let flows = self.flows(self.time);
+        let mut data = vec![0; DATA_LEN];
+        // Signed 16-bit registers are sent as their unsigned value
+        let signed = |w: f64| (w.round() as i32).clamp(-32768, 32767) & 0xFFFF;
+        for phase in 0..3 {
+            data[phase] = 2300;
+            data[3 + phase] = (flows.grid_w.abs() / 3.0 / 230.0 * 10.0).round() as i32;
+            data[6 + phase] = signed(flows.grid_w / 3.0);
+            data[16 + phase] = 5000;
+        }
+        let pv_w = flows.solar_w / 2.0;
+        for string in 0..2 {
+            let volts = if pv_w > 0.0 { 350.0 } else { 0.0 };
+            data[10 + string] = (volts * 10.0) as i32;
+            data[12 + string] = if pv_w > 0.0 { (pv_w / volts * 10.0).round() as i32 } else { 0 };
+            data[14 + string] = pv_w.round() as i32;
+        }
+        // Normal
+        data[19] = 2;
+        data[34] = signed(flows.grid_w);
+        data[39] = 20_000;
+        data[41] = signed(flows.battery_w);
+        data[47] = signed(flows.load_w);
+        let yield_total = (10_000.0 + self.totals.solar_kwh * 10.0).round() as i64;
+        data[68] = (yield_total >> 16) as i32;
+        data[69] = (yield_total & 0xFFFF) as i32;
+        data[70] = (self.yield_today_kwh * 10.0).round() as i32;
+        data[103] = self.soc_pct.round() as i32;
+        InverterResponse {
+            inverter_type: 14,
+            sn: "SIMULATED".to_string(),
+            ver: "3.001.02".to_string(),
+            data,
+            information: serde_json::json!([10.0, 14, "SIMULATED", 8]),
+        }
+    }
+}
+
+/// Which firmware generation a simulated dongle authenticates like.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Firmware {
+    /// Takes `pwd=<password>` on every request.
+    Legacy,
+    /// Refuses `pwd` but on `optType=Login`, which returns a token good for `token_uses`
+    /// requests.
+    Session { token_uses: u32 },
+}
+
+/// The authentication of a simulated dongle.
+#[derive(Debug, Clone)]
+pub struct DongleAuth {
+    pub firmware: Firmware,
+    pub password: String,
+    /// Logins so far; the current token is `token-<logins>`.
+    pub logins: u32,
+    uses_left: u32,
+}
+
+impl DongleAuth {
+    pub fn new(firmware: Firmware, password: &str) -> Self {
+        Self { firmware, password: password.to_string(), logins: 0, uses_left: 0 }
+    }
+
+    /// The status and body for a form `body`, with `reply` for an authenticated request.
+    /// Refusals are 401, like the firmware's.
+    pub fn answer(&mut self, body: &str, reply: impl FnOnce() -> String) -> (u16, String) {
+        let field = |name: &str| body.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.to_string());
+        let password_matches = field("pwd").as_deref() == Some(self.password.as_str());
+        match self.firmware {
+            Firmware::Legacy if password_matches => (200, reply()),
+            Firmware::Legacy => (401, String::new()),
+            Firmware::Session { token_uses } => {
+                if field("optType").as_deref() == Some("Login") {
+                    if !password_matches {
+                        return (401, String::new());
+                    }
+                    self.logins += 1;
+                    self.uses_left = token_uses;
+                    return (200, serde_json::json!({ "token": format!("token-{}", self.logins) }).to_string());
+                }
+                let current = format!("token-{}", self.logins);
+                if self.logins == 0 || self.uses_left == 0 || field("token") != Some(current) {
+                    return (401, String::new());
+                }
+                self.uses_left -= 1;
+                (200, reply())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inverter::X3HybridG4;
+
+    #[test]
+    fn simulated_day_balances_and_decodes() {
+        let mut simulator = Simulator::new(DayConfig::default());
+        simulator.advance(13 * 3600);
+        let flows = simulator.flows(simulator.time);
+        let snapshot = X3HybridG4::new(&[], std::time::Duration::ZERO).decode(&simulator.response());
+        assert!(!snapshot.partial);
+        assert!((snapshot.value("Total Solar Power").unwrap() - flows.solar_w).abs() <= 1.0);
+        assert_eq!(snapshot.value("Battery Power"), Some(flows.battery_w.round()));
+        assert_eq!(snapshot.value("Grid Power"), Some(flows.grid_w.round()));
+        assert!(snapshot.value("Power Balance Residual").unwrap().abs() <= 3.0);
+
+        simulator.advance(86_400);
+        let totals = &simulator.totals;
+        let balance = totals.solar_kwh + totals.imported_kwh + totals.discharged_kwh
+            - totals.exported_kwh - totals.charged_kwh - totals.load_kwh;
+        assert!(balance.abs() < 1e-6);
+        assert!(totals.imported_kwh > 0.0 && totals.exported_kwh > 0.0);
+        // Evening peak empties the battery down to the minimum
+        assert!((simulator.soc_pct - MIN_SOC_PCT).abs() < 0.1);
+        // The day's yield starts over at midnight
+        assert_eq!(simulator.response().data[70], 0);
+    }
+}
@@ -0,0 +1,219 @@
+//! Sends the measurements of every poll as StatsD gauges over UDP or a unix datagram socket.
+
+use crate::status::RawOutput;
+use std::path::PathBuf;
+
+pub const DEFAULT_PORT: u16 = 8125;
+
+/// Largest datagram sent by default: an Ethernet MTU less the IP and UDP headers.
+pub const DEFAULT_MAX_PACKET: usize = 1432;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Udp(String),
+    Unix(PathBuf),
+}
+
+impl Target {
+    /// Parses `host:port`, `host` (port 8125) or `unix:/path`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        if let Some(path) = value.strip_prefix("unix:") {
+            return match path {
+                "" => Err("Missing StatsD socket path".to_string()),
+                path => Ok(Target::Unix(PathBuf::from(path))),
+            };
+        }
+        match value.rsplit_once(':') {
+            _ if value.is_empty() => Err("Missing StatsD host".to_string()),
+            // An unbracketed IPv6 address has colons but no port
+            Some((_, port)) if !value.ends_with(']') && port.parse::<u16>().is_ok() => Ok(Target::Udp(value.to_string())),
+            _ => Ok(Target::Udp(format!("{}:{}", value, DEFAULT_PORT))),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Udp(addr) => write!(f, "{}", addr),
+            Target::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// How tags are attached to a metric.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagStyle {
+    /// `name:value|g|#tag:value,tag:value`
+    Dogstatsd,
+    /// `name#tag=value,tag=value:value|g`
+    Librato,
+}
+
+impl TagStyle {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "dogstatsd" => Ok(TagStyle::Dogstatsd),
+            "librato" => Ok(TagStyle::Librato),
+            other => Err(format!("Unknown StatsD tag style {:?} (dogstatsd, librato)", other)),
+        }
+    }
+}
+
+/// Parses `key:value,key:value`; a tag without a value is kept as just the key.
+pub fn parse_tags(value: &str) -> Vec<(String, String)> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag.split_once(':') {
+            Some((key, value)) => (key.trim().to_string(), value.trim().to_string()),
+            None => (tag.to_string(), String::new()),
+        })
+        .collect()
+}
+
+/// Replaces the characters that separate metrics, values and tags in either style.
+fn clean(text: &str) -> String {
+    text.chars().map(|c| if matches!(c, '|' | ',' | '#' | '=' | ':') || c.is_whitespace() { '_' } else { c }).collect()
+}
+
+/// One gauge line, e.g. `solax.grid_power:-450|g|#site:cabin`.
+pub fn gauge(name: &str, value: f64, tags: &[(String, String)], style: TagStyle) -> String {
+    let separator = match style {
+        TagStyle::Dogstatsd => ":",
+        TagStyle::Librato => "=",
+    };
+    let tags: Vec<String> = tags.iter()
+        .map(|(key, value)| match value.as_str() {
+            "" => clean(key),
+            value => format!("{}{}{}", clean(key), separator, clean(value)),
+        })
+        .collect();
+    match (style, tags.is_empty()) {
+        (_, true) => format!("{}:{}|g", name, value),
+        (TagStyle::Dogstatsd, false) => format!("{}:{}|g|#{}", name, value, tags.join(",")),
+        (TagStyle::Librato, false) => format!("{}#{}:{}|g", name, tags.join(","), value),
+    }
+}
+
+/// The lines sent after a poll: `<prefix>heartbeat`, which goes out whether or not the
+/// inverter answered, then a gauge per measurement of a successful poll, tagged with the
+/// configured tags and the snapshot's labels.
+pub fn poll_lines(prefix: &str, tags: &[(String, String)], style: TagStyle, raw: Option<&RawOutput>) -> Vec<String> {
+    let mut tags = tags.to_vec();
+    if let Some(raw) = raw {
+        tags.extend(raw.labels.iter().map(|(key, value)| (key.clone(), value.clone())));
+    }
+    let mut lines = vec![gauge(&format!("{}heartbeat", prefix), 1.0, &tags, style)];
+    for (name, measurement) in raw.iter().flat_map(|raw| &raw.measurements) {
+        if measurement.value.is_finite() {
+            lines.push(gauge(&crate::zabbix::item_key(prefix, name), measurement.value, &tags, style));
+        }
+    }
+    lines
+}
+
+/// Packs lines into newline-separated datagrams of at most `max_len` bytes. A line longer
+/// than that on its own still goes out alone.
+pub fn packets(lines: &[String], max_len: usize) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > max_len {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+pub enum Socket {
+    Udp(tokio::net::UdpSocket, String),
+    Unix(tokio::net::UnixDatagram, PathBuf),
+}
+
+impl Socket {
+    pub async fn open(target: &Target) -> std::io::Result<Self> {
+        match target {
+            Target::Udp(addr) => {
+                let bind = if addr.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+                Ok(Socket::Udp(tokio::net::UdpSocket::bind(bind).await?, addr.clone()))
+            }
+            Target::Unix(path) => Ok(Socket::Unix(tokio::net::UnixDatagram::unbound()?, path.clone())),
+        }
+    }
+
+    /// Sends every packet, stopping at the first failure.
+    pub async fn send(&self, packets: &[String]) -> std::io::Result<()> {
+        for packet in packets {
+            match self {
+                Socket::Udp(socket, addr) => socket.send_to(packet.as_bytes(), addr.as_str()).await?,
+                Socket::Unix(socket, path) => socket.send_to(packet.as_bytes(), path).await?,
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(Target::parse("10.0.0.5:8126"), Ok(Target::Udp("10.0.0.5:8126".to_string())));
+        assert_eq!(Target::parse("datadog"), Ok(Target::Udp("datadog:8125".to_string())));
+        assert_eq!(Target::parse("[::1]"), Ok(Target::Udp("[::1]:8125".to_string())));
+        assert_eq!(Target::parse("unix:/var/run/datadog/dsd.socket"), Ok(Target::Unix(PathBuf::from("/var/run/datadog/dsd.socket"))));
+        assert!(Target::parse("unix:").is_err());
+    }
+
+    #[test]
+    fn formats_tags() {
+        let tags = [("site".to_string(), "cabin".to_string()), ("sn".to_string(), "X3".to_string())];
+        assert_eq!(gauge("solax.grid_power", -450.0, &tags, TagStyle::Dogstatsd), "solax.grid_power:-450|g|#site:cabin,sn:X3");
+        assert_eq!(gauge("solax.grid_power", 1.5, &tags, TagStyle::Librato), "solax.grid_power#site=cabin,sn=X3:1.5|g");
+        assert_eq!(gauge("solax.heartbeat", 1.0, &[], TagStyle::Librato), "solax.heartbeat:1|g");
+    }
+
+    #[test]
+    fn poll_lines_carry_the_labels() {
+        let mut raw = RawOutput::default();
+        raw.labels.insert("site".to_string(), "cabin roof".to_string());
+        raw.measurements.insert("Load/Generator Power".to_string(), crate::status::RawMeasurement::new(812.0, "W"));
+        let tags = parse_tags("env:home, dc");
+        assert_eq!(
+            poll_lines("solax.", &tags, TagStyle::Dogstatsd, Some(&raw)),
+            ["solax.heartbeat:1|g|#env:home,dc,site:cabin_roof", "solax.load_generator_power:812|g|#env:home,dc,site:cabin_roof"],
+        );
+        // A failed poll still sends the heartbeat
+        assert_eq!(poll_lines("solax.", &[], TagStyle::Librato, None), ["solax.heartbeat:1|g"]);
+    }
+
+    #[test]
+    fn packs_lines_under_the_limit() {
+        let lines: Vec<String> = (0..5).map(|n| format!("solax.m{}:1|g", n)).collect();
+        let packed = packets(&lines, 30);
+        assert_eq!(packed, ["solax.m0:1|g\nsolax.m1:1|g", "solax.m2:1|g\nsolax.m3:1|g", "solax.m4:1|g"]);
+        assert!(packed.iter().all(|packet| packet.len() <= 30));
+        assert_eq!(packets(&["x".repeat(40)], 30).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sends_over_udp() {
+        let receiver = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = Target::parse(&receiver.local_addr().unwrap().to_string()).unwrap();
+        let socket = Socket::open(&target).await.unwrap();
+        socket.send(&["solax.heartbeat:1|g".to_string()]).await.unwrap();
+        let mut buf = [0u8; 64];
+        let len = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"solax.heartbeat:1|g");
+    }
+}
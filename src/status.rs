@@ -0,0 +1,1516 @@
+//! Response schemas of the HTTP API, shared by the service and the ssh monitor.
+//!
+//! These structs are the `/v1` contract: their serialized field names are pinned by the
+//! tests below, and a breaking change to any of them belongs under a new `/v2` prefix.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// `/v1/status`: the formatted summary the ssh monitor consumes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StatusOutput {
+    // Defaulted so the monitor can read instances that predate these fields
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub solar_panels: String,
+    pub batteries: String,
+    #[serde(default)]
+    pub battery_soc_raw: String,
+    pub battery_status: String,
+    /// Deprecated: the magnitude only, the direction is in `battery_status`; use `battery_power_w`.
+    pub battery_power: String,
+    pub grid_status: String,
+    /// Deprecated: the magnitude only, the direction is in `grid_status`; use `grid_power_w`.
+    pub grid_power: String,
+    pub home_consumption: String,
+    /// Power the battery's BMS currently allows it to deliver, empty when unknown.
+    #[serde(default)]
+    pub bms_discharge_limit: String,
+    #[serde(default)]
+    pub partial: bool,
+    /// Battery power, positive while charging; null before the first poll and from instances
+    /// that predate it.
+    #[serde(default)]
+    pub battery_power_w: Option<f64>,
+    #[serde(default)]
+    pub battery_direction: Option<BatteryDirection>,
+    /// Grid power, positive while exporting.
+    #[serde(default)]
+    pub grid_power_w: Option<f64>,
+    #[serde(default)]
+    pub grid_direction: Option<GridDirection>,
+    /// The fresh EXTERNAL_METER readings in watts by measurement name: the circuits of
+    /// `home_consumption` metered separately.
+    #[serde(default)]
+    pub circuits_w: BTreeMap<String, f64>,
+    /// The part of `home_consumption` on the EPS-backed circuits (ESSENTIAL_LOAD); null without
+    /// it, or while a meter it reads is stale.
+    #[serde(default)]
+    pub essential_load_w: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BatteryDirection {
+    Charging,
+    Discharging,
+    Idle,
+}
+
+impl BatteryDirection {
+    pub fn from_w(battery_w: f64) -> Self {
+        if battery_w < 0.0 {
+            BatteryDirection::Discharging
+        } else if battery_w > 0.0 {
+            BatteryDirection::Charging
+        } else {
+            BatteryDirection::Idle
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GridDirection {
+    Importing,
+    Exporting,
+    Idle,
+}
+
+impl GridDirection {
+    pub fn from_w(grid_w: f64) -> Self {
+        if grid_w < 0.0 {
+            GridDirection::Importing
+        } else if grid_w > 0.0 {
+            GridDirection::Exporting
+        } else {
+            GridDirection::Idle
+        }
+    }
+}
+
+impl StatusOutput {
+    /// Signed battery power; from the magnitude and `battery_status` for older instances.
+    pub fn battery_w(&self) -> f64 {
+        self.battery_power_w.unwrap_or_else(|| match self.battery_status.as_str() {
+            "Discharging" => -parse_power_value(&self.battery_power),
+            _ => parse_power_value(&self.battery_power),
+        })
+    }
+
+    /// Signed grid power; from the magnitude and `grid_status` for older instances.
+    pub fn grid_w(&self) -> f64 {
+        self.grid_power_w.unwrap_or_else(|| match self.grid_status.as_str() {
+            "Importing" => -parse_power_value(&self.grid_power),
+            _ => parse_power_value(&self.grid_power),
+        })
+    }
+
+    pub fn bms_discharge_limit_w(&self) -> Option<f64> {
+        Some(&self.bms_discharge_limit).filter(|limit| !limit.is_empty()).map(|limit| parse_power_value(limit))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RawMeasurement {
+    pub value: f64,
+    pub unit: String,
+    /// Sequence number of the poll the value was read in; null for values not read from the
+    /// inverter, and from instances older than this field.
+    #[serde(default)]
+    pub seq: Option<u64>,
+    /// Unix time of that poll.
+    #[serde(default)]
+    pub observed_at: Option<u64>,
+    /// The value is repeated from an earlier poll rather than read in the latest one.
+    #[serde(default)]
+    pub carried_over: bool,
+}
+
+impl RawMeasurement {
+    /// A value not tied to a poll.
+    pub fn new(value: f64, unit: &str) -> Self {
+        RawMeasurement { value, unit: unit.to_string(), seq: None, observed_at: None, carried_over: false }
+    }
+}
+
+/// `/v1/status/raw`: every published measurement with its unit.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct RawOutput {
+    pub labels: BTreeMap<String, String>,
+    pub partial: bool,
+    pub data_len: usize,
+    pub measurements: BTreeMap<String, RawMeasurement>,
+    /// How long the charge above the reserve lasts at the typical overnight load, or at the
+    /// current load until enough nights have been seen; null without BATTERY_CAPACITY_KWH.
+    #[serde(default)]
+    pub backup_runtime_estimate_hours: Option<f64>,
+    /// Whether the battery may be charged, from its temperature and the BMS charge limit; null
+    /// without either.
+    #[serde(default)]
+    pub battery_charge_advisory: Option<ChargeAdvisory>,
+    /// Only present with STATUS_SIGNING_KEY.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+}
+
+/// `inhibited` below CHARGE_TEMP_FLOOR_C or while the BMS allows no charge current, `reduced`
+/// below CHARGE_TEMP_REDUCED_C.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChargeAdvisory {
+    Ok,
+    Reduced,
+    Inhibited,
+}
+
+/// Signature of a /status/raw response, see `signing`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Signature {
+    pub algorithm: String,
+    /// Unix time the response was signed at.
+    pub signed_at: u64,
+    /// Base64 raw public key, the same as on /info; check against a key you trust.
+    pub public_key: String,
+    /// Base64 signature.
+    pub value: String,
+}
+
+impl RawOutput {
+    /// When the newest of the measurements was read from the inverter.
+    pub fn observed_at(&self) -> Option<u64> {
+        self.measurements.values().filter_map(|measurement| measurement.observed_at).max()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SourceHealth {
+    pub url: String,
+    pub up: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct BackoffHealth {
+    pub state: String,
+    pub consecutive_failures: u32,
+    pub next_poll: Option<u64>,
+}
+
+/// `/v1/health`
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct HealthOutput {
+    pub healthy: bool,
+    pub partial: bool,
+    pub source: Option<String>,
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+    pub sources: Vec<SourceHealth>,
+    pub backoff: BackoffHealth,
+    /// Why the last write of PROMETHEUS_TEXTFILE failed; null when it succeeded or is off.
+    #[serde(default)]
+    pub textfile_error: Option<String>,
+    /// `degraded` while several readings are impossible poll after poll, which usually means
+    /// the register map doesn't fit the firmware; `ok` otherwise, empty before the first poll
+    /// or with CONSISTENCY_POLLS=0.
+    #[serde(default)]
+    pub quality: String,
+    /// The invariants the last poll broke, with the readings breaking them.
+    #[serde(default)]
+    pub failing_invariants: Vec<String>,
+}
+
+/// Poll counts for one day, or over all kept days when `date` is null.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AvailabilitySummary {
+    pub date: Option<chrono::NaiveDate>,
+    pub attempted: u32,
+    pub succeeded: u32,
+    pub ratio: Option<f64>,
+    pub longest_gap_secs: u64,
+}
+
+impl AvailabilitySummary {
+    pub fn new(date: Option<chrono::NaiveDate>, attempted: u32, succeeded: u32, longest_gap_secs: u64) -> Self {
+        let ratio = (attempted > 0).then(|| f64::from(succeeded) / f64::from(attempted));
+        Self { date, attempted, succeeded, ratio, longest_gap_secs }
+    }
+}
+
+/// `/v1/stats/availability`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AvailabilityOutput {
+    pub days: Vec<AvailabilitySummary>,
+    pub overall: AvailabilitySummary,
+}
+
+/// Energy through the battery on one local day (`date` is null for the lifetime totals).
+/// `cycles` is the equivalent number of full cycles, null without BATTERY_CAPACITY_KWH.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BatteryThroughputSummary {
+    pub date: Option<chrono::NaiveDate>,
+    pub charged_kwh: f64,
+    pub discharged_kwh: f64,
+    pub cycles: Option<f64>,
+}
+
+/// `/v1/stats/battery`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BatteryStatsOutput {
+    pub days: Vec<BatteryThroughputSummary>,
+    pub lifetime: BatteryThroughputSummary,
+    pub capacity: CapacityOutput,
+}
+
+/// The battery capacity learned from clean discharges next to the configured one.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CapacityOutput {
+    pub configured_kwh: Option<f64>,
+    pub learned_kwh: Option<f64>,
+    /// The capacity the runtime estimates use: the learned one once it is trusted.
+    pub in_use_kwh: Option<f64>,
+    /// Discharge segments learned from.
+    pub segments: u32,
+    /// `none`, `low`, `medium` or `high`, from the number of segments.
+    pub confidence: String,
+    pub last_updated: Option<u64>,
+}
+
+/// One module of a stacked battery, as its BMS reports it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct BatteryModule {
+    /// Position in the stack, from 1.
+    pub module: usize,
+    pub soc_pct: f64,
+    pub voltage_v: f64,
+    pub temperature_c: f64,
+}
+
+/// `/v1/status/battery/modules`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BatteryModulesOutput {
+    pub modules: Vec<BatteryModule>,
+    /// Highest minus lowest module SoC; null with a single module.
+    pub soc_spread_pct: Option<f64>,
+    /// BATTERY_MODULE_DRIFT_PCT, null while the drift alert is off.
+    pub drift_alert_pct: Option<f64>,
+    /// Whether the drift alert has fired and not cleared yet.
+    pub drifting: bool,
+}
+
+/// Requests served on one path, and how many of them got a 4xx or 5xx response.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct RouteStats {
+    pub requests: u64,
+    pub errors: u64,
+}
+
+/// `/v1/stats/http`, keyed by request path; requests that matched no route are counted
+/// under `unmatched`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct HttpStatsOutput {
+    pub routes: BTreeMap<String, RouteStats>,
+}
+
+/// `/v1/info`: what the inverter reported about itself at the first successful poll.
+/// The decoded fields are only filled in for known Information layouts; `information`
+/// always carries the array as received.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InfoOutput {
+    pub labels: BTreeMap<String, String>,
+    pub sn: String,
+    pub model: String,
+    pub rated_power_kw: Option<f64>,
+    pub machine_type: Option<i64>,
+    pub module_sn: Option<String>,
+    pub information: serde_json::Value,
+    /// Base64 ed25519 public key /status/raw is signed with; only with STATUS_SIGNING_KEY.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_public_key: Option<String>,
+    /// How the dongle is being authenticated: `legacy` (password per request) or `session`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dongle_protocol: Option<String>,
+}
+
+/// One measurement of `/v1/measurements`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CatalogEntry {
+    /// The canonical name, as used by PUBLISH, THRESHOLD_ALERT and the decoding.
+    pub name: String,
+    pub unit: String,
+    /// First register of the value in the Data array; null for derived measurements.
+    pub index: Option<usize>,
+    /// Registers the value spans.
+    pub words: Option<usize>,
+    pub signed: bool,
+    /// Computed from other measurements rather than read from a register.
+    pub derived: bool,
+    /// Whether PUBLISH lets it onto /status/raw and /metrics.
+    pub published: bool,
+    /// The name it is published under, when PUBLISH renames it.
+    pub alias: Option<String>,
+}
+
+/// `/v1/measurements`: what this instance's inverter profile provides.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CatalogOutput {
+    pub measurements: Vec<CatalogEntry>,
+}
+
+/// One mapped measurement of `/v1/debug/decode`, from its registers to its value.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DecodedRegister {
+    pub measurement: String,
+    pub index: usize,
+    /// The registers the value is read from, starting at `index`; empty when the Data array
+    /// is too short to hold them.
+    pub raw: Vec<i32>,
+    /// `raw` when the register is published as is.
+    pub transform: String,
+    /// Before the SoC calibration; null when the registers are missing.
+    pub value: Option<f64>,
+    pub unit: String,
+}
+
+/// `/v1/debug/decode`: how the latest poll's Data array was read.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DecodeOutput {
+    pub seq: u64,
+    pub time: u64,
+    pub sn: String,
+    pub model: String,
+    pub firmware: String,
+    pub data_len: usize,
+    pub partial: bool,
+    /// By register index.
+    pub mapped: Vec<DecodedRegister>,
+    /// The raw value of every register no measurement reads, by index.
+    pub unmapped: BTreeMap<usize, i32>,
+}
+
+/// The decoded positions of the inverter's `ReadSetData` array.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InverterSettings {
+    pub export_limit_w: i64,
+    /// `self_use`, `feed_in_priority`, `backup` or `manual`.
+    pub work_mode: String,
+    /// The battery mode as set through /control/battery-mode; null in feed-in priority and
+    /// backup modes.
+    pub battery_mode: Option<String>,
+    pub charge_power_limit_w: i64,
+    /// The whole array, for settings without a decoded field such as the charge periods.
+    pub raw: Vec<i64>,
+}
+
+/// `/v1/settings`: the inverter settings as last read, and how reading them goes. Reading
+/// them is separate from polling, so failures here don't touch /health.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SettingsOutput {
+    pub settings: Option<InverterSettings>,
+    /// When `settings` were read.
+    pub read_at: Option<u64>,
+    pub last_attempt: Option<u64>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+}
+
+/// Estimated PV energy the export limit cost on one local day.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CurtailmentDay {
+    pub date: chrono::NaiveDate,
+    pub estimated_kwh: f64,
+    /// How long the inverter was curtailing.
+    pub curtailed_secs: u64,
+}
+
+/// `/v1/stats/curtailment`: how close the export is to the inverter's export limit, and the
+/// PV power lost to it. The loss is an estimate, from the PV power expected by `estimate`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CurtailmentOutput {
+    /// How the expected PV power is estimated: `recent_max` or `same_time`.
+    pub estimate: String,
+    /// Null until the settings poller has read the limit, or when there is none.
+    pub export_limit_w: Option<f64>,
+    pub export_headroom_w: Option<f64>,
+    pub expected_solar_w: Option<f64>,
+    pub curtailing: bool,
+    pub estimated_today_kwh: f64,
+    pub days: Vec<CurtailmentDay>,
+}
+
+/// Export against the contracted limit on one local day.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ComplianceDay {
+    pub date: chrono::NaiveDate,
+    /// Stored grid power readings of the day.
+    pub samples: usize,
+    /// Median time between two readings; null without any.
+    pub sampling_interval_secs: Option<u64>,
+    /// Longest time between two readings, ending on this day.
+    pub longest_gap_secs: u64,
+    /// Null without any readings, or when none of them was an export.
+    pub max_export_w: Option<f64>,
+    /// Each reading above the limit counts for the time since the previous one.
+    pub minutes_above_limit: f64,
+    pub exported_kwh: f64,
+}
+
+/// `/v1/reports/export-compliance`: the daily export of a month from the PostgreSQL history,
+/// against EXPORT_COMPLIANCE_LIMIT_W.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ComplianceOutput {
+    /// `YYYY-MM`.
+    pub month: String,
+    pub limit_w: f64,
+    /// The name grid power is stored under.
+    pub measurement: String,
+    /// Median time between two readings over the month; null without any.
+    pub sampling_interval_secs: Option<u64>,
+    /// What the sampling interval means for the report.
+    pub note: String,
+    /// No reading of the month exceeded the limit.
+    pub compliant: bool,
+    pub days: Vec<ComplianceDay>,
+}
+
+/// The solar generated on one day of a report.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ReportDay {
+    pub date: chrono::NaiveDate,
+    pub generated_kwh: f64,
+}
+
+/// `/v1/reports/monthly` and `/v1/reports/yearly`: the totals of a month or a year, summed from
+/// the daily rollups.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ReportOutput {
+    /// `YYYY-MM` or `YYYY`.
+    pub period: String,
+    /// The period isn't over yet, so the totals are the ones so far.
+    pub partial: bool,
+    pub days_in_period: usize,
+    /// Days of the period the service recorded.
+    pub days_recorded: usize,
+    pub generated_kwh: f64,
+    pub consumed_kwh: f64,
+    pub imported_kwh: f64,
+    pub exported_kwh: f64,
+    pub battery_charged_kwh: f64,
+    pub battery_discharged_kwh: f64,
+    /// The recorded days with the most and the least solar, leaving out today; null without any.
+    pub best_day: Option<ReportDay>,
+    pub worst_day: Option<ReportDay>,
+    /// Poll counts over the recorded days.
+    pub availability: AvailabilitySummary,
+}
+
+/// `/v1/control/export-limit`: the limit the inverter confirmed after the write.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ExportLimitOutput {
+    pub watts: u32,
+    /// Null when the old limit couldn't be read before writing.
+    pub previous_watts: Option<u32>,
+}
+
+/// `/v1/control/battery-mode`: the mode that was set, and when a forced mode reverts to
+/// self use (unix seconds, null for modes that don't revert).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BatteryModeOutput {
+    pub mode: String,
+    pub revert_at: Option<u64>,
+}
+
+/// `/v1/evc/status`: the EV charger, and the home consumption with and without the car.
+/// The consumption fields are null until the inverter has been read.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EvcStatusOutput {
+    pub labels: BTreeMap<String, String>,
+    /// idle, charging, fault or unavailable
+    pub state: String,
+    pub plugged: bool,
+    pub charging_power_w: f64,
+    pub session_energy_kwh: f64,
+    pub home_consumption_w: Option<f64>,
+    pub home_consumption_without_evc_w: Option<f64>,
+}
+
+/// One device of the surplus controller; `since` is when it was last switched (unix seconds).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SurplusDeviceStatus {
+    pub name: String,
+    pub on: bool,
+    pub since: Option<u64>,
+    pub switches: u64,
+    pub last_decision: Option<String>,
+    pub last_error: Option<String>,
+}
+
+/// `/v1/stats/surplus`: the devices in priority order and the export they were last judged on.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SurplusOutput {
+    pub export_w: Option<f64>,
+    /// The inverter's export limit as last read by the settings poller.
+    #[serde(default)]
+    pub export_limit_w: Option<f64>,
+    pub devices: Vec<SurplusDeviceStatus>,
+}
+
+/// `/v1/stats/zabbix`: pushes to the Zabbix server and what it made of the items.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ZabbixStatsOutput {
+    pub sends: u64,
+    pub failed_sends: u64,
+    pub items_processed: u64,
+    pub items_failed: u64,
+    pub last_send: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// `/v1/stats/redis`: snapshots written to Redis and the connection's troubles.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct RedisStatsOutput {
+    pub connected: bool,
+    pub writes: u64,
+    pub failed_writes: u64,
+    pub connection_failures: u64,
+    pub last_write: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// `/v1/stats/postgres`: batches inserted into PostgreSQL and the rows waiting for it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct PostgresStatsOutput {
+    pub connected: bool,
+    pub inserts: u64,
+    pub failed_inserts: u64,
+    pub connection_failures: u64,
+    pub rows_inserted: u64,
+    pub rows_buffered: usize,
+    /// Rows given up on because the buffer was full.
+    pub rows_dropped: u64,
+    pub last_insert: Option<u64>,
+    pub last_insert_latency_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// `/v1/stats/events`: events published to the broker and the ones still in the outbox.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct EventStatsOutput {
+    pub connected: bool,
+    pub published: u64,
+    pub failed_publishes: u64,
+    pub pending: usize,
+    /// Events lost because the outbox or the queue to the publisher was full.
+    pub dropped: u64,
+    pub last_seq: Option<u64>,
+    pub last_publish: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// One peer instance on `/v1/federation/status`. The last snapshot is kept while the peer is
+/// offline; `stale` says whether it is older than FEDERATION_STALE_SECS.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FederationSite {
+    pub name: String,
+    pub url: String,
+    pub stale: bool,
+    /// Seconds since the last successful poll; null if the peer was never reached.
+    pub age_secs: Option<u64>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub snapshot: Option<RawOutput>,
+}
+
+/// `/v1/federation/status`: every peer, and the power and energy measurements summed over
+/// the peers that aren't stale.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct FederationOutput {
+    pub sites: Vec<FederationSite>,
+    pub totals: BTreeMap<String, RawMeasurement>,
+}
+
+/// A threshold rule firing or clearing, as posted to THRESHOLD_WEBHOOK and MQTT.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ThresholdEvent {
+    pub rule: String,
+    /// `fired` or `cleared`.
+    pub state: String,
+    pub metric: String,
+    pub condition: String,
+    pub value: f64,
+    pub time: u64,
+}
+
+/// An inverter reboot noticed between two polls, as appended to the restart log and
+/// published as a `restart` event.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InverterRestart {
+    /// What gave it away, e.g. a counter going backwards.
+    pub reason: String,
+    pub firmware: String,
+    pub previous_firmware: String,
+    /// Unix time of the poll that noticed it; the reboot happened after `previous_time`.
+    pub time: u64,
+    pub previous_time: u64,
+}
+
+/// Something that changed between two polls, as listed on /status/changes and published
+/// as a `change` event.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Change {
+    /// A derived state such as `battery` going from `charging` to `discharging`.
+    State { name: String, from: String, to: String },
+    /// A measurement that moved further than its threshold since it was last reported.
+    Value { metric: String, from: f64, to: f64, unit: String },
+}
+
+/// The changes one poll brought.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SnapshotDiff {
+    pub seq: u64,
+    pub previous_seq: u64,
+    pub time: u64,
+    pub changes: Vec<Change>,
+}
+
+/// The answer to an MQTT command, published on `solax/<sn>/cmd/result`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CommandResult {
+    pub id: Option<String>,
+    pub command: Option<String>,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl CommandResult {
+    pub fn succeeded(id: Option<String>, command: &str) -> Self {
+        Self { id, command: Some(command.to_string()), ok: true, error: None }
+    }
+
+    pub fn failed(id: Option<String>, command: Option<&str>, error: String) -> Self {
+        Self { id, command: command.map(str::to_string), ok: false, error: Some(error) }
+    }
+}
+
+/// The numeric values shutdown rules are evaluated against.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Readings {
+    /// Positive while exporting, negative while importing.
+    pub grid_w: f64,
+    pub solar_w: f64,
+    pub load_w: f64,
+    /// The essential load; the whole load from instances without ESSENTIAL_LOAD.
+    #[serde(default)]
+    pub essential_load_w: f64,
+    pub battery_pct: f64,
+    /// Positive while charging, negative while discharging.
+    pub battery_w: f64,
+    /// Estimated minutes until the battery is empty; None without a known capacity.
+    pub runtime_min: Option<f64>,
+}
+
+/// Field names that rules may reference, optionally qualified with a source name or `total`.
+pub const READING_FIELDS: [&str; 7] = ["grid_w", "solar_w", "load_w", "essential_load_w", "battery_pct", "battery_w", "runtime_min"];
+
+/// Longest runtime estimate, reported while the battery is idle or charging instead of infinity.
+pub const MAX_RUNTIME_MIN: f64 = 24.0 * 60.0;
+
+/// Minutes the remaining charge lasts at `battery_w`, capped at MAX_RUNTIME_MIN.
+pub fn runtime_minutes(battery_pct: f64, capacity_kwh: f64, battery_w: f64) -> f64 {
+    let remaining_wh = battery_pct.clamp(0.0, 100.0) / 100.0 * capacity_kwh * 1000.0;
+    let draw_w = -battery_w;
+    // Below a watt of draw the estimate is noise around zero
+    if draw_w < 1.0 {
+        return MAX_RUNTIME_MIN;
+    }
+    (remaining_wh / draw_w * 60.0).min(MAX_RUNTIME_MIN)
+}
+
+impl Readings {
+    pub fn from_status(status: &StatusOutput) -> Self {
+        let load_w = parse_power_value(&status.home_consumption);
+        Self {
+            grid_w: status.grid_w(),
+            solar_w: parse_power_value(&status.solar_panels),
+            load_w,
+            essential_load_w: status.essential_load_w.unwrap_or(load_w),
+            battery_pct: parse_battery_percentage(&status.batteries),
+            battery_w: status.battery_w(),
+            runtime_min: None,
+        }
+    }
+
+    /// Sums the power figures across sources; the battery and runtime are the lowest of them.
+    pub fn combine(readings: &[Readings]) -> Self {
+        Self {
+            grid_w: readings.iter().map(|r| r.grid_w).sum(),
+            solar_w: readings.iter().map(|r| r.solar_w).sum(),
+            load_w: readings.iter().map(|r| r.load_w).sum(),
+            essential_load_w: readings.iter().map(|r| r.essential_load_w).sum(),
+            battery_pct: readings.iter().map(|r| r.battery_pct).fold(f64::INFINITY, f64::min),
+            battery_w: readings.iter().map(|r| r.battery_w).sum(),
+            runtime_min: readings.iter()
+                .map(|r| r.runtime_min)
+                .try_fold(MAX_RUNTIME_MIN, |lowest, runtime| Some(lowest.min(runtime?))),
+        }
+    }
+
+    pub fn field(&self, name: &str) -> Option<f64> {
+        match name {
+            "grid_w" => Some(self.grid_w),
+            "solar_w" => Some(self.solar_w),
+            "load_w" => Some(self.load_w),
+            "essential_load_w" => Some(self.essential_load_w),
+            "battery_pct" => Some(self.battery_pct),
+            "battery_w" => Some(self.battery_w),
+            "runtime_min" => self.runtime_min,
+            _ => None,
+        }
+    }
+}
+
+pub fn parse_power_value(value: &str) -> f64 {
+    value.trim_end_matches('W')
+        .parse::<f64>()
+        .unwrap_or(0.0)
+}
+
+pub fn parse_battery_percentage(value: &str) -> f64 {
+    value.trim_end_matches('%')
+        .parse::<f64>()
+        .unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+    use serde_json::{json, Value};
+
+    /// Serializes `value`, checks it against the pinned JSON and parses it back.
+    fn assert_schema<T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug>(value: T, expected: Value) {
+        let serialized = serde_json::to_value(&value).unwrap();
+        assert_eq!(serialized, expected);
+        assert_eq!(serde_json::from_value::<T>(serialized).unwrap(), value);
+    }
+
+    #[test]
+    fn status_schema() {
+        assert_schema(
+            StatusOutput {
+                labels: BTreeMap::from([("site".to_string(), "cabin".to_string())]),
+                solar_panels: "2800.0W".to_string(),
+                batteries: "55.0%".to_string(),
+                battery_soc_raw: "55.0%".to_string(),
+                battery_status: "Discharging".to_string(),
+                battery_power: "200.0W".to_string(),
+                grid_status: "Exporting".to_string(),
+                grid_power: "800.0W".to_string(),
+                home_consumption: "1800.0W".to_string(),
+                bms_discharge_limit: "2500.0W".to_string(),
+                partial: false,
+                battery_power_w: Some(-200.0),
+                battery_direction: Some(BatteryDirection::Discharging),
+                grid_power_w: Some(800.0),
+                grid_direction: Some(GridDirection::Exporting),
+                circuits_w: BTreeMap::from([("Heat Pump Power".to_string(), 1200.0)]),
+                essential_load_w: Some(600.0),
+            },
+            json!({
+                "labels": {"site": "cabin"},
+                "solar_panels": "2800.0W",
+                "batteries": "55.0%",
+                "battery_soc_raw": "55.0%",
+                "battery_status": "Discharging",
+                "battery_power": "200.0W",
+                "grid_status": "Exporting",
+                "grid_power": "800.0W",
+                "home_consumption": "1800.0W",
+                "bms_discharge_limit": "2500.0W",
+                "partial": false,
+                "battery_power_w": -200.0,
+                "battery_direction": "discharging",
+                "grid_power_w": 800.0,
+                "grid_direction": "exporting",
+                "circuits_w": {"Heat Pump Power": 1200.0},
+                "essential_load_w": 600.0,
+            }),
+        );
+    }
+
+    #[test]
+    fn raw_schema() {
+        assert_schema(
+            RawOutput {
+                labels: BTreeMap::new(),
+                partial: true,
+                data_len: 50,
+                measurements: BTreeMap::from([(
+                    "Grid 1 Voltage".to_string(),
+                    RawMeasurement {
+                        value: 230.1,
+                        unit: "V".to_string(),
+                        seq: Some(41),
+                        observed_at: Some(1_700_000_000),
+                        carried_over: false,
+                    },
+                )]),
+                backup_runtime_estimate_hours: Some(7.5),
+                battery_charge_advisory: Some(ChargeAdvisory::Reduced),
+                signature: Some(Signature {
+                    algorithm: "ed25519".to_string(),
+                    signed_at: 1_700_000_005,
+                    public_key: "cHVibGlj".to_string(),
+                    value: "c2lnbmF0dXJl".to_string(),
+                }),
+            },
+            json!({
+                "labels": {},
+                "partial": true,
+                "data_len": 50,
+                "measurements": {"Grid 1 Voltage": {
+                    "value": 230.1,
+                    "unit": "V",
+                    "seq": 41,
+                    "observed_at": 1_700_000_000,
+                    "carried_over": false
+                }},
+                "backup_runtime_estimate_hours": 7.5,
+                "battery_charge_advisory": "reduced",
+                "signature": {
+                    "algorithm": "ed25519",
+                    "signed_at": 1_700_000_005,
+                    "public_key": "cHVibGlj",
+                    "value": "c2lnbmF0dXJl"
+                },
+            }),
+        );
+    }
+
+    #[test]
+    fn raw_measurements_without_observation_parse() {
+        let measurement: RawMeasurement = serde_json::from_value(json!({"value": 230.1, "unit": "V"})).unwrap();
+        assert_eq!(measurement, RawMeasurement::new(230.1, "V"));
+    }
+
+    #[test]
+    fn health_schema() {
+        assert_schema(
+            HealthOutput {
+                healthy: true,
+                partial: false,
+                source: Some("http://10.0.0.50".to_string()),
+                last_success: Some(1_700_000_000),
+                last_error: None,
+                sources: vec![SourceHealth { url: "http://10.0.0.50".to_string(), up: Some(true) }],
+                backoff: BackoffHealth {
+                    state: "normal".to_string(),
+                    consecutive_failures: 0,
+                    next_poll: Some(1_700_000_060),
+                },
+                textfile_error: None,
+                quality: "degraded".to_string(),
+                failing_invariants: vec!["grid_voltage: Grid 1 Voltage 6553.5 V".to_string()],
+            },
+            json!({
+                "healthy": true,
+                "partial": false,
+                "source": "http://10.0.0.50",
+                "last_success": 1_700_000_000u64,
+                "last_error": null,
+                "sources": [{"url": "http://10.0.0.50", "up": true}],
+                "backoff": {"state": "normal", "consecutive_failures": 0, "next_poll": 1_700_000_060u64},
+                "textfile_error": null,
+                "quality": "degraded",
+                "failing_invariants": ["grid_voltage: Grid 1 Voltage 6553.5 V"],
+            }),
+        );
+    }
+
+    #[test]
+    fn availability_schema() {
+        let date = chrono::NaiveDate::from_ymd_opt(2026, 1, 1).unwrap();
+        assert_schema(
+            AvailabilityOutput {
+                days: vec![AvailabilitySummary::new(Some(date), 4, 2, 180)],
+                overall: AvailabilitySummary::new(None, 4, 2, 180),
+            },
+            json!({
+                "days": [{"date": "2026-01-01", "attempted": 4, "succeeded": 2, "ratio": 0.5, "longest_gap_secs": 180}],
+                "overall": {"date": null, "attempted": 4, "succeeded": 2, "ratio": 0.5, "longest_gap_secs": 180},
+            }),
+        );
+    }
+
+    #[test]
+    fn status_without_newer_fields_parses() {
+        let status: StatusOutput = serde_json::from_value(json!({
+            "solar_panels": "2800.0W",
+            "batteries": "55.0%",
+            "battery_status": "Charging",
+            "battery_power": "200.0W",
+            "grid_status": "Importing",
+            "grid_power": "300.0W",
+            "home_consumption": "1800.0W",
+        })).unwrap();
+        assert!(status.labels.is_empty() && !status.partial);
+        assert_eq!(
+            Readings::from_status(&status),
+            Readings { grid_w: -300.0, solar_w: 2800.0, load_w: 1800.0, essential_load_w: 1800.0, battery_pct: 55.0, battery_w: 200.0, runtime_min: None }
+        );
+    }
+
+    #[test]
+    fn power_is_signed_in_both_directions() {
+        let status = |battery_status: &str, grid_status: &str| -> StatusOutput {
+            serde_json::from_value(json!({
+                "solar_panels": "0.0W",
+                "batteries": "55.0%",
+                "battery_status": battery_status,
+                "battery_power": "200.0W",
+                "grid_status": grid_status,
+                "grid_power": "300.0W",
+                "home_consumption": "500.0W",
+            })).unwrap()
+        };
+        // From the magnitude and direction of instances without the signed fields
+        let older = status("Charging", "Exporting");
+        assert_eq!((older.battery_w(), older.grid_w()), (200.0, 300.0));
+        let older = status("Discharging", "Importing");
+        assert_eq!((older.battery_w(), older.grid_w()), (-200.0, -300.0));
+
+        // The signed fields win over the direction strings
+        let current = StatusOutput { battery_power_w: Some(-200.0), grid_power_w: Some(300.0), ..status("Charging", "Importing") };
+        assert_eq!((current.battery_w(), current.grid_w()), (-200.0, 300.0));
+        let current = StatusOutput { battery_power_w: Some(200.0), grid_power_w: Some(-300.0), ..status("Discharging", "Exporting") };
+        assert_eq!((current.battery_w(), current.grid_w()), (200.0, -300.0));
+        let readings = Readings::from_status(&current);
+        assert_eq!((readings.battery_w, readings.grid_w), (200.0, -300.0));
+
+        assert_eq!(BatteryDirection::from_w(200.0), BatteryDirection::Charging);
+        assert_eq!(BatteryDirection::from_w(-200.0), BatteryDirection::Discharging);
+        assert_eq!(BatteryDirection::from_w(0.0), BatteryDirection::Idle);
+        assert_eq!(GridDirection::from_w(300.0), GridDirection::Exporting);
+        assert_eq!(GridDirection::from_w(-300.0), GridDirection::Importing);
+        assert_eq!(GridDirection::from_w(0.0), GridDirection::Idle);
+    }
+
+    #[test]
+    fn readings_combine_sums_power_and_takes_lowest_battery() {
+        let combined = Readings::combine(&[
+            Readings { grid_w: 0.0, solar_w: 100.0, load_w: 500.0, essential_load_w: 200.0, battery_pct: 40.0, battery_w: -400.0, runtime_min: Some(60.0) },
+            Readings { grid_w: 200.0, solar_w: 300.0, load_w: 100.0, essential_load_w: 100.0, battery_pct: 20.0, battery_w: 100.0, runtime_min: Some(MAX_RUNTIME_MIN) },
+        ]);
+        assert_eq!(combined, Readings {
+            grid_w: 200.0,
+            solar_w: 400.0,
+            load_w: 600.0,
+            essential_load_w: 300.0,
+            battery_pct: 20.0,
+            battery_w: -300.0,
+            runtime_min: Some(60.0),
+        });
+        assert_eq!(combined.field("load_w"), Some(600.0));
+        assert_eq!(combined.field("voltage"), None);
+
+        // One source without an estimate leaves the total without one
+        let partial = Readings::combine(&[Readings { runtime_min: Some(60.0), ..Readings::default() }, Readings::default()]);
+        assert_eq!(partial.field("runtime_min"), None);
+    }
+
+    #[test]
+    fn runtime_estimate_is_capped_around_zero_power() {
+        // 15% of 10 kWh is 1.5 kWh: 7.5 hours at 200 W, 30 minutes at 3 kW
+        assert_eq!(runtime_minutes(15.0, 10.0, -200.0), 450.0);
+        assert_eq!(runtime_minutes(15.0, 10.0, -3000.0), 30.0);
+        assert_eq!(runtime_minutes(15.0, 10.0, -0.4), MAX_RUNTIME_MIN);
+        assert_eq!(runtime_minutes(15.0, 10.0, 0.0), MAX_RUNTIME_MIN);
+        assert_eq!(runtime_minutes(90.0, 10.0, 2500.0), MAX_RUNTIME_MIN);
+        assert_eq!(runtime_minutes(100.0, 10.0, -5.0), MAX_RUNTIME_MIN);
+    }
+
+    #[test]
+    fn http_stats_schema() {
+        assert_schema(
+            HttpStatsOutput {
+                routes: BTreeMap::from([("/v1/status".to_string(), RouteStats { requests: 10, errors: 1 })]),
+            },
+            json!({"routes": {"/v1/status": {"requests": 10, "errors": 1}}}),
+        );
+    }
+
+    #[test]
+    fn info_schema() {
+        assert_schema(
+            InfoOutput {
+                labels: BTreeMap::new(),
+                sn: "SXXXXXXXXX".to_string(),
+                model: "X3-Hybrid-G4".to_string(),
+                rated_power_kw: Some(10.0),
+                machine_type: Some(14),
+                module_sn: Some("H34A10XXXXXXXX".to_string()),
+                information: json!([10.0, 14, "H34A10XXXXXXXX"]),
+                signing_public_key: None,
+                dongle_protocol: Some("session".to_string()),
+            },
+            json!({
+                "labels": {},
+                "sn": "SXXXXXXXXX",
+                "model": "X3-Hybrid-G4",
+                "rated_power_kw": 10.0,
+                "machine_type": 14,
+                "module_sn": "H34A10XXXXXXXX",
+                "information": [10.0, 14, "H34A10XXXXXXXX"],
+                "dongle_protocol": "session"
+            }),
+        );
+    }
+
+    #[test]
+    fn battery_stats_schema() {
+        let date = chrono::NaiveDate::from_ymd_opt(2026, 3, 1).unwrap();
+        assert_schema(
+            BatteryStatsOutput {
+                days: vec![BatteryThroughputSummary { date: Some(date), charged_kwh: 6.5, discharged_kwh: 5.0, cycles: Some(0.575) }],
+                lifetime: BatteryThroughputSummary { date: None, charged_kwh: 650.0, discharged_kwh: 600.0, cycles: None },
+                capacity: CapacityOutput {
+                    configured_kwh: Some(10.0),
+                    learned_kwh: Some(8.2),
+                    in_use_kwh: Some(8.2),
+                    segments: 3,
+                    confidence: "medium".to_string(),
+                    last_updated: Some(1_700_000_000),
+                },
+            },
+            json!({
+                "days": [{"date": "2026-03-01", "charged_kwh": 6.5, "discharged_kwh": 5.0, "cycles": 0.575}],
+                "lifetime": {"date": null, "charged_kwh": 650.0, "discharged_kwh": 600.0, "cycles": null},
+                "capacity": {
+                    "configured_kwh": 10.0,
+                    "learned_kwh": 8.2,
+                    "in_use_kwh": 8.2,
+                    "segments": 3,
+                    "confidence": "medium",
+                    "last_updated": 1_700_000_000
+                }
+            }),
+        );
+    }
+
+    #[test]
+    fn battery_modules_schema() {
+        assert_schema(
+            BatteryModulesOutput {
+                modules: vec![
+                    BatteryModule { module: 1, soc_pct: 62.0, voltage_v: 104.5, temperature_c: 21.0 },
+                    BatteryModule { module: 2, soc_pct: 55.0, voltage_v: 103.9, temperature_c: 22.0 },
+                ],
+                soc_spread_pct: Some(7.0),
+                drift_alert_pct: Some(5.0),
+                drifting: true,
+            },
+            json!({
+                "modules": [
+                    {"module": 1, "soc_pct": 62.0, "voltage_v": 104.5, "temperature_c": 21.0},
+                    {"module": 2, "soc_pct": 55.0, "voltage_v": 103.9, "temperature_c": 22.0}
+                ],
+                "soc_spread_pct": 7.0,
+                "drift_alert_pct": 5.0,
+                "drifting": true
+            }),
+        );
+    }
+
+    #[test]
+    fn export_limit_schema() {
+        assert_schema(
+            ExportLimitOutput { watts: 4000, previous_watts: Some(10000) },
+            json!({"watts": 4000, "previous_watts": 10000}),
+        );
+    }
+
+    #[test]
+    fn battery_mode_schema() {
+        assert_schema(
+            BatteryModeOutput { mode: "force_charge".to_string(), revert_at: Some(1_700_010_800) },
+            json!({"mode": "force_charge", "revert_at": 1_700_010_800}),
+        );
+    }
+
+    #[test]
+    fn evc_status_schema() {
+        assert_schema(
+            EvcStatusOutput {
+                labels: BTreeMap::from([("device".to_string(), "evc".to_string())]),
+                state: "charging".to_string(),
+                plugged: true,
+                charging_power_w: 11030.0,
+                session_energy_kwh: 12.5,
+                home_consumption_w: Some(12000.0),
+                home_consumption_without_evc_w: Some(970.0),
+            },
+            json!({
+                "labels": {"device": "evc"},
+                "state": "charging",
+                "plugged": true,
+                "charging_power_w": 11030.0,
+                "session_energy_kwh": 12.5,
+                "home_consumption_w": 12000.0,
+                "home_consumption_without_evc_w": 970.0
+            }),
+        );
+    }
+
+    #[test]
+    fn surplus_schema() {
+        assert_schema(
+            SurplusOutput {
+                export_w: Some(2100.0),
+                export_limit_w: Some(5000.0),
+                devices: vec![SurplusDeviceStatus {
+                    name: "heater".to_string(),
+                    on: true,
+                    since: Some(1_700_000_000),
+                    switches: 3,
+                    last_decision: Some("export 2100 W above 1000 W".to_string()),
+                    last_error: None,
+                }],
+            },
+            json!({
+                "export_w": 2100.0,
+                "export_limit_w": 5000.0,
+                "devices": [{
+                    "name": "heater",
+                    "on": true,
+                    "since": 1_700_000_000,
+                    "switches": 3,
+                    "last_decision": "export 2100 W above 1000 W",
+                    "last_error": null
+                }]
+            }),
+        );
+    }
+
+    #[test]
+    fn curtailment_schema() {
+        assert_schema(
+            CurtailmentOutput {
+                estimate: "recent_max".to_string(),
+                export_limit_w: Some(4000.0),
+                export_headroom_w: Some(20.0),
+                expected_solar_w: Some(6200.0),
+                curtailing: true,
+                estimated_today_kwh: 1.5,
+                days: vec![CurtailmentDay {
+                    date: chrono::NaiveDate::from_ymd_opt(2024, 6, 21).unwrap(),
+                    estimated_kwh: 1.5,
+                    curtailed_secs: 5400,
+                }],
+            },
+            json!({
+                "estimate": "recent_max",
+                "export_limit_w": 4000.0,
+                "export_headroom_w": 20.0,
+                "expected_solar_w": 6200.0,
+                "curtailing": true,
+                "estimated_today_kwh": 1.5,
+                "days": [{"date": "2024-06-21", "estimated_kwh": 1.5, "curtailed_secs": 5400}]
+            }),
+        );
+    }
+
+    #[test]
+    fn report_schema() {
+        let date = chrono::NaiveDate::from_ymd_opt(2026, 6, 21).unwrap();
+        assert_schema(
+            ReportOutput {
+                period: "2026-06".to_string(),
+                partial: false,
+                days_in_period: 30,
+                days_recorded: 29,
+                generated_kwh: 812.5,
+                consumed_kwh: 402.0,
+                imported_kwh: 35.2,
+                exported_kwh: 390.1,
+                battery_charged_kwh: 120.0,
+                battery_discharged_kwh: 110.4,
+                best_day: Some(ReportDay { date, generated_kwh: 41.2 }),
+                worst_day: None,
+                availability: AvailabilitySummary::new(None, 4, 3, 120),
+            },
+            json!({
+                "period": "2026-06",
+                "partial": false,
+                "days_in_period": 30,
+                "days_recorded": 29,
+                "generated_kwh": 812.5,
+                "consumed_kwh": 402.0,
+                "imported_kwh": 35.2,
+                "exported_kwh": 390.1,
+                "battery_charged_kwh": 120.0,
+                "battery_discharged_kwh": 110.4,
+                "best_day": {"date": "2026-06-21", "generated_kwh": 41.2},
+                "worst_day": null,
+                "availability": {"date": null, "attempted": 4, "succeeded": 3, "ratio": 0.75, "longest_gap_secs": 120}
+            }),
+        );
+    }
+
+    #[test]
+    fn settings_schema() {
+        assert_schema(
+            SettingsOutput {
+                settings: Some(InverterSettings {
+                    export_limit_w: 5000,
+                    work_mode: "self_use".to_string(),
+                    battery_mode: Some("self_use".to_string()),
+                    charge_power_limit_w: 3000,
+                    raw: vec![0, 1],
+                }),
+                read_at: Some(1_700_000_000),
+                last_attempt: Some(1_700_000_900),
+                last_error: Some("timed out".to_string()),
+                consecutive_failures: 1,
+            },
+            json!({
+                "settings": {
+                    "export_limit_w": 5000,
+                    "work_mode": "self_use",
+                    "battery_mode": "self_use",
+                    "charge_power_limit_w": 3000,
+                    "raw": [0, 1]
+                },
+                "read_at": 1_700_000_000,
+                "last_attempt": 1_700_000_900,
+                "last_error": "timed out",
+                "consecutive_failures": 1
+            }),
+        );
+    }
+
+    #[test]
+    fn command_result_schema() {
+        assert_schema(
+            CommandResult::failed(Some("a1".to_string()), Some("set_export_limit"), "Control commands are disabled".to_string()),
+            json!({"id": "a1", "command": "set_export_limit", "ok": false, "error": "Control commands are disabled"}),
+        );
+    }
+
+    #[test]
+    fn zabbix_stats_schema() {
+        assert_schema(
+            ZabbixStatsOutput {
+                sends: 10,
+                failed_sends: 1,
+                items_processed: 250,
+                items_failed: 2,
+                last_send: Some(1_700_000_000),
+                last_error: Some("connection refused".to_string()),
+            },
+            json!({
+                "sends": 10,
+                "failed_sends": 1,
+                "items_processed": 250,
+                "items_failed": 2,
+                "last_send": 1_700_000_000,
+                "last_error": "connection refused"
+            }),
+        );
+    }
+
+    #[test]
+    fn redis_stats_schema() {
+        assert_schema(
+            RedisStatsOutput {
+                connected: false,
+                writes: 120,
+                failed_writes: 1,
+                connection_failures: 4,
+                last_write: Some(1_700_000_000),
+                last_error: Some("connection refused".to_string()),
+            },
+            json!({
+                "connected": false,
+                "writes": 120,
+                "failed_writes": 1,
+                "connection_failures": 4,
+                "last_write": 1_700_000_000,
+                "last_error": "connection refused"
+            }),
+        );
+    }
+
+    #[test]
+    fn postgres_stats_schema() {
+        assert_schema(
+            PostgresStatsOutput {
+                connected: true,
+                inserts: 300,
+                failed_inserts: 2,
+                connection_failures: 1,
+                rows_inserted: 9000,
+                rows_buffered: 0,
+                rows_dropped: 0,
+                last_insert: Some(1_700_000_000),
+                last_insert_latency_ms: Some(12),
+                last_error: None,
+            },
+            json!({
+                "connected": true,
+                "inserts": 300,
+                "failed_inserts": 2,
+                "connection_failures": 1,
+                "rows_inserted": 9000,
+                "rows_buffered": 0,
+                "rows_dropped": 0,
+                "last_insert": 1_700_000_000,
+                "last_insert_latency_ms": 12,
+                "last_error": null
+            }),
+        );
+    }
+
+    #[test]
+    fn event_stats_schema() {
+        assert_schema(
+            EventStatsOutput {
+                connected: true,
+                published: 42,
+                failed_publishes: 1,
+                pending: 0,
+                dropped: 0,
+                last_seq: Some(41),
+                last_publish: Some(1_700_000_000),
+                last_error: None,
+            },
+            json!({
+                "connected": true,
+                "published": 42,
+                "failed_publishes": 1,
+                "pending": 0,
+                "dropped": 0,
+                "last_seq": 41,
+                "last_publish": 1_700_000_000,
+                "last_error": null
+            }),
+        );
+    }
+
+    #[test]
+    fn federation_schema() {
+        let snapshot = RawOutput {
+            labels: BTreeMap::new(),
+            partial: false,
+            data_len: 300,
+            measurements: BTreeMap::from([("Grid Power".to_string(), RawMeasurement::new(-500.0, "W"))]),
+            backup_runtime_estimate_hours: None,
+            battery_charge_advisory: None,
+            signature: None,
+        };
+        assert_schema(
+            FederationOutput {
+                sites: vec![FederationSite {
+                    name: "cabin".to_string(),
+                    url: "http://10.0.0.7:3000".to_string(),
+                    stale: true,
+                    age_secs: Some(400),
+                    consecutive_failures: 2,
+                    last_error: Some("connection refused".to_string()),
+                    snapshot: Some(snapshot),
+                }],
+                totals: BTreeMap::new(),
+            },
+            json!({
+                "sites": [{
+                    "name": "cabin",
+                    "url": "http://10.0.0.7:3000",
+                    "stale": true,
+                    "age_secs": 400,
+                    "consecutive_failures": 2,
+                    "last_error": "connection refused",
+                    "snapshot": {
+                        "labels": {},
+                        "partial": false,
+                        "data_len": 300,
+                        "measurements": {"Grid Power": {
+                            "value": -500.0,
+                            "unit": "W",
+                            "seq": null,
+                            "observed_at": null,
+                            "carried_over": false
+                        }},
+                        "backup_runtime_estimate_hours": null,
+                        "battery_charge_advisory": null
+                    }
+                }],
+                "totals": {}
+            }),
+        );
+    }
+
+    #[test]
+    fn threshold_event_schema() {
+        assert_schema(
+            ThresholdEvent {
+                rule: "high_load".to_string(),
+                state: "fired".to_string(),
+                metric: "Load/Generator Power".to_string(),
+                condition: "> 5000".to_string(),
+                value: 5230.0,
+                time: 1_700_000_000,
+            },
+            json!({
+                "rule": "high_load",
+                "state": "fired",
+                "metric": "Load/Generator Power",
+                "condition": "> 5000",
+                "value": 5230.0,
+                "time": 1_700_000_000
+            }),
+        );
+    }
+
+    #[test]
+    fn inverter_restart_schema() {
+        assert_schema(
+            InverterRestart {
+                reason: "Firmware changed from 3.008.10 to 3.009.02".to_string(),
+                firmware: "3.009.02".to_string(),
+                previous_firmware: "3.008.10".to_string(),
+                time: 1_700_000_600,
+                previous_time: 1_700_000_000,
+            },
+            json!({
+                "reason": "Firmware changed from 3.008.10 to 3.009.02",
+                "firmware": "3.009.02",
+                "previous_firmware": "3.008.10",
+                "time": 1_700_000_600,
+                "previous_time": 1_700_000_000
+            }),
+        );
+    }
+
+    #[test]
+    fn snapshot_diff_schema() {
+        assert_schema(
+            SnapshotDiff {
+                seq: 12,
+                previous_seq: 11,
+                time: 1_700_000_060,
+                changes: vec![
+                    Change::State { name: "battery".to_string(), from: "charging".to_string(), to: "discharging".to_string() },
+                    Change::Value { metric: "Grid Power".to_string(), from: -450.0, to: 820.0, unit: "W".to_string() },
+                ],
+            },
+            json!({
+                "seq": 12,
+                "previous_seq": 11,
+                "time": 1_700_000_060,
+                "changes": [
+                    {"kind": "state", "name": "battery", "from": "charging", "to": "discharging"},
+                    {"kind": "value", "metric": "Grid Power", "from": -450.0, "to": 820.0, "unit": "W"}
+                ]
+            }),
+        );
+    }
+}
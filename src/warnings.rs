@@ -0,0 +1,122 @@
+//! Warnings about conditions that last for many polls, like an unreachable sink or a truncated
+//! Data array: logged once when the condition starts, again only after the repeat interval while
+//! it lasts, and once more when it clears.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+/// One condition while it lasts.
+#[derive(Debug, Clone)]
+struct Episode {
+    since: Instant,
+    last_logged: Instant,
+    /// Times warned about, including the logged ones.
+    count: u64,
+}
+
+/// The ongoing conditions, by key.
+#[derive(Debug, Clone, Default)]
+pub struct Warnings {
+    /// How often a lasting condition is logged again; once per episode without one.
+    repeat: Option<Duration>,
+    active: HashMap<String, Episode>,
+}
+
+impl Warnings {
+    /// Logs each condition once per episode.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Logs each condition again every `interval` while it lasts.
+    pub fn repeating(interval: Duration) -> Self {
+        Self { repeat: Some(interval), active: HashMap::new() }
+    }
+
+    /// Notes that the condition `key` holds, logging `message` to stderr if it's due.
+    pub fn warn(&mut self, key: &str, message: impl Display) {
+        if let Some(line) = self.warning(key, message, Instant::now()) {
+            eprintln!("{}", line);
+        }
+    }
+
+    /// Notes that the condition `key` no longer holds, logging `message` if it did.
+    pub fn clear(&mut self, key: &str, message: impl Display) {
+        if let Some(line) = self.clearing(key, message, Instant::now()) {
+            println!("{}", line);
+        }
+    }
+
+    /// `warn` or `clear`, depending on `holds`.
+    pub fn track(&mut self, key: &str, holds: bool, warning: impl Display, cleared: impl Display) {
+        if holds {
+            self.warn(key, warning);
+        } else {
+            self.clear(key, cleared);
+        }
+    }
+
+    pub fn is_active(&self, key: &str) -> bool {
+        self.active.contains_key(key)
+    }
+
+    /// The line `warn` logs at `now`, if any.
+    pub fn warning(&mut self, key: &str, message: impl Display, now: Instant) -> Option<String> {
+        let Some(episode) = self.active.get_mut(key) else {
+            self.active.insert(key.to_string(), Episode { since: now, last_logged: now, count: 1 });
+            return Some(message.to_string());
+        };
+        episode.count += 1;
+        let due = self.repeat.is_some_and(|interval| now.duration_since(episode.last_logged) >= interval);
+        if !due {
+            return None;
+        }
+        episode.last_logged = now;
+        Some(format!("{} ({} times in {}s)", message, episode.count, now.duration_since(episode.since).as_secs()))
+    }
+
+    /// The line `clear` logs at `now`, if any.
+    pub fn clearing(&mut self, key: &str, message: impl Display, now: Instant) -> Option<String> {
+        let episode = self.active.remove(key)?;
+        Some(format!("{} (after {} warning(s) in {}s)", message, episode.count, now.duration_since(episode.since).as_secs()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_an_episode_once_and_its_end() {
+        let mut warnings = Warnings::new();
+        let start = Instant::now();
+        let mut lines = Vec::new();
+        for poll in 0..100 {
+            let now = start + Duration::from_secs(10 * poll);
+            lines.extend(warnings.warning("redis", "Failed to write to Redis", now));
+            assert!(warnings.clearing("statsd", "Sending to StatsD again", now).is_none());
+        }
+        lines.extend(warnings.clearing("redis", "Writing to Redis again", start + Duration::from_secs(1000)));
+        assert_eq!(lines, [
+            "Failed to write to Redis",
+            "Writing to Redis again (after 100 warning(s) in 1000s)",
+        ]);
+        assert!(!warnings.is_active("redis"));
+        assert_eq!(warnings.warning("redis", "Failed to write to Redis", start).as_deref(), Some("Failed to write to Redis"));
+    }
+
+    #[test]
+    fn repeats_lasting_conditions_at_the_interval() {
+        let mut warnings = Warnings::repeating(Duration::from_secs(600));
+        let start = Instant::now();
+        let logged: Vec<u64> = (0..100)
+            .filter(|poll| warnings.warning("peer cabin", "Federation peer cabin is unreachable", start + Duration::from_secs(10 * poll)).is_some())
+            .collect();
+        assert_eq!(logged, [0, 60]);
+        let repeated = warnings.warning("peer cabin", "Federation peer cabin is unreachable", start + Duration::from_secs(1200));
+        assert_eq!(repeated.as_deref(), Some("Federation peer cabin is unreachable (101 times in 1200s)"));
+        // Other keys are episodes of their own
+        assert!(warnings.warning("peer barn", "Federation peer barn is unreachable", start).is_some());
+    }
+}
@@ -0,0 +1,156 @@
+//! Pushes values to a Zabbix server or proxy with the trapper (zabbix_sender) protocol.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+pub const DEFAULT_PORT: u16 = 10051;
+
+/// `ZBXD` followed by the protocol flags (0x01, plain JSON).
+const HEADER: &[u8; 5] = b"ZBXD\x01";
+/// Largest response read back; the server answers with a short summary.
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Item {
+    pub host: String,
+    pub key: String,
+    pub value: String,
+    pub clock: u64,
+}
+
+#[derive(Serialize)]
+struct SenderData<'a> {
+    request: &'static str,
+    data: &'a [Item],
+}
+
+#[derive(Deserialize)]
+struct SenderResponse {
+    response: String,
+    #[serde(default)]
+    info: String,
+}
+
+/// What the server did with a batch, from its `processed: 3; failed: 0; total: 3` summary.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Acceptance {
+    pub processed: u64,
+    pub failed: u64,
+    pub total: u64,
+}
+
+impl Acceptance {
+    pub fn parse(info: &str) -> Option<Self> {
+        let mut acceptance = Acceptance::default();
+        for part in info.split(';') {
+            let (name, value) = part.split_once(':')?;
+            let value = value.trim();
+            match name.trim() {
+                "processed" => acceptance.processed = value.parse().ok()?,
+                "failed" => acceptance.failed = value.parse().ok()?,
+                "total" => acceptance.total = value.parse().ok()?,
+                _ => {}
+            }
+        }
+        Some(acceptance)
+    }
+}
+
+/// Turns a measurement name like "Load/Generator Power" into `<prefix>load_generator_power`.
+pub fn item_key(prefix: &str, name: &str) -> String {
+    let mut key = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            key.push(c.to_ascii_lowercase());
+        } else if !key.is_empty() && !key.ends_with('_') {
+            key.push('_');
+        }
+    }
+    format!("{}{}", prefix, key.trim_end_matches('_'))
+}
+
+/// Frames a payload: the header, then its length as a little-endian u64.
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER.len() + 8 + payload.len());
+    packet.extend_from_slice(HEADER);
+    packet.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Reads one framed packet and returns its payload.
+async fn read_packet<R: tokio::io::AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut header = [0u8; 13];
+    reader.read_exact(&mut header).await?;
+    if &header[..4] != b"ZBXD" {
+        return Err("Response is not a Zabbix protocol packet".into());
+    }
+    let len = u64::from_le_bytes(header[5..13].try_into()?);
+    if len > MAX_RESPONSE_LEN {
+        return Err(format!("Zabbix response of {} bytes is too long", len).into());
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+/// Sends all items over one connection and returns how many the server accepted.
+pub async fn send(server: &str, items: &[Item], timeout: Duration) -> Result<Acceptance, Box<dyn std::error::Error + Send + Sync>> {
+    let payload = serde_json::to_vec(&SenderData { request: "sender data", data: items })?;
+    let exchange = async {
+        let mut stream = tokio::net::TcpStream::connect(server).await?;
+        stream.write_all(&encode(&payload)).await?;
+        read_packet(&mut stream).await
+    };
+    let response = tokio::time::timeout(timeout, exchange).await
+        .map_err(|_| format!("Zabbix server {} didn't answer within {}s", server, timeout.as_secs()))??;
+    let response: SenderResponse = serde_json::from_slice(&response)?;
+    if response.response != "success" {
+        return Err(format!("Zabbix server answered {:?}: {}", response.response, response.info).into());
+    }
+    Acceptance::parse(&response.info)
+        .ok_or_else(|| format!("Unexpected Zabbix response info {:?}", response.info).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_item_keys() {
+        assert_eq!(item_key("solax.", "Load/Generator Power"), "solax.load_generator_power");
+        assert_eq!(item_key("", "Battery SoC (calibrated)"), "battery_soc_calibrated");
+    }
+
+    #[test]
+    fn parses_acceptance() {
+        let info = "processed: 3; failed: 1; total: 4; seconds spent: 0.000055";
+        assert_eq!(Acceptance::parse(info), Some(Acceptance { processed: 3, failed: 1, total: 4 }));
+        assert_eq!(Acceptance::parse("garbage"), None);
+    }
+
+    #[tokio::test]
+    async fn sends_a_batch_and_reads_the_summary() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request: serde_json::Value = serde_json::from_slice(&read_packet(&mut stream).await.unwrap()).unwrap();
+            let reply = br#"{"response":"success","info":"processed: 1; failed: 1; total: 2; seconds spent: 0.000041"}"#;
+            stream.write_all(&encode(reply)).await.unwrap();
+            request
+        });
+
+        let items: Vec<Item> = ["solax.grid_power", "solax.battery_soc"].iter()
+            .map(|key| Item { host: "cabin".to_string(), key: key.to_string(), value: "1.5".to_string(), clock: 1_700_000_000 })
+            .collect();
+        let acceptance = send(&addr.to_string(), &items, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(acceptance, Acceptance { processed: 1, failed: 1, total: 2 });
+
+        let request = server.await.unwrap();
+        assert_eq!(request["request"], "sender data");
+        assert_eq!(request["data"][1]["key"], "solax.battery_soc");
+        assert_eq!(request["data"][0]["host"], "cabin");
+    }
+}
@@ -0,0 +1,233 @@
+//! The status handler's read path under many concurrent HTTP readers of `Latest`. The default
+//! suite checks what the readers see: never a torn poll, never one older than they already saw.
+//! The load test compares the latencies against the lock the polls used to be written under:
+//! `cargo test --test concurrent_readers -- --ignored --nocapture`.
+
+use axum::extract::State;
+use axum::routing::get;
+use axum::{Json, Router};
+use solax_mon::inverter::X3HybridG4;
+use solax_mon::latest::Latest;
+use solax_mon::simulator::{DayConfig, Simulator};
+use solax_mon::status::StatusOutput;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+const READERS: usize = 64;
+const REQUESTS: usize = 100;
+
+/// The read path of `/status`: an `Arc` clone of the latest poll, serialized.
+async fn get_status(State(latest): State<Arc<Latest<StatusOutput>>>) -> Json<StatusOutput> {
+    Json((*latest.get()).clone())
+}
+
+/// The read path before `Latest`: the status behind the lock the poller writes it under.
+async fn get_status_locked(State(status): State<Arc<RwLock<StatusOutput>>>) -> Json<StatusOutput> {
+    Json(status.read().await.clone())
+}
+
+/// Where the poller publishes and the readers read.
+#[derive(Clone)]
+enum Store {
+    Channel(Arc<Latest<StatusOutput>>),
+    Locked(Arc<RwLock<StatusOutput>>),
+}
+
+impl Store {
+    fn new(locked: bool, initial: StatusOutput) -> Self {
+        if locked {
+            Store::Locked(Arc::new(RwLock::new(initial)))
+        } else {
+            Store::Channel(Arc::new(Latest::new(initial)))
+        }
+    }
+
+    async fn publish(&self, status: StatusOutput) {
+        match self {
+            Store::Channel(latest) => latest.publish(status),
+            Store::Locked(lock) => *lock.write().await = status,
+        }
+    }
+
+    /// Serves `/status` from the store, returning its URL and the server task.
+    fn serve(&self) -> (String, tokio::task::JoinHandle<Result<(), hyper::Error>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/status", listener.local_addr().unwrap());
+        let app = match self {
+            Store::Channel(latest) => Router::new().route("/status", get(get_status)).with_state(latest.clone()),
+            Store::Locked(lock) => Router::new().route("/status", get(get_status_locked)).with_state(lock.clone()),
+        };
+        (url, tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service())))
+    }
+}
+
+/// A simulated day of statuses, one every 15 minutes.
+fn simulated_day() -> Vec<StatusOutput> {
+    let inverter = X3HybridG4::new(&[], Duration::ZERO);
+    let mut simulator = Simulator::new(DayConfig::default());
+    (0..86_400).step_by(900)
+        .map(|time| {
+            simulator.advance(time);
+            inverter.format_status(&inverter.decode(&simulator.response()))
+        })
+        .collect()
+}
+
+/// Poll `n`, with every power reading set to `n` W so a torn read shows.
+fn numbered(n: u32, template: &StatusOutput) -> StatusOutput {
+    let watts = format!("{}.0W", n);
+    StatusOutput {
+        solar_panels: watts.clone(),
+        battery_power: watts.clone(),
+        grid_power: watts.clone(),
+        home_consumption: watts,
+        ..template.clone()
+    }
+}
+
+/// The number of a poll from `numbered`, None when its readings disagree.
+fn number(status: &StatusOutput) -> Option<u32> {
+    let readings = [&status.solar_panels, &status.battery_power, &status.grid_power, &status.home_consumption];
+    if readings.iter().any(|reading| *reading != readings[0]) {
+        return None;
+    }
+    readings[0].trim_end_matches(".0W").parse().ok()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn readers_never_see_torn_or_older_polls() {
+    const POLLS: u32 = 500;
+    let template = simulated_day()[0].clone();
+    let store = Store::new(false, numbered(0, &template));
+    let (url, server) = store.serve();
+
+    let poller = tokio::spawn({
+        let (store, template) = (store.clone(), template.clone());
+        async move {
+            for n in 1..=POLLS {
+                store.publish(numbered(n, &template)).await;
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let url = url.clone();
+            tokio::spawn(async move {
+                let client = reqwest::Client::new();
+                let mut last = 0;
+                for _ in 0..50 {
+                    let status: StatusOutput = client.get(&url).send().await.unwrap().json().await.unwrap();
+                    let n = number(&status).unwrap_or_else(|| panic!("torn poll: {:?}", status));
+                    assert!(n >= last, "poll {} read after poll {}", n, last);
+                    last = n;
+                }
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.await.unwrap();
+    }
+    poller.await.unwrap();
+
+    // Once the poller is done, every reader gets its last poll
+    let status: StatusOutput = reqwest::get(&url).await.unwrap().json().await.unwrap();
+    assert_eq!(number(&status), Some(POLLS));
+    server.abort();
+}
+
+struct Latencies {
+    sorted: Vec<Duration>,
+    /// Distinct solar readings the readers saw.
+    seen: usize,
+}
+
+impl Latencies {
+    fn percentile(&self, pct: usize) -> Duration {
+        self.sorted[(self.sorted.len() * pct / 100).min(self.sorted.len() - 1)]
+    }
+}
+
+async fn run(readers: usize, publishing: bool, locked: bool) -> Latencies {
+    let day = simulated_day();
+    let store = Store::new(locked, day[0].clone());
+    let (url, server) = store.serve();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let poller = tokio::spawn({
+        let (store, done) = (store.clone(), done.clone());
+        async move {
+            let mut ticker = tokio::time::interval(Duration::from_millis(1));
+            for status in day.iter().cycle() {
+                if done.load(Ordering::SeqCst) {
+                    break;
+                }
+                ticker.tick().await;
+                if publishing {
+                    store.publish(status.clone()).await;
+                }
+            }
+        }
+    });
+
+    let tasks: Vec<_> = (0..readers)
+        .map(|_| {
+            let url = url.clone();
+            tokio::spawn(async move {
+                let client = reqwest::Client::new();
+                let mut latencies = Vec::with_capacity(REQUESTS);
+                let mut seen = HashSet::new();
+                for _ in 0..REQUESTS {
+                    let started = Instant::now();
+                    let status: StatusOutput = client.get(&url).send().await.unwrap().json().await.unwrap();
+                    latencies.push(started.elapsed());
+                    seen.insert(status.solar_panels);
+                }
+                (latencies, seen)
+            })
+        })
+        .collect();
+    let (mut sorted, mut seen) = (Vec::new(), HashSet::new());
+    for task in tasks {
+        let (latencies, solar) = task.await.unwrap();
+        sorted.extend(latencies);
+        seen.extend(solar);
+    }
+    done.store(true, Ordering::SeqCst);
+    poller.await.unwrap();
+    server.abort();
+    sorted.sort();
+    Latencies { sorted, seen: seen.len() }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "load test, timing dependent"]
+async fn status_latency_against_the_locked_read_path() {
+    let runs = [
+        ("1 reader", run(1, true, false).await),
+        ("64 readers, idle poller", run(READERS, false, false).await),
+        ("64 readers, busy poller", run(READERS, true, false).await),
+        ("64 readers, busy, locked", run(READERS, true, true).await),
+    ];
+    for (name, latencies) in &runs {
+        println!(
+            "{:<26} p50 {:>8.2?}  p99 {:>8.2?}  max {:>8.2?}  {} polls seen",
+            name,
+            latencies.percentile(50),
+            latencies.percentile(99),
+            latencies.sorted[latencies.sorted.len() - 1],
+            latencies.seen,
+        );
+    }
+
+    let [_, (_, idle), (_, busy), (_, locked)] = &runs;
+    assert!(busy.seen > 1, "the readers never saw a new poll");
+    // Publishing doesn't hold up the readers: the tail stays where the idle one is, with room
+    // for a loaded debug build, and no worse than behind the lock
+    let (idle_p99, busy_p99, locked_p99) = (idle.percentile(99), busy.percentile(99), locked.percentile(99));
+    assert!(busy_p99 <= idle_p99 * 3 + Duration::from_millis(20), "p99 {:?} busy against {:?} idle", busy_p99, idle_p99);
+    assert!(busy_p99 <= locked_p99 * 2 + Duration::from_millis(20), "p99 {:?} against {:?} locked", busy_p99, locked_p99);
+}
@@ -0,0 +1,55 @@
+//! Reads a file from the Parquet writer back with pyarrow, an independent reader. Needs
+//! python3 with pyarrow installed: `cargo test --test parquet_roundtrip -- --ignored`.
+
+use solax_mon::parquet::{Column, Kind, Values, Writer};
+use std::process::Command;
+
+#[test]
+#[ignore = "needs python3 with pyarrow"]
+fn pyarrow_reads_back_what_was_written() {
+    let columns = vec![
+        Column::new("time", Kind::TimestampMillis),
+        Column::new("date", Kind::Date),
+        Column::new("polls", Kind::Int64),
+        Column::new("grid_power", Kind::Double),
+    ];
+    let mut writer = Writer::new(Vec::new(), columns).unwrap();
+    writer.write_row_group(&[
+        Values::Int(vec![Some(1_750_000_000_000), Some(1_750_000_060_000)]),
+        Values::Int(vec![Some(20_254), None]),
+        Values::Int(vec![None, Some(7)]),
+        Values::Double(vec![Some(-450.5), Some(6553.5)]),
+    ]).unwrap();
+    // Enough row groups for the long list form of the footer
+    for n in 0..20 {
+        writer.write_row_group(&[
+            Values::Int(vec![Some(1_750_000_120_000 + n * 60_000)]),
+            Values::Int(vec![Some(20_255)]),
+            Values::Int(vec![Some(n)]),
+            Values::Double(vec![None]),
+        ]).unwrap();
+    }
+    let path = std::env::temp_dir().join(format!("solax-roundtrip-{}.parquet", std::process::id()));
+    std::fs::write(&path, writer.finish().unwrap()).unwrap();
+
+    let script = r#"
+import sys, pyarrow.parquet as pq
+table = pq.read_table(sys.argv[1])
+print(table.schema.field("time").type, table.schema.field("date").type, table.schema.field("polls").type, table.schema.field("grid_power").type)
+print(table.num_rows, pq.ParquetFile(sys.argv[1]).metadata.num_row_groups)
+for row in table.to_pylist()[:3]:
+    print(int(row["time"].timestamp() * 1000), row["date"], row["polls"], row["grid_power"])
+"#;
+    let output = Command::new("python3").arg("-c").arg(script).arg(&path).output().expect("python3 runs");
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success(), "pyarrow failed: {}", String::from_utf8_lossy(&output.stderr));
+    let text = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines, [
+        "timestamp[ms, tz=UTC] date32[day] int64 double",
+        "22 21",
+        "1750000000000 2025-06-15 None -450.5",
+        "1750000060000 None 7 6553.5",
+        "1750000120000 2025-06-16 0 None",
+    ]);
+}